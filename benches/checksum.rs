@@ -0,0 +1,23 @@
+use bitcasky::internals::ChecksumAlgorithm;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{thread_rng, RngCore};
+
+fn checksum_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("checksum");
+    for size in [1024, 64 * 1024] {
+        let mut value = vec![0; size];
+        thread_rng().fill_bytes(&mut value);
+        group.throughput(Throughput::Bytes(size as u64));
+        for algorithm in [ChecksumAlgorithm::Crc32Posix, ChecksumAlgorithm::Crc32c] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", algorithm), size),
+                &value,
+                |b, value| b.iter(|| algorithm.checksum(value)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, checksum_benchmark);
+criterion_main!(benches);
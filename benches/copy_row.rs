@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use bitcasky::internals::data_storage::{DataStorage, DataStorageReader, DataStorageWriter};
+use bitcasky::internals::{BitcaskyFormatter, RowLocation, RowToWrite, TimedValue};
+use bitcasky::options::{BitcaskyOptions, DataSotrageType};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tempfile::{Builder, TempDir};
+
+const ROWS: usize = 64;
+const VALUE_SIZE: usize = 64 * 1024;
+
+fn create_data_storage(dir: &TempDir, verify_crc_on_read: bool) -> DataStorage {
+    DataStorage::new(
+        dir,
+        1,
+        Arc::new(BitcaskyFormatter::default()),
+        Arc::new(
+            BitcaskyOptions::default()
+                .max_data_file_size(usize::MAX)
+                .max_value_size(VALUE_SIZE)
+                .init_data_file_capacity(2 * ROWS * VALUE_SIZE)
+                .storage_type(DataSotrageType::File)
+                .verify_crc_on_read(verify_crc_on_read),
+        ),
+    )
+    .unwrap()
+}
+
+fn write_source_rows(source: &mut DataStorage) -> Vec<RowLocation> {
+    let locations = (0..ROWS)
+        .map(|i| {
+            source
+                .write_row(&RowToWrite::new(
+                    format!("key-{:08}", i).into_bytes(),
+                    vec![i as u8; VALUE_SIZE],
+                ))
+                .unwrap()
+        })
+        .collect();
+    source.flush().unwrap();
+    locations
+}
+
+/// Merges rows with large values by reading and writing them again, and by copying them as
+/// they are with and without checking their crc
+fn merge_large_values_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge-large-values");
+    group.throughput(Throughput::Bytes((ROWS * VALUE_SIZE) as u64));
+
+    for verify_crc in [true, false] {
+        let source_dir = Builder::new().prefix("source").tempdir().unwrap();
+        let dest_dir = Builder::new().prefix("dest").tempdir().unwrap();
+        let mut source = create_data_storage(&source_dir, verify_crc);
+        let mut dest = create_data_storage(&dest_dir, verify_crc);
+        let locations = write_source_rows(&mut source);
+        let keys = (0..ROWS)
+            .map(|i| format!("key-{:08}", i).into_bytes())
+            .collect::<Vec<Vec<u8>>>();
+
+        let name = if verify_crc { "verify-crc" } else { "skip-crc" };
+        group.bench_function(format!("rewrite-{}", name), |b| {
+            b.iter(|| {
+                for (key, location) in keys.iter().zip(locations.iter()) {
+                    let v = source.read_value(location.row_offset).unwrap().unwrap();
+                    dest.write_row(&RowToWrite::new_with_timestamp(
+                        key,
+                        TimedValue::expirable_value(v.value, v.expire_timestamp),
+                        v.expire_timestamp,
+                    ))
+                    .unwrap();
+                }
+                dest.rewind().unwrap();
+            })
+        });
+        group.bench_function(format!("copy-{}", name), |b| {
+            b.iter(|| {
+                for location in locations.iter() {
+                    source
+                        .copy_row_to(location.row_offset, location.row_size, &mut dest)
+                        .unwrap();
+                }
+                dest.rewind().unwrap();
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default();
+    targets = merge_large_values_benchmark
+}
+
+criterion_main!(benches);
@@ -0,0 +1,145 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    thread,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use bitcasky::bitcasky::{KeyDirBackend, MemoryKeyDirBackend};
+use bitcasky::internals::RowLocation;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use dashmap::DashMap;
+use parking_lot::RwLock;
+use rand::{seq::SliceRandom, thread_rng};
+
+const KEYS: usize = 100_000;
+const READERS: usize = 8;
+const OPERATIONS_PER_THREAD: usize = 10_000;
+
+trait Index: Sync {
+    fn get(&self, key: &[u8]) -> Option<RowLocation>;
+
+    fn put(&self, key: Vec<u8>, location: RowLocation) -> Option<RowLocation>;
+}
+
+impl Index for DashMap<Vec<u8>, RowLocation> {
+    fn get(&self, key: &[u8]) -> Option<RowLocation> {
+        DashMap::get(self, key).map(|r| *r.value())
+    }
+
+    fn put(&self, key: Vec<u8>, location: RowLocation) -> Option<RowLocation> {
+        self.insert(key, location)
+    }
+}
+
+impl Index for RwLock<AHashMap<Vec<u8>, RowLocation>> {
+    fn get(&self, key: &[u8]) -> Option<RowLocation> {
+        self.read().get(key).copied()
+    }
+
+    fn put(&self, key: Vec<u8>, location: RowLocation) -> Option<RowLocation> {
+        self.write().insert(key, location)
+    }
+}
+
+fn location(i: usize) -> RowLocation {
+    RowLocation {
+        storage_id: (i / 1000) as u32,
+        row_offset: i * 64,
+        row_size: 64,
+    }
+}
+
+fn generate_keys() -> Vec<Vec<u8>> {
+    let mut keys = (0..KEYS)
+        .map(|i| format!("key-{:08}", i).into_bytes())
+        .collect::<Vec<_>>();
+    keys.shuffle(&mut thread_rng());
+    keys
+}
+
+fn fill<I: Index>(index: &I, keys: &[Vec<u8>]) {
+    for (i, key) in keys.iter().enumerate() {
+        index.put(key.clone(), location(i));
+    }
+}
+
+/// Runs readers and one writer concurrently for `iters` rounds
+fn run_readers_and_writer<I: Index>(index: &I, keys: &[Vec<u8>], iters: u64) -> Duration {
+    let start = Instant::now();
+    for round in 0..iters as usize {
+        thread::scope(|s| {
+            for reader in 0..READERS {
+                s.spawn(move || {
+                    for i in 0..OPERATIONS_PER_THREAD {
+                        let key = &keys[(reader * OPERATIONS_PER_THREAD + i) % keys.len()];
+                        assert!(index.get(key).is_some());
+                    }
+                });
+            }
+            s.spawn(move || {
+                for i in 0..OPERATIONS_PER_THREAD {
+                    let n = (round * OPERATIONS_PER_THREAD + i) % keys.len();
+                    index.put(keys[n].clone(), location(n));
+                }
+            });
+        });
+    }
+    start.elapsed()
+}
+
+fn keydir_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("keydir-8-readers-1-writer");
+    group.throughput(Throughput::Elements(
+        ((READERS + 1) * OPERATIONS_PER_THREAD) as u64,
+    ));
+    let keys = generate_keys();
+
+    let dashmap = DashMap::new();
+    fill(&dashmap, &keys);
+    group.bench_function("dashmap", |b| {
+        b.iter_custom(|iters| run_readers_and_writer(&dashmap, &keys, iters))
+    });
+
+    let locked_map = RwLock::new(AHashMap::new());
+    fill(&locked_map, &keys);
+    group.bench_function("rwlock-ahashmap", |b| {
+        b.iter_custom(|iters| run_readers_and_writer(&locked_map, &keys, iters))
+    });
+
+    group.finish();
+}
+
+fn get_all<S: BuildHasher + Send + Sync>(backend: &MemoryKeyDirBackend<S>, keys: &[Vec<u8>]) {
+    for key in keys {
+        black_box(backend.get(key));
+    }
+}
+
+fn hasher_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("keydir-get-16-byte-keys");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    let keys = (0..KEYS)
+        .map(|i| (i as u128).to_be_bytes().to_vec())
+        .collect::<Vec<_>>();
+
+    let mut ahash = MemoryKeyDirBackend::<ahash::RandomState>::default();
+    let mut siphash = MemoryKeyDirBackend::with_hasher(RandomState::new());
+    for (i, key) in keys.iter().enumerate() {
+        ahash.put(key.clone(), location(i));
+        siphash.put(key.clone(), location(i));
+    }
+    group.bench_function("ahash", |b| b.iter(|| get_all(&ahash, &keys)));
+    group.bench_function("siphash", |b| b.iter(|| get_all(&siphash, &keys)));
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default();
+    targets = keydir_benchmark, hasher_benchmark
+}
+
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use bitcasky::bitcasky::{Bitcasky, SledKeyDirBackend};
+use bitcasky::options::BitcaskyOptions;
+use criterion::{criterion_group, criterion_main, Criterion};
+use tempfile::{Builder, TempDir};
+
+const KEYS: usize = 1_000_000;
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+}
+
+fn open_with_sled(dir: &Path, keydir_dir: &Path) -> Bitcasky {
+    Bitcasky::open_with_keydir_backend(
+        dir,
+        get_options(),
+        Box::new(SledKeyDirBackend::open(keydir_dir).unwrap()),
+    )
+    .unwrap()
+}
+
+/// Writes keys with sled keydir backend and closes the database, so the keydir can be loaded
+/// from sled on next open
+fn prepare_database() -> (TempDir, PathBuf) {
+    let dir = Builder::new().prefix("keydir_backend").tempdir().unwrap();
+    let keydir_dir = dir.path().join("keydir");
+    let bc = open_with_sled(dir.path(), &keydir_dir);
+    for i in 0..KEYS {
+        bc.put(format!("key-{:08}", i), format!("value-{:08}", i))
+            .unwrap();
+    }
+    drop(bc);
+    (dir, keydir_dir)
+}
+
+fn open_benchmark(c: &mut Criterion) {
+    let (dir, keydir_dir) = prepare_database();
+    let mut group = c.benchmark_group("open-1m-keys");
+    group.sample_size(10);
+
+    group.bench_function("recover-data-files", |b| {
+        b.iter(|| {
+            let bc = Bitcasky::open(dir.path(), get_options()).unwrap();
+            assert_eq!(KEYS, bc.count_keys().unwrap());
+        })
+    });
+
+    group.bench_function("sled-keydir-backend", |b| {
+        b.iter(|| {
+            let bc = open_with_sled(dir.path(), &keydir_dir);
+            assert!(
+                bc.get_telemetry_data()
+                    .keydir
+                    .recovery_stats
+                    .recovered_from_keydir_backend
+            );
+            assert_eq!(KEYS, bc.count_keys().unwrap());
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default();
+    targets = open_benchmark
+}
+
+criterion_main!(benches);
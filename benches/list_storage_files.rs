@@ -0,0 +1,35 @@
+use std::fs::File;
+
+use bitcasky::internals::{get_temporary_directory_path, list_storage_files, FileType};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+const ENTRIES: u32 = 100_000;
+
+fn list_storage_files_benchmark(c: &mut Criterion) {
+    let dir = get_temporary_directory_path();
+    // data files along with their hint files, and a few temporary leftovers
+    for id in 0..ENTRIES / 2 {
+        File::create(FileType::DataFile.get_path(&dir, Some(id))).unwrap();
+        File::create(FileType::HintFile.get_path(&dir, Some(id))).unwrap();
+        if id % 1000 == 0 {
+            File::create(dir.join(format!("tmp-{}.data", id))).unwrap();
+        }
+    }
+
+    let mut group = c.benchmark_group("list_storage_files");
+    group.throughput(Throughput::Elements(ENTRIES as u64));
+    group.sample_size(20);
+    group.bench_function("data_files", |b| {
+        b.iter(|| list_storage_files(&dir, &[FileType::DataFile]).unwrap())
+    });
+    group.bench_function("data_and_erlang_data_files", |b| {
+        b.iter(|| {
+            list_storage_files(&dir, &[FileType::DataFile, FileType::ErlangDataFile]).unwrap()
+        })
+    });
+    group.finish();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, list_storage_files_benchmark);
+criterion_main!(benches);
@@ -3,13 +3,21 @@ use std::{sync::Arc, vec};
 use bitcasky::internals::data_storage::{DataStorage, DataStorageReader, DataStorageWriter};
 use bitcasky::internals::RandomTestingDataGenerator;
 use bitcasky::internals::{BitcaskyFormatter, RowToWrite};
-use bitcasky::options::BitcaskyOptions;
+use bitcasky::options::{BitcaskyOptions, DataSotrageType};
 
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use rand::{seq::SliceRandom, thread_rng};
 use tempfile::{Builder, TempDir};
 
+/// Size of the data files compared by `storage_type_read_row_benchmark`, can be changed by
+/// the `BITCASKY_BENCH_FILE_SIZE` env in bytes
+const COMPARED_FILE_SIZE: usize = 1_073_741_824;
+
 fn create_data_storage(dir: &TempDir) -> DataStorage {
+    create_data_storage_of_type(dir, DataSotrageType::Mmap)
+}
+
+fn create_data_storage_of_type(dir: &TempDir, storage_type: DataSotrageType) -> DataStorage {
     DataStorage::new(
         dir,
         100,
@@ -18,7 +26,7 @@ fn create_data_storage(dir: &TempDir) -> DataStorage {
             BitcaskyOptions::default()
                 .max_data_file_size(usize::MAX)
                 .init_data_file_capacity(100)
-                .storage_type(bitcasky::options::DataSotrageType::Mmap),
+                .storage_type(storage_type),
         ),
     )
     .unwrap()
@@ -173,10 +181,69 @@ fn sequential_read_row_benchmark(c: &mut Criterion) {
     println!("Read {} times", counter,);
 }
 
+/// Compares reading a large sealed data file through file IO and through mmap
+fn storage_type_read_row_benchmark(c: &mut Criterion) {
+    let file_size = std::env::var("BITCASKY_BENCH_FILE_SIZE")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(COMPARED_FILE_SIZE);
+    let key_size = 100;
+    let value_size = 100;
+    let input = RandomTestingDataGenerator::new(key_size, value_size, vec![]).generate_testing_kv();
+
+    let mut group = c.benchmark_group("storage-type-read-row");
+    for storage_type in [DataSotrageType::File, DataSotrageType::Mmap] {
+        let dir = Builder::new().prefix("storage_dir").tempdir().unwrap();
+        let mut data_storage = create_data_storage_of_type(&dir, storage_type);
+        let mut offsets = vec![];
+        let mut written = 0;
+        while written < file_size {
+            let location = data_storage
+                .write_row(&RowToWrite::new(input.key_ref(), input.value()))
+                .unwrap();
+            offsets.push(location.row_offset);
+            written += location.row_size;
+        }
+        data_storage.transit_to_readonly().unwrap();
+        offsets.shuffle(&mut thread_rng());
+
+        let mut counter = 0;
+        group.bench_function(
+            BenchmarkId::new("rand-read-row", format!("{:?}", storage_type)),
+            |b| {
+                b.iter(|| {
+                    let v = data_storage
+                        .read_value(offsets[counter % offsets.len()])
+                        .unwrap();
+                    assert_eq!(input.value(), *v.unwrap().value);
+                    counter += 1;
+                })
+            },
+        );
+
+        data_storage.rewind().unwrap();
+        group.bench_function(
+            BenchmarkId::new("sequential-read-row", format!("{:?}", storage_type)),
+            |b| {
+                b.iter(|| {
+                    if data_storage.read_next_row().unwrap().is_none() {
+                        data_storage.rewind().unwrap();
+                    }
+                })
+            },
+        );
+        println!(
+            "Read {:?} data file of {}",
+            storage_type,
+            format_bytes(written)
+        );
+    }
+    group.finish();
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default();
-    targets = write_row_benchmark, sync_write_row_benchmark, rand_read_row_benchmark, sequential_read_row_benchmark
+    targets = write_row_benchmark, sync_write_row_benchmark, rand_read_row_benchmark, sequential_read_row_benchmark, storage_type_read_row_benchmark
 }
 
 criterion_main!(benches);
@@ -0,0 +1,87 @@
+#!/usr/bin/env python3
+"""Writes a database directory in the file format of the original Erlang bitcask.
+
+It follows the layout in bitcask_fileops.erl and bitcask.erl of Erlang bitcask 2.x, and does
+not share any code with bitcasky. Timestamps are fixed so the output is reproducible.
+
+Usage: generate.py <output dir>
+"""
+
+import os
+import struct
+import sys
+import zlib
+
+TOMBSTONE_V1 = b"bitcask_tombstone"
+TOMBSTONE_V2 = b"bitcask_tombstone2"
+MAX_OFFSET = (1 << 63) - 1
+TIMESTAMP = 1700000000
+
+
+class FilePair:
+    def __init__(self, file_id, with_hint):
+        self.file_id = file_id
+        self.with_hint = with_hint
+        self.data = b""
+        self.hint = b""
+
+    def put(self, key, value, tombstone=False):
+        body = struct.pack(">IHI", TIMESTAMP, len(key), len(value)) + key + value
+        entry = struct.pack(">I", zlib.crc32(body)) + body
+        offset = len(self.data)
+        self.data += entry
+        offset_field = offset | (1 << 63) if tombstone else offset
+        self.hint += struct.pack(">IHIQ", TIMESTAMP, len(key), len(entry), offset_field) + key
+
+    def delete(self, key):
+        # tombstones of version 2 keep the id of the file the deleted value is in
+        self.put(key, TOMBSTONE_V2 + struct.pack(">I", self.file_id), tombstone=True)
+
+    def delete_v1(self, key):
+        self.put(key, TOMBSTONE_V1, tombstone=True)
+
+    def write(self, out_dir):
+        with open(os.path.join(out_dir, "%d.bitcask.data" % self.file_id), "wb") as f:
+            f.write(self.data)
+        if self.with_hint:
+            crc_record = struct.pack(">IHIQ", 0, 0, zlib.crc32(self.hint), MAX_OFFSET)
+            with open(os.path.join(out_dir, "%d.bitcask.hint" % self.file_id), "wb") as f:
+                f.write(self.hint + crc_record)
+
+
+def main(out_dir):
+    os.makedirs(out_dir, exist_ok=True)
+    expected = {}
+
+    f1 = FilePair(1, with_hint=True)
+    for i in range(10):
+        f1.put(b"key-%d" % i, b"value-%d-0" % i)
+        expected["key-%d" % i] = "value-%d-0" % i
+
+    f2 = FilePair(2, with_hint=False)
+    for i in range(5, 8):
+        f2.put(b"key-%d" % i, b"value-%d-1" % i)
+        expected["key-%d" % i] = "value-%d-1" % i
+    f2.delete(b"key-3")
+    expected["key-3"] = None
+
+    f3 = FilePair(3, with_hint=True)
+    for i in range(10, 13):
+        f3.put(b"key-%d" % i, b"value-%d-0" % i)
+        expected["key-%d" % i] = "value-%d-0" % i
+    f3.delete_v1(b"key-5")
+    expected["key-5"] = None
+    f3.put(b"key-8", b"value-8-2")
+    expected["key-8"] = "value-8-2"
+
+    for f in (f1, f2, f3):
+        f.write(out_dir)
+    with open(os.path.join(out_dir, "expected"), "w") as f:
+        for k in sorted(expected):
+            v = expected[k]
+            f.write(k if v is None else "%s\t%s" % (k, v))
+            f.write("\n")
+
+
+if __name__ == "__main__":
+    main(sys.argv[1])
@@ -0,0 +1,138 @@
+//! Generates database directories with the bitcasky in this repository which writes data files
+//! with formatter v2. Same fixtures as the generator of v1 fixtures.
+//!
+//! Usage: `cargo run -- <output directory>`
+//!
+//! Every fixture has an `expected` file along with it. Each line in it is a key, a tab and
+//! the value of the key. A key without value means it's deleted.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bitcasky::{
+    bitcasky::Bitcasky,
+    options::{BitcaskyOptions, SyncStrategy},
+};
+
+struct Fixture {
+    dir: PathBuf,
+    db: Option<Bitcasky>,
+    expected: BTreeMap<String, Option<String>>,
+}
+
+impl Fixture {
+    fn new(base: &Path, name: &str, max_data_file_size: usize) -> Fixture {
+        let dir = base.join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        let options = BitcaskyOptions::default()
+            .max_data_file_size(max_data_file_size)
+            .init_data_file_capacity(max_data_file_size)
+            .init_hint_file_capacity(1024)
+            .sync_strategy(SyncStrategy::None);
+        let db = Bitcasky::open(&dir, options).unwrap();
+        Fixture {
+            dir,
+            db: Some(db),
+            expected: BTreeMap::new(),
+        }
+    }
+
+    fn db(&self) -> &Bitcasky {
+        self.db.as_ref().unwrap()
+    }
+
+    fn put(&mut self, key: String, value: String) {
+        self.db().put(key.clone(), &value).unwrap();
+        self.expected.insert(key, Some(value));
+    }
+
+    fn delete(&mut self, key: String) {
+        self.db().delete(&key).unwrap();
+        self.expected.insert(key, None);
+    }
+
+    fn put_values(&mut self, round: usize, keys: std::ops::Range<usize>) {
+        for i in keys {
+            self.put(format!("key-{}", i), format!("value-{}-{}", i, round));
+        }
+    }
+
+    fn close(mut self) -> PathBuf {
+        self.db().sync().unwrap();
+        drop(self.db.take());
+        // wait hint files written by background thread
+        std::thread::sleep(Duration::from_millis(500));
+        let tmp_hint_dir = self.dir.join("TmpHint");
+        if tmp_hint_dir.exists() {
+            fs::remove_dir_all(tmp_hint_dir).unwrap();
+        }
+
+        let mut f = fs::File::create(self.dir.join("expected")).unwrap();
+        for (k, v) in self.expected.iter() {
+            match v {
+                Some(v) => writeln!(f, "{}\t{}", k, v).unwrap(),
+                None => writeln!(f, "{}", k).unwrap(),
+            }
+        }
+        self.dir
+    }
+}
+
+fn remove_files_with_extension(dir: &Path, extension: &str) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map(|e| e == extension).unwrap_or(false) {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+fn main() {
+    let base = PathBuf::from(
+        std::env::args()
+            .nth(1)
+            .expect("usage: compat-fixtures-generator <output directory>"),
+    );
+    fs::create_dir_all(&base).unwrap();
+
+    // stable data files without hint files
+    let mut f = Fixture::new(&base, "no_hints", 256);
+    f.put_values(0, 0..20);
+    f.delete("key-3".into());
+    f.put_values(1, 5..10);
+    let dir = f.close();
+    remove_files_with_extension(&dir, "hint");
+
+    // stable data files with hint files
+    let mut f = Fixture::new(&base, "hints", 256);
+    f.put_values(0, 0..20);
+    f.delete("key-3".into());
+    f.put_values(1, 5..10);
+    f.close();
+
+    // merged data files and some data files written after merge
+    let mut f = Fixture::new(&base, "merged", 256);
+    f.put_values(0, 0..20);
+    for i in 0..5 {
+        f.delete(format!("key-{}", i));
+    }
+    f.put_values(1, 10..15);
+    f.db().merge().unwrap();
+    f.put_values(2, 12..25);
+    f.delete("key-20".into());
+    f.close();
+
+    // all the data are in the writing file which is not flushed to a stable file
+    let mut f = Fixture::new(&base, "writing_file", 4096);
+    f.put_values(0, 0..10);
+    f.delete("key-3".into());
+    f.put_values(1, 5..8);
+    f.close();
+}
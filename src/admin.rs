@@ -0,0 +1,236 @@
+//! Admin operations over a [`Maintenance`] trait for scripting the maintenance of an embedded
+//! database.
+//!
+//! Every operation returns a report which can be serialized with the `serde` feature. Operations
+//! are safe to call repeatedly and concurrently. When the same operation is already running,
+//! [`MaintenanceOutcome::AlreadyRunning`] is returned instead of an error. `merge_if_needed`,
+//! `drop_dead_files` and `compact_small_files` all merge data files, so they also return
+//! `AlreadyRunning` while any of them or any other merge is running. `rebuild_hint_files` and
+//! `quiesce` both write hint files, so they do not run at the same time either.
+//!
+//! `Bitcasky` has inherent functions named `verify` and `rebuild_hint_files` too, call these
+//! operations like `Maintenance::verify(&db)` to tell them apart.
+//!
+//! [`DoctorReport`] gathers options, files, telemetry and health of a database in one report to
+//! attach to bug reports.
+
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
+
+use parking_lot::Mutex;
+
+use crate::{
+    bitcasky::BitcaskTelemetry, database::VerifyReport, error::BitcaskyResult,
+    mutation_trace::KeyMutation, options::BitcaskyOptions, storage_id::StorageId,
+};
+
+/// Dead bytes ratio for `merge_if_needed` when `auto_merge_threshold` is not set in options
+pub const DEFAULT_MERGE_THRESHOLD: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MaintenanceOperation {
+    Rotate,
+    MergeIfNeeded,
+    RebuildHintFiles,
+    Verify,
+    DropDeadFiles,
+    CompactSmallFiles,
+    Quiesce,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MaintenanceOutcome<R> {
+    Completed(R),
+    /// The operation was skipped because another run of it is in progress
+    AlreadyRunning,
+}
+
+impl<R> MaintenanceOutcome<R> {
+    pub fn is_completed(&self) -> bool {
+        matches!(self, MaintenanceOutcome::Completed(_))
+    }
+
+    /// Returns the report if the operation completed
+    pub fn report(self) -> Option<R> {
+        match self {
+            MaintenanceOutcome::Completed(r) => Some(r),
+            MaintenanceOutcome::AlreadyRunning => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RotateReport {
+    /// Storage id of the writing file turned into a stable file. None if nothing was written to it
+    pub rotated_storage_id: Option<StorageId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MergeIfNeededReport {
+    pub threshold: f64,
+    /// Ratio of dead bytes to all the bytes in data files before merge
+    pub dead_bytes_ratio: f64,
+    pub merged: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RebuildHintFilesReport {
+    pub rebuilt_hint_files: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DropDeadFilesReport {
+    /// Stable data files without any live value which were removed
+    pub dropped_storage_ids: Vec<StorageId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompactSmallFilesReport {
+    /// Small stable data files which were rewritten into fewer data files
+    pub compacted_storage_ids: Vec<StorageId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QuiesceReport {
+    pub rebuilt_hint_files: usize,
+    pub writing_storage_id: StorageId,
+    /// Offset in the writing file before which all the rows are on disk
+    pub synced_offset: u64,
+}
+
+/// How much [`Bitcasky::doctor_report`](crate::bitcasky::Bitcasky::doctor_report) checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DoctorDepth {
+    /// Only metadata, every lock is held briefly so it's fine to run on a busy database
+    Quick,
+    /// Also scans keydir and verifies checksum of every row in data files
+    Deep,
+}
+
+/// A file under the database directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DoctorFile {
+    pub name: String,
+    pub size: u64,
+    /// Formatter version in the header of a data file opened by the database
+    pub formatter_version: Option<u8>,
+}
+
+/// Lengths of keys in keydir, keys themselves are never reported
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyLengthStats {
+    pub keys: usize,
+    pub total_bytes: u64,
+    pub max_length: usize,
+}
+
+/// Everything needed to look into a database for a bug report. Serialize it to JSON with the
+/// `serde` feature. Contents of keys and values are never included.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DoctorReport {
+    pub depth: DoctorDepth,
+    pub directory: PathBuf,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_options"))]
+    pub options: Arc<BitcaskyOptions>,
+    /// Files under the directory sorted by name
+    pub files: Vec<DoctorFile>,
+    /// Content of the lock file, pid of the holder process followed by its instance id
+    pub lock_holder: Option<String>,
+    /// Error which turned the database broken, if any
+    pub database_error: Option<String>,
+    pub read_only: bool,
+    /// Includes recovery stats, fragmentation and counters. Amplification ratios are only
+    /// computed in deep mode as they take a scan of keydir
+    pub telemetry: BitcaskTelemetry,
+    /// Only in deep mode
+    pub key_lengths: Option<KeyLengthStats>,
+    /// Only in deep mode
+    pub verify: Option<VerifyReport>,
+    /// Latest keydir mutations, only when `BitcaskyOptions::mutation_trace` is enabled
+    pub mutation_trace: Option<Vec<KeyMutation>>,
+}
+
+#[cfg(feature = "serde")]
+fn serialize_options<S: serde::Serializer>(
+    options: &Arc<BitcaskyOptions>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(options.as_ref(), serializer)
+}
+
+pub trait Maintenance {
+    /// Turns the writing file into a stable file and starts a new writing file
+    fn rotate(&self) -> BitcaskyResult<MaintenanceOutcome<RotateReport>>;
+
+    /// Merges all data files when dead bytes ratio exceeds `auto_merge_threshold` in options,
+    /// or [`DEFAULT_MERGE_THRESHOLD`] if it's not set
+    fn merge_if_needed(&self) -> BitcaskyResult<MaintenanceOutcome<MergeIfNeededReport>>;
+
+    /// Writes hint files for stable data files without a valid hint file before return
+    fn rebuild_hint_files(&self) -> BitcaskyResult<MaintenanceOutcome<RebuildHintFilesReport>>;
+
+    /// Verifies checksum of every row in all the data files
+    fn verify(&self) -> BitcaskyResult<MaintenanceOutcome<VerifyReport>>;
+
+    /// Removes stable data files which have no live value. Tombstones in them still needed
+    /// to cover older data files are kept in a new data file.
+    fn drop_dead_files(&self) -> BitcaskyResult<MaintenanceOutcome<DropDeadFilesReport>>;
+
+    /// Rewrites stable data files smaller than half of `max_data_file_size` into fewer files
+    fn compact_small_files(&self) -> BitcaskyResult<MaintenanceOutcome<CompactSmallFilesReport>>;
+
+    /// Flushes the writing file and writes all the missing hint files, so nothing is left to
+    /// recover from data files for the stable files
+    fn quiesce(&self) -> BitcaskyResult<MaintenanceOutcome<QuiesceReport>>;
+}
+
+/// Operations in progress, used to run each operation one at a time
+#[derive(Debug, Default)]
+pub(crate) struct RunningOperations {
+    running: Mutex<HashSet<MaintenanceOperation>>,
+}
+
+impl RunningOperations {
+    /// Returns None if the operation, or another operation excluding it, is running
+    pub fn try_start(&self, operation: MaintenanceOperation) -> Option<RunningOperationGuard<'_>> {
+        // operations merging data files pick files to merge before the merge starts, so
+        // they must not run at the same time, otherwise the picked files may be gone
+        let operation = match operation {
+            MaintenanceOperation::DropDeadFiles | MaintenanceOperation::CompactSmallFiles => {
+                MaintenanceOperation::MergeIfNeeded
+            }
+            // both write hint files
+            MaintenanceOperation::Quiesce => MaintenanceOperation::RebuildHintFiles,
+            op => op,
+        };
+        if !self.running.lock().insert(operation) {
+            return None;
+        }
+        Some(RunningOperationGuard {
+            operations: self,
+            operation,
+        })
+    }
+}
+
+pub(crate) struct RunningOperationGuard<'a> {
+    operations: &'a RunningOperations,
+    operation: MaintenanceOperation,
+}
+
+impl Drop for RunningOperationGuard<'_> {
+    fn drop(&mut self) {
+        self.operations.running.lock().remove(&self.operation);
+    }
+}
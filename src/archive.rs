@@ -0,0 +1,297 @@
+//! Portable archive of live keys and values written by `Bitcasky::export` and read by
+//! `Bitcasky::import`. It does not depend on the layout of data files, so it can be imported
+//! by a database with other options or a later version of bitcasky.
+//!
+//! Integers are in big endian:
+//!
+//! ```text
+//! header: magic "BCKYARCH" | version: u8
+//! entry:  tag 1: u8 | key size: u32 | value size: u32 | write timestamp: u64
+//!         | expire timestamp: u64 | key | value
+//! footer: tag 0: u8 | entry count: u64 | CRC-32C of all the bytes before it: u32
+//! ```
+
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::{BitcaskyError, BitcaskyResult};
+
+const MAGIC: &[u8; 8] = b"BCKYARCH";
+/// Version of archives written. Archives of any version up to it can be read
+const ARCHIVE_VERSION: u8 = 1;
+const ENTRY_TAG: u8 = 1;
+const FOOTER_TAG: u8 = 0;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ArchiveEntry {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub write_timestamp: u64,
+    pub expire_timestamp: u64,
+}
+
+/// Writes bytes to the inner writer along with their checksum
+pub(crate) struct ChecksumWriter<W: Write> {
+    pub inner: W,
+    pub crc: u32,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc32c::crc32c_append(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads bytes from the inner reader along with their checksum
+pub(crate) struct ChecksumReader<R: Read> {
+    pub inner: R,
+    pub crc: u32,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc32c::crc32c_append(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
+pub(crate) struct ArchiveWriter<W: Write> {
+    writer: ChecksumWriter<W>,
+    entries: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(writer: W) -> BitcaskyResult<ArchiveWriter<W>> {
+        let mut writer = ChecksumWriter {
+            inner: writer,
+            crc: 0,
+        };
+        writer.write_all(MAGIC)?;
+        writer.write_u8(ARCHIVE_VERSION)?;
+        Ok(ArchiveWriter { writer, entries: 0 })
+    }
+
+    pub fn write_entry(&mut self, entry: &ArchiveEntry) -> BitcaskyResult<()> {
+        let w = &mut self.writer;
+        w.write_u8(ENTRY_TAG)?;
+        w.write_u32::<BigEndian>(entry.key.len() as u32)?;
+        w.write_u32::<BigEndian>(entry.value.len() as u32)?;
+        w.write_u64::<BigEndian>(entry.write_timestamp)?;
+        w.write_u64::<BigEndian>(entry.expire_timestamp)?;
+        w.write_all(&entry.key)?;
+        w.write_all(&entry.value)?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Writes the footer and flushes the writer, returns how many entries are written
+    pub fn finish(mut self) -> BitcaskyResult<u64> {
+        self.writer.write_u8(FOOTER_TAG)?;
+        self.writer.write_u64::<BigEndian>(self.entries)?;
+        let crc = self.writer.crc;
+        self.writer.write_u32::<BigEndian>(crc)?;
+        self.writer.flush()?;
+        Ok(self.entries)
+    }
+}
+
+pub(crate) struct ArchiveReader<R: Read> {
+    reader: ChecksumReader<R>,
+    max_key_size: usize,
+    max_value_size: usize,
+    entries: u64,
+    finished: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    /// Reads the header. Entries with key or value larger than the max sizes are rejected before
+    /// they are read
+    pub fn new(
+        reader: R,
+        max_key_size: usize,
+        max_value_size: usize,
+    ) -> BitcaskyResult<ArchiveReader<R>> {
+        let mut reader = ChecksumReader {
+            inner: reader,
+            crc: 0,
+        };
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != MAGIC {
+            return Err(invalid_archive("not an archive of bitcasky"));
+        }
+        let version = reader.read_u8().map_err(truncated)?;
+        if version == 0 || version > ARCHIVE_VERSION {
+            return Err(invalid_archive(format!(
+                "unsupported archive version: {}",
+                version
+            )));
+        }
+        Ok(ArchiveReader {
+            reader,
+            max_key_size,
+            max_value_size,
+            entries: 0,
+            finished: false,
+        })
+    }
+
+    /// Returns the next entry, or None after the footer is read and the checksum is verified
+    pub fn next_entry(&mut self) -> BitcaskyResult<Option<ArchiveEntry>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let r = &mut self.reader;
+        match r.read_u8().map_err(truncated)? {
+            ENTRY_TAG => {}
+            FOOTER_TAG => {
+                self.read_footer()?;
+                return Ok(None);
+            }
+            tag => return Err(invalid_archive(format!("unknown tag: {}", tag))),
+        }
+        let key_size = r.read_u32::<BigEndian>().map_err(truncated)? as usize;
+        let value_size = r.read_u32::<BigEndian>().map_err(truncated)? as usize;
+        if key_size > self.max_key_size || value_size > self.max_value_size {
+            return Err(invalid_archive(format!(
+                "entry with key size: {} and value size: {} exceeds max size",
+                key_size, value_size
+            )));
+        }
+        let write_timestamp = r.read_u64::<BigEndian>().map_err(truncated)?;
+        let expire_timestamp = r.read_u64::<BigEndian>().map_err(truncated)?;
+        let mut key = vec![0; key_size];
+        r.read_exact(&mut key).map_err(truncated)?;
+        let mut value = vec![0; value_size];
+        r.read_exact(&mut value).map_err(truncated)?;
+        self.entries += 1;
+        Ok(Some(ArchiveEntry {
+            key,
+            value,
+            write_timestamp,
+            expire_timestamp,
+        }))
+    }
+
+    fn read_footer(&mut self) -> BitcaskyResult<()> {
+        let entries = self.reader.read_u64::<BigEndian>().map_err(truncated)?;
+        let actual_crc = self.reader.crc;
+        let expected_crc = self.reader.read_u32::<BigEndian>().map_err(truncated)?;
+        if entries != self.entries {
+            return Err(invalid_archive(format!(
+                "expect {} entries, but read {}",
+                entries, self.entries
+            )));
+        }
+        if expected_crc != actual_crc {
+            return Err(invalid_archive(format!(
+                "checksum mismatch, expect: {}, actual: {}",
+                expected_crc, actual_crc
+            )));
+        }
+        self.finished = true;
+        Ok(())
+    }
+}
+
+fn invalid_archive<S: Into<String>>(reason: S) -> BitcaskyError {
+    BitcaskyError::InvalidArchive(reason.into())
+}
+
+fn truncated(e: io::Error) -> BitcaskyError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        return invalid_archive("archive ends unexpectedly");
+    }
+    BitcaskyError::IoError(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn entry(i: u64) -> ArchiveEntry {
+        ArchiveEntry {
+            key: format!("k{}", i).into_bytes(),
+            value: vec![i as u8; i as usize],
+            write_timestamp: 1000 + i,
+            expire_timestamp: i * 10,
+        }
+    }
+
+    fn write_archive(count: u64) -> Vec<u8> {
+        let mut buf = vec![];
+        let mut writer = ArchiveWriter::new(&mut buf).unwrap();
+        for i in 0..count {
+            writer.write_entry(&entry(i)).unwrap();
+        }
+        assert_eq!(count, writer.finish().unwrap());
+        buf
+    }
+
+    fn read_archive(buf: &[u8]) -> BitcaskyResult<Vec<ArchiveEntry>> {
+        let mut reader = ArchiveReader::new(buf, 1024, 1024)?;
+        let mut entries = vec![];
+        while let Some(e) = reader.next_entry()? {
+            entries.push(e);
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let buf = write_archive(10);
+        assert_eq!(
+            (0..10).map(entry).collect::<Vec<ArchiveEntry>>(),
+            read_archive(&buf).unwrap()
+        );
+        assert!(read_archive(&write_archive(0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_invalid_archive() {
+        let buf = write_archive(3);
+
+        let mut corrupted = buf.clone();
+        corrupted[20] ^= 1;
+        assert!(matches!(
+            read_archive(&corrupted),
+            Err(BitcaskyError::InvalidArchive(_))
+        ));
+
+        assert!(matches!(
+            read_archive(&buf[..buf.len() - 1]),
+            Err(BitcaskyError::InvalidArchive(reason)) if reason.contains("ends unexpectedly")
+        ));
+
+        let mut unknown_version = buf.clone();
+        unknown_version[MAGIC.len()] = ARCHIVE_VERSION + 1;
+        assert!(matches!(
+            read_archive(&unknown_version),
+            Err(BitcaskyError::InvalidArchive(reason)) if reason.contains("version")
+        ));
+
+        assert!(matches!(
+            read_archive(b"not an archive"),
+            Err(BitcaskyError::InvalidArchive(_))
+        ));
+
+        // values of the first two entries are no larger than 1 byte
+        let mut reader = ArchiveReader::new(buf.as_slice(), 1024, 1).unwrap();
+        reader.next_entry().unwrap();
+        reader.next_entry().unwrap();
+        assert!(matches!(
+            reader.next_entry(),
+            Err(BitcaskyError::InvalidArchive(reason)) if reason.contains("max size")
+        ));
+    }
+}
@@ -0,0 +1,188 @@
+//! Async facade of [`Bitcasky`] for tokio, only available with the `tokio` feature.
+//!
+//! Every operation runs on the blocking pool of the current tokio runtime, so it never blocks
+//! the async worker threads.
+//!
+//! Dropping the future of an operation does not cancel it once it started on the blocking pool.
+//! The operation runs to the end with the keys and values it owns, so a write is either done
+//! or not started, and no lock is left held. A dropped `put` may still be visible afterwards.
+//!
+//! Writes waiting for the blocking pool are limited by `max_pending_writes`. Once the limit is
+//! reached, more writes wait asynchronously until earlier ones finish, so a burst of writes can
+//! not pile up unbounded tasks on the blocking pool. A write dropped while waiting is never
+//! started.
+
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinError,
+};
+
+use crate::{
+    bitcasky::{Bitcasky, MergeReport, PutResult},
+    error::{BitcaskyError, BitcaskyResult},
+    options::BitcaskyOptions,
+};
+
+/// Writes allowed to wait for or run on the blocking pool at the same time by default
+pub const DEFAULT_MAX_PENDING_WRITES: usize = 256;
+
+/// Key value pairs buffered by `ScanStream` ahead of its consumer
+const SCAN_BUFFER_SIZE: usize = 64;
+
+/// Async facade of `Bitcasky`. Cloning it is cheap, clones share the same database.
+#[derive(Clone)]
+pub struct AsyncBitcasky {
+    inner: Arc<Bitcasky>,
+    write_permits: Arc<Semaphore>,
+}
+
+impl AsyncBitcasky {
+    /// Opens the database on the blocking pool
+    pub async fn open(
+        directory: impl Into<PathBuf>,
+        options: BitcaskyOptions,
+    ) -> BitcaskyResult<AsyncBitcasky> {
+        let directory = directory.into();
+        let bc = run_blocking(move || Bitcasky::open(&directory, options)).await?;
+        Ok(AsyncBitcasky::new(bc))
+    }
+
+    /// Wraps an opened database, allowing `DEFAULT_MAX_PENDING_WRITES` pending writes
+    pub fn new(bitcasky: Bitcasky) -> AsyncBitcasky {
+        AsyncBitcasky::with_max_pending_writes(bitcasky, DEFAULT_MAX_PENDING_WRITES)
+    }
+
+    pub fn with_max_pending_writes(bitcasky: Bitcasky, max_pending_writes: usize) -> AsyncBitcasky {
+        assert!(
+            max_pending_writes > 0,
+            "max_pending_writes must be positive"
+        );
+        AsyncBitcasky {
+            inner: Arc::new(bitcasky),
+            write_permits: Arc::new(Semaphore::new(max_pending_writes)),
+        }
+    }
+
+    /// The wrapped database, to call operations not provided here in blocking context
+    pub fn blocking(&self) -> &Arc<Bitcasky> {
+        &self.inner
+    }
+
+    pub async fn get<K: Into<Vec<u8>>>(&self, key: K) -> BitcaskyResult<Option<Vec<u8>>> {
+        let key = key.into();
+        let bc = self.inner.clone();
+        run_blocking(move || bc.get(key)).await
+    }
+
+    pub async fn put<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> BitcaskyResult<()> {
+        let (key, value) = (key.into(), value.into());
+        self.write(move |bc| bc.put(key, value)).await
+    }
+
+    /// Puts the pairs under one hold of the keydir lock like `Bitcasky::put_multi`, which
+    /// takes one trip to the blocking pool for all of them
+    pub async fn put_multi(
+        &self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> BitcaskyResult<Vec<PutResult>> {
+        self.write(move |bc| bc.put_multi(pairs)).await
+    }
+
+    /// Returns true if the key was present
+    pub async fn delete<K: Into<Vec<u8>>>(&self, key: K) -> BitcaskyResult<bool> {
+        let key = key.into();
+        self.write(move |bc| bc.delete(key)).await
+    }
+
+    pub async fn sync(&self) -> BitcaskyResult<()> {
+        let bc = self.inner.clone();
+        run_blocking(move || bc.sync()).await
+    }
+
+    /// Merges all data files like `Bitcasky::merge`. It's not cancelled when the future is
+    /// dropped, use `Bitcasky::merge_cancellable` through `blocking` for that.
+    pub async fn merge(&self) -> BitcaskyResult<MergeReport> {
+        let bc = self.inner.clone();
+        run_blocking(move || bc.merge()).await
+    }
+
+    /// Streams all the key value pairs of a snapshot taken when this is called. Pairs are read
+    /// on the blocking pool ahead of the consumer up to a small buffer, and reading stops once
+    /// the stream is dropped.
+    pub async fn scan(&self) -> BitcaskyResult<ScanStream> {
+        let bc = self.inner.clone();
+        let snapshot = run_blocking(move || bc.snapshot()).await?;
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER_SIZE);
+        tokio::task::spawn_blocking(move || {
+            for pair in snapshot.iter() {
+                let failed = pair.is_err();
+                if sender.blocking_send(pair).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(ScanStream { receiver })
+    }
+
+    async fn write<T, F>(&self, f: F) -> BitcaskyResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Bitcasky) -> BitcaskyResult<T> + Send + 'static,
+    {
+        let permit = self
+            .write_permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("write permits are never closed");
+        let bc = self.inner.clone();
+        run_blocking(move || {
+            let _permit = permit;
+            f(&bc)
+        })
+        .await
+    }
+}
+
+/// Stream of key value pairs returned by `AsyncBitcasky::scan`. It ends after the first error.
+pub struct ScanStream {
+    receiver: mpsc::Receiver<BitcaskyResult<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl Stream for ScanStream {
+    type Item = BitcaskyResult<(Vec<u8>, Vec<u8>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+async fn run_blocking<T, F>(f: F) -> BitcaskyResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> BitcaskyResult<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(join_error(e)))
+}
+
+fn join_error(e: JoinError) -> BitcaskyError {
+    match e.try_into_panic() {
+        Ok(panic) => std::panic::resume_unwind(panic),
+        // the runtime is shutting down before the task started
+        Err(e) => BitcaskyError::IoError(std::io::Error::new(std::io::ErrorKind::Interrupted, e)),
+    }
+}
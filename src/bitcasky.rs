@@ -1,65 +1,194 @@
+use std::collections::HashMap;
 use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::Path;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
-use crate::options::BitcaskyOptions;
-use log::{debug, error};
-use parking_lot::RwLock;
+use crate::admin::{
+    CompactSmallFilesReport, DoctorDepth, DoctorFile, DoctorReport, DropDeadFilesReport,
+    KeyLengthStats, Maintenance, MaintenanceOperation, MaintenanceOutcome, MergeIfNeededReport,
+    QuiesceReport, RebuildHintFilesReport, RotateReport, RunningOperations,
+    DEFAULT_MERGE_THRESHOLD,
+};
+use crate::clock::Clock;
+#[cfg(feature = "instrument-locks")]
+pub use crate::lock_stats::LockStats;
+use crate::lock_stats::{LockTimer, TimedRwLock};
+use crate::logging::{debug, error, info, warn, OperationSpan};
+use crate::options::{BitcaskyOptions, CorruptionPolicy};
+use parking_lot::Mutex;
 use uuid::Uuid;
 
-use crate::database::{deleted_value, Database, DatabaseTelemetry, TimedValue};
+use crate::archive::{ArchiveEntry, ArchiveReader, ArchiveWriter};
+use crate::bucket::bucket_prefix;
+use crate::csv;
+use crate::database::{
+    self, deleted_value, DataStorageError, Database, DatabaseError, DatabaseTelemetry, TimedValue,
+};
 use crate::error::{BitcaskyError, BitcaskyResult};
+use crate::expiry::ExpirySweeper;
+#[cfg(feature = "sled")]
+pub use crate::keydir::SledKeyDirBackend;
 use crate::keydir::{KeyDir, KeyDirTelemetry};
-use crate::merge::{MergeManager, MergeManagerTelemetry};
+pub use crate::keydir::{
+    KeyDirBackend, LocationInvalidation, MemoryKeyDirBackend, SnapshotKeyDirBackend,
+};
+use crate::maintenance::MaintenancePoolTelemetry;
+use crate::merge::{AutoMergeWorker, MergeManager, MergeManagerTelemetry};
+pub use crate::mutation_trace::{key_hash, KeyMutation, MutationCause};
+
+pub use crate::bloom::BloomFilterStats;
+pub use crate::bucket::{Bucket, BucketStats, Namespace};
+pub use crate::database::{
+    DataFileReport, DurabilityState, HintFileReport, IntegrityReport, KeyCountEstimate,
+    RecoveryProgress, RepairReport, RowLocation, ValueReader, VerifyReport,
+};
+pub use crate::expiry::ExpirySweeperTelemetry;
+pub use crate::merge::{
+    AutoMergeHandle, MergeCancelToken, MergeEstimate, MergeHandle, MergeReport,
+};
+pub use crate::scan::{KeyLocations, ScanIter};
+pub use crate::snapshot::{Snapshot, SnapshotIter};
+pub use crate::storage_id::StorageId;
 use crate::{
     fs::{self},
     storage_id::StorageIdGenerator,
 };
 
+/// Number of keys deleted under one hold of the keydir lock by `delete_prefix` and `delete_all`
+const DELETE_CHUNK_SIZE: usize = 1024;
+
+/// Outcome of one pair put by `Bitcasky::put_multi`, where its row is written on success
+pub type PutResult = BitcaskyResult<RowLocation>;
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BitcaskTelemetry {
     pub keydir: KeyDirTelemetry,
     pub database: DatabaseTelemetry,
     pub merge_manager: MergeManagerTelemetry,
+    pub maintenance_pool: MaintenancePoolTelemetry,
+    pub expiry_sweeper: ExpirySweeperTelemetry,
+}
+
+/// Value of a key along with its metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Vec<u8>,
+    /// Milliseconds since epoch when the value is written, 0 if written by a formatter not
+    /// keeping it
+    pub write_timestamp: u64,
+    /// Milliseconds since epoch when the value expires, 0 if it never expires
+    pub expire_timestamp: u64,
+    /// Where the row of the value is stored. It changes when the key is overwritten or the row
+    /// is moved by merge
+    pub location: RowLocation,
 }
 
 pub struct Bitcasky {
     instance_id: String,
     _directory_lock_file: File,
-    keydir: RwLock<KeyDir>,
+    keydir: Arc<TimedRwLock<KeyDir>>,
     options: Arc<BitcaskyOptions>,
-    database: Database,
-    merge_manager: MergeManager,
+    database: Arc<Database>,
+    merge_manager: Arc<MergeManager>,
+    auto_merge_worker: Option<AutoMergeWorker>,
+    expiry_sweeper: ExpirySweeper,
+    running_operations: RunningOperations,
 }
 
 impl Bitcasky {
     /// Open opens the database at the given path with optional options.
     pub fn open(directory: &Path, options: BitcaskyOptions) -> BitcaskyResult<Bitcasky> {
-        let _directory_lock_file = match fs::lock_directory(directory)? {
-            Some(f) => f,
-            None => {
-                return Err(BitcaskyError::LockDirectoryFailed(
-                    directory.display().to_string(),
-                ));
-            }
+        let keydir_backend: Box<dyn KeyDirBackend> = if options.keydir_snapshot {
+            Box::new(SnapshotKeyDirBackend::open(directory)?)
+        } else {
+            Box::<MemoryKeyDirBackend>::default()
         };
+        Bitcasky::open_with_keydir_backend(directory, options, keydir_backend)
+    }
 
+    /// Opens the database with keys kept by the keydir backend. A persistent backend keeps keys
+    /// across restarts, so data files are not recovered on open if the database was closed
+    /// gracefully and nothing changed its data files since then.
+    pub fn open_with_keydir_backend(
+        directory: &Path,
+        mut options: BitcaskyOptions,
+        keydir_backend: Box<dyn KeyDirBackend>,
+    ) -> BitcaskyResult<Bitcasky> {
+        let id = Uuid::new_v4();
+        let _directory_lock_file =
+            match fs::lock_directory(directory, &id.to_string(), options.lock_wait_timeout)? {
+                Some(f) => f,
+                None => {
+                    return Err(BitcaskyError::LockDirectoryFailed(
+                        directory.display().to_string(),
+                    ));
+                }
+            };
+
+        options.validate()?;
         validate_database_directory(directory)?;
 
+        // data storage enforces the same size limits in case rows are written without Bitcasky
+        options.database.storage.max_key_size = options.max_key_size;
+        options.database.storage.max_value_size = options.max_value_size;
+        if options.validate_on_open && options.corruption_policy == CorruptionPolicy::SkipCorrupted
+        {
+            options.database.storage.skip_corrupted = true;
+        }
         let options = Arc::new(options);
-        let id = Uuid::new_v4();
         let storage_id_generator = Arc::new(StorageIdGenerator::default());
-        let merge_manager = MergeManager::new(
+        let merge_manager = Arc::new(MergeManager::new(
             id.to_string(),
             directory,
             storage_id_generator.clone(),
             options.clone(),
-        );
+        ));
         merge_manager.recover_merge()?;
 
-        let database = Database::open(directory, storage_id_generator, options.clone())?;
-        let keydir = RwLock::new(KeyDir::new(&database)?);
+        if options.validate_on_open {
+            let report = database::validate(directory, options.clone())?;
+            if let (CorruptionPolicy::FailFast, Some((storage_id, offset))) =
+                (options.corruption_policy, report.bad_locations.first())
+            {
+                return Err(BitcaskyError::DataFileCorrupted(*storage_id, *offset));
+            }
+        }
+
+        let database = Arc::new(
+            Database::open(directory, storage_id_generator, options.clone()).map_err(
+                |e| match e {
+                    DatabaseError::StorageError(DataStorageError::IncompleteDataFile(id)) => {
+                        BitcaskyError::IncompleteDataFile(id)
+                    }
+                    e => e.into(),
+                },
+            )?,
+        );
+        let mut keydir = KeyDir::open(&database, keydir_backend, options.initial_keydir_capacity)?;
+        if let Some(bloom_filter) = options.bloom_filter.as_ref() {
+            keydir.enable_bloom_filter(bloom_filter);
+        }
+        if let Some(capacity) = options.mutation_trace_capacity {
+            keydir.enable_mutation_trace(capacity, options.clone());
+        }
+        database.reset_dead_bytes(&keydir.live_bytes());
+        let keydir = Arc::new(TimedRwLock::new(keydir, LockTimer::default()));
+
+        let auto_merge_worker = options.auto_merge_threshold.map(|threshold| {
+            merge_manager.start_auto_merge(
+                database.clone(),
+                keydir.clone(),
+                threshold,
+                options.auto_merge_check_interval,
+            )
+        });
+        let mut expiry_sweeper = ExpirySweeper::new(options.clone());
+        if let Some(interval) = options.expiry_sweep_interval {
+            expiry_sweeper.start(&database, &keydir, interval);
+        }
 
         debug!(target: "Bitcasky", "Bitcask created. instanceId: {}", id);
         Ok(Bitcasky {
@@ -69,16 +198,198 @@ impl Bitcasky {
             database,
             options,
             merge_manager,
+            auto_merge_worker,
+            expiry_sweeper,
+            running_operations: RunningOperations::default(),
         })
     }
 
+    /// Checks data files and hint files under the directory without opening the database.
+    /// It only reads files so it can run while the directory is used by another process,
+    /// but data being written at the same time may be reported as a torn write.
+    pub fn check_integrity(
+        directory: &Path,
+        options: BitcaskyOptions,
+    ) -> BitcaskyResult<IntegrityReport> {
+        Ok(database::check_integrity(directory, Arc::new(options))?)
+    }
+
+    /// Truncates torn writes at the end of data files, rewrites data files with corrupted rows
+    /// in the middle keeping only valid rows, and deletes hint files without data file.
+    /// It does not open the database, so it can fix directories failed to open.
+    /// The directory must not be used by other process.
+    pub fn repair(directory: &Path, options: BitcaskyOptions) -> BitcaskyResult<RepairReport> {
+        let _directory_lock_file =
+            match fs::lock_directory(directory, "repair", options.lock_wait_timeout)? {
+                Some(f) => f,
+                None => {
+                    return Err(BitcaskyError::LockDirectoryFailed(
+                        directory.display().to_string(),
+                    ));
+                }
+            };
+
+        Ok(database::repair(directory, Arc::new(options))?)
+    }
+
+    /// Verifies checksum of every row in all the data files of this opened database.
+    /// Unlike `check_integrity`, it keeps going after corrupted rows and reports all of them.
+    pub fn verify(&self) -> BitcaskyResult<VerifyReport> {
+        self.database.check_db_error()?;
+        Ok(self.database.verify()?)
+    }
+
+    /// Gathers options, files under the directory, lock holder, health and telemetry of the
+    /// database for a bug report. `DoctorDepth::Deep` also scans key lengths and runs `verify`,
+    /// which reads all the data files. Contents of keys and values are never included.
+    pub fn doctor_report(&self, depth: DoctorDepth) -> BitcaskyResult<DoctorReport> {
+        let directory = &self.database.database_dir;
+        let (telemetry, key_lengths, verify) = match depth {
+            DoctorDepth::Quick => {
+                let keydir = self.keydir.read().get_telemetry_data();
+                let telemetry = BitcaskTelemetry {
+                    keydir,
+                    database: self.database.get_telemetry_data(),
+                    merge_manager: self.merge_manager.get_telemetry_data(),
+                    maintenance_pool: self
+                        .database
+                        .maintenance_queue()
+                        .pool()
+                        .get_telemetry_data(),
+                    expiry_sweeper: self.expiry_sweeper.get_telemetry_data(),
+                };
+                (telemetry, None, None)
+            }
+            DoctorDepth::Deep => {
+                let key_lengths = self.keydir.read().iter().fold(
+                    KeyLengthStats::default(),
+                    |mut stats, (k, _)| {
+                        stats.keys += 1;
+                        stats.total_bytes += k.len() as u64;
+                        stats.max_length = stats.max_length.max(k.len());
+                        stats
+                    },
+                );
+                (
+                    self.get_telemetry_data(),
+                    Some(key_lengths),
+                    Some(self.database.verify()?),
+                )
+            }
+        };
+
+        let mut formatter_versions: HashMap<StorageId, u8> = telemetry
+            .database
+            .stable_storages
+            .values()
+            .map(|t| (t.storage_id, t.formatter_version))
+            .collect();
+        let writing_storage = &telemetry.database.writing_storage;
+        formatter_versions.insert(
+            writing_storage.storage_id,
+            writing_storage.formatter_version,
+        );
+        let mut files = vec![];
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let formatter_version = [fs::FileType::DataFile, fs::FileType::ErlangDataFile]
+                .into_iter()
+                .find(|t| t.check_file_belongs_to_type(&path))
+                .and_then(|t| t.parse_storage_id_from_file_name(&path))
+                .and_then(|id| formatter_versions.get(&id).copied());
+            files.push(DoctorFile {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                size: metadata.len(),
+                formatter_version,
+            });
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(DoctorReport {
+            depth,
+            directory: directory.clone(),
+            options: self.options.clone(),
+            files,
+            lock_holder: fs::read_directory_lock_holder(directory)?,
+            database_error: self.database.check_db_error().err().map(|e| e.to_string()),
+            read_only: self.database.is_read_only(),
+            telemetry,
+            key_lengths,
+            verify,
+            mutation_trace: self.keydir.read().mutation_trace(),
+        })
+    }
+
+    /// Latest mutations of the key in keydir in the order they happened, like puts, deletes,
+    /// relocations by merge and removals by the expiry sweeper. Only mutations recorded in the
+    /// trace enabled by `BitcaskyOptions::mutation_trace` are returned, it's empty if disabled.
+    pub fn trace_for_key<K: AsRef<[u8]>>(&self, key: K) -> Vec<KeyMutation> {
+        self.keydir
+            .read()
+            .trace_for_key(key.as_ref())
+            .unwrap_or_default()
+    }
+
     /// Stores the key and value in the database.
-    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> BitcaskyResult<()> {
-        self.do_put(key, TimedValue::permanent_value(value))
+    pub fn put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V) -> BitcaskyResult<()> {
+        self.do_put(key, TimedValue::permanent_value(value), false, false)?;
+        Ok(())
+    }
+
+    /// Stores the pairs like `put` under one hold of the keydir lock, so no other write can
+    /// interleave them and readers see all of them at once. Returns the outcome of each pair in
+    /// the order of pairs, a pair rejected like a too large key does not stop the others.
+    /// Failing to write a row fails the whole call, pairs put before it stay put.
+    pub fn put_multi<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> BitcaskyResult<Vec<PutResult>> {
+        self.check_writable()?;
+        let mut kd = self.keydir.write();
+        let mut results = vec![];
+        for (key, value) in pairs {
+            let key: Vec<u8> = key.into();
+            if let Err(e) = self.check_key_value_size(&key, value.as_ref().len()) {
+                results.push(Err(e));
+                continue;
+            }
+            let value =
+                TimedValue::permanent_value(value).with_write_timestamp(self.options.clock.now());
+            results.push(Ok(self.write_locked(&mut kd, key, value, false)?));
+        }
+        debug!(target: "Bitcasky", "put {} of {} pairs", results.iter().filter(|r| r.is_ok()).count(), results.len());
+        Ok(results)
+    }
+
+    /// Stores the key and value in the database like `put`, and returns the value it replaced.
+    /// Returns `None` if the key did not exist. The previous value is read while the key is
+    /// locked for writing, so it costs an extra read compared to `put`.
+    pub fn put_previous<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> BitcaskyResult<Option<Vec<u8>>> {
+        self.do_put(key, TimedValue::permanent_value(value), false, true)
+    }
+
+    /// Stores the key and value in the database and flushes them to disk before return,
+    /// regardless of the configured sync strategy.
+    pub fn put_sync<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> BitcaskyResult<()> {
+        self.do_put(key, TimedValue::permanent_value(value), true, false)?;
+        Ok(())
     }
 
     /// Stores the key, value in the database and set a expire time with this value.
-    pub fn put_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    pub fn put_with_ttl<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
         &self,
         key: K,
         value: V,
@@ -91,59 +402,332 @@ impl Bitcasky {
             ));
         }
 
-        let expire_timestamp =
-            (SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + ttl).as_millis() as u64;
+        let expire_timestamp = self
+            .options
+            .clock
+            .now()
+            .saturating_add(ttl.as_millis() as u64);
 
-        self.do_put(key, TimedValue::expirable_value(value, expire_timestamp))
+        self.do_put(
+            key,
+            TimedValue::expirable_value(value, expire_timestamp),
+            false,
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// Stores the key and value with the time it was written in milliseconds since epoch, like
+    /// when applying changes replicated from another database. Returns false without writing
+    /// anything if the stored value of the key was written later than `timestamp`.
+    ///
+    /// On equal timestamps the value written later wins, which is the same rule used to recover
+    /// keys on open: rows are replayed in the order they were written. Values written by other
+    /// functions are stamped with the current time, values written by older versions are taken
+    /// as older than any timestamp. A deleted or expired key keeps no timestamp, so any value
+    /// put with a timestamp is written to it.
+    ///
+    /// The row is synced like any other write by the sync strategy. Merge keeps the timestamp
+    /// along with the row, so the order of writes holds after merge and reopen. Returns
+    /// `InvalidParameter` if `max_clock_skew` is set and `timestamp` is later than local time
+    /// by more than it.
+    pub fn put_with_timestamp<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        timestamp: u64,
+    ) -> BitcaskyResult<bool> {
+        let key: Vec<u8> = key.into();
+        let span = OperationSpan::put(key.len());
+        self.check_key_value_size(&key, value.as_ref().len())?;
+        self.check_timestamp(timestamp)?;
+        self.check_writable()?;
+
+        let mut kd = self.keydir.write();
+        let Some(location) = self.checked_put_if_newer(&mut kd, key, value, timestamp)? else {
+            return Ok(false);
+        };
+        span.record_location(&location);
+        Ok(true)
+    }
+
+    /// Stores the key and value with the time it was written on the source database, like
+    /// `put_with_timestamp`, but does not tell whether it's skipped as older than the stored
+    /// value.
+    pub fn put_raw_timestamp<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        timestamp: u64,
+    ) -> BitcaskyResult<()> {
+        self.put_with_timestamp(key, value, timestamp)?;
+        Ok(())
     }
 
     /// Fetches value for a key
     pub fn get<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<Option<Vec<u8>>> {
+        Ok(self.get_timed_value(key.as_ref())?.map(|(_, v)| v.value))
+    }
+
+    /// Fetches value for a key along with when it's written and where it's stored
+    pub fn get_with_metadata<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<Option<Entry>> {
+        Ok(self
+            .get_timed_value(key.as_ref())?
+            .map(|(location, v)| Entry {
+                value: v.value,
+                write_timestamp: v.write_timestamp,
+                expire_timestamp: v.expire_timestamp,
+                location,
+            }))
+    }
+
+    fn get_timed_value(
+        &self,
+        key: &[u8],
+    ) -> BitcaskyResult<Option<(RowLocation, TimedValue<Vec<u8>>)>> {
+        let span = OperationSpan::get(key.len());
         self.database.check_db_error()?;
 
-        let row_pos = {
-            self.keydir
-                .read()
-                .get(&key.as_ref().into())
-                .map(|r| *r.value())
-        };
+        let row_pos = { self.keydir.read().get(key) };
 
         match row_pos {
             Some(e) => {
-                if let Some(v) = self.database.read_value(&e)? {
-                    return Ok(Some(v.value.to_vec()));
+                span.record_location(&e);
+                let (v, pending_purge) = self.database.read_value_with_purge_state(&e)?;
+                if pending_purge {
+                    self.repair_row(key, &e, v.as_ref())?;
                 }
-                Ok(None)
+                Ok(v.map(|v| (e, v)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Opens a reader over the value of a key, so a large value can be streamed without reading
+    /// it into memory whole. The reader reads from a file handle of its own, so no lock is held
+    /// while it's read. See `ValueReader` for how the checksum is verified.
+    pub fn get_reader<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<Option<ValueReader>> {
+        let span = OperationSpan::get(key.as_ref().len());
+        self.database.check_db_error()?;
+
+        let row_pos = { self.keydir.read().get(key.as_ref()) };
+
+        match row_pos {
+            Some(e) => {
+                span.record_location(&e);
+                Ok(self.database.value_reader(&e)?)
             }
             None => Ok(None),
         }
     }
 
+    /// Fetches the location of the value for a key, along with the location generation observed.
+    /// The location can be cached and read by `read_at` until the location generation changes.
+    pub fn get_location<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> BitcaskyResult<Option<(RowLocation, u64)>> {
+        self.database.check_db_error()?;
+
+        let kd = self.keydir.read();
+        Ok(kd.get(key.as_ref()).map(|r| (r, kd.location_generation())))
+    }
+
+    /// Reads value at the location got by `get_location` at `generation`.
+    /// Returns `BitcaskyError::StaleLocation` if any location was changed since then by merge or drop,
+    /// otherwise returns the value and the location generation observed.
+    pub fn read_at(
+        &self,
+        location: &RowLocation,
+        generation: u64,
+    ) -> BitcaskyResult<(Option<Vec<u8>>, u64)> {
+        self.database.check_db_error()?;
+
+        let kd = self.keydir.read();
+        let current_generation = kd.location_generation();
+        if current_generation != generation {
+            return Err(BitcaskyError::StaleLocation(generation, current_generation));
+        }
+        let value = self
+            .database
+            .read_value(location)?
+            .map(|v| v.value.to_vec());
+        Ok((value, current_generation))
+    }
+
+    /// Returns current location generation. It bumps whenever any key is relocated by merge or
+    /// drop. It starts from a random epoch each time the database is opened, so locations cached
+    /// before the database is reopened are stale as well.
+    pub fn location_generation(&self) -> u64 {
+        self.keydir.read().location_generation()
+    }
+
+    /// Registers a callback which is called after locations are changed by merge or drop.
+    /// It's called on the thread doing merge or drop, so it should return quickly.
+    pub fn on_location_invalidated<F>(&self, f: F)
+    where
+        F: Fn(&LocationInvalidation) + Send + Sync + 'static,
+    {
+        self.keydir.write().add_location_listener(Arc::new(f));
+    }
+
     /// Returns true if the key exists in the database, false otherwise.
     pub fn has<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<bool> {
         self.database.check_db_error()?;
 
-        Ok(self
-            .keydir
-            .read()
-            .get(&key.as_ref().into())
-            .map(|r| *r.value())
-            .is_some())
+        Ok(self.keydir.read().contains_key(key.as_ref()))
+    }
+
+    /// Same as `has`
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<bool> {
+        self.has(key)
+    }
+
+    /// Returns the number of keys in the database. It's O(1) and does no I/O, unlike
+    /// `get_telemetry_data`. Expired keys are counted until they are merged, like `has`.
+    pub fn count_keys(&self) -> BitcaskyResult<usize> {
+        self.database.check_db_error()?;
+
+        Ok(self.keydir.read().len())
+    }
+
+    /// Reserves room for at least `additional` more keys in keydir, so it does not grow
+    /// repeatedly on a bulk load
+    pub fn reserve(&self, additional: usize) {
+        self.keydir.write().reserve(additional);
+    }
+
+    /// Releases memory keydir holds for keys deleted. Merge does it after applying its result
+    pub fn shrink_to_fit(&self) {
+        self.keydir.write().shrink_to_fit();
     }
 
-    /// Iterates all the keys in database and apply each of them to the function f
+    /// Returns true if there is no key in the database
+    pub fn is_empty(&self) -> BitcaskyResult<bool> {
+        Ok(self.count_keys()? == 0)
+    }
+
+    /// Returns false if the key definitely does not exist in the database. With bloom filter
+    /// enabled, it may return true for absent keys at about the configured false positive rate.
+    /// Otherwise it's the same as `has`.
+    pub fn may_contain<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<bool> {
+        self.database.check_db_error()?;
+
+        Ok(self.keydir.read().may_contain(key.as_ref()))
+    }
+
+    /// Returns statistics of the bloom filter, or None if bloom filter is not enabled
+    pub fn bloom_stats(&self) -> Option<BloomFilterStats> {
+        self.keydir.read().bloom_filter_stats()
+    }
+
+    /// Iterates keys in `[start, end)` in lexicographic order along with their values.
+    /// Keydir is not ordered, so all the keys are scanned to find keys in range and then
+    /// sorted, which takes O(n) time no matter how small the range is. Values are read
+    /// lazily during iteration.
+    pub fn scan<K: AsRef<[u8]>>(&self, start: K, end: K) -> BitcaskyResult<ScanIter> {
+        self.database.check_db_error()?;
+
+        Ok(ScanIter::new(
+            self.keydir.clone(),
+            self.database.clone(),
+            start.as_ref(),
+            end.as_ref(),
+        ))
+    }
+
+    /// Iterates keys starting with the prefix in lexicographic order along with their values,
+    /// like `scan`
+    pub fn scan_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> BitcaskyResult<ScanIter> {
+        self.database.check_db_error()?;
+
+        Ok(ScanIter::with_prefix(
+            self.keydir.clone(),
+            self.database.clone(),
+            prefix.as_ref(),
+        ))
+    }
+
+    /// Returns all the keys along with the locations of their values. Only keydir is read, no
+    /// data file is touched, so it's much cheaper than `foreach` when values are not needed.
+    /// Expired keys are returned until they are merged, like `has`.
+    pub fn key_locations(&self) -> BitcaskyResult<KeyLocations> {
+        self.database.check_db_error()?;
+
+        Ok(KeyLocations::new(&self.keydir.read()))
+    }
+
+    /// Iterates all the keys in database and apply each of them to the function f. Keydir is
+    /// read locked during the whole iteration, so writes and merge applying its result wait
+    /// for it, and each key is visited exactly once.
     pub fn foreach_key<F>(&self, mut f: F) -> BitcaskyResult<()>
     where
         F: FnMut(&Vec<u8>),
     {
         self.database.check_db_error()?;
         let kd = self.keydir.read();
-        for k in kd.iter() {
-            f(k.key());
+        for (k, _) in kd.iter() {
+            f(k);
         }
         Ok(())
     }
 
+    pub(crate) fn foreach_key_with_prefix<F>(&self, prefix: &[u8], mut f: F) -> BitcaskyResult<()>
+    where
+        F: FnMut(&[u8]),
+    {
+        self.database.check_db_error()?;
+        let kd = self.keydir.read();
+        for (k, _) in kd.iter().filter(|(k, _)| k.starts_with(prefix)) {
+            f(k);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn prefix_stats(&self, prefix: &[u8]) -> BitcaskyResult<BucketStats> {
+        self.database.check_db_error()?;
+        let kd = self.keydir.read();
+        Ok(kd.iter().filter(|(k, _)| k.starts_with(prefix)).fold(
+            BucketStats::default(),
+            |mut stats, (_, location)| {
+                stats.key_count += 1;
+                stats.approximate_bytes += location.row_size;
+                stats
+            },
+        ))
+    }
+
+    /// Returns the handle of the bucket with the name. Keys in buckets are stored along with
+    /// the plain keys of the database, prefixed by the bucket name, so they are counted by
+    /// `count_keys` and visited by `foreach_key` with the prefix.
+    pub fn bucket<N: AsRef<[u8]>>(&self, name: N) -> BitcaskyResult<Bucket<'_>> {
+        Ok(Bucket::new(self, bucket_prefix(name.as_ref())?))
+    }
+
+    /// Returns the handle of the namespace with the name. Namespaces are buckets, keys in
+    /// different namespaces never collide as namespace names are length framed in keys.
+    pub fn namespace<N: AsRef<[u8]>>(&self, name: N) -> BitcaskyResult<Namespace<'_>> {
+        self.bucket(name)
+    }
+
+    /// Deletes all the keys in the bucket with the name and returns how many keys are deleted.
+    /// Only keydir is scanned to find the keys, no value is read.
+    pub fn drop_bucket<N: AsRef<[u8]>>(&self, name: N) -> BitcaskyResult<usize> {
+        let prefix = bucket_prefix(name.as_ref())?;
+        self.check_writable()?;
+        let mut kd = self.keydir.write();
+        let keys = kd
+            .iter()
+            .filter(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<Vec<u8>>>();
+        for key in keys.iter() {
+            self.delete_locked(&mut kd, key, self.options.clock.now())?;
+        }
+        debug!(target: "Bitcasky", "dropped {} keys in bucket: {:?}", keys.len(), name.as_ref());
+        Ok(keys.len())
+    }
+
     /// Iterates all the keys in database and apply them to the function f with a initial accumulator.
     pub fn fold_key<T, F>(&self, mut f: F, init: Option<T>) -> BitcaskyResult<Option<T>>
     where
@@ -151,8 +735,8 @@ impl Bitcasky {
     {
         self.database.check_db_error()?;
         let mut acc = init;
-        for kd in self.keydir.read().iter() {
-            acc = f(kd.key(), acc)?;
+        for (k, _) in self.keydir.read().iter() {
+            acc = f(k, acc)?;
         }
         Ok(acc)
     }
@@ -193,48 +777,632 @@ impl Bitcasky {
         Ok(acc)
     }
 
-    /// Deletes the named key.
-    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<()> {
+    /// Applies each key matching the predicate along with its value to the function f. Keys are
+    /// matched in keydir, so only values of matching keys are read from data files. Matching
+    /// keys are collected before iterating, so writes are not blocked during it. A key deleted
+    /// before its value is read is skipped.
+    pub fn foreach_matching<P, F>(&self, predicate: P, mut f: F) -> BitcaskyResult<()>
+    where
+        P: Fn(&[u8]) -> bool,
+        F: FnMut(&[u8], &[u8]),
+    {
+        self.fold_matching(
+            predicate,
+            |k, v, _: Option<()>| {
+                f(k, v);
+                Ok(None)
+            },
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// Applies each key matching the predicate along with its value to the function f with a
+    /// initial accumulator, like `foreach_matching`.
+    pub fn fold_matching<T, P, F>(
+        &self,
+        predicate: P,
+        mut f: F,
+        init: Option<T>,
+    ) -> BitcaskyResult<Option<T>>
+    where
+        P: Fn(&[u8]) -> bool,
+        F: FnMut(&[u8], &[u8], Option<T>) -> BitcaskyResult<Option<T>>,
+    {
         self.database.check_db_error()?;
-        let kd = self.keydir.write();
+        let keys = {
+            let kd = self.keydir.read();
+            kd.iter()
+                .filter(|(k, _)| predicate(k))
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<Vec<u8>>>()
+        };
+        let mut acc = init;
+        for key in keys {
+            if let Some((_, v)) = self.get_timed_value(&key)? {
+                acc = f(&key, &v.value, acc)?;
+            }
+        }
+        Ok(acc)
+    }
 
-        if kd.contains_key(&key.as_ref().into()) {
-            let delete_location = self.database.write(&key, deleted_value())?;
-            let (_, prev_lo) = kd.delete(&key.as_ref().into()).unwrap();
-            self.database
-                .add_dead_bytes(prev_lo.storage_id, prev_lo.row_size);
+    /// Takes a consistent snapshot of all the keys and values in the database. Data files
+    /// referenced by the snapshot are kept open until the snapshot is dropped, and merge
+    /// defers deleting them until then. Pins are in memory only, a crash releases them.
+    pub fn snapshot(&self) -> BitcaskyResult<Snapshot> {
+        self.database.check_db_error()?;
+        let kd = self.keydir.read();
+        let storage_ids = kd.live_bytes().into_keys().collect::<Vec<StorageId>>();
+        let storages = self.database.pin_storages(&storage_ids)?;
+        Ok(Snapshot::new(kd.clone(), storages))
+    }
+
+    /// Enqueues hint files to be written in background for data files without a valid hint file,
+    /// like those whose hint file was deleted or corrupted. Returns how many hint files are enqueued.
+    pub fn rebuild_hint_files(&self) -> BitcaskyResult<usize> {
+        self.database.check_db_error()?;
+        Ok(self.database.rebuild_hint_files(false, false)?)
+    }
+
+    /// Same as `rebuild_hint_files` but writes hint files before return.
+    /// Returns how many hint files are rebuilt.
+    pub fn rebuild_hint_files_blocking(&self) -> BitcaskyResult<usize> {
+        self.database.check_db_error()?;
+        Ok(self.database.rebuild_hint_files(true, false)?)
+    }
+
+    /// Rewrites hint files of all the data files but the writing one before return, valid ones
+    /// included, like after an upgrade or when hint files are suspected stale. Returns how many
+    /// hint files are rewritten, which is 0 when `write_hint_files` is off.
+    pub fn rebuild_all_hint_files(&self) -> BitcaskyResult<usize> {
+        self.database.check_db_error()?;
+        Ok(self.database.rebuild_hint_files(true, true)?)
+    }
+
+    /// Deletes the named key. Returns true if the key existed and a tombstone is written for it,
+    /// nothing is written for an absent key.
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<bool> {
+        self.check_writable()?;
+        let mut kd = self.keydir.write();
+        self.delete_present_locked(&mut kd, key.as_ref())
+    }
+
+    /// Deletes the keys like `delete` under one hold of the keydir lock, so no other write can
+    /// interleave them. Returns whether each key existed, in the order of keys. Keys deleted
+    /// before an error stay deleted.
+    pub fn delete_many<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> BitcaskyResult<Vec<bool>> {
+        self.check_writable()?;
+        let mut kd = self.keydir.write();
+        let deleted = keys
+            .into_iter()
+            .map(|key| self.delete_present_locked(&mut kd, key.as_ref()))
+            .collect::<BitcaskyResult<Vec<bool>>>()?;
+        debug!(target: "Bitcasky", "deleted {} of {} keys", deleted.iter().filter(|d| **d).count(), deleted.len());
+        Ok(deleted)
+    }
+
+    /// Deletes all the keys starting with the prefix and returns how many keys are deleted.
+    /// Matching keys are collected from keydir first, then deleted in chunks of 1024 keys,
+    /// releasing the keydir lock between chunks so other writes are not blocked for long. A key
+    /// with the prefix written during it may or may not be deleted.
+    /// An empty prefix is rejected, use `delete_all` to delete all the keys.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> BitcaskyResult<usize> {
+        if prefix.is_empty() {
+            return Err(BitcaskyError::InvalidParameter(
+                "prefix".into(),
+                "empty prefix matches all the keys, use delete_all instead".into(),
+            ));
+        }
+        self.check_writable()?;
+        let keys = {
+            let kd = self.keydir.read();
+            kd.iter()
+                .filter(|(k, _)| k.starts_with(prefix))
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<Vec<u8>>>()
+        };
+        let deleted = self.delete_in_chunks(&keys)?;
+        debug!(target: "Bitcasky", "deleted {} keys with prefix: {:?}", deleted, prefix);
+        Ok(deleted)
+    }
+
+    /// Deletes all the keys like `delete_prefix` and returns how many keys are deleted. Unlike
+    /// `drop`, tombstones are written for deleted keys and data files are kept until merge.
+    pub fn delete_all(&self) -> BitcaskyResult<usize> {
+        self.check_writable()?;
+        let keys = {
+            let kd = self.keydir.read();
+            kd.iter().map(|(k, _)| k.clone()).collect::<Vec<Vec<u8>>>()
+        };
+        let deleted = self.delete_in_chunks(&keys)?;
+        debug!(target: "Bitcasky", "deleted all {} keys", deleted);
+        Ok(deleted)
+    }
+
+    /// Deletes keys still existing, taking the keydir lock once for each chunk of keys
+    fn delete_in_chunks(&self, keys: &[Vec<u8>]) -> BitcaskyResult<usize> {
+        let mut deleted = 0;
+        for chunk in keys.chunks(DELETE_CHUNK_SIZE) {
+            self.check_writable()?;
+            let mut kd = self.keydir.write();
+            for key in chunk {
+                if self.delete_present_locked(&mut kd, key)? {
+                    deleted += 1;
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Deletes the named key at the time in milliseconds since epoch it was deleted on another
+    /// database, the counterpart of `put_with_timestamp`. Returns false without deleting anything
+    /// if the stored value of the key was written or deleted later than `timestamp`.
+    ///
+    /// A tombstone stamped with `timestamp` is written even if the key does not exist, and the
+    /// timestamp is kept in memory until the key is put again, so a value put with an older
+    /// timestamp after the deletion is skipped. It's not kept across reopen, appliers should
+    /// still replay changes of a key in order. Returns `InvalidParameter` if `max_clock_skew` is
+    /// set and `timestamp` is later than local time by more than it.
+    pub fn delete_with_timestamp<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        timestamp: u64,
+    ) -> BitcaskyResult<bool> {
+        self.check_timestamp(timestamp)?;
+        self.check_writable()?;
+
+        let key = key.as_ref();
+        let mut kd = self.keydir.write();
+        if self.written_later_than(&kd, key, timestamp)? {
+            return Ok(false);
+        }
+        if kd.get(key).is_some() {
+            self.delete_locked(&mut kd, key, timestamp)?;
+        } else {
+            let delete_location = self
+                .database
+                .write(key, deleted_value().with_write_timestamp(timestamp))?;
             self.database
                 .add_dead_bytes(delete_location.storage_id, delete_location.row_size);
         }
+        kd.set_deletion_timestamp(key.to_vec(), timestamp);
+        Ok(true)
+    }
+
+    /// Deletes the named key like `delete`, and returns the value it removed.
+    /// Returns `None` if the key did not exist.
+    pub fn delete_previous<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<Option<Vec<u8>>> {
+        self.check_writable()?;
+        let mut kd = self.keydir.write();
+
+        let mut previous_value = None;
+        if let Some(lo) = kd.get(key.as_ref()) {
+            previous_value = self.database.read_value(&lo)?.map(|v| v.value);
+            self.delete_locked(&mut kd, key.as_ref(), self.options.clock.now())?;
+        }
+
+        Ok(previous_value)
+    }
+
+    /// Deletes every key whose value the function f returns false for, and returns how many keys
+    /// are deleted. Keys are collected before iterating, so keys written during it are not visited.
+    /// A key updated after its value passed to f is kept.
+    pub fn retain<F>(&self, mut f: F) -> BitcaskyResult<usize>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.check_writable()?;
+        let keys = {
+            let kd = self.keydir.read();
+            kd.iter().map(|(k, _)| k.clone()).collect::<Vec<Vec<u8>>>()
+        };
+
+        let mut deleted = 0;
+        for key in keys {
+            let (location, value) = {
+                // hold keydir so the location is not changed by merge during reading
+                let kd = self.keydir.read();
+                let Some(location) = kd.get(&key) else {
+                    continue;
+                };
+                match self.database.read_value(&location)? {
+                    Some(v) => (location, v),
+                    None => continue,
+                }
+            };
+            if f(&key, &value.value) {
+                continue;
+            }
+
+            let mut kd = self.keydir.write();
+            if kd.get(&key) != Some(location) {
+                continue;
+            }
+            self.delete_locked(&mut kd, &key, self.options.clock.now())?;
+            deleted += 1;
+        }
+        debug!(target: "Bitcasky", "retain deleted {} keys", deleted);
+        Ok(deleted)
+    }
+
+    /// Writes every key along with its value and write timestamp to the writer as CSV rows of
+    /// `key_hex,value_hex,timestamp` after a header row, and returns how many rows are written.
+    /// Keys are collected before writing, so keys written during it are not exported. Expire
+    /// timestamps are not exported, values imported from the CSV never expire.
+    pub fn export_to_csv(&self, mut writer: impl Write) -> BitcaskyResult<u64> {
+        self.database.check_db_error()?;
+        let keys = {
+            let kd = self.keydir.read();
+            kd.iter().map(|(k, _)| k.clone()).collect::<Vec<Vec<u8>>>()
+        };
+
+        csv::write_header(&mut writer)?;
+        let mut rows = 0;
+        for key in keys {
+            let value = {
+                // hold keydir so the location is not changed by merge during reading
+                let kd = self.keydir.read();
+                let Some(location) = kd.get(&key) else {
+                    continue;
+                };
+                match self.database.read_value(&location)? {
+                    Some(v) => v,
+                    None => continue,
+                }
+            };
+            csv::write_row(&mut writer, &key, &value.value, value.write_timestamp)?;
+            rows += 1;
+        }
+        writer.flush()?;
+        debug!(target: "Bitcasky", "exported {} rows to csv", rows);
+        Ok(rows)
+    }
+
+    /// Reads CSV written by `export_to_csv` and writes each row by `put_with_timestamp`, so a
+    /// row older than the value stored for its key is skipped. Returns how many rows are
+    /// written. Returns `InvalidCsvData` on the first malformed row, rows before it are kept.
+    pub fn import_from_csv(&self, reader: impl BufRead) -> BitcaskyResult<u64> {
+        let mut rows = 0;
+        csv::read_rows(reader, |key, value, timestamp| {
+            if self.put_with_timestamp(key, value, timestamp)? {
+                rows += 1;
+            }
+            Ok(())
+        })?;
+        debug!(target: "Bitcasky", "imported {} rows from csv", rows);
+        Ok(rows)
+    }
+
+    /// Writes all live keys and values, along with their write and expire timestamps, to a
+    /// portable archive which can be imported by `import`. The archive ends with a checksum of
+    /// the whole content. Returns how many entries are written.
+    pub fn export(&self, writer: impl Write) -> BitcaskyResult<u64> {
+        self.database.check_db_error()?;
+        let keys = {
+            let kd = self.keydir.read();
+            kd.iter().map(|(k, _)| k.clone()).collect::<Vec<Vec<u8>>>()
+        };
+
+        let mut archive = ArchiveWriter::new(BufWriter::new(writer))?;
+        for key in keys {
+            let value = {
+                // hold keydir so the location is not changed by merge during reading
+                let kd = self.keydir.read();
+                let Some(location) = kd.get(&key) else {
+                    continue;
+                };
+                match self.database.read_value(&location)? {
+                    Some(v) => v,
+                    None => continue,
+                }
+            };
+            archive.write_entry(&ArchiveEntry {
+                key,
+                value: value.value,
+                write_timestamp: value.write_timestamp,
+                expire_timestamp: value.expire_timestamp,
+            })?;
+        }
+        let entries = archive.finish()?;
+        debug!(target: "Bitcasky", "exported {} entries to archive", entries);
+        Ok(entries)
+    }
+
+    /// Opens an empty database at the directory and writes all the entries in the archive
+    /// written by `export` to it, keeping their write and expire timestamps. Entries already
+    /// expired are skipped. Returns `InvalidArchive` if the archive is malformed or its checksum
+    /// mismatches, in which case everything imported is dropped.
+    pub fn import(
+        directory: &Path,
+        reader: impl Read,
+        options: BitcaskyOptions,
+    ) -> BitcaskyResult<Bitcasky> {
+        let bc = Bitcasky::open(directory, options)?;
+        if bc.count_keys()? != 0 {
+            return Err(BitcaskyError::InvalidParameter(
+                "directory".into(),
+                format!(
+                    "can only import to an empty database, but found keys in: {}",
+                    directory.display()
+                ),
+            ));
+        }
+        match bc.import_archive(reader) {
+            Ok(entries) => {
+                debug!(target: "Bitcasky", "imported {} entries from archive", entries);
+                Ok(bc)
+            }
+            Err(e) => {
+                error!(target: "Bitcasky", "import archive failed with error: {}", &e);
+                bc.drop()?;
+                Err(e)
+            }
+        }
+    }
 
+    fn import_archive(&self, reader: impl Read) -> BitcaskyResult<u64> {
+        let mut archive = ArchiveReader::new(
+            BufReader::new(reader),
+            self.options.max_key_size,
+            self.options.max_value_size,
+        )?;
+        let mut entries = 0;
+        let mut kd = self.keydir.write();
+        while let Some(entry) = archive.next_entry()? {
+            let value = TimedValue::expirable_value(entry.value, entry.expire_timestamp)
+                .with_write_timestamp(entry.write_timestamp);
+            if !value.is_valid(self.options.clock.now()) {
+                continue;
+            }
+            self.write_locked(&mut kd, entry.key, value, false)?;
+            entries += 1;
+        }
+        Ok(entries)
+    }
+
+    /// Writes tombstone for the key if it exists in keydir locked by caller, and returns whether
+    /// it existed
+    fn delete_present_locked(&self, kd: &mut KeyDir, key: &[u8]) -> BitcaskyResult<bool> {
+        if !kd.contains_key(key) {
+            return Ok(false);
+        }
+        self.delete_locked(kd, key, self.options.clock.now())?;
+        Ok(true)
+    }
+
+    /// Writes tombstone stamped with `write_timestamp` for the key which exists in keydir locked
+    /// by caller and removes it from keydir
+    fn delete_locked(
+        &self,
+        kd: &mut KeyDir,
+        key: &[u8],
+        write_timestamp: u64,
+    ) -> BitcaskyResult<()> {
+        let delete_location = self
+            .database
+            .write(key, deleted_value().with_write_timestamp(write_timestamp))?;
+        let (_, prev_lo) = kd.delete(key, MutationCause::Delete).unwrap();
+        self.database
+            .add_dead_bytes(prev_lo.storage_id, prev_lo.row_size);
+        self.database
+            .add_dead_bytes(delete_location.storage_id, delete_location.row_size);
         Ok(())
     }
 
     /// Drop this entire database
     pub fn drop(&self) -> BitcaskyResult<()> {
-        let kd = self.keydir.write();
+        self.check_writable()?;
+        let pending_invalidation = {
+            let mut kd = self.keydir.write();
 
-        if let Err(e) = self.database.drop() {
-            self.database
-                .mark_db_error(format!("drop database failed. {}", e));
-            return Err(BitcaskyError::DatabaseError(e));
-        }
+            if let Err(e) = (*self.database).drop() {
+                self.database
+                    .mark_db_error(format!("drop database failed. {}", e));
+                return Err(BitcaskyError::DatabaseError(e));
+            }
 
-        kd.clear();
+            kd.clear();
+            kd.invalidate_locations(vec![])
+        };
+        pending_invalidation.notify();
         Ok(())
     }
 
+    /// Turns read only mode on or off at runtime. In read only mode writes, deletes, drop, merge
+    /// and rotate are rejected with `BitcaskyError::ReadOnlyMode`, and auto merge is suspended.
+    /// Reads, stats, verify, sync and hint files are not affected. Operations already started,
+    /// like a running merge, are not interrupted.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.database.set_read_only(read_only);
+        info!(target: "Bitcasky", "read only mode is turned {}", if read_only { "on" } else { "off" });
+    }
+
+    /// Returns true if the database is in read only mode
+    pub fn is_read_only(&self) -> bool {
+        self.database.is_read_only()
+    }
+
+    /// Removes keys with expired values from keydir, like the expiry sweeper enabled by
+    /// `BitcaskyOptions::expiry_sweep` does in background, and returns how many keys are removed.
+    /// It runs even if sweeping in background is paused.
+    pub fn sweep_expired(&self) -> BitcaskyResult<usize> {
+        self.check_writable()?;
+        self.expiry_sweeper.sweep(&self.database, &self.keydir)
+    }
+
+    /// Pauses or resumes the expiry sweeper running in background. A sweep running when it's
+    /// paused is not interrupted.
+    pub fn set_expiry_sweep_paused(&self, paused: bool) {
+        self.expiry_sweeper.set_paused(paused);
+    }
+
     /// Flushes all buffers to disk ensuring all data is written
     pub fn sync(&self) -> BitcaskyResult<()> {
         Ok(self.database.sync()?)
     }
 
+    /// Blocks until hint files of sealed data files queued for the background hint writer are
+    /// written, or the timeout elapsed, so the next open recovers from them instead of scanning
+    /// data files. Close does the same waiting up to `hint_write_timeout`.
+    /// Returns false on timeout.
+    pub fn wait_for_hint_flush(&self, timeout: Duration) -> bool {
+        self.database.wait_for_hint_flush(timeout)
+    }
+
+    /// Syncs the database and waits for pending hint files up to `hint_write_timeout` when the
+    /// process receives `SIGTERM` or `SIGINT`, then runs the default action of the signal which
+    /// exits the process. Calling it again with the same database does nothing. Only the
+    /// database is kept weakly, so it's closed as usual once all the other references are
+    /// dropped.
+    ///
+    /// Handlers are installed by signal-hook on the first call. Handlers installed before are
+    /// chained and still run when the signal arrives.
+    #[cfg(all(unix, feature = "signal-handling"))]
+    pub fn install_signal_handlers(bitcasky: Arc<Bitcasky>) -> BitcaskyResult<()> {
+        Ok(crate::signal::register(bitcasky)?)
+    }
+
+    #[cfg(all(unix, feature = "signal-handling"))]
+    pub(crate) fn flush_on_signal(&self) -> BitcaskyResult<()> {
+        self.sync()?;
+        if !self.wait_for_hint_flush(self.options.database.hint_write_timeout) {
+            warn!(target: "Bitcasky", "hint files are not all written on signal in {:?}",
+                self.options.database.hint_write_timeout);
+        }
+        Ok(())
+    }
+
+    /// Returns which writing file is in use and how much of it is written and flushed to disk.
+    pub fn durability_state(&self) -> DurabilityState {
+        self.database.durability_state()
+    }
+
+    /// Blocks until the row at `location` is covered by a completed flush, or the timeout elapsed.
+    /// Rows in data files other than the writing file are always durable.
+    /// Returns false on timeout. This function never flushes by itself.
+    pub fn wait_durable(&self, location: RowLocation, timeout: Duration) -> BitcaskyResult<bool> {
+        self.database.check_db_error()?;
+
+        Ok(self.database.wait_durable(&location, timeout))
+    }
+
     /// Merges all datafiles in the database. Old keys are squashed and deleted keys removes.
     /// Duplicate key/value pairs are also removed. Call this function periodically to reclaim disk space.
-    pub fn merge(&self) -> BitcaskyResult<()> {
+    pub fn merge(&self) -> BitcaskyResult<MergeReport> {
+        self.merge_cancellable(&MergeCancelToken::default())
+    }
+
+    /// Merges like `merge`, but stops with `BitcaskyError::MergeCancelled` once the token is
+    /// cancelled, like to yield to a shutdown. Files written by the cancelled merge are deleted
+    /// and the data files are left as they were.
+    pub fn merge_cancellable(&self, cancel: &MergeCancelToken) -> BitcaskyResult<MergeReport> {
+        self.database.check_db_error()?;
+
+        self.merge_manager
+            .merge(&self.database, &self.keydir, cancel)
+    }
+
+    /// Merges only the data files with given storage ids, leaving other data files untouched.
+    /// Live values in these files are rewritten to new data files and then these files are deleted.
+    /// Use `fragmented_files` to find data files worth merging. Rows are read by iterating these
+    /// files, so a read error fails it regardless of `merge_error_policy`.
+    pub fn merge_files(&self, storage_ids: &[StorageId]) -> BitcaskyResult<MergeReport> {
         self.database.check_db_error()?;
 
-        self.merge_manager.merge(&self.database, &self.keydir)
+        self.merge_manager
+            .merge_files(&self.database, &self.keydir, storage_ids)
+    }
+
+    /// Merges only the data files which ratio of dead bytes exceeds the threshold, like
+    /// `merge_files` on the result of `fragmented_files`, so the other data files are not
+    /// rewritten. Keys located in the other data files keep their locations. Does nothing if no
+    /// data file exceeds the threshold.
+    pub fn merge_fragmented(&self, threshold: f64) -> BitcaskyResult<MergeReport> {
+        self.database.check_db_error()?;
+
+        self.merge_manager
+            .merge_fragmented(&self.database, &self.keydir, threshold)
+    }
+
+    /// Returns storage ids of data files which ratio of dead bytes exceeds the threshold.
+    /// The writing file is never returned.
+    pub fn fragmented_files(&self, threshold: f64) -> Vec<StorageId> {
+        self.merge_manager
+            .fragmented_files(&self.database, threshold)
+    }
+
+    /// Returns true if the ratio of dead bytes, which are taken by overwritten or deleted values,
+    /// to all the bytes in data files exceeds the threshold.
+    pub fn should_merge(&self, threshold: f64) -> bool {
+        self.merge_manager.should_merge(&self.database, threshold)
+    }
+
+    /// Estimates bytes rewritten, data files written, free space required and time taken by
+    /// `merge` if it starts now, from bytes of live rows in keydir and the moving average of
+    /// bytes written per second by recent writes.
+    pub fn merge_estimate(&self) -> MergeEstimate {
+        self.merge_manager
+            .estimate(&self.database, &self.keydir.read(), &[])
+    }
+
+    /// Starts merging all datafiles on a background thread and returns a handle to wait for it.
+    /// Writes continue against a new writing file while the merge is running.
+    /// Only one merge can run at a time, otherwise `BitcaskyError::MergeInProgress` is returned.
+    pub fn merge_async(&self) -> BitcaskyResult<MergeHandle> {
+        self.database.check_db_error()?;
+
+        self.merge_manager
+            .merge_async(self.database.clone(), self.keydir.clone())
+    }
+
+    /// Starts checking dead bytes ratio at the interval in background, and merges all data files
+    /// when it exceeds the threshold. Works alongside `auto_merge` in options. Errors of failed
+    /// merges are kept in the returned handle, and checking stops when the handle is dropped.
+    pub fn start_auto_merge(
+        &self,
+        interval: Duration,
+        dead_byte_threshold: f64,
+    ) -> BitcaskyResult<AutoMergeHandle> {
+        if !(dead_byte_threshold > 0.0 && dead_byte_threshold < 1.0) {
+            return Err(BitcaskyError::InvalidParameter(
+                "dead_byte_threshold".into(),
+                format!("should be between 0 and 1, but is {}", dead_byte_threshold),
+            ));
+        }
+        if interval.is_zero() {
+            return Err(BitcaskyError::InvalidParameter(
+                "interval".into(),
+                "should not be zero".into(),
+            ));
+        }
+        let errors = Arc::new(Mutex::new(vec![]));
+        let task = {
+            let errors = errors.clone();
+            self.merge_manager.schedule_merge(
+                &self.database,
+                &self.keydir,
+                dead_byte_threshold,
+                interval,
+                move |e| {
+                    error!(target: "Bitcasky", "auto merge failed with error: {}", e);
+                    errors.lock().push(e);
+                },
+            )
+        };
+        Ok(AutoMergeHandle::new(task, errors))
+    }
+
+    /// Returns wait time and hold time of the keydir lock and the data file locks since
+    /// the database was opened. Only available with the `instrument-locks` feature.
+    #[cfg(feature = "instrument-locks")]
+    pub fn lock_stats(&self) -> LockStats {
+        LockStats {
+            keydir: self.keydir.stat(),
+            ..self.database.lock_stats()
+        }
     }
 
     /// Returns statistics about the database, like the number of data files,
@@ -244,33 +1412,169 @@ impl Bitcasky {
         let keydir = kd.get_telemetry_data();
         BitcaskTelemetry {
             keydir,
-            database: self.database.get_telemetry_data(),
+            database: self
+                .database
+                .get_telemetry_data()
+                .with_live_bytes(kd.total_live_bytes()),
             merge_manager: self.merge_manager.get_telemetry_data(),
+            maintenance_pool: self
+                .database
+                .maintenance_queue()
+                .pool()
+                .get_telemetry_data(),
+            expiry_sweeper: self.expiry_sweeper.get_telemetry_data(),
         }
     }
 
-    fn do_put<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    fn do_put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
         &self,
         key: K,
         value: TimedValue<V>,
-    ) -> BitcaskyResult<()> {
-        if key.as_ref().len() > self.options.max_key_size {
+        sync: bool,
+        read_previous: bool,
+    ) -> BitcaskyResult<Option<Vec<u8>>> {
+        // keydir owns the key, take it without copying when possible
+        let key: Vec<u8> = key.into();
+        let span = OperationSpan::put(key.len());
+        self.check_key_value_size(&key, value.len())?;
+
+        self.check_writable()?;
+
+        let mut kd = self.keydir.write();
+        let previous_value = match kd.get(&key) {
+            Some(lo) if read_previous => self.database.read_value(&lo)?.map(|v| v.value),
+            _ => None,
+        };
+        let value = value.with_write_timestamp(self.options.clock.now());
+        let location = self.write_locked(&mut kd, key, value, sync)?;
+        span.record_location(&location);
+        Ok(previous_value)
+    }
+
+    fn check_writable(&self) -> BitcaskyResult<()> {
+        self.database.check_db_error()?;
+        if self.database.is_read_only() {
+            return Err(BitcaskyError::ReadOnlyMode());
+        }
+        Ok(())
+    }
+
+    fn check_timestamp(&self, timestamp: u64) -> BitcaskyResult<()> {
+        if let Some(skew) = self.options.max_clock_skew {
+            let now = self.options.clock.now();
+            if timestamp > now.saturating_add(skew.as_millis() as u64) {
+                return Err(BitcaskyError::InvalidParameter(
+                    "timestamp".into(),
+                    format!(
+                        "{} is later than local time {} by more than {:?}",
+                        timestamp, now, skew
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the value of the key in keydir locked by caller was written later than
+    /// `timestamp`
+    /// Writes the value stamped with `timestamp` and puts its location to keydir, only when the
+    /// stored value of the key was not written later. Returns `None` if it's skipped.
+    fn checked_put_if_newer<V: AsRef<[u8]>>(
+        &self,
+        kd: &mut KeyDir,
+        key: Vec<u8>,
+        value: V,
+        timestamp: u64,
+    ) -> BitcaskyResult<Option<RowLocation>> {
+        if self.written_later_than(kd, &key, timestamp)? {
+            return Ok(None);
+        }
+        let location = self.write_locked(
+            kd,
+            key,
+            TimedValue::permanent_value(value).with_write_timestamp(timestamp),
+            false,
+        )?;
+        Ok(Some(location))
+    }
+
+    fn written_later_than(&self, kd: &KeyDir, key: &[u8], timestamp: u64) -> BitcaskyResult<bool> {
+        if let Some(lo) = kd.get(key) {
+            if let Some(v) = self.database.read_value(&lo)? {
+                if v.write_timestamp > timestamp {
+                    debug!(target: "Bitcasky", "skip write with timestamp: {} older than: {}. key: {:?}",
+                        timestamp, v.write_timestamp, key);
+                    return Ok(true);
+                }
+            }
+        } else if let Some(deletion_timestamp) = kd.deletion_timestamp(key) {
+            if deletion_timestamp > timestamp {
+                debug!(target: "Bitcasky", "skip write with timestamp: {} older than deletion at: {}. key: {:?}",
+                    timestamp, deletion_timestamp, key);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn check_key_value_size(&self, key: &[u8], value_size: usize) -> BitcaskyResult<()> {
+        if key.len() > self.options.max_key_size {
             return Err(BitcaskyError::InvalidParameter(
                 "key".into(),
                 "key size overflow".into(),
             ));
         }
-        if value.len() > self.options.max_value_size {
+        if value_size > self.options.max_value_size {
             return Err(BitcaskyError::InvalidParameter(
                 "value".into(),
                 "values size overflow".into(),
             ));
         }
+        Ok(())
+    }
 
-        self.database.check_db_error()?;
+    /// Rewrites the value read from a data file going to be purged to the writing file, if the key
+    /// still points to it in keydir. So the key is still reachable after the data file purged.
+    fn repair_row(
+        &self,
+        key: &[u8],
+        location: &RowLocation,
+        value: Option<&TimedValue<Vec<u8>>>,
+    ) -> BitcaskyResult<()> {
+        let mut kd = self.keydir.write();
+        if kd.get(key).as_ref() != Some(location) {
+            // moved by merge or overwritten
+            return Ok(());
+        }
+        debug!(target: "Bitcasky", "repair key: {:?} located in data file pending purge with id: {}",
+            key, location.storage_id);
+        match value {
+            Some(v) => {
+                self.write_locked(&mut kd, key.to_vec(), v.as_slice(), false)?;
+                Ok(())
+            }
+            None => {
+                kd.delete(key, MutationCause::Repair);
+                Ok(())
+            }
+        }
+    }
 
-        let kd = self.keydir.write();
-        let ret = self.database.write(&key, value).map_err(|e| {
+    /// Writes the row and points the key to it in keydir, which is locked by caller.
+    /// Returns where the row is written.
+    fn write_locked<V: AsRef<[u8]>>(
+        &self,
+        kd: &mut KeyDir,
+        key: Vec<u8>,
+        value: TimedValue<V>,
+        sync: bool,
+    ) -> BitcaskyResult<RowLocation> {
+        let ret = if sync {
+            self.database.write_sync(&key, value)
+        } else {
+            self.database.write(&key, value)
+        }
+        .map_err(|e| {
             error!(target: "BitcaskPut", "put data failed with error: {}", &e);
 
             self.database.mark_db_error(e.to_string());
@@ -278,20 +1582,194 @@ impl Bitcasky {
         })?;
 
         debug!(target: "Bitcasky", "put data success. key: {:?}, storage_id: {}, row_offset: {}", 
-            key.as_ref(), ret.storage_id, ret.row_offset);
-        if let Some(lo) = kd.put(key.as_ref().into(), ret) {
+            key, ret.storage_id, ret.row_offset);
+        if let Some(lo) = kd.put(key, ret) {
             self.database.add_dead_bytes(lo.storage_id, lo.row_size);
         }
-        Ok(())
+        Ok(ret)
+    }
+}
+
+/// Maps merge in progress to `AlreadyRunning` for operations merging data files
+fn merge_outcome<T, R>(
+    ret: BitcaskyResult<T>,
+    report: impl FnOnce() -> R,
+) -> BitcaskyResult<MaintenanceOutcome<R>> {
+    match ret {
+        Ok(_) => Ok(MaintenanceOutcome::Completed(report())),
+        Err(BitcaskyError::MergeInProgress()) => Ok(MaintenanceOutcome::AlreadyRunning),
+        Err(e) => Err(e),
+    }
+}
+
+impl Maintenance for Bitcasky {
+    fn rotate(&self) -> BitcaskyResult<MaintenanceOutcome<RotateReport>> {
+        let Some(_guard) = self
+            .running_operations
+            .try_start(MaintenanceOperation::Rotate)
+        else {
+            return Ok(MaintenanceOutcome::AlreadyRunning);
+        };
+        self.check_writable()?;
+        let before = self.database.get_storage_ids().writing_storage_id;
+        self.database.flush_writing_file()?;
+        let after = self.database.get_storage_ids().writing_storage_id;
+        Ok(MaintenanceOutcome::Completed(RotateReport {
+            rotated_storage_id: (before != after).then_some(before),
+        }))
+    }
+
+    fn merge_if_needed(&self) -> BitcaskyResult<MaintenanceOutcome<MergeIfNeededReport>> {
+        let Some(_guard) = self
+            .running_operations
+            .try_start(MaintenanceOperation::MergeIfNeeded)
+        else {
+            return Ok(MaintenanceOutcome::AlreadyRunning);
+        };
+        self.database.check_db_error()?;
+        let threshold = self
+            .options
+            .auto_merge_threshold
+            .unwrap_or(DEFAULT_MERGE_THRESHOLD);
+        let storage_aggregate = self.database.get_telemetry_data().storage_aggregate;
+        let dead_bytes_ratio = if storage_aggregate.total_data_size == 0 {
+            0.0
+        } else {
+            storage_aggregate.total_dead_bytes as f64 / storage_aggregate.total_data_size as f64
+        };
+        let merged = dead_bytes_ratio > threshold;
+        let ret = if merged {
+            self.merge().map(|_| ())
+        } else {
+            Ok(())
+        };
+        merge_outcome(ret, || MergeIfNeededReport {
+            threshold,
+            dead_bytes_ratio,
+            merged,
+        })
+    }
+
+    fn rebuild_hint_files(&self) -> BitcaskyResult<MaintenanceOutcome<RebuildHintFilesReport>> {
+        let Some(_guard) = self
+            .running_operations
+            .try_start(MaintenanceOperation::RebuildHintFiles)
+        else {
+            return Ok(MaintenanceOutcome::AlreadyRunning);
+        };
+        let rebuilt_hint_files = self.rebuild_hint_files_blocking()?;
+        Ok(MaintenanceOutcome::Completed(RebuildHintFilesReport {
+            rebuilt_hint_files,
+        }))
+    }
+
+    fn verify(&self) -> BitcaskyResult<MaintenanceOutcome<VerifyReport>> {
+        let Some(_guard) = self
+            .running_operations
+            .try_start(MaintenanceOperation::Verify)
+        else {
+            return Ok(MaintenanceOutcome::AlreadyRunning);
+        };
+        Ok(MaintenanceOutcome::Completed(Bitcasky::verify(self)?))
+    }
+
+    fn drop_dead_files(&self) -> BitcaskyResult<MaintenanceOutcome<DropDeadFilesReport>> {
+        let Some(_guard) = self
+            .running_operations
+            .try_start(MaintenanceOperation::DropDeadFiles)
+        else {
+            return Ok(MaintenanceOutcome::AlreadyRunning);
+        };
+        self.database.check_db_error()?;
+        let live_bytes = self.keydir.read().live_bytes();
+        let mut dead_storage_ids = self
+            .database
+            .get_storage_ids()
+            .stable_storage_ids
+            .into_iter()
+            .filter(|id| !live_bytes.contains_key(id))
+            .collect::<Vec<StorageId>>();
+        dead_storage_ids.sort();
+        let ret = self.merge_files(&dead_storage_ids);
+        merge_outcome(ret, || DropDeadFilesReport {
+            dropped_storage_ids: dead_storage_ids,
+        })
+    }
+
+    fn compact_small_files(&self) -> BitcaskyResult<MaintenanceOutcome<CompactSmallFilesReport>> {
+        let Some(_guard) = self
+            .running_operations
+            .try_start(MaintenanceOperation::CompactSmallFiles)
+        else {
+            return Ok(MaintenanceOutcome::AlreadyRunning);
+        };
+        self.database.check_db_error()?;
+        let small_file_size = self.options.database.storage.max_data_file_size / 2;
+        let mut small_storage_ids = self
+            .database
+            .get_telemetry_data()
+            .stable_storages
+            .values()
+            .filter(|s| s.data_size < small_file_size)
+            .map(|s| s.storage_id)
+            .collect::<Vec<StorageId>>();
+        // rewriting a single file does not reduce the number of files
+        if small_storage_ids.len() < 2 {
+            small_storage_ids.clear();
+        }
+        small_storage_ids.sort();
+        let ret = self.merge_files(&small_storage_ids);
+        merge_outcome(ret, || CompactSmallFilesReport {
+            compacted_storage_ids: small_storage_ids,
+        })
+    }
+
+    fn quiesce(&self) -> BitcaskyResult<MaintenanceOutcome<QuiesceReport>> {
+        let Some(_guard) = self
+            .running_operations
+            .try_start(MaintenanceOperation::Quiesce)
+        else {
+            return Ok(MaintenanceOutcome::AlreadyRunning);
+        };
+        self.database.check_db_error()?;
+        self.sync()?;
+        let rebuilt_hint_files = self.rebuild_hint_files_blocking()?;
+        let durability_state = self.durability_state();
+        Ok(MaintenanceOutcome::Completed(QuiesceReport {
+            rebuilt_hint_files,
+            writing_storage_id: durability_state.writing_file,
+            synced_offset: durability_state.synced_offset,
+        }))
     }
 }
 
 impl Drop for Bitcasky {
     fn drop(&mut self) {
+        if let Some(worker) = self.auto_merge_worker.take() {
+            drop(worker);
+        }
+        self.expiry_sweeper.stop();
+        // data files changed by a merge running in background are not known by the checkpoint
+        if self.database.check_db_error().is_ok() && self.merge_manager.stop_merging() {
+            if let Err(e) = self.keydir.write().save_checkpoint(&self.database) {
+                warn!(target: "Bitcasky", "save keydir checkpoint failed, keydir is rebuilt from data files on next open. Error: {}", e);
+            }
+        }
         debug!(target: "Bitcasky", "Bitcask shutdown. instanceId = {}", self.instance_id);
     }
 }
 
+/// Estimates how many keys are under the directory without opening the database. Keys in data
+/// files with hint file are counted from hint files, and rows in other data files are estimated
+/// by their size. It takes no lock and only reads files, so it can run while the directory is
+/// opened by another process.
+pub fn estimate_key_count(directory: &Path) -> BitcaskyResult<KeyCountEstimate> {
+    Ok(database::estimate_key_count(
+        directory,
+        Arc::new(BitcaskyOptions::default()),
+    )?)
+}
+
 fn validate_database_directory(dir: &Path) -> BitcaskyResult<()> {
     std::fs::create_dir_all(dir)?;
     if !fs::check_directory_is_writable(dir) {
@@ -302,3 +1780,38 @@ fn validate_database_directory(dir: &Path) -> BitcaskyResult<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::DebugClock;
+    use crate::test_utils::get_temporary_directory_path;
+    use test_log::test;
+
+    #[test]
+    fn test_rows_stamped_by_clock() {
+        let clock = Arc::new(DebugClock::new(1000));
+        let bc = Bitcasky::open(
+            &get_temporary_directory_path(),
+            BitcaskyOptions::default().debug_clock(clock.clone()),
+        )
+        .unwrap();
+        bc.put("k1", "value1").unwrap();
+        clock.set(2000);
+        bc.put_with_ttl("k2", "value2", Duration::from_millis(500))
+            .unwrap();
+
+        let entry = bc.get_with_metadata("k1").unwrap().unwrap();
+        assert_eq!((1000, 0), (entry.write_timestamp, entry.expire_timestamp));
+        let entry = bc.get_with_metadata("k2").unwrap().unwrap();
+        assert_eq!(
+            (2000, 2500),
+            (entry.write_timestamp, entry.expire_timestamp)
+        );
+
+        clock.set(2500);
+        assert!(bc.get("k2").unwrap().is_none());
+        assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+    }
+}
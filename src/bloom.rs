@@ -0,0 +1,210 @@
+use std::hash::{BuildHasher, Hasher};
+
+use ahash::RandomState;
+
+use crate::options::BloomFilterOptions;
+
+// fixed seeds so a filter built from the same keys always has the same bits
+const SEEDS: [u64; 4] = [
+    0x243f_6a88_85a3_08d3,
+    0x1319_8a2e_0370_7344,
+    0xa409_3822_299f_31d0,
+    0x082e_fa98_ec4e_6c89,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BloomFilterStats {
+    pub bits: usize,
+    pub hashes: u32,
+    /// Items inserted since the filter was last rebuilt. Keys put again after being deleted
+    /// are counted more than once
+    pub items: usize,
+    /// False positive rate estimated by bits, hashes and items
+    pub estimated_fpp: f64,
+}
+
+/// Bloom filter over keys in keydir. Keys can not be removed, so it's rebuilt
+/// from keydir after merge to forget deleted keys.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: usize,
+    num_hashes: u32,
+    items: usize,
+    hash_builder: RandomState,
+}
+
+impl BloomFilter {
+    pub fn new(options: &BloomFilterOptions) -> BloomFilter {
+        let expected_items = options.expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-expected_items * options.false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as usize;
+        let num_hashes = ((num_bits as f64 / expected_items) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64)],
+            num_bits,
+            num_hashes,
+            items: 0,
+            hash_builder: RandomState::with_seeds(SEEDS[0], SEEDS[1], SEEDS[2], SEEDS[3]),
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        let (h1, h2) = self.hash(key);
+        for i in 0..self.num_hashes {
+            let bit = self.bit_index(h1, h2, i);
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.items += 1;
+    }
+
+    /// Returns false if the key was never inserted, true if it may be inserted
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let (h1, h2) = self.hash(key);
+        (0..self.num_hashes).all(|i| {
+            let bit = self.bit_index(h1, h2, i);
+            self.bits[bit / 64] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|b| *b = 0);
+        self.items = 0;
+    }
+
+    pub fn stats(&self) -> BloomFilterStats {
+        let k = self.num_hashes as f64;
+        let estimated_fpp = (1.0 - (-k * self.items as f64 / self.num_bits as f64).exp()).powf(k);
+        BloomFilterStats {
+            bits: self.num_bits,
+            hashes: self.num_hashes,
+            items: self.items,
+            estimated_fpp,
+        }
+    }
+
+    /// Encodes bits and parameters in big endian: bits count u64 | hashes u32 | items u64 |
+    /// bits in u64 words
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bs = Vec::with_capacity(20 + self.bits.len() * 8);
+        bs.extend_from_slice(&(self.num_bits as u64).to_be_bytes());
+        bs.extend_from_slice(&self.num_hashes.to_be_bytes());
+        bs.extend_from_slice(&(self.items as u64).to_be_bytes());
+        for word in self.bits.iter() {
+            bs.extend_from_slice(&word.to_be_bytes());
+        }
+        bs
+    }
+
+    /// Decodes a filter encoded by `encode`. Returns `None` if it's malformed or built by
+    /// other options, then it has to be rebuilt
+    pub fn decode(bs: &[u8], options: &BloomFilterOptions) -> Option<BloomFilter> {
+        let mut filter = BloomFilter::new(options);
+        if bs.len() != 20 + filter.bits.len() * 8 {
+            return None;
+        }
+        let (header, words) = bs.split_at(20);
+        let num_bits = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let num_hashes = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        if num_bits != filter.num_bits as u64 || num_hashes != filter.num_hashes {
+            return None;
+        }
+        filter.items = u64::from_be_bytes(header[12..20].try_into().unwrap()) as usize;
+        for (word, bs) in filter.bits.iter_mut().zip(words.chunks_exact(8)) {
+            *word = u64::from_be_bytes(bs.try_into().unwrap());
+        }
+        Some(filter)
+    }
+
+    fn hash(&self, key: &[u8]) -> (u64, u64) {
+        let mut hasher = self.hash_builder.build_hasher();
+        hasher.write(key);
+        let h = hasher.finish();
+        let mut hasher = self.hash_builder.build_hasher();
+        hasher.write_u64(h);
+        // the second hash must be odd to visit different bits on each round
+        (h, hasher.finish() | 1)
+    }
+
+    fn bit_index(&self, h1: u64, h2: u64, round: u32) -> usize {
+        (h1.wrapping_add((round as u64).wrapping_mul(h2)) % self.num_bits as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+
+    fn get_options(expected_items: usize, false_positive_rate: f64) -> BloomFilterOptions {
+        BloomFilterOptions {
+            expected_items,
+            false_positive_rate,
+        }
+    }
+
+    #[test]
+    fn test_no_false_negative() {
+        let mut filter = BloomFilter::new(&get_options(1000, 0.01));
+        for i in 0..1000 {
+            filter.insert(format!("key-{}", i).as_bytes());
+        }
+        for i in 0..1000 {
+            assert!(filter.may_contain(format!("key-{}", i).as_bytes()));
+        }
+        assert_eq!(1000, filter.stats().items);
+
+        filter.clear();
+        assert_eq!(0, filter.stats().items);
+        assert!(!filter.may_contain("key-0".as_bytes()));
+    }
+
+    #[test]
+    fn test_decode_encoded() {
+        let options = get_options(1000, 0.01);
+        let mut filter = BloomFilter::new(&options);
+        for i in 0..100 {
+            filter.insert(format!("key-{}", i).as_bytes());
+        }
+        let bs = filter.encode();
+
+        let decoded = BloomFilter::decode(&bs, &options).unwrap();
+        assert_eq!(filter.stats(), decoded.stats());
+        assert_eq!(filter.bits, decoded.bits);
+        for i in 0..100 {
+            assert!(decoded.may_contain(format!("key-{}", i).as_bytes()));
+        }
+
+        // built by other options
+        assert!(BloomFilter::decode(&bs, &get_options(1000, 0.001)).is_none());
+        assert!(BloomFilter::decode(&bs[..bs.len() - 1], &options).is_none());
+    }
+
+    #[test]
+    fn test_observed_false_positive_rate() {
+        for target in [0.1, 0.01, 0.001] {
+            let expected_items = 20_000;
+            let mut filter = BloomFilter::new(&get_options(expected_items, target));
+            for i in 0..expected_items {
+                filter.insert(format!("inserted-{}", i).as_bytes());
+            }
+            let stats = filter.stats();
+            assert!((stats.estimated_fpp - target).abs() < target * 0.2);
+
+            let candidates = 200_000;
+            let false_positives = (0..candidates)
+                .filter(|i| filter.may_contain(format!("absent-{}", i).as_bytes()))
+                .count();
+            let observed = false_positives as f64 / candidates as f64;
+            assert!(
+                observed < target * 1.5,
+                "observed false positive rate: {}, target: {}",
+                observed,
+                target
+            );
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Buckets group keys of a database under a name. A key in a bucket is stored as
+//! `name size | name | key` with a 2 bytes big endian name size. It's only a convention of
+//! encoding keys, so data files, merge and recovery treat these keys as any other key.
+
+use std::time::Duration;
+
+use crate::bitcasky::{Bitcasky, ScanIter};
+use crate::error::{BitcaskyError, BitcaskyResult};
+
+const NAME_SIZE_SIZE: usize = 2;
+
+/// Returns the prefix of keys in the bucket with the name
+pub(crate) fn bucket_prefix(name: &[u8]) -> BitcaskyResult<Vec<u8>> {
+    if name.len() > u16::MAX as usize {
+        return Err(BitcaskyError::InvalidParameter(
+            "bucket".into(),
+            "bucket name size overflow".into(),
+        ));
+    }
+    let mut prefix = Vec::with_capacity(NAME_SIZE_SIZE + name.len());
+    prefix.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    prefix.extend_from_slice(name);
+    Ok(prefix)
+}
+
+/// Statistics of keys in a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BucketStats {
+    pub key_count: usize,
+    /// Bytes of rows of the keys in data files, including row headers
+    pub approximate_bytes: usize,
+}
+
+/// Handle of a namespace got by `Bitcasky::namespace`, which is a bucket
+pub type Namespace<'a> = Bucket<'a>;
+
+/// Handle of a bucket got by `Bitcasky::bucket`. Keys passed to it and returned by it do not
+/// include the bucket prefix.
+pub struct Bucket<'a> {
+    bitcasky: &'a Bitcasky,
+    prefix: Vec<u8>,
+}
+
+impl<'a> Bucket<'a> {
+    pub(crate) fn new(bitcasky: &'a Bitcasky, prefix: Vec<u8>) -> Bucket<'a> {
+        Bucket { bitcasky, prefix }
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.prefix[NAME_SIZE_SIZE..]
+    }
+
+    /// Stores a key and a value in this bucket
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> BitcaskyResult<()> {
+        self.bitcasky.put(self.bucket_key(key), value)
+    }
+
+    /// Stores a key and a value which expires after `ttl` in this bucket
+    pub fn put_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> BitcaskyResult<()> {
+        self.bitcasky.put_with_ttl(self.bucket_key(key), value, ttl)
+    }
+
+    /// Fetches value for a key in this bucket
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<Option<Vec<u8>>> {
+        self.bitcasky.get(self.bucket_key(key))
+    }
+
+    /// Returns true if the key exists in this bucket
+    pub fn has<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<bool> {
+        self.bitcasky.has(self.bucket_key(key))
+    }
+
+    /// Deletes the named key in this bucket, returns true if the key existed
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<bool> {
+        self.bitcasky.delete(self.bucket_key(key))
+    }
+
+    /// Iterates keys in `[start, end)` of this bucket in lexicographic order along with their
+    /// values, like `Bitcasky::scan`
+    pub fn scan<K: AsRef<[u8]>>(&self, start: K, end: K) -> BitcaskyResult<ScanIter> {
+        Ok(self
+            .bitcasky
+            .scan(self.bucket_key(start), self.bucket_key(end))?
+            .strip_key_prefix(self.prefix.len()))
+    }
+
+    /// Iterates keys starting with the prefix in this bucket in lexicographic order along with
+    /// their values
+    pub fn scan_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> BitcaskyResult<ScanIter> {
+        Ok(self
+            .bitcasky
+            .scan_prefix(self.bucket_key(prefix))?
+            .strip_key_prefix(self.prefix.len()))
+    }
+
+    /// Iterates all the keys and values in this bucket and apply each of them to the function f
+    pub fn foreach<F>(&self, mut f: F) -> BitcaskyResult<()>
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        for kv in self.scan_prefix(b"")? {
+            let (k, v) = kv?;
+            f(&k, &v);
+        }
+        Ok(())
+    }
+
+    /// Iterates all the keys in this bucket and apply each of them to the function f
+    pub fn foreach_key<F>(&self, mut f: F) -> BitcaskyResult<()>
+    where
+        F: FnMut(&[u8]),
+    {
+        self.bitcasky
+            .foreach_key_with_prefix(&self.prefix, |k| f(&k[self.prefix.len()..]))
+    }
+
+    /// Returns how many keys in this bucket and the bytes they take in data files
+    pub fn stats(&self) -> BitcaskyResult<BucketStats> {
+        self.bitcasky.prefix_stats(&self.prefix)
+    }
+
+    fn bucket_key<K: AsRef<[u8]>>(&self, key: K) -> Vec<u8> {
+        let mut bucket_key = Vec::with_capacity(self.prefix.len() + key.as_ref().len());
+        bucket_key.extend_from_slice(&self.prefix);
+        bucket_key.extend_from_slice(key.as_ref());
+        bucket_key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn test_bucket_prefix() {
+        assert_eq!(vec![0, 3, b'a', b'b', b'c'], bucket_prefix(b"abc").unwrap());
+        assert_eq!(vec![0, 0], bucket_prefix(b"").unwrap());
+        // prefix of a bucket is never a prefix of another bucket
+        assert!(!bucket_prefix(b"ab")
+            .unwrap()
+            .starts_with(&bucket_prefix(b"a").unwrap()));
+        assert!(matches!(
+            bucket_prefix(&vec![b'a'; u16::MAX as usize + 1]),
+            Err(BitcaskyError::InvalidParameter(_, _))
+        ));
+    }
+}
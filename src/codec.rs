@@ -0,0 +1,24 @@
+//! Hook to transform values before they are written to data files, e.g. to encrypt them.
+//!
+//! The codec configured by [`BitcaskyOptions::value_codec`](crate::options::BitcaskyOptions::value_codec)
+//! is applied to value bytes of every row written to data files, and reversed on every read.
+//! Keys are stored as is, hint files only store keys and offsets so they are not affected.
+//! Checksums are computed over encoded bytes, so integrity can be checked without the codec.
+//!
+//! A database must always be opened with the same codec it was written with.
+
+use std::fmt::Debug;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct ValueCodecError(pub String);
+
+pub trait ValueCodec: Debug + Send + Sync {
+    /// Transforms a value before it's written to data file
+    fn encode(&self, value: &[u8]) -> Vec<u8>;
+
+    /// Restores a value encoded by `encode`
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, ValueCodecError>;
+}
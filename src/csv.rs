@@ -0,0 +1,125 @@
+//! CSV format of `Bitcasky::export_to_csv` and `Bitcasky::import_from_csv`. Each row is
+//! `key_hex,value_hex,timestamp`, keys and values are hex encoded as they are arbitrary bytes.
+
+use std::io::{BufRead, Write};
+
+use crate::error::{BitcaskyError, BitcaskyResult};
+
+const HEADER: &str = "key_hex,value_hex,timestamp";
+
+pub(crate) fn write_header(writer: &mut impl Write) -> BitcaskyResult<()> {
+    writeln!(writer, "{}", HEADER)?;
+    Ok(())
+}
+
+pub(crate) fn write_row(
+    writer: &mut impl Write,
+    key: &[u8],
+    value: &[u8],
+    timestamp: u64,
+) -> BitcaskyResult<()> {
+    writeln!(
+        writer,
+        "{},{},{}",
+        encode_hex(key),
+        encode_hex(value),
+        timestamp
+    )?;
+    Ok(())
+}
+
+/// Parses each row in the reader and applies it to the function f, until the reader is
+/// exhausted or f fails. The header row and empty lines are skipped. Returns `InvalidCsvData`
+/// with the line number, starting from 1, on the first malformed row.
+pub(crate) fn read_rows<F>(mut reader: impl BufRead, mut f: F) -> BitcaskyResult<()>
+where
+    F: FnMut(Vec<u8>, Vec<u8>, u64) -> BitcaskyResult<()>,
+{
+    let mut line = Vec::new();
+    let mut line_number = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        line_number += 1;
+        let text = std::str::from_utf8(&line)
+            .map_err(|e| invalid_csv(line_number, format!("invalid UTF-8: {}", e)))?
+            .trim_end_matches(['\n', '\r']);
+        if text.is_empty() || (line_number == 1 && text == HEADER) {
+            continue;
+        }
+
+        let (key, value, timestamp) =
+            parse_row(text).map_err(|reason| invalid_csv(line_number, reason))?;
+        f(key, value, timestamp)?;
+    }
+}
+
+fn invalid_csv(line_number: u64, reason: String) -> BitcaskyError {
+    BitcaskyError::InvalidCsvData(line_number, reason)
+}
+
+fn parse_row(text: &str) -> Result<(Vec<u8>, Vec<u8>, u64), String> {
+    let fields: Vec<&str> = text.split(',').collect();
+    if fields.len() != 3 {
+        return Err(format!("expect 3 columns, found {}", fields.len()));
+    }
+    let key = decode_hex(fields[0]).map_err(|e| format!("invalid key: {}", e))?;
+    let value = decode_hex(fields[1]).map_err(|e| format!("invalid value: {}", e))?;
+    let timestamp = fields[2]
+        .parse::<u64>()
+        .map_err(|e| format!("invalid timestamp: \"{}\", {}", fields[2], e))?;
+    Ok((key, value, timestamp))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        s.push(DIGITS[(b >> 4) as usize] as char);
+        s.push(DIGITS[(b & 0xf) as usize] as char);
+    }
+    s
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    if s.len() % 2 == 1 {
+        return Err(format!("odd number of hex digits: {}", s.len()));
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_| "non-ASCII hex digit".to_string())?;
+            u8::from_str_radix(pair, 16).map_err(|_| format!("invalid hex digits: \"{}\"", pair))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let s = encode_hex(&bytes);
+        assert_eq!(512, s.len());
+        assert_eq!(bytes, decode_hex(&s).unwrap());
+        assert_eq!(vec![0xab, 0xcd], decode_hex("ABcd").unwrap());
+        assert!(decode_hex("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid_row() {
+        assert_eq!(
+            (b"k".to_vec(), b"v".to_vec(), 100),
+            parse_row("6b,76,100").unwrap()
+        );
+        assert!(parse_row("6b,76").unwrap_err().contains("3 columns"));
+        assert!(parse_row("6b,7,100").unwrap_err().contains("value"));
+        assert!(parse_row("zz,76,100").unwrap_err().contains("key"));
+        assert!(parse_row("6b,76,-1").unwrap_err().contains("timestamp"));
+        assert!(parse_row("6bé,76,1").unwrap_err().contains("key"));
+    }
+}
@@ -1,22 +1,35 @@
 use crate::formatter::FormatterError;
 use crate::tombstone::is_tombstone;
-use crate::{storage_id::StorageId, tombstone::TOMBSTONE_VALUE};
+use crate::{
+    storage_id::{StorageId, StorageIdOverflowError},
+    tombstone::TOMBSTONE_VALUE,
+};
 use std::ops::Deref;
 use thiserror::Error;
 
-use crate::database::DataStorageError;
+use crate::database::{DataStorageError, Database};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RowLocation {
     pub storage_id: StorageId,
     pub row_offset: usize,
     pub row_size: usize,
 }
 
+impl RowLocation {
+    /// Whether the row is in the writing file of the database rather than a stable file
+    pub fn is_in_writing_file(&self, database: &Database) -> bool {
+        database.is_writing_storage_id(self.storage_id)
+    }
+}
+
 #[derive(Debug)]
 pub struct TimedValue<V: AsRef<[u8]>> {
     pub value: V,
     pub expire_timestamp: u64,
+    /// Milliseconds since epoch when the value is written, 0 if unknown
+    pub write_timestamp: u64,
 }
 
 impl<V: AsRef<[u8]>> TimedValue<V> {
@@ -54,6 +67,7 @@ impl<V: AsRef<[u8]>> TimedValue<V> {
         TimedValue {
             value,
             expire_timestamp: 0,
+            write_timestamp: 0,
         }
     }
 
@@ -61,6 +75,21 @@ impl<V: AsRef<[u8]>> TimedValue<V> {
         TimedValue {
             value,
             expire_timestamp,
+            write_timestamp: 0,
+        }
+    }
+
+    pub fn with_write_timestamp(mut self, write_timestamp: u64) -> TimedValue<V> {
+        self.write_timestamp = write_timestamp;
+        self
+    }
+
+    /// Borrows the value along with its timestamps, so it can be written without cloning it
+    pub fn as_slice(&self) -> TimedValue<&[u8]> {
+        TimedValue {
+            value: self.value.as_ref(),
+            expire_timestamp: self.expire_timestamp,
+            write_timestamp: self.write_timestamp,
         }
     }
 }
@@ -88,10 +117,16 @@ pub enum DatabaseError {
     DatabaseBroken(String),
     #[error("Hint file with file id {1} under path {2} corrupted")]
     HintFileCorrupted(#[source] FormatterError, u32, String),
+    #[error("Hint file with file id {0} is written by formatter version {1} which does not keep deleted keys")]
+    HintFileOutdated(u32, u8),
+    #[error("Row at offset {1} in data file with id {0} is beyond durable offset {2}")]
+    RowNotDurable(u32, usize, usize),
     #[error("Read non-existent file with id {0}")]
     TargetFileIdNotFound(u32),
     #[error(transparent)]
     StorageError(#[from] DataStorageError),
+    #[error(transparent)]
+    StorageIdOverflow(#[from] StorageIdOverflowError),
 }
 
 pub type DatabaseResult<T> = Result<T, DatabaseError>;
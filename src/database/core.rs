@@ -1,44 +1,86 @@
 use std::{
     cell::Cell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     mem,
     path::{Path, PathBuf},
-    sync::Arc,
-    thread::{self, JoinHandle},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
-use crossbeam_channel::{select, Receiver, Sender};
-use dashmap::{mapref::one::RefMut, DashMap};
-use parking_lot::{Mutex, MutexGuard};
+use crossbeam_channel::{Receiver, Sender};
+use fail::fail_point;
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use std::ops::Deref;
 
-use crate::options::{BitcaskyOptions, SyncStrategy};
+#[cfg(feature = "instrument-locks")]
+use crate::lock_stats::{LockStat, LockStats};
+#[cfg(any(feature = "sync-worker", not(unix)))]
+use crate::options::SyncStrategy;
+use crate::options::{BitcaskyOptions, RecoveryProgressCallback};
 use crate::{
     clock::Clock,
-    formatter::{BitcaskyFormatter, RowToWrite},
+    formatter::{self, BitcaskyFormatter, FormatterV3, RowToWrite},
     fs::{self as SelfFs, FileType},
+    lock_stats::{LockTimer, TimedGuard, TimedMutex},
+    maintenance::{MaintenancePool, MaintenanceQueue},
     storage_id::{StorageId, StorageIdGenerator},
 };
 
 use crate::database::{
     common::{DatabaseError, DatabaseResult},
     data_storage::DataStorageTelemetry,
+    erlang_hint,
     hint::{self, HintWriter},
+    integrity::{self, VerifyReport},
 };
 
-use log::{debug, error, info, trace, warn};
+#[cfg(any(feature = "sync-worker", not(unix)))]
+use crate::logging::error;
+#[cfg(feature = "sync-worker")]
+use crate::logging::trace;
+use crate::logging::{debug, info, warn, OperationSpan};
+use crate::maintenance::PeriodicTask;
+use crate::merge::rate_limiter::RateLimiter;
 
 use super::{
     common::{RecoveredRow, TimedValue},
-    data_storage::{DataStorage, DataStorageReader, DataStorageWriter, StorageIter},
+    data_storage::{DataStorage, DataStorageReader, DataStorageWriter, StorageIter, ValueReader},
     DataStorageError,
 };
 use super::{
     common::{RowLocation, RowToRead},
+    file_pin::{self, FilePinSet, PinGuard},
     hint::HintFile,
+    stable_storages::StableStorages,
+    throughput::ThroughputMeter,
 };
 
+/// Threads of the maintenance pool created for a database when no pool is given in options
+#[cfg(not(feature = "small-footprint"))]
+const DEFAULT_MAINTENANCE_THREADS: usize = 2;
+#[cfg(feature = "small-footprint")]
+const DEFAULT_MAINTENANCE_THREADS: usize = 1;
+
+/// How often data files whose purge was deferred by pins are checked to be deleted
+const DEFERRED_PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Result of `Database::copy_row_to`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowCopy {
+    /// Location of the row copied
+    Copied(RowLocation),
+    /// The row expired so it's not copied
+    Expired,
+    /// Rows in the data file are encoded differently from rows written by the destination, so
+    /// the row has to be read and written again
+    Unsupported,
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StorageAggregatedTelemetry {
     pub total_data_capacity: usize,
     pub total_data_size: usize,
@@ -47,6 +89,10 @@ pub struct StorageAggregatedTelemetry {
     pub total_read_value_times: u64,
     pub total_write_times: u64,
     pub total_dead_bytes: usize,
+    /// How many times data files are reopened after closed to keep open files under
+    /// `max_open_files`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub total_reopen_times: u64,
 }
 
 /**
@@ -54,11 +100,74 @@ pub struct StorageAggregatedTelemetry {
  * Some of the metrics may not accurate due to concurrent access.
  */
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DatabaseTelemetry {
     pub writing_storage: DataStorageTelemetry,
     pub stable_storages: HashMap<StorageId, DataStorageTelemetry>,
     pub storage_aggregate: StorageAggregatedTelemetry,
     pub hint_file_writer: hint::HintWriterTelemetry,
+    /// How many data files can still be created before storage ids run out
+    pub remaining_storage_ids: u64,
+    /// Bytes of rows in data files on open plus bytes written since then, including the ones
+    /// rewritten by merge
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub total_written_bytes: u64,
+    /// Total written bytes divided by live bytes, which are bytes of rows referenced by keydir.
+    /// 0 if there's no live bytes or it's not computed with keydir
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub write_amplification_ratio: f64,
+    /// Bytes of rows in data files divided by live bytes. 0 if there's no live bytes or it's
+    /// not computed with keydir
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub space_amplification_ratio: f64,
+    /// How many data files of stable storages are open
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub open_stable_files: usize,
+    /// Data files purged by merge but kept until pins on them, like by snapshots, are released
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub deferred_purges: usize,
+}
+
+impl DatabaseTelemetry {
+    /// Computes amplification ratios with bytes of rows referenced by keydir
+    pub(crate) fn with_live_bytes(mut self, live_bytes: u64) -> DatabaseTelemetry {
+        if live_bytes > 0 {
+            self.write_amplification_ratio = self.total_written_bytes as f64 / live_bytes as f64;
+            self.space_amplification_ratio =
+                self.storage_aggregate.total_data_size as f64 / live_bytes as f64;
+        }
+        self
+    }
+}
+
+/// How data files were recovered when rebuilding keydir
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecoveryStats {
+    /// True when all the stable data files were recovered from their hint files
+    pub recovered_from_hint: bool,
+    pub hint_files: usize,
+    pub data_files: usize,
+    /// True when keys were kept by a persistent keydir backend and no data file was recovered
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub recovered_from_keydir_backend: bool,
+}
+
+/// Progress of rebuilding keydir from data files, reported to `on_recovery_progress` each time
+/// a data file is recovered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    /// Id of the data file just recovered
+    pub storage_id: StorageId,
+    /// Size of data in the data files recovered so far, including the one just recovered
+    pub processed_bytes: u64,
+    /// Size of data in all the data files to recover
+    pub total_bytes: u64,
+    pub recovered_files: usize,
+    pub total_files: usize,
+    /// Rows recovered so far. Rows of a key overwritten in the same file may be skipped by
+    /// parallel recovery
+    pub recovered_rows: u64,
 }
 
 #[derive(Debug)]
@@ -67,18 +176,84 @@ pub struct StorageIds {
     pub writing_storage_id: StorageId,
 }
 
+/// Snapshot of how much of the writing storage is flushed to disk.
+/// Rows in any storage other than the writing storage are always durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DurabilityState {
+    pub writing_file: StorageId,
+    pub synced_offset: u64,
+    pub written_offset: u64,
+}
+
+impl DurabilityState {
+    pub fn is_durable(&self, location: &RowLocation) -> bool {
+        if location.storage_id != self.writing_file {
+            return true;
+        }
+        self.synced_offset >= (location.row_offset + location.row_size) as u64
+    }
+}
+
+/// Wakes up threads waiting for writing storage to be flushed
+#[derive(Debug, Default)]
+struct SyncListener {
+    epoch: Mutex<u64>,
+    cond: Condvar,
+}
+
+impl SyncListener {
+    fn epoch(&self) -> u64 {
+        *self.epoch.lock()
+    }
+
+    fn notify(&self) {
+        *self.epoch.lock() += 1;
+        self.cond.notify_all();
+    }
+
+    /// Wait until a flush happened after `epoch` was read or the deadline reached.
+    /// Returns false if timeout.
+    fn wait(&self, epoch: u64, deadline: Instant) -> bool {
+        let mut current = self.epoch.lock();
+        while *current == epoch {
+            if self.cond.wait_until(&mut current, deadline).timed_out() {
+                return *current != epoch;
+            }
+        }
+        true
+    }
+}
+
 #[derive(Debug)]
 pub struct Database {
     pub database_dir: PathBuf,
     storage_id_generator: Arc<StorageIdGenerator>,
-    writing_storage: Arc<Mutex<DataStorage>>,
-    stable_storages: DashMap<StorageId, Mutex<DataStorage>>,
+    writing_storage: Arc<TimedMutex<DataStorage>>,
+    stable_storages: StableStorages,
+    /// Shared by the locks of all the stable storages
+    stable_storage_lock_timer: LockTimer,
+    /// Data files merged but not purged yet. Purge takes the write lock, so it waits for
+    /// reads in flight on these files.
+    pending_purge_storages: RwLock<HashMap<StorageId, Mutex<DataStorage>>>,
+    /// Data files pinned by snapshots are not deleted by purge until unpinned
+    file_pins: FilePinSet,
+    /// Task that periodically deletes data files whose purge was deferred by pins
+    _deferred_purge_task: PeriodicTask,
     options: Arc<BitcaskyOptions>,
     hint_file_writer: Option<HintWriter>,
-    /// Process that periodically flushes writing storage
+    /// Task that periodically flushes writing storage
+    #[cfg(feature = "sync-worker")]
     sync_worker: Option<SyncWorker>,
+    /// Background tasks of this database run on the maintenance pool through this queue
+    maintenance: Arc<MaintenanceQueue>,
     formatter: Arc<BitcaskyFormatter>,
     is_error: Mutex<Option<String>>,
+    /// Set when writes are rejected at runtime, reads are still served
+    read_only: AtomicBool,
+    /// Measures bytes written by writes, including the ones from merge
+    write_throughput: ThroughputMeter,
+    written_bytes: AtomicU64,
+    sync_listener: Arc<SyncListener>,
 }
 
 impl Database {
@@ -92,15 +267,39 @@ impl Database {
         debug!(target: "Database", "opening database at directory {:?}", directory);
 
         hint::clear_temp_hint_file_directory(&database_dir);
+        file_pin::clear_deferred_purge_directory(&database_dir);
 
-        let data_storage_ids = SelfFs::get_storage_ids_in_dir(&database_dir, FileType::DataFile);
+        let data_files = SelfFs::list_storage_files(
+            &database_dir,
+            options.database.storage.format_compat.data_file_types(),
+        )?;
+        super::clear_temp_data_files(&data_files.leftovers);
+        let data_storage_ids = data_files.storage_ids;
         if let Some(id) = data_storage_ids.iter().max() {
-            storage_id_generator.update_id(*id);
+            storage_id_generator.update_id(*id)?;
         }
 
-        let hint_file_writer = Some(HintWriter::start(&database_dir, options.clone()));
-
-        let formatter = Arc::new(BitcaskyFormatter::default());
+        let maintenance_pool = options
+            .maintenance_pool
+            .clone()
+            .unwrap_or_else(|| Arc::new(MaintenancePool::new(DEFAULT_MAINTENANCE_THREADS)));
+        let maintenance = Arc::new(maintenance_pool.queue());
+
+        let hint_file_writer = options
+            .database
+            .write_hint_files
+            .then(|| HintWriter::start(&database_dir, maintenance.clone(), options.clone()));
+
+        let formatter = match options.database.storage.row_formatter {
+            Some(f) => {
+                formatter::register_row_formatter(f).map_err(DataStorageError::from)?;
+                BitcaskyFormatter::custom(f)
+            }
+            None => BitcaskyFormatter::V3(FormatterV3::with_checksum_algorithm(
+                options.database.storage.checksum_algorithm,
+            )),
+        };
+        let formatter = Arc::new(formatter);
         let (writing_storage, storages) = prepare_db_storages(
             &database_dir,
             &data_storage_ids,
@@ -109,73 +308,111 @@ impl Database {
             options.clone(),
         )?;
 
-        let stable_storages = storages.into_iter().fold(DashMap::new(), |m, s| {
-            m.insert(s.storage_id(), Mutex::new(s));
-            m
-        });
+        let stable_storage_lock_timer = LockTimer::default();
+        let stable_storages = storages.into_iter().fold(
+            StableStorages::new(options.database.max_open_files),
+            |m, s| {
+                m.insert(
+                    s.storage_id(),
+                    TimedMutex::new(s, stable_storage_lock_timer.clone()),
+                );
+                m
+            },
+        );
 
-        let writing_storage = Arc::new(Mutex::new(writing_storage));
-        let mut db = Database {
+        let writing_storage = Arc::new(TimedMutex::new(writing_storage, LockTimer::default()));
+        let sync_listener = Arc::new(SyncListener::default());
+        #[cfg(feature = "sync-worker")]
+        let sync_worker = SyncWorker::start(
+            &options.database.sync_strategy,
+            &maintenance,
+            writing_storage.clone(),
+            sync_listener.clone(),
+        );
+        let file_pins = FilePinSet::default();
+        let deferred_purge_task = {
+            let file_pins = file_pins.clone();
+            let database_dir = database_dir.clone();
+            maintenance.schedule(DEFERRED_PURGE_INTERVAL, move || {
+                file_pins.purge_released(&database_dir);
+            })
+        };
+        let db = Database {
             writing_storage,
+            stable_storages,
+            stable_storage_lock_timer,
+            pending_purge_storages: RwLock::new(HashMap::new()),
+            file_pins,
+            _deferred_purge_task: deferred_purge_task,
             storage_id_generator,
             database_dir,
-            stable_storages,
             options: options.clone(),
             hint_file_writer,
-            sync_worker: None,
+            #[cfg(feature = "sync-worker")]
+            sync_worker,
+            maintenance,
             formatter,
             is_error: Mutex::new(None),
+            read_only: AtomicBool::new(false),
+            write_throughput: ThroughputMeter::default(),
+            written_bytes: AtomicU64::new(0),
+            sync_listener,
         };
+        db.written_bytes.store(
+            db.get_telemetry_data().storage_aggregate.total_data_size as u64,
+            Ordering::Relaxed,
+        );
 
-        if let SyncStrategy::Interval(interval) = options.database.sync_strategy {
-            let secs = interval.as_secs();
-            if secs > 0 {
-                db.sync_worker = Some(SyncWorker::start_sync_worker(
-                    db.writing_storage.clone(),
-                    secs,
-                ));
-            }
+        if options.database.rebuild_hint_files_on_open {
+            db.rebuild_hint_files(false, false)?;
         }
 
         info!(target: "Database", "database opened at directory: {:?}, with {} data files", directory, data_storage_ids.len());
         Ok(db)
     }
 
+    pub fn file_pins(&self) -> &FilePinSet {
+        &self.file_pins
+    }
+
+    pub fn maintenance_queue(&self) -> &Arc<MaintenanceQueue> {
+        &self.maintenance
+    }
+
     pub fn get_database_dir(&self) -> &Path {
         &self.database_dir
     }
 
-    pub fn get_max_storage_id(&self) -> StorageId {
+    /// Storage id of the writing file, which is the largest storage id in the database. It
+    /// changes when the writing file is rotated.
+    pub fn writing_storage_id(&self) -> StorageId {
         let writing_file_ref = self.writing_storage.lock();
         writing_file_ref.storage_id()
     }
 
+    /// Whether the storage id is of the writing file rather than a stable file. The writing
+    /// file may be rotated right after this returns, so readers of the writing file check it
+    /// under the writing storage lock instead.
+    pub fn is_writing_storage_id(&self, storage_id: StorageId) -> bool {
+        self.writing_storage_id() == storage_id
+    }
+
     pub fn write<K: AsRef<[u8]>, V: AsRef<[u8]>>(
         &self,
         key: K,
         value: TimedValue<V>,
     ) -> DatabaseResult<RowLocation> {
-        let ts = value.expire_timestamp;
-        let row: RowToWrite<K, TimedValue<V>> = RowToWrite::new_with_timestamp(key, value, ts);
-        let mut writing_storage_ref = self.writing_storage.lock();
+        self.do_write(key, value, false)
+    }
 
-        match writing_storage_ref.write_row(&row) {
-            Err(DataStorageError::StorageOverflow(id)) => {
-                debug!("Flush writing storage with id: {} on overflow", id);
-                self.do_flush_writing_file(&mut writing_storage_ref)?;
-                Ok(writing_storage_ref.write_row(&row)?)
-            }
-            r => {
-                let ret = r?;
-                #[cfg(not(unix))]
-                if let SyncStrategy::OSync = self.options.database.sync_strategy {
-                    if let Err(e) = self.sync() {
-                        error!(target: "Database", "flush database failed: {}", e);
-                    }
-                };
-                Ok(ret)
-            }
-        }
+    /// Write a row like `write` and flush the storage which received this row before return.
+    /// Any flush error is returned to caller instead of being left to the background sync worker.
+    pub fn write_sync<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: TimedValue<V>,
+    ) -> DatabaseResult<RowLocation> {
+        self.do_write(key, value, true)
     }
 
     pub fn add_dead_bytes(&self, storage_id: StorageId, dead_bytes: usize) {
@@ -187,8 +424,22 @@ impl Database {
         }
     }
 
+    /// Recalculate dead bytes of every storage by bytes of rows still referenced by keydir
+    pub fn reset_dead_bytes(&self, live_bytes: &HashMap<StorageId, usize>) {
+        let mut writing_storage_ref = self.writing_storage.lock();
+        let writing_storage_id = writing_storage_ref.storage_id();
+        writing_storage_ref.reset_dead_bytes(*live_bytes.get(&writing_storage_id).unwrap_or(&0));
+        self.stable_storages.for_each(|s| {
+            let mut storage = s.lock();
+            let live = *live_bytes.get(&storage.storage_id()).unwrap_or(&0);
+            storage.reset_dead_bytes(live);
+        });
+    }
+
     pub fn flush_writing_file(&self) -> DatabaseResult<()> {
+        let span = OperationSpan::flush();
         let mut writing_file_ref = self.writing_storage.lock();
+        span.record_storage_id(writing_file_ref.storage_id());
         debug!(
             "Flush writing file with id: {}",
             writing_file_ref.storage_id()
@@ -205,29 +456,62 @@ impl Database {
             let writing_storage = self.writing_storage.lock();
             let writing_storage_id = writing_storage.storage_id();
 
-            storage_ids = self
-                .stable_storages
-                .iter()
-                .map(|f| f.lock().storage_id())
-                .collect::<Vec<StorageId>>();
+            storage_ids = self.stable_storages.storage_ids();
             storage_ids.push(writing_storage_id);
             storage_ids.sort();
             storage_ids.reverse();
         }
-        DatabaseRecoverIter::new(self.database_dir.clone(), storage_ids, self.options.clone())
+        let hint_files = storage_ids
+            .iter()
+            .filter(|id| {
+                FileType::HintFile
+                    .get_path(&self.database_dir, Some(**id))
+                    .exists()
+                    || FileType::ErlangHintFile
+                        .get_path(&self.database_dir, Some(**id))
+                        .exists()
+            })
+            .count();
+        let recovery_stats = RecoveryStats {
+            // writing storage never has a hint file
+            recovered_from_hint: storage_ids.len() > 1 && hint_files == storage_ids.len() - 1,
+            hint_files,
+            data_files: storage_ids.len() - hint_files,
+            recovered_from_keydir_backend: false,
+        };
+        let progress = self.options.on_recovery_progress.clone().map(|callback| {
+            let data_sizes = self
+                .data_files_state()
+                .into_iter()
+                .map(|(id, size)| (id, size as u64))
+                .collect::<HashMap<StorageId, u64>>();
+            ProgressReporter::new(callback, data_sizes, storage_ids.len())
+        });
+        DatabaseRecoverIter::new(
+            &self.maintenance,
+            self.database_dir.clone(),
+            storage_ids,
+            recovery_stats,
+            progress,
+            self.options.clone(),
+        )
     }
 
+    /// Iterates rows in all the storages. Rows written before the iterator is created are
+    /// iterated exactly once, rows written after that are not iterated, even if the writing
+    /// storage rotates during iteration.
     pub fn iter(&self) -> DatabaseResult<DatabaseIter> {
         let mut storage_ids: Vec<StorageId>;
+        let writing_storage_id;
+        let writing_end_offset;
         {
-            let writing_storage = self.writing_storage.lock();
-            let writing_storage_id = writing_storage.storage_id();
+            let mut writing_storage = self.writing_storage.lock();
+            writing_storage_id = writing_storage.storage_id();
+            // the writing file is iterated through another handle
+            writing_storage.write_buffered_rows()?;
+            writing_end_offset = writing_storage.offset();
 
-            storage_ids = self
-                .stable_storages
-                .iter()
-                .map(|f| f.lock().storage_id())
-                .collect::<Vec<StorageId>>();
+            storage_ids = self.stable_storages.storage_ids();
             storage_ids.push(writing_storage_id);
         }
 
@@ -241,27 +525,211 @@ impl Database {
 
         let mut opened_stable_files = files?;
         opened_stable_files.sort_by_key(|e| e.storage_id());
-        let iters: crate::database::data_storage::Result<Vec<StorageIter>> =
-            opened_stable_files.iter().rev().map(|f| f.iter()).collect();
+        let iters: crate::database::data_storage::Result<Vec<StorageIter>> = opened_stable_files
+            .iter()
+            .rev()
+            .map(|f| {
+                let iter = f.iter()?;
+                if f.storage_id() == writing_storage_id {
+                    Ok(iter.end_at(writing_end_offset))
+                } else {
+                    Ok(iter)
+                }
+            })
+            .collect();
 
         Ok(DatabaseIter::new(iters?))
     }
 
+    /// Iterates rows in a stable storage
+    pub fn stable_storage_iter(&self, storage_id: StorageId) -> DatabaseResult<StorageIter> {
+        let storage = self.get_file_to_read(storage_id)?;
+        let iter = storage.lock().iter()?;
+        Ok(iter)
+    }
+
     pub fn read_value(
         &self,
         row_location: &RowLocation,
     ) -> DatabaseResult<Option<TimedValue<Vec<u8>>>> {
+        self.read_value_at(row_location, self.options.clock.now())
+    }
+
+    /// Reads value like `read_value`, but the value is taken as expired only if it expired at
+    /// `now` instead of the current time
+    pub fn read_value_at(
+        &self,
+        row_location: &RowLocation,
+        now: u64,
+    ) -> DatabaseResult<Option<TimedValue<Vec<u8>>>> {
+        {
+            let mut writing_file_ref = self.writing_storage.lock();
+            if row_location.storage_id == writing_file_ref.storage_id() {
+                return Ok(writing_file_ref.read_value_at(row_location.row_offset, now)?);
+            }
+        }
+
+        match self.get_file_to_read(row_location.storage_id) {
+            Ok(l) => {
+                let mut f = l.lock();
+                let ret = f.read_value_at(row_location.row_offset, now)?;
+                Ok(ret)
+            }
+            // the location may be got before merge applied
+            Err(e) => match self.read_pending_purge_value(row_location, now)? {
+                Some(ret) => Ok(ret),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Reads the expire timestamp of the row at the location without reading its key and value,
+    /// see `DataStorage::read_expire_timestamp`
+    pub fn read_expire_timestamp(&self, row_location: &RowLocation) -> DatabaseResult<u64> {
         {
             let mut writing_file_ref = self.writing_storage.lock();
             if row_location.storage_id == writing_file_ref.storage_id() {
-                return Ok(writing_file_ref.read_value(row_location.row_offset)?);
+                return Ok(writing_file_ref.read_expire_timestamp(row_location.row_offset)?);
             }
         }
 
+        match self.get_file_to_read(row_location.storage_id) {
+            Ok(l) => Ok(l.lock().read_expire_timestamp(row_location.row_offset)?),
+            // the location may be got before merge applied
+            Err(e) => {
+                let storages = self.pending_purge_storages.read();
+                match storages.get(&row_location.storage_id) {
+                    Some(storage) => Ok(storage
+                        .lock()
+                        .read_expire_timestamp(row_location.row_offset)?),
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Opens a reader over the value at the location, see `DataStorage::value_reader`
+    pub fn value_reader(&self, row_location: &RowLocation) -> DatabaseResult<Option<ValueReader>> {
+        {
+            let mut writing_file_ref = self.writing_storage.lock();
+            if row_location.storage_id == writing_file_ref.storage_id() {
+                return Ok(writing_file_ref.value_reader(row_location.row_offset)?);
+            }
+        }
+
+        match self.get_file_to_read(row_location.storage_id) {
+            Ok(l) => Ok(l.lock().value_reader(row_location.row_offset)?),
+            // the location may be got before merge applied
+            Err(e) => {
+                let storages = self.pending_purge_storages.read();
+                match storages.get(&row_location.storage_id) {
+                    Some(storage) => Ok(storage.lock().value_reader(row_location.row_offset)?),
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Copies the row at the location to the writing storage of `dest` without decoding it, see
+    /// `DataStorage::copy_row_to`. The row is not copied if it expired at `now`. `dest` has to be
+    /// another database.
+    pub fn copy_row_to(
+        &self,
+        row_location: &RowLocation,
+        now: u64,
+        dest: &Database,
+    ) -> DatabaseResult<RowCopy> {
+        {
+            let mut writing_file_ref = self.writing_storage.lock();
+            if row_location.storage_id == writing_file_ref.storage_id() {
+                return dest.copy_row_from(&mut writing_file_ref, row_location, now);
+            }
+        }
         let l = self.get_file_to_read(row_location.storage_id)?;
         let mut f = l.lock();
-        let ret = f.read_value(row_location.row_offset)?;
-        Ok(ret)
+        dest.copy_row_from(&mut f, row_location, now)
+    }
+
+    fn copy_row_from(
+        &self,
+        source: &mut DataStorage,
+        row_location: &RowLocation,
+        now: u64,
+    ) -> DatabaseResult<RowCopy> {
+        let mut writing_storage_ref = self.writing_storage.lock();
+        if !source.can_copy_rows_to(&writing_storage_ref) {
+            return Ok(RowCopy::Unsupported);
+        }
+        let meta = source.read_row_meta(row_location.row_offset)?;
+        if meta.expire_timestamp != 0 && meta.expire_timestamp <= now {
+            return Ok(RowCopy::Expired);
+        }
+        let (row_offset, row_size) = (row_location.row_offset, row_location.row_size);
+        let ret = match source.copy_row_to(row_offset, row_size, &mut writing_storage_ref) {
+            Err(DataStorageError::StorageOverflow(id)) => {
+                debug!("Flush writing storage with id: {} on overflow", id);
+                self.do_flush_writing_file(&mut writing_storage_ref)?;
+                source.copy_row_to(row_offset, row_size, &mut writing_storage_ref)?
+            }
+            r => r?,
+        };
+        self.write_throughput
+            .record(ret.row_size as u64, self.options.clock.now());
+        self.written_bytes
+            .fetch_add(ret.row_size as u64, Ordering::Relaxed);
+        Ok(RowCopy::Copied(ret))
+    }
+
+    /// Reads value like `read_value`, and returns true along with it if the value is in a data
+    /// file merged but not purged yet. Such value is expected to be moved to other data file
+    /// before the file is purged.
+    pub fn read_value_with_purge_state(
+        &self,
+        row_location: &RowLocation,
+    ) -> DatabaseResult<(Option<TimedValue<Vec<u8>>>, bool)> {
+        match self.read_pending_purge_value(row_location, self.options.clock.now())? {
+            Some(ret) => Ok((ret, true)),
+            None => Ok((self.read_value(row_location)?, false)),
+        }
+    }
+
+    /// Returns None if the storage of the location is not pending purge
+    fn read_pending_purge_value(
+        &self,
+        row_location: &RowLocation,
+        now: u64,
+    ) -> DatabaseResult<Option<Option<TimedValue<Vec<u8>>>>> {
+        let storages = self.pending_purge_storages.read();
+        let Some(storage) = storages.get(&row_location.storage_id) else {
+            return Ok(None);
+        };
+        fail_point!("database::read_pending_purge_value");
+        let ret = storage.lock().read_value_at(row_location.row_offset, now)?;
+        Ok(Some(ret))
+    }
+
+    /// Keeps data files with these ids readable after they are removed from database by
+    /// `reload_data_files`, until `purge_pending_storages` is called
+    pub fn mark_pending_purge(&self, storage_ids: &[StorageId]) -> DatabaseResult<()> {
+        let mut storages = self.pending_purge_storages.write();
+        for storage_id in storage_ids {
+            let storage = DataStorage::open(&self.database_dir, *storage_id, self.options.clone())?;
+            storages.insert(*storage_id, Mutex::new(storage));
+        }
+        debug!(target: "Database", "data files with ids: {:?} are pending purge", storage_ids);
+        Ok(())
+    }
+
+    /// Closes data files pending purge after reads in flight on them finished, so they can be
+    /// deleted
+    pub fn purge_pending_storages(&self) {
+        let storage_ids = self
+            .pending_purge_storages
+            .write()
+            .drain()
+            .map(|(id, _)| id)
+            .collect::<Vec<StorageId>>();
+        debug!(target: "Database", "closed data files pending purge with ids: {:?}", storage_ids);
     }
 
     pub fn reload_data_files(&self, data_storage_ids: Vec<StorageId>) -> DatabaseResult<()> {
@@ -281,6 +749,7 @@ impl Database {
             );
             let _ = mem::replace(&mut *writing_storage_ref, writing);
         }
+        self.sync_listener.notify();
 
         self.stable_storages.clear();
 
@@ -289,7 +758,10 @@ impl Database {
                 core::panic!("file id: {} already loaded in database", s.storage_id());
             }
             debug!("reload stable file with id: {}", s.storage_id());
-            self.stable_storages.insert(s.storage_id(), Mutex::new(s));
+            self.stable_storages.insert(
+                s.storage_id(),
+                TimedMutex::new(s, self.stable_storage_lock_timer.clone()),
+            );
         }
         Ok(())
     }
@@ -297,28 +769,155 @@ impl Database {
     pub fn get_storage_ids(&self) -> StorageIds {
         let writing_file_ref = self.writing_storage.lock();
         let writing_storage_id = writing_file_ref.storage_id();
-        let stable_storage_ids: Vec<StorageId> = self
-            .stable_storages
-            .iter()
-            .map(|f| f.value().lock().storage_id())
-            .collect();
+        let stable_storage_ids = self.stable_storages.storage_ids();
         StorageIds {
             stable_storage_ids,
             writing_storage_id,
         }
     }
 
+    /// Verifies checksum of every row in all the data files. Stable files removed by a concurrent
+    /// merge are skipped. Writing file is verified at last with writes blocked.
+    pub fn verify(&self) -> DatabaseResult<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut stable_storage_ids = self.get_storage_ids().stable_storage_ids;
+        stable_storage_ids.sort();
+        for storage_id in stable_storage_ids {
+            let path = FileType::DataFile.get_path(&self.database_dir, Some(storage_id));
+            if !path.exists() {
+                continue;
+            }
+            let mut file_report = VerifyReport::default();
+            integrity::verify_data_file(
+                &self.database_dir,
+                storage_id,
+                self.options.clone(),
+                &mut file_report,
+            )?;
+            // the data file may be removed by merge after the check above and fail to open
+            if !path.exists() {
+                continue;
+            }
+            report.good_rows += file_report.good_rows;
+            report.bad_rows += file_report.bad_rows;
+            report.bad_locations.extend(file_report.bad_locations);
+        }
+
+        let mut writing_storage = self.writing_storage.lock();
+        writing_storage.write_buffered_rows()?;
+        integrity::verify_data_file(
+            &self.database_dir,
+            writing_storage.storage_id(),
+            self.options.clone(),
+            &mut report,
+        )?;
+        debug!(target: "Database", "verified database, {} good rows, {} bad rows", report.good_rows, report.bad_rows);
+        Ok(report)
+    }
+
+    /// Opens the storages with given ids for reading. The returned storages are independent
+    /// of those held by this database, so they stay readable after merge renames the underlying
+    /// data files. They are pinned in `file_pins`, so purge does not delete them until dropped.
+    pub fn pin_storages(&self, storage_ids: &[StorageId]) -> DatabaseResult<PinnedStorages> {
+        let pins = storage_ids
+            .iter()
+            .map(|id| self.file_pins.pin(*id))
+            .collect::<Vec<PinGuard>>();
+        let mut storages = HashMap::new();
+        {
+            let mut writing_storage = self.writing_storage.lock();
+            if storage_ids.contains(&writing_storage.storage_id()) {
+                writing_storage.write_buffered_rows()?;
+            }
+        }
+        for storage_id in storage_ids {
+            let storage = DataStorage::open(&self.database_dir, *storage_id, self.options.clone())?;
+            storages.insert(*storage_id, Mutex::new(storage));
+        }
+        debug!(target: "Database", "pinned data files with ids: {:?}", storage_ids);
+        Ok(PinnedStorages {
+            storages,
+            _pins: pins,
+        })
+    }
+
+    /// Writes hint files for stable storages which have no valid hint file, or for all of them
+    /// if `all` is true. Hint files are written on the maintenance pool unless `wait` is true.
+    /// Returns how many hint files are rebuilt, or are going to be rebuilt when not waiting.
+    /// Nothing is written when hint files are turned off by `write_hint_files`.
+    pub fn rebuild_hint_files(&self, wait: bool, all: bool) -> DatabaseResult<usize> {
+        let Some(hint_file_writer) = self.hint_file_writer.as_ref() else {
+            return Ok(0);
+        };
+        let mut rebuilt = 0;
+        for storage_id in self.get_storage_ids().stable_storage_ids {
+            if !all && self.has_valid_hint_file(storage_id) {
+                continue;
+            }
+            // rows in data files of Erlang bitcask are moved to data files of bitcasky by merge
+            if let BitcaskyFormatter::ErlangBitcask(_) =
+                self.get_file_to_read(storage_id)?.lock().formatter()
+            {
+                continue;
+            }
+            debug!(target: "Database", "rebuild hint file for data file with id: {}", storage_id);
+            let durable_offset = self
+                .get_file_to_read(storage_id)?
+                .lock()
+                .transit_to_readonly()?;
+            if wait {
+                HintWriter::write_hint_file(
+                    &self.database_dir,
+                    storage_id,
+                    durable_offset,
+                    self.options.clone(),
+                )?;
+            } else {
+                hint_file_writer.async_write_hint_file(storage_id, durable_offset);
+            }
+            rebuilt += 1;
+        }
+        Ok(rebuilt)
+    }
+
+    /// Removes hint files left behind by data files which no longer exist, and rewrites hint
+    /// files to drop rows covered by later hint files. Safe to call while the database is
+    /// serving reads and writes. Returns how many hint files are removed.
+    pub fn compact_hint_files(&self) -> DatabaseResult<usize> {
+        hint::compact_hint_files(&self.database_dir, &self.options)
+    }
+
+    /// Lock statistics of the storages. Keydir is not owned by database so its statistics
+    /// are left empty.
+    #[cfg(feature = "instrument-locks")]
+    pub fn lock_stats(&self) -> LockStats {
+        LockStats {
+            keydir: LockStat::default(),
+            writing_storage: self.writing_storage.stat(),
+            stable_storages: self.stable_storage_lock_timer.stat(),
+        }
+    }
+
+    fn has_valid_hint_file(&self, storage_id: StorageId) -> bool {
+        FileType::HintFile
+            .get_path(&self.database_dir, Some(storage_id))
+            .exists()
+            && HintFile::open_iterator(
+                &self.database_dir,
+                storage_id,
+                self.options.clock.now(),
+                self.options.clone(),
+            )
+            .is_ok()
+    }
+
     pub fn get_telemetry_data(&self) -> DatabaseTelemetry {
         let writing_storage = { self.writing_storage.lock().get_telemetry_data() };
-        let stable_storages: HashMap<StorageId, DataStorageTelemetry> = HashMap::from_iter(
-            self.stable_storages
-                .iter()
-                .map(|s| {
-                    let d = s.lock();
-                    (d.storage_id(), d.get_telemetry_data())
-                })
-                .collect::<Vec<_>>(),
-        );
+        let mut stable_storages: HashMap<StorageId, DataStorageTelemetry> = HashMap::new();
+        self.stable_storages.for_each(|s| {
+            let d = s.lock();
+            stable_storages.insert(d.storage_id(), d.get_telemetry_data());
+        });
 
         let total_telemetry =
             stable_storages
@@ -329,6 +928,7 @@ impl Database {
                     acc.dead_bytes += next.dead_bytes;
                     acc.read_value_times += next.read_value_times;
                     acc.write_times += next.write_times;
+                    acc.reopen_times += next.reopen_times;
                     acc
                 });
         let total_fragment = total_telemetry.dead_bytes as f64 / total_telemetry.data_size as f64;
@@ -341,6 +941,7 @@ impl Database {
             total_read_value_times: total_telemetry.read_value_times,
             total_write_times: total_telemetry.write_times,
             total_dead_bytes: total_telemetry.dead_bytes,
+            total_reopen_times: total_telemetry.reopen_times,
         };
         DatabaseTelemetry {
             hint_file_writer: self
@@ -351,6 +952,12 @@ impl Database {
             writing_storage,
             stable_storages,
             storage_aggregate,
+            remaining_storage_ids: self.storage_id_generator.remaining_ids(),
+            total_written_bytes: self.written_bytes.load(Ordering::Relaxed),
+            write_amplification_ratio: 0.0,
+            space_amplification_ratio: 0.0,
+            open_stable_files: self.stable_storages.open_files(),
+            deferred_purges: self.file_pins.deferred_purges().len(),
         }
     }
 
@@ -367,9 +974,19 @@ impl Database {
             // flush file only when we actually wrote something
             self.do_flush_writing_file(&mut writing_file_ref)?;
         }
-        for storage_id in self.stable_storages.iter().map(|v| v.lock().storage_id()) {
+        for storage_id in self.stable_storages.storage_ids() {
             SelfFs::delete_file(&self.database_dir, FileType::DataFile, Some(storage_id))?;
             SelfFs::delete_file(&self.database_dir, FileType::HintFile, Some(storage_id))?;
+            SelfFs::delete_file(
+                &self.database_dir,
+                FileType::ErlangDataFile,
+                Some(storage_id),
+            )?;
+            SelfFs::delete_file(
+                &self.database_dir,
+                FileType::ErlangHintFile,
+                Some(storage_id),
+            )?;
         }
         self.stable_storages.clear();
         Ok(())
@@ -378,14 +995,48 @@ impl Database {
     pub fn sync(&self) -> DatabaseResult<()> {
         let mut f = self.writing_storage.lock();
         f.flush()?;
+        self.sync_listener.notify();
         Ok(())
     }
 
+    /// Paces hint files written from now on by the rate limiter
+    pub(crate) fn set_hint_rate_limiter(&mut self, rate_limiter: Arc<RateLimiter>) {
+        if let Some(w) = self.hint_file_writer.as_mut() {
+            w.set_rate_limiter(rate_limiter);
+        }
+    }
+
+    /// Waits for pending hint files to be written. Returns false on timeout.
+    pub fn wait_for_hint_flush(&self, timeout: Duration) -> bool {
+        self.hint_file_writer
+            .as_ref()
+            .map(|w| w.flush_pending(timeout))
+            .unwrap_or(true)
+    }
+
     pub fn mark_db_error(&self, error_string: String) {
         let mut err = self.is_error.lock();
         *err = Some(error_string)
     }
 
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Release);
+    }
+
+    /// Adds bytes written to this database through other instances, like the one of merge
+    pub fn add_written_bytes(&self, bytes: u64) {
+        self.written_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the moving average of bytes written per second, None until it's measured
+    pub fn write_bytes_per_sec(&self) -> Option<f64> {
+        self.write_throughput.bytes_per_sec()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
     pub fn check_db_error(&self) -> Result<(), DatabaseError> {
         let err = self.is_error.lock();
         if err.is_some() {
@@ -394,9 +1045,97 @@ impl Database {
         Ok(())
     }
 
+    /// Storage id and data size of every data file with data, sorted by storage id. It changes
+    /// whenever rows are written, merged or purged.
+    pub fn data_files_state(&self) -> Vec<(StorageId, usize)> {
+        let mut state = vec![];
+        {
+            let writing_storage = self.writing_storage.lock();
+            state.push((
+                writing_storage.storage_id(),
+                writing_storage.get_telemetry_data().data_size,
+            ));
+        }
+        self.stable_storages.for_each(|s| {
+            let s = s.lock();
+            state.push((s.storage_id(), s.get_telemetry_data().data_size));
+        });
+        // a writing file without data may be created on open
+        state.retain(|(_, data_size)| *data_size > 0);
+        state.sort();
+        state
+    }
+
+    pub fn durability_state(&self) -> DurabilityState {
+        let writing_storage = self.writing_storage.lock();
+        DurabilityState {
+            writing_file: writing_storage.storage_id(),
+            synced_offset: writing_storage.synced_offset() as u64,
+            written_offset: writing_storage.offset() as u64,
+        }
+    }
+
+    /// Blocks until the row at `location` is flushed to disk, or the timeout elapsed.
+    /// Returns false on timeout.
+    pub fn wait_durable(&self, location: &RowLocation, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let epoch = self.sync_listener.epoch();
+            if self.durability_state().is_durable(location) {
+                return true;
+            }
+            if !self.sync_listener.wait(epoch, deadline) {
+                return false;
+            }
+        }
+    }
+
+    fn do_write<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: TimedValue<V>,
+        sync: bool,
+    ) -> DatabaseResult<RowLocation> {
+        let ts = value.expire_timestamp;
+        let write_ts = value.write_timestamp;
+        let row: RowToWrite<K, TimedValue<V>> =
+            RowToWrite::new_with_timestamp(key, value, ts).with_write_timestamp(write_ts);
+        let mut writing_storage_ref = self.writing_storage.lock();
+
+        let ret = match writing_storage_ref.write_row(&row) {
+            Err(DataStorageError::StorageOverflow(id)) => {
+                debug!("Flush writing storage with id: {} on overflow", id);
+                self.do_flush_writing_file(&mut writing_storage_ref)?;
+                writing_storage_ref.write_row(&row)?
+            }
+            r => r?,
+        };
+        self.write_throughput
+            .record(ret.row_size as u64, self.options.clock.now());
+        self.written_bytes
+            .fetch_add(ret.row_size as u64, Ordering::Relaxed);
+
+        // the row always lands in the current writing storage, even if it was just
+        // replaced on overflow, so flushing it covers the row we just wrote
+        if sync {
+            writing_storage_ref.flush()?;
+            self.sync_listener.notify();
+        } else {
+            #[cfg(not(unix))]
+            if let SyncStrategy::OSync = self.options.database.sync_strategy {
+                if let Err(e) = writing_storage_ref.flush() {
+                    error!(target: "Database", "flush database failed: {}", e);
+                } else {
+                    self.sync_listener.notify();
+                }
+            };
+        }
+        Ok(ret)
+    }
+
     fn do_flush_writing_file(
         &self,
-        writing_file_ref: &mut MutexGuard<DataStorage>,
+        writing_file_ref: &mut TimedGuard<MutexGuard<DataStorage>>,
     ) -> DatabaseResult<()> {
         if !writing_file_ref.is_dirty() {
             debug!(
@@ -405,7 +1144,7 @@ impl Database {
             );
             return Ok(());
         }
-        let next_storage_id = self.storage_id_generator.generate_next_id();
+        let next_storage_id = self.storage_id_generator.generate_next_id()?;
         let next_writing_file = DataStorage::new(
             &self.database_dir,
             next_storage_id,
@@ -413,12 +1152,16 @@ impl Database {
             self.options.clone(),
         )?;
         let mut old_storage = mem::replace(&mut **writing_file_ref, next_writing_file);
-        old_storage.flush()?;
+        // hint file can only be written after the data file is sealed and synced
+        let durable_offset = old_storage.transit_to_readonly()?;
         let storage_id = old_storage.storage_id();
-        self.stable_storages
-            .insert(storage_id, Mutex::new(old_storage));
+        self.stable_storages.insert(
+            storage_id,
+            TimedMutex::new(old_storage, self.stable_storage_lock_timer.clone()),
+        );
+        self.sync_listener.notify();
         if let Some(w) = self.hint_file_writer.as_ref() {
-            w.async_write_hint_file(storage_id);
+            w.async_write_hint_file(storage_id, durable_offset);
         }
         debug!(target: "Database", "writing file with id: {} flushed, new writing file with id: {} created", storage_id, next_storage_id);
         Ok(())
@@ -427,91 +1170,113 @@ impl Database {
     fn get_file_to_read(
         &self,
         storage_id: StorageId,
-    ) -> DatabaseResult<RefMut<StorageId, Mutex<DataStorage>>> {
+    ) -> DatabaseResult<impl Deref<Target = TimedMutex<DataStorage>> + '_> {
+        self.stable_storages.close_files_over_limit();
         self.stable_storages
-            .get_mut(&storage_id)
+            .get(&storage_id)
             .ok_or(DatabaseError::TargetFileIdNotFound(storage_id))
     }
 }
 
-impl Drop for Database {
-    fn drop(&mut self) {
-        let mut writing_file_ref = self.writing_storage.lock();
-        if let Err(e) = writing_file_ref.flush() {
-            warn!(target: "Database", "sync database failed: {}", e)
+/// Data files opened by `Database::pin_storages`
+#[derive(Debug)]
+pub struct PinnedStorages {
+    storages: HashMap<StorageId, Mutex<DataStorage>>,
+    _pins: Vec<PinGuard>,
+}
+
+impl PinnedStorages {
+    pub fn read_value(
+        &self,
+        row_location: &RowLocation,
+    ) -> DatabaseResult<Option<TimedValue<Vec<u8>>>> {
+        let storage = self
+            .storages
+            .get(&row_location.storage_id)
+            .ok_or(DatabaseError::TargetFileIdNotFound(row_location.storage_id))?;
+        let ret = storage.lock().read_value(row_location.row_offset)?;
+        Ok(ret)
+    }
+}
+
+impl Database {
+    /// Flush writing storage and make sure hint files for all the data files are written
+    /// before database closed, so the next open can recover from hint files.
+    fn close(&mut self) {
+        {
+            let mut writing_file_ref = self.writing_storage.lock();
+            match writing_file_ref.transit_to_readonly() {
+                Err(e) => warn!(target: "Database", "sync database failed: {}", e),
+                Ok(durable_offset) => {
+                    // writing storage will be treated as a stable storage on next open
+                    // if it has a hint file, so only write hint for it when something written
+                    if writing_file_ref.is_dirty() && self.hint_file_writer.is_some() {
+                        let storage_id = writing_file_ref.storage_id();
+                        if let Err(e) = HintWriter::write_hint_file(
+                            &self.database_dir,
+                            storage_id,
+                            durable_offset,
+                            self.options.clone(),
+                        ) {
+                            warn!(target: "Database", "write hint file for writing file with id: {} failed: {}", storage_id, e)
+                        }
+                    }
+                }
+            }
         }
 
+        #[cfg(feature = "sync-worker")]
         if let Some(worker) = self.sync_worker.take() {
             drop(worker);
         }
 
         if let Some(hint_w) = self.hint_file_writer.take() {
+            // wait pending hint files to be written
             drop(hint_w);
         }
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        self.close();
 
         info!(target: "Database", "database on directory: {:?} closed", self.database_dir)
     }
 }
 
+#[cfg(feature = "sync-worker")]
 #[derive(Debug)]
 struct SyncWorker {
-    stop_sender: Sender<()>,
-    handle: Option<JoinHandle<()>>,
+    _task: PeriodicTask,
 }
 
+#[cfg(feature = "sync-worker")]
 impl SyncWorker {
-    fn start_sync_worker(
-        datastorage: Arc<Mutex<DataStorage>>,
-        sync_interval_sec: u64,
-    ) -> SyncWorker {
-        let channel = crossbeam_channel::bounded(1);
-        let stop_sender = channel.0;
-        let stop_receiver: Receiver<()> = channel.1;
-
-        let sync_duration = Duration::from_secs(sync_interval_sec);
-        let receiver = crossbeam_channel::tick(sync_duration);
-        let handle = thread::spawn(move || {
-            let mut last_sync = Instant::now();
-            loop {
-                select! {
-                    recv(stop_receiver) -> _ => {
-                        info!(target: "Database", "stopping sync worker");
-                        return
-                    }
-
-                    recv(receiver) -> _ => {
-                        if last_sync.elapsed() < sync_duration {
-                            continue;
-                        }
-
-                        trace!("Attempting syncing");
-                        let mut f = datastorage.lock();
-                        if let Err(e) = f.flush() {
-                            error!(target: "Database", "flush database failed: {}", e);
-                        }
-                        last_sync = Instant::now();
-                    },
-                }
-            }
-        });
-        SyncWorker {
-            stop_sender,
-            handle: Some(handle),
-        }
-    }
-}
-
-impl Drop for SyncWorker {
-    fn drop(&mut self) {
-        if self.stop_sender.send(()).is_err() {
-            warn!("Failed to stop sync worker.");
+    /// Starts flushing writing storage periodically if sync strategy is `Interval`
+    fn start(
+        sync_strategy: &SyncStrategy,
+        maintenance: &MaintenanceQueue,
+        datastorage: Arc<TimedMutex<DataStorage>>,
+        sync_listener: Arc<SyncListener>,
+    ) -> Option<SyncWorker> {
+        let SyncStrategy::Interval(interval) = sync_strategy else {
+            return None;
+        };
+        let sync_interval_sec = interval.as_secs();
+        if sync_interval_sec == 0 {
+            return None;
         }
-
-        if let Some(handle) = self.handle.take() {
-            if handle.join().is_err() {
-                warn!(target: "Database", "wait sync worker done failed");
+        let task = maintenance.schedule(Duration::from_secs(sync_interval_sec), move || {
+            trace!("Attempting syncing");
+            let mut f = datastorage.lock();
+            if let Err(e) = f.flush() {
+                error!(target: "Database", "flush database failed: {}", e);
+            } else {
+                sync_listener.notify();
             }
-        }
+        });
+        Some(SyncWorker { _task: task })
     }
 }
 
@@ -552,67 +1317,271 @@ impl Iterator for DatabaseIter {
                 },
             }
         }
-        None
+        None
+    }
+}
+
+fn recovered_iter(
+    database_dir: &Path,
+    storage_id: StorageId,
+    options: Arc<BitcaskyOptions>,
+) -> DatabaseResult<Box<dyn Iterator<Item = DatabaseResult<RecoveredRow>>>> {
+    if FileType::ErlangHintFile
+        .get_path(database_dir, Some(storage_id))
+        .exists()
+        && !FileType::DataFile
+            .get_path(database_dir, Some(storage_id))
+            .exists()
+    {
+        debug!(target: "Database", "recover from Erlang bitcask hint file with id: {}", storage_id);
+        match erlang_hint::open_erlang_hint_iterator(database_dir, storage_id) {
+            Ok(iter) => return Ok(Box::new(iter)),
+            Err(e @ DatabaseError::HintFileCorrupted(..)) => {
+                warn!(target: "Database", "{}, recover from data file with id: {} instead", e, storage_id);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if FileType::HintFile
+        .get_path(database_dir, Some(storage_id))
+        .exists()
+    {
+        debug!(target: "Database", "recover from hint file with id: {}", storage_id);
+        match HintFile::open_iterator(
+            database_dir,
+            storage_id,
+            options.clock.now(),
+            options.clone(),
+        ) {
+            Ok(iter) => return Ok(Box::new(iter)),
+            Err(
+                e @ (DatabaseError::HintFileCorrupted(..) | DatabaseError::HintFileOutdated(..)),
+            ) => {
+                warn!(target: "Database", "{}, recover from data file with id: {} instead", e, storage_id);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    debug!(target: "Database", "recover from data file with id: {}", storage_id);
+    let stable_file = DataStorage::open(database_dir, storage_id, options.clone())?;
+    let i = stable_file.iter().map(move |iter| {
+        iter.map(move |row| {
+            row.map(|r| RecoveredRow {
+                row_location: r.row_location,
+                key: r.key,
+                invalid: !r.value.is_valid(options.clock.now()),
+            })
+            .map_err(DatabaseError::StorageError)
+        })
+    })?;
+    Ok(Box::new(i))
+}
+
+type RecoveredRows = DatabaseResult<Vec<RecoveredRow>>;
+
+/// Scans data files on a pool of worker threads ahead of [`DatabaseRecoverIter`].
+/// Files are independent of each other, so they can be read concurrently, but
+/// rows are still handed out file by file in the order they were requested
+/// to keep "later file wins, later offset wins" semantics of the recovery.
+/// At most `parallelism` files are scanned or held scanned at a time, the next
+/// file is only handed to workers once a scanned one is taken.
+struct RecoveryPrefetcher {
+    job_sender: Option<Sender<StorageId>>,
+    job_receiver: Receiver<StorageId>,
+    result_receiver: Receiver<(StorageId, RecoveredRows)>,
+    prefetched: HashMap<StorageId, RecoveredRows>,
+    pending_ids: std::vec::IntoIter<StorageId>,
+}
+
+impl RecoveryPrefetcher {
+    fn start(
+        maintenance: &MaintenanceQueue,
+        database_dir: &Path,
+        storage_ids: Vec<StorageId>,
+        parallelism: usize,
+        options: Arc<BitcaskyOptions>,
+    ) -> RecoveryPrefetcher {
+        let in_flight = parallelism.min(storage_ids.len());
+        let (job_sender, job_receiver) = crossbeam_channel::bounded(in_flight);
+        let (result_sender, result_receiver) = crossbeam_channel::bounded(in_flight);
+        let mut pending_ids = storage_ids.into_iter();
+        for id in pending_ids.by_ref().take(in_flight) {
+            job_sender.send(id).unwrap();
+        }
+
+        for _ in 0..in_flight {
+            let jobs: Receiver<StorageId> = job_receiver.clone();
+            let results: Sender<(StorageId, RecoveredRows)> = result_sender.clone();
+            let dir = database_dir.to_path_buf();
+            let options = options.clone();
+            maintenance.submit(move || {
+                while let Ok(storage_id) = jobs.recv() {
+                    let rows = recovered_iter(&dir, storage_id, options.clone())
+                        .and_then(|iter| iter.collect::<RecoveredRows>())
+                        .map(keep_last_row_of_keys);
+                    if results.send((storage_id, rows)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        RecoveryPrefetcher {
+            job_sender: Some(job_sender),
+            job_receiver,
+            result_receiver,
+            prefetched: HashMap::with_capacity(in_flight),
+            pending_ids,
+        }
+    }
+
+    fn take(&mut self, storage_id: StorageId) -> RecoveredRows {
+        loop {
+            if let Some(rows) = self.prefetched.remove(&storage_id) {
+                // a slot is freed, so the next file can be scanned without exceeding the bound
+                if let (Some(sender), Some(id)) =
+                    (self.job_sender.as_ref(), self.pending_ids.next())
+                {
+                    let _ = sender.send(id);
+                }
+                return rows;
+            }
+            match self.result_receiver.recv() {
+                Ok((id, rows)) => {
+                    self.prefetched.insert(id, rows);
+                }
+                Err(_) => return Err(DatabaseError::TargetFileIdNotFound(storage_id)),
+            }
+        }
+    }
+}
+
+/// Keeps only the last row of each key in a file, which overrides earlier rows of the key in
+/// the same file anyway, so less rows are held and applied to keydir. Rows are kept in order.
+fn keep_last_row_of_keys(rows: Vec<RecoveredRow>) -> Vec<RecoveredRow> {
+    let mut keep = vec![false; rows.len()];
+    let mut seen = HashSet::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate().rev() {
+        keep[i] = seen.insert(row.key.as_slice());
+    }
+    drop(seen);
+    rows.into_iter()
+        .zip(keep)
+        .filter_map(|(row, keep)| keep.then_some(row))
+        .collect()
+}
+
+impl Drop for RecoveryPrefetcher {
+    fn drop(&mut self) {
+        // stop handing out files and steal all the remaining jobs so tasks can stop
+        // after their current file, then wait for them to drop their result senders
+        self.job_sender.take();
+        while self.job_receiver.try_recv().is_ok() {}
+        while self.result_receiver.recv().is_ok() {}
+    }
+}
+
+/// Reports progress to the callback registered by `on_recovery_progress`
+struct ProgressReporter {
+    callback: RecoveryProgressCallback,
+    data_sizes: HashMap<StorageId, u64>,
+    progress: RecoveryProgress,
+}
+
+impl ProgressReporter {
+    fn new(
+        callback: RecoveryProgressCallback,
+        data_sizes: HashMap<StorageId, u64>,
+        total_files: usize,
+    ) -> ProgressReporter {
+        let total_bytes = data_sizes.values().sum();
+        ProgressReporter {
+            callback,
+            data_sizes,
+            progress: RecoveryProgress {
+                storage_id: 0,
+                processed_bytes: 0,
+                total_bytes,
+                recovered_files: 0,
+                total_files,
+                recovered_rows: 0,
+            },
+        }
     }
-}
 
-fn recovered_iter(
-    database_dir: &Path,
-    storage_id: StorageId,
-    options: Arc<BitcaskyOptions>,
-) -> DatabaseResult<Box<dyn Iterator<Item = DatabaseResult<RecoveredRow>>>> {
-    if FileType::HintFile
-        .get_path(database_dir, Some(storage_id))
-        .exists()
-    {
-        debug!(target: "Database", "recover from hint file with id: {}", storage_id);
-        Ok(Box::new(HintFile::open_iterator(database_dir, storage_id)?))
-    } else {
-        debug!(target: "Database", "recover from data file with id: {}", storage_id);
-        let stable_file = DataStorage::open(database_dir, storage_id, options.clone())?;
-        let i = stable_file.iter().map(move |iter| {
-            iter.map(move |row| {
-                row.map(|r| RecoveredRow {
-                    row_location: r.row_location,
-                    key: r.key,
-                    invalid: !r.value.is_valid(options.clock.now()),
-                })
-                .map_err(DatabaseError::StorageError)
-            })
-        })?;
-        Ok(Box::new(i))
+    fn file_recovered(&mut self, storage_id: StorageId) {
+        self.progress.storage_id = storage_id;
+        self.progress.processed_bytes += self.data_sizes.get(&storage_id).copied().unwrap_or(0);
+        self.progress.recovered_files += 1;
+        (self.callback.0)(self.progress);
     }
 }
 
 pub struct DatabaseRecoverIter {
     current_iter: Cell<Option<Box<dyn Iterator<Item = DatabaseResult<RecoveredRow>>>>>,
+    current_storage_id: StorageId,
     data_storage_ids: Vec<StorageId>,
     database_dir: PathBuf,
     options: Arc<BitcaskyOptions>,
+    prefetcher: Option<RecoveryPrefetcher>,
+    recovery_stats: RecoveryStats,
+    progress: Option<ProgressReporter>,
 }
 
 impl DatabaseRecoverIter {
     fn new(
+        maintenance: &MaintenanceQueue,
         database_dir: PathBuf,
         mut iters: Vec<StorageId>,
+        recovery_stats: RecoveryStats,
+        progress: Option<ProgressReporter>,
         options: Arc<BitcaskyOptions>,
     ) -> DatabaseResult<Self> {
+        let parallelism = options.database.recovery_parallelism;
+        let mut prefetcher = None;
+        if parallelism > 1 && iters.len() > 1 {
+            // storage ids are consumed from the tail, so prefetch them in the same order
+            let ids = iters.iter().rev().copied().collect::<Vec<StorageId>>();
+            prefetcher = Some(RecoveryPrefetcher::start(
+                maintenance,
+                &database_dir,
+                ids,
+                parallelism,
+                options.clone(),
+            ));
+        }
+
+        let mut recover_iter = DatabaseRecoverIter {
+            database_dir,
+            data_storage_ids: vec![],
+            current_iter: Cell::new(None),
+            current_storage_id: 0,
+            options,
+            prefetcher,
+            recovery_stats,
+            progress,
+        };
         if let Some(id) = iters.pop() {
-            let iter: Box<dyn Iterator<Item = DatabaseResult<RecoveredRow>>> =
-                recovered_iter(&database_dir, id, options.clone())?;
-            Ok(DatabaseRecoverIter {
-                database_dir,
-                data_storage_ids: iters,
-                current_iter: Cell::new(Some(iter)),
-                options,
-            })
-        } else {
-            Ok(DatabaseRecoverIter {
-                database_dir,
-                data_storage_ids: iters,
-                current_iter: Cell::new(None),
-                options,
-            })
+            let iter = recover_iter.open_storage_iter(id)?;
+            recover_iter.current_iter.replace(Some(iter));
+            recover_iter.current_storage_id = id;
+        }
+        recover_iter.data_storage_ids = iters;
+        Ok(recover_iter)
+    }
+
+    pub fn recovery_stats(&self) -> RecoveryStats {
+        self.recovery_stats
+    }
+
+    fn open_storage_iter(
+        &mut self,
+        storage_id: StorageId,
+    ) -> DatabaseResult<Box<dyn Iterator<Item = DatabaseResult<RecoveredRow>>>> {
+        match self.prefetcher.as_mut() {
+            Some(prefetcher) => Ok(Box::new(prefetcher.take(storage_id)?.into_iter().map(Ok))),
+            None => recovered_iter(&self.database_dir, storage_id, self.options.clone()),
         }
     }
 }
@@ -626,10 +1595,15 @@ impl Iterator for DatabaseRecoverIter {
                 None => break,
                 Some(iter) => match iter.next() {
                     None => {
+                        self.current_iter.replace(None);
+                        if let Some(progress) = self.progress.as_mut() {
+                            progress.file_recovered(self.current_storage_id);
+                        }
                         if let Some(id) = self.data_storage_ids.pop() {
-                            match recovered_iter(&self.database_dir, id, self.options.clone()) {
+                            match self.open_storage_iter(id) {
                                 Ok(iter) => {
                                     self.current_iter.replace(Some(iter));
+                                    self.current_storage_id = id;
                                 }
                                 Err(e) => return Some(Err(e)),
                             }
@@ -637,7 +1611,12 @@ impl Iterator for DatabaseRecoverIter {
                             break;
                         }
                     }
-                    other => return other,
+                    Some(row) => {
+                        if let Some(progress) = self.progress.as_mut() {
+                            progress.progress.recovered_rows += 1;
+                        }
+                        return Some(row);
+                    }
                 },
             }
         }
@@ -653,10 +1632,33 @@ fn open_storages<P: AsRef<Path>>(
     let mut storage_ids = data_storage_ids.to_owned();
     storage_ids.sort();
 
-    Ok(storage_ids
-        .iter()
-        .map(|id| DataStorage::open(&database_dir, *id, options.clone()))
-        .collect::<crate::database::data_storage::Result<Vec<DataStorage>>>()?)
+    let newest_storage_id = storage_ids.last().copied();
+    let mut storages = Vec::with_capacity(storage_ids.len());
+    let max_open_files = options.database.max_open_files.unwrap_or(usize::MAX);
+    for id in storage_ids {
+        match DataStorage::open(&database_dir, id, options.clone()) {
+            // the newest data file may be reused as the writing file, keep it open
+            Ok(mut s) if Some(id) != newest_storage_id && storages.len() >= max_open_files => {
+                s.skip_to_end();
+                s.close_file();
+                storages.push(s);
+            }
+            Ok(s) => storages.push(s),
+            // only the newest data file can be left incomplete by a crash while creating it.
+            // Without hint file, no row in it is referenced
+            Err(DataStorageError::IncompleteDataFile(_))
+                if Some(id) == newest_storage_id
+                    && !FileType::HintFile
+                        .get_path(&database_dir, Some(id))
+                        .exists() =>
+            {
+                warn!(target: "Database", "delete incomplete data file with id: {}", id);
+                SelfFs::delete_file(database_dir.as_ref(), FileType::DataFile, Some(id))?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(storages)
 }
 
 fn prepare_db_storages<P: AsRef<Path>>(
@@ -667,26 +1669,52 @@ fn prepare_db_storages<P: AsRef<Path>>(
     options: Arc<BitcaskyOptions>,
 ) -> DatabaseResult<(DataStorage, Vec<DataStorage>)> {
     let mut storages = open_storages(&database_dir, data_storage_ids, options.clone())?;
-    let mut writing_storage;
+    let writing_storage;
     if storages.is_empty() {
-        let writing_storage_id = storage_id_generator.generate_next_id();
+        let writing_storage_id = storage_id_generator.generate_next_id()?;
         let storage = DataStorage::new(&database_dir, writing_storage_id, formatter, options)?;
         debug!(target: "Database", "create writing file with id: {}", writing_storage_id);
         writing_storage = storage;
+    } else if storage_id_generator.remaining_ids() > 0
+        && FileType::HintFile
+            .get_path(&database_dir, Some(storages.last().unwrap().storage_id()))
+            .exists()
+    {
+        // last data file was closed gracefully with its hint file written,
+        // keep it stable so the hint file is always consistent with the data file
+        let writing_storage_id = storage_id_generator.generate_next_id()?;
+        let storage = DataStorage::new(&database_dir, writing_storage_id, formatter, options)?;
+        debug!(target: "Database", "create writing file with id: {} after stable file with hint file", writing_storage_id);
+        writing_storage = storage;
     } else {
-        writing_storage = storages.pop().unwrap();
-        if let Err(e) = writing_storage.seek_to_end() {
-            match e {
-                DataStorageError::EofError() => {
-                    warn!(target: "Database", "got EOF in writing file with id: {}", writing_storage.storage_id());
-                }
-                DataStorageError::DataStorageFormatter(e) => {
-                    warn!(target: "Database", "has invalid data in writing file with id: {}, reason: {}", writing_storage.storage_id(), e);
-                }
-                _ => return Err(DatabaseError::StorageError(e)),
-            }
+        let mut last_storage = storages.pop().unwrap();
+        // no storage id is left for a new writing file, keep writing to the last data file and
+        // drop its hint file which does not cover rows written from now on
+        SelfFs::delete_file(
+            database_dir.as_ref(),
+            FileType::HintFile,
+            Some(last_storage.storage_id()),
+        )?;
+        // torn write at the end of the file is truncated in seek_to_end,
+        // any other broken data means the writing file is corrupted
+        last_storage.seek_to_end()?;
+        if *last_storage.formatter() == *formatter {
+            debug!(target: "Database", "reuse writing file with id: {}", last_storage.storage_id());
+            writing_storage = last_storage;
+        } else {
+            // new rows are always written by the current formatter, leave the file written
+            // by other formatter stable. It's rewritten by the current formatter on merge
+            let writing_storage_id = storage_id_generator.generate_next_id()?;
+            debug!(target: "Database", "create writing file with id: {} after writing file with id: {} written by formatter version: {}",
+                writing_storage_id, last_storage.storage_id(), last_storage.formatter().version());
+            writing_storage =
+                DataStorage::new(&database_dir, writing_storage_id, formatter, options)?;
+            storages.push(last_storage);
         }
-        debug!(target: "Database", "reuse writing file with id: {}", writing_storage.storage_id());
+    }
+
+    for s in storages.iter_mut() {
+        s.skip_to_end();
     }
 
     Ok((writing_storage, storages))
@@ -695,20 +1723,32 @@ fn prepare_db_storages<P: AsRef<Path>>(
 #[cfg(test)]
 pub mod database_tests {
     use std::{
+        collections::HashMap,
         io::{Seek, Write},
-        sync::Arc,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
         time::Duration,
     };
 
-    use crate::options::{BitcaskyOptions, SyncStrategy};
+    use crate::options::{BitcaskyOptions, DataSotrageType, SyncStrategy};
     use crate::test_utils::{get_temporary_directory_path, TestingKV};
-    use crate::{clock::DebugClock, fs, fs::FileType, storage_id::StorageIdGenerator};
+    use crate::{
+        clock::DebugClock,
+        fs,
+        fs::FileType,
+        storage_id::{StorageId, StorageIdGenerator},
+    };
 
     use test_log::test;
 
-    use crate::database::{data_storage::DataStorageReader, RowLocation, TimedValue};
+    use crate::database::{
+        data_storage::DataStorageReader, DataStorageError, DatabaseError, RowLocation, TimedValue,
+    };
+    use crate::formatter::{BitcaskyFormatter, Formatter, FormatterError};
 
-    use super::Database;
+    use super::{keep_last_row_of_keys, Database, RecoveredRow};
 
     #[derive(Debug)]
     pub struct TestingRow {
@@ -799,6 +1839,35 @@ pub mod database_tests {
         assert_database_rows(&db, &rows);
     }
 
+    #[test]
+    fn test_read_write_with_write_buffer() {
+        let dir = get_temporary_directory_path();
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        let options = || {
+            get_database_options()
+                .storage_type(DataSotrageType::File)
+                .write_buffer_size(4096)
+        };
+        let rows = {
+            let db =
+                Database::open(&dir, storage_id_generator.clone(), Arc::new(options())).unwrap();
+            let kvs = vec![
+                TestingKV::new("k1", "value1"),
+                TestingKV::new("k2", "value2"),
+                TestingKV::new("k1", "value3"),
+            ];
+            let rows = write_kvs_to_db(&db, kvs);
+            assert_rows_value(&db, &rows);
+            // buffered rows are written to file before iterating it
+            assert_database_rows(&db, &rows);
+            assert!(db.verify().unwrap().is_healthy());
+            rows
+        };
+        // buffered rows are written to file on close
+        let db = Database::open(&dir, storage_id_generator, Arc::new(options())).unwrap();
+        assert_rows_value(&db, &rows);
+    }
+
     #[test]
     fn test_read_write_expirable_value_in_writing_file() {
         let dir = get_temporary_directory_path();
@@ -923,8 +1992,9 @@ pub mod database_tests {
             Arc::new(get_database_options()),
         )
         .unwrap();
-        assert_eq!(1, storage_id_generator.get_id());
-        assert_eq!(0, db.stable_storages.len());
+        // every reopen starts a new writing file after the one closed with hint file
+        assert_eq!(3, storage_id_generator.get_id());
+        assert_eq!(2, db.stable_storages.len());
         assert_rows_value(&db, &rows);
         assert_database_rows(&db, &rows);
     }
@@ -979,6 +2049,94 @@ pub mod database_tests {
         assert_database_rows(&db, &rows);
     }
 
+    #[test]
+    fn test_recovery_from_torn_write() {
+        let dir = get_temporary_directory_path();
+        let mut rows: Vec<TestingRow> = vec![];
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        let torn_pos = {
+            let db = Database::open(
+                &dir,
+                storage_id_generator.clone(),
+                Arc::new(get_database_options()),
+            )
+            .unwrap();
+            rows.push(write_kv_to_db(&db, TestingKV::new("k1", "value1")));
+            let pos = write_kv_to_db(&db, TestingKV::new("k2", "value2_value2_value2")).pos;
+            db.sync().unwrap();
+
+            // only the first half of the last row reached disk
+            let mut f = fs::open_file(&dir, FileType::DataFile, Some(pos.storage_id))
+                .unwrap()
+                .file;
+            let half = pos.row_size / 2;
+            f.seek(std::io::SeekFrom::Start((pos.row_offset + half) as u64))
+                .unwrap();
+            f.write_all(&vec![0; pos.row_size - half]).unwrap();
+            // simulate a crash, skip all the flush works in drop
+            std::mem::forget(db);
+            pos
+        };
+        {
+            let db = Database::open(
+                &dir,
+                storage_id_generator.clone(),
+                Arc::new(get_database_options()),
+            )
+            .unwrap();
+            assert_rows_value(&db, &rows);
+            assert_database_rows(&db, &rows);
+            let row = write_kv_to_db(&db, TestingKV::new("k3", "hello"));
+            assert_eq!(torn_pos.storage_id, row.pos.storage_id);
+            assert_eq!(torn_pos.row_offset, row.pos.row_offset);
+            rows.push(row);
+        }
+
+        let db = Database::open(
+            &dir,
+            storage_id_generator.clone(),
+            Arc::new(get_database_options()),
+        )
+        .unwrap();
+        assert_rows_value(&db, &rows);
+        assert_database_rows(&db, &rows);
+    }
+
+    #[test]
+    fn test_recovery_from_corrupted_middle_row() {
+        let dir = get_temporary_directory_path();
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        {
+            let db = Database::open(
+                &dir,
+                storage_id_generator.clone(),
+                Arc::new(get_database_options()),
+            )
+            .unwrap();
+            write_kv_to_db(&db, TestingKV::new("k1", "value1"));
+            let pos = write_kv_to_db(&db, TestingKV::new("k2", "value2")).pos;
+            write_kv_to_db(&db, TestingKV::new("k3", "value3"));
+            db.sync().unwrap();
+
+            let mut f = fs::open_file(&dir, FileType::DataFile, Some(pos.storage_id))
+                .unwrap()
+                .file;
+            f.seek(std::io::SeekFrom::Start(
+                (pos.row_offset + BitcaskyFormatter::default().row_header_size()) as u64,
+            ))
+            .unwrap();
+            f.write_all(&[1]).unwrap();
+            std::mem::forget(db);
+        }
+
+        assert_matches!(
+            Database::open(&dir, storage_id_generator, Arc::new(get_database_options())),
+            Err(DatabaseError::StorageError(
+                DataStorageError::DataStorageFormatter(FormatterError::CrcCheckFailed { .. })
+            ))
+        );
+    }
+
     #[test]
     fn test_recovery_from_header_not_fully_written() {
         let dir = get_temporary_directory_path();
@@ -1082,6 +2240,171 @@ pub mod database_tests {
         assert_database_rows(&db, &rows);
     }
 
+    #[test]
+    fn test_keep_last_row_of_keys() {
+        let row = |key: &str, row_offset: usize, invalid: bool| RecoveredRow {
+            row_location: RowLocation {
+                storage_id: 1,
+                row_offset,
+                row_size: 8,
+            },
+            key: key.into(),
+            invalid,
+        };
+        let rows = keep_last_row_of_keys(vec![
+            row("k1", 0, false),
+            row("k2", 8, false),
+            row("k1", 16, true),
+            row("k3", 24, false),
+            row("k2", 32, false),
+        ]);
+        assert_eq!(
+            vec![
+                (b"k1".to_vec(), 16, true),
+                (b"k3".to_vec(), 24, false),
+                (b"k2".to_vec(), 32, false)
+            ],
+            rows.into_iter()
+                .map(|r| (r.key, r.row_location.row_offset, r.invalid))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_parallel_recovery() {
+        let dir = get_temporary_directory_path();
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        {
+            let db = Database::open(
+                &dir,
+                storage_id_generator.clone(),
+                Arc::new(get_database_options()),
+            )
+            .unwrap();
+            for i in 0..5 {
+                let kvs = vec![
+                    TestingKV::new("k1", &format!("value{}", i)),
+                    TestingKV::new(&format!("key{}", i), "value"),
+                    TestingKV::new_expirable("k2", "value", 100),
+                ];
+                write_kvs_to_db(&db, kvs);
+                db.flush_writing_file().unwrap();
+            }
+        }
+
+        let recover = |parallelism: usize| {
+            let db = Database::open(
+                &dir,
+                storage_id_generator.clone(),
+                Arc::new(get_database_options().recovery_parallelism(parallelism)),
+            )
+            .unwrap();
+            db.recovery_iter()
+                .unwrap()
+                .map(|r| {
+                    let r = r.unwrap();
+                    (r.key, r.row_location, r.invalid)
+                })
+                .collect::<Vec<_>>()
+        };
+        // rows applied in order, the last row of a key wins
+        let apply = |rows: &Vec<(Vec<u8>, RowLocation, bool)>| {
+            let mut keys = HashMap::new();
+            for (k, location, invalid) in rows {
+                if *invalid {
+                    keys.remove(k);
+                } else {
+                    keys.insert(k.clone(), *location);
+                }
+            }
+            keys
+        };
+
+        let sequential = recover(1);
+        assert_eq!(15, sequential.len());
+        let parallel = recover(4);
+        assert_eq!(apply(&sequential), apply(&parallel));
+        // fewer workers than files, files are handed out as scanned ones are taken
+        assert_eq!(apply(&sequential), apply(&recover(2)));
+    }
+
+    #[test]
+    fn test_write_sync_survives_without_close() {
+        let dir = get_temporary_directory_path();
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        let options = Arc::new(
+            BitcaskyOptions::default()
+                .max_data_file_size(120)
+                .init_data_file_capacity(100)
+                .sync_strategy(SyncStrategy::None),
+        );
+        let mut rows: Vec<TestingRow> = vec![];
+        {
+            let db = Database::open(&dir, storage_id_generator.clone(), options.clone()).unwrap();
+            for kv in [
+                TestingKV::new("k1", "value1_value1_value1"),
+                TestingKV::new("k2", "value2_value2_value2"),
+                TestingKV::new("k3", "value3_value3_value3"),
+            ] {
+                let pos = db
+                    .write_sync(kv.key(), TimedValue::permanent_value(kv.value()))
+                    .unwrap();
+                rows.push(TestingRow::new(kv, pos));
+            }
+            // rows were spread over multiple files by overflow
+            assert!(!db.stable_storages.is_empty());
+            // simulate a crash, skip all the flush works in drop
+            std::mem::forget(db);
+        }
+
+        let db = Database::open(&dir, storage_id_generator, options).unwrap();
+        assert_rows_value(&db, &rows);
+    }
+
+    #[test]
+    fn test_wait_durable() {
+        let dir = get_temporary_directory_path();
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        let db =
+            Database::open(&dir, storage_id_generator, Arc::new(get_database_options())).unwrap();
+        let pos = db
+            .write("k1", TimedValue::permanent_value("value1"))
+            .unwrap();
+        let state = db.durability_state();
+        assert_eq!(pos.storage_id, state.writing_file);
+        assert_eq!((pos.row_offset + pos.row_size) as u64, state.written_offset);
+        assert!(state.synced_offset < state.written_offset);
+        assert!(!db.wait_durable(&pos, Duration::from_millis(10)));
+
+        db.sync().unwrap();
+        assert_eq!(
+            db.durability_state().written_offset,
+            db.durability_state().synced_offset
+        );
+        assert!(db.wait_durable(&pos, Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_rotation_satisfies_durable_waiters() {
+        let dir = get_temporary_directory_path();
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        let db = Arc::new(
+            Database::open(&dir, storage_id_generator, Arc::new(get_database_options())).unwrap(),
+        );
+        let pos = db
+            .write("k1", TimedValue::permanent_value("value1"))
+            .unwrap();
+
+        let waiter = {
+            let db = db.clone();
+            std::thread::spawn(move || db.wait_durable(&pos, Duration::from_secs(10)))
+        };
+        db.flush_writing_file().unwrap();
+
+        assert!(waiter.join().unwrap());
+        assert_ne!(pos.storage_id, db.durability_state().writing_file);
+    }
+
     #[test]
     fn test_wrap_file() {
         let storage_id_generator = Arc::new(StorageIdGenerator::default());
@@ -1091,7 +2414,7 @@ pub mod database_tests {
             storage_id_generator,
             Arc::new(
                 BitcaskyOptions::default()
-                    .max_data_file_size(120)
+                    .max_data_file_size(140)
                     .init_data_file_capacity(100),
             ),
         )
@@ -1109,6 +2432,128 @@ pub mod database_tests {
         assert_database_rows(&db, &rows);
     }
 
+    #[test]
+    fn test_iter_skips_rows_written_after_created() {
+        for storage_type in [DataSotrageType::File, DataSotrageType::default()] {
+            let dir = get_temporary_directory_path();
+            let storage_id_generator = Arc::new(StorageIdGenerator::default());
+            let db = Database::open(
+                &dir,
+                storage_id_generator,
+                // preallocated data files leave room for rows written after the iterator created
+                Arc::new(
+                    get_database_options()
+                        .init_data_file_capacity(1024)
+                        .storage_type(storage_type),
+                ),
+            )
+            .unwrap();
+            let rows = write_kvs_to_db(
+                &db,
+                (0..50)
+                    .map(|i| TestingKV::new(&format!("k{}", i), "value"))
+                    .collect(),
+            );
+            assert!(!db.stable_storages.is_empty());
+
+            let iter = db.iter().unwrap();
+            write_kv_to_db(&db, TestingKV::new("after1", "value"));
+            db.flush_writing_file().unwrap();
+            write_kv_to_db(&db, TestingKV::new("after2", "value"));
+
+            let keys = iter.map(|r| r.unwrap().key).collect::<Vec<Vec<u8>>>();
+            assert_eq!(
+                rows.iter().map(|r| r.kv.key()).collect::<Vec<Vec<u8>>>(),
+                keys
+            );
+        }
+    }
+
+    #[test]
+    fn test_iter_during_concurrent_writes() {
+        let dir = get_temporary_directory_path();
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        let db = Arc::new(
+            Database::open(
+                &dir,
+                storage_id_generator,
+                Arc::new(get_database_options().init_data_file_capacity(1024)),
+            )
+            .unwrap(),
+        );
+        let rows = write_kvs_to_db(
+            &db,
+            (0..30)
+                .map(|i| TestingKV::new(&format!("k{}", i), "value"))
+                .collect(),
+        );
+
+        let iter = db.iter().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let db = db.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut i = 0;
+                while !stop.load(Ordering::Acquire) {
+                    write_kv_to_db(&db, TestingKV::new(&format!("after{}", i), "value"));
+                    i += 1;
+                }
+                i
+            })
+        };
+
+        let mut keys = vec![];
+        for row in iter {
+            keys.push(row.unwrap().key);
+            // a slow iteration lets the writer rotate the writing storage
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        stop.store(true, Ordering::Release);
+        assert!(writer.join().unwrap() > 0);
+
+        assert_eq!(
+            rows.iter().map(|r| r.kv.key()).collect::<Vec<Vec<u8>>>(),
+            keys
+        );
+    }
+
+    #[test]
+    fn test_flush_writing_file_after_storage_id_exhausted() {
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        storage_id_generator.update_id(StorageId::MAX - 2).unwrap();
+        let dir = get_temporary_directory_path();
+        let db = Database::open(
+            &dir,
+            storage_id_generator.clone(),
+            Arc::new(get_database_options()),
+        )
+        .unwrap();
+        assert_eq!(StorageId::MAX - 1, db.writing_storage.lock().storage_id());
+        assert_eq!(0, db.get_telemetry_data().remaining_storage_ids);
+
+        let rows = write_kvs_to_db(&db, vec![TestingKV::new("k1", "value1")]);
+        assert!(matches!(
+            db.flush_writing_file(),
+            Err(DatabaseError::StorageIdOverflow(_))
+        ));
+        // writing file is kept untouched
+        assert_eq!(StorageId::MAX - 1, db.writing_storage.lock().storage_id());
+        assert_eq!(0, db.stable_storages.len());
+        assert_rows_value(&db, &rows);
+        drop(db);
+
+        // the store can still be reopened with the largest storage id
+        let db = Database::open(
+            &dir,
+            Arc::new(StorageIdGenerator::default()),
+            Arc::new(get_database_options()),
+        )
+        .unwrap();
+        assert_eq!(StorageId::MAX - 1, db.writing_storage.lock().storage_id());
+        assert_rows_value(&db, &rows);
+    }
+
     #[test]
     fn test_add_dead_bytes() {
         let storage_id_generator = Arc::new(StorageIdGenerator::default());
@@ -1147,4 +2592,26 @@ pub mod database_tests {
                 .dead_bytes
         );
     }
+
+    #[test]
+    fn test_is_in_writing_file() {
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        let dir = get_temporary_directory_path();
+        let db = Database::open(
+            &dir,
+            storage_id_generator,
+            Arc::new(BitcaskyOptions::default()),
+        )
+        .unwrap();
+        let row_lo = db
+            .write("key", TimedValue::permanent_value("value"))
+            .unwrap();
+        assert_eq!(row_lo.storage_id, db.writing_storage_id());
+        assert!(row_lo.is_in_writing_file(&db));
+
+        db.flush_writing_file().unwrap();
+        assert!(!row_lo.is_in_writing_file(&db));
+        assert!(!db.is_writing_storage_id(row_lo.storage_id));
+        assert!(db.is_writing_storage_id(db.writing_storage_id()));
+    }
 }
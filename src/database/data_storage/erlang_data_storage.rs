@@ -0,0 +1,349 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Deref,
+    sync::Arc,
+};
+
+use crate::logging::warn;
+
+use crate::{
+    formatter::{ErlangBitcaskFormatter, FormatterError, RowToWrite},
+    options::BitcaskyOptions,
+    storage_id::StorageId,
+    tombstone::TOMBSTONE_VALUE,
+};
+
+use crate::database::{common::RowToRead, DataStorageError, RowLocation, TimedValue};
+
+use super::{DataStorageReader, DataStorageWriter, Result};
+
+/// Key, value, write timestamp and size of a row
+type KeyValueAndSize = (Vec<u8>, Vec<u8>, u64, usize);
+
+/// Reads data file left by the original Erlang bitcask through file IO. Rows in it never expire
+/// and tombstones of any Erlang bitcask version are read as tombstones of bitcasky. It is read
+/// only, rows in it are moved to data files of bitcasky by merge.
+#[derive(Debug)]
+pub struct ErlangDataStorage {
+    pub offset: usize,
+    pub capacity: usize,
+    pub read_value_times: u64,
+    pub write_times: u64,
+    data_file: File,
+    storage_id: StorageId,
+    options: Arc<BitcaskyOptions>,
+    formatter: ErlangBitcaskFormatter,
+}
+
+impl ErlangDataStorage {
+    pub fn new(
+        storage_id: StorageId,
+        data_file: File,
+        capacity: usize,
+        options: Arc<BitcaskyOptions>,
+    ) -> Result<Self> {
+        Ok(ErlangDataStorage {
+            data_file,
+            storage_id,
+            offset: 0,
+            capacity,
+            options,
+            formatter: ErlangBitcaskFormatter::default(),
+            read_value_times: 0,
+            write_times: 0,
+        })
+    }
+
+    /// Nothing is written to this storage
+    pub fn sync_all(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        let mut bs = vec![0; len];
+        let mut f = &self.data_file;
+        f.seek(SeekFrom::Start(offset as u64))?;
+        f.read_exact(&mut bs)?;
+        Ok(bs)
+    }
+
+    fn do_read_row(&self, offset: usize, verify_crc: bool) -> Result<Option<KeyValueAndSize>> {
+        if offset == self.capacity {
+            return Ok(None);
+        }
+        let Some(row_end) = self.row_end(offset) else {
+            return Err(DataStorageError::EofError());
+        };
+
+        let mut bs = self.read_at(offset, row_end - offset)?;
+        if verify_crc {
+            self.formatter.validate_row(&bs)?;
+        }
+        let header = self.formatter.decode_row_header(&bs);
+        let mut key = bs.split_off(self.formatter.row_header_size());
+        let mut value = key.split_off(header.meta.key_size);
+        if self.formatter.is_tombstone(&value) {
+            value = TOMBSTONE_VALUE.as_bytes().to_vec();
+        }
+        Ok(Some((
+            key,
+            value,
+            header.meta.write_timestamp,
+            row_end - offset,
+        )))
+    }
+
+    /// End of the row at offset according to the size info in its header.
+    /// Returns None if the row is cut by the end of file.
+    fn row_end(&self, offset: usize) -> Option<usize> {
+        let header_size = self.formatter.row_header_size();
+        if offset + header_size > self.capacity {
+            return None;
+        }
+        let header = self
+            .formatter
+            .decode_row_header(&self.read_at(offset, header_size).ok()?);
+        header_size
+            .checked_add(header.meta.key_size)
+            .and_then(|s| s.checked_add(header.meta.value_size))
+            .and_then(|s| s.checked_add(offset))
+            .filter(|row_end| *row_end <= self.capacity)
+    }
+
+    /// Move offset to the next row without validating the current one.
+    /// Returns false if the row under current offset is cut by the end of file.
+    pub fn skip_row(&mut self) -> bool {
+        match self.row_end(self.offset) {
+            Some(row_end) => {
+                self.offset = row_end;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Move offset to the end of valid rows. Returns true if it stops at a row cut by the end of
+    /// file, or an error if a corrupted row is followed by other data.
+    pub fn seek_to_last_valid_row(&mut self) -> Result<bool> {
+        loop {
+            match self.read_next_row() {
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(false),
+                Err(e) => {
+                    let broken_row_end = self.row_end(self.offset).unwrap_or(self.capacity);
+                    if broken_row_end != self.capacity {
+                        return Err(e);
+                    }
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    pub fn truncate_torn_tail(&mut self) -> Result<()> {
+        Err(DataStorageError::PermissionDenied(self.storage_id))
+    }
+}
+
+impl DataStorageWriter for ErlangDataStorage {
+    fn write_row<K: AsRef<[u8]>, V: Deref<Target = [u8]>>(
+        &mut self,
+        _row: &RowToWrite<K, V>,
+    ) -> Result<RowLocation> {
+        Err(DataStorageError::PermissionDenied(self.storage_id))
+    }
+
+    fn rewind(&mut self) -> Result<()> {
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl DataStorageReader for ErlangDataStorage {
+    fn read_value(&mut self, row_offset: usize) -> Result<Option<TimedValue<Vec<u8>>>> {
+        self.read_value_at(row_offset, 0)
+    }
+
+    /// Rows of Erlang bitcask never expire
+    fn read_value_at(
+        &mut self,
+        row_offset: usize,
+        _now: u64,
+    ) -> Result<Option<TimedValue<Vec<u8>>>> {
+        let storage_id = self.storage_id;
+        let verify_crc = self.options.database.storage.verify_crc_on_read;
+        let row = self
+            .do_read_row(row_offset, verify_crc)
+            .map_err(|e| match e {
+                DataStorageError::DataStorageFormatter(FormatterError::CrcCheckFailed {
+                    expected_crc,
+                    actual_crc,
+                }) => DataStorageError::CrcCheckFailed {
+                    storage_id,
+                    row_offset,
+                    expected_crc,
+                    actual_crc,
+                },
+                _ => DataStorageError::ReadRowFailed(storage_id, e.to_string()),
+            })?;
+        let Some((_, value, write_timestamp, _)) = row else {
+            return Err(DataStorageError::ReadRowFailed(
+                self.storage_id,
+                format!("no value found at offset: {}", row_offset),
+            ));
+        };
+
+        self.read_value_times += 1;
+        Ok(TimedValue::permanent_value(value)
+            .with_write_timestamp(write_timestamp)
+            .validate())
+    }
+
+    fn read_next_row(&mut self) -> Result<Option<RowToRead>> {
+        let row_offset = self.offset;
+        let Some((key, value, write_timestamp, row_size)) = self.do_read_row(row_offset, true)?
+        else {
+            return Ok(None);
+        };
+        self.offset += row_size;
+        Ok(Some(RowToRead {
+            key,
+            value: TimedValue::permanent_value(value).with_write_timestamp(write_timestamp),
+            row_location: RowLocation {
+                storage_id: self.storage_id,
+                row_offset,
+                row_size,
+            },
+        }))
+    }
+
+    fn seek_to_end(&mut self) -> Result<()> {
+        if self.seek_to_last_valid_row()? {
+            // the file is left by Erlang bitcask, keep it as is
+            warn!(
+                "ignore torn write at the end of Erlang bitcask data file with storage id: {}, offset: {}",
+                self.storage_id, self.offset
+            );
+        }
+        Ok(())
+    }
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::fs::{self, FileType};
+    use crate::tombstone::is_tombstone;
+
+    use super::*;
+
+    use crate::test_utils::get_temporary_directory_path;
+    use test_log::test;
+
+    fn write_erlang_data_file(rows: &[(&[u8], &[u8])]) -> File {
+        let dir = get_temporary_directory_path();
+        let formatter = ErlangBitcaskFormatter::default();
+        let mut f = fs::create_file(&dir, FileType::ErlangDataFile, Some(1)).unwrap();
+        for (k, v) in rows {
+            let mut bs = vec![0; formatter.row_header_size() + k.len() + v.len()];
+            formatter.encode_row(k, v, 1700000000, &mut bs);
+            f.write_all(&bs).unwrap();
+        }
+        f.flush().unwrap();
+        fs::open_file(&dir, FileType::ErlangDataFile, Some(1))
+            .unwrap()
+            .file
+    }
+
+    fn get_erlang_storage(file: File) -> ErlangDataStorage {
+        let capacity = file.metadata().unwrap().len() as usize;
+        ErlangDataStorage::new(1, file, capacity, Arc::new(BitcaskyOptions::default())).unwrap()
+    }
+
+    #[test]
+    fn test_read_rows() {
+        let file = write_erlang_data_file(&[
+            (b"key1", b"value1"),
+            (b"key2", b"value2"),
+            (b"key1", b"bitcask_tombstone2\0\0\0\x01"),
+        ]);
+        let mut storage = get_erlang_storage(file);
+
+        let r1 = storage.read_next_row().unwrap().unwrap();
+        assert_eq!(b"key1".to_vec(), r1.key);
+        assert_eq!(b"value1".to_vec(), r1.value.value);
+        assert_eq!(0, r1.row_location.row_offset);
+        assert_eq!(24, r1.row_location.row_size);
+        let r2 = storage.read_next_row().unwrap().unwrap();
+        assert_eq!(24, r2.row_location.row_offset);
+        let r3 = storage.read_next_row().unwrap().unwrap();
+        assert_eq!(b"key1".to_vec(), r3.key);
+        assert!(is_tombstone(&r3.value.value));
+        assert!(storage.read_next_row().unwrap().is_none());
+        assert_eq!(storage.capacity, storage.offset());
+
+        assert_eq!(
+            b"value2".to_vec(),
+            *storage
+                .read_value(r2.row_location.row_offset)
+                .unwrap()
+                .unwrap()
+        );
+        assert!(storage
+            .read_value(r3.row_location.row_offset)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_corrupted_row() {
+        let file = write_erlang_data_file(&[(b"key1", b"value1"), (b"key2", b"value2")]);
+        let mut f = &file;
+        f.seek(SeekFrom::Start(20)).unwrap();
+        f.write_all(b"x").unwrap();
+        let mut storage = get_erlang_storage(file);
+
+        assert!(matches!(
+            storage.read_value(0),
+            Err(DataStorageError::CrcCheckFailed { row_offset: 0, .. })
+        ));
+        assert!(storage.seek_to_last_valid_row().is_err());
+        assert!(storage.skip_row());
+        assert_eq!(
+            b"key2".to_vec(),
+            storage.read_next_row().unwrap().unwrap().key
+        );
+    }
+
+    #[test]
+    fn test_seek_to_end_with_torn_write() {
+        let file = write_erlang_data_file(&[(b"key1", b"value1"), (b"key2", b"value2")]);
+        file.set_len(40).unwrap();
+        let mut storage = get_erlang_storage(file);
+
+        storage.seek_to_end().unwrap();
+        assert_eq!(24, storage.offset());
+        assert_eq!(40, storage.capacity);
+    }
+
+    #[test]
+    fn test_write_is_denied() {
+        let file = write_erlang_data_file(&[(b"key1", b"value1")]);
+        let mut storage = get_erlang_storage(file);
+        assert!(matches!(
+            storage.write_row(&RowToWrite::new(b"key2".to_vec(), b"value2".to_vec())),
+            Err(DataStorageError::PermissionDenied(1))
+        ));
+        assert!(storage.truncate_torn_tail().is_err());
+    }
+}
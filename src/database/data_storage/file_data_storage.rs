@@ -0,0 +1,694 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Deref,
+    sync::Arc,
+};
+
+use crate::logging::{debug, warn};
+use crate::options::BitcaskyOptions;
+use crate::{
+    clock::Clock,
+    formatter::{
+        padding, BitcaskyFormatter, Formatter, FormatterError, RowHeader, RowMeta, RowToWrite,
+        FILE_HEADER_SIZE,
+    },
+    storage_id::StorageId,
+};
+
+use crate::database::{common::RowToRead, DataStorageError, RowLocation, TimedValue};
+
+use super::{raw_row_size, DataStorageReader, DataStorageWriter, RawRow, Result};
+
+/// Bytes checked at a time when looking for data after a row
+const ZERO_CHECK_CHUNK_SIZE: usize = 4096;
+
+type MetaAndKeyValue = (RowMeta, Vec<u8>, Option<Vec<u8>>);
+
+/// Reads and writes data file through file IO instead of mapping it into memory. Only the row
+/// being read or written is kept in memory.
+#[derive(Debug)]
+pub struct FileDataStorage {
+    pub offset: usize,
+    pub capacity: usize,
+    pub read_value_times: u64,
+    pub write_times: u64,
+    data_file: File,
+    storage_id: StorageId,
+    options: Arc<BitcaskyOptions>,
+    formatter: Arc<BitcaskyFormatter>,
+    /// Reused to encode rows before written to file
+    write_buffer: Vec<u8>,
+    /// Rows not written to file yet when `write_buffer_size` is set. They end at `offset`
+    buffered_rows: Vec<u8>,
+}
+
+impl FileDataStorage {
+    pub fn new(
+        storage_id: StorageId,
+        data_file: File,
+        write_offset: usize,
+        capacity: usize,
+        formatter: Arc<BitcaskyFormatter>,
+        options: Arc<BitcaskyOptions>,
+    ) -> Result<Self> {
+        Ok(FileDataStorage {
+            data_file,
+            storage_id,
+            offset: write_offset,
+            capacity,
+            options,
+            formatter,
+            read_value_times: 0,
+            write_times: 0,
+            write_buffer: vec![],
+            buffered_rows: vec![],
+        })
+    }
+
+    fn ensure_capacity(&mut self, row_size: usize) -> Result<()> {
+        let required_capacity = row_size + self.offset;
+        if required_capacity > self.options.database.storage.max_data_file_size {
+            return Err(DataStorageError::StorageOverflow(self.storage_id));
+        }
+
+        if required_capacity > self.capacity {
+            let mut new_capacity =
+                std::cmp::max(required_capacity + 8, self.capacity + self.capacity / 3);
+            new_capacity = std::cmp::min(
+                new_capacity,
+                self.options.database.storage.max_data_file_size,
+            );
+
+            new_capacity = crate::fs::resize_file(&self.data_file, new_capacity)?;
+            debug!(
+                "data file with storage id: {:?}, require {} bytes, resizing from {} to {} bytes. ",
+                self.storage_id, required_capacity, self.capacity, new_capacity
+            );
+            self.capacity = new_capacity;
+        }
+        Ok(())
+    }
+
+    /// Fsync the data file so its length and content are both durable
+    pub fn sync_all(&mut self) -> Result<()> {
+        self.write_buffered_rows()?;
+        Ok(self.data_file.sync_all()?)
+    }
+
+    /// Hands buffered rows to the OS without syncing them
+    pub fn write_buffered_rows(&mut self) -> Result<()> {
+        if self.buffered_rows.is_empty() {
+            return Ok(());
+        }
+        let mut f = &self.data_file;
+        f.seek(SeekFrom::Start(
+            (self.offset - self.buffered_rows.len()) as u64,
+        ))?;
+        f.write_all(&self.buffered_rows)?;
+        self.buffered_rows.clear();
+        Ok(())
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        let mut bs = vec![0; len];
+        let mut f = &self.data_file;
+        f.seek(SeekFrom::Start(offset as u64))?;
+        f.read_exact(&mut bs)?;
+
+        // buffered rows are newer than what is in file
+        let buffered_start = self.offset - self.buffered_rows.len();
+        let start = std::cmp::max(offset, buffered_start);
+        let end = std::cmp::min(offset + len, self.offset);
+        if start < end {
+            bs[start - offset..end - offset]
+                .copy_from_slice(&self.buffered_rows[start - buffered_start..end - buffered_start]);
+        }
+        Ok(bs)
+    }
+
+    /// Whether all the bytes from offset to the end of file are zero
+    fn is_zero_from(&self, offset: usize) -> Result<bool> {
+        let mut start = offset;
+        while start < self.capacity {
+            let end = std::cmp::min(start + ZERO_CHECK_CHUNK_SIZE, self.capacity);
+            if self.read_at(start, end - start)?.iter().any(|b| *b != 0) {
+                return Ok(false);
+            }
+            start = end;
+        }
+        Ok(true)
+    }
+
+    /// Value of a row expired at `now` is not read
+    fn do_read_row(
+        &mut self,
+        offset: usize,
+        verify_crc: bool,
+        now: u64,
+    ) -> Result<Option<MetaAndKeyValue>> {
+        if offset > self.capacity {
+            return Err(DataStorageError::EofError());
+        }
+
+        if offset == self.capacity {
+            return Ok(None);
+        }
+
+        let header_size = self.formatter.row_header_size();
+        if offset + header_size >= self.capacity {
+            // no room for another row in the rest of the file
+            if self.is_zero_from(offset)? {
+                return Ok(None);
+            }
+            return Err(DataStorageError::EofError());
+        }
+
+        let header = self
+            .formatter
+            .decode_row_header(&self.read_at(offset, header_size)?);
+        if header.meta.key_size == 0 {
+            return Ok(None);
+        }
+
+        if offset + header_size + header.meta.key_size + header.meta.value_size > self.capacity {
+            return Err(DataStorageError::EofError());
+        }
+
+        let mut kv_bs = self.read_at(
+            offset + header_size,
+            header.meta.key_size + header.meta.value_size,
+        )?;
+
+        if verify_crc {
+            self.formatter.validate_key_value(&header, &kv_bs)?;
+        }
+
+        let v = kv_bs.split_off(header.meta.key_size);
+        if header.meta.expire_timestamp != 0 && header.meta.expire_timestamp <= now {
+            Ok(Some((header.meta, kv_bs, None)))
+        } else {
+            Ok(Some((header.meta, kv_bs, Some(v))))
+        }
+    }
+
+    /// Move offset to the next row without validating the current one.
+    /// Returns false if the row under current offset has no valid size info to skip.
+    pub fn skip_row(&mut self) -> bool {
+        match self.row_end(self.offset) {
+            Some(row_end) => {
+                let net_size = row_end - self.offset;
+                self.offset += net_size + padding(net_size);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// End of the row at offset according to the size info in its header.
+    /// Returns None if the header is empty or the sizes in it are invalid.
+    fn row_end(&self, offset: usize) -> Option<usize> {
+        let header_size = self.formatter.row_header_size();
+        if offset + header_size >= self.capacity {
+            return None;
+        }
+        let header = self
+            .formatter
+            .decode_row_header(&self.read_at(offset, header_size).ok()?);
+        if header.meta.key_size == 0 {
+            return None;
+        }
+        header_size
+            .checked_add(header.meta.key_size)
+            .and_then(|s| s.checked_add(header.meta.value_size))
+            .and_then(|s| s.checked_add(offset))
+            .filter(|row_end| *row_end <= self.capacity)
+    }
+
+    /// A broken row under current offset is a torn write if nothing was written after it
+    fn is_torn_tail(&self) -> bool {
+        let broken_row_end = self.row_end(self.offset).unwrap_or(std::cmp::min(
+            self.offset + self.formatter.row_header_size(),
+            self.capacity,
+        ));
+        self.is_zero_from(broken_row_end).unwrap_or(false)
+    }
+
+    /// Move offset to the end of valid rows. Returns true if it stops at a torn write,
+    /// or an error if a corrupted row is followed by other data.
+    pub fn seek_to_last_valid_row(&mut self) -> Result<bool> {
+        loop {
+            match self.read_next_row() {
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(false),
+                Err(e) => {
+                    // corruption in the middle of the file can not be fixed by truncating
+                    if !self.is_torn_tail() {
+                        return Err(e);
+                    }
+                    debug!(
+                        "found torn write in data file with storage id: {}, offset: {}, error: {}",
+                        self.storage_id, self.offset, e
+                    );
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Clear everything after current offset so new rows can be appended here
+    pub fn truncate_torn_tail(&mut self) -> Result<()> {
+        // shrinking then growing the file back fills the tail with zeros
+        self.data_file.set_len(self.offset as u64)?;
+        self.data_file.set_len(self.capacity as u64)?;
+        self.flush()
+    }
+
+    /// Reads the header of the row at offset, and returns a reader over all the bytes of the row
+    pub fn raw_row(&mut self, offset: usize) -> Result<(RowHeader, RawRow<'_>)> {
+        let header_size = self.formatter.row_header_size();
+        if offset + header_size > self.capacity {
+            return Err(DataStorageError::EofError());
+        }
+        // the reader reads from file, where buffered rows are not written yet
+        self.write_buffered_rows()?;
+        let header = self
+            .formatter
+            .decode_row_header(&self.read_at(offset, header_size)?);
+        let row_size = raw_row_size(&header, header_size, offset, self.capacity)?;
+        let mut f = &self.data_file;
+        f.seek(SeekFrom::Start(offset as u64))?;
+        Ok((header, RawRow::File(f.take(row_size as u64))))
+    }
+
+    /// Appends a row copied from another storage as it is. Bytes copied from another data file
+    /// are copied in kernel where supported, like by `copy_file_range` on Linux
+    pub fn append_raw_row(&mut self, row: RawRow) -> Result<RowLocation> {
+        let row_size = row.size();
+        self.ensure_capacity(row_size)?;
+        // the row is written to file directly, after the rows buffered before it
+        self.write_buffered_rows()?;
+
+        let row_offset = self.offset;
+        let mut f = &self.data_file;
+        f.seek(SeekFrom::Start(row_offset as u64))?;
+        let ret = match row {
+            RawRow::File(mut r) => io::copy(&mut r, &mut f).and_then(|copied| {
+                if copied != row_size as u64 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                Ok(())
+            }),
+            RawRow::Mapped(bs) => f.write_all(bs),
+        };
+        if let Err(e) = ret {
+            // a partially written row is taken as a corrupted row on recovery
+            f.seek(SeekFrom::Start(row_offset as u64))?;
+            f.write_all(&vec![0; row_size])?;
+            return Err(e.into());
+        }
+        self.offset += row_size;
+        self.write_times += 1;
+
+        Ok(RowLocation {
+            storage_id: self.storage_id,
+            row_offset,
+            row_size,
+        })
+    }
+}
+
+impl DataStorageWriter for FileDataStorage {
+    fn write_row<K: AsRef<[u8]>, V: Deref<Target = [u8]>>(
+        &mut self,
+        row: &RowToWrite<K, V>,
+    ) -> super::Result<RowLocation> {
+        let net_size = self.formatter.net_row_size(row);
+        let row_size = net_size + padding(net_size);
+        self.ensure_capacity(row_size)?;
+
+        let value_offset = self.offset;
+        let write_buffer_size = self.options.database.storage.write_buffer_size;
+        if write_buffer_size == 0 {
+            self.write_buffer.clear();
+            self.write_buffer.resize(row_size, 0);
+            self.formatter.encode_row(row, &mut self.write_buffer);
+
+            let mut f = &self.data_file;
+            f.seek(SeekFrom::Start(value_offset as u64))?;
+            f.write_all(&self.write_buffer)?;
+        } else {
+            let start = self.buffered_rows.len();
+            self.buffered_rows.resize(start + row_size, 0);
+            self.formatter
+                .encode_row(row, &mut self.buffered_rows[start..]);
+        }
+        self.offset += row_size;
+        self.write_times += 1;
+        if write_buffer_size > 0 && self.buffered_rows.len() >= write_buffer_size {
+            self.write_buffered_rows()?;
+        }
+
+        Ok(RowLocation {
+            storage_id: self.storage_id,
+            row_offset: value_offset,
+            row_size,
+        })
+    }
+
+    fn rewind(&mut self) -> super::Result<()> {
+        self.write_buffered_rows()?;
+        self.data_file.flush()?;
+        self.offset = FILE_HEADER_SIZE;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> super::Result<()> {
+        self.write_buffered_rows()?;
+        Ok(self.data_file.sync_data()?)
+    }
+}
+
+impl DataStorageReader for FileDataStorage {
+    fn read_value(&mut self, row_offset: usize) -> super::Result<Option<TimedValue<Vec<u8>>>> {
+        self.read_value_at(row_offset, self.options.clock.now())
+    }
+
+    fn read_value_at(
+        &mut self,
+        row_offset: usize,
+        now: u64,
+    ) -> super::Result<Option<TimedValue<Vec<u8>>>> {
+        let storage_id = self.storage_id;
+        let verify_crc = self.options.database.storage.verify_crc_on_read;
+        let row = self
+            .do_read_row(row_offset, verify_crc, now)
+            .map_err(|e| match e {
+                DataStorageError::DataStorageFormatter(FormatterError::CrcCheckFailed {
+                    expected_crc,
+                    actual_crc,
+                }) => DataStorageError::CrcCheckFailed {
+                    storage_id,
+                    row_offset,
+                    expected_crc,
+                    actual_crc,
+                },
+                _ => DataStorageError::ReadRowFailed(storage_id, e.to_string()),
+            })?;
+        let Some((meta, _, v_op)) = row else {
+            return Err(DataStorageError::ReadRowFailed(
+                self.storage_id,
+                format!("no value found at offset: {}", row_offset),
+            ));
+        };
+
+        self.read_value_times += 1;
+        Ok(v_op.and_then(|v| {
+            TimedValue {
+                value: v,
+                expire_timestamp: meta.expire_timestamp,
+                write_timestamp: meta.write_timestamp,
+            }
+            .validate()
+        }))
+    }
+
+    fn read_next_row(&mut self) -> super::Result<Option<RowToRead>> {
+        let row_offset = self.offset;
+        let Some((meta, key, v)) = self.do_read_row(row_offset, true, self.options.clock.now())?
+        else {
+            return Ok(None);
+        };
+
+        let net_size: usize = self.formatter.row_header_size() + meta.key_size + meta.value_size;
+        let row_size = net_size + padding(net_size);
+        let row_to_read = RowToRead {
+            key,
+            value: TimedValue::expirable_value(v.unwrap_or_default(), meta.expire_timestamp)
+                .with_write_timestamp(meta.write_timestamp),
+            row_location: RowLocation {
+                storage_id: self.storage_id,
+                row_offset,
+                row_size,
+            },
+        };
+
+        self.offset += row_size;
+
+        Ok(Some(row_to_read))
+    }
+
+    fn seek_to_end(&mut self) -> Result<()> {
+        if self.seek_to_last_valid_row()? {
+            warn!(
+                "truncate torn write at the end of data file with storage id: {}, offset: {}",
+                self.storage_id, self.offset
+            );
+            return self.truncate_torn_tail();
+        }
+        Ok(())
+    }
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::create_data_file;
+    use crate::options::DataSotrageType;
+    use crate::{clock::DebugClock, formatter::FILE_HEADER_SIZE, fs::FileType};
+
+    use super::*;
+
+    use crate::test_utils::get_temporary_directory_path;
+    use test_log::test;
+
+    fn get_options(max_size: usize) -> BitcaskyOptions {
+        BitcaskyOptions::default()
+            .max_data_file_size(max_size)
+            .init_data_file_capacity(max_size)
+            .storage_type(DataSotrageType::File)
+    }
+
+    fn get_file_storage(options: BitcaskyOptions) -> FileDataStorage {
+        let dir = get_temporary_directory_path();
+        let formatter = Arc::new(BitcaskyFormatter::default());
+        create_data_file(&dir, FileType::DataFile, Some(1), &formatter, false, 512).unwrap();
+        open_file_storage(&dir, options)
+    }
+
+    fn open_file_storage(dir: &std::path::Path, options: BitcaskyOptions) -> FileDataStorage {
+        let file = crate::fs::open_file(dir, FileType::DataFile, Some(1))
+            .unwrap()
+            .file;
+        let meta = file.metadata().unwrap();
+        FileDataStorage::new(
+            1,
+            file,
+            FILE_HEADER_SIZE,
+            meta.len() as usize,
+            Arc::new(BitcaskyFormatter::default()),
+            Arc::new(options),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_read_write_expired_value() {
+        let time = 1000;
+        let clock = Arc::new(DebugClock::new(time));
+        let mut storage = get_file_storage(get_options(1024).debug_clock(clock.clone()));
+
+        let row_location1 = storage
+            .write_row(&RowToWrite::new_with_timestamp(
+                b"key1".to_vec(),
+                b"value1".to_vec(),
+                time,
+            ))
+            .unwrap();
+        let row_location2 = storage
+            .write_row(&RowToWrite::new_with_timestamp(
+                b"key2".to_vec(),
+                b"value2".to_vec(),
+                time + 1,
+            ))
+            .unwrap();
+
+        assert!(storage
+            .read_value(row_location1.row_offset)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            b"value2".to_vec(),
+            *storage
+                .read_value(row_location2.row_offset)
+                .unwrap()
+                .unwrap()
+        );
+
+        clock.set(time + 1);
+        assert!(storage
+            .read_value(row_location2.row_offset)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_write_overflow() {
+        let mut storage = get_file_storage(get_options(2));
+        storage
+            .write_row(&RowToWrite::new(b"key1".to_vec(), b"value1".to_vec()))
+            .expect_err("overflow");
+    }
+
+    #[test]
+    fn test_expand_file_size() {
+        let mut storage = get_file_storage(get_options(4096));
+        let init_size = storage.data_file.metadata().unwrap().len();
+
+        let locations = (0..40)
+            .map(|i| {
+                storage
+                    .write_row(&RowToWrite::new(
+                        format!("key{}", i).into_bytes(),
+                        format!("value{}", i).into_bytes(),
+                    ))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        assert!(storage.data_file.metadata().unwrap().len() > init_size);
+        for (i, l) in locations.iter().enumerate() {
+            assert_eq!(
+                format!("value{}", i).into_bytes(),
+                *storage.read_value(l.row_offset).unwrap().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_next_row_after_rewind() {
+        let mut storage = get_file_storage(get_options(1024));
+        let location = storage
+            .write_row(&RowToWrite::new(b"key1".to_vec(), b"value1".to_vec()))
+            .unwrap();
+        storage
+            .write_row(&RowToWrite::new(b"key2".to_vec(), b"value2".to_vec()))
+            .unwrap();
+        storage.rewind().unwrap();
+
+        let r = storage.read_next_row().unwrap().unwrap();
+        assert_eq!(b"key1".to_vec(), r.key);
+        assert_eq!(b"value1".to_vec(), r.value.value);
+        assert_eq!(location, r.row_location);
+        let r = storage.read_next_row().unwrap().unwrap();
+        assert_eq!(b"key2".to_vec(), r.key);
+        assert!(storage.read_next_row().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_truncate_torn_tail() {
+        let mut storage = get_file_storage(get_options(1024));
+        storage
+            .write_row(&RowToWrite::new(b"key1".to_vec(), b"value1".to_vec()))
+            .unwrap();
+        let torn = storage
+            .write_row(&RowToWrite::new(b"key2".to_vec(), b"value2".to_vec()))
+            .unwrap();
+        // break the value of the last row
+        let mut f = &storage.data_file;
+        f.seek(SeekFrom::Start(
+            (torn.row_offset + storage.formatter.row_header_size() + 5) as u64,
+        ))
+        .unwrap();
+        f.write_all(b"x").unwrap();
+
+        storage.rewind().unwrap();
+        storage.seek_to_end().unwrap();
+        assert_eq!(torn.row_offset, storage.offset());
+        assert!(storage.is_zero_from(torn.row_offset).unwrap());
+    }
+
+    #[test]
+    fn test_buffered_write() {
+        let mut storage = get_file_storage(get_options(1024).write_buffer_size(512));
+        let location = storage
+            .write_row(&RowToWrite::new(b"key1".to_vec(), b"value1".to_vec()))
+            .unwrap();
+        assert_eq!(FILE_HEADER_SIZE, location.row_offset);
+        assert_eq!(location.row_offset + location.row_size, storage.offset());
+        // buffered row is readable but not in file yet
+        assert_eq!(
+            b"value1".to_vec(),
+            *storage.read_value(location.row_offset).unwrap().unwrap()
+        );
+        assert!(storage.is_zero_from(storage.offset()).unwrap());
+        let mut in_file = vec![0; location.row_size];
+        let mut f = storage.data_file.try_clone().unwrap();
+        f.seek(SeekFrom::Start(location.row_offset as u64)).unwrap();
+        f.read_exact(&mut in_file).unwrap();
+        assert!(in_file.iter().all(|b| *b == 0));
+
+        storage.flush().unwrap();
+        f.seek(SeekFrom::Start(location.row_offset as u64)).unwrap();
+        f.read_exact(&mut in_file).unwrap();
+        assert_eq!(
+            storage
+                .read_at(location.row_offset, location.row_size)
+                .unwrap(),
+            in_file
+        );
+    }
+
+    #[test]
+    fn test_write_full_buffer() {
+        let mut storage = get_file_storage(get_options(1024).write_buffer_size(64));
+        let location = storage
+            .write_row(&RowToWrite::new(b"key1".to_vec(), b"value1".to_vec()))
+            .unwrap();
+        assert!(!storage.buffered_rows.is_empty());
+        for _ in 1..64usize.div_ceil(location.row_size) {
+            storage
+                .write_row(&RowToWrite::new(b"key1".to_vec(), b"value1".to_vec()))
+                .unwrap();
+        }
+        // written to file once the buffer is full
+        assert!(storage.buffered_rows.is_empty());
+        storage.rewind().unwrap();
+        assert_eq!(
+            b"key1".to_vec(),
+            storage.read_next_row().unwrap().unwrap().key
+        );
+    }
+
+    #[test]
+    fn test_recover_flushed_rows_only() {
+        let dir = get_temporary_directory_path();
+        let formatter = Arc::new(BitcaskyFormatter::default());
+        create_data_file(&dir, FileType::DataFile, Some(1), &formatter, false, 512).unwrap();
+        let options = || get_options(1024).write_buffer_size(512);
+        let flushed_end = {
+            let mut storage = open_file_storage(&dir, options());
+            storage
+                .write_row(&RowToWrite::new(b"key1".to_vec(), b"value1".to_vec()))
+                .unwrap();
+            storage.flush().unwrap();
+            let flushed_end = storage.offset();
+            storage
+                .write_row(&RowToWrite::new(b"key2".to_vec(), b"value2".to_vec()))
+                .unwrap();
+            // crash without flush
+            flushed_end
+        };
+
+        let mut storage = open_file_storage(&dir, options());
+        let r = storage.read_next_row().unwrap().unwrap();
+        assert_eq!(b"key1".to_vec(), r.key);
+        assert!(storage.read_next_row().unwrap().is_none());
+        storage.rewind().unwrap();
+        storage.seek_to_end().unwrap();
+        assert_eq!(flushed_end, storage.offset());
+    }
+}
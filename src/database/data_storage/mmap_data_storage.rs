@@ -1,20 +1,49 @@
-use std::{fs::File, io::Write, mem, ops::Deref, sync::Arc, vec};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    mem,
+    ops::Deref,
+    sync::Arc,
+    vec,
+};
 
+use crate::logging::{debug, warn};
 use crate::options::BitcaskyOptions;
 use crate::{
     clock::Clock,
-    formatter::{padding, BitcaskyFormatter, Formatter, RowMeta, RowToWrite, FILE_HEADER_SIZE},
+    formatter::{
+        padding, BitcaskyFormatter, Formatter, FormatterError, RowHeader, RowMeta, RowToWrite,
+        FILE_HEADER_SIZE,
+    },
     storage_id::StorageId,
 };
-use log::debug;
-use memmap2::{MmapMut, MmapOptions};
+use memmap2::{Mmap, MmapMut, MmapOptions};
 
 use crate::database::{common::RowToRead, DataStorageError, RowLocation, TimedValue};
 
-use super::{DataStorageReader, DataStorageWriter, Result};
+use super::{raw_row_size, DataStorageReader, DataStorageWriter, RawRow, Result};
 
 type MetaAndKeyValue<'a> = (RowMeta, &'a [u8], Option<Vec<u8>>);
 
+/// Mapping of the data file. Sealed data files and those opened without write permission are
+/// mapped read only.
+#[derive(Debug)]
+enum MapView {
+    Writable(MmapMut),
+    ReadOnly(Mmap),
+}
+
+impl Deref for MapView {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            MapView::Writable(m) => m,
+            MapView::ReadOnly(m) => m,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct MmapDataStorage {
     pub offset: usize,
@@ -25,7 +54,7 @@ pub struct MmapDataStorage {
     storage_id: StorageId,
     options: Arc<BitcaskyOptions>,
     formatter: Arc<BitcaskyFormatter>,
-    map_view: MmapMut,
+    map_view: MapView,
 }
 
 impl MmapDataStorage {
@@ -37,11 +66,13 @@ impl MmapDataStorage {
         formatter: Arc<BitcaskyFormatter>,
         options: Arc<BitcaskyOptions>,
     ) -> Result<Self> {
-        let mmap = unsafe {
-            MmapOptions::new()
-                .offset(0)
-                .len(capacity)
-                .map_mut(&data_file)?
+        let map_view = match unsafe { MmapOptions::new().len(capacity).map_mut(&data_file) } {
+            Ok(mmap) => MapView::Writable(mmap),
+            // data file opened without write permission
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                MapView::ReadOnly(unsafe { MmapOptions::new().len(capacity).map(&data_file)? })
+            }
+            Err(e) => return Err(e.into()),
         };
 
         Ok(MmapDataStorage {
@@ -51,18 +82,16 @@ impl MmapDataStorage {
             capacity,
             options,
             formatter,
-            map_view: mmap,
+            map_view,
             read_value_times: 0,
             write_times: 0,
         })
     }
 
-    fn ensure_capacity<K: AsRef<[u8]>, V: Deref<Target = [u8]>>(
-        &mut self,
-        row: &RowToWrite<K, V>,
-    ) -> Result<()> {
-        let mut row_size = self.formatter.net_row_size(row);
-        row_size += padding(row_size);
+    fn ensure_capacity(&mut self, row_size: usize) -> Result<()> {
+        if let MapView::ReadOnly(_) = self.map_view {
+            return Err(DataStorageError::PermissionDenied(self.storage_id));
+        }
         let required_capacity = row_size + self.offset;
         if required_capacity > self.options.database.storage.max_data_file_size {
             return Err(DataStorageError::StorageOverflow(self.storage_id));
@@ -83,27 +112,51 @@ impl MmapDataStorage {
                 "data file with storage id: {:?}, require {} bytes, resizing from {} to {} bytes. ",
                 self.storage_id, required_capacity, self.capacity, new_capacity
             );
-            let mut mmap = unsafe {
+            let mut mmap = MapView::Writable(unsafe {
                 MmapOptions::new()
                     .offset(0)
                     .len(new_capacity)
                     .map_mut(&self.data_file)?
-            };
+            });
             mem::swap(&mut mmap, &mut self.map_view);
             self.capacity = new_capacity;
         }
         Ok(())
     }
 
-    fn as_mut_slice(&mut self) -> &mut [u8] {
-        &mut self.map_view[0..self.capacity]
+    /// Fsync the data file so its length and content are both durable
+    pub fn sync_all(&mut self) -> Result<()> {
+        Ok(self.data_file.sync_all()?)
+    }
+
+    /// Maps the data file read only after it's sealed, so rows on it can not be overwritten
+    /// by mistake. Rows must be flushed before.
+    pub fn seal(&mut self) -> Result<()> {
+        if let MapView::Writable(_) = self.map_view {
+            let mmap = unsafe { MmapOptions::new().len(self.capacity).map(&self.data_file)? };
+            self.map_view = MapView::ReadOnly(mmap);
+        }
+        Ok(())
+    }
+
+    fn as_mut_slice(&mut self) -> Result<&mut [u8]> {
+        match &mut self.map_view {
+            MapView::Writable(m) => Ok(&mut m[0..self.capacity]),
+            MapView::ReadOnly(_) => Err(DataStorageError::PermissionDenied(self.storage_id)),
+        }
     }
 
     fn as_slice(&self) -> &[u8] {
         &self.map_view[0..self.capacity]
     }
 
-    fn do_read_row(&mut self, offset: usize) -> Result<Option<MetaAndKeyValue>> {
+    /// Value of a row expired at `now` is not read
+    fn do_read_row(
+        &mut self,
+        offset: usize,
+        verify_crc: bool,
+        now: u64,
+    ) -> Result<Option<MetaAndKeyValue>> {
         if offset > self.capacity {
             return Err(DataStorageError::EofError());
         }
@@ -114,6 +167,10 @@ impl MmapDataStorage {
 
         let header_size = self.formatter.row_header_size();
         if offset + header_size >= self.capacity {
+            // no room for another row in the rest of the file
+            if self.as_slice()[offset..].iter().all(|b| *b == 0) {
+                return Ok(None);
+            }
             return Err(DataStorageError::EofError());
         }
 
@@ -133,18 +190,133 @@ impl MmapDataStorage {
 
         let kv_bs = &self.as_slice()[offset + self.formatter.row_header_size()..offset + net_size];
 
-        self.formatter.validate_key_value(&header, kv_bs)?;
+        if verify_crc {
+            self.formatter.validate_key_value(&header, kv_bs)?;
+        }
 
         let k = &kv_bs[0..header.meta.key_size];
-        if header.meta.expire_timestamp != 0
-            && header.meta.expire_timestamp <= self.options.clock.now()
-        {
+        if header.meta.expire_timestamp != 0 && header.meta.expire_timestamp <= now {
             Ok(Some((header.meta, k, None)))
         } else {
             let v = Some(kv_bs[header.meta.key_size..].into());
             Ok(Some((header.meta, k, v)))
         }
     }
+
+    /// Move offset to the next row without validating the current one.
+    /// Returns false if the row under current offset has no valid size info to skip.
+    pub fn skip_row(&mut self) -> bool {
+        match self.row_end(self.offset) {
+            Some(row_end) => {
+                let net_size = row_end - self.offset;
+                self.offset += net_size + padding(net_size);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// End of the row at offset according to the size info in its header.
+    /// Returns None if the header is empty or the sizes in it are invalid.
+    fn row_end(&self, offset: usize) -> Option<usize> {
+        let header_size = self.formatter.row_header_size();
+        if offset + header_size >= self.capacity {
+            return None;
+        }
+        let header = self
+            .formatter
+            .decode_row_header(&self.as_slice()[offset..(offset + header_size)]);
+        if header.meta.key_size == 0 {
+            return None;
+        }
+        header_size
+            .checked_add(header.meta.key_size)
+            .and_then(|s| s.checked_add(header.meta.value_size))
+            .and_then(|s| s.checked_add(offset))
+            .filter(|row_end| *row_end <= self.capacity)
+    }
+
+    /// A broken row under current offset is a torn write if nothing was written after it
+    fn is_torn_tail(&self) -> bool {
+        let broken_row_end = self.row_end(self.offset).unwrap_or(std::cmp::min(
+            self.offset + self.formatter.row_header_size(),
+            self.capacity,
+        ));
+        self.as_slice()[broken_row_end..].iter().all(|b| *b == 0)
+    }
+
+    /// Move offset to the end of valid rows. Returns true if it stops at a torn write,
+    /// or an error if a corrupted row is followed by other data.
+    pub fn seek_to_last_valid_row(&mut self) -> Result<bool> {
+        loop {
+            match self.read_next_row() {
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(false),
+                Err(e) => {
+                    // corruption in the middle of the file can not be fixed by truncating
+                    if !self.is_torn_tail() {
+                        return Err(e);
+                    }
+                    debug!(
+                        "found torn write in data file with storage id: {}, offset: {}, error: {}",
+                        self.storage_id, self.offset, e
+                    );
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Clear everything after current offset so new rows can be appended here
+    pub fn truncate_torn_tail(&mut self) -> Result<()> {
+        let offset = self.offset;
+        let capacity = self.capacity;
+        self.as_mut_slice()?[offset..capacity].fill(0);
+        self.flush()
+    }
+
+    /// Reads the header of the row at offset, and returns all the bytes of the row
+    pub fn raw_row(&mut self, offset: usize) -> Result<(RowHeader, RawRow<'_>)> {
+        let header_size = self.formatter.row_header_size();
+        if offset + header_size > self.capacity {
+            return Err(DataStorageError::EofError());
+        }
+        let header = self
+            .formatter
+            .decode_row_header(&self.as_slice()[offset..(offset + header_size)]);
+        let row_size = raw_row_size(&header, header_size, offset, self.capacity)?;
+        Ok((
+            header,
+            RawRow::Mapped(&self.as_slice()[offset..(offset + row_size)]),
+        ))
+    }
+
+    /// Appends a row copied from another storage as it is
+    pub fn append_raw_row(&mut self, row: RawRow) -> Result<RowLocation> {
+        let row_size = row.size();
+        self.ensure_capacity(row_size)?;
+
+        let row_offset = self.offset;
+        let dest = &mut self.as_mut_slice()?[row_offset..(row_offset + row_size)];
+        match row {
+            RawRow::File(mut r) => {
+                if let Err(e) = r.read_exact(dest) {
+                    // a partially written row is taken as a corrupted row on recovery
+                    dest.fill(0);
+                    return Err(e.into());
+                }
+            }
+            RawRow::Mapped(bs) => dest.copy_from_slice(bs),
+        }
+        self.offset += row_size;
+        self.write_times += 1;
+
+        Ok(RowLocation {
+            storage_id: self.storage_id,
+            row_offset,
+            row_size,
+        })
+    }
 }
 
 impl DataStorageWriter for MmapDataStorage {
@@ -152,11 +324,12 @@ impl DataStorageWriter for MmapDataStorage {
         &mut self,
         row: &RowToWrite<K, V>,
     ) -> super::Result<RowLocation> {
-        self.ensure_capacity(row)?;
+        let net_size = self.formatter.net_row_size(row);
+        self.ensure_capacity(net_size + padding(net_size))?;
 
         let value_offset = self.offset;
         let formatter = self.formatter.clone();
-        let net_size = formatter.encode_row(row, &mut self.as_mut_slice()[value_offset..]);
+        let net_size = formatter.encode_row(row, &mut self.as_mut_slice()?[value_offset..]);
         let row_size = net_size + padding(net_size);
         self.offset += row_size;
         self.write_times += 1;
@@ -175,16 +348,39 @@ impl DataStorageWriter for MmapDataStorage {
     }
 
     fn flush(&mut self) -> super::Result<()> {
-        Ok(self.map_view.flush_range(0, self.capacity)?)
+        match &self.map_view {
+            MapView::Writable(m) => Ok(m.flush_range(0, self.capacity)?),
+            MapView::ReadOnly(_) => Ok(()),
+        }
     }
 }
 
 impl DataStorageReader for MmapDataStorage {
     fn read_value(&mut self, row_offset: usize) -> super::Result<Option<TimedValue<Vec<u8>>>> {
+        self.read_value_at(row_offset, self.options.clock.now())
+    }
+
+    fn read_value_at(
+        &mut self,
+        row_offset: usize,
+        now: u64,
+    ) -> super::Result<Option<TimedValue<Vec<u8>>>> {
         let storage_id = self.storage_id;
+        let verify_crc = self.options.database.storage.verify_crc_on_read;
         let row = self
-            .do_read_row(row_offset)
-            .map_err(|e| DataStorageError::ReadRowFailed(storage_id, e.to_string()))?;
+            .do_read_row(row_offset, verify_crc, now)
+            .map_err(|e| match e {
+                DataStorageError::DataStorageFormatter(FormatterError::CrcCheckFailed {
+                    expected_crc,
+                    actual_crc,
+                }) => DataStorageError::CrcCheckFailed {
+                    storage_id,
+                    row_offset,
+                    expected_crc,
+                    actual_crc,
+                },
+                _ => DataStorageError::ReadRowFailed(storage_id, e.to_string()),
+            })?;
         if row.is_none() {
             return Err(DataStorageError::ReadRowFailed(
                 self.storage_id,
@@ -198,6 +394,7 @@ impl DataStorageReader for MmapDataStorage {
                 Ok(TimedValue {
                     value: v,
                     expire_timestamp: meta.expire_timestamp,
+                    write_timestamp: meta.write_timestamp,
                 }
                 .validate())
             } else {
@@ -210,7 +407,7 @@ impl DataStorageReader for MmapDataStorage {
 
     fn read_next_row(&mut self) -> super::Result<Option<RowToRead>> {
         let row_offset = self.offset;
-        let row = self.do_read_row(row_offset)?;
+        let row = self.do_read_row(row_offset, true, self.options.clock.now())?;
         if row.is_none() {
             return Ok(None);
         }
@@ -221,7 +418,8 @@ impl DataStorageReader for MmapDataStorage {
         let row_size = net_size + padding(net_size);
         let row_to_read = RowToRead {
             key,
-            value: TimedValue::expirable_value(v.unwrap_or(vec![]), meta.expire_timestamp),
+            value: TimedValue::expirable_value(v.unwrap_or(vec![]), meta.expire_timestamp)
+                .with_write_timestamp(meta.write_timestamp),
             row_location: RowLocation {
                 storage_id: self.storage_id,
                 row_offset,
@@ -235,10 +433,12 @@ impl DataStorageReader for MmapDataStorage {
     }
 
     fn seek_to_end(&mut self) -> Result<()> {
-        loop {
-            if self.read_next_row()?.is_none() {
-                break;
-            }
+        if self.seek_to_last_valid_row()? {
+            warn!(
+                "truncate torn write at the end of data file with storage id: {}, offset: {}",
+                self.storage_id, self.offset
+            );
+            return self.truncate_torn_tail();
         }
         Ok(())
     }
@@ -461,6 +661,63 @@ mod tests {
         assert!(storage.read_next_row().unwrap().is_none());
     }
 
+    #[test]
+    fn test_seal() {
+        let mut storage = get_file_storage(get_options(1024));
+        let row_to_write: RowToWrite<&[u8], &[u8]> = RowToWrite::new(b"key1", b"value1");
+        let location = storage.write_row(&row_to_write).unwrap();
+        storage.flush().unwrap();
+
+        storage.seal().unwrap();
+        assert_matches!(
+            storage.write_row(&row_to_write).unwrap_err(),
+            DataStorageError::PermissionDenied(1)
+        );
+        assert_eq!(
+            b"value1".to_vec(),
+            *storage.read_value(location.row_offset).unwrap().unwrap()
+        );
+        storage.flush().unwrap();
+    }
+
+    #[test]
+    fn test_open_without_write_permission() {
+        let dir = get_temporary_directory_path();
+        let formatter = Arc::new(BitcaskyFormatter::default());
+        let mut storage = MmapDataStorage::new(
+            1,
+            create_data_file(&dir, FileType::DataFile, Some(1), &formatter, false, 512).unwrap(),
+            FILE_HEADER_SIZE,
+            512,
+            formatter.clone(),
+            Arc::new(get_options(1024)),
+        )
+        .unwrap();
+        let row_to_write: RowToWrite<&[u8], &[u8]> = RowToWrite::new(b"key1", b"value1");
+        let location = storage.write_row(&row_to_write).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        let file = File::open(FileType::DataFile.get_path(&dir, Some(1))).unwrap();
+        let mut storage = MmapDataStorage::new(
+            1,
+            file,
+            FILE_HEADER_SIZE,
+            512,
+            formatter,
+            Arc::new(get_options(1024)),
+        )
+        .unwrap();
+        assert_eq!(
+            b"value1".to_vec(),
+            *storage.read_value(location.row_offset).unwrap().unwrap()
+        );
+        assert_matches!(
+            storage.write_row(&row_to_write).unwrap_err(),
+            DataStorageError::PermissionDenied(1)
+        );
+    }
+
     #[test]
     fn test_rewind() {
         let mut storage = get_file_storage(get_options(1024));
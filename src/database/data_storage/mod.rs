@@ -1,27 +1,43 @@
+pub mod erlang_data_storage;
+pub mod file_data_storage;
+#[cfg(feature = "mmap")]
 pub mod mmap_data_storage;
+mod value_reader;
+pub use self::value_reader::ValueReader;
 
-use log::{debug, error};
+use crate::logging::{debug, error, warn};
+use fail::fail_point;
 use std::{
     fs::{File, Metadata},
+    io::{Read, Seek, SeekFrom, Take},
     ops::Deref,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 use thiserror::Error;
 
 use crate::{
+    clock::Clock,
+    codec::ValueCodecError,
     database::create_data_file,
-    options::{BitcaskyOptions, SyncStrategy},
+    options::{BitcaskyOptions, DataSotrageType, FormatCompat, SyncStrategy},
+    tombstone::{is_tombstone, TOMBSTONE_VALUE},
 };
 use crate::{
     formatter::{
-        self, get_formatter_from_file, BitcaskyFormatter, FormatterError, RowToWrite,
-        FILE_HEADER_SIZE,
+        self, get_formatter_from_file, padding, BitcaskyFormatter, Formatter, FormatterError,
+        RowHeader, RowMeta, RowToWrite, FILE_HEADER_SIZE,
     },
     fs::{self, FileType},
     storage_id::StorageId,
 };
 
+use self::erlang_data_storage::ErlangDataStorage;
+use self::file_data_storage::FileDataStorage;
+#[cfg(feature = "mmap")]
 use self::mmap_data_storage::MmapDataStorage;
 
 use super::{common::RowToRead, RowLocation, TimedValue};
@@ -41,14 +57,29 @@ pub enum DataStorageError {
     StorageOverflow(StorageId),
     #[error("No permission to write storage with id: {0}")]
     PermissionDenied(StorageId),
+    #[error("Key size: {0} exceeds max key size: {1}")]
+    KeySizeExceeded(usize, usize),
+    #[error("Value size: {0} exceeds max value size: {1}")]
+    ValueSizeExceeded(usize, usize),
     #[error("Got IO Error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("Got IO Error: {0}")]
     DataStorageFormatter(#[from] FormatterError),
     #[error("Failed to read file header for storage with id: {1}")]
     ReadFileHeaderError(#[source] FormatterError, StorageId),
+    #[error("Data file with id: {0} has no file header, it was not completely created")]
+    IncompleteDataFile(StorageId),
     #[error("Read end of file")]
     EofError(),
+    #[error("Decode value on data file with id: {0}, offset: {1} failed")]
+    DecodeValueFailed(StorageId, usize, #[source] ValueCodecError),
+    #[error("Crc check failed on data file with id: {storage_id}, offset: {row_offset}. expect crc is: {expected_crc}, actual crc is: {actual_crc}")]
+    CrcCheckFailed {
+        storage_id: StorageId,
+        row_offset: usize,
+        expected_crc: u32,
+        actual_crc: u32,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, DataStorageError>;
@@ -68,6 +99,11 @@ pub trait DataStorageReader {
     /// Read value from this storage at row_offset
     fn read_value(&mut self, row_offset: usize) -> Result<Option<TimedValue<Vec<u8>>>>;
 
+    /// Read value from this storage at row_offset like `read_value`, but the value is taken as
+    /// expired only if it expired at `now` instead of the current time
+    fn read_value_at(&mut self, row_offset: usize, now: u64)
+        -> Result<Option<TimedValue<Vec<u8>>>>;
+
     /// Read next value from this storage
     fn read_next_row(&mut self) -> Result<Option<RowToRead>>;
 
@@ -76,12 +112,87 @@ pub trait DataStorageReader {
     fn offset(&self) -> usize;
 }
 
+/// File is shorter than file header or file header is all zeros
+fn is_header_missing(file: &mut File, meta: &Metadata) -> Result<bool> {
+    if meta.len() < FILE_HEADER_SIZE as u64 {
+        return Ok(true);
+    }
+    let mut header = vec![0; FILE_HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+    Ok(header.iter().all(|b| *b == 0))
+}
+
+/// All the bytes of a row including its padding, read from a data file or mapped in memory
 #[derive(Debug)]
+pub enum RawRow<'a> {
+    File(Take<&'a File>),
+    Mapped(&'a [u8]),
+}
+
+impl RawRow<'_> {
+    pub fn size(&self) -> usize {
+        match self {
+            RawRow::File(r) => r.limit() as usize,
+            RawRow::Mapped(bs) => bs.len(),
+        }
+    }
+}
+
+/// Size of the row with the header at offset including its padding, checked against the
+/// capacity of the data file
+fn raw_row_size(
+    header: &RowHeader,
+    header_size: usize,
+    offset: usize,
+    capacity: usize,
+) -> Result<usize> {
+    if header.meta.key_size == 0 {
+        return Err(DataStorageError::EofError());
+    }
+    let net_size = header_size + header.meta.key_size + header.meta.value_size;
+    let row_size = net_size + padding(net_size);
+    if offset + row_size > capacity {
+        return Err(DataStorageError::EofError());
+    }
+    Ok(row_size)
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum DataStorageImpl {
+    #[cfg(feature = "mmap")]
     MmapStorage(MmapDataStorage),
+    FileStorage(FileDataStorage),
+    ErlangStorage(ErlangDataStorage),
+    /// Data file closed by `close_file`, it's reopened on next read
+    Closed(ClosedStorage),
+}
+
+/// What's kept of a storage implementation while its data file is closed
+#[derive(Debug)]
+struct ClosedStorage {
+    offset: usize,
+    capacity: usize,
+    read_value_times: u64,
+    write_times: u64,
+}
+
+/// Runs the same expression on whichever storage implementation is in use
+macro_rules! with_storage_impl {
+    ($storage_impl:expr, $s:ident => $body:expr) => {
+        match $storage_impl {
+            #[cfg(feature = "mmap")]
+            DataStorageImpl::MmapStorage($s) => $body,
+            DataStorageImpl::FileStorage($s) => $body,
+            DataStorageImpl::ErlangStorage($s) => $body,
+            DataStorageImpl::Closed(_) => unreachable!("data file is closed"),
+        }
+    };
 }
 
 #[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DataStorageTelemetry {
     pub storage_id: StorageId,
     pub formatter_version: u8,
@@ -92,6 +203,10 @@ pub struct DataStorageTelemetry {
     pub read_value_times: u64,
     pub write_times: u64,
     pub dead_bytes: usize,
+    /// How many times the data file is reopened after closed to keep open files under
+    /// `max_open_files`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reopen_times: u64,
 }
 
 #[derive(Debug)]
@@ -103,6 +218,16 @@ pub struct DataStorage {
     formatter: Arc<BitcaskyFormatter>,
     dirty: bool,
     dead_bytes: usize,
+    synced_offset: usize,
+    /// Set by `transit_to_readonly`, no more rows can be written after that
+    sealed: bool,
+    /// Reused to check rows read from file by `copy_row_to`
+    copy_buffer: Vec<u8>,
+    /// Set on every read, cleared by `take_accessed`
+    accessed: bool,
+    reopen_times: u64,
+    /// Counts open data files of stable storages, see `count_open_files`
+    open_files: Option<Arc<AtomicUsize>>,
 }
 
 impl DataStorage {
@@ -149,13 +274,33 @@ impl DataStorage {
         options: Arc<BitcaskyOptions>,
     ) -> Result<Self> {
         let path = database_dir.as_ref().to_path_buf();
+        if options.database.storage.format_compat == FormatCompat::ErlangBitcask
+            && !FileType::DataFile
+                .get_path(&path, Some(storage_id))
+                .exists()
+            && FileType::ErlangDataFile
+                .get_path(&path, Some(storage_id))
+                .exists()
+        {
+            return DataStorage::open_erlang_bitcask(&path, storage_id, options);
+        }
         let mut data_file = fs::open_file(&path, FileType::DataFile, Some(storage_id))?;
         debug!(
             "Open storage under path: {:?} with storage id: {}",
             &path, storage_id
         );
         let meta = data_file.file.metadata()?;
-        let formatter = Arc::new(get_formatter_from_file(&mut data_file.file)?);
+        let formatter = match get_formatter_from_file(&mut data_file.file) {
+            Ok(f) => Arc::new(f),
+            Err(e) => {
+                // a crash right after the data file was created may leave it without header,
+                // so nothing was written to it
+                if is_header_missing(&mut data_file.file, &meta)? {
+                    return Err(DataStorageError::IncompleteDataFile(storage_id));
+                }
+                return Err(e.into());
+            }
+        };
 
         DataStorage::open_by_file(
             &path,
@@ -168,19 +313,406 @@ impl DataStorage {
         )
     }
 
+    /// Opens data file left by the original Erlang bitcask, it has no file header
+    fn open_erlang_bitcask(
+        database_dir: &Path,
+        storage_id: StorageId,
+        options: Arc<BitcaskyOptions>,
+    ) -> Result<Self> {
+        let data_file = fs::open_file(database_dir, FileType::ErlangDataFile, Some(storage_id))?;
+        debug!(
+            "Open Erlang bitcask storage under path: {:?} with storage id: {}",
+            database_dir, storage_id
+        );
+        let meta = data_file.file.metadata()?;
+        DataStorage::open_by_file(
+            database_dir,
+            storage_id,
+            data_file.file,
+            meta,
+            0,
+            Arc::new(BitcaskyFormatter::ErlangBitcask(Default::default())),
+            options,
+        )
+    }
+
     pub fn storage_id(&self) -> StorageId {
         self.storage_id
     }
 
+    /// Formatter declared in the header of the data file, rows are read and written with it
+    pub fn formatter(&self) -> &BitcaskyFormatter {
+        &self.formatter
+    }
+
     pub fn is_dirty(&mut self) -> bool {
         self.dirty
     }
 
+    /// Offset before which all the written data has been flushed to disk
+    pub fn synced_offset(&self) -> usize {
+        self.synced_offset
+    }
+
+    /// Seals this storage so no more rows can be written, then flushes and fsyncs it.
+    /// Returns the offset up to which rows are durable. A hint file for this storage
+    /// can only describe rows before this offset.
+    pub fn transit_to_readonly(&mut self) -> Result<usize> {
+        self.ensure_open()?;
+        self.sealed = true;
+        self.flush()?;
+        fail_point!("data_storage::before_sync_all", |_| {
+            Err(DataStorageError::FlushStorageFailed(
+                self.storage_id,
+                "failpoint data_storage::before_sync_all".into(),
+            ))
+        });
+        with_storage_impl!(&mut self.storage_impl, s => s.sync_all())
+            .map_err(|e| DataStorageError::FlushStorageFailed(self.storage_id, e.to_string()))?;
+        #[cfg(feature = "mmap")]
+        if let DataStorageImpl::MmapStorage(s) = &mut self.storage_impl {
+            s.seal()?;
+        }
+        Ok(self.synced_offset)
+    }
+
+    /// Writes rows buffered in memory to the data file without syncing them, so they can be read
+    /// through other handles of the data file
+    pub fn write_buffered_rows(&mut self) -> Result<()> {
+        match &mut self.storage_impl {
+            DataStorageImpl::FileStorage(s) => s.write_buffered_rows(),
+            _ => Ok(()),
+        }
+    }
+
     pub fn add_dead_bytes(&mut self, dead_bytes: usize) {
         self.dead_bytes += dead_bytes;
     }
 
+    /// Reset dead bytes by the size of rows still referenced by keydir in this storage
+    pub fn reset_dead_bytes(&mut self, live_bytes: usize) {
+        self.dead_bytes = self.data_size().saturating_sub(live_bytes);
+    }
+
+    /// Move offset to the end of data by row headers without validating rows.
+    /// Used on stable storages to know how many bytes they hold.
+    pub fn skip_to_end(&mut self) {
+        // the offset of a closed data file is at the end already
+        if !self.is_file_open() {
+            return;
+        }
+        while self.skip_row() {}
+    }
+
+    /// Move offset to the end of valid rows. Returns true if the data file ends with a torn write.
+    /// Returns an error if a corrupted row is followed by other data.
+    pub fn find_torn_tail(&mut self) -> Result<bool> {
+        with_storage_impl!(&mut self.storage_impl, s => s.seek_to_last_valid_row())
+    }
+
+    /// Clear the torn write found by `find_torn_tail`
+    pub fn truncate_torn_tail(&mut self) -> Result<()> {
+        with_storage_impl!(&mut self.storage_impl, s => s.truncate_torn_tail())?;
+        self.synced_offset = self.offset();
+        Ok(())
+    }
+
+    fn data_size(&self) -> usize {
+        self.offset() - self.formatter.file_header_size()
+    }
+
+    /// Counts the data file of this storage by the counter while it's open
+    pub fn count_open_files(&mut self, counter: Arc<AtomicUsize>) {
+        if self.is_file_open() {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        self.open_files = Some(counter);
+    }
+
+    pub fn is_file_open(&self) -> bool {
+        !matches!(self.storage_impl, DataStorageImpl::Closed(_))
+    }
+
+    /// Returns whether this storage was read since last call
+    pub fn take_accessed(&mut self) -> bool {
+        std::mem::take(&mut self.accessed)
+    }
+
+    /// Closes the data file to release its file descriptor, it's reopened on next read. Returns
+    /// false if it's not closed, like when rows may still be written to it, or it's a data file
+    /// of Erlang bitcask.
+    pub fn close_file(&mut self) -> bool {
+        if (self.dirty && !self.sealed)
+            || matches!(
+                self.storage_impl,
+                DataStorageImpl::ErlangStorage(_) | DataStorageImpl::Closed(_)
+            )
+        {
+            return false;
+        }
+        let (offset, capacity, read_value_times, write_times) = with_storage_impl!(
+            &self.storage_impl,
+            s => (s.offset, s.capacity, s.read_value_times, s.write_times)
+        );
+        self.storage_impl = DataStorageImpl::Closed(ClosedStorage {
+            offset,
+            capacity,
+            read_value_times,
+            write_times,
+        });
+        if let Some(counter) = self.open_files.as_ref() {
+            counter.fetch_sub(1, Ordering::Relaxed);
+        }
+        debug!(target: "DataStorage", "closed data file with storage id: {}", self.storage_id);
+        true
+    }
+
+    fn ensure_open(&mut self) -> Result<()> {
+        self.accessed = true;
+        let DataStorageImpl::Closed(closed) = &self.storage_impl else {
+            return Ok(());
+        };
+        let data_file = fs::open_file(
+            &self.database_dir,
+            FileType::DataFile,
+            Some(self.storage_id),
+        )?
+        .file;
+        let mut storage_impl = new_storage_impl(
+            self.storage_id,
+            data_file,
+            closed.offset,
+            closed.capacity,
+            &self.formatter,
+            &self.options,
+        )?;
+        with_storage_impl!(&mut storage_impl, s => {
+            s.read_value_times = closed.read_value_times;
+            s.write_times = closed.write_times;
+        });
+        self.storage_impl = storage_impl;
+        self.reopen_times += 1;
+        if let Some(counter) = self.open_files.as_ref() {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        debug!(target: "DataStorage", "reopened data file with storage id: {}", self.storage_id);
+        Ok(())
+    }
+
+    /// Opens a reader over the value of the row at offset on a file handle of its own, so the
+    /// value can be read without holding this storage. Returns None if the row is deleted or
+    /// expired. Values which can not be read as they are stored in the data file, like values
+    /// encoded by value codec, are read into memory instead.
+    pub fn value_reader(&mut self, row_offset: usize) -> Result<Option<ValueReader>> {
+        let BitcaskyFormatter::V3(formatter) = *self.formatter else {
+            return self.buffered_value_reader(row_offset);
+        };
+        if self.options.value_codec.is_some() {
+            return self.buffered_value_reader(row_offset);
+        }
+        // rows buffered in memory are not in the data file yet
+        self.write_buffered_rows()?;
+
+        let mut file = fs::open_file(
+            &self.database_dir,
+            FileType::DataFile,
+            Some(self.storage_id),
+        )?
+        .file;
+        let file_size = file.metadata()?.len() as usize;
+        let header_size = formatter.row_header_size();
+        if row_offset + header_size > file_size {
+            return Err(DataStorageError::EofError());
+        }
+        let mut header_bs = vec![0; header_size];
+        file.seek(SeekFrom::Start(row_offset as u64))?;
+        file.read_exact(&mut header_bs)?;
+        let header = formatter.decode_row_header(&header_bs);
+        if header.meta.key_size == 0 {
+            return Err(DataStorageError::ReadRowFailed(
+                self.storage_id,
+                format!("no value found at offset: {}", row_offset),
+            ));
+        }
+        if row_offset + header_size + header.meta.key_size + header.meta.value_size > file_size {
+            return Err(DataStorageError::EofError());
+        }
+        if header.meta.expire_timestamp != 0
+            && header.meta.expire_timestamp <= self.options.clock.now()
+        {
+            return Ok(None);
+        }
+        // tombstone is told by its value
+        if header.meta.value_size == TOMBSTONE_VALUE.len() {
+            return self.buffered_value_reader(row_offset);
+        }
+
+        let mut key = vec![0; header.meta.key_size];
+        file.read_exact(&mut key)?;
+        let checksum = if self.options.database.storage.verify_crc_on_read {
+            let mut ck = formatter.row_checksum(&header.meta);
+            ck.update(&key);
+            Some(ck)
+        } else {
+            None
+        };
+        Ok(Some(ValueReader::streamed(
+            file,
+            header.meta.value_size,
+            checksum,
+            header.crc,
+            self.storage_id,
+            row_offset,
+        )))
+    }
+
+    /// Whether rows of this storage can be copied to `dest` as they are, which requires both
+    /// to encode rows by the same formatter
+    pub fn can_copy_rows_to(&self, dest: &DataStorage) -> bool {
+        !matches!(*self.formatter, BitcaskyFormatter::ErlangBitcask(_))
+            && self.formatter == dest.formatter
+    }
+
+    /// Reads the meta of the row at offset without reading its key and value
+    pub fn read_row_meta(&mut self, row_offset: usize) -> Result<RowMeta> {
+        let storage_id = self.storage_id;
+        Ok(self
+            .raw_row(row_offset)
+            .map_err(|e| DataStorageError::ReadRowFailed(storage_id, e.to_string()))?
+            .0
+            .meta)
+    }
+
+    /// Reads the expire timestamp of the row at offset from its header, 0 if it never expires.
+    /// Rows of Erlang bitcask never expire
+    pub fn read_expire_timestamp(&mut self, row_offset: usize) -> Result<u64> {
+        if matches!(self.storage_impl, DataStorageImpl::ErlangStorage(_)) {
+            return Ok(0);
+        }
+        Ok(self.read_row_meta(row_offset)?.expire_timestamp)
+    }
+
+    /// Copies the row at offset to the end of `dest` as it is, without decoding its value and
+    /// encoding it again. The crc of the row is checked before copying if `verify_crc_on_read`
+    /// is set, otherwise bytes between data files are copied in kernel where supported.
+    pub fn copy_row_to(
+        &mut self,
+        row_offset: usize,
+        row_size: usize,
+        dest: &mut DataStorage,
+    ) -> Result<RowLocation> {
+        if dest.sealed {
+            return Err(DataStorageError::PermissionDenied(dest.storage_id));
+        }
+        if !self.can_copy_rows_to(dest) {
+            return Err(DataStorageError::WriteRowFailed(
+                dest.storage_id,
+                format!(
+                    "rows of storage with id: {} are encoded by another formatter",
+                    self.storage_id
+                ),
+            ));
+        }
+        let storage_id = self.storage_id;
+        let formatter = self.formatter.clone();
+        let verify_crc = self.options.database.storage.verify_crc_on_read;
+        let mut buf = std::mem::take(&mut self.copy_buffer);
+        let (header, row) = self
+            .raw_row(row_offset)
+            .map_err(|e| DataStorageError::ReadRowFailed(storage_id, e.to_string()))?;
+        if row.size() != row_size {
+            return Err(DataStorageError::ReadRowFailed(
+                storage_id,
+                format!(
+                    "expect row with size: {} at offset: {}, but found size: {}",
+                    row_size,
+                    row_offset,
+                    row.size()
+                ),
+            ));
+        }
+        let validate = |bs: &[u8]| {
+            let header_size = formatter.row_header_size();
+            let kv_end = header_size + header.meta.key_size + header.meta.value_size;
+            formatter
+                .validate_key_value(&header, &bs[header_size..kv_end])
+                .map_err(|e| match e {
+                    FormatterError::CrcCheckFailed {
+                        expected_crc,
+                        actual_crc,
+                    } => DataStorageError::CrcCheckFailed {
+                        storage_id,
+                        row_offset,
+                        expected_crc,
+                        actual_crc,
+                    },
+                    _ => DataStorageError::ReadRowFailed(storage_id, e.to_string()),
+                })
+        };
+        let ret = match row {
+            RawRow::Mapped(bs) if verify_crc => {
+                validate(bs).and_then(|_| dest.append_raw_row(RawRow::Mapped(bs)))
+            }
+            RawRow::File(mut r) if verify_crc => {
+                // rows are read into the same buffer, so values are not allocated one by one
+                buf.resize(row_size, 0);
+                r.read_exact(&mut buf)
+                    .map_err(DataStorageError::from)
+                    .and_then(|_| validate(&buf))
+                    .and_then(|_| dest.append_raw_row(RawRow::Mapped(&buf)))
+            }
+            row => dest.append_raw_row(row),
+        };
+        self.copy_buffer = buf;
+        let location = ret?;
+        dest.dirty = true;
+        Ok(location)
+    }
+
+    fn raw_row(&mut self, row_offset: usize) -> Result<(RowHeader, RawRow<'_>)> {
+        self.ensure_open()?;
+        match &mut self.storage_impl {
+            #[cfg(feature = "mmap")]
+            DataStorageImpl::MmapStorage(s) => s.raw_row(row_offset),
+            DataStorageImpl::FileStorage(s) => s.raw_row(row_offset),
+            DataStorageImpl::ErlangStorage(_) => Err(DataStorageError::ReadRowFailed(
+                self.storage_id,
+                "rows of Erlang bitcask can not be read as they are".into(),
+            )),
+            DataStorageImpl::Closed(_) => unreachable!("data file is closed"),
+        }
+    }
+
+    fn append_raw_row(&mut self, row: RawRow) -> Result<RowLocation> {
+        match &mut self.storage_impl {
+            #[cfg(feature = "mmap")]
+            DataStorageImpl::MmapStorage(s) => s.append_raw_row(row),
+            DataStorageImpl::FileStorage(s) => s.append_raw_row(row),
+            DataStorageImpl::ErlangStorage(_) | DataStorageImpl::Closed(_) => {
+                Err(DataStorageError::PermissionDenied(self.storage_id))
+            }
+        }
+    }
+
+    fn buffered_value_reader(&mut self, row_offset: usize) -> Result<Option<ValueReader>> {
+        Ok(self
+            .read_value(row_offset)?
+            .map(|v| ValueReader::buffered(v.value)))
+    }
+
     pub fn iter(&self) -> Result<StorageIter> {
+        if let BitcaskyFormatter::ErlangBitcask(_) = *self.formatter {
+            return Ok(StorageIter {
+                skip_corrupted: self.options.database.storage.skip_corrupted,
+                corrupted_offsets: vec![],
+                end_offset: None,
+                storage: DataStorage::open_erlang_bitcask(
+                    &self.database_dir,
+                    self.storage_id,
+                    self.options.clone(),
+                )?,
+            });
+        }
         let mut data_file = fs::open_file(
             &self.database_dir,
             FileType::DataFile,
@@ -196,6 +728,9 @@ impl DataStorage {
         );
         let meta = data_file.file.metadata()?;
         Ok(StorageIter {
+            skip_corrupted: self.options.database.storage.skip_corrupted,
+            corrupted_offsets: vec![],
+            end_offset: None,
             storage: DataStorage::open_by_file(
                 &self.database_dir,
                 self.storage_id,
@@ -209,26 +744,30 @@ impl DataStorage {
     }
 
     pub fn get_telemetry_data(&self) -> DataStorageTelemetry {
-        match &self.storage_impl {
-            DataStorageImpl::MmapStorage(s) => {
-                let data_size = s.offset - FILE_HEADER_SIZE;
-                let data_capacity = s.capacity - FILE_HEADER_SIZE;
-                let mut fragment = self.dead_bytes as f64 / data_size as f64;
-                if fragment.is_nan() {
-                    fragment = 0.0;
-                }
-                DataStorageTelemetry {
-                    storage_id: self.storage_id,
-                    formatter_version: self.formatter.version(),
-                    data_capacity,
-                    data_size,
-                    usage: data_size as f64 / data_capacity as f64,
-                    fragment,
-                    read_value_times: s.read_value_times,
-                    write_times: s.write_times,
-                    dead_bytes: self.dead_bytes,
-                }
-            }
+        let (offset, capacity, read_value_times, write_times) = match &self.storage_impl {
+            DataStorageImpl::Closed(c) => (c.offset, c.capacity, c.read_value_times, c.write_times),
+            storage_impl => with_storage_impl!(
+                storage_impl,
+                s => (s.offset, s.capacity, s.read_value_times, s.write_times)
+            ),
+        };
+        let data_size = offset - self.formatter.file_header_size();
+        let data_capacity = capacity - self.formatter.file_header_size();
+        let mut fragment = self.dead_bytes as f64 / data_size as f64;
+        if fragment.is_nan() {
+            fragment = 0.0;
+        }
+        DataStorageTelemetry {
+            storage_id: self.storage_id,
+            formatter_version: self.formatter.version(),
+            data_capacity,
+            data_size,
+            usage: data_size as f64 / data_capacity as f64,
+            fragment,
+            read_value_times,
+            write_times,
+            dead_bytes: self.dead_bytes,
+            reopen_times: self.reopen_times,
         }
     }
 
@@ -242,14 +781,14 @@ impl DataStorage {
         options: Arc<BitcaskyOptions>,
     ) -> Result<Self> {
         let capacity = meta.len() as usize;
-        let storage_impl = DataStorageImpl::MmapStorage(MmapDataStorage::new(
+        let storage_impl = new_storage_impl(
             storage_id,
             data_file,
             write_offset,
             capacity,
-            formatter.clone(),
-            options.clone(),
-        )?);
+            &formatter,
+            &options,
+        )?;
         Ok(DataStorage {
             storage_impl,
             storage_id,
@@ -258,86 +797,547 @@ impl DataStorage {
             formatter,
             dirty: false,
             dead_bytes: 0,
+            synced_offset: write_offset,
+            sealed: false,
+            copy_buffer: vec![],
+            accessed: false,
+            reopen_times: 0,
+            open_files: None,
         })
     }
 }
 
+fn new_storage_impl(
+    storage_id: StorageId,
+    data_file: File,
+    write_offset: usize,
+    capacity: usize,
+    formatter: &Arc<BitcaskyFormatter>,
+    options: &Arc<BitcaskyOptions>,
+) -> Result<DataStorageImpl> {
+    Ok(match options.database.storage.storage_type {
+        _ if matches!(**formatter, BitcaskyFormatter::ErlangBitcask(_)) => {
+            DataStorageImpl::ErlangStorage(ErlangDataStorage::new(
+                storage_id,
+                data_file,
+                capacity,
+                options.clone(),
+            )?)
+        }
+        #[cfg(feature = "mmap")]
+        DataSotrageType::Mmap => DataStorageImpl::MmapStorage(MmapDataStorage::new(
+            storage_id,
+            data_file,
+            write_offset,
+            capacity,
+            formatter.clone(),
+            options.clone(),
+        )?),
+        DataSotrageType::File => DataStorageImpl::FileStorage(FileDataStorage::new(
+            storage_id,
+            data_file,
+            write_offset,
+            capacity,
+            formatter.clone(),
+            options.clone(),
+        )?),
+    })
+}
+
 impl DataStorageWriter for DataStorage {
     fn write_row<K: AsRef<[u8]>, V: Deref<Target = [u8]>>(
         &mut self,
         row: &RowToWrite<K, V>,
     ) -> Result<RowLocation> {
-        let r = match &mut self.storage_impl {
-            DataStorageImpl::MmapStorage(s) => s.write_row(row),
+        if self.sealed {
+            return Err(DataStorageError::PermissionDenied(self.storage_id));
+        }
+        let storage_options = &self.options.database.storage;
+        let key_size = row.key.as_ref().len();
+        if key_size > storage_options.max_key_size {
+            return Err(DataStorageError::KeySizeExceeded(
+                key_size,
+                storage_options.max_key_size,
+            ));
+        }
+        if row.value.len() > storage_options.max_value_size {
+            return Err(DataStorageError::ValueSizeExceeded(
+                row.value.len(),
+                storage_options.max_value_size,
+            ));
+        }
+        let r = match &self.options.value_codec {
+            // tombstone is not user data, keep it as is to tell deleted rows without decoding
+            Some(codec) if !is_tombstone(&row.value) => {
+                let encoded = RowToWrite::new_with_timestamp(
+                    row.key.as_ref(),
+                    codec.encode(&row.value),
+                    row.meta.expire_timestamp,
+                )
+                .with_write_timestamp(row.meta.write_timestamp);
+                with_storage_impl!(&mut self.storage_impl, s => s.write_row(&encoded))
+            }
+            _ => with_storage_impl!(&mut self.storage_impl, s => s.write_row(row)),
         }?;
         self.dirty = true;
         Ok(r)
     }
 
     fn rewind(&mut self) -> Result<()> {
-        match &mut self.storage_impl {
-            DataStorageImpl::MmapStorage(s) => {
-                let storage_id = self.storage_id;
-                s.rewind()
-                    .map_err(|e| DataStorageError::RewindFailed(storage_id, e.to_string()))
-            }
-        }
+        let storage_id = self.storage_id;
+        with_storage_impl!(&mut self.storage_impl, s => s.rewind())
+            .map_err(|e| DataStorageError::RewindFailed(storage_id, e.to_string()))
     }
 
     fn flush(&mut self) -> Result<()> {
-        match &mut self.storage_impl {
-            DataStorageImpl::MmapStorage(s) => s
-                .flush()
-                .map_err(|e| DataStorageError::FlushStorageFailed(self.storage_id, e.to_string())),
-        }
+        let offset = self.offset();
+        with_storage_impl!(&mut self.storage_impl, s => s.flush())
+            .map_err(|e| DataStorageError::FlushStorageFailed(self.storage_id, e.to_string()))?;
+        self.synced_offset = offset;
+        Ok(())
     }
 }
 
 impl DataStorageReader for DataStorage {
     fn read_value(&mut self, row_offset: usize) -> Result<Option<TimedValue<Vec<u8>>>> {
-        match &mut self.storage_impl {
-            DataStorageImpl::MmapStorage(s) => s
-                .read_value(row_offset)
-                .map_err(|e| DataStorageError::ReadRowFailed(self.storage_id, e.to_string())),
+        self.read_value_at(row_offset, self.options.clock.now())
+    }
+
+    fn read_value_at(
+        &mut self,
+        row_offset: usize,
+        now: u64,
+    ) -> Result<Option<TimedValue<Vec<u8>>>> {
+        self.ensure_open()?;
+        let value =
+            with_storage_impl!(&mut self.storage_impl, s => s.read_value_at(row_offset, now))
+                .map_err(|e| match e {
+                    DataStorageError::CrcCheckFailed { .. } => e,
+                    _ => DataStorageError::ReadRowFailed(self.storage_id, e.to_string()),
+                })?;
+        match value {
+            Some(mut v) => {
+                v.value = self.decode_value(row_offset, v.value)?;
+                Ok(Some(v))
+            }
+            None => Ok(None),
         }
     }
 
     fn read_next_row(&mut self) -> Result<Option<RowToRead>> {
-        match &mut self.storage_impl {
-            DataStorageImpl::MmapStorage(s) => s.read_next_row(),
+        let row = with_storage_impl!(&mut self.storage_impl, s => s.read_next_row())?;
+        match row {
+            // values of expired rows are not read
+            Some(mut r) if r.value.is_valid(self.options.clock.now()) => {
+                r.value.value = self.decode_value(r.row_location.row_offset, r.value.value)?;
+                Ok(Some(r))
+            }
+            r => Ok(r),
         }
     }
 
     fn seek_to_end(&mut self) -> Result<()> {
-        match &mut self.storage_impl {
-            DataStorageImpl::MmapStorage(s) => s.seek_to_end(),
-        }
+        let ret = with_storage_impl!(&mut self.storage_impl, s => s.seek_to_end());
+        // rows found on disk are durable already
+        self.synced_offset = self.offset();
+        // a reused writing file holding rows can be replaced when it's full
+        self.dirty |= self.offset() > self.formatter.file_header_size();
+        ret
     }
 
     fn offset(&self) -> usize {
         match &self.storage_impl {
-            DataStorageImpl::MmapStorage(s) => s.offset(),
+            DataStorageImpl::Closed(c) => c.offset,
+            storage_impl => with_storage_impl!(storage_impl, s => s.offset()),
         }
     }
 }
 
+impl DataStorage {
+    fn decode_value(&self, row_offset: usize, value: Vec<u8>) -> Result<Vec<u8>> {
+        // values written by Erlang bitcask are never encoded
+        if let BitcaskyFormatter::ErlangBitcask(_) = *self.formatter {
+            return Ok(value);
+        }
+        match &self.options.value_codec {
+            Some(codec) if !is_tombstone(&value) => codec
+                .decode(&value)
+                .map_err(|e| DataStorageError::DecodeValueFailed(self.storage_id, row_offset, e)),
+            _ => Ok(value),
+        }
+    }
+
+    fn skip_row(&mut self) -> bool {
+        with_storage_impl!(&mut self.storage_impl, s => s.skip_row())
+    }
+}
+
 #[derive(Debug)]
 pub struct StorageIter {
     storage: DataStorage,
+    skip_corrupted: bool,
+    corrupted_offsets: Vec<u64>,
+    end_offset: Option<usize>,
+}
+
+impl StorageIter {
+    /// Overrides `skip_corrupted` in options
+    pub fn skip_corrupted(mut self, skip_corrupted: bool) -> StorageIter {
+        self.skip_corrupted = skip_corrupted;
+        self
+    }
+
+    /// Stops iteration at `end_offset`, so rows written after it are not iterated
+    pub fn end_at(mut self, end_offset: usize) -> StorageIter {
+        self.end_offset = Some(end_offset);
+        self
+    }
+
+    /// Offsets of the corrupted rows met so far during iteration
+    pub fn corrupted_offsets(&self) -> &Vec<u64> {
+        &self.corrupted_offsets
+    }
 }
 
 impl Iterator for StorageIter {
     type Item = Result<RowToRead>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let ret = self.storage.read_next_row();
-        match ret {
-            Ok(o) => o.map(Ok),
-            Err(e) => {
-                error!(target: "Storage", "Data file with file id {} was corrupted. Error: {}", 
-                self.storage.storage_id(), &e);
-                None
+        loop {
+            let offset = self.storage.offset();
+            if self.end_offset.is_some_and(|end| offset >= end) {
+                return None;
+            }
+            let ret = self.storage.read_next_row();
+            match ret {
+                Ok(o) => return o.map(Ok),
+                Err(e) => {
+                    self.corrupted_offsets.push(offset as u64);
+                    if self.skip_corrupted && self.storage.skip_row() {
+                        warn!(target: "Storage", "Skip corrupted row in data file with file id {} at offset {}. Error: {}", 
+                        self.storage.storage_id(), offset, &e);
+                        continue;
+                    }
+                    error!(target: "Storage", "Data file with file id {} was corrupted at offset {}. Error: {}", 
+                    self.storage.storage_id(), offset, &e);
+                    return None;
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::formatter::Formatter;
+    use crate::options::DataStorageOptions;
+    use crate::test_utils::get_temporary_directory_path;
+    use test_log::test;
+
+    fn write_rows_and_break_second(options: BitcaskyOptions) -> (DataStorage, Vec<RowLocation>) {
+        let dir = get_temporary_directory_path();
+        let formatter = Arc::new(BitcaskyFormatter::default());
+        let mut storage = DataStorage::new(&dir, 1, formatter.clone(), Arc::new(options)).unwrap();
+        let locations = ["k1", "k2", "k3"]
+            .iter()
+            .map(|k| {
+                storage
+                    .write_row(&RowToWrite::new(k.as_bytes(), b"value".to_vec()))
+                    .unwrap()
+            })
+            .collect::<Vec<RowLocation>>();
+        storage.flush().unwrap();
+
+        let mut f = fs::open_file(&dir, FileType::DataFile, Some(1))
+            .unwrap()
+            .file;
+        f.seek(SeekFrom::Start(
+            (locations[1].row_offset + formatter.row_header_size()) as u64,
+        ))
+        .unwrap();
+        f.write_all(b"x").unwrap();
+        (storage, locations)
+    }
+
+    #[test]
+    fn test_reopen_closed_file() {
+        let dir = get_temporary_directory_path();
+        let options = Arc::new(BitcaskyOptions::default());
+        let mut storage =
+            DataStorage::new(&dir, 1, Arc::new(BitcaskyFormatter::default()), options).unwrap();
+        let location = storage
+            .write_row(&RowToWrite::new(b"k1", b"value".to_vec()))
+            .unwrap();
+        // rows may still be written
+        assert!(!storage.close_file());
+        storage.transit_to_readonly().unwrap();
+        storage.read_value(location.row_offset).unwrap();
+
+        let open_files = Arc::new(AtomicUsize::new(0));
+        storage.count_open_files(open_files.clone());
+        assert_eq!(1, open_files.load(Ordering::Relaxed));
+        let before = storage.get_telemetry_data();
+        assert!(storage.take_accessed());
+        assert!(storage.close_file());
+        assert!(!storage.is_file_open());
+        assert_eq!(0, open_files.load(Ordering::Relaxed));
+        assert_eq!(before.data_size, storage.get_telemetry_data().data_size);
+
+        assert_eq!(
+            b"value".to_vec(),
+            storage
+                .read_value(location.row_offset)
+                .unwrap()
+                .unwrap()
+                .value
+        );
+        assert!(storage.is_file_open());
+        assert_eq!(1, open_files.load(Ordering::Relaxed));
+        let after = storage.get_telemetry_data();
+        assert_eq!(1, after.reopen_times);
+        assert_eq!(before.read_value_times + 1, after.read_value_times);
+    }
+
+    #[test]
+    fn test_stop_on_corrupted_row() {
+        let (storage, locations) = write_rows_and_break_second(BitcaskyOptions::default());
+        let mut iter = storage.iter().unwrap();
+        let rows = iter
+            .by_ref()
+            .map(|r| r.unwrap())
+            .collect::<Vec<RowToRead>>();
+        assert_eq!(1, rows.len());
+        assert_eq!(b"k1".to_vec(), rows[0].key);
+        assert_eq!(
+            &vec![locations[1].row_offset as u64],
+            iter.corrupted_offsets()
+        );
+    }
+
+    #[test]
+    fn test_skip_corrupted_row() {
+        let (storage, locations) =
+            write_rows_and_break_second(BitcaskyOptions::default().skip_corrupted(true));
+        let mut iter = storage.iter().unwrap();
+        let rows = iter
+            .by_ref()
+            .map(|r| r.unwrap())
+            .collect::<Vec<RowToRead>>();
+        assert_eq!(2, rows.len());
+        assert_eq!(b"k1".to_vec(), rows[0].key);
+        assert_eq!(b"k3".to_vec(), rows[1].key);
+        assert_eq!(locations[2], rows[1].row_location);
+        assert_eq!(
+            &vec![locations[1].row_offset as u64],
+            iter.corrupted_offsets()
+        );
+    }
+
+    #[test]
+    fn test_verify_crc_on_read() {
+        let (mut storage, locations) = write_rows_and_break_second(BitcaskyOptions::default());
+        assert_eq!(
+            b"value".to_vec(),
+            storage
+                .read_value(locations[0].row_offset)
+                .unwrap()
+                .unwrap()
+                .value
+        );
+        assert_matches!(
+            storage.read_value(locations[1].row_offset),
+            Err(DataStorageError::CrcCheckFailed {
+                storage_id: 1,
+                row_offset,
+                expected_crc,
+                actual_crc,
+            }) if row_offset == locations[1].row_offset && expected_crc != actual_crc
+        );
+    }
+
+    #[test]
+    fn test_skip_verify_crc_on_read() {
+        let (mut storage, locations) =
+            write_rows_and_break_second(BitcaskyOptions::default().verify_crc_on_read(false));
+        assert_eq!(
+            b"value".to_vec(),
+            storage
+                .read_value(locations[1].row_offset)
+                .unwrap()
+                .unwrap()
+                .value
+        );
+    }
+
+    fn storage_of_type(
+        storage_type: DataSotrageType,
+        verify_crc_on_read: bool,
+        formatter: BitcaskyFormatter,
+    ) -> DataStorage {
+        let options = BitcaskyOptions::default()
+            .storage_type(storage_type)
+            .verify_crc_on_read(verify_crc_on_read);
+        DataStorage::new(
+            get_temporary_directory_path(),
+            1,
+            Arc::new(formatter),
+            Arc::new(options),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_copy_row() {
+        let storage_types = [
+            DataSotrageType::File,
+            #[cfg(feature = "mmap")]
+            DataSotrageType::Mmap,
+        ];
+        for src_type in storage_types {
+            for dest_type in storage_types {
+                for verify_crc in [true, false] {
+                    let formatter = BitcaskyFormatter::default();
+                    let mut src = storage_of_type(src_type, verify_crc, formatter);
+                    let mut dest = storage_of_type(dest_type, verify_crc, formatter);
+                    src.write_row(&RowToWrite::new(b"k1".to_vec(), b"value1".to_vec()))
+                        .unwrap();
+                    let location = src
+                        .write_row(
+                            &RowToWrite::new_with_timestamp(
+                                b"k2".to_vec(),
+                                vec![7; 1000],
+                                u64::MAX,
+                            )
+                            .with_write_timestamp(100),
+                        )
+                        .unwrap();
+                    dest.write_row(&RowToWrite::new(b"k3".to_vec(), b"value3".to_vec()))
+                        .unwrap();
+
+                    let copied = src
+                        .copy_row_to(location.row_offset, location.row_size, &mut dest)
+                        .unwrap();
+                    assert_eq!(location.row_size, copied.row_size);
+                    let value = dest.read_value(copied.row_offset).unwrap().unwrap();
+                    assert_eq!(vec![7; 1000], value.value);
+                    assert_eq!(u64::MAX, value.expire_timestamp);
+                    assert_eq!(100, value.write_timestamp);
+
+                    dest.flush().unwrap();
+                    let keys = dest
+                        .iter()
+                        .unwrap()
+                        .map(|r| r.unwrap().key)
+                        .collect::<Vec<Vec<u8>>>();
+                    assert_eq!(vec![b"k3".to_vec(), b"k2".to_vec()], keys);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_corrupted_row() {
+        let (mut storage, locations) = write_rows_and_break_second(BitcaskyOptions::default());
+        let mut dest = storage_of_type(
+            DataSotrageType::default(),
+            true,
+            BitcaskyFormatter::default(),
+        );
+        assert_matches!(
+            storage.copy_row_to(locations[1].row_offset, locations[1].row_size, &mut dest),
+            Err(DataStorageError::CrcCheckFailed { storage_id: 1, .. })
+        );
+        assert_matches!(
+            storage.copy_row_to(
+                locations[0].row_offset,
+                locations[0].row_size + 8,
+                &mut dest
+            ),
+            Err(DataStorageError::ReadRowFailed(1, _))
+        );
+        let copied = storage
+            .copy_row_to(locations[0].row_offset, locations[0].row_size, &mut dest)
+            .unwrap();
+        assert_eq!(FILE_HEADER_SIZE, copied.row_offset);
+
+        let (mut storage, locations) =
+            write_rows_and_break_second(BitcaskyOptions::default().verify_crc_on_read(false));
+        let mut dest = storage_of_type(
+            DataSotrageType::default(),
+            true,
+            BitcaskyFormatter::default(),
+        );
+        // copied as it is, so the corruption is still found on read
+        let copied = storage
+            .copy_row_to(locations[1].row_offset, locations[1].row_size, &mut dest)
+            .unwrap();
+        assert_matches!(
+            dest.read_value(copied.row_offset),
+            Err(DataStorageError::CrcCheckFailed { .. })
+        );
+    }
+
+    #[test]
+    fn test_copy_row_to_another_formatter() {
+        let mut src = storage_of_type(
+            DataSotrageType::default(),
+            true,
+            BitcaskyFormatter::default(),
+        );
+        let mut dest = storage_of_type(
+            DataSotrageType::default(),
+            true,
+            BitcaskyFormatter::V2(Default::default()),
+        );
+        let location = src
+            .write_row(&RowToWrite::new(b"k1".to_vec(), b"value1".to_vec()))
+            .unwrap();
+        assert!(!src.can_copy_rows_to(&dest));
+        assert_matches!(
+            src.copy_row_to(location.row_offset, location.row_size, &mut dest),
+            Err(DataStorageError::WriteRowFailed(1, _))
+        );
+        assert_eq!(FILE_HEADER_SIZE, dest.offset());
+    }
+
+    #[test]
+    fn test_reject_oversized_row() {
+        let dir = get_temporary_directory_path();
+        let mut options = BitcaskyOptions::default();
+        options.database.storage = DataStorageOptions::default()
+            .max_key_size(4)
+            .max_value_size(8);
+        let mut storage = DataStorage::new(
+            &dir,
+            1,
+            Arc::new(BitcaskyFormatter::default()),
+            Arc::new(options),
+        )
+        .unwrap();
+        storage
+            .write_row(&RowToWrite::new(b"k1".to_vec(), b"value".to_vec()))
+            .unwrap();
+        storage.flush().unwrap();
+        let offset = storage.offset();
+        let file_content = std::fs::read(FileType::DataFile.get_path(&dir, Some(1))).unwrap();
+
+        assert_matches!(
+            storage.write_row(&RowToWrite::new(b"large_key".to_vec(), b"value".to_vec())),
+            Err(DataStorageError::KeySizeExceeded(9, 4))
+        );
+        assert_matches!(
+            storage.write_row(&RowToWrite::new(b"k2".to_vec(), b"large_value".to_vec())),
+            Err(DataStorageError::ValueSizeExceeded(11, 8))
+        );
+        storage.flush().unwrap();
+
+        assert_eq!(offset, storage.offset());
+        assert_eq!(
+            file_content,
+            std::fs::read(FileType::DataFile.get_path(&dir, Some(1))).unwrap()
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Take},
+};
+
+use crate::{formatter::Checksum, storage_id::StorageId};
+
+use super::DataStorageError;
+
+/// Reads a value by pieces. Values of rows written by the builtin formatter without value codec
+/// are read from data file directly as the reader is consumed, others are read into memory
+/// when the reader is created.
+///
+/// When crc is verified on read, the checksum of a streamed value is verified along with the
+/// last piece of it, and an `io::ErrorKind::InvalidData` error is returned if it mismatches.
+#[derive(Debug)]
+pub struct ValueReader {
+    inner: ValueReaderImpl,
+}
+
+enum ValueReaderImpl {
+    Streamed {
+        value: Take<File>,
+        checksum: Option<Checksum>,
+        expected_crc: u32,
+        storage_id: StorageId,
+        row_offset: usize,
+    },
+    Buffered(Cursor<Vec<u8>>),
+}
+
+impl std::fmt::Debug for ValueReaderImpl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueReaderImpl::Streamed {
+                value,
+                storage_id,
+                row_offset,
+                ..
+            } => f
+                .debug_struct("Streamed")
+                .field("storage_id", storage_id)
+                .field("row_offset", row_offset)
+                .field("remaining", &value.limit())
+                .finish(),
+            ValueReaderImpl::Buffered(c) => {
+                f.debug_tuple("Buffered").field(&c.get_ref().len()).finish()
+            }
+        }
+    }
+}
+
+impl ValueReader {
+    /// Reads `value_size` bytes from the current position of the file, which is the start of
+    /// the value of the row at `row_offset`
+    pub(super) fn streamed(
+        file: File,
+        value_size: usize,
+        checksum: Option<Checksum>,
+        expected_crc: u32,
+        storage_id: StorageId,
+        row_offset: usize,
+    ) -> ValueReader {
+        ValueReader {
+            inner: ValueReaderImpl::Streamed {
+                value: file.take(value_size as u64),
+                checksum,
+                expected_crc,
+                storage_id,
+                row_offset,
+            },
+        }
+    }
+
+    pub(super) fn buffered(value: Vec<u8>) -> ValueReader {
+        ValueReader {
+            inner: ValueReaderImpl::Buffered(Cursor::new(value)),
+        }
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (value, checksum, expected_crc, storage_id, row_offset) = match &mut self.inner {
+            ValueReaderImpl::Buffered(c) => return c.read(buf),
+            ValueReaderImpl::Streamed {
+                value,
+                checksum,
+                expected_crc,
+                storage_id,
+                row_offset,
+            } => (value, checksum, *expected_crc, *storage_id, *row_offset),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = value.read(buf)?;
+        if n == 0 && value.limit() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "data file with id: {} ends before the value at offset: {}",
+                    storage_id, row_offset
+                ),
+            ));
+        }
+        if let Some(ck) = checksum.as_mut() {
+            ck.update(&buf[..n]);
+        }
+        if value.limit() == 0 {
+            if let Some(ck) = checksum.take() {
+                let actual_crc = ck.finalize();
+                if actual_crc != expected_crc {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        DataStorageError::CrcCheckFailed {
+                            storage_id,
+                            row_offset,
+                            expected_crc,
+                            actual_crc,
+                        },
+                    ));
+                }
+            }
+        }
+        Ok(n)
+    }
+}
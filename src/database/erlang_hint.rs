@@ -0,0 +1,212 @@
+//! Recovers keys from hint files left by the original Erlang bitcask. A hint file is only used
+//! when the crc in its last record matches all the entries before it and all the entries point
+//! into its data file, otherwise keys are recovered from the data file.
+
+use std::path::Path;
+
+use crate::logging::debug;
+
+use crate::{
+    formatter::{ErlangBitcaskFormatter, FormatterError},
+    fs::FileType,
+    storage_id::StorageId,
+};
+
+use super::{
+    common::{DatabaseResult, RecoveredRow},
+    DatabaseError, RowLocation,
+};
+
+const DEFAULT_LOG_TARGET: &str = "ErlangHint";
+
+/// Reads and validates the whole hint file, then iterates over its entries. Tombstones are
+/// returned as invalid rows so their keys are removed on recovery.
+pub fn open_erlang_hint_iterator(
+    database_dir: &Path,
+    storage_id: StorageId,
+) -> DatabaseResult<impl Iterator<Item = DatabaseResult<RecoveredRow>>> {
+    let corrupted = |e: FormatterError| {
+        DatabaseError::HintFileCorrupted(e, storage_id, database_dir.display().to_string())
+    };
+    let unexpected_eof = |msg: String| {
+        corrupted(FormatterError::IoError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            msg,
+        )))
+    };
+
+    let formatter = ErlangBitcaskFormatter::default();
+    let header_size = formatter.row_hint_header_size();
+    let bs = std::fs::read(FileType::ErlangHintFile.get_path(database_dir, Some(storage_id)))?;
+    let data_file_size = FileType::ErlangDataFile
+        .get_path(database_dir, Some(storage_id))
+        .metadata()?
+        .len() as usize;
+
+    let Some(entries_end) = bs.len().checked_sub(header_size) else {
+        return Err(unexpected_eof(
+            "hint file is too small to have crc record".into(),
+        ));
+    };
+    let Some(expected_crc) = formatter.decode_hint_crc_record(&bs[entries_end..]) else {
+        return Err(unexpected_eof(
+            "hint file does not end with crc record".into(),
+        ));
+    };
+    let actual_crc = formatter.hint_entries_crc(&bs[..entries_end]);
+    if expected_crc != actual_crc {
+        return Err(corrupted(FormatterError::CrcCheckFailed {
+            expected_crc,
+            actual_crc,
+        }));
+    }
+
+    let mut rows = vec![];
+    let mut offset = 0;
+    while offset < entries_end {
+        if offset + header_size > entries_end {
+            return Err(unexpected_eof(format!(
+                "hint entry at: {} is cut by crc record",
+                offset
+            )));
+        }
+        let (header, tombstone) = formatter.decode_row_hint_header(&bs[offset..]);
+        let key_start = offset + header_size;
+        let key_end = key_start + header.key_size;
+        if key_end > entries_end {
+            return Err(unexpected_eof(format!(
+                "key of hint entry at: {} is cut by crc record",
+                offset
+            )));
+        }
+        let row_end = header.row_offset.saturating_add(header.row_size);
+        if row_end > data_file_size {
+            return Err(unexpected_eof(format!(
+                "hint entry at: {} ends at: {} beyond data file size: {}",
+                offset, row_end, data_file_size
+            )));
+        }
+        rows.push(RecoveredRow {
+            row_location: RowLocation {
+                storage_id,
+                row_offset: header.row_offset,
+                row_size: header.row_size,
+            },
+            key: bs[key_start..key_end].to_vec(),
+            invalid: tombstone,
+        });
+        offset = key_end;
+    }
+    debug!(
+        target: DEFAULT_LOG_TARGET,
+        "read {} entries from Erlang bitcask hint file with id: {}",
+        rows.len(),
+        storage_id
+    );
+    Ok(rows.into_iter().map(Ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::formatter::{RowHint, RowHintHeader};
+    use crate::fs;
+    use crate::test_utils::get_temporary_directory_path;
+
+    use super::*;
+
+    use test_log::test;
+
+    fn write_erlang_files(dir: &Path, rows: &[(&[u8], bool)]) {
+        let formatter = ErlangBitcaskFormatter::default();
+        let mut data_bs = vec![];
+        let mut hint_bs = vec![];
+        for (key, tombstone) in rows {
+            let value: &[u8] = if *tombstone {
+                b"bitcask_tombstone"
+            } else {
+                b"value"
+            };
+            let mut row = vec![0; formatter.row_header_size() + key.len() + value.len()];
+            formatter.encode_row(key, value, 1, &mut row);
+            let hint = RowHint {
+                header: RowHintHeader {
+                    expire_timestamp: 0,
+                    key_size: key.len(),
+                    row_offset: data_bs.len(),
+                    row_size: row.len(),
+                },
+                key: key.to_vec(),
+            };
+            data_bs.extend_from_slice(&row);
+            let mut entry = vec![0; formatter.row_hint_header_size() + key.len()];
+            formatter.encode_row_hint(&hint, *tombstone, 1, &mut entry);
+            hint_bs.extend_from_slice(&entry);
+        }
+        let mut crc_record = vec![0; formatter.row_hint_header_size()];
+        formatter.encode_hint_crc_record(&hint_bs, &mut crc_record);
+        hint_bs.extend_from_slice(&crc_record);
+
+        fs::create_file(dir, FileType::ErlangDataFile, Some(1))
+            .unwrap()
+            .write_all(&data_bs)
+            .unwrap();
+        fs::create_file(dir, FileType::ErlangHintFile, Some(1))
+            .unwrap()
+            .write_all(&hint_bs)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_read_erlang_hint_file() {
+        let dir = get_temporary_directory_path();
+        write_erlang_files(&dir, &[(b"k1", false), (b"k2", false), (b"k1", true)]);
+
+        let rows = open_erlang_hint_iterator(&dir, 1)
+            .unwrap()
+            .collect::<DatabaseResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(3, rows.len());
+        assert_eq!(b"k1".to_vec(), rows[0].key);
+        assert!(!rows[0].invalid);
+        assert_eq!(0, rows[0].row_location.row_offset);
+        assert_eq!(21, rows[0].row_location.row_size);
+        assert_eq!(21, rows[1].row_location.row_offset);
+        assert_eq!(b"k1".to_vec(), rows[2].key);
+        assert!(rows[2].invalid);
+    }
+
+    #[test]
+    fn test_corrupted_erlang_hint_file() {
+        let dir = get_temporary_directory_path();
+        write_erlang_files(&dir, &[(b"k1", false), (b"k2", false)]);
+        let path = FileType::ErlangHintFile.get_path(&dir, Some(1));
+        let mut bs = std::fs::read(&path).unwrap();
+        bs[20] = b'x';
+        std::fs::write(&path, &bs).unwrap();
+
+        assert!(matches!(
+            open_erlang_hint_iterator(&dir, 1),
+            Err(DatabaseError::HintFileCorrupted(
+                FormatterError::CrcCheckFailed { .. },
+                1,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_erlang_hint_file_without_crc_record() {
+        let dir = get_temporary_directory_path();
+        write_erlang_files(&dir, &[(b"k1", false), (b"k2", false)]);
+        let path = FileType::ErlangHintFile.get_path(&dir, Some(1));
+        let bs = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bs[..bs.len() - 18]).unwrap();
+
+        assert!(matches!(
+            open_erlang_hint_iterator(&dir, 1),
+            Err(DatabaseError::HintFileCorrupted(..))
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{path::Path, sync::Arc};
+
+use crate::logging::warn;
+
+use crate::{
+    clock::Clock,
+    fs::{self, FileType},
+    options::BitcaskyOptions,
+    storage_id::StorageId,
+};
+
+use super::{
+    common::DatabaseResult,
+    data_storage::{DataStorage, DataStorageReader},
+    hint::HintFile,
+};
+
+const DEFAULT_LOG_TARGET: &str = "Estimate";
+/// Rows read from data files without hint file to know the average row size, when no hint file
+/// is available
+const SAMPLE_ROWS: usize = 64;
+
+/// Number of keys under a directory estimated from hint files and the size of data files
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyCountEstimate {
+    /// Live keys in hint files, counted exactly for each data file with a valid hint file
+    pub exact: usize,
+    /// Rows in data files without hint file, estimated by their data size and the average
+    /// row size
+    pub estimated: usize,
+    pub hinted_files: usize,
+    pub unhinted_files: usize,
+    /// Average row size used to estimate. None if no row was found
+    pub avg_row_size: Option<usize>,
+}
+
+impl KeyCountEstimate {
+    /// Keys overwritten or deleted in later data files are counted in every data file having
+    /// them, so it's usually larger than the actual count until data files are merged
+    pub fn total(&self) -> usize {
+        self.exact + self.estimated
+    }
+}
+
+#[derive(Default)]
+struct RowSizes {
+    rows: usize,
+    bytes: usize,
+}
+
+impl RowSizes {
+    fn add(&mut self, row_size: usize) {
+        self.rows += 1;
+        self.bytes += row_size;
+    }
+
+    fn average(&self) -> Option<usize> {
+        if self.rows == 0 {
+            return None;
+        }
+        Some((self.bytes / self.rows).max(1))
+    }
+}
+
+/// Counts live keys in hint files and estimates rows in data files without hint file. It only
+/// reads files, so it can run while the directory is used by another process.
+pub fn estimate_key_count(
+    database_dir: &Path,
+    options: Arc<BitcaskyOptions>,
+) -> DatabaseResult<KeyCountEstimate> {
+    std::fs::metadata(database_dir)?;
+    let data_storage_ids = fs::get_storage_ids_in_dir(database_dir, FileType::DataFile);
+    let hint_storage_ids = fs::get_storage_ids_in_dir(database_dir, FileType::HintFile);
+    let now = options.clock.now();
+
+    let mut estimate = KeyCountEstimate::default();
+    let mut row_sizes = RowSizes::default();
+    let mut unhinted_storage_ids = vec![];
+    for storage_id in data_storage_ids {
+        if !hint_storage_ids.contains(&storage_id) {
+            unhinted_storage_ids.push(storage_id);
+            continue;
+        }
+        match count_hinted_keys(database_dir, storage_id, now, options.clone()) {
+            Ok((keys, sizes)) => {
+                estimate.exact += keys;
+                estimate.hinted_files += 1;
+                row_sizes.rows += sizes.rows;
+                row_sizes.bytes += sizes.bytes;
+            }
+            Err(e) => {
+                warn!(target: DEFAULT_LOG_TARGET, "read hint file with id: {} failed, estimate keys by data file size. {}", storage_id, e);
+                unhinted_storage_ids.push(storage_id);
+            }
+        }
+    }
+
+    let mut data_sizes = vec![];
+    for storage_id in unhinted_storage_ids {
+        let mut storage = DataStorage::open(database_dir, storage_id, options.clone())?;
+        if row_sizes.rows == 0 {
+            // no hint file tells the row size, read the first rows instead
+            let mut sample = RowSizes::default();
+            for row in storage.iter()?.take(SAMPLE_ROWS) {
+                sample.add(row?.row_location.row_size);
+            }
+            if sample.rows > 0 {
+                row_sizes = sample;
+            }
+        }
+        // only row headers are read to find the end of data
+        storage.skip_to_end();
+        data_sizes.push(storage.offset() - storage.formatter().file_header_size());
+        estimate.unhinted_files += 1;
+    }
+
+    estimate.avg_row_size = row_sizes.average();
+    if let Some(avg_row_size) = estimate.avg_row_size {
+        estimate.estimated = data_sizes
+            .iter()
+            .map(|s| (s + avg_row_size / 2) / avg_row_size)
+            .sum();
+    }
+    Ok(estimate)
+}
+
+/// Counts keys neither deleted nor expired in the hint file, along with size of all the rows
+fn count_hinted_keys(
+    database_dir: &Path,
+    storage_id: StorageId,
+    now: u64,
+    options: Arc<BitcaskyOptions>,
+) -> DatabaseResult<(usize, RowSizes)> {
+    let mut keys = 0;
+    let mut row_sizes = RowSizes::default();
+    for hint in HintFile::open_iterator(database_dir, storage_id, now, options)? {
+        let hint = hint?;
+        row_sizes.add(hint.row_location.row_size);
+        if !hint.invalid {
+            keys += 1;
+        }
+    }
+    Ok((keys, row_sizes))
+}
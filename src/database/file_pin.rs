@@ -0,0 +1,201 @@
+//! Pins keeping data files from being deleted while they are still read, like by snapshots.
+//!
+//! Pins are in memory only, a crash releases all of them.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use crate::fs::{self, FileType};
+use crate::logging::{debug, warn};
+use crate::storage_id::StorageId;
+
+/// Directory under the database directory keeping data files whose purge is deferred
+const DEFERRED_PURGE_DIRECTORY: &str = "Purge";
+
+/// File types purged along with a data file
+const PURGED_FILE_TYPES: [FileType; 4] = [
+    FileType::DataFile,
+    FileType::HintFile,
+    FileType::ErlangDataFile,
+    FileType::ErlangHintFile,
+];
+
+#[derive(Debug, Default)]
+struct PinState {
+    pins: HashMap<StorageId, usize>,
+    /// Data files moved to the deferred purge directory while pinned
+    deferred: BTreeSet<StorageId>,
+}
+
+/// Reference counts of pinned data files. A pinned data file is not deleted by purge, it's
+/// moved out of the database directory instead and deleted once all of its pins are released.
+#[derive(Debug, Default, Clone)]
+pub struct FilePinSet {
+    state: Arc<Mutex<PinState>>,
+}
+
+/// Keeps a data file pinned until dropped
+#[derive(Debug)]
+pub struct PinGuard {
+    storage_id: StorageId,
+    state: Arc<Mutex<PinState>>,
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if let Some(count) = state.pins.get_mut(&self.storage_id) {
+            *count -= 1;
+            if *count == 0 {
+                state.pins.remove(&self.storage_id);
+            }
+        }
+    }
+}
+
+impl FilePinSet {
+    pub fn pin(&self, storage_id: StorageId) -> PinGuard {
+        *self.state.lock().pins.entry(storage_id).or_insert(0) += 1;
+        PinGuard {
+            storage_id,
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn is_pinned(&self, storage_id: StorageId) -> bool {
+        self.state.lock().pins.contains_key(&storage_id)
+    }
+
+    /// Data files whose purge is deferred by pins, including those released but not purged yet
+    pub fn deferred_purges(&self) -> Vec<StorageId> {
+        self.state.lock().deferred.iter().copied().collect()
+    }
+
+    /// Deletes the files of a data file under `base_dir`, or moves them to the deferred purge
+    /// directory if the data file is pinned. Files moved are deleted by `purge_released`.
+    pub fn purge(&self, base_dir: &Path, storage_id: StorageId) -> std::io::Result<()> {
+        let mut state = self.state.lock();
+        if !state.pins.contains_key(&storage_id) {
+            drop(state);
+            delete_files(base_dir, storage_id);
+            return Ok(());
+        }
+        let purge_dir = deferred_purge_dir(base_dir);
+        std::fs::create_dir_all(&purge_dir)?;
+        for file_type in PURGED_FILE_TYPES {
+            fs::move_file(file_type, Some(storage_id), base_dir, &purge_dir)?;
+        }
+        state.deferred.insert(storage_id);
+        debug!(target: "Database", "purge of pinned data file with id: {} is deferred", storage_id);
+        Ok(())
+    }
+
+    /// Deletes data files whose purge was deferred and are not pinned any more. Returns their
+    /// storage ids.
+    pub fn purge_released(&self, base_dir: &Path) -> Vec<StorageId> {
+        let released = {
+            let mut state = self.state.lock();
+            let released = state
+                .deferred
+                .iter()
+                .filter(|id| !state.pins.contains_key(id))
+                .copied()
+                .collect::<Vec<StorageId>>();
+            for id in released.iter() {
+                state.deferred.remove(id);
+            }
+            released
+        };
+        if released.is_empty() {
+            return released;
+        }
+        let purge_dir = deferred_purge_dir(base_dir);
+        for id in released.iter() {
+            delete_files(&purge_dir, *id);
+        }
+        debug!(target: "Database", "purged released data files with ids: {:?}", released);
+        released
+    }
+}
+
+fn deferred_purge_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join(DEFERRED_PURGE_DIRECTORY)
+}
+
+fn delete_files(dir: &Path, storage_id: StorageId) {
+    for file_type in PURGED_FILE_TYPES {
+        fs::delete_file(dir, file_type, Some(storage_id)).unwrap_or_default();
+    }
+}
+
+/// Deletes data files left in the deferred purge directory. Pins do not survive a restart, so
+/// none of them is read again.
+pub fn clear_deferred_purge_directory(base_dir: &Path) {
+    let purge_dir = deferred_purge_dir(base_dir);
+    if !purge_dir.exists() {
+        return;
+    }
+    if let Err(e) = fs::delete_dir(&purge_dir) {
+        warn!(target: "Database", "delete deferred purge directory: {:?} failed. {}", purge_dir, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::get_temporary_directory_path;
+    use test_log::test;
+
+    fn create_files(dir: &Path, storage_id: StorageId) {
+        std::fs::write(FileType::DataFile.get_path(dir, Some(storage_id)), b"data").unwrap();
+        std::fs::write(FileType::HintFile.get_path(dir, Some(storage_id)), b"hint").unwrap();
+    }
+
+    #[test]
+    fn test_purge_deferred_until_released() {
+        let dir = get_temporary_directory_path();
+        create_files(&dir, 1);
+        create_files(&dir, 2);
+        let pins = FilePinSet::default();
+        let pin1 = pins.pin(1);
+        let pin2 = pins.pin(1);
+        assert!(pins.is_pinned(1));
+
+        pins.purge(&dir, 1).unwrap();
+        pins.purge(&dir, 2).unwrap();
+        assert!(!FileType::DataFile.get_path(&dir, Some(1)).exists());
+        assert!(!FileType::HintFile.get_path(&dir, Some(1)).exists());
+        assert!(!FileType::DataFile.get_path(&dir, Some(2)).exists());
+        let purge_dir = deferred_purge_dir(&dir);
+        assert!(FileType::DataFile.get_path(&purge_dir, Some(1)).exists());
+        assert!(FileType::HintFile.get_path(&purge_dir, Some(1)).exists());
+        assert!(!FileType::DataFile.get_path(&purge_dir, Some(2)).exists());
+        assert_eq!(vec![1], pins.deferred_purges());
+
+        drop(pin1);
+        assert!(pins.purge_released(&dir).is_empty());
+        drop(pin2);
+        assert!(!pins.is_pinned(1));
+        assert_eq!(vec![1], pins.purge_released(&dir));
+        assert!(!FileType::DataFile.get_path(&purge_dir, Some(1)).exists());
+        assert!(pins.deferred_purges().is_empty());
+    }
+
+    #[test]
+    fn test_clear_deferred_purge_directory() {
+        let dir = get_temporary_directory_path();
+        create_files(&dir, 1);
+        let pins = FilePinSet::default();
+        let _pin = pins.pin(1);
+        pins.purge(&dir, 1).unwrap();
+
+        clear_deferred_purge_directory(&dir);
+        assert!(!deferred_purge_dir(&dir).exists());
+    }
+}
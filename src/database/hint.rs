@@ -1,47 +1,84 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::Write,
-    mem::ManuallyDrop,
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use log::{debug, error, warn};
+use crate::logging::{debug, error, warn};
+use fail::fail_point;
+use parking_lot::Mutex;
+use uuid::Uuid;
 
 use crate::{
-    clock::Clock,
+    database::create_data_file, maintenance::MaintenanceQueue, merge::rate_limiter::RateLimiter,
+    options::BitcaskyOptions,
+};
+use crate::{
     formatter::{
-        get_formatter_from_file, padding, BitcaskyFormatter, Formatter, RowHint, RowHintHeader,
-        FILE_HEADER_SIZE,
+        get_formatter_from_file, padding, BitcaskyFormatter, Formatter, FormatterError, RowHint,
+        RowHintHeader, FILE_HEADER_SIZE,
     },
     fs::{self, FileType},
     storage_id::StorageId,
+    tombstone::is_tombstone,
 };
-use crate::{database::create_data_file, options::BitcaskyOptions};
-use memmap2::{MmapMut, MmapOptions};
 
 use crate::database::{
     common::{DatabaseError, DatabaseResult},
-    data_storage::DataStorage,
+    data_storage::{DataStorage, DataStorageReader},
     RowLocation,
 };
-use crossbeam_channel::{unbounded, Sender};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 
 use super::common::RecoveredRow;
 
 const DEFAULT_LOG_TARGET: &str = "Hint";
 const HINT_FILES_TMP_DIRECTORY: &str = "TmpHint";
+/// Hint rows of deleted keys are written as expired at the very beginning of epoch,
+/// so they are always invalid on recovery and can cover the same key in older files.
+const TOMBSTONE_EXPIRE_TIMESTAMP: u64 = 1;
+
+/// Directory of its own under the temporary hint file directory for one write of a hint file.
+/// The same hint file can be written by the hint writer, by rebuilding hint files and by
+/// compacting them at the same time, so each write has its own temporary file and the latest
+/// rename to the final name wins. The directory is deleted on drop.
+struct HintFileTmpDir(PathBuf);
+
+impl HintFileTmpDir {
+    fn create(base_dir: &Path) -> DatabaseResult<HintFileTmpDir> {
+        let parent = hint_file_tmp_dir(base_dir);
+        fs::create_dir(&parent)?;
+        let p = parent.join(Uuid::new_v4().to_string());
+        fs::create_dir(&p)?;
+        Ok(HintFileTmpDir(p))
+    }
+}
+
+impl Drop for HintFileTmpDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::delete_dir(&self.0) {
+            warn!(
+                target: DEFAULT_LOG_TARGET,
+                "delete temp hint file directory: {} failed. {}",
+                self.0.display(),
+                e
+            );
+        }
+    }
+}
 
 pub struct HintFile {
+    database_dir: PathBuf,
     storage_id: StorageId,
     file: File,
     formatter: BitcaskyFormatter,
-    map_view: MmapMut,
+    view: HintFileView,
     offset: usize,
     capacity: usize,
 }
@@ -65,22 +102,25 @@ impl HintFile {
             target: DEFAULT_LOG_TARGET,
             "create hint file with id: {}", storage_id
         );
-        Self::new(file, storage_id, formatter)
+        Self::new(file, database_dir, storage_id, formatter)
     }
 
     pub fn open_iterator(
         database_dir: &Path,
         storage_id: StorageId,
+        now: u64,
+        options: Arc<BitcaskyOptions>,
     ) -> DatabaseResult<HintFileIterator> {
-        let file = Self::open(database_dir, storage_id)?;
+        let file = Self::open_validated(database_dir, storage_id, options)?;
         debug!(
             target: DEFAULT_LOG_TARGET,
             "open hint file iterator with id: {}", storage_id
         );
-        Ok(HintFileIterator { file })
+        Ok(HintFileIterator { file, now })
     }
 
     pub fn write_hint_row(&mut self, hint: &RowHint) -> DatabaseResult<()> {
+        self.ensure_capacity(hint)?;
         let value_offset = self.offset;
 
         let formatter = self.formatter;
@@ -94,76 +134,255 @@ impl HintFile {
     }
 
     pub fn read_hint_row(&mut self) -> DatabaseResult<Option<RowHint>> {
-        if self.offset + self.formatter.row_hint_header_size() >= self.capacity {
+        let header_size = self.formatter.row_hint_header_size();
+        if self.offset + header_size >= self.capacity {
             return Ok(None);
         }
 
-        let mut offset = self.offset;
-        let header_bs = &self.as_slice()[offset..offset + self.formatter.row_hint_header_size()];
+        let header_bs = &self.as_slice()[self.offset..self.offset + header_size];
         let header = self.formatter.decode_row_hint_header(header_bs);
 
-        offset += self.formatter.row_hint_header_size();
+        let net_size = header_size.saturating_add(header.key_size);
+        if self.capacity - self.offset < net_size {
+            return Err(self.corrupted(FormatterError::IoError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "hint row at offset: {} with key size: {} exceeds file size: {}",
+                    self.offset, header.key_size, self.capacity
+                ),
+            ))));
+        }
 
-        let key: Vec<u8> = self.as_slice()[offset..(offset + header.key_size)].into();
+        let hint_bs = &self.as_slice()[self.offset..self.offset + net_size];
+        if let Err(e) = self.formatter.validate_row_hint(hint_bs) {
+            return Err(self.corrupted(e));
+        }
+        let key: Vec<u8> = hint_bs[header_size..].into();
 
         debug!(target: DEFAULT_LOG_TARGET, "read hint row success. key: {:?}, header: {:?}, offset: {}", 
             key, header, self.offset);
 
-        let net_size = self.formatter.row_hint_header_size() + header.key_size;
         self.offset += net_size + padding(net_size);
 
         Ok(Some(RowHint { header, key }))
     }
 
     pub fn finish_write(&mut self) -> DatabaseResult<()> {
+        self.view.flush(&self.file)?;
         fs::truncate_file(&mut self.file, self.offset)?;
+        self.file.sync_all()?;
         Ok(())
     }
 
+    /// Opens a hint file which can be used to recover the data file
+    fn open_validated(
+        database_dir: &Path,
+        storage_id: StorageId,
+        options: Arc<BitcaskyOptions>,
+    ) -> DatabaseResult<Self> {
+        let mut file = Self::open(database_dir, storage_id)?;
+        // hint files written by formatter v1 skip deleted keys, so the same keys in older
+        // data files would come back if recovered from them
+        if let BitcaskyFormatter::V1(_) = file.formatter {
+            return Err(DatabaseError::HintFileOutdated(
+                storage_id,
+                file.formatter.version(),
+            ));
+        }
+        file.validate(options)?;
+        Ok(file)
+    }
+
     fn open(database_dir: &Path, storage_id: StorageId) -> DatabaseResult<Self> {
         let mut file = fs::open_file(database_dir, FileType::HintFile, Some(storage_id))?;
         let formatter = get_formatter_from_file(&mut file.file).map_err(|e| {
             DatabaseError::HintFileCorrupted(e, storage_id, database_dir.display().to_string())
         })?;
-        Self::new(file.file, storage_id, formatter)
+        Self::new(file.file, database_dir, storage_id, formatter)
+    }
+
+    /// Reads through all the hint rows to make sure none of them is corrupted and all of them
+    /// point into rows of the data file, then rewinds to the first row. Data files may be
+    /// preallocated, so rows are checked against the end of the last row in the data file
+    /// instead of its size.
+    fn validate(&mut self, options: Arc<BitcaskyOptions>) -> DatabaseResult<()> {
+        let mut data_file = DataStorage::open(&self.database_dir, self.storage_id, options)?;
+        // only row headers are read to find the end of rows
+        data_file.skip_to_end();
+        let rows_end = data_file.offset();
+        drop(data_file);
+        while let Some(r) = self.read_hint_row()? {
+            let row_end = r.header.row_offset.saturating_add(r.header.row_size);
+            if row_end > rows_end {
+                return Err(self.corrupted(FormatterError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "hint row of key: {:?} ends at: {} beyond the last row in data file at: {}",
+                        r.key, row_end, rows_end
+                    ),
+                ))));
+            }
+        }
+        self.offset = FILE_HEADER_SIZE;
+        Ok(())
+    }
+
+    fn corrupted(&self, e: FormatterError) -> DatabaseError {
+        DatabaseError::HintFileCorrupted(
+            e,
+            self.storage_id,
+            self.database_dir.display().to_string(),
+        )
     }
 
     fn new(
         file: File,
+        database_dir: &Path,
         storage_id: StorageId,
         formatter: BitcaskyFormatter,
     ) -> DatabaseResult<HintFile> {
         let capacity = file.metadata()?.len() as usize;
-        let mmap = unsafe { MmapOptions::new().offset(0).len(capacity).map_mut(&file)? };
+        let view = HintFileView::load(&file, capacity)?;
         Ok(HintFile {
+            database_dir: database_dir.to_path_buf(),
             storage_id,
             file,
             formatter,
             offset: FILE_HEADER_SIZE,
-            map_view: mmap,
+            view,
             capacity,
         })
     }
 
+    fn ensure_capacity(&mut self, hint: &RowHint) -> DatabaseResult<()> {
+        let net_size = self.formatter.row_hint_header_size() + hint.key.len();
+        let required_capacity = self.offset + net_size + padding(net_size);
+        if required_capacity <= self.capacity {
+            return Ok(());
+        }
+
+        let new_capacity = std::cmp::max(required_capacity + 8, self.capacity + self.capacity / 3);
+        let new_capacity = self.view.resize(&self.file, new_capacity)?;
+        debug!(
+            target: DEFAULT_LOG_TARGET,
+            "hint file with id: {}, require {} bytes, resizing from {} to {} bytes",
+            self.storage_id,
+            required_capacity,
+            self.capacity,
+            new_capacity
+        );
+        self.capacity = new_capacity;
+        Ok(())
+    }
+
     fn as_mut_slice(&mut self) -> &mut [u8] {
-        &mut self.map_view[0..self.capacity]
+        &mut self.view.as_mut_slice()[0..self.capacity]
     }
 
     fn as_slice(&self) -> &[u8] {
-        &self.map_view[0..self.capacity]
+        &self.view.as_slice()[0..self.capacity]
     }
 }
 
 impl Drop for HintFile {
     fn drop(&mut self) {
-        if let Err(e) = self.file.flush() {
+        if let Err(e) = self.view.flush(&self.file).and_then(|_| self.file.flush()) {
             warn!(target: DEFAULT_LOG_TARGET, "flush hint file failed. {}", e)
         }
     }
 }
 
+/// Content of a hint file mapped into memory
+#[cfg(feature = "mmap")]
+struct HintFileView(memmap2::MmapMut);
+
+#[cfg(feature = "mmap")]
+impl HintFileView {
+    fn load(file: &File, capacity: usize) -> std::io::Result<Self> {
+        let mmap = unsafe {
+            memmap2::MmapOptions::new()
+                .offset(0)
+                .len(capacity)
+                .map_mut(file)?
+        };
+        Ok(HintFileView(mmap))
+    }
+
+    /// Resizes the file and maps it again, returns the new capacity
+    fn resize(&mut self, file: &File, capacity: usize) -> std::io::Result<usize> {
+        self.0.flush()?;
+        let capacity = fs::resize_file(file, capacity)?;
+        *self = Self::load(file, capacity)?;
+        Ok(capacity)
+    }
+
+    fn flush(&mut self, _file: &File) -> std::io::Result<()> {
+        self.0.flush()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Content of a hint file read into memory, written back to the file on flush if changed
+#[cfg(not(feature = "mmap"))]
+struct HintFileView {
+    buffer: Vec<u8>,
+    dirty: bool,
+}
+
+#[cfg(not(feature = "mmap"))]
+impl HintFileView {
+    fn load(file: &File, capacity: usize) -> std::io::Result<Self> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut buffer = vec![0; capacity];
+        let mut f = file;
+        f.seek(SeekFrom::Start(0))?;
+        f.read_exact(&mut buffer)?;
+        Ok(HintFileView {
+            buffer,
+            dirty: false,
+        })
+    }
+
+    /// Only grows the buffer, the file grows on flush. Returns the new capacity
+    fn resize(&mut self, _file: &File, capacity: usize) -> std::io::Result<usize> {
+        self.buffer.resize(capacity, 0);
+        Ok(capacity)
+    }
+
+    fn flush(&mut self, file: &File) -> std::io::Result<()> {
+        use std::io::{Seek, SeekFrom};
+
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut f = file;
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&self.buffer)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.dirty = true;
+        &mut self.buffer
+    }
+}
+
 pub struct HintFileIterator {
     file: HintFile,
+    now: u64,
 }
 
 impl Iterator for HintFileIterator {
@@ -182,7 +401,7 @@ impl Iterator for HintFileIterator {
                     row_offset: r.header.row_offset,
                     row_size: r.header.row_size,
                 },
-                invalid: false,
+                invalid: r.header.expire_timestamp != 0 && r.header.expire_timestamp <= self.now,
                 key: r.key,
             })),
             _ => None,
@@ -191,6 +410,7 @@ impl Iterator for HintFileIterator {
 }
 
 #[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HintWriterTelemetry {
     pub number_of_pending_hint_files: usize,
     pub write_times: u64,
@@ -198,48 +418,114 @@ pub struct HintWriterTelemetry {
 
 #[derive(Debug)]
 pub struct HintWriter {
-    sender: ManuallyDrop<Sender<StorageId>>,
-    worker_join_handle: Option<JoinHandle<()>>,
+    database_dir: PathBuf,
+    options: Arc<BitcaskyOptions>,
+    #[cfg_attr(not(feature = "hint-writer"), allow(dead_code))]
+    maintenance: Arc<MaintenanceQueue>,
+    /// Storage ids of the data files to write hint file for, along with offsets
+    /// up to which the data files are durable
+    sender: Sender<(StorageId, usize)>,
+    /// Used to take over pending hint files on close
+    receiver: Receiver<(StorageId, usize)>,
+    /// Held by the task writing hint file
+    writing: Arc<Mutex<()>>,
     write_counter: Arc<AtomicU64>,
+    /// Paces reading data files and writing hint files, set for hint files of merged data files
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl HintWriter {
-    pub fn start(database_dir: &Path, options: Arc<BitcaskyOptions>) -> HintWriter {
+    pub fn start(
+        database_dir: &Path,
+        maintenance: Arc<MaintenanceQueue>,
+        options: Arc<BitcaskyOptions>,
+    ) -> HintWriter {
         let (sender, receiver) = unbounded();
+        HintWriter {
+            database_dir: database_dir.to_path_buf(),
+            options,
+            maintenance,
+            sender,
+            receiver,
+            writing: Arc::new(Mutex::new(())),
+            write_counter: Arc::new(AtomicU64::new(0)),
+            rate_limiter: None,
+        }
+    }
 
-        let write_counter = Arc::new(AtomicU64::new(0));
-        let moved_counter = write_counter.clone();
-        let moved_dir = database_dir.to_path_buf();
-        let worker_join_handle = Some(thread::spawn(move || {
-            while let Ok(storage_id) = receiver.recv() {
-                if let Err(e) = Self::write_hint_file(&moved_dir, storage_id, options.clone()) {
-                    warn!(
-                        target: DEFAULT_LOG_TARGET,
-                        "write hint file with id: {} under path: {} failed {}",
-                        storage_id,
-                        moved_dir.display(),
-                        e
-                    );
-                } else {
-                    moved_counter.fetch_add(1, Ordering::Relaxed);
+    pub(crate) fn set_rate_limiter(&mut self, rate_limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(rate_limiter);
+    }
+
+    /// Writes hint file in background for a data file which was sealed by `transit_to_readonly`
+    /// and is durable up to `durable_offset`. Without the `hint-writer` feature the hint file
+    /// is written in current thread instead.
+    pub fn async_write_hint_file(&self, data_storage_id: StorageId, durable_offset: usize) {
+        #[cfg(not(feature = "hint-writer"))]
+        {
+            match Self::write_hint_file_with_limiter(
+                &self.database_dir,
+                data_storage_id,
+                durable_offset,
+                self.options.clone(),
+                self.rate_limiter.as_deref(),
+            ) {
+                Ok(_) => {
+                    self.write_counter.fetch_add(1, Ordering::Relaxed);
                 }
+                Err(e) => warn!(
+                    target: DEFAULT_LOG_TARGET,
+                    "write hint file with id: {} under path: {} failed {}",
+                    data_storage_id,
+                    self.database_dir.display(),
+                    e
+                ),
             }
-        }));
-
-        HintWriter {
-            sender: ManuallyDrop::new(sender),
-            worker_join_handle,
-            write_counter,
         }
+        #[cfg(feature = "hint-writer")]
+        self.submit_hint_file(data_storage_id, durable_offset);
     }
 
-    pub fn async_write_hint_file(&self, data_storage_id: StorageId) {
-        if let Err(e) = self.sender.send(data_storage_id) {
+    #[cfg(feature = "hint-writer")]
+    fn submit_hint_file(&self, data_storage_id: StorageId, durable_offset: usize) {
+        if let Err(e) = self.sender.send((data_storage_id, durable_offset)) {
             error!(
                 target: DEFAULT_LOG_TARGET,
                 "send file id: {} to hint file writer failed with error {}", data_storage_id, e
             );
+            return;
         }
+
+        let database_dir = self.database_dir.clone();
+        let options = self.options.clone();
+        let receiver = self.receiver.clone();
+        let writing = self.writing.clone();
+        let write_counter = self.write_counter.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        self.maintenance.submit(move || {
+            let _writing = writing.lock();
+            // the storage id may be taken over on close
+            let Ok((storage_id, durable_offset)) = receiver.try_recv() else {
+                return;
+            };
+            if let Err(e) = Self::write_hint_file_with_limiter(
+                &database_dir,
+                storage_id,
+                durable_offset,
+                options,
+                rate_limiter.as_deref(),
+            ) {
+                warn!(
+                    target: DEFAULT_LOG_TARGET,
+                    "write hint file with id: {} under path: {} failed {}",
+                    storage_id,
+                    database_dir.display(),
+                    e
+                );
+            } else {
+                write_counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
     }
 
     pub fn get_telemetry_data(&self) -> HintWriterTelemetry {
@@ -249,29 +535,66 @@ impl HintWriter {
         }
     }
 
-    fn write_hint_file(
+    /// Write hint file for a data file in current thread. The data file must be durable up to
+    /// `durable_offset`, and the hint file is only renamed to its final name after all of its
+    /// rows are synced, so a hint file never describes rows which are not durable.
+    pub fn write_hint_file(
+        database_dir: &Path,
+        data_storage_id: StorageId,
+        durable_offset: usize,
+        options: Arc<BitcaskyOptions>,
+    ) -> DatabaseResult<()> {
+        Self::write_hint_file_with_limiter(
+            database_dir,
+            data_storage_id,
+            durable_offset,
+            options,
+            None,
+        )
+    }
+
+    /// Writes hint file like `write_hint_file`, consuming bytes of rows read from the data file
+    /// and bytes of hint rows written from the rate limiter as they are read and written
+    fn write_hint_file_with_limiter(
         database_dir: &Path,
         data_storage_id: StorageId,
+        durable_offset: usize,
         options: Arc<BitcaskyOptions>,
+        rate_limiter: Option<&RateLimiter>,
     ) -> DatabaseResult<()> {
-        let m = HintWriter::build_row_hint(database_dir, data_storage_id, options.clone())?;
+        let m = HintWriter::build_row_hint(
+            database_dir,
+            data_storage_id,
+            durable_offset,
+            options.clone(),
+            rate_limiter,
+        )?;
 
-        let hint_file_tmp_dir = create_hint_file_tmp_dir(database_dir)?;
+        let hint_file_tmp_dir = HintFileTmpDir::create(database_dir)?;
         let mut hint_file = HintFile::create(
-            &hint_file_tmp_dir,
+            &hint_file_tmp_dir.0,
             data_storage_id,
             options.database.init_hint_file_capacity,
         )?;
-        m.values()
-            .map(|r| hint_file.write_hint_row(r))
-            .collect::<DatabaseResult<Vec<_>>>()?;
+        for r in m.values() {
+            let offset = hint_file.offset;
+            hint_file.write_hint_row(r)?;
+            if let Some(limiter) = rate_limiter {
+                limiter.consume((hint_file.offset - offset) as u64);
+            }
+        }
 
         hint_file.finish_write()?;
 
+        fail_point!("hint::before_rename", |_| {
+            Err(DatabaseError::IoError(std::io::Error::other(
+                "failpoint hint::before_rename",
+            )))
+        });
         fs::move_file(
             FileType::HintFile,
             Some(data_storage_id),
-            &hint_file_tmp_dir,
+            &hint_file_tmp_dir.0,
             database_dir,
         )?;
         Ok(())
@@ -280,7 +603,9 @@ impl HintWriter {
     fn build_row_hint(
         database_dir: &Path,
         data_storage_id: StorageId,
+        durable_offset: usize,
         options: Arc<BitcaskyOptions>,
+        rate_limiter: Option<&RateLimiter>,
     ) -> DatabaseResult<HashMap<Vec<u8>, RowHint>> {
         let stable_file_opt = DataStorage::open(database_dir, data_storage_id, options.clone())?;
 
@@ -290,22 +615,35 @@ impl HintWriter {
         for row in data_itr {
             match row {
                 Ok(r) => {
-                    if !r.value.is_valid(options.clock.now()) {
-                        m.remove(&r.key);
+                    if let Some(limiter) = rate_limiter {
+                        limiter.consume(r.row_location.row_size as u64);
+                    }
+                    let row_end = r.row_location.row_offset + r.row_location.row_size;
+                    if row_end > durable_offset {
+                        return Err(DatabaseError::RowNotDurable(
+                            data_storage_id,
+                            r.row_location.row_offset,
+                            durable_offset,
+                        ));
+                    }
+                    // keep invalid rows too, they need to cover the same key in older files on recovery
+                    let expire_timestamp = if is_tombstone(&r.value.value) {
+                        TOMBSTONE_EXPIRE_TIMESTAMP
                     } else {
-                        m.insert(
-                            r.key.clone(),
-                            RowHint {
-                                header: RowHintHeader {
-                                    expire_timestamp: r.value.expire_timestamp,
-                                    key_size: r.key.len(),
-                                    row_offset: r.row_location.row_offset,
-                                    row_size: r.row_location.row_size,
-                                },
-                                key: r.key,
+                        r.value.expire_timestamp
+                    };
+                    m.insert(
+                        r.key.clone(),
+                        RowHint {
+                            header: RowHintHeader {
+                                expire_timestamp,
+                                key_size: r.key.len(),
+                                row_offset: r.row_location.row_offset,
+                                row_size: r.row_location.row_size,
                             },
-                        );
-                    }
+                            key: r.key,
+                        },
+                    );
                 }
                 Err(e) => return Err(DatabaseError::StorageError(e)),
             }
@@ -314,32 +652,178 @@ impl HintWriter {
     }
 }
 
-impl Drop for HintWriter {
-    fn drop(&mut self) {
-        unsafe { ManuallyDrop::drop(&mut self.sender) }
-        if let Some(join_handle) = self.worker_join_handle.take() {
-            if join_handle.join().is_err() {
-                error!(
+impl HintWriter {
+    /// Writes pending hint files in current thread then waits for the one in progress, until
+    /// the timeout elapsed. Returns false if some hint file is still pending or in progress
+    /// on timeout.
+    pub fn flush_pending(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        // take over pending hint files so we do not wait for them to be scheduled on the pool
+        let pending_storage_ids = self
+            .receiver
+            .try_iter()
+            .collect::<Vec<(StorageId, usize)>>();
+
+        let mut flushed = true;
+        for (storage_id, durable_offset) in pending_storage_ids {
+            if Instant::now() >= deadline {
+                warn!(
                     target: DEFAULT_LOG_TARGET,
-                    "wait worker thread finish failed"
+                    "skip writing hint file with id: {} due to timeout", storage_id
                 );
+                flushed = false;
+                continue;
+            }
+            match Self::write_hint_file_with_limiter(
+                &self.database_dir,
+                storage_id,
+                durable_offset,
+                self.options.clone(),
+                self.rate_limiter.as_deref(),
+            ) {
+                Ok(_) => {
+                    self.write_counter.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => warn!(
+                    target: DEFAULT_LOG_TARGET,
+                    "write hint file with id: {} failed {}", storage_id, e
+                ),
             }
         }
+
+        // wait for the hint file in progress
+        if self.writing.try_lock_until(deadline).is_none() {
+            warn!(
+                target: DEFAULT_LOG_TARGET,
+                "hint file writer did not finish in {:?}, leave it running", timeout
+            );
+            return false;
+        }
+        flushed
     }
 }
 
-pub fn clear_temp_hint_file_directory(database_dir: &Path) {
-    if let Err(e) = create_hint_file_tmp_dir(database_dir).and_then(|hint_file_tmp_dir| {
-        let paths = std::fs::read_dir(hint_file_tmp_dir)?;
-        for path in paths {
-            let file_path = path?;
-            if !file_path.path().is_file() {
+impl Drop for HintWriter {
+    fn drop(&mut self) {
+        self.flush_pending(self.options.database.hint_write_timeout);
+    }
+}
+
+/// Removes hint files which data files no longer exist, then drops hint rows covered by rows of
+/// the same key in later hint files. Hint files which can not be used on recovery are left
+/// untouched. Returns how many hint files are removed.
+pub fn compact_hint_files(
+    database_dir: &Path,
+    options: &Arc<BitcaskyOptions>,
+) -> DatabaseResult<usize> {
+    let storage_ids = fs::get_storage_ids_in_dir(database_dir, FileType::HintFile);
+
+    let mut removed = 0;
+    let mut hinted_storage_ids = vec![];
+    for storage_id in storage_ids {
+        if FileType::DataFile
+            .get_path(database_dir, Some(storage_id))
+            .exists()
+        {
+            hinted_storage_ids.push(storage_id);
+            continue;
+        }
+        fs::delete_file(database_dir, FileType::HintFile, Some(storage_id))?;
+        debug!(
+            target: DEFAULT_LOG_TARGET,
+            "remove hint file with id: {} which has no data file", storage_id
+        );
+        removed += 1;
+    }
+
+    // walk from the latest hint file, rows of keys seen already are covered by later rows
+    let mut seen_keys = HashSet::new();
+    for storage_id in hinted_storage_ids.into_iter().rev() {
+        let rows = match read_hint_rows(database_dir, storage_id, options.clone()) {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!(
+                    target: DEFAULT_LOG_TARGET,
+                    "skip compacting hint file with id: {}. {}", storage_id, e
+                );
                 continue;
             }
-            std::fs::remove_file(file_path.path())?;
+        };
+        let total_rows = rows.len();
+        let live_rows = rows
+            .into_iter()
+            .filter(|r| seen_keys.insert(r.key.clone()))
+            .collect::<Vec<RowHint>>();
+        if live_rows.len() == total_rows {
+            continue;
         }
-        Ok(())
-    }) {
+        rewrite_hint_file(
+            database_dir,
+            storage_id,
+            &live_rows,
+            options.database.init_hint_file_capacity,
+        )?;
+        debug!(
+            target: DEFAULT_LOG_TARGET,
+            "compact hint file with id: {} from {} rows to {} rows",
+            storage_id,
+            total_rows,
+            live_rows.len()
+        );
+    }
+    Ok(removed)
+}
+
+fn read_hint_rows(
+    database_dir: &Path,
+    storage_id: StorageId,
+    options: Arc<BitcaskyOptions>,
+) -> DatabaseResult<Vec<RowHint>> {
+    let mut file = HintFile::open_validated(database_dir, storage_id, options)?;
+    let mut rows = vec![];
+    while let Some(r) = file.read_hint_row()? {
+        rows.push(r);
+    }
+    Ok(rows)
+}
+
+/// Writes rows to a temporary hint file then renames it to replace the hint file of the data file
+fn rewrite_hint_file(
+    database_dir: &Path,
+    storage_id: StorageId,
+    rows: &[RowHint],
+    init_hint_file_capacity: usize,
+) -> DatabaseResult<()> {
+    // data file may be purged by merge after its hint file was read
+    if !FileType::DataFile
+        .get_path(database_dir, Some(storage_id))
+        .exists()
+    {
+        return Ok(());
+    }
+    let hint_file_tmp_dir = HintFileTmpDir::create(database_dir)?;
+    let mut hint_file =
+        HintFile::create(&hint_file_tmp_dir.0, storage_id, init_hint_file_capacity)?;
+    for r in rows {
+        hint_file.write_hint_row(r)?;
+    }
+    hint_file.finish_write()?;
+    fs::move_file(
+        FileType::HintFile,
+        Some(storage_id),
+        &hint_file_tmp_dir.0,
+        database_dir,
+    )?;
+    Ok(())
+}
+
+/// Deletes temporary hint files left by writes interrupted by crash
+pub fn clear_temp_hint_file_directory(database_dir: &Path) {
+    let hint_file_tmp_dir = hint_file_tmp_dir(database_dir);
+    if !hint_file_tmp_dir.exists() {
+        return;
+    }
+    if let Err(e) = fs::delete_dir(&hint_file_tmp_dir) {
         error!(
             target: DEFAULT_LOG_TARGET,
             "clear temp hint file directory failed. {}", e
@@ -347,20 +831,17 @@ pub fn clear_temp_hint_file_directory(database_dir: &Path) {
     }
 }
 
-fn create_hint_file_tmp_dir(base_dir: &Path) -> DatabaseResult<PathBuf> {
-    let p = hint_file_tmp_dir(base_dir);
-    fs::create_dir(p.as_path())?;
-    Ok(p)
-}
-
 fn hint_file_tmp_dir(base_dir: &Path) -> PathBuf {
     base_dir.join(HINT_FILES_TMP_DIRECTORY)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::{Seek, SeekFrom};
+
     use crate::database::data_storage::DataStorageWriter;
-    use crate::formatter::RowToWrite;
+    use crate::formatter::{FormatterV1, RowToWrite};
+    use crate::maintenance::MaintenancePool;
 
     use super::*;
     use test_log::test;
@@ -413,18 +894,19 @@ mod tests {
         let pos = writing_file
             .write_row(&RowToWrite::new(&key, val.to_vec()))
             .unwrap();
-        writing_file.flush().unwrap();
+        let durable_offset = writing_file.transit_to_readonly().unwrap();
 
         {
             let writer = HintWriter::start(
                 &dir,
+                Arc::new(Arc::new(MaintenancePool::new(1)).queue()),
                 Arc::new(
                     BitcaskyOptions::default()
                         .max_data_file_size(1024)
                         .init_data_file_capacity(100),
                 ),
             );
-            writer.async_write_hint_file(storage_id);
+            writer.async_write_hint_file(storage_id, durable_offset);
         }
 
         let mut hint_file = HintFile::open(&dir, storage_id).unwrap();
@@ -436,4 +918,173 @@ mod tests {
             unreachable!();
         }
     }
+
+    #[test]
+    fn test_write_same_hint_file_concurrently() {
+        let dir = get_temporary_directory_path();
+        let options = Arc::new(BitcaskyOptions::default());
+        let mut data_file = DataStorage::new(
+            &dir,
+            1,
+            Arc::new(BitcaskyFormatter::default()),
+            options.clone(),
+        )
+        .unwrap();
+        for i in 0..100 {
+            data_file
+                .write_row(&RowToWrite::new(&format!("k{}", i), b"value".to_vec()))
+                .unwrap();
+        }
+        let durable_offset = data_file.transit_to_readonly().unwrap();
+
+        // each write has its own temporary file
+        let handles = (0..4)
+            .map(|_| {
+                let dir = dir.clone();
+                let options = options.clone();
+                std::thread::spawn(move || {
+                    HintWriter::write_hint_file(&dir, 1, durable_offset, options)
+                })
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap().unwrap();
+        }
+
+        let iter = HintFile::open_iterator(&dir, 1, 0, options).unwrap();
+        assert_eq!(100, iter.count());
+        assert_eq!(
+            0,
+            std::fs::read_dir(hint_file_tmp_dir(&dir)).unwrap().count()
+        );
+    }
+
+    fn write_hint_rows(
+        dir: &Path,
+        storage_id: StorageId,
+        formatter: BitcaskyFormatter,
+    ) -> Vec<RowLocation> {
+        // the data file is preallocated beyond its rows
+        let mut data_file = DataStorage::new(
+            dir,
+            storage_id,
+            Arc::new(formatter),
+            Arc::new(BitcaskyOptions::default().init_data_file_capacity(1024)),
+        )
+        .unwrap();
+        let locations = [b"k1", b"k2"]
+            .iter()
+            .map(|k| {
+                data_file
+                    .write_row(&RowToWrite::new(k, b"value".to_vec()))
+                    .unwrap()
+            })
+            .collect::<Vec<RowLocation>>();
+        data_file.flush().unwrap();
+
+        let file = create_data_file(
+            dir,
+            FileType::HintFile,
+            Some(storage_id),
+            &formatter,
+            false,
+            64,
+        )
+        .unwrap();
+        let mut hint_file = HintFile::new(file, dir, storage_id, formatter).unwrap();
+        for (key, location) in [b"k1", b"k2"].iter().zip(locations.iter()) {
+            hint_file
+                .write_hint_row(&RowHint {
+                    header: RowHintHeader {
+                        expire_timestamp: 0,
+                        key_size: key.len(),
+                        row_offset: location.row_offset,
+                        row_size: location.row_size,
+                    },
+                    key: key.to_vec(),
+                })
+                .unwrap();
+        }
+        hint_file.finish_write().unwrap();
+        locations
+    }
+
+    #[test]
+    fn test_open_hint_file_without_checksum() {
+        let dir = get_temporary_directory_path();
+        write_hint_rows(&dir, 1, BitcaskyFormatter::V1(FormatterV1::default()));
+
+        // hint files written by formatter v1 do not keep deleted keys, they are not used on recovery
+        assert_matches!(
+            HintFile::open_iterator(&dir, 1, 0, Arc::new(BitcaskyOptions::default())).err(),
+            Some(DatabaseError::HintFileOutdated(1, 1))
+        );
+    }
+
+    #[test]
+    fn test_open_corrupted_hint_file() {
+        let dir = get_temporary_directory_path();
+        write_hint_rows(&dir, 1, BitcaskyFormatter::default());
+
+        let mut file = fs::open_file(&dir, FileType::HintFile, Some(1))
+            .unwrap()
+            .file;
+        // flip the row offset of the second hint row
+        let formatter = BitcaskyFormatter::default();
+        let row_size = formatter.row_hint_header_size() + 2;
+        file.seek(SeekFrom::Start(
+            (FILE_HEADER_SIZE + row_size + padding(row_size) + 20) as u64,
+        ))
+        .unwrap();
+        file.write_all(&[0xff]).unwrap();
+
+        assert_matches!(
+            HintFile::open_iterator(&dir, 1, 0, Arc::new(BitcaskyOptions::default())).err(),
+            Some(DatabaseError::HintFileCorrupted(..))
+        );
+    }
+
+    #[test]
+    fn test_open_hint_file_beyond_data_file() {
+        let dir = get_temporary_directory_path();
+        let locations = write_hint_rows(&dir, 1, BitcaskyFormatter::default());
+        let options = Arc::new(BitcaskyOptions::default());
+        assert!(HintFile::open_iterator(&dir, 1, 0, options.clone()).is_ok());
+
+        let mut data_file = fs::open_file(&dir, FileType::DataFile, Some(1))
+            .unwrap()
+            .file;
+        fs::truncate_file(&mut data_file, locations[1].row_offset + 1).unwrap();
+
+        assert_matches!(
+            HintFile::open_iterator(&dir, 1, 0, options).err(),
+            Some(DatabaseError::HintFileCorrupted(..))
+        );
+    }
+
+    #[test]
+    fn test_open_hint_file_beyond_rows_of_preallocated_data_file() {
+        let dir = get_temporary_directory_path();
+        let locations = write_hint_rows(&dir, 1, BitcaskyFormatter::default());
+
+        // the last row is lost while the data file keeps its preallocated size
+        let mut data_file = fs::open_file(&dir, FileType::DataFile, Some(1))
+            .unwrap()
+            .file;
+        data_file
+            .seek(SeekFrom::Start(locations[1].row_offset as u64))
+            .unwrap();
+        data_file
+            .write_all(&vec![0; locations[1].row_size])
+            .unwrap();
+        assert!(
+            data_file.metadata().unwrap().len() as usize
+                > locations[1].row_offset + locations[1].row_size
+        );
+
+        assert_matches!(
+            HintFile::open_iterator(&dir, 1, 0, Arc::new(BitcaskyOptions::default())).err(),
+            Some(DatabaseError::HintFileCorrupted(..))
+        );
+    }
 }
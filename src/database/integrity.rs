@@ -0,0 +1,666 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use crate::logging::{info, warn};
+
+use crate::{
+    clock::Clock,
+    fs::{self, FileType},
+    options::{BitcaskyOptions, CorruptionPolicy},
+    storage_id::StorageId,
+};
+
+use crate::formatter::{get_formatter_from_file, RowToWrite, FILE_HEADER_SIZE};
+
+use super::{
+    common::DatabaseResult,
+    data_storage::{DataStorage, DataStorageError, DataStorageReader, DataStorageWriter},
+    hint::HintFile,
+};
+
+const DEFAULT_LOG_TARGET: &str = "Integrity";
+const REPAIR_FILES_DIRECTORY: &str = "Repair";
+
+/// Integrity of a hint file compared with its data file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HintFileReport {
+    pub rows: usize,
+    /// Hint rows not pointing to a valid row with the same key in data file
+    pub mismatched_rows: usize,
+    /// Hint file can not be read till the end
+    pub corrupted: bool,
+}
+
+/// Integrity of a data file and its hint file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataFileReport {
+    pub storage_id: StorageId,
+    /// Valid rows in this data file
+    pub rows: usize,
+    /// Offset of the first row failed on validation
+    pub first_bad_offset: Option<u64>,
+    /// The bad row is a torn write at the end of file which can be truncated by repair
+    pub torn_tail: bool,
+    pub hint_file: Option<HintFileReport>,
+}
+
+impl DataFileReport {
+    pub fn is_healthy(&self) -> bool {
+        self.first_bad_offset.is_none()
+            && self
+                .hint_file
+                .as_ref()
+                .map(|h| h.mismatched_rows == 0 && !h.corrupted)
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    pub data_files: Vec<DataFileReport>,
+    /// Hint files without data file
+    pub orphaned_hint_files: Vec<StorageId>,
+}
+
+impl IntegrityReport {
+    pub fn is_healthy(&self) -> bool {
+        self.orphaned_hint_files.is_empty() && self.data_files.iter().all(|f| f.is_healthy())
+    }
+}
+
+/// Checksum verification of all the rows in data files
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VerifyReport {
+    pub good_rows: usize,
+    pub bad_rows: usize,
+    /// Storage id and offset of each row failed on verification. When a corrupted row
+    /// can not be skipped, rows after it in the same data file are not verified.
+    pub bad_locations: Vec<(StorageId, u64)>,
+}
+
+impl VerifyReport {
+    pub fn is_healthy(&self) -> bool {
+        self.bad_rows == 0
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Data files which torn write at the end was truncated
+    pub truncated_data_files: Vec<StorageId>,
+    /// Data files rewritten without their corrupted rows
+    pub rewritten_data_files: Vec<StorageId>,
+    /// Hint files deleted due to their data file is missing or rewritten
+    pub deleted_hint_files: Vec<StorageId>,
+    /// Data files skipped as their header can not be read, they are left untouched
+    pub unreadable_data_files: Vec<StorageId>,
+    /// Corrupted rows dropped, including torn writes
+    pub dropped_rows: usize,
+    /// Bytes of data files dropped along with the corrupted rows
+    pub dropped_bytes: usize,
+}
+
+/// Walks every data file and hint file under the directory without changing anything
+pub fn check_integrity(
+    database_dir: &Path,
+    options: Arc<BitcaskyOptions>,
+) -> DatabaseResult<IntegrityReport> {
+    let data_storage_ids = fs::get_storage_ids_in_dir(database_dir, FileType::DataFile);
+    let hint_storage_ids = fs::get_storage_ids_in_dir(database_dir, FileType::HintFile);
+
+    let mut report = IntegrityReport::default();
+    for storage_id in data_storage_ids.iter() {
+        report.data_files.push(check_data_file(
+            database_dir,
+            *storage_id,
+            hint_storage_ids.contains(storage_id),
+            options.clone(),
+        )?);
+    }
+    report.orphaned_hint_files = hint_storage_ids
+        .into_iter()
+        .filter(|id| !data_storage_ids.contains(id))
+        .collect();
+    Ok(report)
+}
+
+/// Truncates torn writes at the end of data files, rewrites data files with corrupted rows in
+/// the middle by copying all the valid rows to a fresh file with the same storage id, and deletes
+/// hint files without data file. It only relies on reading data files row by row, so it works on
+/// directories which can not be opened.
+pub fn repair(database_dir: &Path, options: Arc<BitcaskyOptions>) -> DatabaseResult<RepairReport> {
+    let data_storage_ids = fs::get_storage_ids_in_dir(database_dir, FileType::DataFile);
+
+    let mut report = RepairReport::default();
+    for storage_id in data_storage_ids.iter() {
+        let mut storage = match DataStorage::open(database_dir, *storage_id, options.clone()) {
+            Ok(s) => s,
+            Err(
+                e @ (DataStorageError::ReadFileHeaderError(..)
+                | DataStorageError::DataStorageFormatter(_)
+                | DataStorageError::IncompleteDataFile(_)),
+            ) => {
+                warn!(target: DEFAULT_LOG_TARGET, "skip data file with id: {} which header can not be read. {}", storage_id, e);
+                report.unreadable_data_files.push(*storage_id);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match storage.find_torn_tail() {
+            Ok(true) => {
+                let data_end = find_data_end(database_dir, *storage_id)?;
+                report.dropped_rows += 1;
+                report.dropped_bytes += data_end.saturating_sub(storage.offset());
+                storage.truncate_torn_tail()?;
+                info!(target: DEFAULT_LOG_TARGET, "truncated torn write in data file with id: {}", storage_id);
+                report.truncated_data_files.push(*storage_id);
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!(target: DEFAULT_LOG_TARGET, "data file with id: {} is corrupted, rewrite it without corrupted rows. {}", storage_id, e);
+                drop(storage);
+                rewrite_data_file(database_dir, *storage_id, options.clone(), &mut report)?;
+                report.rewritten_data_files.push(*storage_id);
+            }
+        }
+    }
+
+    for storage_id in fs::get_storage_ids_in_dir(database_dir, FileType::HintFile)
+        .into_iter()
+        .filter(|id| !data_storage_ids.contains(id))
+    {
+        fs::delete_file(database_dir, FileType::HintFile, Some(storage_id))?;
+        info!(target: DEFAULT_LOG_TARGET, "deleted orphaned hint file with id: {}", storage_id);
+        report.deleted_hint_files.push(storage_id);
+    }
+    Ok(report)
+}
+
+/// Copies valid rows of the data file to a new data file in repair directory, then replaces
+/// the data file with it. The storage id is kept so rows in later data files still win. The hint
+/// file of the data file is deleted before the replacement, so a crash in between never leaves
+/// it pointing into the new data file.
+fn rewrite_data_file(
+    database_dir: &Path,
+    storage_id: StorageId,
+    options: Arc<BitcaskyOptions>,
+    report: &mut RepairReport,
+) -> DatabaseResult<()> {
+    let repair_dir = database_dir.join(REPAIR_FILES_DIRECTORY);
+    if repair_dir.exists() {
+        fs::delete_dir(&repair_dir)?;
+    }
+    fs::create_dir(&repair_dir)?;
+
+    // keep the formatter of the data file so valid rows always fit in the new file
+    let formatter = {
+        let mut f = fs::open_file(database_dir, FileType::DataFile, Some(storage_id))?;
+        get_formatter_from_file(&mut f.file)
+            .map_err(|e| DataStorageError::ReadFileHeaderError(e, storage_id))?
+    };
+    let source = DataStorage::open(database_dir, storage_id, options.clone())?;
+    let file_size = FileType::DataFile
+        .get_path(database_dir, Some(storage_id))
+        .metadata()?
+        .len() as usize;
+    let mut target = DataStorage::new(
+        &repair_dir,
+        storage_id,
+        Arc::new(formatter),
+        Arc::new(rewrite_options(&options, file_size)),
+    )?;
+
+    let mut kept_bytes = 0;
+    let mut last_row_end = FILE_HEADER_SIZE;
+    let mut iter = source.iter()?.skip_corrupted(true);
+    for row in iter.by_ref() {
+        let row = row?;
+        kept_bytes += row.row_location.row_size;
+        last_row_end = row.row_location.row_offset + row.row_location.row_size;
+        target.write_row(
+            &RowToWrite::new_with_timestamp(row.key, row.value.value, row.value.expire_timestamp)
+                .with_write_timestamp(row.value.write_timestamp),
+        )?;
+    }
+    target.transit_to_readonly()?;
+    drop(target);
+
+    // paddings of the last valid row are zeros
+    let data_size = find_data_end(database_dir, storage_id)?.max(last_row_end) - FILE_HEADER_SIZE;
+    let dropped_rows = iter.corrupted_offsets().len();
+    let dropped_bytes = data_size.saturating_sub(kept_bytes);
+    report.dropped_rows += dropped_rows;
+    report.dropped_bytes += dropped_bytes;
+
+    // offsets in the hint file are changed by the rewrite
+    if FileType::HintFile
+        .get_path(database_dir, Some(storage_id))
+        .exists()
+    {
+        fs::delete_file(database_dir, FileType::HintFile, Some(storage_id))?;
+        info!(target: DEFAULT_LOG_TARGET, "deleted hint file of rewritten data file with id: {}", storage_id);
+        report.deleted_hint_files.push(storage_id);
+    }
+    fs::move_file(
+        FileType::DataFile,
+        Some(storage_id),
+        &repair_dir,
+        database_dir,
+    )?;
+    fs::delete_dir(&repair_dir)?;
+    info!(target: DEFAULT_LOG_TARGET, "rewrote data file with id: {}, dropped {} rows with {} bytes", 
+        storage_id, dropped_rows, dropped_bytes);
+    Ok(())
+}
+
+/// Options of the data file rewritten from a data file of the size. Its capacity and size limit
+/// are the size, so valid rows always fit even if `max_data_file_size` was lowered since the data
+/// file was written.
+fn rewrite_options(options: &BitcaskyOptions, file_size: usize) -> BitcaskyOptions {
+    let storage = &options.database.storage;
+    let mut rewrite_options = BitcaskyOptions::default()
+        .max_data_file_size(file_size)
+        .init_data_file_capacity(file_size)
+        .storage_type(storage.storage_type);
+    rewrite_options.database.storage.max_key_size = storage.max_key_size;
+    rewrite_options.database.storage.max_value_size = storage.max_value_size;
+    rewrite_options
+}
+
+/// Verifies checksum of every row in data files under the directory before it's opened, and
+/// handles corrupted rows by `corruption_policy` in options. With `FailFast`, it stops at the
+/// first data file with corrupted rows.
+pub fn validate(
+    database_dir: &Path,
+    options: Arc<BitcaskyOptions>,
+) -> DatabaseResult<VerifyReport> {
+    let mut report = VerifyReport::default();
+    for storage_id in fs::get_storage_ids_in_dir(database_dir, FileType::DataFile) {
+        let mut file_report = VerifyReport::default();
+        verify_data_file(database_dir, storage_id, options.clone(), &mut file_report)?;
+        report.good_rows += file_report.good_rows;
+        report.bad_rows += file_report.bad_rows;
+        report
+            .bad_locations
+            .extend(file_report.bad_locations.iter().copied());
+        let Some((_, offset)) = file_report.bad_locations.first() else {
+            continue;
+        };
+        match options.corruption_policy {
+            CorruptionPolicy::FailFast => break,
+            CorruptionPolicy::SkipCorrupted => {}
+            CorruptionPolicy::TruncateAtCorruption => {
+                truncate_data_file(database_dir, storage_id, *offset as usize)?
+            }
+        }
+    }
+    info!(target: DEFAULT_LOG_TARGET, "validated data files, {} good rows, {} bad rows", report.good_rows, report.bad_rows);
+    Ok(report)
+}
+
+/// Clears the data file from offset, so the row at offset and all the rows after it are
+/// dropped. The data file is deleted if its header is corrupted. Its hint file is deleted too,
+/// as it may point to dropped rows.
+fn truncate_data_file(
+    database_dir: &Path,
+    storage_id: StorageId,
+    offset: usize,
+) -> DatabaseResult<()> {
+    if offset < FILE_HEADER_SIZE {
+        fs::delete_file(database_dir, FileType::DataFile, Some(storage_id))?;
+        warn!(target: DEFAULT_LOG_TARGET, "deleted data file with id: {} with corrupted header", storage_id);
+    } else {
+        let f = fs::open_file(database_dir, FileType::DataFile, Some(storage_id))?.file;
+        let capacity = f.metadata()?.len();
+        // shrinking then growing the file back fills the tail with zeros
+        f.set_len(offset as u64)?;
+        f.set_len(capacity)?;
+        f.sync_all()?;
+        warn!(target: DEFAULT_LOG_TARGET, "truncated data file with id: {} at corrupted row at offset: {}", storage_id, offset);
+    }
+    if FileType::HintFile
+        .get_path(database_dir, Some(storage_id))
+        .exists()
+    {
+        fs::delete_file(database_dir, FileType::HintFile, Some(storage_id))?;
+    }
+    Ok(())
+}
+
+/// Data files are preallocated with zeros, so data ends after the last non-zero byte
+fn find_data_end(database_dir: &Path, storage_id: StorageId) -> DatabaseResult<usize> {
+    let bs = std::fs::read(FileType::DataFile.get_path(database_dir, Some(storage_id)))?;
+    Ok(bs
+        .iter()
+        .rposition(|b| *b != 0)
+        .map(|p| p + 1)
+        .unwrap_or(0)
+        .max(FILE_HEADER_SIZE))
+}
+
+/// Verifies checksum of every row in the data file and keeps going after corrupted rows
+pub fn verify_data_file(
+    database_dir: &Path,
+    storage_id: StorageId,
+    options: Arc<BitcaskyOptions>,
+    report: &mut VerifyReport,
+) -> DatabaseResult<()> {
+    let storage = match DataStorage::open(database_dir, storage_id, options) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(target: DEFAULT_LOG_TARGET, "open data file with id: {} failed. {}", storage_id, e);
+            report.bad_rows += 1;
+            report.bad_locations.push((storage_id, 0));
+            return Ok(());
+        }
+    };
+    let mut iter = storage.iter()?.skip_corrupted(true);
+    for row in iter.by_ref() {
+        row?;
+        report.good_rows += 1;
+    }
+    for offset in iter.corrupted_offsets() {
+        report.bad_rows += 1;
+        report.bad_locations.push((storage_id, *offset));
+    }
+    Ok(())
+}
+
+fn check_data_file(
+    database_dir: &Path,
+    storage_id: StorageId,
+    has_hint_file: bool,
+    options: Arc<BitcaskyOptions>,
+) -> DatabaseResult<DataFileReport> {
+    let mut report = DataFileReport {
+        storage_id,
+        rows: 0,
+        first_bad_offset: None,
+        torn_tail: false,
+        hint_file: None,
+    };
+    let mut storage = match DataStorage::open(database_dir, storage_id, options.clone()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(target: DEFAULT_LOG_TARGET, "open data file with id: {} failed. {}", storage_id, e);
+            report.first_bad_offset = Some(0);
+            return Ok(report);
+        }
+    };
+
+    // key and size of valid rows by their offset
+    let mut rows = HashMap::new();
+    let mut iter = storage.iter()?;
+    for row in iter.by_ref() {
+        let row = row?;
+        rows.insert(
+            row.row_location.row_offset,
+            (row.key, row.row_location.row_size),
+        );
+    }
+    report.rows = rows.len();
+    report.first_bad_offset = iter.corrupted_offsets().first().copied();
+    if report.first_bad_offset.is_some() {
+        report.torn_tail = storage.find_torn_tail().unwrap_or(false);
+    }
+
+    if has_hint_file {
+        report.hint_file = Some(check_hint_file(database_dir, storage_id, &rows, options));
+    }
+    Ok(report)
+}
+
+fn check_hint_file(
+    database_dir: &Path,
+    storage_id: StorageId,
+    rows: &HashMap<usize, (Vec<u8>, usize)>,
+    options: Arc<BitcaskyOptions>,
+) -> HintFileReport {
+    let mut report = HintFileReport {
+        rows: 0,
+        mismatched_rows: 0,
+        corrupted: false,
+    };
+    let now = options.clock.now();
+    let iter = match HintFile::open_iterator(database_dir, storage_id, now, options) {
+        Ok(iter) => iter,
+        Err(e) => {
+            warn!(target: DEFAULT_LOG_TARGET, "open hint file with id: {} failed. {}", storage_id, e);
+            report.corrupted = true;
+            return report;
+        }
+    };
+    for hint in iter {
+        match hint {
+            Ok(hint) => {
+                report.rows += 1;
+                let location = hint.row_location;
+                if rows.get(&location.row_offset) != Some(&(hint.key, location.row_size)) {
+                    report.mismatched_rows += 1;
+                }
+            }
+            Err(e) => {
+                warn!(target: DEFAULT_LOG_TARGET, "read hint file with id: {} failed. {}", storage_id, e);
+                report.corrupted = true;
+                break;
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::{
+        database::hint::HintWriter,
+        database::{
+            data_storage::{DataStorageReader, DataStorageWriter},
+            RowLocation,
+        },
+        formatter::{BitcaskyFormatter, Formatter, RowToWrite},
+        test_utils::get_temporary_directory_path,
+    };
+    use test_log::test;
+
+    fn write_rows(dir: &Path, storage_id: StorageId, keys: &[&str]) -> Vec<RowLocation> {
+        let options = Arc::new(BitcaskyOptions::default());
+        let mut storage = DataStorage::new(
+            dir,
+            storage_id,
+            Arc::new(BitcaskyFormatter::default()),
+            options,
+        )
+        .unwrap();
+        let locations = keys
+            .iter()
+            .map(|k| {
+                storage
+                    .write_row(&RowToWrite::new(k.as_bytes(), b"value".to_vec()))
+                    .unwrap()
+            })
+            .collect();
+        storage.flush().unwrap();
+        locations
+    }
+
+    fn write_hint_file(dir: &Path, storage_id: StorageId, options: Arc<BitcaskyOptions>) {
+        let mut storage = DataStorage::open(dir, storage_id, options.clone()).unwrap();
+        storage.skip_to_end();
+        let durable_offset = storage.transit_to_readonly().unwrap();
+        HintWriter::write_hint_file(dir, storage_id, durable_offset, options).unwrap();
+    }
+
+    fn write_at(dir: &Path, storage_id: StorageId, offset: usize, bs: &[u8]) {
+        let mut f = fs::open_file(dir, FileType::DataFile, Some(storage_id))
+            .unwrap()
+            .file;
+        f.seek(SeekFrom::Start(offset as u64)).unwrap();
+        f.write_all(bs).unwrap();
+    }
+
+    #[test]
+    fn test_check_healthy_directory() {
+        let dir = get_temporary_directory_path();
+        write_rows(&dir, 1, &["k1", "k2"]);
+        write_rows(&dir, 2, &["k3"]);
+        let options = Arc::new(BitcaskyOptions::default());
+        write_hint_file(&dir, 1, options.clone());
+
+        let report = check_integrity(&dir, options).unwrap();
+        assert!(report.is_healthy());
+        assert_eq!(
+            Some(HintFileReport {
+                rows: 2,
+                mismatched_rows: 0,
+                corrupted: false
+            }),
+            report.data_files[0].hint_file
+        );
+        assert_eq!(None, report.data_files[1].hint_file);
+        assert_eq!(
+            vec![(1, 2), (2, 1)],
+            report
+                .data_files
+                .iter()
+                .map(|f| (f.storage_id, f.rows))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_check_and_repair_torn_tail() {
+        let dir = get_temporary_directory_path();
+        let locations = write_rows(&dir, 1, &["k1", "k2"]);
+        let torn_offset = locations[1].row_offset + locations[1].row_size;
+        // header of a row which key and value were not written
+        let mut header = vec![1; 4];
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&2u64.to_le_bytes());
+        header.extend_from_slice(&5u64.to_le_bytes());
+        write_at(&dir, 1, torn_offset, &header);
+        HintFile::create(&dir, 2, 1024).unwrap();
+
+        let options = Arc::new(BitcaskyOptions::default());
+        let report = check_integrity(&dir, options.clone()).unwrap();
+        assert!(!report.is_healthy());
+        assert_eq!(vec![2], report.orphaned_hint_files);
+        assert_eq!(2, report.data_files[0].rows);
+        assert_eq!(
+            Some(torn_offset as u64),
+            report.data_files[0].first_bad_offset
+        );
+        assert!(report.data_files[0].torn_tail);
+
+        let repair_report = repair(&dir, options.clone()).unwrap();
+        assert_eq!(vec![1], repair_report.truncated_data_files);
+        assert_eq!(vec![2], repair_report.deleted_hint_files);
+        assert_eq!(1, repair_report.dropped_rows);
+        // trailing zeros of the torn write can not be told apart from unused space
+        assert_eq!(header.len() - 7, repair_report.dropped_bytes);
+        assert!(check_integrity(&dir, options.clone()).unwrap().is_healthy());
+
+        let mut storage = DataStorage::open(&dir, 1, options).unwrap();
+        assert_eq!(
+            b"value".to_vec(),
+            storage
+                .read_value(locations[1].row_offset)
+                .unwrap()
+                .unwrap()
+                .value
+        );
+    }
+
+    #[test]
+    fn test_repair_corrupted_middle_row() {
+        let dir = get_temporary_directory_path();
+        let locations = write_rows(&dir, 1, &["k1", "k2", "k3"]);
+        let options = Arc::new(BitcaskyOptions::default());
+        write_hint_file(&dir, 1, options.clone());
+        let formatter = BitcaskyFormatter::default();
+        write_at(
+            &dir,
+            1,
+            locations[1].row_offset + formatter.row_header_size(),
+            b"x",
+        );
+
+        let report = check_integrity(&dir, options.clone()).unwrap();
+        assert_eq!(1, report.data_files[0].rows);
+        assert_eq!(
+            Some(locations[1].row_offset as u64),
+            report.data_files[0].first_bad_offset
+        );
+        assert!(!report.data_files[0].torn_tail);
+        // rows after the corrupted one are not validated
+        assert_eq!(
+            2,
+            report.data_files[0]
+                .hint_file
+                .as_ref()
+                .unwrap()
+                .mismatched_rows
+        );
+
+        let repair_report = repair(&dir, options.clone()).unwrap();
+        assert_eq!(vec![1], repair_report.rewritten_data_files);
+        assert_eq!(vec![1], repair_report.deleted_hint_files);
+        assert_eq!(1, repair_report.dropped_rows);
+        assert_eq!(locations[1].row_size, repair_report.dropped_bytes);
+
+        let report = check_integrity(&dir, options.clone()).unwrap();
+        assert!(report.is_healthy());
+        assert_eq!(2, report.data_files[0].rows);
+        let storage = DataStorage::open(&dir, 1, options).unwrap();
+        assert_eq!(
+            vec![b"k1".to_vec(), b"k3".to_vec()],
+            storage
+                .iter()
+                .unwrap()
+                .map(|r| r.unwrap().key)
+                .collect::<Vec<_>>()
+        );
+        assert!(!dir.join(REPAIR_FILES_DIRECTORY).exists());
+    }
+
+    #[test]
+    fn test_repair_with_lowered_max_data_file_size() {
+        let dir = get_temporary_directory_path();
+        let locations = write_rows(&dir, 1, &["k1", "k2", "k3"]);
+        let formatter = BitcaskyFormatter::default();
+        write_at(
+            &dir,
+            1,
+            locations[1].row_offset + formatter.row_header_size(),
+            b"x",
+        );
+
+        let options = Arc::new(BitcaskyOptions::default().max_data_file_size(FILE_HEADER_SIZE));
+        let repair_report = repair(&dir, options.clone()).unwrap();
+        assert_eq!(vec![1], repair_report.rewritten_data_files);
+        assert_eq!(1, repair_report.dropped_rows);
+        assert!(check_integrity(&dir, options).unwrap().is_healthy());
+    }
+
+    #[test]
+    fn test_repair_skips_data_file_with_unreadable_header() {
+        let dir = get_temporary_directory_path();
+        write_rows(&dir, 1, &["k1"]);
+        let locations = write_rows(&dir, 2, &["k2", "k3"]);
+        let formatter = BitcaskyFormatter::default();
+        write_at(
+            &dir,
+            2,
+            locations[0].row_offset + formatter.row_header_size(),
+            b"x",
+        );
+        write_at(&dir, 1, 0, b"xxxx");
+
+        let repair_report = repair(&dir, Arc::new(BitcaskyOptions::default())).unwrap();
+        assert_eq!(vec![1], repair_report.unreadable_data_files);
+        assert_eq!(vec![2], repair_report.rewritten_data_files);
+        assert!(FileType::DataFile.get_path(&dir, Some(1)).exists());
+    }
+}
@@ -4,10 +4,25 @@ pub use self::core::*;
 mod common;
 pub use self::common::{deleted_value, DatabaseError, RowLocation, TimedValue};
 
+mod erlang_hint;
+mod file_pin;
+pub use self::file_pin::FilePinSet;
 mod hint;
 
+mod stable_storages;
+mod throughput;
+
+mod estimate;
+pub use self::estimate::{estimate_key_count, KeyCountEstimate};
+
+mod integrity;
+pub use self::integrity::{
+    check_integrity, repair, validate, DataFileReport, HintFileReport, IntegrityReport,
+    RepairReport, VerifyReport,
+};
+
 pub mod data_storage;
-pub use self::data_storage::DataStorageError;
+pub use self::data_storage::{DataStorageError, ValueReader};
 
 #[cfg(unix)]
 use libc::O_SYNC;
@@ -19,14 +34,19 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use fail::fail_point;
+
 use crate::formatter::BitcaskyFormatter;
 use crate::fs::FileType;
+use crate::logging::warn;
 use crate::storage_id::StorageId;
 #[cfg(not(unix))]
 use fs4::FileExt;
 
 use crate::formatter::FILE_HEADER_SIZE;
 
+const TEMP_DATA_FILE_PREFIX: &str = "tmp-";
+
 pub fn create_data_file<P: AsRef<Path>>(
     base_dir: P,
     file_type: FileType,
@@ -46,8 +66,8 @@ pub fn create_data_file<P: AsRef<Path>>(
         .expect("File name required");
 
     let tmp_file_path = match path.parent() {
-        Some(parent) => parent.join(format!("tmp-{file_name}")),
-        None => PathBuf::from(format!("tmp-{file_name}")),
+        Some(parent) => parent.join(format!("{TEMP_DATA_FILE_PREFIX}{file_name}")),
+        None => PathBuf::from(format!("{TEMP_DATA_FILE_PREFIX}{file_name}")),
     };
 
     {
@@ -60,7 +80,12 @@ pub fn create_data_file<P: AsRef<Path>>(
 
         crate::fs::truncate_file(&mut file, capacity)?;
 
-        crate::formatter::initialize_new_file(&mut file, formatter.version())?;
+        fail_point!("database::create_data_file::before_header", |_| {
+            Err(std::io::Error::other(
+                "failpoint database::create_data_file::before_header",
+            ))
+        });
+        crate::formatter::initialize_new_file(&mut file, formatter)?;
 
         // Manually sync each file in Windows since sync-ing cannot be done for the whole directory.
         #[cfg(target_os = "windows")]
@@ -85,6 +110,26 @@ pub fn create_data_file<P: AsRef<Path>>(
     Ok(file)
 }
 
+/// Deletes temporary data files left by a crash in `create_data_file` before they were renamed,
+/// among the leftovers found by listing data files. Other leftovers are not ours, they are left
+/// untouched.
+pub fn clear_temp_data_files(leftovers: &[PathBuf]) {
+    for path in leftovers {
+        let is_temp = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with(TEMP_DATA_FILE_PREFIX));
+        if !is_temp || !FileType::DataFile.check_file_belongs_to_type(path) {
+            warn!(target: "Database", "ignore file without storage id in its name: {:?}", path);
+            continue;
+        }
+        match std::fs::remove_file(path) {
+            Ok(_) => warn!(target: "Database", "deleted temp data file: {:?}", path),
+            Err(e) => warn!(target: "Database", "delete temp data file: {:?} failed. {}", path, e),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{Read, Write};
@@ -0,0 +1,159 @@
+//! Stable storages of a database by storage id. A sharded concurrent map by default, or a plain
+//! map behind a lock with the `small-footprint` feature, which saves the memory of the shards.
+//!
+//! With `max_open_files`, data files of storages not read recently are closed when more files
+//! are open, and reopened on next read.
+
+use std::ops::Deref;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use parking_lot::Mutex;
+
+use crate::{lock_stats::TimedMutex, storage_id::StorageId};
+
+use super::data_storage::DataStorage;
+
+type StableStorage = TimedMutex<DataStorage>;
+
+#[cfg(not(feature = "small-footprint"))]
+#[derive(Debug, Default)]
+pub(crate) struct StableStorages {
+    storages: dashmap::DashMap<StorageId, StableStorage>,
+    open_files: Arc<AtomicUsize>,
+    max_open_files: Option<usize>,
+    closing: Mutex<()>,
+}
+
+#[cfg(not(feature = "small-footprint"))]
+impl StableStorages {
+    pub fn get(&self, storage_id: &StorageId) -> Option<impl Deref<Target = StableStorage> + '_> {
+        self.storages.get(storage_id)
+    }
+
+    pub fn insert(&self, storage_id: StorageId, storage: StableStorage) {
+        storage.lock().count_open_files(self.open_files.clone());
+        self.storages.insert(storage_id, storage);
+    }
+
+    pub fn contains_key(&self, storage_id: &StorageId) -> bool {
+        self.storages.contains_key(storage_id)
+    }
+
+    pub fn storage_ids(&self) -> Vec<StorageId> {
+        self.storages.iter().map(|s| *s.key()).collect()
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&StableStorage)) {
+        self.storages.iter().for_each(|s| f(s.value()));
+    }
+
+    pub fn clear(&self) {
+        self.storages.clear();
+        self.open_files.store(0, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.storages.len()
+    }
+}
+
+#[cfg(feature = "small-footprint")]
+#[derive(Debug, Default)]
+pub(crate) struct StableStorages {
+    storages: parking_lot::RwLock<std::collections::HashMap<StorageId, StableStorage>>,
+    open_files: Arc<AtomicUsize>,
+    max_open_files: Option<usize>,
+    closing: Mutex<()>,
+}
+
+#[cfg(feature = "small-footprint")]
+impl StableStorages {
+    pub fn get(&self, storage_id: &StorageId) -> Option<impl Deref<Target = StableStorage> + '_> {
+        parking_lot::RwLockReadGuard::try_map(self.storages.read(), |m| m.get(storage_id)).ok()
+    }
+
+    pub fn insert(&self, storage_id: StorageId, storage: StableStorage) {
+        storage.lock().count_open_files(self.open_files.clone());
+        self.storages.write().insert(storage_id, storage);
+    }
+
+    pub fn contains_key(&self, storage_id: &StorageId) -> bool {
+        self.storages.read().contains_key(storage_id)
+    }
+
+    pub fn storage_ids(&self) -> Vec<StorageId> {
+        self.storages.read().keys().copied().collect()
+    }
+
+    pub fn for_each(&self, f: impl FnMut(&StableStorage)) {
+        self.storages.read().values().for_each(f);
+    }
+
+    pub fn clear(&self) {
+        self.storages.write().clear();
+        self.open_files.store(0, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.storages.read().len()
+    }
+}
+
+impl StableStorages {
+    pub fn new(max_open_files: Option<usize>) -> StableStorages {
+        StableStorages {
+            max_open_files,
+            ..Default::default()
+        }
+    }
+
+    /// How many data files of stable storages are open
+    pub fn open_files(&self) -> usize {
+        self.open_files.load(Ordering::Relaxed)
+    }
+
+    /// Closes data files when more than `max_open_files` are open, down to three quarters of
+    /// it, so files are closed in batches instead of one on every reopen. Files not read since
+    /// last time are closed first, like a clock approximating LRU. Files in use are skipped, so
+    /// the limit can be exceeded by files read concurrently.
+    pub fn close_files_over_limit(&self) {
+        let Some(max_open_files) = self.max_open_files else {
+            return;
+        };
+        if self.open_files() <= max_open_files {
+            return;
+        }
+        let Some(_closing) = self.closing.try_lock() else {
+            return;
+        };
+        let target = max_open_files - max_open_files / 4;
+        // the first round only clears access marks of storages read since last time
+        for _ in 0..2 {
+            self.for_each(|s| {
+                if self.open_files() <= target {
+                    return;
+                }
+                if let Some(mut storage) = s.try_lock() {
+                    if !storage.take_accessed() {
+                        storage.close_file();
+                    }
+                }
+            });
+            if self.open_files() <= target {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl StableStorages {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
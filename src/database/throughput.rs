@@ -0,0 +1,78 @@
+use parking_lot::Mutex;
+
+/// Bytes written are measured over windows of at least this long
+const WINDOW_MILLIS: u64 = 1000;
+/// A window longer than this had no write for most of it, so it's not measured
+const IDLE_MILLIS: u64 = 10 * WINDOW_MILLIS;
+/// Weight of the latest window in the moving average
+const SMOOTHING: f64 = 0.2;
+
+#[derive(Debug, Default)]
+struct MeterState {
+    window_start: u64,
+    window_bytes: u64,
+    bytes_per_sec: Option<f64>,
+}
+
+/// Exponential moving average of bytes written per second, updated by writes
+#[derive(Debug, Default)]
+pub(crate) struct ThroughputMeter {
+    state: Mutex<MeterState>,
+}
+
+impl ThroughputMeter {
+    /// Records bytes written at `now`, in milliseconds since epoch
+    pub fn record(&self, bytes: u64, now: u64) {
+        let mut state = self.state.lock();
+        let elapsed = now.saturating_sub(state.window_start);
+        if elapsed >= IDLE_MILLIS {
+            state.window_start = now;
+            state.window_bytes = bytes;
+            return;
+        }
+        state.window_bytes += bytes;
+        if elapsed >= WINDOW_MILLIS {
+            let rate = state.window_bytes as f64 * 1000.0 / elapsed as f64;
+            state.bytes_per_sec = Some(match state.bytes_per_sec {
+                Some(avg) => avg + SMOOTHING * (rate - avg),
+                None => rate,
+            });
+            state.window_start = now;
+            state.window_bytes = 0;
+        }
+    }
+
+    /// Returns None until a whole window is measured
+    pub fn bytes_per_sec(&self) -> Option<f64> {
+        self.state.lock().bytes_per_sec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn test_measure_throughput() {
+        let meter = ThroughputMeter::default();
+        meter.record(100, 10_000);
+        meter.record(100, 10_500);
+        assert_eq!(None, meter.bytes_per_sec());
+
+        // 2000 bytes in 2 seconds
+        meter.record(1800, 12_000);
+        assert_eq!(Some(1000.0), meter.bytes_per_sec());
+
+        // 3000 bytes/s in the next window moves the average by a fifth of the difference
+        meter.record(3000, 13_000);
+        assert_eq!(Some(1400.0), meter.bytes_per_sec());
+
+        // idle for a long time, restarts the window without measuring
+        meter.record(10, 100_000);
+        assert_eq!(Some(1400.0), meter.bytes_per_sec());
+        meter.record(1390, 101_000);
+        assert_eq!(Some(1400.0), meter.bytes_per_sec());
+    }
+}
@@ -17,10 +17,28 @@ pub enum BitcaskyError {
     MergeFileDirectoryNotEmpty(String),
     #[error("Another merge is in progress")]
     MergeInProgress(),
+    #[error("Merge is cancelled")]
+    MergeCancelled(),
+    #[error("Database is in read only mode")]
+    ReadOnlyMode(),
+    #[error("Merge requires {0} bytes of free space, but only {1} bytes are available")]
+    InsufficientFreeSpace(u64, u64),
+    #[error("Data file with id {0} has no file header. It's left by a crash while creating it and holds no row, so it can be deleted")]
+    IncompleteDataFile(u32),
+    #[error("Data file with id {0} is corrupted at offset {1}")]
+    DataFileCorrupted(u32, u64),
     #[error("Invalid file id {0} in MergeMeta file. Min file ids in Merge directory is {1}")]
     InvalidMergeDataFile(u32, u32),
+    #[error("Location got at generation {0} is stale. Current location generation is {1}")]
+    StaleLocation(u64, u64),
     #[error("Lock directory: {0} failed. Maybe there's another process is using this directory")]
     LockDirectoryFailed(String),
+    #[error("Invalid CSV data at line {0}: {1}")]
+    InvalidCsvData(u64, String),
+    #[error("Invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("Invalid key encoding: {0}")]
+    InvalidKeyEncoding(String),
     #[error(transparent)]
     DatabaseError(#[from] DatabaseError),
 }
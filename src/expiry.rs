@@ -0,0 +1,172 @@
+//! Sweeps keys with expired values out of keydir. Reads already treat expired values as absent,
+//! but their keys stay in keydir until merge if they are never written again, which takes
+//! memory and is counted by `count_keys` and telemetry.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::database::{deleted_value, Database, RowLocation};
+use crate::error::BitcaskyResult;
+use crate::keydir::KeyDir;
+use crate::lock_stats::TimedRwLock;
+use crate::logging::{debug, error};
+use crate::maintenance::PeriodicTask;
+use crate::mutation_trace::MutationCause;
+use crate::options::BitcaskyOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExpirySweeperTelemetry {
+    /// Whether sweeping in background is enabled by options
+    pub enabled: bool,
+    pub paused: bool,
+    /// Keys removed from keydir because their values expired, by sweeps in background or
+    /// called explicitly
+    pub purged_keys: u64,
+}
+
+#[derive(Debug, Default)]
+struct SweepState {
+    paused: AtomicBool,
+    purged_keys: AtomicU64,
+}
+
+#[derive(Debug)]
+pub(crate) struct ExpirySweeper {
+    state: Arc<SweepState>,
+    options: Arc<BitcaskyOptions>,
+    task: Option<PeriodicTask>,
+}
+
+impl ExpirySweeper {
+    pub fn new(options: Arc<BitcaskyOptions>) -> ExpirySweeper {
+        ExpirySweeper {
+            state: Arc::new(SweepState::default()),
+            options,
+            task: None,
+        }
+    }
+
+    /// Sweeps at the interval on the maintenance pool. The task only keeps weak references, so
+    /// it does nothing after the database is closed.
+    pub fn start(
+        &mut self,
+        database: &Arc<Database>,
+        keydir: &Arc<TimedRwLock<KeyDir>>,
+        interval: Duration,
+    ) {
+        let state = self.state.clone();
+        let weak_database = Arc::downgrade(database);
+        let keydir = Arc::downgrade(keydir);
+        let options = self.options.clone();
+        self.task = Some(database.maintenance_queue().schedule(interval, move || {
+            let (Some(database), Some(keydir)) = (weak_database.upgrade(), keydir.upgrade()) else {
+                return;
+            };
+            if state.paused.load(Ordering::Acquire)
+                || database.check_db_error().is_err()
+                || database.is_read_only()
+            {
+                return;
+            }
+            match sweep(&database, &keydir, &options) {
+                Ok(purged) => {
+                    state
+                        .purged_keys
+                        .fetch_add(purged as u64, Ordering::Relaxed);
+                }
+                Err(e) => error!(target: "Bitcasky", "sweep expired keys failed with error: {}", e),
+            }
+        }));
+    }
+
+    /// Stops sweeping in background, after waiting for the running sweep if any
+    pub fn stop(&mut self) {
+        self.task.take();
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.state.paused.store(paused, Ordering::Release);
+    }
+
+    /// Sweeps once regardless of whether sweeping in background is paused
+    pub fn sweep(
+        &self,
+        database: &Database,
+        keydir: &TimedRwLock<KeyDir>,
+    ) -> BitcaskyResult<usize> {
+        let purged = sweep(database, keydir, &self.options)?;
+        self.state
+            .purged_keys
+            .fetch_add(purged as u64, Ordering::Relaxed);
+        Ok(purged)
+    }
+
+    pub fn get_telemetry_data(&self) -> ExpirySweeperTelemetry {
+        ExpirySweeperTelemetry {
+            enabled: self.task.is_some(),
+            paused: self.state.paused.load(Ordering::Acquire),
+            purged_keys: self.state.purged_keys.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Removes keys with expired values from keydir and returns how many are removed. Keys are
+/// checked in chunks of `expiry_sweep_chunk_size` under the read lock of keydir, then expired
+/// keys of the chunk are removed under the write lock. Only the expire timestamp in row headers
+/// is read to check keys, not their values. A key written after it's checked points to another
+/// row, so it's kept.
+fn sweep(
+    database: &Database,
+    keydir: &TimedRwLock<KeyDir>,
+    options: &BitcaskyOptions,
+) -> BitcaskyResult<usize> {
+    let entries = keydir
+        .read()
+        .iter()
+        .map(|(k, location)| (k.clone(), *location))
+        .collect::<Vec<(Vec<u8>, RowLocation)>>();
+    let mut purged = 0;
+    for chunk in entries.chunks(options.expiry_sweep_chunk_size) {
+        let mut expired = vec![];
+        {
+            // hold keydir so the locations are not changed by merge during reading
+            let kd = keydir.read();
+            let now = options.clock.now();
+            for (k, location) in chunk {
+                if kd.get(k).as_ref() != Some(location) {
+                    continue;
+                }
+                let expire_timestamp = database.read_expire_timestamp(location)?;
+                if expire_timestamp != 0 && expire_timestamp <= now {
+                    expired.push((k, location));
+                }
+            }
+        }
+        if expired.is_empty() {
+            continue;
+        }
+
+        let mut kd = keydir.write();
+        let now = options.clock.now();
+        for (k, location) in expired {
+            if kd.get(k).as_ref() != Some(location) {
+                continue;
+            }
+            if options.expiry_sweep_write_tombstones {
+                let delete_location =
+                    database.write(k, deleted_value().with_write_timestamp(now))?;
+                database.add_dead_bytes(delete_location.storage_id, delete_location.row_size);
+            }
+            kd.delete(k, MutationCause::TtlSweep);
+            database.add_dead_bytes(location.storage_id, location.row_size);
+            purged += 1;
+        }
+    }
+    debug!(target: "Bitcasky", "swept {} expired keys out of {} keys", purged, entries.len());
+    Ok(purged)
+}
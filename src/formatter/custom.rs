@@ -0,0 +1,79 @@
+use std::fmt::Debug;
+
+use parking_lot::RwLock;
+
+use super::{FormatterError, Result, RowHeader, RowToWrite};
+
+/// Formatter versions below this are reserved for formatters shipped with bitcasky
+pub const MIN_CUSTOM_FORMATTER_VERSION: u8 = 128;
+
+/// Custom formatters registered in this process, looked up by the version in file header
+static ROW_FORMATTERS: RwLock<Vec<&'static dyn RowFormatter>> = RwLock::new(Vec::new());
+
+/// Encodes and decodes rows of data files.
+///
+/// A row is laid out as a header of `row_header_size` bytes followed by the key and the value.
+/// The formatter owns the header, which must at least keep the expire timestamp, key size and
+/// value size of the row, and decides how the row is checksummed. Rows decoded without their
+/// write timestamp are taken as older than any row written by `Bitcasky::put_with_timestamp`.
+///
+/// Data files record the version of their formatter in file header. Files written by different
+/// formatters can live in the same directory as long as all of their formatters are registered.
+///
+/// Stability: bitcasky only promises to keep its own formatters readable across releases.
+/// Keeping a custom format readable is on its author: a formatter must never change its
+/// encoding under the same version, and must stay registered as long as any data file written
+/// by it exists, including files written before a merge finishes. Hint files and merge meta
+/// files are always written by the builtin formatter.
+pub trait RowFormatter: Debug + Send + Sync {
+    /// Recorded in the header of data files written by this formatter. Must be unique among
+    /// registered formatters and not less than `MIN_CUSTOM_FORMATTER_VERSION`
+    fn version(&self) -> u8;
+
+    fn row_header_size(&self) -> usize;
+
+    /// Writes the header, key and value of the row to `output`, returns bytes written which
+    /// must be `row_header_size` plus the size of key and value
+    fn encode_row(&self, row: &RowToWrite<&[u8], &[u8]>, output: &mut [u8]) -> usize;
+
+    fn decode_row_header(&self, bs: &[u8]) -> RowHeader;
+
+    /// Checks the key and value bytes right after the header against the decoded header
+    fn validate_key_value(&self, header: &RowHeader, kv: &[u8]) -> Result<()>;
+}
+
+/// Registers a formatter so data files written by it can be opened. Registering the same
+/// formatter again does nothing.
+pub fn register_row_formatter(formatter: &'static dyn RowFormatter) -> Result<()> {
+    let version = formatter.version();
+    if version < MIN_CUSTOM_FORMATTER_VERSION {
+        return Err(FormatterError::ReservedFormatterVersion(version));
+    }
+    let mut formatters = ROW_FORMATTERS.write();
+    if let Some(registered) = formatters.iter().find(|f| f.version() == version) {
+        if std::ptr::addr_eq(*registered, formatter) {
+            return Ok(());
+        }
+        return Err(FormatterError::FormatterVersionConflict(version));
+    }
+    formatters.push(formatter);
+    Ok(())
+}
+
+pub(crate) fn find_row_formatter(version: u8) -> Option<&'static dyn RowFormatter> {
+    ROW_FORMATTERS
+        .read()
+        .iter()
+        .find(|f| f.version() == version)
+        .copied()
+}
+
+/// Custom formatter in place of a builtin formatter
+#[derive(Clone, Copy, Debug)]
+pub struct CustomFormatter(pub(crate) &'static dyn RowFormatter);
+
+impl PartialEq for CustomFormatter {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.version() == other.0.version()
+    }
+}
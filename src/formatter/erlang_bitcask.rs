@@ -0,0 +1,262 @@
+//! Layout of data files and hint files written by the original Erlang bitcask. Files have no
+//! header and all the integers are big endian.
+//!
+//! A data file entry is `crc | timestamp | key size | value size | key | value` in 4, 4, 2 and
+//! 4 bytes. The crc covers everything after it. Deleted keys are written with a value starting
+//! with `bitcask_tombstone`.
+//!
+//! A hint file entry is `timestamp | key size | entry size | tombstone | offset | key` in 4, 2,
+//! 4 bytes, 1 bit and 63 bits. The last record of a hint file keeps the crc of all the entries
+//! before it in the entry size field, with zero timestamp and key size and max offset.
+
+use byteorder::{BigEndian, ByteOrder};
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+#[cfg(test)]
+use super::RowHint;
+use super::{FormatterError, Result, RowHeader, RowHintHeader, RowMeta};
+
+const CRC_SIZE: usize = 4;
+const TSTAMP_SIZE: usize = 4;
+const KEY_SIZE_SIZE: usize = 2;
+const VALUE_SIZE_SIZE: usize = 4;
+const ROW_SIZE_SIZE: usize = 4;
+const ROW_OFFSET_SIZE: usize = 8;
+const DATA_FILE_KEY_SIZE_OFFSET: usize = CRC_SIZE + TSTAMP_SIZE;
+const DATA_FILE_VALUE_SIZE_OFFSET: usize = DATA_FILE_KEY_SIZE_OFFSET + KEY_SIZE_SIZE;
+const DATA_FILE_KEY_OFFSET: usize = DATA_FILE_VALUE_SIZE_OFFSET + VALUE_SIZE_SIZE;
+const HINT_FILE_KEY_SIZE_OFFSET: usize = TSTAMP_SIZE;
+const HINT_FILE_ROW_SIZE_OFFSET: usize = HINT_FILE_KEY_SIZE_OFFSET + KEY_SIZE_SIZE;
+const HINT_FILE_ROW_OFFSET_OFFSET: usize = HINT_FILE_ROW_SIZE_OFFSET + ROW_SIZE_SIZE;
+const HINT_FILE_KEY_OFFSET: usize = HINT_FILE_ROW_OFFSET_OFFSET + ROW_OFFSET_SIZE;
+
+const TOMBSTONE_BIT: u64 = 1 << 63;
+const MAX_ROW_OFFSET: u64 = TOMBSTONE_BIT - 1;
+const TOMBSTONE_PREFIX: &[u8] = b"bitcask_tombstone";
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Decodes the files of the original Erlang bitcask. Timestamps in them are the time rows were
+/// written in seconds instead of expire time, so rows decoded by it never expire.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ErlangBitcaskFormatter {}
+
+impl ErlangBitcaskFormatter {
+    pub fn row_header_size(&self) -> usize {
+        DATA_FILE_KEY_OFFSET
+    }
+
+    pub fn decode_row_header(&self, bs: &[u8]) -> RowHeader {
+        RowHeader {
+            crc: BigEndian::read_u32(bs),
+            meta: RowMeta {
+                expire_timestamp: 0,
+                write_timestamp: BigEndian::read_u32(&bs[CRC_SIZE..]) as u64 * 1000,
+                key_size: BigEndian::read_u16(&bs[DATA_FILE_KEY_SIZE_OFFSET..]) as usize,
+                value_size: BigEndian::read_u32(&bs[DATA_FILE_VALUE_SIZE_OFFSET..]) as usize,
+            },
+        }
+    }
+
+    /// Validates a whole data file entry including its header, key and value
+    pub fn validate_row(&self, row_bs: &[u8]) -> Result<()> {
+        let expected_crc = BigEndian::read_u32(row_bs);
+        let actual_crc = CRC32.checksum(&row_bs[CRC_SIZE..]);
+        if expected_crc != actual_crc {
+            return Err(FormatterError::CrcCheckFailed {
+                expected_crc,
+                actual_crc,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes a data file entry to `output` and returns bytes written
+    #[cfg(test)]
+    pub fn encode_row(&self, key: &[u8], value: &[u8], timestamp: u32, output: &mut [u8]) -> usize {
+        let key_end = DATA_FILE_KEY_OFFSET + key.len();
+        let row_end = key_end + value.len();
+        BigEndian::write_u32(&mut output[CRC_SIZE..], timestamp);
+        BigEndian::write_u16(&mut output[DATA_FILE_KEY_SIZE_OFFSET..], key.len() as u16);
+        BigEndian::write_u32(
+            &mut output[DATA_FILE_VALUE_SIZE_OFFSET..],
+            value.len() as u32,
+        );
+        output[DATA_FILE_KEY_OFFSET..key_end].copy_from_slice(key);
+        output[key_end..row_end].copy_from_slice(value);
+        let crc = CRC32.checksum(&output[CRC_SIZE..row_end]);
+        BigEndian::write_u32(output, crc);
+        row_end
+    }
+
+    pub fn row_hint_header_size(&self) -> usize {
+        HINT_FILE_KEY_OFFSET
+    }
+
+    /// Returns the header of a hint file entry and whether the entry is a tombstone
+    pub fn decode_row_hint_header(&self, header_bs: &[u8]) -> (RowHintHeader, bool) {
+        let offset_field = BigEndian::read_u64(&header_bs[HINT_FILE_ROW_OFFSET_OFFSET..]);
+        (
+            RowHintHeader {
+                expire_timestamp: 0,
+                key_size: BigEndian::read_u16(&header_bs[HINT_FILE_KEY_SIZE_OFFSET..]) as usize,
+                row_offset: (offset_field & MAX_ROW_OFFSET) as usize,
+                row_size: BigEndian::read_u32(&header_bs[HINT_FILE_ROW_SIZE_OFFSET..]) as usize,
+            },
+            offset_field & TOMBSTONE_BIT != 0,
+        )
+    }
+
+    /// Writes a hint file entry to `output` and returns bytes written
+    #[cfg(test)]
+    pub fn encode_row_hint(
+        &self,
+        hint: &RowHint,
+        tombstone: bool,
+        timestamp: u32,
+        output: &mut [u8],
+    ) -> usize {
+        let mut offset_field = hint.header.row_offset as u64 & MAX_ROW_OFFSET;
+        if tombstone {
+            offset_field |= TOMBSTONE_BIT;
+        }
+        BigEndian::write_u32(output, timestamp);
+        BigEndian::write_u16(
+            &mut output[HINT_FILE_KEY_SIZE_OFFSET..],
+            hint.key.len() as u16,
+        );
+        BigEndian::write_u32(
+            &mut output[HINT_FILE_ROW_SIZE_OFFSET..],
+            hint.header.row_size as u32,
+        );
+        BigEndian::write_u64(&mut output[HINT_FILE_ROW_OFFSET_OFFSET..], offset_field);
+        let key_end = HINT_FILE_KEY_OFFSET + hint.key.len();
+        output[HINT_FILE_KEY_OFFSET..key_end].copy_from_slice(&hint.key);
+        key_end
+    }
+
+    /// Returns the crc kept in the header if it is the last record of a hint file
+    pub fn decode_hint_crc_record(&self, header_bs: &[u8]) -> Option<u32> {
+        let is_crc_record = BigEndian::read_u32(header_bs) == 0
+            && BigEndian::read_u16(&header_bs[HINT_FILE_KEY_SIZE_OFFSET..]) == 0
+            && BigEndian::read_u64(&header_bs[HINT_FILE_ROW_OFFSET_OFFSET..]) == MAX_ROW_OFFSET;
+        is_crc_record.then(|| BigEndian::read_u32(&header_bs[HINT_FILE_ROW_SIZE_OFFSET..]))
+    }
+
+    /// Writes the last record of a hint file with the crc of `entries_bs` to `output`
+    #[cfg(test)]
+    pub fn encode_hint_crc_record(&self, entries_bs: &[u8], output: &mut [u8]) -> usize {
+        output[..HINT_FILE_ROW_SIZE_OFFSET].fill(0);
+        BigEndian::write_u32(
+            &mut output[HINT_FILE_ROW_SIZE_OFFSET..],
+            self.hint_entries_crc(entries_bs),
+        );
+        BigEndian::write_u64(&mut output[HINT_FILE_ROW_OFFSET_OFFSET..], MAX_ROW_OFFSET);
+        HINT_FILE_KEY_OFFSET
+    }
+
+    pub fn hint_entries_crc(&self, entries_bs: &[u8]) -> u32 {
+        CRC32.checksum(entries_bs)
+    }
+
+    /// Erlang bitcask appends the id of the data file to tombstones since version 2
+    pub fn is_tombstone(&self, value: &[u8]) -> bool {
+        value.starts_with(TOMBSTONE_PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn test_encode_decode_row() {
+        let formatter = ErlangBitcaskFormatter::default();
+        let mut bs = vec![0; 64];
+        let size = formatter.encode_row(b"key", b"value", 1700000000, &mut bs);
+        assert_eq!(formatter.row_header_size() + 8, size);
+
+        let header = formatter.decode_row_header(&bs);
+        assert_eq!(
+            RowMeta {
+                expire_timestamp: 0,
+                write_timestamp: 1700000000000,
+                key_size: 3,
+                value_size: 5,
+            },
+            header.meta
+        );
+        assert_eq!(b"keyvalue", &bs[formatter.row_header_size()..size]);
+        formatter.validate_row(&bs[..size]).unwrap();
+
+        bs[size - 1] = b'x';
+        assert!(matches!(
+            formatter.validate_row(&bs[..size]),
+            Err(FormatterError::CrcCheckFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_row_crc_is_crc32_of_erlang() {
+        let formatter = ErlangBitcaskFormatter::default();
+        let mut bs = vec![0; 32];
+        let size = formatter.encode_row(b"k", b"v", 0, &mut bs);
+        // erlang:crc32(<<0:32, 1:16, 1:32, "k", "v">>)
+        assert_eq!(
+            CRC32.checksum(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 1, b'k', b'v']),
+            BigEndian::read_u32(&bs)
+        );
+        assert_eq!(16, size);
+        assert_eq!(
+            0x414fa339,
+            CRC32.checksum(b"The quick brown fox jumps over the lazy dog")
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_row_hint() {
+        let formatter = ErlangBitcaskFormatter::default();
+        let hint = RowHint {
+            header: RowHintHeader {
+                expire_timestamp: 0,
+                key_size: 3,
+                row_offset: 1024,
+                row_size: 22,
+            },
+            key: b"key".to_vec(),
+        };
+        let mut bs = vec![0; 64];
+        for tombstone in [false, true] {
+            let size = formatter.encode_row_hint(&hint, tombstone, 1700000000, &mut bs);
+            assert_eq!(formatter.row_hint_header_size() + 3, size);
+            assert_eq!(
+                (hint.header.clone(), tombstone),
+                formatter.decode_row_hint_header(&bs)
+            );
+            assert!(formatter.decode_hint_crc_record(&bs).is_none());
+        }
+    }
+
+    #[test]
+    fn test_hint_crc_record() {
+        let formatter = ErlangBitcaskFormatter::default();
+        let entries = b"some hint entries";
+        let mut bs = vec![0; formatter.row_hint_header_size()];
+        formatter.encode_hint_crc_record(entries, &mut bs);
+        assert_eq!(
+            Some(formatter.hint_entries_crc(entries)),
+            formatter.decode_hint_crc_record(&bs)
+        );
+    }
+
+    #[test]
+    fn test_tombstone() {
+        let formatter = ErlangBitcaskFormatter::default();
+        assert!(formatter.is_tombstone(b"bitcask_tombstone"));
+        assert!(formatter.is_tombstone(b"bitcask_tombstone2\0\0\0\x01"));
+        assert!(!formatter.is_tombstone(b"bitcask_tomb"));
+        assert!(!formatter.is_tombstone(b"value"));
+    }
+}
@@ -1,7 +1,7 @@
 use std::{ops::Deref, ptr};
 
 use byteorder::{ByteOrder, LittleEndian};
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use crc::{Crc, CRC_32_CKSUM};
 
 use super::{
@@ -25,7 +25,8 @@ const HINT_FILE_ROW_SIZE_OFFSET: usize = HINT_FILE_ROW_OFFSET_OFFSET + ROW_OFFSE
 const HINT_FILE_KEY_OFFSET: usize = HINT_FILE_ROW_SIZE_OFFSET + ROW_SIZE_SIZE;
 const HINT_FILE_HEADER_SIZE: usize = TSTAMP_SIZE + KEY_SIZE_SIZE + ROW_OFFSET_SIZE + ROW_SIZE_SIZE;
 
-const MERGE_META_FILE_SIZE: usize = 4;
+const STORAGE_ID_SIZE: usize = 4;
+const MERGE_META_FILE_SIZE: usize = STORAGE_ID_SIZE;
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct FormatterV1 {}
@@ -70,7 +71,9 @@ impl Formatter for FormatterV1 {
         row: &RowToWrite<K, V>,
         bs: &mut [u8],
     ) -> usize {
-        let crc = self.gen_crc(&row.meta, row.key.as_ref(), &row.value);
+        let crc = row
+            .custom_crc
+            .unwrap_or_else(|| self.gen_crc(&row.meta, row.key.as_ref(), &row.value));
         LittleEndian::write_u32(bs, crc);
         LittleEndian::write_u64(&mut bs[4..], row.meta.expire_timestamp);
         LittleEndian::write_u64(&mut bs[12..], row.meta.key_size as u64);
@@ -94,6 +97,7 @@ impl Formatter for FormatterV1 {
             crc: expected_crc,
             meta: RowMeta {
                 expire_timestamp: timestamp,
+                write_timestamp: 0,
                 key_size,
                 value_size: val_size,
             },
@@ -155,18 +159,58 @@ impl Formatter for FormatterV1 {
         }
     }
 
+    fn validate_row_hint(&self, _: &[u8]) -> Result<()> {
+        // hint rows have no checksum in this version
+        Ok(())
+    }
+
     fn merge_meta_size(&self) -> usize {
         MERGE_META_FILE_SIZE
     }
 
     fn encode_merge_meta(&self, meta: &super::MergeMeta) -> Bytes {
-        Bytes::copy_from_slice(&meta.known_max_storage_id.to_be_bytes())
+        let mut bs = BytesMut::with_capacity(
+            MERGE_META_FILE_SIZE
+                + (meta.source_storage_ids.len() + meta.retained_storage_ids.len() + 2)
+                    * STORAGE_ID_SIZE,
+        );
+        bs.put_u32(meta.known_max_storage_id);
+        // merge meta written by full merge only has known max storage id
+        if !meta.source_storage_ids.is_empty() || !meta.retained_storage_ids.is_empty() {
+            bs.put_u32(meta.source_storage_ids.len() as u32);
+            meta.source_storage_ids
+                .iter()
+                .for_each(|id| bs.put_u32(*id));
+        }
+        if !meta.retained_storage_ids.is_empty() {
+            bs.put_u32(meta.retained_storage_ids.len() as u32);
+            meta.retained_storage_ids
+                .iter()
+                .for_each(|id| bs.put_u32(*id));
+        }
+        bs.freeze()
     }
 
     fn decode_merge_meta(&self, mut meta: Bytes) -> MergeMeta {
         let known_max_storage_id = meta.get_u32();
+        let mut source_storage_ids = vec![];
+        if meta.remaining() >= STORAGE_ID_SIZE {
+            let len = meta.get_u32() as usize;
+            for _ in 0..len {
+                source_storage_ids.push(meta.get_u32());
+            }
+        }
+        let mut retained_storage_ids = vec![];
+        if meta.remaining() >= STORAGE_ID_SIZE {
+            let len = meta.get_u32() as usize;
+            for _ in 0..len {
+                retained_storage_ids.push(meta.get_u32());
+            }
+        }
         MergeMeta {
             known_max_storage_id,
+            source_storage_ids,
+            retained_storage_ids,
         }
     }
 }
@@ -191,6 +235,8 @@ mod tests {
     fn test_encode_decode_merge_meta() {
         let merge_meta = MergeMeta {
             known_max_storage_id: 123,
+            source_storage_ids: vec![],
+            retained_storage_ids: vec![],
         };
 
         let formatter = FormatterV1 {};
@@ -199,6 +245,38 @@ mod tests {
         assert_eq!(merge_meta, formatter.decode_merge_meta(bytes));
     }
 
+    #[test]
+    fn test_encode_decode_partial_merge_meta() {
+        let merge_meta = MergeMeta {
+            known_max_storage_id: 123,
+            source_storage_ids: vec![3, 5],
+            retained_storage_ids: vec![],
+        };
+
+        let formatter = FormatterV1 {};
+        let bytes = formatter.encode_merge_meta(&merge_meta);
+        assert_eq!(merge_meta, formatter.decode_merge_meta(bytes));
+        assert!(merge_meta.is_merge_source(3));
+        assert!(!merge_meta.is_merge_source(4));
+    }
+
+    #[test]
+    fn test_encode_decode_merge_meta_with_retained_files() {
+        let formatter = FormatterV1 {};
+        for source_storage_ids in [vec![], vec![3, 5]] {
+            let merge_meta = MergeMeta {
+                known_max_storage_id: 123,
+                source_storage_ids,
+                retained_storage_ids: vec![3, 7],
+            };
+            let bytes = formatter.encode_merge_meta(&merge_meta);
+            assert_eq!(merge_meta, formatter.decode_merge_meta(bytes));
+            assert!(!merge_meta.is_merge_source(3));
+            assert!(!merge_meta.is_merge_source(7));
+            assert!(merge_meta.is_merge_source(5));
+        }
+    }
+
     #[test]
     fn test_encode_decode_row_hint() {
         let k = b"Hello".to_vec();
@@ -225,11 +303,13 @@ mod tests {
         let row = RowToWrite {
             meta: RowMeta {
                 expire_timestamp: 12345,
+                write_timestamp: 0,
                 key_size: k.len(),
                 value_size: v.len(),
             },
             key: k,
             value: v,
+            custom_crc: None,
         };
 
         let formatter = FormatterV1 {};
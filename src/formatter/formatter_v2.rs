@@ -0,0 +1,129 @@
+use std::ops::Deref;
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
+use crc::{Crc, CRC_32_CKSUM};
+
+use super::{
+    Formatter, FormatterError, FormatterV1, MergeMeta, Result, RowHeader, RowHint, RowHintHeader,
+    RowToWrite,
+};
+
+const CRC_SIZE: usize = 4;
+
+/// Same as [`FormatterV1`] except that every hint row is prefixed with a crc
+/// of the hint header and key.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FormatterV2 {
+    v1: FormatterV1,
+}
+
+impl FormatterV2 {
+    fn gen_hint_crc(&self, hint_bs: &[u8]) -> u32 {
+        let crc32 = Crc::<u32>::new(&CRC_32_CKSUM);
+        crc32.checksum(hint_bs)
+    }
+}
+
+impl Formatter for FormatterV2 {
+    fn row_header_size(&self) -> usize {
+        self.v1.row_header_size()
+    }
+
+    fn net_row_size<K: AsRef<[u8]>, V: Deref<Target = [u8]>>(
+        &self,
+        row: &RowToWrite<K, V>,
+    ) -> usize {
+        self.v1.net_row_size(row)
+    }
+
+    fn encode_row<K: AsRef<[u8]>, V: Deref<Target = [u8]>>(
+        &self,
+        row: &RowToWrite<K, V>,
+        output: &mut [u8],
+    ) -> usize {
+        self.v1.encode_row(row, output)
+    }
+
+    fn decode_row_header(&self, bs: &[u8]) -> RowHeader {
+        self.v1.decode_row_header(bs)
+    }
+
+    fn validate_key_value(&self, header: &RowHeader, kv: &[u8]) -> Result<()> {
+        self.v1.validate_key_value(header, kv)
+    }
+
+    fn encode_row_hint(&self, hint: &RowHint, output: &mut [u8]) -> usize {
+        let size = self.v1.encode_row_hint(hint, &mut output[CRC_SIZE..]);
+        let crc = self.gen_hint_crc(&output[CRC_SIZE..CRC_SIZE + size]);
+        LittleEndian::write_u32(output, crc);
+        CRC_SIZE + size
+    }
+
+    fn row_hint_header_size(&self) -> usize {
+        CRC_SIZE + self.v1.row_hint_header_size()
+    }
+
+    fn decode_row_hint_header(&self, header_bs: &[u8]) -> RowHintHeader {
+        self.v1.decode_row_hint_header(&header_bs[CRC_SIZE..])
+    }
+
+    fn validate_row_hint(&self, hint_bs: &[u8]) -> Result<()> {
+        let expected_crc = LittleEndian::read_u32(&hint_bs[0..CRC_SIZE]);
+        let actual_crc = self.gen_hint_crc(&hint_bs[CRC_SIZE..]);
+        if expected_crc != actual_crc {
+            return Err(FormatterError::CrcCheckFailed {
+                expected_crc,
+                actual_crc,
+            });
+        }
+        Ok(())
+    }
+
+    fn merge_meta_size(&self) -> usize {
+        self.v1.merge_meta_size()
+    }
+
+    fn encode_merge_meta(&self, meta: &MergeMeta) -> Bytes {
+        self.v1.encode_merge_meta(meta)
+    }
+
+    fn decode_merge_meta(&self, meta: Bytes) -> MergeMeta {
+        self.v1.decode_merge_meta(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+
+    #[test]
+    fn test_encode_decode_row_hint() {
+        let formatter = FormatterV2::default();
+        let key = b"hello".to_vec();
+        let hint = RowHint {
+            header: RowHintHeader {
+                expire_timestamp: 123,
+                key_size: key.len(),
+                row_offset: 456,
+                row_size: 789,
+            },
+            key,
+        };
+        let mut bs = vec![0; 128];
+        let size = formatter.encode_row_hint(&hint, &mut bs);
+        assert_eq!(formatter.row_hint_header_size() + hint.key.len(), size);
+        assert_eq!(
+            hint.header,
+            formatter.decode_row_hint_header(&bs[0..formatter.row_hint_header_size()])
+        );
+        formatter.validate_row_hint(&bs[0..size]).unwrap();
+
+        bs[size - 1] ^= 0xff;
+        assert_matches!(
+            formatter.validate_row_hint(&bs[0..size]),
+            Err(FormatterError::CrcCheckFailed { .. })
+        );
+    }
+}
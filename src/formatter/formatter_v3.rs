@@ -0,0 +1,224 @@
+use std::ops::Deref;
+
+use super::{
+    Checksum, ChecksumAlgorithm, Formatter, FormatterError, FormatterV2, MergeMeta, Result,
+    RowHeader, RowHint, RowHintHeader, RowMeta, RowToWrite,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
+
+const CRC_SIZE: usize = 4;
+const TSTAMP_SIZE: usize = 8;
+const KEY_SIZE_SIZE: usize = 8;
+const VALUE_SIZE_SIZE: usize = 8;
+const DATA_FILE_EXPIRE_TSTAMP_OFFSET: usize = CRC_SIZE;
+const DATA_FILE_WRITE_TSTAMP_OFFSET: usize = DATA_FILE_EXPIRE_TSTAMP_OFFSET + TSTAMP_SIZE;
+const DATA_FILE_KEY_SIZE_OFFSET: usize = DATA_FILE_WRITE_TSTAMP_OFFSET + TSTAMP_SIZE;
+const DATA_FILE_VALUE_SIZE_OFFSET: usize = DATA_FILE_KEY_SIZE_OFFSET + KEY_SIZE_SIZE;
+const DATA_FILE_KEY_OFFSET: usize = DATA_FILE_VALUE_SIZE_OFFSET + VALUE_SIZE_SIZE;
+
+/// Same as [`FormatterV2`] except that every row also keeps the time it is written,
+/// right after its expire timestamp. Rows are checksummed by the algorithm recorded in the
+/// file header.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FormatterV3 {
+    v2: FormatterV2,
+    checksum_algorithm: ChecksumAlgorithm,
+}
+
+impl FormatterV3 {
+    pub fn with_checksum_algorithm(checksum_algorithm: ChecksumAlgorithm) -> FormatterV3 {
+        FormatterV3 {
+            v2: FormatterV2::default(),
+            checksum_algorithm,
+        }
+    }
+
+    pub fn checksum_algorithm(&self) -> ChecksumAlgorithm {
+        self.checksum_algorithm
+    }
+
+    /// Starts the checksum of a row from its meta. Feed the key and value of the row to it
+    /// in order, then its result is the crc in the row header
+    pub fn row_checksum(&self, meta: &RowMeta) -> Checksum {
+        let mut ck = self.checksum_algorithm.digest();
+        ck.update(&meta.expire_timestamp.to_be_bytes());
+        ck.update(&meta.write_timestamp.to_be_bytes());
+        ck.update(&meta.key_size.to_be_bytes());
+        ck.update(&meta.value_size.to_be_bytes());
+        ck
+    }
+
+    fn gen_crc(&self, meta: &RowMeta, kv: &[&[u8]]) -> u32 {
+        let mut ck = self.row_checksum(meta);
+        kv.iter().for_each(|bs| ck.update(bs));
+        ck.finalize()
+    }
+}
+
+impl Formatter for FormatterV3 {
+    fn row_header_size(&self) -> usize {
+        DATA_FILE_KEY_OFFSET
+    }
+
+    fn net_row_size<K: AsRef<[u8]>, V: Deref<Target = [u8]>>(
+        &self,
+        row: &RowToWrite<K, V>,
+    ) -> usize {
+        self.row_header_size() + row.key.as_ref().len() + row.value.len()
+    }
+
+    fn encode_row<K: AsRef<[u8]>, V: Deref<Target = [u8]>>(
+        &self,
+        row: &RowToWrite<K, V>,
+        output: &mut [u8],
+    ) -> usize {
+        let key = row.key.as_ref();
+        let key_end = DATA_FILE_KEY_OFFSET + key.len();
+        let row_end = key_end + row.value.len();
+        let crc = row
+            .custom_crc
+            .unwrap_or_else(|| self.gen_crc(&row.meta, &[key, &row.value]));
+        LittleEndian::write_u32(output, crc);
+        LittleEndian::write_u64(
+            &mut output[DATA_FILE_EXPIRE_TSTAMP_OFFSET..],
+            row.meta.expire_timestamp,
+        );
+        LittleEndian::write_u64(
+            &mut output[DATA_FILE_WRITE_TSTAMP_OFFSET..],
+            row.meta.write_timestamp,
+        );
+        LittleEndian::write_u64(
+            &mut output[DATA_FILE_KEY_SIZE_OFFSET..],
+            row.meta.key_size as u64,
+        );
+        LittleEndian::write_u64(
+            &mut output[DATA_FILE_VALUE_SIZE_OFFSET..],
+            row.meta.value_size as u64,
+        );
+        output[DATA_FILE_KEY_OFFSET..key_end].copy_from_slice(key);
+        output[key_end..row_end].copy_from_slice(&row.value);
+        row_end
+    }
+
+    fn decode_row_header(&self, bs: &[u8]) -> RowHeader {
+        RowHeader {
+            crc: LittleEndian::read_u32(bs),
+            meta: RowMeta {
+                expire_timestamp: LittleEndian::read_u64(&bs[DATA_FILE_EXPIRE_TSTAMP_OFFSET..]),
+                write_timestamp: LittleEndian::read_u64(&bs[DATA_FILE_WRITE_TSTAMP_OFFSET..]),
+                key_size: LittleEndian::read_u64(&bs[DATA_FILE_KEY_SIZE_OFFSET..]) as usize,
+                value_size: LittleEndian::read_u64(&bs[DATA_FILE_VALUE_SIZE_OFFSET..]) as usize,
+            },
+        }
+    }
+
+    fn validate_key_value(&self, header: &RowHeader, kv: &[u8]) -> Result<()> {
+        let actual_crc = self.gen_crc(&header.meta, &[kv]);
+        if header.crc != actual_crc {
+            return Err(FormatterError::CrcCheckFailed {
+                expected_crc: header.crc,
+                actual_crc,
+            });
+        }
+        Ok(())
+    }
+
+    fn encode_row_hint(&self, hint: &RowHint, output: &mut [u8]) -> usize {
+        self.v2.encode_row_hint(hint, output)
+    }
+
+    fn row_hint_header_size(&self) -> usize {
+        self.v2.row_hint_header_size()
+    }
+
+    fn decode_row_hint_header(&self, header_bs: &[u8]) -> RowHintHeader {
+        self.v2.decode_row_hint_header(header_bs)
+    }
+
+    fn validate_row_hint(&self, hint_bs: &[u8]) -> Result<()> {
+        self.v2.validate_row_hint(hint_bs)
+    }
+
+    fn merge_meta_size(&self) -> usize {
+        self.v2.merge_meta_size()
+    }
+
+    fn encode_merge_meta(&self, meta: &MergeMeta) -> Bytes {
+        self.v2.encode_merge_meta(meta)
+    }
+
+    fn decode_merge_meta(&self, meta: Bytes) -> MergeMeta {
+        self.v2.decode_merge_meta(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+
+    #[test]
+    fn test_encode_decode_row() {
+        let formatter = FormatterV3::default();
+        let row = RowToWrite::new_with_timestamp(b"hello".to_vec(), b"world".to_vec(), 123)
+            .with_write_timestamp(456);
+        let mut bs = vec![0; 128];
+        let size = formatter.encode_row(&row, &mut bs);
+        assert_eq!(formatter.net_row_size(&row), size);
+
+        let header = formatter.decode_row_header(&bs);
+        assert_eq!(row.meta, header.meta);
+        let kv = &bs[formatter.row_header_size()..size];
+        assert_eq!(b"helloworld", kv);
+        formatter.validate_key_value(&header, kv).unwrap();
+
+        // write timestamp is checksummed
+        LittleEndian::write_u64(&mut bs[DATA_FILE_WRITE_TSTAMP_OFFSET..], 789);
+        let header = formatter.decode_row_header(&bs);
+        assert_matches!(
+            formatter.validate_key_value(&header, &bs[formatter.row_header_size()..size]),
+            Err(FormatterError::CrcCheckFailed { .. })
+        );
+    }
+
+    #[test]
+    fn test_checksum_algorithm() {
+        let formatter = FormatterV3::with_checksum_algorithm(ChecksumAlgorithm::Crc32c);
+        let row = RowToWrite::new(b"hello".to_vec(), b"world".to_vec());
+        let mut bs = vec![0; 128];
+        let size = formatter.encode_row(&row, &mut bs);
+        let header = formatter.decode_row_header(&bs);
+        let kv = &bs[formatter.row_header_size()..size];
+        formatter.validate_key_value(&header, kv).unwrap();
+
+        assert_matches!(
+            FormatterV3::default().validate_key_value(&header, kv),
+            Err(FormatterError::CrcCheckFailed { .. })
+        );
+    }
+
+    #[test]
+    fn test_encode_row_with_custom_crc() {
+        let formatter = FormatterV3::default();
+        let row = RowToWrite::new(b"hello".to_vec(), b"world".to_vec());
+        let mut expect = vec![0; 128];
+        let size = formatter.encode_row(&row, &mut expect);
+        let crc = formatter.decode_row_header(&expect).crc;
+
+        // same bytes with the computed crc
+        let mut bs = vec![0; 128];
+        let row = RowToWrite::with_custom_crc(b"hello".to_vec(), b"world".to_vec(), 0, crc);
+        assert_eq!(size, formatter.encode_row(&row, &mut bs));
+        assert_eq!(expect, bs);
+
+        let row = RowToWrite::with_custom_crc(b"hello".to_vec(), b"world".to_vec(), 0, !crc);
+        formatter.encode_row(&row, &mut bs);
+        let header = formatter.decode_row_header(&bs);
+        assert_eq!(!crc, header.crc);
+        assert_matches!(
+            formatter.validate_key_value(&header, &bs[formatter.row_header_size()..size]),
+            Err(FormatterError::CrcCheckFailed { .. })
+        );
+    }
+}
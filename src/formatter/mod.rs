@@ -7,18 +7,105 @@ use std::{
 use crate::storage_id::StorageId;
 
 use bytes::{BufMut, Bytes, BytesMut};
+use crc::{Crc, Digest, CRC_32_CKSUM};
 use thiserror::Error;
 
+mod custom;
+mod erlang_bitcask;
 mod formatter_v1;
+mod formatter_v2;
+mod formatter_v3;
+pub use self::custom::{
+    register_row_formatter, CustomFormatter, RowFormatter, MIN_CUSTOM_FORMATTER_VERSION,
+};
+pub use self::erlang_bitcask::ErlangBitcaskFormatter;
 pub use self::formatter_v1::FormatterV1;
+pub use self::formatter_v2::FormatterV2;
+pub use self::formatter_v3::FormatterV3;
 
 const MAGIC: &[u8; 3] = b"btk";
 const FORMATTER_V1_VERSION: u8 = 1;
+const FORMATTER_V2_VERSION: u8 = 2;
+const FORMATTER_V3_VERSION: u8 = 3;
+/// Files of Erlang bitcask have no header, this version is never written to any file
+const ERLANG_BITCASK_VERSION: u8 = 0;
 pub const FILE_HEADER_SIZE: usize = 8;
+/// Offset in file header of the checksum algorithm of rows. Files written before the algorithm
+/// is configurable have 0 here, which is `ChecksumAlgorithm::Crc32Posix`
+const FILE_HEADER_CHECKSUM_OFFSET: usize = 4;
+
+static CRC32_POSIX: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+/// Algorithm to checksum rows of data files written by the builtin formatter. It's recorded in
+/// the header of each data file, so files are always read with the algorithm they are written by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChecksumAlgorithm {
+    /// CRC-32/POSIX, used by all the data files written before the algorithm is configurable
+    #[default]
+    Crc32Posix,
+    /// CRC-32C (Castagnoli), computed by the SSE4.2 or ARMv8 crc32 instruction when available
+    Crc32c,
+}
 
-#[derive(Debug, PartialEq, Eq)]
+impl ChecksumAlgorithm {
+    fn id(&self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Crc32Posix => 0,
+            ChecksumAlgorithm::Crc32c => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<ChecksumAlgorithm> {
+        match id {
+            0 => Ok(ChecksumAlgorithm::Crc32Posix),
+            1 => Ok(ChecksumAlgorithm::Crc32c),
+            _ => Err(FormatterError::UnknownChecksumAlgorithm(id)),
+        }
+    }
+
+    pub fn digest(&self) -> Checksum {
+        match self {
+            ChecksumAlgorithm::Crc32Posix => Checksum::Crc32Posix(CRC32_POSIX.digest()),
+            ChecksumAlgorithm::Crc32c => Checksum::Crc32c(0),
+        }
+    }
+
+    pub fn checksum(&self, bs: &[u8]) -> u32 {
+        let mut ck = self.digest();
+        ck.update(bs);
+        ck.finalize()
+    }
+}
+
+/// Checksum of bytes fed in pieces
+pub enum Checksum {
+    Crc32Posix(Digest<'static, u32>),
+    Crc32c(u32),
+}
+
+impl Checksum {
+    pub fn update(&mut self, bs: &[u8]) {
+        match self {
+            Checksum::Crc32Posix(d) => d.update(bs),
+            Checksum::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, bs),
+        }
+    }
+
+    pub fn finalize(self) -> u32 {
+        match self {
+            Checksum::Crc32Posix(d) => d.finalize(),
+            Checksum::Crc32c(crc) => crc,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct RowMeta {
     pub expire_timestamp: u64,
+    /// Milliseconds since epoch when the row is written. Always 0 for rows of formatters
+    /// not keeping it
+    pub write_timestamp: u64,
     pub key_size: usize,
     pub value_size: usize,
 }
@@ -34,11 +121,31 @@ pub struct RowToWrite<K: AsRef<[u8]>, V: Deref<Target = [u8]>> {
     pub meta: RowMeta,
     pub key: K,
     pub value: V,
+    /// Written by builtin formatters in place of the computed crc. Only set by `with_custom_crc`
+    pub(crate) custom_crc: Option<u32>,
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct MergeMeta {
     pub known_max_storage_id: StorageId,
+    /// Data files rewritten by a partial merge. Empty when all the data files
+    /// before `known_max_storage_id` are merged.
+    pub source_storage_ids: Vec<StorageId>,
+    /// Data files kept after merge as some rows in them failed to be rewritten
+    pub retained_storage_ids: Vec<StorageId>,
+}
+
+impl MergeMeta {
+    /// Returns true if the data file is merged and can be purged after merge committed
+    pub fn is_merge_source(&self, storage_id: StorageId) -> bool {
+        if self.retained_storage_ids.contains(&storage_id) {
+            return false;
+        }
+        if self.source_storage_ids.is_empty() {
+            return storage_id < self.known_max_storage_id;
+        }
+        self.source_storage_ids.contains(&storage_id)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -66,13 +173,30 @@ impl<K: AsRef<[u8]>, V: Deref<Target = [u8]>> RowToWrite<K, V> {
         RowToWrite {
             meta: RowMeta {
                 expire_timestamp,
+                write_timestamp: 0,
                 key_size,
                 value_size,
             },
             key,
             value,
+            custom_crc: None,
         }
     }
+
+    /// Creates a row written with the given crc instead of the one computed from its content, like
+    /// to replicate a row exactly from another database or to write a corrupted row in tests.
+    /// Custom formatters compute crc by themselves and ignore it.
+    #[cfg(any(test, feature = "unsafe_crc"))]
+    pub fn with_custom_crc(key: K, value: V, expire_timestamp: u64, crc: u32) -> RowToWrite<K, V> {
+        let mut row = RowToWrite::new_with_timestamp(key, value, expire_timestamp);
+        row.custom_crc = Some(crc);
+        row
+    }
+
+    pub fn with_write_timestamp(mut self, write_timestamp: u64) -> RowToWrite<K, V> {
+        self.meta.write_timestamp = write_timestamp;
+        self
+    }
 }
 
 #[derive(Error, Debug)]
@@ -88,6 +212,12 @@ pub enum FormatterError {
     MagicNotMatch(),
     #[error("Unknown formatter version: {0}")]
     UnknownFormatterVersion(u8),
+    #[error("Unknown checksum algorithm: {0}")]
+    UnknownChecksumAlgorithm(u8),
+    #[error("Formatter version: {0} is reserved for builtin formatters")]
+    ReservedFormatterVersion(u8),
+    #[error("Another formatter with version: {0} is registered")]
+    FormatterVersionConflict(u8),
 }
 
 pub type Result<T> = std::result::Result<T, FormatterError>;
@@ -116,6 +246,9 @@ pub trait Formatter: std::marker::Send + 'static + Copy {
 
     fn decode_row_hint_header(&self, header_bs: &[u8]) -> RowHintHeader;
 
+    /// Validates an encoded hint row including its header and key
+    fn validate_row_hint(&self, hint_bs: &[u8]) -> Result<()>;
+
     fn merge_meta_size(&self) -> usize;
 
     fn encode_merge_meta(&self, meta: &MergeMeta) -> Bytes;
@@ -126,12 +259,45 @@ pub trait Formatter: std::marker::Send + 'static + Copy {
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BitcaskyFormatter {
     V1(FormatterV1),
+    V2(FormatterV2),
+    V3(FormatterV3),
+    /// Rows are encoded by a custom formatter, other files are encoded the same as `V2`
+    Custom(CustomFormatter),
+    /// Rows in data files left by the original Erlang bitcask. These data files are read only and
+    /// their rows are validated by `ErlangDataStorage` which sees the timestamp of each row.
+    /// Other files are encoded the same as `V2`
+    ErlangBitcask(ErlangBitcaskFormatter),
 }
 
 impl BitcaskyFormatter {
     pub fn version(&self) -> u8 {
         match self {
             BitcaskyFormatter::V1(_) => FORMATTER_V1_VERSION,
+            BitcaskyFormatter::V2(_) => FORMATTER_V2_VERSION,
+            BitcaskyFormatter::V3(_) => FORMATTER_V3_VERSION,
+            BitcaskyFormatter::Custom(f) => f.0.version(),
+            BitcaskyFormatter::ErlangBitcask(_) => ERLANG_BITCASK_VERSION,
+        }
+    }
+
+    /// Size of the header before the first row of data files
+    pub fn file_header_size(&self) -> usize {
+        match self {
+            BitcaskyFormatter::ErlangBitcask(_) => 0,
+            _ => FILE_HEADER_SIZE,
+        }
+    }
+
+    pub fn custom(formatter: &'static dyn RowFormatter) -> BitcaskyFormatter {
+        BitcaskyFormatter::Custom(CustomFormatter(formatter))
+    }
+
+    /// Algorithm checksumming rows. Formatters other than `V3` always use CRC-32/POSIX for
+    /// their own rows or checksum rows on their own
+    pub fn checksum_algorithm(&self) -> ChecksumAlgorithm {
+        match self {
+            BitcaskyFormatter::V3(f) => f.checksum_algorithm(),
+            _ => ChecksumAlgorithm::Crc32Posix,
         }
     }
 }
@@ -140,6 +306,10 @@ impl Formatter for BitcaskyFormatter {
     fn row_header_size(&self) -> usize {
         match self {
             BitcaskyFormatter::V1(f) => f.row_header_size(),
+            BitcaskyFormatter::V2(f) => f.row_header_size(),
+            BitcaskyFormatter::V3(f) => f.row_header_size(),
+            BitcaskyFormatter::Custom(f) => f.0.row_header_size(),
+            BitcaskyFormatter::ErlangBitcask(f) => f.row_header_size(),
         }
     }
 
@@ -149,6 +319,14 @@ impl Formatter for BitcaskyFormatter {
     ) -> usize {
         match self {
             BitcaskyFormatter::V1(f) => f.net_row_size(row),
+            BitcaskyFormatter::V2(f) => f.net_row_size(row),
+            BitcaskyFormatter::V3(f) => f.net_row_size(row),
+            BitcaskyFormatter::Custom(f) => {
+                f.0.row_header_size() + row.key.as_ref().len() + row.value.len()
+            }
+            BitcaskyFormatter::ErlangBitcask(f) => {
+                f.row_header_size() + row.key.as_ref().len() + row.value.len()
+            }
         }
     }
 
@@ -159,70 +337,136 @@ impl Formatter for BitcaskyFormatter {
     ) -> usize {
         match self {
             BitcaskyFormatter::V1(f) => f.encode_row(row, output),
+            BitcaskyFormatter::V2(f) => f.encode_row(row, output),
+            BitcaskyFormatter::V3(f) => f.encode_row(row, output),
+            BitcaskyFormatter::Custom(f) => f.0.encode_row(
+                &RowToWrite {
+                    meta: row.meta.clone(),
+                    key: row.key.as_ref(),
+                    value: &*row.value,
+                    custom_crc: row.custom_crc,
+                },
+                output,
+            ),
+            BitcaskyFormatter::ErlangBitcask(_) => {
+                unreachable!("rows are never written to data files of Erlang bitcask")
+            }
         }
     }
 
     fn decode_row_header(&self, bs: &[u8]) -> RowHeader {
         match self {
             BitcaskyFormatter::V1(f) => f.decode_row_header(bs),
+            BitcaskyFormatter::V2(f) => f.decode_row_header(bs),
+            BitcaskyFormatter::V3(f) => f.decode_row_header(bs),
+            BitcaskyFormatter::Custom(f) => f.0.decode_row_header(bs),
+            BitcaskyFormatter::ErlangBitcask(f) => f.decode_row_header(bs),
         }
     }
 
     fn validate_key_value(&self, header: &RowHeader, kv: &[u8]) -> Result<()> {
         match self {
             BitcaskyFormatter::V1(f) => f.validate_key_value(header, kv),
+            BitcaskyFormatter::V2(f) => f.validate_key_value(header, kv),
+            BitcaskyFormatter::V3(f) => f.validate_key_value(header, kv),
+            BitcaskyFormatter::Custom(f) => f.0.validate_key_value(header, kv),
+            BitcaskyFormatter::ErlangBitcask(_) => {
+                unreachable!("rows of Erlang bitcask are validated along with their header")
+            }
         }
     }
 
     fn row_hint_header_size(&self) -> usize {
         match self {
             BitcaskyFormatter::V1(f) => f.row_hint_header_size(),
+            BitcaskyFormatter::V2(f) => f.row_hint_header_size(),
+            BitcaskyFormatter::V3(f) => f.row_hint_header_size(),
+            BitcaskyFormatter::Custom(_) | BitcaskyFormatter::ErlangBitcask(_) => {
+                FormatterV2::default().row_hint_header_size()
+            }
         }
     }
 
     fn encode_row_hint(&self, hint: &RowHint, output: &mut [u8]) -> usize {
         match self {
             BitcaskyFormatter::V1(f) => f.encode_row_hint(hint, output),
+            BitcaskyFormatter::V2(f) => f.encode_row_hint(hint, output),
+            BitcaskyFormatter::V3(f) => f.encode_row_hint(hint, output),
+            BitcaskyFormatter::Custom(_) | BitcaskyFormatter::ErlangBitcask(_) => {
+                FormatterV2::default().encode_row_hint(hint, output)
+            }
         }
     }
 
     fn decode_row_hint_header(&self, header_bs: &[u8]) -> RowHintHeader {
         match self {
             BitcaskyFormatter::V1(f) => f.decode_row_hint_header(header_bs),
+            BitcaskyFormatter::V2(f) => f.decode_row_hint_header(header_bs),
+            BitcaskyFormatter::V3(f) => f.decode_row_hint_header(header_bs),
+            BitcaskyFormatter::Custom(_) | BitcaskyFormatter::ErlangBitcask(_) => {
+                FormatterV2::default().decode_row_hint_header(header_bs)
+            }
+        }
+    }
+
+    fn validate_row_hint(&self, hint_bs: &[u8]) -> Result<()> {
+        match self {
+            BitcaskyFormatter::V1(f) => f.validate_row_hint(hint_bs),
+            BitcaskyFormatter::V2(f) => f.validate_row_hint(hint_bs),
+            BitcaskyFormatter::V3(f) => f.validate_row_hint(hint_bs),
+            BitcaskyFormatter::Custom(_) | BitcaskyFormatter::ErlangBitcask(_) => {
+                FormatterV2::default().validate_row_hint(hint_bs)
+            }
         }
     }
 
     fn merge_meta_size(&self) -> usize {
         match self {
             BitcaskyFormatter::V1(f) => f.merge_meta_size(),
+            BitcaskyFormatter::V2(f) => f.merge_meta_size(),
+            BitcaskyFormatter::V3(f) => f.merge_meta_size(),
+            BitcaskyFormatter::Custom(_) | BitcaskyFormatter::ErlangBitcask(_) => {
+                FormatterV2::default().merge_meta_size()
+            }
         }
     }
 
     fn encode_merge_meta(&self, meta: &MergeMeta) -> Bytes {
         match self {
             BitcaskyFormatter::V1(f) => f.encode_merge_meta(meta),
+            BitcaskyFormatter::V2(f) => f.encode_merge_meta(meta),
+            BitcaskyFormatter::V3(f) => f.encode_merge_meta(meta),
+            BitcaskyFormatter::Custom(_) | BitcaskyFormatter::ErlangBitcask(_) => {
+                FormatterV2::default().encode_merge_meta(meta)
+            }
         }
     }
 
     fn decode_merge_meta(&self, meta: Bytes) -> MergeMeta {
         match self {
             BitcaskyFormatter::V1(f) => f.decode_merge_meta(meta),
+            BitcaskyFormatter::V2(f) => f.decode_merge_meta(meta),
+            BitcaskyFormatter::V3(f) => f.decode_merge_meta(meta),
+            BitcaskyFormatter::Custom(_) | BitcaskyFormatter::ErlangBitcask(_) => {
+                FormatterV2::default().decode_merge_meta(meta)
+            }
         }
     }
 }
 
 impl Default for BitcaskyFormatter {
     fn default() -> Self {
-        BitcaskyFormatter::V1(FormatterV1::default())
+        BitcaskyFormatter::V3(FormatterV3::default())
     }
 }
 
-pub fn initialize_new_file(file: &mut File, version: u8) -> std::io::Result<()> {
+pub fn initialize_new_file(file: &mut File, formatter: &BitcaskyFormatter) -> std::io::Result<()> {
     let mut bs = BytesMut::with_capacity(FILE_HEADER_SIZE);
 
     bs.extend_from_slice(MAGIC);
-    bs.put_u8(version);
-    bs.put_u32(0);
+    bs.put_u8(formatter.version());
+    bs.put_u8(formatter.checksum_algorithm().id());
+    bs.put_bytes(0, 3);
 
     file.write_all(&bs.freeze())?;
     file.flush()?;
@@ -243,6 +487,19 @@ pub fn get_formatter_from_file(file: &mut File) -> Result<BitcaskyFormatter> {
     if formatter_version == FORMATTER_V1_VERSION {
         return Ok(BitcaskyFormatter::V1(FormatterV1::default()));
     }
+    if formatter_version == FORMATTER_V2_VERSION {
+        return Ok(BitcaskyFormatter::V2(FormatterV2::default()));
+    }
+    if formatter_version == FORMATTER_V3_VERSION {
+        let checksum_algorithm =
+            ChecksumAlgorithm::from_id(file_header[FILE_HEADER_CHECKSUM_OFFSET])?;
+        return Ok(BitcaskyFormatter::V3(FormatterV3::with_checksum_algorithm(
+            checksum_algorithm,
+        )));
+    }
+    if let Some(f) = custom::find_row_formatter(formatter_version) {
+        return Ok(BitcaskyFormatter::custom(f));
+    }
 
     Err(FormatterError::UnknownFormatterVersion(formatter_version))
 }
@@ -267,7 +524,7 @@ mod tests {
         let storage_id = 1;
         let mut file = create_file(&dir, FileType::DataFile, Some(storage_id)).unwrap();
         let init_formatter = BitcaskyFormatter::V1(FormatterV1::default());
-        initialize_new_file(&mut file, init_formatter.version()).unwrap();
+        initialize_new_file(&mut file, &init_formatter).unwrap();
 
         let mut file = open_file(&dir, FileType::DataFile, Some(storage_id))
             .unwrap()
@@ -278,6 +535,51 @@ mod tests {
         assert_eq!(init_formatter, read_formatter);
     }
 
+    #[test]
+    fn test_checksum_algorithm_in_file_header() {
+        let dir = get_temporary_directory_path();
+        for (storage_id, algorithm) in [ChecksumAlgorithm::Crc32Posix, ChecksumAlgorithm::Crc32c]
+            .into_iter()
+            .enumerate()
+        {
+            let storage_id = storage_id as StorageId;
+            let mut file = create_file(&dir, FileType::DataFile, Some(storage_id)).unwrap();
+            let init_formatter =
+                BitcaskyFormatter::V3(FormatterV3::with_checksum_algorithm(algorithm));
+            initialize_new_file(&mut file, &init_formatter).unwrap();
+
+            let mut file = open_file(&dir, FileType::DataFile, Some(storage_id))
+                .unwrap()
+                .file;
+            let read_formatter = get_formatter_from_file(&mut file).unwrap();
+            assert_eq!(algorithm, read_formatter.checksum_algorithm());
+            assert_eq!(init_formatter, read_formatter);
+        }
+
+        let mut file = create_file(&dir, FileType::DataFile, Some(2)).unwrap();
+        file.write_all(MAGIC).unwrap();
+        file.write_all(&[FORMATTER_V3_VERSION, 9, 0, 0, 0]).unwrap();
+        let mut file = open_file(&dir, FileType::DataFile, Some(2)).unwrap().file;
+        assert_matches!(
+            get_formatter_from_file(&mut file),
+            Err(FormatterError::UnknownChecksumAlgorithm(9))
+        );
+    }
+
+    #[test]
+    fn test_checksum_check_values() {
+        assert_eq!(
+            0x765E7680,
+            ChecksumAlgorithm::Crc32Posix.checksum(b"123456789")
+        );
+        assert_eq!(0xE3069283, ChecksumAlgorithm::Crc32c.checksum(b"123456789"));
+
+        let mut ck = ChecksumAlgorithm::Crc32c.digest();
+        ck.update(b"1234");
+        ck.update(b"56789");
+        assert_eq!(0xE3069283, ck.finalize());
+    }
+
     #[test]
     fn test_read_file_header_failed() {
         let dir = get_temporary_directory_path();
@@ -4,7 +4,9 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use log::debug;
+use fail::fail_point;
+
+use crate::logging::debug;
 
 use crate::{fs::FileType, storage_id::StorageId};
 
@@ -160,25 +162,58 @@ pub fn delete_dir(base_dir: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn get_storage_ids_in_dir(dir_path: &Path, file_type: FileType) -> Vec<StorageId> {
-    let mut actual_storage_ids = vec![];
-    for path in fs::read_dir(dir_path).unwrap() {
-        let file_dir_entry = path.unwrap();
-        let file_path = file_dir_entry.path();
-        if file_path.is_dir() {
+/// Files of some types found in a directory by `list_storage_files`
+#[derive(Debug, Default)]
+pub struct StorageFiles {
+    /// Storage ids of the files, sorted and deduplicated
+    pub storage_ids: Vec<StorageId>,
+    /// Files of the types without a storage id in their names, like temporary files left
+    /// before renamed to their final names
+    pub leftovers: Vec<PathBuf>,
+}
+
+/// Lists files of any of the given types in one pass over the directory, without reading
+/// metadata of each entry on platforms where the entry tells its type
+pub fn list_storage_files(dir_path: &Path, file_types: &[FileType]) -> Result<StorageFiles> {
+    let mut files = StorageFiles::default();
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
             continue;
         }
-        if !file_type.check_file_belongs_to_type(&file_path) {
+        let file_path = entry.path();
+        let file_type = FileType::of_path(&file_path);
+        if !file_types.contains(&file_type) {
             continue;
         }
-
-        let id = file_type
-            .parse_storage_id_from_file_name(&file_path)
-            .unwrap();
-        actual_storage_ids.push(id);
+        match file_type.parse_storage_id_from_file_name(&file_path) {
+            Some(id) => files.storage_ids.push(id),
+            None => files.leftovers.push(file_path),
+        }
     }
-    actual_storage_ids.sort();
-    actual_storage_ids
+    files.storage_ids.sort_unstable();
+    files.storage_ids.dedup();
+    Ok(files)
+}
+
+/// Sorted storage ids of files in the type
+pub fn get_storage_ids_in_dir(dir_path: &Path, file_type: FileType) -> Vec<StorageId> {
+    get_storage_ids_of_types_in_dir(dir_path, &[file_type])
+}
+
+/// Sorted storage ids of files in any of the given types, each id listed once
+pub fn get_storage_ids_of_types_in_dir(dir_path: &Path, file_types: &[FileType]) -> Vec<StorageId> {
+    list_storage_files(dir_path, file_types)
+        .unwrap()
+        .storage_ids
+}
+
+/// Returns bytes available to this process on the file system of the directory
+pub fn available_space(dir: &Path) -> Result<u64> {
+    fail_point!("fs::available_space", |space: Option<String>| {
+        Ok(space.and_then(|s| s.parse().ok()).unwrap_or(0))
+    });
+    fs4::available_space(dir)
 }
 
 // used by some tests
@@ -351,4 +386,27 @@ mod tests {
         let storage_ids = get_storage_ids_in_dir(&dir, FileType::DataFile);
         assert_eq!(vec![101, 102, 103], storage_ids);
     }
+
+    #[test]
+    fn test_list_storage_files_with_leftovers() {
+        let dir = get_temporary_directory_path();
+        create_file(&dir, FileType::DataFile, Some(3)).unwrap();
+        create_file(&dir, FileType::DataFile, Some(1)).unwrap();
+        create_file(&dir, FileType::ErlangDataFile, Some(1)).unwrap();
+        create_file(&dir, FileType::HintFile, Some(2)).unwrap();
+        // temporary file of a data file not renamed yet, and the one renamed
+        let leftover = dir.join("tmp-2.data");
+        File::create(&leftover).unwrap();
+        create_file(&dir, FileType::DataFile, Some(2)).unwrap();
+        create_dir(&dir.join("4.data")).unwrap();
+
+        let files =
+            list_storage_files(&dir, &[FileType::DataFile, FileType::ErlangDataFile]).unwrap();
+        assert_eq!(vec![1, 2, 3], files.storage_ids);
+        assert_eq!(vec![leftover], files.leftovers);
+
+        let files = list_storage_files(&dir, &[FileType::HintFile]).unwrap();
+        assert_eq!(vec![2], files.storage_ids);
+        assert!(files.leftovers.is_empty());
+    }
 }
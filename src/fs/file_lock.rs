@@ -1,18 +1,72 @@
 use std::{
-    fs::{self, File},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
     path::Path,
+    thread,
+    time::{Duration, Instant},
 };
 
+use crate::logging::warn;
+
 use super::FileType;
 
 use fs4::FileExt;
 
-pub fn lock_directory(base_dir: &Path) -> std::io::Result<Option<File>> {
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Locks the directory exclusively and records the pid of current process along with
+/// `holder_id` in the lock file. Waits up to `wait_timeout` for the lock to be released
+/// by other holder. Returns None if the lock is still held by others.
+///
+/// The lock is released by OS when the holder exits, even if it's killed, so a lock file
+/// left by a dead process never blocks opening the directory.
+pub fn lock_directory(
+    base_dir: &Path,
+    holder_id: &str,
+    wait_timeout: Duration,
+) -> std::io::Result<Option<File>> {
     fs::create_dir_all(base_dir)?;
     let p = FileType::LockFile.get_path(base_dir, None);
-    let file = File::create(p)?;
-    match file.try_lock_exclusive() {
-        Ok(_) => Ok(Some(file)),
-        _ => Ok(None),
+    // do not truncate the lock file until it's locked, it records the current holder
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(p)?;
+    let deadline = Instant::now() + wait_timeout;
+    while file.try_lock_exclusive().is_err() {
+        let now = Instant::now();
+        if now >= deadline {
+            warn!(
+                "lock directory: {} failed, it's held by: {}",
+                base_dir.display(),
+                read_lock_holder(&mut file).unwrap_or_default()
+            );
+            return Ok(None);
+        }
+        thread::sleep(LOCK_RETRY_INTERVAL.min(deadline - now));
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(file, "{} {}", std::process::id(), holder_id)?;
+    file.sync_data()?;
+    Ok(Some(file))
+}
+
+/// Reads who holds the lock of the directory without locking it. None if there's no lock file.
+pub fn read_directory_lock_holder(base_dir: &Path) -> std::io::Result<Option<String>> {
+    match fs::read_to_string(FileType::LockFile.get_path(base_dir, None)) {
+        Ok(holder) => Ok(Some(holder)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
     }
 }
+
+fn read_lock_holder(file: &mut File) -> std::io::Result<String> {
+    let mut holder = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut holder)?;
+    Ok(holder)
+}
@@ -7,6 +7,9 @@ const LOCK_FILE_EXTENSION: &str = "lock";
 const MERGE_META_FILE_EXTENSION: &str = "meta";
 const DATA_FILE_EXTENSION: &str = "data";
 const HINT_FILE_EXTENSION: &str = "hint";
+const ERLANG_DATA_FILE_EXTENSION: &str = "bitcask.data";
+const ERLANG_HINT_FILE_EXTENSION: &str = "bitcask.hint";
+const KEYDIR_SNAPSHOT_FILE_EXTENSION: &str = "snapshot";
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum FileType {
@@ -15,6 +18,12 @@ pub enum FileType {
     MergeMeta,
     DataFile,
     HintFile,
+    /// Data file left by the original Erlang bitcask
+    ErlangDataFile,
+    /// Hint file left by the original Erlang bitcask
+    ErlangHintFile,
+    /// Keydir written on close by `SnapshotKeyDirBackend`
+    KeydirSnapshot,
 }
 
 impl FileType {
@@ -24,22 +33,44 @@ impl FileType {
             Self::MergeMeta => format!("merge.{}", MERGE_META_FILE_EXTENSION),
             Self::DataFile => format!("{}.{}", storage_id.unwrap(), DATA_FILE_EXTENSION),
             Self::HintFile => format!("{}.{}", storage_id.unwrap(), HINT_FILE_EXTENSION),
+            Self::ErlangDataFile => {
+                format!("{}.{}", storage_id.unwrap(), ERLANG_DATA_FILE_EXTENSION)
+            }
+            Self::ErlangHintFile => {
+                format!("{}.{}", storage_id.unwrap(), ERLANG_HINT_FILE_EXTENSION)
+            }
+            Self::KeydirSnapshot => format!("keydir.{}", KEYDIR_SNAPSHOT_FILE_EXTENSION),
             Self::Unknown => panic!("get path for unknown data type"),
         })
     }
 
     pub fn check_file_belongs_to_type(&self, file_path: &Path) -> bool {
-        let ft = match file_path.extension() {
-            None => FileType::Unknown,
-            Some(os_str) => match os_str.to_str() {
-                Some(LOCK_FILE_EXTENSION) => FileType::LockFile,
-                Some(MERGE_META_FILE_EXTENSION) => FileType::MergeMeta,
-                Some(DATA_FILE_EXTENSION) => FileType::DataFile,
-                Some(HINT_FILE_EXTENSION) => FileType::HintFile,
-                _ => FileType::Unknown,
+        *self == FileType::of_path(file_path)
+    }
+
+    /// Type of the file told by its name
+    pub fn of_path(file_path: &Path) -> FileType {
+        let file_name = file_path.file_name().and_then(|n| n.to_str());
+        match file_name {
+            // "1.bitcask.data" has the same extension as data files of bitcasky
+            Some(n) if n.ends_with(&format!(".{}", ERLANG_DATA_FILE_EXTENSION)) => {
+                FileType::ErlangDataFile
+            }
+            Some(n) if n.ends_with(&format!(".{}", ERLANG_HINT_FILE_EXTENSION)) => {
+                FileType::ErlangHintFile
+            }
+            _ => match file_path.extension() {
+                None => FileType::Unknown,
+                Some(os_str) => match os_str.to_str() {
+                    Some(LOCK_FILE_EXTENSION) => FileType::LockFile,
+                    Some(MERGE_META_FILE_EXTENSION) => FileType::MergeMeta,
+                    Some(DATA_FILE_EXTENSION) => FileType::DataFile,
+                    Some(HINT_FILE_EXTENSION) => FileType::HintFile,
+                    Some(KEYDIR_SNAPSHOT_FILE_EXTENSION) => FileType::KeydirSnapshot,
+                    _ => FileType::Unknown,
+                },
             },
-        };
-        *self == ft
+        }
     }
 
     pub fn parse_storage_id_from_file_name(&self, file_path: &Path) -> Option<StorageId> {
@@ -50,6 +81,9 @@ impl FileType {
             Self::MergeMeta => None,
             Self::DataFile => Some(storage_id_str),
             Self::HintFile => Some(storage_id_str),
+            Self::ErlangDataFile => Some(storage_id_str),
+            Self::ErlangHintFile => Some(storage_id_str),
+            Self::KeydirSnapshot => None,
             Self::Unknown => panic!("get path for unknown data type"),
         }
         .map(|storage_id_str| storage_id_str.parse::<StorageId>())
@@ -63,6 +97,9 @@ impl FileType {
             Self::MergeMeta => MERGE_META_FILE_EXTENSION,
             Self::DataFile => DATA_FILE_EXTENSION,
             Self::HintFile => HINT_FILE_EXTENSION,
+            Self::ErlangDataFile => ERLANG_DATA_FILE_EXTENSION,
+            Self::ErlangHintFile => ERLANG_HINT_FILE_EXTENSION,
+            Self::KeydirSnapshot => KEYDIR_SNAPSHOT_FILE_EXTENSION,
             Self::Unknown => panic!("get path for unknown data type"),
         }
     }
@@ -76,6 +113,9 @@ impl std::fmt::Display for FileType {
             FileType::MergeMeta => f.write_str("MergeMetaFile"),
             FileType::DataFile => f.write_str("DataFile"),
             FileType::HintFile => f.write_str("HintFile"),
+            FileType::ErlangDataFile => f.write_str("ErlangDataFile"),
+            FileType::ErlangHintFile => f.write_str("ErlangHintFile"),
+            FileType::KeydirSnapshot => f.write_str("KeydirSnapshotFile"),
         }
     }
 }
@@ -97,6 +137,8 @@ mod tests {
         assert!(FileType::DataFile.check_file_belongs_to_type(&p));
         let p = FileType::MergeMeta.get_path(&dir, Some(100));
         assert!(FileType::MergeMeta.check_file_belongs_to_type(&p));
+        let p = FileType::KeydirSnapshot.get_path(&dir, None);
+        assert!(FileType::KeydirSnapshot.check_file_belongs_to_type(&p));
 
         assert!(!FileType::LockFile.check_file_belongs_to_type(&dir.join("")));
         assert!(!FileType::DataFile.check_file_belongs_to_type(&dir.join("")));
@@ -108,4 +150,30 @@ mod tests {
         assert!(!FileType::HintFile.check_file_belongs_to_type(&dir.join(".abc")));
         assert!(!FileType::MergeMeta.check_file_belongs_to_type(&dir.join(".abc")));
     }
+
+    #[test]
+    fn test_erlang_file_type() {
+        let dir = get_temporary_directory_path();
+
+        let p = FileType::ErlangDataFile.get_path(&dir, Some(12));
+        assert_eq!(dir.join("12.bitcask.data"), p);
+        assert!(FileType::ErlangDataFile.check_file_belongs_to_type(&p));
+        assert!(!FileType::DataFile.check_file_belongs_to_type(&p));
+        assert_eq!(
+            Some(12),
+            FileType::ErlangDataFile.parse_storage_id_from_file_name(&p)
+        );
+
+        let p = FileType::ErlangHintFile.get_path(&dir, Some(12));
+        assert_eq!(dir.join("12.bitcask.hint"), p);
+        assert!(FileType::ErlangHintFile.check_file_belongs_to_type(&p));
+        assert!(!FileType::HintFile.check_file_belongs_to_type(&p));
+        assert_eq!(
+            Some(12),
+            FileType::ErlangHintFile.parse_storage_id_from_file_name(&p)
+        );
+
+        let p = FileType::DataFile.get_path(&dir, Some(12));
+        assert!(!FileType::ErlangDataFile.check_file_belongs_to_type(&p));
+    }
 }
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use crate::database::RowLocation;
+use crate::error::BitcaskyResult;
+
+/// Storage of the location of the latest row of every key behind `KeyDir`. It's always accessed
+/// behind the keydir lock. Entries are iterated by reference, so they are kept in memory and may
+/// be mirrored to durable storage.
+///
+/// A backend which keeps entries across restarts returns the checkpoint saved on last close
+/// from `checkpoint`. When the checkpoint still matches data files on open, entries are used
+/// as they are and data files are not scanned to rebuild them.
+pub trait KeyDirBackend: Send + Sync {
+    fn put(&mut self, key: Vec<u8>, location: RowLocation) -> Option<RowLocation>;
+
+    fn get(&self, key: &[u8]) -> Option<RowLocation>;
+
+    fn delete(&mut self, key: &[u8]) -> Option<(Vec<u8>, RowLocation)>;
+
+    /// Iterates all the entries in arbitrary order
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Vec<u8>, &RowLocation)> + '_>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn clear(&mut self);
+
+    /// Hints that at least `additional` more entries are going to be put
+    fn reserve(&mut self, _additional: usize) {}
+
+    /// Number of entries which can be held without allocating more memory
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    /// Releases memory held for entries removed
+    fn shrink_to_fit(&mut self) {}
+
+    /// Copies all the entries to memory, like for snapshots
+    fn to_memory(&self) -> MemoryKeyDirBackend {
+        MemoryKeyDirBackend {
+            index: self.iter().map(|(k, l)| (k.clone(), *l)).collect(),
+        }
+    }
+
+    /// Checkpoint saved along with entries by last `set_checkpoint`
+    fn checkpoint(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Saves the checkpoint of data files, which entries are consistent with, durably along with
+    /// entries. It's saved on close and cleared with `None` after open.
+    fn set_checkpoint(&mut self, _checkpoint: Option<&[u8]>) -> BitcaskyResult<()> {
+        Ok(())
+    }
+
+    /// Encoded bloom filter over keys saved along with entries by last `set_checkpoint`, as
+    /// long as entries are not changed since
+    fn bloom_filter(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Keeps the encoded bloom filter over keys to save it along with entries on next
+    /// `set_checkpoint`. Backends not saving it rebuild the filter from keys on every open.
+    fn set_bloom_filter(&mut self, _bloom_filter: Option<&[u8]>) {}
+}
+
+/// Keeps all the entries in a plain hash map, which is cheaper than a concurrent one under
+/// heavy writes. Entries are rebuilt from data files on every open.
+///
+/// Keys are hashed by aHash with random keys by default, which is fast on short keys and still
+/// hard to flood with collisions. Use `with_hasher` to pick another hasher, like an unkeyed one
+/// for trusted keys or SipHash from `std::collections::hash_map::RandomState`.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKeyDirBackend<S = ahash::RandomState> {
+    index: HashMap<Vec<u8>, RowLocation, S>,
+}
+
+impl MemoryKeyDirBackend {
+    /// Allocates room for at least `capacity` entries up front
+    pub fn with_capacity(capacity: usize) -> MemoryKeyDirBackend {
+        MemoryKeyDirBackend {
+            index: HashMap::with_capacity_and_hasher(capacity, ahash::RandomState::default()),
+        }
+    }
+}
+
+impl<S: BuildHasher> MemoryKeyDirBackend<S> {
+    pub fn with_hasher(hasher: S) -> MemoryKeyDirBackend<S> {
+        MemoryKeyDirBackend {
+            index: HashMap::with_hasher(hasher),
+        }
+    }
+}
+
+impl<S: BuildHasher + Send + Sync> KeyDirBackend for MemoryKeyDirBackend<S> {
+    fn put(&mut self, key: Vec<u8>, location: RowLocation) -> Option<RowLocation> {
+        self.index.insert(key, location)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<RowLocation> {
+        self.index.get(key).copied()
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<(Vec<u8>, RowLocation)> {
+        self.index.remove_entry(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Vec<u8>, &RowLocation)> + '_> {
+        Box::new(self.index.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.index.contains_key(key)
+    }
+
+    fn clear(&mut self) {
+        self.index.clear();
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.index.reserve(additional);
+    }
+
+    fn capacity(&self) -> usize {
+        self.index.capacity()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.index.shrink_to_fit();
+    }
+}
@@ -0,0 +1,531 @@
+mod backend;
+#[cfg(feature = "sled")]
+mod sled_backend;
+mod snapshot_backend;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::bloom::{BloomFilter, BloomFilterStats};
+use crate::database::{Database, RecoveryStats, RowLocation};
+use crate::error::BitcaskyResult;
+use crate::logging::info;
+use crate::mutation_trace::{key_hash, KeyMutation, MutationCause, MutationTrace};
+use crate::options::{BitcaskyOptions, BloomFilterOptions};
+use crate::storage_id::StorageId;
+
+pub use backend::{KeyDirBackend, MemoryKeyDirBackend};
+#[cfg(feature = "sled")]
+pub use sled_backend::SledKeyDirBackend;
+pub use snapshot_backend::SnapshotKeyDirBackend;
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyDirTelemetry {
+    pub number_of_keys: usize,
+    /// Number of keys keydir can hold without allocating more memory
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub capacity: usize,
+    #[cfg_attr(feature = "serde", serde(with = "crate::options::duration_secs"))]
+    pub recovery_duration: Duration,
+    pub recovery_stats: RecoveryStats,
+}
+
+/// Rows sampled on recovery to estimate how many keys are going to be put to keydir
+const RESERVE_SAMPLE_ROWS: usize = 1024;
+
+/// Max number of relocated keys listed in a `LocationInvalidation`.
+/// When more keys were relocated, only the new generation is reported.
+const MAX_INVALIDATED_KEYS: usize = 1024;
+
+/// Describes which row locations got from the database are stale
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocationInvalidation {
+    /// Only locations of these keys were changed
+    Keys { generation: u64, keys: Vec<Vec<u8>> },
+    /// Any location got before this generation could be changed
+    All { generation: u64 },
+}
+
+impl LocationInvalidation {
+    pub fn generation(&self) -> u64 {
+        match self {
+            LocationInvalidation::Keys { generation, .. } => *generation,
+            LocationInvalidation::All { generation } => *generation,
+        }
+    }
+}
+
+pub type LocationListener = Arc<dyn Fn(&LocationInvalidation) + Send + Sync>;
+
+/// A location invalidation waiting to be sent to listeners after the keydir is unlocked
+pub struct PendingLocationInvalidation {
+    invalidation: LocationInvalidation,
+    listeners: Vec<LocationListener>,
+}
+
+impl PendingLocationInvalidation {
+    pub fn notify(self) {
+        for listener in self.listeners.iter() {
+            listener(&self.invalidation);
+        }
+    }
+}
+
+/// Index from key to the location of its latest row, kept by a `KeyDirBackend`
+pub struct KeyDir {
+    index: Box<dyn KeyDirBackend>,
+    recovery_duration: Duration,
+    recovery_stats: RecoveryStats,
+    location_generation: u64,
+    location_listeners: Vec<LocationListener>,
+    bloom_filter: Option<BloomFilter>,
+    mutation_trace: Option<MutationTrace>,
+    /// Timestamps of keys deleted by `Bitcasky::delete_with_timestamp` since open and not put
+    /// again, so values put with an older timestamp after the deletion are skipped
+    deletion_timestamps: HashMap<Vec<u8>, u64>,
+}
+
+impl fmt::Debug for KeyDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyDir")
+            .field("index", &self.index.len())
+            .field("recovery_duration", &self.recovery_duration)
+            .field("recovery_stats", &self.recovery_stats)
+            .field("location_generation", &self.location_generation)
+            .field("location_listeners", &self.location_listeners.len())
+            .field(
+                "bloom_filter",
+                &self.bloom_filter.as_ref().map(|b| b.stats()),
+            )
+            .field("mutation_trace", &self.mutation_trace.is_some())
+            .field("deletion_timestamps", &self.deletion_timestamps.len())
+            .finish()
+    }
+}
+
+/// Copies entries to memory whatever the backend is. The copy does not trace mutations
+impl Clone for KeyDir {
+    fn clone(&self) -> Self {
+        KeyDir {
+            index: Box::new(self.index.to_memory()),
+            recovery_duration: self.recovery_duration,
+            recovery_stats: self.recovery_stats,
+            location_generation: self.location_generation,
+            location_listeners: self.location_listeners.clone(),
+            bloom_filter: self.bloom_filter.clone(),
+            mutation_trace: None,
+            deletion_timestamps: self.deletion_timestamps.clone(),
+        }
+    }
+}
+
+impl KeyDir {
+    pub fn new_empty_key_dir() -> KeyDir {
+        KeyDir {
+            index: Box::<MemoryKeyDirBackend>::default(),
+            recovery_duration: Duration::ZERO,
+            recovery_stats: RecoveryStats::default(),
+            location_generation: 0,
+            location_listeners: vec![],
+            bloom_filter: None,
+            mutation_trace: None,
+            deletion_timestamps: HashMap::new(),
+        }
+    }
+
+    /// Uses entries in the backend if its checkpoint matches data files in the database,
+    /// otherwise rebuilds them from data files. Room for at least `initial_capacity` keys is
+    /// reserved in the backend
+    pub fn open(
+        database: &Database,
+        mut index: Box<dyn KeyDirBackend>,
+        initial_capacity: usize,
+    ) -> BitcaskyResult<KeyDir> {
+        let start = Instant::now();
+        let data_files = database.data_files_state();
+        let checkpoint = encode_checkpoint(&data_files);
+        let loaded = index.checkpoint().is_some_and(|c| c == checkpoint);
+        // entries are going to change, they are inconsistent with data files on crash
+        index.set_checkpoint(None)?;
+
+        let recovery_stats = if loaded {
+            info!(target: "KeyDir", "use {} keys in keydir backend without recovering data files", index.len());
+            RecoveryStats {
+                recovered_from_keydir_backend: true,
+                ..RecoveryStats::default()
+            }
+        } else {
+            index.clear();
+            index.reserve(initial_capacity);
+            let data_size = data_files.iter().map(|(_, size)| size).sum::<usize>();
+            let (mut sampled_rows, mut sampled_bytes) = (0, 0);
+            let recovery_iter = database.recovery_iter()?;
+            let recovery_stats = recovery_iter.recovery_stats();
+            for ret in recovery_iter {
+                let item = ret?;
+                if sampled_rows < RESERVE_SAMPLE_ROWS {
+                    sampled_rows += 1;
+                    sampled_bytes += item.row_location.row_size;
+                    if sampled_rows == RESERVE_SAMPLE_ROWS {
+                        // rows in data files include overwritten and deleted ones, reserve for
+                        // half of them so keydir grows at most once more when most rows are
+                        // live, without holding much unused room when they are not
+                        let rows = data_size / (sampled_bytes / sampled_rows).max(1);
+                        index.reserve((rows / 2).saturating_sub(index.len()));
+                    }
+                }
+                if item.invalid {
+                    index.delete(&item.key);
+                    continue;
+                }
+
+                index.put(item.key, item.row_location);
+            }
+            recovery_stats
+        };
+        Ok(KeyDir {
+            index,
+            recovery_duration: start.elapsed(),
+            recovery_stats,
+            location_generation: initial_location_generation(),
+            location_listeners: vec![],
+            bloom_filter: None,
+            mutation_trace: None,
+            deletion_timestamps: HashMap::new(),
+        })
+    }
+
+    /// Saves checkpoint of data files in the database to the backend, so entries are used on
+    /// next open if data files are not changed by then
+    pub fn save_checkpoint(&mut self, database: &Database) -> BitcaskyResult<()> {
+        let checkpoint = encode_checkpoint(&database.data_files_state());
+        let bloom_filter = self.bloom_filter.as_ref().map(|b| b.encode());
+        self.index.set_bloom_filter(bloom_filter.as_deref());
+        self.index.set_checkpoint(Some(&checkpoint))
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: RowLocation) -> Option<RowLocation> {
+        if !self.deletion_timestamps.is_empty() {
+            self.deletion_timestamps.remove(&key);
+        }
+        if let Some(bloom_filter) = self.bloom_filter.as_mut() {
+            if !self.index.contains_key(&key) {
+                bloom_filter.insert(&key);
+            }
+        }
+        match self.mutation_trace.as_mut() {
+            Some(trace) => {
+                let key_hash = key_hash(&key);
+                let old = self.index.put(key, value);
+                trace.record(MutationCause::Put, key_hash, old, Some(value));
+                old
+            }
+            None => self.index.put(key, value),
+        }
+    }
+
+    /// Records the latest `capacity` mutations of keys from now on
+    pub fn enable_mutation_trace(&mut self, capacity: usize, options: Arc<BitcaskyOptions>) {
+        self.mutation_trace = Some(MutationTrace::new(capacity, options));
+    }
+
+    /// Recorded mutations in the order they happened, `None` if mutations are not traced
+    pub fn mutation_trace(&self) -> Option<Vec<KeyMutation>> {
+        self.mutation_trace.as_ref().map(|t| t.mutations())
+    }
+
+    /// Recorded mutations of the key in the order they happened, `None` if mutations are not
+    /// traced
+    pub fn trace_for_key(&self, key: &[u8]) -> Option<Vec<KeyMutation>> {
+        self.mutation_trace
+            .as_ref()
+            .map(|t| t.mutations_of_key(key))
+    }
+
+    /// Builds a bloom filter over all the keys and keeps it updated on put. The filter saved
+    /// along with entries in keydir backend is used instead if it's built by the same options.
+    pub fn enable_bloom_filter(&mut self, options: &BloomFilterOptions) {
+        if let Some(bloom_filter) = self
+            .index
+            .bloom_filter()
+            .and_then(|bs| BloomFilter::decode(&bs, options))
+        {
+            info!(target: "KeyDir", "use bloom filter in keydir backend without rebuilding it");
+            self.bloom_filter = Some(bloom_filter);
+            return;
+        }
+        self.bloom_filter = Some(BloomFilter::new(options));
+        self.rebuild_bloom_filter();
+    }
+
+    /// Rebuilds bloom filter from keys currently in keydir to forget deleted keys
+    pub fn rebuild_bloom_filter(&mut self) {
+        if let Some(bloom_filter) = self.bloom_filter.as_mut() {
+            bloom_filter.clear();
+            for (k, _) in self.index.iter() {
+                bloom_filter.insert(k);
+            }
+        }
+    }
+
+    /// Returns false if the key definitely does not exist. Without bloom filter,
+    /// it's the same as `contains_key`
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        match self.bloom_filter.as_ref() {
+            Some(bloom_filter) => bloom_filter.may_contain(key),
+            None => self.index.contains_key(key),
+        }
+    }
+
+    pub fn bloom_filter_stats(&self) -> Option<BloomFilterStats> {
+        self.bloom_filter.as_ref().map(|b| b.stats())
+    }
+
+    /// Put merged location of a key only when the key was not written or deleted during merge.
+    /// That is the key still exists and is located in a file before `known_max_storage_id`.
+    pub fn checked_put(
+        &mut self,
+        key: &[u8],
+        value: RowLocation,
+        known_max_storage_id: StorageId,
+    ) -> Option<RowLocation> {
+        let pos = self.index.get(key)?;
+        if pos.storage_id >= known_max_storage_id {
+            return None;
+        }
+        let old = self.index.put(key.to_vec(), value);
+        if let Some(trace) = self.mutation_trace.as_mut() {
+            trace.record(MutationCause::MergeApply, key_hash(key), old, Some(value));
+        }
+        old
+    }
+
+    /// Applies locations of keys in `other`, which is built by merge, like `checked_put` in bulk.
+    /// Storage ids grow with time, so a key written or deleted since merge started, which is
+    /// not located before `known_max_storage_id`, keeps its newer location. Returns keys whose
+    /// location changed.
+    pub fn merge_from(&mut self, other: &KeyDir, known_max_storage_id: StorageId) -> Vec<Vec<u8>> {
+        other
+            .iter()
+            .filter_map(|(k, v)| {
+                self.checked_put(k, *v, known_max_storage_id)
+                    .map(|_| k.clone())
+            })
+            .collect()
+    }
+
+    /// Update locations in files which storage ids were changed. Returns keys whose location changed.
+    pub fn shift_storage_ids(
+        &mut self,
+        shifted_storage_ids: &HashMap<StorageId, StorageId>,
+    ) -> Vec<Vec<u8>> {
+        let mut shifted_keys = vec![];
+        if shifted_storage_ids.is_empty() {
+            return shifted_keys;
+        }
+        let shifted = self
+            .index
+            .iter()
+            .filter_map(|(key, pos)| {
+                shifted_storage_ids.get(&pos.storage_id).map(|id| {
+                    (
+                        key.clone(),
+                        RowLocation {
+                            storage_id: *id,
+                            ..*pos
+                        },
+                    )
+                })
+            })
+            .collect::<Vec<(Vec<u8>, RowLocation)>>();
+        for (key, pos) in shifted {
+            if let Some(trace) = self.mutation_trace.as_mut() {
+                let old = self.index.get(&key);
+                trace.record(MutationCause::MergeApply, key_hash(&key), old, Some(pos));
+            }
+            shifted_keys.push(key.clone());
+            self.index.put(key, pos);
+        }
+        shifted_keys
+    }
+
+    /// Generation of row locations in this keydir. It bumps whenever any location is changed
+    /// by something other than writing the key, like merge or dropping the database.
+    pub fn location_generation(&self) -> u64 {
+        self.location_generation
+    }
+
+    pub fn add_location_listener(&mut self, listener: LocationListener) {
+        self.location_listeners.push(listener);
+    }
+
+    /// Bumps location generation. Returns the invalidation to send to listeners once the keydir is unlocked.
+    /// Relocated keys are listed in the invalidation only when there are a few of them.
+    pub fn invalidate_locations(
+        &mut self,
+        relocated_keys: Vec<Vec<u8>>,
+    ) -> PendingLocationInvalidation {
+        self.location_generation = self.location_generation.wrapping_add(1);
+        let generation = self.location_generation;
+        let invalidation =
+            if relocated_keys.is_empty() || relocated_keys.len() > MAX_INVALIDATED_KEYS {
+                LocationInvalidation::All { generation }
+            } else {
+                LocationInvalidation::Keys {
+                    generation,
+                    keys: relocated_keys,
+                }
+            };
+        PendingLocationInvalidation {
+            invalidation,
+            listeners: self.location_listeners.clone(),
+        }
+    }
+
+    /// Bytes of rows referenced by this keydir in each storage
+    pub fn live_bytes(&self) -> HashMap<StorageId, usize> {
+        let mut live_bytes = HashMap::new();
+        for (_, r) in self.index.iter() {
+            *live_bytes.entry(r.storage_id).or_insert(0) += r.row_size;
+        }
+        live_bytes
+    }
+
+    /// Returns bytes of all the rows referenced by keydir
+    pub fn total_live_bytes(&self) -> u64 {
+        self.index.iter().map(|(_, r)| r.row_size as u64).sum()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<RowLocation> {
+        self.index.get(key)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.index.reserve(additional);
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.index.shrink_to_fit();
+    }
+
+    pub fn iter(&self) -> KeyDirIterator<'_> {
+        KeyDirIterator {
+            iter: self.index.iter(),
+        }
+    }
+
+    pub fn delete(&mut self, key: &[u8], cause: MutationCause) -> Option<(Vec<u8>, RowLocation)> {
+        let deleted = self.index.delete(key);
+        if let (Some(trace), Some((_, old))) = (self.mutation_trace.as_mut(), deleted.as_ref()) {
+            trace.record(cause, key_hash(key), Some(*old), None);
+        }
+        deleted
+    }
+
+    /// Time the key was deleted at by `Bitcasky::delete_with_timestamp`, if it's not put since
+    pub fn deletion_timestamp(&self, key: &[u8]) -> Option<u64> {
+        self.deletion_timestamps.get(key).copied()
+    }
+
+    pub fn set_deletion_timestamp(&mut self, key: Vec<u8>, timestamp: u64) {
+        self.deletion_timestamps.insert(key, timestamp);
+    }
+
+    pub fn clear(&mut self) {
+        self.index.clear();
+        self.deletion_timestamps.clear();
+        if let Some(bloom_filter) = self.bloom_filter.as_mut() {
+            bloom_filter.clear();
+        }
+    }
+
+    pub fn get_telemetry_data(&self) -> KeyDirTelemetry {
+        KeyDirTelemetry {
+            number_of_keys: self.len(),
+            capacity: self.index.capacity(),
+            recovery_duration: self.recovery_duration,
+            recovery_stats: self.recovery_stats,
+        }
+    }
+}
+
+pub struct KeyDirIterator<'a> {
+    iter: Box<dyn Iterator<Item = (&'a Vec<u8>, &'a RowLocation)> + 'a>,
+}
+
+impl<'a> Iterator for KeyDirIterator<'a> {
+    type Item = (&'a Vec<u8>, &'a RowLocation);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+/// A random epoch in the high 32 bits and zero in the low 32 bits, which count bumps since open.
+/// Locations cached before the database is reopened are found stale by their generation, unless
+/// the epoch of the last open is picked again.
+fn initial_location_generation() -> u64 {
+    (rand::random::<u32>() as u64) << 32
+}
+
+/// Storage id and data size of data files, entries of keydir are consistent with data files
+/// as long as they are the same
+fn encode_checkpoint(data_files: &[(StorageId, usize)]) -> Vec<u8> {
+    let mut checkpoint = Vec::with_capacity(data_files.len() * 12);
+    for (storage_id, data_size) in data_files {
+        checkpoint.extend_from_slice(&storage_id.to_be_bytes());
+        checkpoint.extend_from_slice(&(*data_size as u64).to_be_bytes());
+    }
+    checkpoint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn location(storage_id: StorageId, row_offset: usize) -> RowLocation {
+        RowLocation {
+            storage_id,
+            row_offset,
+            row_size: 10,
+        }
+    }
+
+    #[test]
+    fn test_merge_from_keeps_writes_during_merge() {
+        let known_max_storage_id = 3;
+        let mut kd = KeyDir::new_empty_key_dir();
+        for (i, k) in ["k1", "k2", "k3"].iter().enumerate() {
+            kd.put(k.as_bytes().to_vec(), location(i as StorageId, 0));
+        }
+
+        // merge rewrites all the keys while k2 is put again and k3 is deleted
+        let mut merged = KeyDir::new_empty_key_dir();
+        for (i, k) in ["k1", "k2", "k3"].iter().enumerate() {
+            merged.put(k.as_bytes().to_vec(), location(10, i * 10));
+        }
+        kd.put(b"k2".to_vec(), location(known_max_storage_id, 100));
+        kd.delete(b"k3", MutationCause::Delete);
+
+        let relocated = kd.merge_from(&merged, known_max_storage_id);
+        assert_eq!(vec![b"k1".to_vec()], relocated);
+        assert_eq!(Some(location(10, 0)), kd.get(b"k1"));
+        assert_eq!(Some(location(known_max_storage_id, 100)), kd.get(b"k2"));
+        assert_eq!(None, kd.get(b"k3"));
+    }
+}
@@ -0,0 +1,207 @@
+use std::{io, path::Path};
+
+use crate::database::RowLocation;
+use crate::error::{BitcaskyError, BitcaskyResult};
+use crate::logging::{error, info};
+
+use super::backend::{KeyDirBackend, MemoryKeyDirBackend};
+
+const LOCATIONS_TREE: &str = "locations";
+const CHECKPOINT_KEY: &str = "checkpoint";
+const LOCATION_SIZE: usize = 20;
+
+/// Mirrors entries to a sled database under a directory, so they are loaded from it on open
+/// instead of rebuilt from data files after the database was closed gracefully. Entries are
+/// also kept in memory and read from there, so sled is only written.
+///
+/// A failed write to sled only loses the mirror: the checkpoint is not saved on close and
+/// entries are rebuilt from data files on next open.
+pub struct SledKeyDirBackend {
+    memory: MemoryKeyDirBackend,
+    db: sled::Db,
+    locations: sled::Tree,
+    mirror_failed: bool,
+}
+
+impl SledKeyDirBackend {
+    /// Opens the sled database under the directory. Use a directory only for the keydir of one
+    /// database.
+    pub fn open<P: AsRef<Path>>(directory: P) -> BitcaskyResult<SledKeyDirBackend> {
+        // without background flusher, the directory lock is released as soon as the backend is
+        // dropped, so the database can be reopened right after closed. Entries are flushed
+        // along with the checkpoint anyway
+        let db = sled::Config::new()
+            .path(directory.as_ref())
+            .flush_every_ms(None)
+            .open()
+            .map_err(to_bitcasky_error)?;
+        let locations = db.open_tree(LOCATIONS_TREE).map_err(to_bitcasky_error)?;
+        let mut memory = MemoryKeyDirBackend::default();
+        // entries without checkpoint are rebuilt anyway
+        if db.contains_key(CHECKPOINT_KEY).map_err(to_bitcasky_error)? {
+            for entry in locations.iter() {
+                let (k, v) = entry.map_err(to_bitcasky_error)?;
+                memory.put(k.to_vec(), decode_location(&v)?);
+            }
+            info!(target: "KeyDir", "loaded {} keys from keydir under directory: {:?}", memory.len(), directory.as_ref());
+        }
+        Ok(SledKeyDirBackend {
+            memory,
+            db,
+            locations,
+            mirror_failed: false,
+        })
+    }
+
+    fn mirror<T>(&mut self, ret: sled::Result<T>) {
+        if let Err(e) = ret {
+            if !self.mirror_failed {
+                error!(target: "KeyDir", "write keydir to sled failed, it's rebuilt from data files on next open. Error: {}", e);
+            }
+            self.mirror_failed = true;
+        }
+    }
+}
+
+impl KeyDirBackend for SledKeyDirBackend {
+    fn put(&mut self, key: Vec<u8>, location: RowLocation) -> Option<RowLocation> {
+        let ret = self
+            .locations
+            .insert(key.as_slice(), &encode_location(&location));
+        self.mirror(ret);
+        self.memory.put(key, location)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<RowLocation> {
+        self.memory.get(key)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<(Vec<u8>, RowLocation)> {
+        let ret = self.locations.remove(key);
+        self.mirror(ret);
+        self.memory.delete(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Vec<u8>, &RowLocation)> + '_> {
+        self.memory.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.memory.contains_key(key)
+    }
+
+    fn clear(&mut self) {
+        let ret = self.locations.clear();
+        self.mirror(ret);
+        self.memory.clear();
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.memory.reserve(additional);
+    }
+
+    fn capacity(&self) -> usize {
+        self.memory.capacity()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.memory.shrink_to_fit();
+    }
+
+    fn to_memory(&self) -> MemoryKeyDirBackend {
+        self.memory.clone()
+    }
+
+    fn checkpoint(&self) -> Option<Vec<u8>> {
+        match self.db.get(CHECKPOINT_KEY) {
+            Ok(c) => c.map(|c| c.to_vec()),
+            Err(e) => {
+                error!(target: "KeyDir", "read keydir checkpoint from sled failed: {}", e);
+                None
+            }
+        }
+    }
+
+    fn set_checkpoint(&mut self, checkpoint: Option<&[u8]>) -> BitcaskyResult<()> {
+        let ret = match checkpoint {
+            Some(_) if self.mirror_failed => self.db.remove(CHECKPOINT_KEY),
+            Some(c) => self.db.insert(CHECKPOINT_KEY, c),
+            None => self.db.remove(CHECKPOINT_KEY),
+        };
+        ret.map_err(to_bitcasky_error)?;
+        // entries are flushed along with the checkpoint, and the checkpoint is cleared durably
+        // before entries change
+        self.db.flush().map_err(to_bitcasky_error)?;
+        Ok(())
+    }
+}
+
+fn encode_location(location: &RowLocation) -> [u8; LOCATION_SIZE] {
+    let mut bs = [0; LOCATION_SIZE];
+    bs[0..4].copy_from_slice(&location.storage_id.to_be_bytes());
+    bs[4..12].copy_from_slice(&(location.row_offset as u64).to_be_bytes());
+    bs[12..20].copy_from_slice(&(location.row_size as u64).to_be_bytes());
+    bs
+}
+
+fn decode_location(bs: &[u8]) -> BitcaskyResult<RowLocation> {
+    if bs.len() != LOCATION_SIZE {
+        return Err(BitcaskyError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid keydir entry size: {}", bs.len()),
+        )));
+    }
+    Ok(RowLocation {
+        storage_id: u32::from_be_bytes(bs[0..4].try_into().unwrap()),
+        row_offset: u64::from_be_bytes(bs[4..12].try_into().unwrap()) as usize,
+        row_size: u64::from_be_bytes(bs[12..20].try_into().unwrap()) as usize,
+    })
+}
+
+fn to_bitcasky_error(e: sled::Error) -> BitcaskyError {
+    BitcaskyError::IoError(e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::get_temporary_directory_path;
+    use test_log::test;
+
+    fn location(i: usize) -> RowLocation {
+        RowLocation {
+            storage_id: i as u32,
+            row_offset: i * 64,
+            row_size: 64,
+        }
+    }
+
+    #[test]
+    fn test_entries_loaded_with_checkpoint() {
+        let dir = get_temporary_directory_path();
+        {
+            let mut backend = SledKeyDirBackend::open(&dir).unwrap();
+            for i in 0..10 {
+                backend.put(format!("k{}", i).into_bytes(), location(i));
+            }
+            backend.delete(b"k0");
+            backend.set_checkpoint(Some(b"checkpoint")).unwrap();
+        }
+        {
+            let mut backend = SledKeyDirBackend::open(&dir).unwrap();
+            assert_eq!(Some(b"checkpoint".to_vec()), backend.checkpoint());
+            assert_eq!(9, backend.len());
+            assert_eq!(None, backend.get(b"k0"));
+            assert_eq!(Some(location(9)), backend.get(b"k9"));
+            backend.set_checkpoint(None).unwrap();
+        }
+        let backend = SledKeyDirBackend::open(&dir).unwrap();
+        assert_eq!(None, backend.checkpoint());
+        assert!(backend.is_empty());
+    }
+}
@@ -0,0 +1,377 @@
+//! Keydir snapshot written by `SnapshotKeyDirBackend` when the database is closed gracefully.
+//!
+//! Integers are in big endian:
+//!
+//! ```text
+//! header: magic "BCKYKDSN" | version: u8 | checkpoint size: u32 | checkpoint | entry count: u64
+//! entry:  key size: u32 | key | storage id: u32 | row offset: u64 | row size: u64
+//! bloom:  bloom filter size: u64 | bloom filter by `BloomFilter::encode`, size 0 without it
+//! footer: CRC-32C of all the bytes before it: u32
+//! ```
+//!
+//! Snapshots of version 1 have no bloom filter after entries.
+//!
+//! Entries have no write timestamp on purpose. Keydir only keeps row locations, and write
+//! timestamps of rows stay in data files, where `Bitcasky::put_with_timestamp` reads them from.
+//! Writing them to the snapshot would read every row on close, and nothing would use them on
+//! open.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::archive::{ChecksumReader, ChecksumWriter};
+use crate::database::RowLocation;
+use crate::error::{BitcaskyError, BitcaskyResult};
+use crate::fs::FileType;
+use crate::logging::{info, warn};
+
+use super::backend::{KeyDirBackend, MemoryKeyDirBackend};
+
+const MAGIC: &[u8; 8] = b"BCKYKDSN";
+const SNAPSHOT_VERSION: u8 = 2;
+
+/// Keeps entries in memory and writes all of them to a snapshot file under the database
+/// directory when the database is closed gracefully, so they are loaded from it on next open
+/// instead of rebuilt from data files. Unlike `SledKeyDirBackend`, writes cost nothing more than
+/// `MemoryKeyDirBackend`, but the whole keydir is written on close.
+///
+/// The snapshot file is deleted on the first change of entries after open, so it's never loaded
+/// once entries went ahead of it.
+pub struct SnapshotKeyDirBackend {
+    memory: MemoryKeyDirBackend,
+    path: PathBuf,
+    /// Checkpoint of the snapshot file on disk, as long as entries are not changed since it's
+    /// loaded or written
+    snapshot_checkpoint: Option<Vec<u8>>,
+    /// Encoded bloom filter loaded along with entries, or kept to be written on next
+    /// `set_checkpoint`
+    bloom_filter: Option<Vec<u8>>,
+    /// Whether `bloom_filter` is in the snapshot file on disk
+    bloom_filter_saved: bool,
+}
+
+impl SnapshotKeyDirBackend {
+    /// Loads entries from the snapshot file under the database directory if there's one. A
+    /// corrupted snapshot file is ignored, so entries are rebuilt from data files.
+    pub fn open<P: AsRef<Path>>(database_dir: P) -> BitcaskyResult<SnapshotKeyDirBackend> {
+        let path = FileType::KeydirSnapshot.get_path(database_dir, None);
+        let mut backend = SnapshotKeyDirBackend {
+            memory: MemoryKeyDirBackend::default(),
+            path,
+            snapshot_checkpoint: None,
+            bloom_filter: None,
+            bloom_filter_saved: false,
+        };
+        let file = match File::open(&backend.path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(backend),
+            Err(e) => return Err(e.into()),
+        };
+        match read_snapshot(file, &mut backend.memory) {
+            Ok((checkpoint, bloom_filter)) => {
+                info!(target: "KeyDir", "loaded {} keys from keydir snapshot: {:?}", backend.memory.len(), backend.path);
+                backend.snapshot_checkpoint = Some(checkpoint);
+                backend.bloom_filter = bloom_filter;
+                backend.bloom_filter_saved = true;
+            }
+            Err(e) => {
+                warn!(target: "KeyDir", "ignore corrupted keydir snapshot: {:?}, error: {}", backend.path, e);
+                backend.memory.clear();
+                backend.remove_snapshot();
+            }
+        }
+        Ok(backend)
+    }
+
+    fn remove_snapshot(&mut self) {
+        self.snapshot_checkpoint = None;
+        // a bloom filter loaded along with entries misses keys put from now on
+        self.bloom_filter = None;
+        self.bloom_filter_saved = false;
+        if let Err(e) = fs::remove_file(&self.path) {
+            // a snapshot left behind is not loaded anyway, its checkpoint mismatches data files
+            // changed along with entries
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!(target: "KeyDir", "delete keydir snapshot: {:?} failed with error: {}", self.path, e);
+            }
+        }
+    }
+
+    fn before_change(&mut self) {
+        if self.snapshot_checkpoint.is_some() {
+            self.remove_snapshot();
+        }
+    }
+
+    /// Writes to a temporary file then renames it, so a snapshot file is always complete
+    fn write_snapshot(&self, checkpoint: &[u8]) -> BitcaskyResult<()> {
+        let file_name = self
+            .path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .expect("File name required");
+        let tmp_path = self.path.with_file_name(format!("tmp-{file_name}"));
+
+        let mut writer = ChecksumWriter {
+            inner: BufWriter::new(File::create(&tmp_path)?),
+            crc: 0,
+        };
+        writer.write_all(MAGIC)?;
+        writer.write_u8(SNAPSHOT_VERSION)?;
+        writer.write_u32::<BigEndian>(checkpoint.len() as u32)?;
+        writer.write_all(checkpoint)?;
+        writer.write_u64::<BigEndian>(self.memory.len() as u64)?;
+        for (k, location) in self.memory.iter() {
+            writer.write_u32::<BigEndian>(k.len() as u32)?;
+            writer.write_all(k)?;
+            writer.write_u32::<BigEndian>(location.storage_id)?;
+            writer.write_u64::<BigEndian>(location.row_offset as u64)?;
+            writer.write_u64::<BigEndian>(location.row_size as u64)?;
+        }
+        let bloom_filter = self.bloom_filter.as_deref().unwrap_or_default();
+        writer.write_u64::<BigEndian>(bloom_filter.len() as u64)?;
+        writer.write_all(bloom_filter)?;
+        let crc = writer.crc;
+        writer.write_u32::<BigEndian>(crc)?;
+        let file = writer.inner.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl KeyDirBackend for SnapshotKeyDirBackend {
+    fn put(&mut self, key: Vec<u8>, location: RowLocation) -> Option<RowLocation> {
+        self.before_change();
+        self.memory.put(key, location)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<RowLocation> {
+        self.memory.get(key)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<(Vec<u8>, RowLocation)> {
+        self.before_change();
+        self.memory.delete(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Vec<u8>, &RowLocation)> + '_> {
+        self.memory.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.memory.contains_key(key)
+    }
+
+    fn clear(&mut self) {
+        self.before_change();
+        self.memory.clear();
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.memory.reserve(additional);
+    }
+
+    fn capacity(&self) -> usize {
+        self.memory.capacity()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.memory.shrink_to_fit();
+    }
+
+    fn to_memory(&self) -> MemoryKeyDirBackend {
+        self.memory.clone()
+    }
+
+    fn checkpoint(&self) -> Option<Vec<u8>> {
+        self.snapshot_checkpoint.clone()
+    }
+
+    fn bloom_filter(&self) -> Option<Vec<u8>> {
+        self.bloom_filter.clone()
+    }
+
+    fn set_bloom_filter(&mut self, bloom_filter: Option<&[u8]>) {
+        if self.bloom_filter.as_deref() != bloom_filter {
+            self.bloom_filter = bloom_filter.map(|b| b.to_vec());
+            self.bloom_filter_saved = false;
+        }
+    }
+
+    fn set_checkpoint(&mut self, checkpoint: Option<&[u8]>) -> BitcaskyResult<()> {
+        // cleared on open, the snapshot file is kept until entries change instead, so a
+        // database opened and closed without writes does not write it again
+        let Some(checkpoint) = checkpoint else {
+            return Ok(());
+        };
+        if self.snapshot_checkpoint.as_deref() == Some(checkpoint) && self.bloom_filter_saved {
+            return Ok(());
+        }
+        self.write_snapshot(checkpoint)?;
+        self.snapshot_checkpoint = Some(checkpoint.to_vec());
+        self.bloom_filter_saved = true;
+        Ok(())
+    }
+}
+
+/// Reads entries of the snapshot to memory and returns its checkpoint and bloom filter
+fn read_snapshot(
+    file: File,
+    memory: &mut MemoryKeyDirBackend,
+) -> BitcaskyResult<(Vec<u8>, Option<Vec<u8>>)> {
+    let mut reader = ChecksumReader {
+        inner: BufReader::new(file),
+        crc: 0,
+    };
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_snapshot("unknown magic"));
+    }
+    let version = reader.read_u8()?;
+    if version != 1 && version != SNAPSHOT_VERSION {
+        return Err(invalid_snapshot(&format!(
+            "unsupported version: {}",
+            version
+        )));
+    }
+    let checkpoint = read_bytes(&mut reader)?;
+    let entries = reader.read_u64::<BigEndian>()?;
+    for _ in 0..entries {
+        let key = read_bytes(&mut reader)?;
+        let location = RowLocation {
+            storage_id: reader.read_u32::<BigEndian>()?,
+            row_offset: reader.read_u64::<BigEndian>()? as usize,
+            row_size: reader.read_u64::<BigEndian>()? as usize,
+        };
+        memory.put(key, location);
+    }
+    let bloom_filter = if version == 1 {
+        None
+    } else {
+        let size = reader.read_u64::<BigEndian>()?;
+        Some(read_sized_bytes(&mut reader, size)?).filter(|b| !b.is_empty())
+    };
+    let crc = reader.crc;
+    if reader.read_u32::<BigEndian>()? != crc {
+        return Err(invalid_snapshot("checksum mismatch"));
+    }
+    Ok((checkpoint, bloom_filter))
+}
+
+/// Reads bytes prefixed by their size
+fn read_bytes<R: Read>(reader: &mut R) -> BitcaskyResult<Vec<u8>> {
+    let size = reader.read_u32::<BigEndian>()? as u64;
+    read_sized_bytes(reader, size)
+}
+
+/// They are not allocated ahead by the size, which is garbage in a corrupted snapshot
+fn read_sized_bytes<R: Read>(reader: &mut R, size: u64) -> BitcaskyResult<Vec<u8>> {
+    let mut bs = vec![];
+    reader.take(size).read_to_end(&mut bs)?;
+    if bs.len() as u64 != size {
+        return Err(BitcaskyError::IoError(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(bs)
+}
+
+fn invalid_snapshot(reason: &str) -> BitcaskyError {
+    BitcaskyError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid keydir snapshot, {}", reason),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::get_temporary_directory_path;
+    use test_log::test;
+
+    fn location(i: usize) -> RowLocation {
+        RowLocation {
+            storage_id: i as u32,
+            row_offset: i * 64,
+            row_size: 64,
+        }
+    }
+
+    fn write_entries(dir: &Path) {
+        let mut backend = SnapshotKeyDirBackend::open(dir).unwrap();
+        for i in 0..10 {
+            backend.put(format!("k{}", i).into_bytes(), location(i));
+        }
+        backend.delete(b"k0");
+        backend.set_bloom_filter(Some(b"bloom filter"));
+        backend.set_checkpoint(Some(b"checkpoint")).unwrap();
+    }
+
+    #[test]
+    fn test_entries_loaded_with_checkpoint() {
+        let dir = get_temporary_directory_path();
+        write_entries(&dir);
+
+        let mut backend = SnapshotKeyDirBackend::open(&dir).unwrap();
+        assert_eq!(Some(b"checkpoint".to_vec()), backend.checkpoint());
+        assert_eq!(9, backend.len());
+        assert_eq!(None, backend.get(b"k0"));
+        assert_eq!(Some(location(9)), backend.get(b"k9"));
+        assert_eq!(Some(b"bloom filter".to_vec()), backend.bloom_filter());
+
+        // the snapshot is kept until entries change
+        backend.set_checkpoint(None).unwrap();
+        assert!(FileType::KeydirSnapshot.get_path(&dir, None).exists());
+        backend.put(b"k0".to_vec(), location(0));
+        assert_eq!(None, backend.checkpoint());
+        assert_eq!(None, backend.bloom_filter());
+        assert!(!FileType::KeydirSnapshot.get_path(&dir, None).exists());
+
+        let backend = SnapshotKeyDirBackend::open(&dir).unwrap();
+        assert_eq!(None, backend.checkpoint());
+        assert!(backend.is_empty());
+    }
+
+    #[test]
+    fn test_corrupted_snapshot_ignored() {
+        let dir = get_temporary_directory_path();
+        write_entries(&dir);
+
+        let path = FileType::KeydirSnapshot.get_path(&dir, None);
+        let mut bs = fs::read(&path).unwrap();
+        let i = bs.len() / 2;
+        bs[i] ^= 0xff;
+        fs::write(&path, bs).unwrap();
+
+        let backend = SnapshotKeyDirBackend::open(&dir).unwrap();
+        assert_eq!(None, backend.checkpoint());
+        assert!(backend.is_empty());
+        assert!(!path.exists());
+    }
+    #[test]
+    fn test_snapshot_rewritten_when_bloom_filter_changed() {
+        let dir = get_temporary_directory_path();
+        write_entries(&dir);
+
+        let mut backend = SnapshotKeyDirBackend::open(&dir).unwrap();
+        backend.set_checkpoint(None).unwrap();
+        backend.set_bloom_filter(None);
+        backend.set_checkpoint(Some(b"checkpoint")).unwrap();
+
+        let backend = SnapshotKeyDirBackend::open(&dir).unwrap();
+        assert_eq!(Some(b"checkpoint".to_vec()), backend.checkpoint());
+        assert_eq!(9, backend.len());
+        assert_eq!(None, backend.bloom_filter());
+    }
+}
@@ -0,0 +1,276 @@
+//! Order preserving encoding of composite keys, like tenant id + timestamp + suffix. Keys built
+//! by [`KeyBuilder`] compare in lexicographic order of bytes the same as their components compare
+//! in tuple order, so they work with `Bitcasky::scan` and `Bitcasky::scan_prefix`. Keys are
+//! decoded back into components by [`KeyReader`], which has to read the components with the
+//! same types in the same order as they were built.
+//!
+//! Components are encoded as:
+//!
+//! ```text
+//! u32 / u64: big endian
+//! i64:       big endian with the sign bit flipped, so negative numbers sort first
+//! bytes:     0x00 escaped as 0x00 0xff, terminated by 0x00 0x01
+//! ```
+//!
+//! Bytes are escaped instead of length prefixed, as a length prefix sorts `"b"` before `"ab"`.
+//! The terminator sorts before any escaped byte, so a segment sorts before all the longer
+//! segments starting with it, whatever components follow.
+//!
+//! Keys built from only some of the leading components are prefixes of keys built from all
+//! of them, and can be passed to `Bitcasky::scan_prefix`.
+
+use crate::error::{BitcaskyError, BitcaskyResult};
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+const SIGN_BIT: u64 = 1 << 63;
+
+/// Builds an order preserving composite key
+#[derive(Debug, Default, Clone)]
+pub struct KeyBuilder {
+    buf: Vec<u8>,
+}
+
+impl KeyBuilder {
+    pub fn new() -> KeyBuilder {
+        KeyBuilder::default()
+    }
+
+    /// Appends a byte segment of any length, which may contain any byte
+    pub fn bytes<B: AsRef<[u8]>>(mut self, bytes: B) -> KeyBuilder {
+        for b in bytes.as_ref() {
+            self.buf.push(*b);
+            if *b == ESCAPE {
+                self.buf.push(ESCAPED_ZERO);
+            }
+        }
+        self.buf.extend_from_slice(&[ESCAPE, TERMINATOR]);
+        self
+    }
+
+    pub fn u32_be(mut self, v: u32) -> KeyBuilder {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u64_be(mut self, v: u64) -> KeyBuilder {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn i64_be(mut self, v: i64) -> KeyBuilder {
+        self.buf
+            .extend_from_slice(&((v as u64) ^ SIGN_BIT).to_be_bytes());
+        self
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Decodes components of a key built by `KeyBuilder`, returns `InvalidKeyEncoding` if the key
+/// does not match the components read
+#[derive(Debug, Clone)]
+pub struct KeyReader<'a> {
+    key: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> KeyReader<'a> {
+    pub fn new(key: &'a [u8]) -> KeyReader<'a> {
+        KeyReader { key, offset: 0 }
+    }
+
+    pub fn bytes(&mut self) -> BitcaskyResult<Vec<u8>> {
+        let mut segment = vec![];
+        let mut i = self.offset;
+        while i < self.key.len() {
+            let b = self.key[i];
+            if b != ESCAPE {
+                segment.push(b);
+                i += 1;
+                continue;
+            }
+            match self.key.get(i + 1) {
+                Some(&ESCAPED_ZERO) => {
+                    segment.push(ESCAPE);
+                    i += 2;
+                }
+                Some(&TERMINATOR) => {
+                    self.offset = i + 2;
+                    return Ok(segment);
+                }
+                Some(b) => {
+                    return Err(invalid_key(format!(
+                        "unexpected byte: {} after 0x00 at offset: {}",
+                        b,
+                        i + 1
+                    )))
+                }
+                None => break,
+            }
+        }
+        Err(invalid_key(format!(
+            "byte segment at offset: {} is not terminated",
+            self.offset
+        )))
+    }
+
+    pub fn u32_be(&mut self) -> BitcaskyResult<u32> {
+        Ok(u32::from_be_bytes(self.fixed()?))
+    }
+
+    pub fn u64_be(&mut self) -> BitcaskyResult<u64> {
+        Ok(u64::from_be_bytes(self.fixed()?))
+    }
+
+    pub fn i64_be(&mut self) -> BitcaskyResult<i64> {
+        Ok((u64::from_be_bytes(self.fixed()?) ^ SIGN_BIT) as i64)
+    }
+
+    /// Returns true if all the components are read
+    pub fn is_empty(&self) -> bool {
+        self.offset == self.key.len()
+    }
+
+    /// Returns an error if there are bytes not read yet
+    pub fn finish(self) -> BitcaskyResult<()> {
+        if !self.is_empty() {
+            return Err(invalid_key(format!(
+                "{} bytes left after offset: {}",
+                self.key.len() - self.offset,
+                self.offset
+            )));
+        }
+        Ok(())
+    }
+
+    fn fixed<const N: usize>(&mut self) -> BitcaskyResult<[u8; N]> {
+        let end = self.offset + N;
+        if end > self.key.len() {
+            return Err(invalid_key(format!(
+                "expect {} bytes at offset: {}, but only {} left",
+                N,
+                self.offset,
+                self.key.len() - self.offset
+            )));
+        }
+        let mut bs = [0; N];
+        bs.copy_from_slice(&self.key[self.offset..end]);
+        self.offset = end;
+        Ok(bs)
+    }
+}
+
+fn invalid_key(reason: String) -> BitcaskyError {
+    BitcaskyError::InvalidKeyEncoding(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use test_log::test;
+
+    type Tuple = (Vec<u8>, u64, i64, Vec<u8>, u32);
+
+    fn encode(t: &Tuple) -> Vec<u8> {
+        KeyBuilder::new()
+            .bytes(&t.0)
+            .u64_be(t.1)
+            .i64_be(t.2)
+            .bytes(&t.3)
+            .u32_be(t.4)
+            .build()
+    }
+
+    fn decode(key: &[u8]) -> BitcaskyResult<Tuple> {
+        let mut reader = KeyReader::new(key);
+        let t = (
+            reader.bytes()?,
+            reader.u64_be()?,
+            reader.i64_be()?,
+            reader.bytes()?,
+            reader.u32_be()?,
+        );
+        reader.finish()?;
+        Ok(t)
+    }
+
+    /// Bytes drawn mostly from the ones used by the encoding, so segments often share prefixes
+    /// and contain escapes
+    fn random_bytes(rng: &mut StdRng) -> Vec<u8> {
+        let len = rng.gen_range(0..4);
+        (0..len)
+            .map(|_| [0x00, 0x01, 0xff, b'a'][rng.gen_range(0..4)])
+            .collect()
+    }
+
+    fn random_u64(rng: &mut StdRng) -> u64 {
+        [0, 1, u64::MAX, 1 << 63, rng.gen()][rng.gen_range(0..5)]
+    }
+
+    fn random_tuple(rng: &mut StdRng) -> Tuple {
+        (
+            random_bytes(rng),
+            random_u64(rng),
+            random_u64(rng) as i64,
+            random_bytes(rng),
+            random_u64(rng) as u32,
+        )
+    }
+
+    #[test]
+    fn test_encoding_order_equals_tuple_order() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20000 {
+            let a = random_tuple(&mut rng);
+            let b = random_tuple(&mut rng);
+            let (ka, kb) = (encode(&a), encode(&b));
+            assert_eq!(a.cmp(&b), ka.cmp(&kb), "{:?} {:?}", a, b);
+            assert_eq!(a, decode(&ka).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_partial_key_is_prefix() {
+        let prefix = KeyBuilder::new().bytes(b"tenant").build();
+        assert!(KeyBuilder::new()
+            .bytes(b"tenant")
+            .u64_be(100)
+            .build()
+            .starts_with(&prefix));
+        assert!(!KeyBuilder::new()
+            .bytes(b"tenant2")
+            .u64_be(100)
+            .build()
+            .starts_with(&prefix));
+    }
+
+    #[test]
+    fn test_decode_invalid_key() {
+        let key = KeyBuilder::new().bytes([0, 1]).u64_be(1).build();
+        assert_eq!(vec![0, 0xff, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1], key);
+
+        let mut reader = KeyReader::new(&key);
+        assert_eq!(vec![0, 1], reader.bytes().unwrap());
+        let mut partial = reader.clone();
+        partial.u32_be().unwrap();
+        assert_matches!(partial.finish(), Err(BitcaskyError::InvalidKeyEncoding(_)));
+        assert_eq!(1, reader.u64_be().unwrap());
+        assert!(reader.is_empty());
+        assert_matches!(reader.u32_be(), Err(BitcaskyError::InvalidKeyEncoding(_)));
+
+        assert_matches!(
+            KeyReader::new(b"abc").bytes(),
+            Err(BitcaskyError::InvalidKeyEncoding(_))
+        );
+        assert_matches!(
+            KeyReader::new(&[b'a', 0, 2]).bytes(),
+            Err(BitcaskyError::InvalidKeyEncoding(_))
+        );
+    }
+}
@@ -2,18 +2,45 @@
 #[macro_use]
 extern crate assert_matches;
 
+mod archive;
+mod bloom;
+mod bucket;
 mod clock;
+mod csv;
 mod database;
+mod expiry;
 mod formatter;
 mod fs;
 mod keydir;
+mod logging;
 mod merge;
+mod mutation_trace;
+mod scan;
+#[cfg(all(unix, feature = "signal-handling"))]
+mod signal;
+mod snapshot;
 mod storage_id;
 mod test_utils;
 mod tombstone;
+mod tuning;
 
+pub mod admin;
+#[cfg(feature = "tokio")]
+pub mod async_bitcasky;
 pub mod bitcasky;
+pub mod codec;
 pub mod error;
+pub mod keys;
+pub mod format {
+    //! Pluggable encoding of rows in data files. See [`RowFormatter`] for what a custom
+    //! formatter has to do and what is expected to stay stable.
+    pub use crate::formatter::{
+        register_row_formatter, ChecksumAlgorithm, FormatterError, RowFormatter, RowHeader,
+        RowMeta, RowToWrite, MIN_CUSTOM_FORMATTER_VERSION,
+    };
+}
+pub mod lock_stats;
+pub mod maintenance;
 pub mod options;
 #[cfg(feature = "internals")]
 pub mod internals {
@@ -21,5 +48,6 @@ pub mod internals {
     //! `internals` feature only.
     pub use crate::database::*;
     pub use crate::formatter::*;
+    pub use crate::fs::{list_storage_files, FileType, StorageFiles};
     pub use crate::test_utils::*;
 }
@@ -0,0 +1,303 @@
+//! Wait and hold time of the locks on the read and write paths. Only recorded with the
+//! `instrument-locks` feature, without it the lock wrappers here are plain locks.
+
+use std::ops::{Deref, DerefMut};
+
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(feature = "instrument-locks")]
+pub use self::instrumented::{DurationHistogram, LockStat, LockStats};
+#[cfg(feature = "instrument-locks")]
+use self::instrumented::{LockTimes, TimedSection};
+
+/// Where a lock records its wait and hold time. Locks of the same kind can share a timer
+/// by cloning it. Empty when the `instrument-locks` feature is off.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LockTimer {
+    #[cfg(feature = "instrument-locks")]
+    times: std::sync::Arc<LockTimes>,
+}
+
+impl LockTimer {
+    #[cfg(feature = "instrument-locks")]
+    pub fn stat(&self) -> LockStat {
+        self.times.stat()
+    }
+}
+
+/// Guard of `TimedMutex` and `TimedRwLock`, records hold time on drop
+pub(crate) struct TimedGuard<'a, G> {
+    guard: G,
+    #[cfg(feature = "instrument-locks")]
+    _section: TimedSection<'a>,
+    #[cfg(not(feature = "instrument-locks"))]
+    _timer: std::marker::PhantomData<&'a LockTimer>,
+}
+
+impl<'a, G> TimedGuard<'a, G> {
+    #[cfg(feature = "instrument-locks")]
+    fn acquire(timer: &'a LockTimer, acquire: impl FnOnce() -> G) -> Self {
+        let (guard, section) = TimedSection::enter(&timer.times, acquire);
+        TimedGuard {
+            guard,
+            _section: section,
+        }
+    }
+
+    #[cfg(not(feature = "instrument-locks"))]
+    #[inline(always)]
+    fn acquire(_timer: &'a LockTimer, acquire: impl FnOnce() -> G) -> Self {
+        TimedGuard {
+            guard: acquire(),
+            _timer: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<G: Deref> Deref for TimedGuard<'_, G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for TimedGuard<'_, G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TimedMutex<T> {
+    inner: Mutex<T>,
+    timer: LockTimer,
+}
+
+impl<T> TimedMutex<T> {
+    pub fn new(value: T, timer: LockTimer) -> Self {
+        TimedMutex {
+            inner: Mutex::new(value),
+            timer,
+        }
+    }
+
+    #[inline]
+    pub fn lock(&self) -> TimedGuard<'_, MutexGuard<'_, T>> {
+        TimedGuard::acquire(&self.timer, || self.inner.lock())
+    }
+
+    /// Locks without waiting, which is not timed
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.inner.try_lock()
+    }
+
+    #[cfg(feature = "instrument-locks")]
+    pub fn stat(&self) -> LockStat {
+        self.timer.stat()
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TimedRwLock<T> {
+    inner: RwLock<T>,
+    timer: LockTimer,
+}
+
+impl<T> TimedRwLock<T> {
+    pub fn new(value: T, timer: LockTimer) -> Self {
+        TimedRwLock {
+            inner: RwLock::new(value),
+            timer,
+        }
+    }
+
+    #[inline]
+    pub fn read(&self) -> TimedGuard<'_, RwLockReadGuard<'_, T>> {
+        TimedGuard::acquire(&self.timer, || self.inner.read())
+    }
+
+    #[inline]
+    pub fn write(&self) -> TimedGuard<'_, RwLockWriteGuard<'_, T>> {
+        TimedGuard::acquire(&self.timer, || self.inner.write())
+    }
+
+    #[cfg(feature = "instrument-locks")]
+    pub fn stat(&self) -> LockStat {
+        self.timer.stat()
+    }
+}
+
+#[cfg(feature = "instrument-locks")]
+mod instrumented {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::{Duration, Instant},
+    };
+
+    /// Bucket `i` counts durations shorter than 2^i microseconds and not shorter than the
+    /// bound of the previous bucket. The last bucket counts everything longer.
+    const HISTOGRAM_BUCKETS: usize = 32;
+
+    /// Snapshot of how long a lock is waited for or held
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct DurationHistogram {
+        /// Count of durations in each bucket, bucket `i` ends at 2^i microseconds
+        pub buckets: Vec<u64>,
+        pub count: u64,
+        pub total_micros: u64,
+        pub max_micros: u64,
+    }
+
+    impl DurationHistogram {
+        /// How many recorded durations are at least `d`, rounded to bucket bounds so
+        /// durations in the bucket containing `d` are counted
+        pub fn count_at_least(&self, d: Duration) -> u64 {
+            self.buckets[bucket_of(d)..].iter().sum()
+        }
+
+        /// Upper bound of the bucket containing the `q` quantile, `q` is in [0, 1]
+        pub fn quantile(&self, q: f64) -> Duration {
+            let target = (self.count as f64 * q.clamp(0.0, 1.0)).ceil() as u64;
+            let mut seen = 0;
+            for (i, n) in self.buckets.iter().enumerate() {
+                seen += n;
+                if seen >= target && *n > 0 {
+                    return Duration::from_micros(1 << i);
+                }
+            }
+            Duration::from_micros(self.max_micros)
+        }
+    }
+
+    /// Wait time and hold time of a lock
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct LockStat {
+        /// Time spent waiting to acquire the lock
+        pub wait: DurationHistogram,
+        /// Time the lock is held after acquired
+        pub hold: DurationHistogram,
+    }
+
+    /// Lock statistics since the database was opened
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct LockStats {
+        pub keydir: LockStat,
+        pub writing_storage: LockStat,
+        /// Aggregated over the locks of all the stable storages
+        pub stable_storages: LockStat,
+    }
+
+    #[derive(Debug, Default)]
+    struct AtomicHistogram {
+        buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+        count: AtomicU64,
+        total_micros: AtomicU64,
+        max_micros: AtomicU64,
+    }
+
+    impl AtomicHistogram {
+        fn record(&self, d: Duration) {
+            let micros = d.as_micros() as u64;
+            self.buckets[bucket_of(d)].fetch_add(1, Ordering::Relaxed);
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.total_micros.fetch_add(micros, Ordering::Relaxed);
+            self.max_micros.fetch_max(micros, Ordering::Relaxed);
+        }
+
+        fn snapshot(&self) -> DurationHistogram {
+            DurationHistogram {
+                buckets: self
+                    .buckets
+                    .iter()
+                    .map(|b| b.load(Ordering::Relaxed))
+                    .collect(),
+                count: self.count.load(Ordering::Relaxed),
+                total_micros: self.total_micros.load(Ordering::Relaxed),
+                max_micros: self.max_micros.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    fn bucket_of(d: Duration) -> usize {
+        let micros = d.as_micros() as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        bucket.min(HISTOGRAM_BUCKETS - 1)
+    }
+
+    #[derive(Debug, Default)]
+    pub(crate) struct LockTimes {
+        wait: AtomicHistogram,
+        hold: AtomicHistogram,
+    }
+
+    impl LockTimes {
+        pub(crate) fn stat(&self) -> LockStat {
+            LockStat {
+                wait: self.wait.snapshot(),
+                hold: self.hold.snapshot(),
+            }
+        }
+    }
+
+    /// Time from a lock acquired to released
+    pub(crate) struct TimedSection<'a> {
+        times: &'a LockTimes,
+        acquired_at: Instant,
+    }
+
+    impl<'a> TimedSection<'a> {
+        pub(crate) fn enter<G>(times: &'a LockTimes, acquire: impl FnOnce() -> G) -> (G, Self) {
+            let start = Instant::now();
+            let guard = acquire();
+            let acquired_at = Instant::now();
+            times.wait.record(acquired_at - start);
+            (guard, TimedSection { times, acquired_at })
+        }
+    }
+
+    impl Drop for TimedSection<'_> {
+        fn drop(&mut self) {
+            self.times.hold.record(self.acquired_at.elapsed());
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use test_log::test;
+
+        #[test]
+        fn test_bucket_of() {
+            assert_eq!(0, bucket_of(Duration::from_nanos(999)));
+            assert_eq!(1, bucket_of(Duration::from_micros(1)));
+            assert_eq!(2, bucket_of(Duration::from_micros(3)));
+            assert_eq!(10, bucket_of(Duration::from_millis(1)));
+            assert_eq!(
+                HISTOGRAM_BUCKETS - 1,
+                bucket_of(Duration::from_secs(100_000))
+            );
+        }
+
+        #[test]
+        fn test_histogram() {
+            let histogram = AtomicHistogram::default();
+            for _ in 0..99 {
+                histogram.record(Duration::from_micros(3));
+            }
+            histogram.record(Duration::from_millis(10));
+
+            let snapshot = histogram.snapshot();
+            assert_eq!(100, snapshot.count);
+            assert_eq!(10_000, snapshot.max_micros);
+            assert_eq!(99 * 3 + 10_000, snapshot.total_micros);
+            assert_eq!(1, snapshot.count_at_least(Duration::from_millis(5)));
+            assert_eq!(Duration::from_micros(4), snapshot.quantile(0.5));
+            assert_eq!(Duration::from_micros(4), snapshot.quantile(0.99));
+            assert_eq!(Duration::from_micros(1 << 14), snapshot.quantile(1.0));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Logging macros used across the crate. They come from `log` by default, or from `tracing`
+//! with the `tracing` feature, which also wraps key operations in spans.
+
+// some macros are only used with some features
+#[cfg(not(feature = "tracing"))]
+#[allow(unused_imports)]
+pub(crate) use log::{debug, error, info, trace, warn};
+#[cfg(feature = "tracing")]
+#[allow(unused_imports)]
+pub(crate) use tracing::{debug, error, info, trace, warn};
+
+use crate::database::RowLocation;
+use crate::storage_id::StorageId;
+
+#[cfg(feature = "tracing")]
+macro_rules! operation_span {
+    ($name:literal) => {
+        operation_span!($name, tracing::field::Empty)
+    };
+    ($name:literal, $key_len:expr) => {
+        OperationSpan {
+            span: tracing::debug_span!(
+                $name,
+                key_len = $key_len,
+                storage_id = tracing::field::Empty,
+                row_offset = tracing::field::Empty,
+            )
+            .entered(),
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! operation_span {
+    ($name:literal) => {
+        OperationSpan {}
+    };
+    ($name:literal, $key_len:expr) => {{
+        let _ = $key_len;
+        OperationSpan {}
+    }};
+}
+
+/// Span entered for the whole operation until dropped. It does nothing without the `tracing`
+/// feature.
+pub(crate) struct OperationSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+}
+
+impl OperationSpan {
+    pub fn put(key_len: usize) -> OperationSpan {
+        operation_span!("put", key_len)
+    }
+
+    pub fn get(key_len: usize) -> OperationSpan {
+        operation_span!("get", key_len)
+    }
+
+    pub fn merge() -> OperationSpan {
+        operation_span!("merge")
+    }
+
+    pub fn flush() -> OperationSpan {
+        operation_span!("flush")
+    }
+
+    pub fn record_storage_id(&self, _storage_id: StorageId) {
+        #[cfg(feature = "tracing")]
+        self.span.record("storage_id", _storage_id);
+    }
+
+    pub fn record_location(&self, _location: &RowLocation) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("storage_id", _location.storage_id);
+            self.span.record("row_offset", _location.row_offset);
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! A bounded thread pool shared by background work of Bitcasky instances.
+//!
+//! Hint file writing, periodic sync, auto merge, background merge and parallel recovery
+//! are all scheduled as tasks on a [`MaintenancePool`] instead of spawning threads for
+//! every instance. Each instance gets its own [`MaintenanceQueue`] and the pool always
+//! picks the next task from the queue with the fewest running tasks, so a long running
+//! merge of one instance can not starve the hint writing of others.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::logging::{debug, error, warn};
+use parking_lot::{Condvar, Mutex};
+
+const DEFAULT_LOG_TARGET: &str = "Maintenance";
+
+type OnceTask = Box<dyn FnOnce() + Send>;
+type RepeatedTask = Arc<dyn Fn() + Send + Sync>;
+
+enum Job {
+    Once(OnceTask),
+    Periodic(u64),
+}
+
+struct PeriodicTaskState {
+    queue_id: u64,
+    interval: Duration,
+    task: RepeatedTask,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: VecDeque<Job>,
+    running: usize,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct PoolState {
+    queues: BTreeMap<u64, QueueState>,
+    periodic_tasks: HashMap<u64, PeriodicTaskState>,
+    running_periodic_tasks: HashSet<u64>,
+    timers: BinaryHeap<Reverse<(Instant, u64)>>,
+    next_id: u64,
+    /// Queue id of the last picked task, used to pick queues in turn
+    last_picked_queue: u64,
+    running: usize,
+    shutdown: bool,
+}
+
+impl PoolState {
+    fn generate_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Moves due periodic tasks to their queues, returns when the next one is due
+    fn schedule_due_timers(&mut self, now: Instant) -> Option<Instant> {
+        while let Some(Reverse((deadline, task_id))) = self.timers.peek().copied() {
+            if deadline > now {
+                return Some(deadline);
+            }
+            self.timers.pop();
+            if let Some(t) = self.periodic_tasks.get(&task_id) {
+                if let Some(q) = self.queues.get_mut(&t.queue_id) {
+                    q.pending.push_back(Job::Periodic(task_id));
+                }
+            }
+        }
+        None
+    }
+
+    /// Picks a task from the queue with the fewest running tasks, queues with the
+    /// same number of running tasks are picked in turn
+    fn pick(&mut self) -> Option<(u64, Job)> {
+        self.queues
+            .retain(|_, q| !(q.closed && q.pending.is_empty() && q.running == 0));
+        let last = self.last_picked_queue;
+        let queue_id = self
+            .queues
+            .range(last + 1..)
+            .chain(self.queues.range(..=last))
+            .filter(|(_, q)| !q.pending.is_empty())
+            .min_by_key(|(_, q)| q.running)
+            .map(|(id, _)| *id)?;
+        let queue = self.queues.get_mut(&queue_id).unwrap();
+        let job = queue.pending.pop_front()?;
+        queue.running += 1;
+        self.running += 1;
+        self.last_picked_queue = queue_id;
+        Some((queue_id, job))
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.queues.values().map(|q| q.pending.len()).sum()
+    }
+}
+
+struct PoolShared {
+    state: Mutex<PoolState>,
+    /// Notified when a task is submitted or a timer is added
+    task_available: Condvar,
+    /// Notified when a task is done
+    task_done: Condvar,
+}
+
+impl PoolShared {
+    fn run_worker(&self) {
+        loop {
+            let (queue_id, job) = {
+                let mut state = self.state.lock();
+                loop {
+                    let next_deadline = if state.shutdown {
+                        None
+                    } else {
+                        state.schedule_due_timers(Instant::now())
+                    };
+                    if let Some(picked) = state.pick() {
+                        break picked;
+                    }
+                    if state.shutdown {
+                        return;
+                    }
+                    match next_deadline {
+                        Some(deadline) => {
+                            self.task_available.wait_until(&mut state, deadline);
+                        }
+                        None => self.task_available.wait(&mut state),
+                    }
+                }
+            };
+            match job {
+                Job::Once(task) => {
+                    Self::run_task(task);
+                    self.finish(queue_id, None);
+                }
+                Job::Periodic(task_id) => {
+                    let task = {
+                        let mut state = self.state.lock();
+                        let task = state.periodic_tasks.get(&task_id).map(|t| t.task.clone());
+                        if task.is_some() {
+                            state.running_periodic_tasks.insert(task_id);
+                        }
+                        task
+                    };
+                    if let Some(task) = task {
+                        Self::run_task(move || task());
+                    }
+                    self.finish(queue_id, Some(task_id));
+                }
+            }
+        }
+    }
+
+    fn run_task<F: FnOnce()>(task: F) {
+        if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+            error!(target: DEFAULT_LOG_TARGET, "maintenance task panicked");
+        }
+    }
+
+    fn finish(&self, queue_id: u64, periodic_task_id: Option<u64>) {
+        let mut state = self.state.lock();
+        if let Some(q) = state.queues.get_mut(&queue_id) {
+            q.running -= 1;
+        }
+        state.running -= 1;
+        if let Some(task_id) = periodic_task_id {
+            state.running_periodic_tasks.remove(&task_id);
+            if let Some(interval) = state.periodic_tasks.get(&task_id).map(|t| t.interval) {
+                state
+                    .timers
+                    .push(Reverse((Instant::now() + interval, task_id)));
+                self.task_available.notify_one();
+            }
+        }
+        self.task_done.notify_all();
+    }
+
+    /// Removes the periodic task and waits for its running execution if any
+    fn cancel_periodic_task(&self, task_id: u64) {
+        let removed = {
+            let mut state = self.state.lock();
+            let removed = state.periodic_tasks.remove(&task_id);
+            while state.running_periodic_tasks.contains(&task_id) {
+                self.task_done.wait(&mut state);
+            }
+            removed
+        };
+        // dropped out of the lock in case the task holds resources which use this pool on drop
+        drop(removed);
+    }
+}
+
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MaintenancePoolTelemetry {
+    pub threads: usize,
+    /// Tasks waiting for a free thread
+    pub queue_depth: usize,
+    pub running_tasks: usize,
+    /// Number of instances using this pool
+    pub queues: usize,
+}
+
+/// A size bounded thread pool for background work of any number of Bitcasky instances.
+/// Threads are stopped and joined when the pool is dropped, after all the submitted
+/// tasks are done.
+pub struct MaintenancePool {
+    threads: usize,
+    shared: Arc<PoolShared>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl MaintenancePool {
+    pub fn new(threads: usize) -> MaintenancePool {
+        assert!(threads > 0);
+        let shared = Arc::new(PoolShared {
+            state: Mutex::new(PoolState::default()),
+            task_available: Condvar::new(),
+            task_done: Condvar::new(),
+        });
+        let workers = (0..threads)
+            .map(|i| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("bitcasky-maintenance-{}", i))
+                    .spawn(move || shared.run_worker())
+                    .expect("spawn maintenance thread failed")
+            })
+            .collect();
+        debug!(target: DEFAULT_LOG_TARGET, "maintenance pool started with {} threads", threads);
+        MaintenancePool {
+            threads,
+            shared,
+            workers: Mutex::new(workers),
+        }
+    }
+
+    /// Creates a queue for an instance to submit tasks to this pool
+    pub fn queue(self: &Arc<Self>) -> MaintenanceQueue {
+        let mut state = self.shared.state.lock();
+        let id = state.generate_id();
+        state.queues.insert(id, QueueState::default());
+        MaintenanceQueue {
+            id,
+            pool: self.clone(),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn get_telemetry_data(&self) -> MaintenancePoolTelemetry {
+        let state = self.shared.state.lock();
+        MaintenancePoolTelemetry {
+            threads: self.threads,
+            queue_depth: state.queue_depth(),
+            running_tasks: state.running,
+            queues: state.queues.values().filter(|q| !q.closed).count(),
+        }
+    }
+}
+
+impl fmt::Debug for MaintenancePool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MaintenancePool")
+            .field("threads", &self.threads)
+            .finish()
+    }
+}
+
+impl Drop for MaintenancePool {
+    fn drop(&mut self) {
+        let periodic_tasks = {
+            let mut state = self.shared.state.lock();
+            state.shutdown = true;
+            state.timers.clear();
+            std::mem::take(&mut state.periodic_tasks)
+        };
+        drop(periodic_tasks);
+        self.shared.task_available.notify_all();
+
+        let current = thread::current().id();
+        for worker in self.workers.lock().drain(..) {
+            // the last reference may be released by a task running on this pool
+            if worker.thread().id() == current {
+                continue;
+            }
+            if worker.join().is_err() {
+                warn!(target: DEFAULT_LOG_TARGET, "wait maintenance thread done failed");
+            }
+        }
+        debug!(target: DEFAULT_LOG_TARGET, "maintenance pool stopped");
+    }
+}
+
+/// Tasks of one instance. Tasks in the same queue may run concurrently on different threads.
+#[derive(Debug)]
+pub struct MaintenanceQueue {
+    id: u64,
+    pool: Arc<MaintenancePool>,
+}
+
+impl MaintenanceQueue {
+    /// Runs the task on the pool once
+    pub fn submit<F: FnOnce() + Send + 'static>(&self, task: F) {
+        let shared = &self.pool.shared;
+        let mut state = shared.state.lock();
+        if let Some(q) = state.queues.get_mut(&self.id) {
+            q.pending.push_back(Job::Once(Box::new(task)));
+            shared.task_available.notify_one();
+        }
+    }
+
+    /// Runs the task on the pool every `interval` until the returned handle is dropped.
+    /// The next run is scheduled after the previous one is done.
+    pub fn schedule<F: Fn() + Send + Sync + 'static>(
+        &self,
+        interval: Duration,
+        task: F,
+    ) -> PeriodicTask {
+        let shared = &self.pool.shared;
+        let mut state = shared.state.lock();
+        let id = state.generate_id();
+        state.periodic_tasks.insert(
+            id,
+            PeriodicTaskState {
+                queue_id: self.id,
+                interval,
+                task: Arc::new(task),
+            },
+        );
+        state.timers.push(Reverse((Instant::now() + interval, id)));
+        shared.task_available.notify_one();
+        PeriodicTask {
+            id,
+            shared: shared.clone(),
+        }
+    }
+
+    pub fn pool(&self) -> &Arc<MaintenancePool> {
+        &self.pool
+    }
+}
+
+impl Drop for MaintenanceQueue {
+    fn drop(&mut self) {
+        // tasks already submitted are still executed
+        let mut state = self.pool.shared.state.lock();
+        if let Some(q) = state.queues.get_mut(&self.id) {
+            q.closed = true;
+        }
+    }
+}
+
+/// Handle of a task scheduled by [`MaintenanceQueue::schedule`]. Dropping it stops the task
+/// and waits for the running execution, so it must not be dropped in the task itself.
+pub struct PeriodicTask {
+    id: u64,
+    shared: Arc<PoolShared>,
+}
+
+impl fmt::Debug for PeriodicTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeriodicTask")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl Drop for PeriodicTask {
+    fn drop(&mut self) {
+        self.shared.cancel_periodic_task(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crossbeam_channel::bounded;
+
+    use super::*;
+    use test_log::test;
+
+    #[test]
+    fn test_run_submitted_tasks() {
+        let pool = Arc::new(MaintenancePool::new(2));
+        let queue = pool.queue();
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let counter = counter.clone();
+            queue.submit(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        drop(queue);
+        drop(pool);
+        assert_eq!(10, counter.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_busy_queue_not_starve_others() {
+        let pool = Arc::new(MaintenancePool::new(2));
+        let busy_queue = pool.queue();
+        let queue = pool.queue();
+        let (release_sender, release_receiver) = bounded::<()>(0);
+        for _ in 0..4 {
+            let release_receiver = release_receiver.clone();
+            busy_queue.submit(move || {
+                let _ = release_receiver.recv();
+            });
+        }
+        let (done_sender, done_receiver) = bounded::<()>(1);
+        queue.submit(move || done_sender.send(()).unwrap());
+        done_receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        drop(release_sender);
+    }
+
+    #[test]
+    fn test_cancel_periodic_task() {
+        let pool = Arc::new(MaintenancePool::new(1));
+        let queue = pool.queue();
+        let counter = Arc::new(AtomicUsize::new(0));
+        let moved_counter = counter.clone();
+        let task = queue.schedule(Duration::from_millis(1), move || {
+            moved_counter.fetch_add(1, Ordering::Relaxed);
+        });
+        while counter.load(Ordering::Relaxed) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(task);
+        let count = counter.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(count, counter.load(Ordering::Relaxed));
+    }
+}
@@ -1,22 +1,37 @@
 use std::{
+    collections::{HashMap, HashSet},
     io::{Read, Write},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Instant,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
+use crossbeam_channel::Receiver;
+use fail::fail_point;
+use parking_lot::Mutex;
+
+use crate::lock_stats::TimedRwLock;
+use crate::logging::{debug, error, info, warn, OperationSpan};
+use crate::mutation_trace::MutationCause;
 
-use log::{debug, error, info, warn};
-use parking_lot::{Mutex, RwLock};
+use crate::database::{
+    deleted_value, DataStorageError, Database, DatabaseError, FilePinSet, RowCopy, RowLocation,
+};
+use crate::options::{BitcaskyOptions, MergeErrorPolicy};
 
-use crate::database::{Database, TimedValue};
-use crate::options::BitcaskyOptions;
+use super::rate_limiter::RateLimiter;
 use crate::{
+    clock::Clock,
     formatter::{
         get_formatter_from_file, initialize_new_file, BitcaskyFormatter, Formatter, MergeMeta,
     },
     fs::{self, FileType},
+    maintenance::PeriodicTask,
     storage_id::{StorageId, StorageIdGenerator},
 };
 
@@ -29,16 +44,257 @@ const MERGE_FILES_DIRECTORY: &str = "Merge";
 const DEFAULT_LOG_TARGET: &str = "DatabaseMerge";
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MergeManagerTelemetry {
     pub is_merging: bool,
+    /// Bytes accounted by all the merges succeeded since open
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub totals: MergeTotals,
+}
+
+/// Bytes read and written by merges, summed over merges succeeded since open. Subtract an
+/// earlier value with `since` to get the bytes of merges in between.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MergeTotals {
+    pub merges: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub hint_bytes: u64,
+    pub live_bytes: u64,
+}
+
+impl MergeTotals {
+    pub fn since(&self, earlier: &MergeTotals) -> MergeTotals {
+        MergeTotals {
+            merges: self.merges.saturating_sub(earlier.merges),
+            read_bytes: self.read_bytes.saturating_sub(earlier.read_bytes),
+            written_bytes: self.written_bytes.saturating_sub(earlier.written_bytes),
+            hint_bytes: self.hint_bytes.saturating_sub(earlier.hint_bytes),
+            live_bytes: self.live_bytes.saturating_sub(earlier.live_bytes),
+        }
+    }
+
+    /// Bytes written to data files and hint files divided by live bytes, 0 if there's no live
+    /// bytes
+    pub fn write_amplification_ratio(&self) -> f64 {
+        write_amplification_ratio(self.written_bytes + self.hint_bytes, self.live_bytes)
+    }
+
+    fn add(&mut self, report: &MergeReport) {
+        self.merges += 1;
+        self.read_bytes += report.read_bytes;
+        self.written_bytes += report.written_bytes;
+        self.hint_bytes += report.hint_bytes;
+        self.live_bytes += report.live_bytes;
+    }
+}
+
+fn write_amplification_ratio(written_bytes: u64, live_bytes: u64) -> f64 {
+    if live_bytes == 0 {
+        return 0.0;
+    }
+    written_bytes as f64 / live_bytes as f64
+}
+
+/// Outcome of a merge
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MergeReport {
+    /// Keys rewritten to merged data files
+    pub merged_keys: usize,
+    /// Keys which rows could not be read after retries and are left at their old locations.
+    /// Only with `MergeErrorPolicy::Skip`
+    pub failed_keys: Vec<Vec<u8>>,
+    /// Merged data files kept because failed keys are still located in them
+    pub retained_storage_ids: Vec<StorageId>,
+    /// Keys which values expired earlier than `merge_expire_margin` ago, they are dropped
+    pub expired_keys: usize,
+    /// Data files merged and purged, not including retained ones
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub files_processed: usize,
+    /// Bytes of rows read from the data files merged
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub read_bytes: u64,
+    /// Bytes written to merged data files
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub written_bytes: u64,
+    /// Bytes of hint files written for merged data files
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub hint_bytes: u64,
+    /// Bytes of the rows rewritten as they were in the data files merged
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub live_bytes: u64,
+    /// Bytes of the purged data files minus bytes written to merged data files
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub bytes_reclaimed: u64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, with = "crate::options::duration_millis")
+    )]
+    pub duration: Duration,
+}
+
+impl MergeReport {
+    /// Bytes written to merged data files and their hint files divided by live bytes, 0 if
+    /// there's no live bytes
+    pub fn write_amplification_ratio(&self) -> f64 {
+        write_amplification_ratio(self.written_bytes + self.hint_bytes, self.live_bytes)
+    }
+}
+
+/// Estimate of the next merge got by `Bitcasky::merge_estimate`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MergeEstimate {
+    /// Bytes of rows still referenced by keydir, which merge rewrites
+    pub live_bytes_to_rewrite: u64,
+    /// Data files written by merge, each up to `max_data_file_size` bytes
+    pub est_output_files: u64,
+    /// Disk space taken by the output files at full size along with their hint files at
+    /// initial capacity. Merged data files are purged only after all of them are written
+    pub required_free_bytes: u64,
+    /// Time to rewrite the live bytes at the measured write throughput, or at
+    /// `merge_rate_limit_bytes_per_sec` if it's slower. Live bytes are read, written and read
+    /// back for hint files under the rate limit. None if neither is known, the throughput is
+    /// measured by writes lasting more than a second
+    pub est_duration: Option<Duration>,
+}
+
+impl MergeEstimate {
+    fn new(
+        live_bytes: u64,
+        max_data_file_size: u64,
+        hint_file_capacity: u64,
+        bytes_per_sec: Option<f64>,
+        rate_limit_bytes_per_sec: Option<u64>,
+    ) -> MergeEstimate {
+        let est_output_files = live_bytes.div_ceil(max_data_file_size);
+        let measured = bytes_per_sec
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f64(live_bytes as f64 / rate));
+        let limited = rate_limit_bytes_per_sec
+            .filter(|limit| *limit > 0)
+            .map(|limit| Duration::from_secs_f64(3.0 * live_bytes as f64 / limit as f64));
+        MergeEstimate {
+            live_bytes_to_rewrite: live_bytes,
+            est_output_files,
+            required_free_bytes: est_output_files * (max_data_file_size + hint_file_capacity),
+            est_duration: measured.max(limited),
+        }
+    }
+}
+
+/// Cancels a merge from another thread. It's checked between rows, a cancelled merge deletes
+/// the files it wrote and fails with `BitcaskyError::MergeCancelled`, leaving the data files
+/// merged as they were. A merge already applying its result is not cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct MergeCancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl MergeCancelToken {
+    pub fn new() -> Self {
+        MergeCancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    fn check(&self) -> BitcaskyResult<()> {
+        if self.is_cancelled() {
+            return Err(BitcaskyError::MergeCancelled());
+        }
+        Ok(())
+    }
+}
+
+/// Handle of a merge running on the maintenance pool
+#[derive(Debug)]
+pub struct MergeHandle {
+    result_receiver: Receiver<thread::Result<BitcaskyResult<MergeReport>>>,
+    cancel: MergeCancelToken,
+}
+
+impl MergeHandle {
+    /// Cancels the background merge, `join` returns `BitcaskyError::MergeCancelled` if it's
+    /// cancelled before applying its result
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Returns true if the background merge is done, whether it succeeded or not
+    pub fn is_finished(&self) -> bool {
+        !self.result_receiver.is_empty()
+    }
+
+    /// Waits for the background merge to finish and returns its result
+    pub fn join(self) -> BitcaskyResult<MergeReport> {
+        match self
+            .result_receiver
+            .recv()
+            .expect("submitted merge task is always executed")
+        {
+            Ok(ret) => ret,
+            Err(e) => std::panic::resume_unwind(e),
+        }
+    }
+}
+
+/// Task that periodically checks whether to merge. It's stopped on drop.
+#[derive(Debug)]
+pub struct AutoMergeWorker {
+    _task: PeriodicTask,
+}
+
+/// Handle of a merge task started by `Bitcasky::start_auto_merge`. Errors of failed merges are
+/// kept until taken. The task is stopped on drop, after waiting for the running merge if any.
+#[derive(Debug)]
+pub struct AutoMergeHandle {
+    _task: PeriodicTask,
+    errors: Arc<Mutex<Vec<BitcaskyError>>>,
+}
+
+impl AutoMergeHandle {
+    pub(crate) fn new(task: PeriodicTask, errors: Arc<Mutex<Vec<BitcaskyError>>>) -> Self {
+        AutoMergeHandle {
+            _task: task,
+            errors,
+        }
+    }
+
+    /// Returns errors of merges failed since the last call
+    pub fn take_errors(&self) -> Vec<BitcaskyError> {
+        std::mem::take(&mut *self.errors.lock())
+    }
+}
+
+/// Key of an expired row dropped by merge along with where the row is
+type ExpiredRow = (Vec<u8>, RowLocation);
+
+/// Clears the merging flag when a merge is finished or failed
+struct MergingGuard<'a> {
+    merging: &'a AtomicBool,
+}
+
+impl<'a> Drop for MergingGuard<'a> {
+    fn drop(&mut self) {
+        self.merging.store(false, Ordering::Release);
+    }
 }
 
 pub struct MergeManager {
     instance_id: String,
     database_dir: PathBuf,
-    merge_lock: Mutex<()>,
+    merging: AtomicBool,
     storage_id_generator: Arc<StorageIdGenerator>,
     options: Arc<BitcaskyOptions>,
+    totals: Mutex<MergeTotals>,
 }
 
 impl MergeManager {
@@ -51,37 +307,294 @@ impl MergeManager {
         MergeManager {
             instance_id,
             database_dir: database_dir.to_path_buf(),
-            merge_lock: Mutex::new(()),
+            merging: AtomicBool::new(false),
             storage_id_generator,
             options,
+            totals: Mutex::new(MergeTotals::default()),
+        }
+    }
+
+    pub fn merge(
+        &self,
+        database: &Database,
+        keydir: &TimedRwLock<KeyDir>,
+        cancel: &MergeCancelToken,
+    ) -> BitcaskyResult<MergeReport> {
+        check_writable(database)?;
+        self.start_merging()?;
+        let _guard = MergingGuard {
+            merging: &self.merging,
+        };
+        self.do_merge(database, keydir, &[], cancel)
+    }
+
+    /// Merges only the data files with the given storage ids. Live rows in these files are rewritten
+    /// to new data files and other data files are left untouched.
+    pub fn merge_files(
+        &self,
+        database: &Database,
+        keydir: &TimedRwLock<KeyDir>,
+        storage_ids: &[StorageId],
+    ) -> BitcaskyResult<MergeReport> {
+        if storage_ids.is_empty() {
+            return Ok(MergeReport::default());
+        }
+        check_writable(database)?;
+        self.start_merging()?;
+        let _guard = MergingGuard {
+            merging: &self.merging,
+        };
+        self.do_merge(database, keydir, storage_ids, &MergeCancelToken::default())
+    }
+
+    /// Merges only the stable data files which dead bytes ratio exceeds the threshold, like
+    /// `merge_files` on `fragmented_files`. Files are picked after no other merge can run, so
+    /// they are not changed by another merge before merged.
+    pub fn merge_fragmented(
+        &self,
+        database: &Database,
+        keydir: &TimedRwLock<KeyDir>,
+        threshold: f64,
+    ) -> BitcaskyResult<MergeReport> {
+        check_writable(database)?;
+        self.start_merging()?;
+        let _guard = MergingGuard {
+            merging: &self.merging,
+        };
+        let storage_ids = self.fragmented_files(database, threshold);
+        if storage_ids.is_empty() {
+            return Ok(MergeReport::default());
+        }
+        self.do_merge(database, keydir, &storage_ids, &MergeCancelToken::default())
+    }
+
+    /// Returns storage ids of stable data files which dead bytes ratio exceeds the threshold
+    pub fn fragmented_files(&self, database: &Database, threshold: f64) -> Vec<StorageId> {
+        let mut storage_ids = database
+            .get_telemetry_data()
+            .stable_storages
+            .values()
+            .filter(|s| s.fragment > threshold)
+            .map(|s| s.storage_id)
+            .collect::<Vec<StorageId>>();
+        storage_ids.sort();
+        storage_ids
+    }
+
+    /// Starts a merge on the maintenance pool. Writes can continue against a new writing file
+    /// while the merge processes the stable files it found at start.
+    pub fn merge_async(
+        self: &Arc<Self>,
+        database: Arc<Database>,
+        keydir: Arc<TimedRwLock<KeyDir>>,
+    ) -> BitcaskyResult<MergeHandle> {
+        check_writable(&database)?;
+        self.start_merging()?;
+        let manager = self.clone();
+        let cancel = MergeCancelToken::default();
+        let task_cancel = cancel.clone();
+        let (result_sender, result_receiver) = crossbeam_channel::bounded(1);
+        let maintenance = database.maintenance_queue().clone();
+        maintenance.submit(move || {
+            let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                let _guard = MergingGuard {
+                    merging: &manager.merging,
+                };
+                manager.do_merge(&database, &keydir, &[], &task_cancel)
+            }));
+            if let Ok(Err(e)) = &ret {
+                if matches!(e, BitcaskyError::MergeCancelled()) {
+                    info!(target: "Bitcasky", "background merge cancelled");
+                } else {
+                    error!(target: "Bitcasky", "background merge failed with error: {}", e);
+                }
+            }
+            let _ = result_sender.send(ret);
+        });
+        Ok(MergeHandle {
+            result_receiver,
+            cancel,
+        })
+    }
+
+    /// Estimates a merge of data files in `source_storage_ids`, or all the data files if it's
+    /// empty
+    pub fn estimate(
+        &self,
+        database: &Database,
+        kd: &KeyDir,
+        source_storage_ids: &[StorageId],
+    ) -> MergeEstimate {
+        let live_bytes = kd
+            .live_bytes()
+            .into_iter()
+            .filter(|(id, _)| source_storage_ids.is_empty() || source_storage_ids.contains(id))
+            .map(|(_, bytes)| bytes as u64)
+            .sum();
+        MergeEstimate::new(
+            live_bytes,
+            self.options.database.storage.max_data_file_size as u64,
+            self.options.database.init_hint_file_capacity as u64,
+            database.write_bytes_per_sec(),
+            self.options.merge_rate_limit_bytes_per_sec,
+        )
+    }
+
+    /// Returns true if dead bytes ratio of all the data files exceeds the threshold
+    pub fn should_merge(&self, database: &Database, threshold: f64) -> bool {
+        let storage_aggregate = database.get_telemetry_data().storage_aggregate;
+        if storage_aggregate.total_data_size == 0 {
+            return false;
         }
+        storage_aggregate.total_dead_bytes as f64 / storage_aggregate.total_data_size as f64
+            > threshold
+    }
+
+    /// Schedule a task to check dead bytes ratio periodically and merge when it exceeds the threshold
+    pub fn start_auto_merge(
+        self: &Arc<Self>,
+        database: Arc<Database>,
+        keydir: Arc<TimedRwLock<KeyDir>>,
+        threshold: f64,
+        check_interval: Duration,
+    ) -> AutoMergeWorker {
+        let task = self.schedule_merge(
+            &database,
+            &keydir,
+            threshold,
+            check_interval,
+            |e| error!(target: "Bitcasky", "auto merge failed with error: {}", e),
+        );
+        AutoMergeWorker { _task: task }
+    }
+
+    /// Schedules a task on the maintenance pool which merges when dead bytes ratio exceeds the
+    /// threshold, and passes errors of failed merges other than `MergeInProgress` to `on_error`.
+    /// The task only keeps weak references, so it does nothing after the database is closed.
+    pub fn schedule_merge<F>(
+        self: &Arc<Self>,
+        database: &Arc<Database>,
+        keydir: &Arc<TimedRwLock<KeyDir>>,
+        threshold: f64,
+        check_interval: Duration,
+        on_error: F,
+    ) -> PeriodicTask
+    where
+        F: Fn(BitcaskyError) + Send + Sync + 'static,
+    {
+        let manager = Arc::downgrade(self);
+        let weak_database = Arc::downgrade(database);
+        let keydir = Arc::downgrade(keydir);
+        database
+            .maintenance_queue()
+            .schedule(check_interval, move || {
+                let (Some(manager), Some(database), Some(keydir)) =
+                    (manager.upgrade(), weak_database.upgrade(), keydir.upgrade())
+                else {
+                    return;
+                };
+                // suspended until read only mode is turned off
+                if database.check_db_error().is_err()
+                    || database.is_read_only()
+                    || !manager.should_merge(&database, threshold)
+                {
+                    return;
+                }
+                info!(target: "Bitcasky", "dead bytes ratio exceeds {}, start auto merge", threshold);
+                match manager.merge(&database, &keydir, &MergeCancelToken::default()) {
+                    Ok(_) | Err(BitcaskyError::MergeInProgress()) => {}
+                    Err(e) => on_error(e),
+                }
+            })
     }
 
-    pub fn merge(&self, database: &Database, keydir: &RwLock<KeyDir>) -> BitcaskyResult<()> {
-        let lock_ret = self.merge_lock.try_lock();
+    /// Prevents any merge from starting, like before closing the database. Returns false if a
+    /// merge is running.
+    pub fn stop_merging(&self) -> bool {
+        self.start_merging().is_ok()
+    }
 
-        if lock_ret.is_none() {
+    fn start_merging(&self) -> BitcaskyResult<()> {
+        if self
+            .merging
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
             return Err(BitcaskyError::MergeInProgress());
         }
+        Ok(())
+    }
 
+    /// Merges data files in `source_storage_ids`, or all the data files if it's empty
+    fn do_merge(
+        &self,
+        database: &Database,
+        keydir: &TimedRwLock<KeyDir>,
+        source_storage_ids: &[StorageId],
+        cancel: &MergeCancelToken,
+    ) -> BitcaskyResult<MergeReport> {
+        let span = OperationSpan::merge();
         let start = Instant::now();
+        if self.options.merge_check_free_space {
+            self.check_free_space(database, keydir, source_storage_ids)?;
+        }
         let (kd, known_max_storage_id) = self.flush_writing_file(database, keydir)?;
+        span.record_storage_id(known_max_storage_id);
+        let mut merge_meta = MergeMeta {
+            known_max_storage_id,
+            source_storage_ids: source_storage_ids.to_vec(),
+            retained_storage_ids: vec![],
+        };
+        validate_merge_source_storage_ids(database, &merge_meta)?;
 
-        debug!(target: "Bitcasky", "start merging. instanceId: {}, knownMaxFileId {}", self.instance_id, known_max_storage_id);
+        debug!(target: "Bitcasky", "start merging. instanceId: {}, knownMaxFileId {}, sourceFileIds: {:?}", 
+            self.instance_id, known_max_storage_id, source_storage_ids);
 
         let merge_dir_path = create_merge_file_dir(database.get_database_dir())?;
-        let (storage_ids, merged_key_dir) =
-            self.write_merged_files(database, &merge_dir_path, &kd, known_max_storage_id)?;
+        let (storage_ids, merged_key_dir, expired_rows, mut report) = match self.write_merged_files(
+            database,
+            &merge_dir_path,
+            &kd,
+            &mut merge_meta,
+            cancel,
+        ) {
+            Ok(ret) => ret,
+            Err(e) => {
+                // files merged partially must not be committed when recovering merge on open
+                if let Err(delete_err) = fs::delete_dir(&merge_dir_path) {
+                    warn!(target: "Bitcasky", "delete merge directory failed. {}", delete_err);
+                }
+                return Err(e);
+            }
+        };
 
-        {
+        let purge_storage_ids = database
+            .get_storage_ids()
+            .stable_storage_ids
+            .into_iter()
+            .filter(|id| merge_meta.is_merge_source(*id))
+            .collect::<Vec<StorageId>>();
+        let purged_bytes: u64 = database
+            .data_files_state()
+            .into_iter()
+            .filter(|(id, _)| purge_storage_ids.contains(id))
+            .map(|(_, data_size)| data_size as u64)
+            .sum();
+        report.files_processed = purge_storage_ids.len();
+        report.bytes_reclaimed = purged_bytes.saturating_sub(report.written_bytes);
+        let pending_invalidation = {
             // stop read/write
-            let kd = keydir.write();
+            let mut kd = keydir.write();
             database.flush_writing_file()?;
-            self.commit_merge(&storage_ids, known_max_storage_id)
-                .and_then(|storage_ids| {
+            // values missed by keydir are still readable until purge
+            database.mark_pending_purge(&purge_storage_ids)?;
+            let shifted_storage_ids = self
+                .commit_merge(&storage_ids, &merge_meta)
+                .and_then(|(storage_ids, shifted_storage_ids)| {
                     database
                         .reload_data_files(storage_ids)
-                        .map_err(BitcaskyError::DatabaseError)
+                        .map_err(BitcaskyError::DatabaseError)?;
+                    Ok(shifted_storage_ids)
                 })
                 .map_err(|e| {
                     database.mark_db_error(e.to_string());
@@ -89,23 +602,61 @@ impl MergeManager {
                     e
                 })?;
 
-            for (k, v) in merged_key_dir.into_iter() {
-                kd.checked_put(k, v);
+            // keys written during merge are located in shifted files
+            let mut relocated_keys = kd.shift_storage_ids(&shifted_storage_ids);
+            relocated_keys
+                .extend(kd.merge_from(&merged_rows_to_apply(merged_key_dir), known_max_storage_id));
+            // expired keys not written since merge started are gone along with purged files
+            for (k, location) in expired_rows {
+                if kd.get(&k) == Some(location) {
+                    kd.delete(&k, MutationCause::MergeApply);
+                    relocated_keys.push(k);
+                }
+            }
+            database.reset_dead_bytes(&kd.live_bytes());
+            kd.rebuild_bloom_filter();
+            // room of keys deleted since keydir grew is kept until now
+            kd.shrink_to_fit();
+            if relocated_keys.is_empty() {
+                None
+            } else {
+                Some(kd.invalidate_locations(relocated_keys))
             }
+        };
+        if let Some(pending_invalidation) = pending_invalidation {
+            pending_invalidation.notify();
         }
 
-        info!(target: "Bitcasky", "purge files with id smaller than: {}", known_max_storage_id);
+        if source_storage_ids.is_empty() {
+            info!(target: "Bitcasky", "purge files with id smaller than: {}", known_max_storage_id);
+        } else {
+            info!(target: "Bitcasky", "purge files with id: {:?}", source_storage_ids);
+        }
 
-        purge_outdated_data_files(&database.database_dir, known_max_storage_id)?;
+        fail_point!("merge::before_purge");
+        database.purge_pending_storages();
+        purge_outdated_data_files(&database.database_dir, &merge_meta, database.file_pins())?;
+        match database.compact_hint_files() {
+            Ok(removed) => {
+                debug!(target: "Bitcasky", "removed {} hint files after merge", removed)
+            }
+            Err(e) => warn!(target: "Bitcasky", "compact hint files after merge failed. {}", e),
+        }
         let delete_ret = fs::delete_dir(&merge_dir_path);
         if delete_ret.is_err() {
             warn!(target: "Bitcasky", "delete merge directory failed. {}", delete_ret.unwrap_err());
         }
 
+        if !report.failed_keys.is_empty() {
+            warn!(target: "Bitcasky", "skipped {} keys failed to read in merge, kept data files with id: {:?}",
+                report.failed_keys.len(), report.retained_storage_ids);
+        }
         info!(target: "Bitcasky", "merge success. instanceId: {}, knownMaxFileId {}, cost: {} millis",
           self.instance_id, known_max_storage_id, start.elapsed().as_millis());
 
-        Ok(())
+        report.duration = start.elapsed();
+        self.totals.lock().add(&report);
+        Ok(report)
     }
 
     pub fn recover_merge(&self) -> BitcaskyResult<()> {
@@ -131,7 +682,8 @@ impl MergeManager {
 
     pub fn get_telemetry_data(&self) -> MergeManagerTelemetry {
         MergeManagerTelemetry {
-            is_merging: self.merge_lock.is_locked(),
+            is_merging: self.merging.load(Ordering::Acquire),
+            totals: *self.totals.lock(),
         }
     }
 
@@ -142,13 +694,12 @@ impl MergeManager {
             return Ok(());
         }
 
-        let mut merge_data_storage_ids =
+        let merge_data_storage_ids =
             fs::get_storage_ids_in_dir(&merge_file_dir, FileType::DataFile);
         if merge_data_storage_ids.is_empty() {
             return Ok(());
         }
 
-        merge_data_storage_ids.sort();
         let merge_meta = read_merge_meta(&merge_file_dir)?;
         if *merge_data_storage_ids.first().unwrap() <= merge_meta.known_max_storage_id {
             return Err(BitcaskyError::InvalidMergeDataFile(
@@ -158,13 +709,15 @@ impl MergeManager {
         }
 
         self.storage_id_generator
-            .update_id(*merge_data_storage_ids.last().unwrap());
+            .update_id(*merge_data_storage_ids.last().unwrap())
+            .map_err(DatabaseError::from)?;
 
         self.shift_data_files(merge_meta.known_max_storage_id)?;
 
         commit_merge_files(&self.database_dir, &merge_data_storage_ids)?;
 
-        purge_outdated_data_files(&self.database_dir, merge_meta.known_max_storage_id)?;
+        // nothing is pinned before the database is opened
+        purge_outdated_data_files(&self.database_dir, &merge_meta, &FilePinSet::default())?;
 
         let delete_ret = fs::delete_dir(&merge_file_dir);
         if delete_ret.is_err() {
@@ -173,15 +726,32 @@ impl MergeManager {
         Ok(())
     }
 
+    fn check_free_space(
+        &self,
+        database: &Database,
+        keydir: &TimedRwLock<KeyDir>,
+        source_storage_ids: &[StorageId],
+    ) -> BitcaskyResult<()> {
+        let required = self
+            .estimate(database, &keydir.read(), source_storage_ids)
+            .required_free_bytes;
+        let available = fs::available_space(database.get_database_dir())?;
+        if required > available {
+            warn!(target: "Bitcasky", "refuse to merge, requires {} bytes but only {} bytes available", required, available);
+            return Err(BitcaskyError::InsufficientFreeSpace(required, available));
+        }
+        Ok(())
+    }
+
     fn flush_writing_file(
         &self,
         database: &Database,
-        keydir: &RwLock<KeyDir>,
+        keydir: &TimedRwLock<KeyDir>,
     ) -> BitcaskyResult<(KeyDir, StorageId)> {
         // stop writing and switch the writing file to stable files
         let _kd = keydir.write();
         database.flush_writing_file()?;
-        let known_max_storage_id = database.get_max_storage_id();
+        let known_max_storage_id = database.writing_storage_id();
         Ok((_kd.clone(), known_max_storage_id))
     }
 
@@ -190,83 +760,156 @@ impl MergeManager {
         database: &Database,
         merge_file_dir: &Path,
         key_dir_to_write: &KeyDir,
-        known_max_storage_id: StorageId,
-    ) -> BitcaskyResult<(Vec<StorageId>, KeyDir)> {
-        write_merge_meta(
-            merge_file_dir,
-            MergeMeta {
-                known_max_storage_id,
-            },
-        )?;
+        merge_meta: &mut MergeMeta,
+        cancel: &MergeCancelToken,
+    ) -> BitcaskyResult<(Vec<StorageId>, KeyDir, Vec<ExpiredRow>, MergeReport)> {
+        write_merge_meta(merge_file_dir, merge_meta)?;
 
-        let merged_key_dir = KeyDir::new_empty_key_dir();
-        let merge_db = Database::open(
+        let mut merged_key_dir = KeyDir::new_empty_key_dir();
+        let mut merge_db = Database::open(
             merge_file_dir,
             self.storage_id_generator.clone(),
             self.options.clone(),
         )?;
 
-        let mut write_key_count = 0;
-        for r in key_dir_to_write.iter() {
-            let k = r.key();
-            if let Some(v) = database.read_value(r.value())? {
-                let pos =
-                    merge_db.write(k, TimedValue::expirable_value(v.value, v.expire_timestamp))?;
-                if let Some(lo) = merged_key_dir.checked_put(k.clone(), pos) {
-                    merge_db.add_dead_bytes(lo.storage_id, lo.row_offset);
-                }
-                debug!(target: "Bitcasky", "put data to merged file success. key: {:?}, storage_id: {}, row_offset: {}, expire_timestamp: {}", 
-                k, pos.storage_id, pos.row_offset, v.expire_timestamp);
-                write_key_count += 1;
+        let mut report = MergeReport::default();
+        let mut expired_rows = vec![];
+        // hint files of merged data files are written by reading them back, paced along with rows
+        let limiter = Arc::new(RateLimiter::new(
+            self.options.merge_rate_limit_bytes_per_sec,
+        ));
+        merge_db.set_hint_rate_limiter(limiter.clone());
+        if merge_meta.source_storage_ids.is_empty() {
+            write_all_merged_rows(
+                database,
+                &merge_db,
+                key_dir_to_write,
+                &mut merged_key_dir,
+                &mut expired_rows,
+                &self.options,
+                &mut report,
+                &limiter,
+                cancel,
+            )?;
+            if !report.retained_storage_ids.is_empty() {
+                report.read_bytes += write_retained_tombstones(
+                    database,
+                    &merge_db,
+                    &merged_key_dir,
+                    &report,
+                    &limiter,
+                )?;
+                // retained files must not be purged even if merge is recovered after crash
+                merge_meta.retained_storage_ids = report.retained_storage_ids.clone();
+                write_merge_meta(merge_file_dir, merge_meta)?;
             }
+        } else {
+            write_partial_merged_rows(
+                database,
+                &merge_db,
+                key_dir_to_write,
+                &mut merged_key_dir,
+                &mut expired_rows,
+                merge_meta,
+                expire_before(&self.options),
+                &mut report,
+                &limiter,
+                cancel,
+            )?;
         }
+        report.expired_keys = expired_rows.len();
 
         merge_db.flush_writing_file()?;
+        // last chance to cancel, the merge can not be cancelled once it starts to apply
+        cancel.check()?;
+        report.written_bytes = merge_db.get_telemetry_data().total_written_bytes;
+        database.add_written_bytes(report.written_bytes);
         let storage_ids = merge_db.get_storage_ids();
-        info!(target: "Bitcasky", "{} keys in database merged to files with ids: {:?}", write_key_count, &storage_ids.stable_storage_ids);
+        // waits for hint files of merged data files to be written
+        drop(merge_db);
+        report.hint_bytes = storage_ids
+            .stable_storage_ids
+            .iter()
+            .filter_map(|id| {
+                std::fs::metadata(FileType::HintFile.get_path(merge_file_dir, Some(*id))).ok()
+            })
+            .map(|m| m.len())
+            .sum();
+        info!(target: "Bitcasky", "{} keys in database merged to files with ids: {:?}, {} expired keys dropped",
+            report.merged_keys, &storage_ids.stable_storage_ids, report.expired_keys);
         // we do not write anything in writing file
         // so we can only use stable files
-        Ok((storage_ids.stable_storage_ids, merged_key_dir))
+        Ok((
+            storage_ids.stable_storage_ids,
+            merged_key_dir,
+            expired_rows,
+            report,
+        ))
     }
 
     fn commit_merge(
         &self,
         merged_storage_ids: &Vec<StorageId>,
-        known_max_storage_id: StorageId,
-    ) -> BitcaskyResult<Vec<StorageId>> {
-        let mut data_storage_ids = self.shift_data_files(known_max_storage_id)?;
+        merge_meta: &MergeMeta,
+    ) -> BitcaskyResult<(Vec<StorageId>, HashMap<StorageId, StorageId>)> {
+        let shifted_storage_ids = self.shift_data_files(merge_meta.known_max_storage_id)?;
 
         commit_merge_files(&self.database_dir, merged_storage_ids)?;
 
+        // data files not merged are kept
+        let mut data_storage_ids = fs::get_storage_ids_of_types_in_dir(
+            &self.database_dir,
+            self.options
+                .database
+                .storage
+                .format_compat
+                .data_file_types(),
+        )
+        .into_iter()
+        .filter(|id| *id < merge_meta.known_max_storage_id && !merge_meta.is_merge_source(*id))
+        .collect::<Vec<StorageId>>();
+        data_storage_ids.extend(shifted_storage_ids.values());
         data_storage_ids.extend(merged_storage_ids.iter());
 
-        Ok(data_storage_ids)
+        Ok((data_storage_ids, shifted_storage_ids))
     }
 
-    fn shift_data_files(&self, known_max_storage_id: StorageId) -> BitcaskyResult<Vec<StorageId>> {
-        let mut data_storage_ids =
-            fs::get_storage_ids_in_dir(&self.database_dir, FileType::DataFile)
-                .into_iter()
-                .filter(|id| *id >= known_max_storage_id)
-                .collect::<Vec<StorageId>>();
-        // must change name in descending order to keep data file's order even when any change name operation failed
-        data_storage_ids.sort_by(|a, b| b.cmp(a));
+    /// Returns the map from the original storage id to the shifted storage id
+    fn shift_data_files(
+        &self,
+        known_max_storage_id: StorageId,
+    ) -> BitcaskyResult<HashMap<StorageId, StorageId>> {
+        let data_storage_ids = fs::get_storage_ids_in_dir(&self.database_dir, FileType::DataFile)
+            .into_iter()
+            .filter(|id| *id >= known_max_storage_id)
+            .collect::<Vec<StorageId>>();
 
         // rename files which file id >= knwon_max_storage_id to files which file id greater than all merged files
-        // because values in these files is written after merged files
-        let mut new_storage_ids = vec![];
-        for from_id in data_storage_ids {
-            let new_storage_id = &self.storage_id_generator.generate_next_id();
+        // because values in these files is written after merged files.
+        // new ids are allocated in the same order with the original ids to keep data file's order
+        let shifted_storage_ids = data_storage_ids
+            .iter()
+            .map(|id| Ok((*id, self.storage_id_generator.generate_next_id()?)))
+            .collect::<Result<Vec<(StorageId, StorageId)>, DatabaseError>>()?;
+
+        // must change name in descending order to keep data file's order even when any change name operation failed
+        for (from_id, new_storage_id) in shifted_storage_ids.iter().rev() {
             fs::change_storage_id(
                 &self.database_dir,
                 FileType::DataFile,
-                from_id,
+                *from_id,
                 *new_storage_id,
             )?;
-            new_storage_ids.push(*new_storage_id);
         }
-        Ok(new_storage_ids)
+        Ok(shifted_storage_ids.into_iter().collect())
+    }
+}
+
+fn check_writable(database: &Database) -> BitcaskyResult<()> {
+    if database.is_read_only() {
+        return Err(BitcaskyError::ReadOnlyMode());
     }
+    Ok(())
 }
 
 fn merge_file_dir(base_dir: &Path) -> PathBuf {
@@ -337,14 +980,295 @@ fn commit_merge_files(base_dir: &Path, storage_ids: &Vec<StorageId>) -> Bitcasky
     Ok(())
 }
 
-fn purge_outdated_data_files(base_dir: &Path, max_storage_id: StorageId) -> BitcaskyResult<()> {
-    fs::get_storage_ids_in_dir(base_dir, FileType::DataFile)
-        .iter()
-        .filter(|id| **id < max_storage_id)
-        .for_each(|id| {
-            fs::delete_file(base_dir, FileType::DataFile, Some(*id)).unwrap_or_default();
-            fs::delete_file(base_dir, FileType::HintFile, Some(*id)).unwrap_or_default();
-        });
+/// Deletes merged data files along with their hint files, including data files left by Erlang
+/// bitcask. Pinned data files are moved out of the directory and deleted once unpinned.
+fn purge_outdated_data_files(
+    base_dir: &Path,
+    merge_meta: &MergeMeta,
+    pins: &FilePinSet,
+) -> BitcaskyResult<()> {
+    for id in fs::get_storage_ids_of_types_in_dir(
+        base_dir,
+        &[FileType::DataFile, FileType::ErlangDataFile],
+    )
+    .into_iter()
+    .filter(|id| merge_meta.is_merge_source(*id))
+    {
+        pins.purge(base_dir, id)?;
+    }
+    Ok(())
+}
+
+/// Rows written by merge to apply to keydir. Failpoint can drop them all to leave keys in
+/// keydir pointing to the data files going to be purged.
+fn merged_rows_to_apply(merged_key_dir: KeyDir) -> KeyDir {
+    fail_point!("merge::skip_apply_merged_rows", |_| {
+        KeyDir::new_empty_key_dir()
+    });
+    merged_key_dir
+}
+
+/// Partial merge can only merge existing stable files
+fn validate_merge_source_storage_ids(
+    database: &Database,
+    merge_meta: &MergeMeta,
+) -> BitcaskyResult<()> {
+    let stable_storage_ids = database.get_storage_ids().stable_storage_ids;
+    for id in merge_meta.source_storage_ids.iter() {
+        if *id >= merge_meta.known_max_storage_id || !stable_storage_ids.contains(id) {
+            return Err(BitcaskyError::InvalidParameter(
+                "storage_ids".into(),
+                format!("data file with id: {} is not a stable data file", id),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the latest row of every key in keydir. A key which row can not be copied after
+/// retries fails the merge, or is left in its data file by `MergeErrorPolicy::Skip`
+#[allow(clippy::too_many_arguments)]
+fn write_all_merged_rows(
+    database: &Database,
+    merge_db: &Database,
+    key_dir_to_write: &KeyDir,
+    merged_key_dir: &mut KeyDir,
+    expired_rows: &mut Vec<ExpiredRow>,
+    options: &BitcaskyOptions,
+    report: &mut MergeReport,
+    limiter: &RateLimiter,
+    cancel: &MergeCancelToken,
+) -> BitcaskyResult<()> {
+    let mut retained_storage_ids = HashSet::new();
+    let expire_before = expire_before(options);
+    for (k, location) in key_dir_to_write.iter() {
+        cancel.check()?;
+        let merged = match merge_row_with_retry(
+            database,
+            merge_db,
+            k,
+            location,
+            expire_before,
+            options,
+        ) {
+            Ok(m) => m,
+            Err(e) if options.merge_error_policy == MergeErrorPolicy::Skip => {
+                warn!(target: DEFAULT_LOG_TARGET, "skip key: {:?} failed to read at storage_id: {}, row_offset: {}. {}",
+                    k, location.storage_id, location.row_offset, e);
+                report.failed_keys.push(k.clone());
+                retained_storage_ids.insert(location.storage_id);
+                continue;
+            }
+            Err(e) => return Err(BitcaskyError::DatabaseError(e)),
+        };
+        report.read_bytes += location.row_size as u64;
+        limiter.consume((location.row_size + merged.map_or(0, |pos| pos.row_size)) as u64);
+        if let Some(pos) = merged {
+            report.live_bytes += location.row_size as u64;
+            if let Some(lo) = merged_key_dir.put(k.clone(), pos) {
+                merge_db.add_dead_bytes(lo.storage_id, lo.row_offset);
+            }
+            debug!(target: "Bitcasky", "put data to merged file success. key: {:?}, storage_id: {}, row_offset: {}",
+                k, pos.storage_id, pos.row_offset);
+            report.merged_keys += 1;
+        } else {
+            // keys in keydir are never tombstones, so the value expired
+            expired_rows.push((k.clone(), *location));
+        }
+    }
+    report.retained_storage_ids = retained_storage_ids.into_iter().collect();
+    report.retained_storage_ids.sort();
+    Ok(())
+}
+
+/// Writes a row to merge to `merge_db`, retrying `merge_read_retries` times on error with
+/// the backoff doubled on each retry. Returns None if the row expired at `expire_before`
+fn merge_row_with_retry(
+    database: &Database,
+    merge_db: &Database,
+    key: &[u8],
+    location: &RowLocation,
+    expire_before: u64,
+    options: &BitcaskyOptions,
+) -> Result<Option<RowLocation>, DatabaseError> {
+    let mut backoff = options.merge_read_retry_backoff;
+    let mut retries = 0;
+    loop {
+        match merge_row(database, merge_db, key, location, expire_before) {
+            Err(e) if retries < options.merge_read_retries => {
+                debug!(target: DEFAULT_LOG_TARGET, "read key: {:?} at storage_id: {}, row_offset: {} failed, retry in {:?}. {}",
+                    key, location.storage_id, location.row_offset, backoff, e);
+                thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+                retries += 1;
+            }
+            ret => return ret,
+        }
+    }
+}
+
+/// Failpoint fails reading the key given as its argument, or any key without argument
+fn merge_row(
+    database: &Database,
+    merge_db: &Database,
+    key: &[u8],
+    location: &RowLocation,
+    expire_before: u64,
+) -> Result<Option<RowLocation>, DatabaseError> {
+    fail_point!("merge::read_value", |failed_key: Option<String>| {
+        if failed_key.map(|k| k.as_bytes() == key).unwrap_or(true) {
+            return Err(DatabaseError::IoError(std::io::Error::other(
+                "injected read failure",
+            )));
+        }
+        copy_or_rewrite_row(database, merge_db, key, location, expire_before)
+    });
+    copy_or_rewrite_row(database, merge_db, key, location, expire_before)
+}
+
+/// Copies the row to `merge_db` as it is, or reads and writes its value again when it can't be
+/// copied. Returns None if the row expired at `expire_before`
+fn copy_or_rewrite_row(
+    database: &Database,
+    merge_db: &Database,
+    key: &[u8],
+    location: &RowLocation,
+    expire_before: u64,
+) -> Result<Option<RowLocation>, DatabaseError> {
+    match database.copy_row_to(location, expire_before, merge_db)? {
+        RowCopy::Copied(pos) => Ok(Some(pos)),
+        RowCopy::Expired => Ok(None),
+        RowCopy::Unsupported => match database.read_value_at(location, expire_before)? {
+            Some(v) => Ok(Some(merge_db.write(key, v)?)),
+            None => Ok(None),
+        },
+    }
+}
+
+/// Rows expired at the returned time are dropped by merge
+fn expire_before(options: &BitcaskyOptions) -> u64 {
+    options
+        .clock
+        .now()
+        .saturating_sub(options.merge_expire_margin.as_millis() as u64)
+}
+
+/// Writes tombstones for keys in retained data files which are neither merged nor failed.
+/// Tombstones of these keys in the purged data files are gone after merge, so their values
+/// in the retained data files would come back on recovery. Returns bytes of rows read.
+fn write_retained_tombstones(
+    database: &Database,
+    merge_db: &Database,
+    merged_key_dir: &KeyDir,
+    report: &MergeReport,
+    limiter: &RateLimiter,
+) -> BitcaskyResult<u64> {
+    let failed_keys = report.failed_keys.iter().collect::<HashSet<&Vec<u8>>>();
+    let mut tombstone_keys = HashSet::new();
+    let mut read_bytes = 0;
+    for storage_id in report.retained_storage_ids.iter() {
+        let mut iter = database.stable_storage_iter(*storage_id)?;
+        for row in iter.by_ref() {
+            let row = row.map_err(DatabaseError::StorageError)?;
+            read_bytes += row.row_location.row_size as u64;
+            limiter.consume(row.row_location.row_size as u64);
+            if merged_key_dir.contains_key(&row.key)
+                || failed_keys.contains(&row.key)
+                || tombstone_keys.contains(&row.key)
+            {
+                continue;
+            }
+            let pos = merge_db.write(&row.key, deleted_value())?;
+            limiter.consume(pos.row_size as u64);
+            tombstone_keys.insert(row.key);
+        }
+        // keys of rows after a corrupted row are unknown, so they can not be deleted
+        if let Some(offset) = iter.corrupted_offsets().first() {
+            return Err(BitcaskyError::DatabaseError(DatabaseError::StorageError(
+                DataStorageError::ReadRowFailed(
+                    *storage_id,
+                    format!("found corrupted row at offset: {}", offset),
+                ),
+            )));
+        }
+    }
+    Ok(read_bytes)
+}
+
+/// Rewrites rows in source data files which are still referenced by keydir and not expired at
+/// `expire_before`. Deleted and expired keys in source files are rewritten as tombstones when
+/// older data files are kept, otherwise the values in those files would come back on recovery.
+#[allow(clippy::too_many_arguments)]
+fn write_partial_merged_rows(
+    database: &Database,
+    merge_db: &Database,
+    key_dir_to_write: &KeyDir,
+    merged_key_dir: &mut KeyDir,
+    expired_rows: &mut Vec<ExpiredRow>,
+    merge_meta: &MergeMeta,
+    expire_before: u64,
+    report: &mut MergeReport,
+    limiter: &RateLimiter,
+    cancel: &MergeCancelToken,
+) -> BitcaskyResult<()> {
+    let kept_storage_ids = database
+        .get_storage_ids()
+        .stable_storage_ids
+        .into_iter()
+        .filter(|id| !merge_meta.is_merge_source(*id))
+        .collect::<Vec<StorageId>>();
+    let mut tombstone_keys = HashSet::new();
+    for storage_id in merge_meta.source_storage_ids.iter() {
+        let has_older_files = kept_storage_ids.iter().any(|id| id < storage_id);
+        let mut iter = database.stable_storage_iter(*storage_id)?;
+        for row in iter.by_ref() {
+            cancel.check()?;
+            let row = row.map_err(DatabaseError::StorageError)?;
+            report.read_bytes += row.row_location.row_size as u64;
+            limiter.consume(row.row_location.row_size as u64);
+            let is_live = key_dir_to_write
+                .get(&row.key)
+                .map(|r| r == row.row_location)
+                .unwrap_or(false);
+            let expired = is_live && !row.value.is_valid(expire_before);
+            if is_live && !expired {
+                let value = if row.value.value.is_empty() && row.value.expire_timestamp != 0 {
+                    // value of a row expired within the margin is not read by the iterator
+                    database.read_value_at(&row.row_location, expire_before)?
+                } else {
+                    Some(row.value)
+                };
+                if let Some(value) = value {
+                    let pos = merge_db.write(&row.key, value)?;
+                    limiter.consume(pos.row_size as u64);
+                    merged_key_dir.put(row.key, pos);
+                    report.merged_keys += 1;
+                    report.live_bytes += row.row_location.row_size as u64;
+                }
+                continue;
+            }
+            if expired {
+                expired_rows.push((row.key.clone(), row.row_location));
+            }
+            if has_older_files
+                && (expired || !key_dir_to_write.contains_key(&row.key))
+                && !tombstone_keys.contains(&row.key)
+            {
+                let pos = merge_db.write(&row.key, deleted_value())?;
+                limiter.consume(pos.row_size as u64);
+                tombstone_keys.insert(row.key);
+            }
+        }
+        // rows after a corrupted row are unknown, so the file can not be purged
+        if let Some(offset) = iter.corrupted_offsets().first() {
+            return Err(BitcaskyError::DatabaseError(DatabaseError::StorageError(
+                DataStorageError::ReadRowFailed(
+                    *storage_id,
+                    format!("found corrupted row at offset: {}", offset),
+                ),
+            )));
+        }
+    }
     Ok(())
 }
 
@@ -356,15 +1280,17 @@ fn read_merge_meta(merge_file_dir: &Path) -> BitcaskyResult<MergeMeta> {
 
     let mut buf = vec![0; formatter.merge_meta_size()];
     merge_meta_file.file.read_exact(&mut buf)?;
+    // source storage ids of partial merge follow the fixed size part
+    merge_meta_file.file.read_to_end(&mut buf)?;
     let bs = Bytes::from(buf);
     Ok(formatter.decode_merge_meta(bs))
 }
 
-fn write_merge_meta(merge_file_dir: &Path, merge_meta: MergeMeta) -> BitcaskyResult<()> {
+fn write_merge_meta(merge_file_dir: &Path, merge_meta: &MergeMeta) -> BitcaskyResult<()> {
     let mut merge_meta_file = fs::create_file(merge_file_dir, FileType::MergeMeta, None)?;
     let formater = BitcaskyFormatter::default();
-    initialize_new_file(&mut merge_meta_file, formater.version())?;
-    merge_meta_file.write_all(&formater.encode_merge_meta(&merge_meta))?;
+    initialize_new_file(&mut merge_meta_file, &formater)?;
+    merge_meta_file.write_all(&formater.encode_merge_meta(merge_meta))?;
     Ok(())
 }
 
@@ -372,7 +1298,7 @@ fn write_merge_meta(merge_file_dir: &Path, merge_meta: MergeMeta) -> BitcaskyRes
 mod tests {
     use std::{time::Duration, vec};
 
-    use crate::database::RowLocation;
+    use crate::database::{RowLocation, TimedValue};
     use crate::options::SyncStrategy;
     use crate::{
         formatter::{initialize_new_file, BitcaskyFormatter},
@@ -380,6 +1306,7 @@ mod tests {
     };
 
     use super::*;
+    use crate::lock_stats::LockTimer;
     use crate::test_utils::{get_temporary_directory_path, TestingKV};
     use test_log::test;
 
@@ -446,7 +1373,7 @@ mod tests {
         let dir = get_temporary_directory_path();
         let merge_file_path = create_merge_file_dir(&dir).unwrap();
         let mut file = fs::create_file(&merge_file_path, FileType::DataFile, Some(0)).unwrap();
-        initialize_new_file(&mut file, BitcaskyFormatter::default().version()).unwrap();
+        initialize_new_file(&mut file, &BitcaskyFormatter::default()).unwrap();
 
         create_merge_file_dir(&dir).unwrap();
 
@@ -470,17 +1397,17 @@ mod tests {
         let merge_file_path = create_merge_file_dir(&dir_path).unwrap();
         initialize_new_file(
             &mut fs::create_file(&merge_file_path, FileType::DataFile, Some(0)).unwrap(),
-            BitcaskyFormatter::default().version(),
+            &BitcaskyFormatter::default(),
         )
         .unwrap();
         initialize_new_file(
             &mut fs::create_file(&merge_file_path, FileType::DataFile, Some(1)).unwrap(),
-            BitcaskyFormatter::default().version(),
+            &BitcaskyFormatter::default(),
         )
         .unwrap();
         initialize_new_file(
             &mut fs::create_file(&merge_file_path, FileType::DataFile, Some(2)).unwrap(),
-            BitcaskyFormatter::default().version(),
+            &BitcaskyFormatter::default(),
         )
         .unwrap();
 
@@ -506,12 +1433,50 @@ mod tests {
         let merge_file_path = create_merge_file_dir(&dir_path).unwrap();
         let expect_meta = MergeMeta {
             known_max_storage_id: 10101,
+            source_storage_ids: vec![],
+            retained_storage_ids: vec![],
+        };
+        write_merge_meta(&merge_file_path, &expect_meta).unwrap();
+        let actual_meta = read_merge_meta(&merge_file_path).unwrap();
+        assert_eq!(expect_meta, actual_meta);
+    }
+
+    #[test]
+    fn test_read_write_partial_merge_meta() {
+        let dir_path = get_temporary_directory_path();
+        let merge_file_path = create_merge_file_dir(&dir_path).unwrap();
+        let expect_meta = MergeMeta {
+            known_max_storage_id: 10101,
+            source_storage_ids: vec![3, 7],
+            retained_storage_ids: vec![],
         };
-        write_merge_meta(&merge_file_path, expect_meta).unwrap();
+        write_merge_meta(&merge_file_path, &expect_meta).unwrap();
         let actual_meta = read_merge_meta(&merge_file_path).unwrap();
         assert_eq!(expect_meta, actual_meta);
     }
 
+    #[test]
+    fn test_purge_partial_merge_source_files() {
+        let dir_path = get_temporary_directory_path();
+        for id in 1..=4 {
+            fs::create_file(&dir_path, FileType::DataFile, Some(id)).unwrap();
+        }
+        purge_outdated_data_files(
+            &dir_path,
+            &MergeMeta {
+                known_max_storage_id: 4,
+                source_storage_ids: vec![1, 3],
+                retained_storage_ids: vec![],
+            },
+            &FilePinSet::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![2, 4],
+            fs::get_storage_ids_in_dir(&dir_path, FileType::DataFile)
+        );
+    }
+
     #[test]
     fn test_recover_merge_with_only_merge_meta() {
         let dir = get_temporary_directory_path();
@@ -528,8 +1493,10 @@ mod tests {
         let merge_file_dir = create_merge_file_dir(&dir).unwrap();
         let merge_meta = MergeMeta {
             known_max_storage_id: 101,
+            source_storage_ids: vec![],
+            retained_storage_ids: vec![],
         };
-        write_merge_meta(&merge_file_dir, merge_meta).unwrap();
+        write_merge_meta(&merge_file_dir, &merge_meta).unwrap();
         let merge_manager = MergeManager::new(
             INSTANCE_ID,
             &dir,
@@ -538,8 +1505,8 @@ mod tests {
         );
         merge_manager.recover_merge().unwrap();
         let db = Database::open(&dir, storage_id_generator.clone(), get_options()).unwrap();
-        assert_eq!(1, storage_id_generator.get_id());
-        assert_eq!(0, db.get_storage_ids().stable_storage_ids.len());
+        assert_eq!(2, storage_id_generator.get_id());
+        assert_eq!(1, db.get_storage_ids().stable_storage_ids.len());
         assert_rows_value(&db, &rows);
         assert_database_rows(&db, &rows);
     }
@@ -570,9 +1537,11 @@ mod tests {
         }
 
         let merge_meta = MergeMeta {
-            known_max_storage_id: storage_id_generator.generate_next_id(),
+            known_max_storage_id: storage_id_generator.generate_next_id().unwrap(),
+            source_storage_ids: vec![],
+            retained_storage_ids: vec![],
         };
-        write_merge_meta(&merge_file_dir, merge_meta).unwrap();
+        write_merge_meta(&merge_file_dir, &merge_meta).unwrap();
         let merge_manager = MergeManager::new(
             INSTANCE_ID,
             &dir,
@@ -581,8 +1550,8 @@ mod tests {
         );
         merge_manager.recover_merge().unwrap();
         let db = Database::open(&dir, storage_id_generator.clone(), get_options()).unwrap();
-        assert_eq!(3, storage_id_generator.get_id());
-        assert_eq!(0, db.get_storage_ids().stable_storage_ids.len());
+        assert_eq!(4, storage_id_generator.get_id());
+        assert_eq!(1, db.get_storage_ids().stable_storage_ids.len());
         assert_rows_value(&db, &rows);
         assert_database_rows(&db, &rows);
         assert!(!merge_file_dir.exists());
@@ -601,10 +1570,12 @@ mod tests {
             write_kvs_to_db(&db, kvs);
         }
         let merge_meta = MergeMeta {
-            known_max_storage_id: storage_id_generator.generate_next_id(),
+            known_max_storage_id: storage_id_generator.generate_next_id().unwrap(),
+            source_storage_ids: vec![],
+            retained_storage_ids: vec![],
         };
         let merge_file_dir = create_merge_file_dir(&dir).unwrap();
-        write_merge_meta(&merge_file_dir, merge_meta).unwrap();
+        write_merge_meta(&merge_file_dir, &merge_meta).unwrap();
         let merge_manager = MergeManager::new(
             INSTANCE_ID,
             &dir,
@@ -633,8 +1604,8 @@ mod tests {
         );
         merge_manager.recover_merge().unwrap();
         let db = Database::open(&dir, storage_id_generator.clone(), get_options()).unwrap();
-        assert_eq!(3, storage_id_generator.get_id());
-        assert_eq!(0, db.get_storage_ids().stable_storage_ids.len());
+        assert_eq!(4, storage_id_generator.get_id());
+        assert_eq!(1, db.get_storage_ids().stable_storage_ids.len());
         assert_rows_value(&db, &rows);
         assert_database_rows(&db, &rows);
         assert!(!merge_file_dir.exists());
@@ -654,10 +1625,12 @@ mod tests {
             rows.append(&mut write_kvs_to_db(&db, kvs));
         }
         let merge_meta = MergeMeta {
-            known_max_storage_id: storage_id_generator.generate_next_id(),
+            known_max_storage_id: storage_id_generator.generate_next_id().unwrap(),
+            source_storage_ids: vec![],
+            retained_storage_ids: vec![],
         };
         let merge_file_dir = create_merge_file_dir(&dir).unwrap();
-        write_merge_meta(&merge_file_dir, merge_meta).unwrap();
+        write_merge_meta(&merge_file_dir, &merge_meta).unwrap();
         {
             // write something to data file in merge dir
             let db = Database::open(&merge_file_dir, storage_id_generator.clone(), get_options())
@@ -711,10 +1684,14 @@ mod tests {
                 get_options(),
             );
 
-            let files = merge_manager
+            let (files, _) = merge_manager
                 .commit_merge(
                     &db.get_storage_ids().stable_storage_ids,
-                    old_db.get_max_storage_id(),
+                    &MergeMeta {
+                        known_max_storage_id: old_db.writing_storage_id(),
+                        source_storage_ids: vec![],
+                        retained_storage_ids: vec![],
+                    },
                 )
                 .unwrap();
 
@@ -724,4 +1701,67 @@ mod tests {
         assert_eq!(5, storage_id_generator.get_id());
         assert_eq!(1, old_db.get_storage_ids().stable_storage_ids.len());
     }
+
+    #[test]
+    fn test_only_one_merge_at_a_time() {
+        let dir = get_temporary_directory_path();
+        let storage_id_generator = Arc::new(StorageIdGenerator::default());
+        let db =
+            Arc::new(Database::open(&dir, storage_id_generator.clone(), get_options()).unwrap());
+        let keydir = Arc::new(TimedRwLock::new(
+            KeyDir::new_empty_key_dir(),
+            LockTimer::default(),
+        ));
+        let merge_manager = Arc::new(MergeManager::new(
+            INSTANCE_ID,
+            &dir,
+            storage_id_generator,
+            get_options(),
+        ));
+
+        merge_manager.start_merging().unwrap();
+        assert!(merge_manager.get_telemetry_data().is_merging);
+        assert_matches!(
+            merge_manager.merge_async(db.clone(), keydir.clone()),
+            Err(BitcaskyError::MergeInProgress())
+        );
+        assert_matches!(
+            merge_manager.merge(&db, &keydir, &MergeCancelToken::default()),
+            Err(BitcaskyError::MergeInProgress())
+        );
+
+        merge_manager.merging.store(false, Ordering::Release);
+        merge_manager
+            .merge_async(db.clone(), keydir.clone())
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(!merge_manager.get_telemetry_data().is_merging);
+    }
+
+    #[test]
+    fn test_merge_estimate() {
+        let estimate = MergeEstimate::new(2500, 1024, 100, Some(500.0), None);
+        assert_eq!(2500, estimate.live_bytes_to_rewrite);
+        assert_eq!(3, estimate.est_output_files);
+        assert_eq!(3 * 1124, estimate.required_free_bytes);
+        assert_eq!(Some(Duration::from_secs(5)), estimate.est_duration);
+
+        let estimate = MergeEstimate::new(2048, 1024, 100, None, None);
+        assert_eq!(2, estimate.est_output_files);
+        assert_eq!(None, estimate.est_duration);
+
+        // live bytes are read, written and read back under the rate limit
+        let estimate = MergeEstimate::new(2500, 1024, 100, None, Some(1500));
+        assert_eq!(Some(Duration::from_secs(5)), estimate.est_duration);
+        let estimate = MergeEstimate::new(2500, 1024, 100, Some(500.0), Some(7500));
+        assert_eq!(Some(Duration::from_secs(5)), estimate.est_duration);
+        let estimate = MergeEstimate::new(2500, 1024, 100, Some(5000.0), Some(1500));
+        assert_eq!(Some(Duration::from_secs(5)), estimate.est_duration);
+
+        let estimate = MergeEstimate::new(0, 1024, 100, Some(500.0), None);
+        assert_eq!(0, estimate.est_output_files);
+        assert_eq!(0, estimate.required_free_bytes);
+        assert_eq!(Some(Duration::ZERO), estimate.est_duration);
+    }
 }
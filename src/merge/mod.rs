@@ -1,2 +1,3 @@
 mod core;
+pub(crate) mod rate_limiter;
 pub use self::core::*;
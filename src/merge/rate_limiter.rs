@@ -0,0 +1,75 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// Sleeps shorter than this are postponed until more bytes are consumed, so merge does not
+/// sleep on every row
+const MIN_SLEEP: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+struct LimiterState {
+    start: Instant,
+    consumed: u64,
+}
+
+/// Paces IO of a merge to a budget of bytes per second. Bytes consumed ahead of the budget
+/// are paid back by sleeping in `consume`.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    /// Never sleeps when `bytes_per_sec` is None
+    pub fn new(bytes_per_sec: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec,
+            state: Mutex::new(LimiterState {
+                start: Instant::now(),
+                consumed: 0,
+            }),
+        }
+    }
+
+    /// Accounts bytes read or written, sleeping until they are within the budget
+    pub fn consume(&self, bytes: u64) {
+        let Some(bytes_per_sec) = self.bytes_per_sec else {
+            return;
+        };
+        let mut state = self.state.lock();
+        state.consumed += bytes;
+        let due = Duration::from_secs_f64(state.consumed as f64 / bytes_per_sec as f64);
+        let ahead = due.saturating_sub(state.start.elapsed());
+        if ahead >= MIN_SLEEP {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consume_paces_to_budget() {
+        let limiter = RateLimiter::new(Some(10_000));
+        let start = Instant::now();
+        for _ in 0..20 {
+            limiter.consume(100);
+        }
+        // 2000 bytes at 10000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[test]
+    fn test_unlimited_never_sleeps() {
+        let limiter = RateLimiter::new(None);
+        let start = Instant::now();
+        limiter.consume(u64::MAX / 2);
+        assert!(start.elapsed() < MIN_SLEEP);
+    }
+}
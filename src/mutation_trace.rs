@@ -0,0 +1,170 @@
+//! Opt-in trace of the latest keydir mutations enabled by `BitcaskyOptions::mutation_trace`, to
+//! find out what happened to a key recently, like why it's gone.
+//!
+//! Keys are never recorded, only a hash of them by `key_hash`. Mutations are recorded while
+//! keydir is locked for write by the mutation itself, so recording one is a single push to a
+//! bounded buffer without taking any other lock.
+
+use std::{
+    collections::VecDeque,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+};
+
+use ahash::RandomState;
+
+use crate::clock::Clock;
+use crate::database::RowLocation;
+use crate::options::BitcaskyOptions;
+
+// fixed seeds so the same key always has the same hash
+const KEY_HASH_SEEDS: [u64; 4] = [
+    0x9e37_79b9_7f4a_7c15,
+    0xbf58_476d_1ce4_e5b9,
+    0x94d0_49bb_1331_11eb,
+    0x2545_f491_4f6c_dd1d,
+];
+
+/// What mutated a key in keydir
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MutationCause {
+    /// Written by put or any other write of a value
+    Put,
+    /// Removed by delete or any other write of a tombstone
+    Delete,
+    /// Relocated to a merged data file, or removed by merge as its value expired
+    MergeApply,
+    /// Removed by the expiry sweeper as its value expired
+    TtlSweep,
+    /// Removed as its value could not be rewritten out of a data file pending purge
+    Repair,
+}
+
+/// A mutation of a key in keydir
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyMutation {
+    /// Counts mutations recorded since open, including those dropped from the trace
+    pub sequence: u64,
+    /// Hash of the key by `key_hash`
+    pub key_hash: u64,
+    /// Milliseconds since epoch
+    pub timestamp: u64,
+    pub cause: MutationCause,
+    /// Location before the mutation, `None` if the key was absent
+    pub old_location: Option<RowLocation>,
+    /// Location after the mutation, `None` if the key was removed
+    pub new_location: Option<RowLocation>,
+}
+
+/// Hash of the key recorded in `KeyMutation`. It's the same for a key as long as the version
+/// of bitcasky is not changed.
+pub fn key_hash(key: &[u8]) -> u64 {
+    let [k0, k1, k2, k3] = KEY_HASH_SEEDS;
+    let mut hasher = RandomState::with_seeds(k0, k1, k2, k3).build_hasher();
+    hasher.write(key);
+    hasher.finish()
+}
+
+/// Latest keydir mutations up to a capacity, older ones are dropped
+#[derive(Debug)]
+pub(crate) struct MutationTrace {
+    mutations: VecDeque<KeyMutation>,
+    capacity: usize,
+    recorded: u64,
+    options: Arc<BitcaskyOptions>,
+}
+
+impl MutationTrace {
+    pub fn new(capacity: usize, options: Arc<BitcaskyOptions>) -> MutationTrace {
+        MutationTrace {
+            mutations: VecDeque::with_capacity(capacity),
+            capacity,
+            recorded: 0,
+            options,
+        }
+    }
+
+    /// Records a mutation of the key with the hash by `key_hash`
+    pub fn record(
+        &mut self,
+        cause: MutationCause,
+        key_hash: u64,
+        old_location: Option<RowLocation>,
+        new_location: Option<RowLocation>,
+    ) {
+        if self.mutations.len() == self.capacity {
+            self.mutations.pop_front();
+        }
+        self.mutations.push_back(KeyMutation {
+            sequence: self.recorded,
+            key_hash,
+            timestamp: self.options.clock.now(),
+            cause,
+            old_location,
+            new_location,
+        });
+        self.recorded += 1;
+    }
+
+    /// Mutations in the order they were recorded
+    pub fn mutations(&self) -> Vec<KeyMutation> {
+        self.mutations.iter().copied().collect()
+    }
+
+    /// Mutations of the key in the order they were recorded
+    pub fn mutations_of_key(&self, key: &[u8]) -> Vec<KeyMutation> {
+        let hash = key_hash(key);
+        self.mutations
+            .iter()
+            .filter(|m| m.key_hash == hash)
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn location(row_offset: usize) -> RowLocation {
+        RowLocation {
+            storage_id: 1,
+            row_offset,
+            row_size: 10,
+        }
+    }
+
+    #[test]
+    fn test_drop_oldest_mutations() {
+        let mut trace = MutationTrace::new(2, Arc::new(BitcaskyOptions::default()));
+        trace.record(MutationCause::Put, key_hash(b"k1"), None, Some(location(0)));
+        trace.record(
+            MutationCause::Put,
+            key_hash(b"k2"),
+            None,
+            Some(location(10)),
+        );
+        trace.record(
+            MutationCause::Delete,
+            key_hash(b"k1"),
+            Some(location(0)),
+            None,
+        );
+
+        let mutations = trace.mutations();
+        assert_eq!(
+            vec![1, 2],
+            mutations.iter().map(|m| m.sequence).collect::<Vec<_>>()
+        );
+        assert_eq!(key_hash(b"k2"), mutations[0].key_hash);
+        assert_ne!(key_hash(b"k1"), key_hash(b"k2"));
+        let k1 = trace.mutations_of_key(b"k1");
+        assert_eq!(1, k1.len());
+        assert_eq!(MutationCause::Delete, k1[0].cause);
+        assert_eq!(Some(location(0)), k1[0].old_location);
+    }
+}
@@ -1,13 +1,20 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use crate::clock::BitcaskyClock;
+use crate::codec::ValueCodec;
+use crate::database::RecoveryProgress;
+use crate::error::{BitcaskyError, BitcaskyResult};
+pub use crate::formatter::ChecksumAlgorithm;
+use crate::formatter::{RowFormatter, MIN_CUSTOM_FORMATTER_VERSION};
+use crate::fs::FileType;
+use crate::maintenance::MaintenancePool;
+pub use crate::tuning::{Durability, TunedOptions, TuningChoice, WorkloadProfile};
 
 #[cfg(test)]
 use crate::clock::DebugClock;
-#[cfg(test)]
-use std::sync::Arc;
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SyncStrategy {
     // Never sync
     None,
@@ -15,28 +22,127 @@ pub enum SyncStrategy {
     // Sync after every write
     OSync,
 
-    // Sync at specified intervals
-    Interval(Duration),
+    // Sync at specified intervals, needs the `sync-worker` feature
+    Interval(#[cfg_attr(feature = "serde", serde(with = "duration_secs"))] Duration),
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataSotrageType {
+    /// Map data files into memory, needs the `mmap` feature
+    #[cfg(feature = "mmap")]
     Mmap,
+    /// Read and write data files through file IO
+    File,
+}
+
+impl Default for DataSotrageType {
+    fn default() -> Self {
+        #[cfg(feature = "mmap")]
+        return DataSotrageType::Mmap;
+        #[cfg(not(feature = "mmap"))]
+        return DataSotrageType::File;
+    }
+}
+
+/// Layouts of files other than bitcasky's own recovered on open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FormatCompat {
+    /// Only files written by bitcasky
+    #[default]
+    Bitcasky,
+    /// Also recover from data files and hint files left by the original Erlang bitcask, named
+    /// like `1.bitcask.data`. They are read only, new rows are written to data files of bitcasky
+    /// and merge rewrites all the rows in them to data files of bitcasky
+    ErlangBitcask,
+}
+
+impl FormatCompat {
+    /// Types of data files recovered with this compatibility mode
+    pub(crate) fn data_file_types(&self) -> &'static [FileType] {
+        match self {
+            FormatCompat::Bitcasky => &[FileType::DataFile],
+            FormatCompat::ErlangBitcask => &[FileType::DataFile, FileType::ErlangDataFile],
+        }
+    }
+}
+
+/// What merge does with a row which still can not be read after retries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MergeErrorPolicy {
+    /// Fail the merge and leave all the data files as they were
+    #[default]
+    Abort,
+    /// Leave the key at its old location and keep the data file holding it, so the key is
+    /// still readable after merge. Skipped keys are reported by `MergeReport::failed_keys`
+    Skip,
+}
+
+/// What opening a database does with corrupted rows found by `validate_on_open`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CorruptionPolicy {
+    /// Fail the open with `BitcaskyError::DataFileCorrupted` at the first corrupted row
+    #[default]
+    FailFast,
+    /// Open anyway with `skip_corrupted` set, so rows after corrupted ones are still recovered.
+    /// Reading a key at a corrupted row still fails
+    SkipCorrupted,
+    /// Drop the first corrupted row and all the rows after it in the same data file, along with
+    /// its hint file
+    TruncateAtCorruption,
 }
 
+/// Initial size of data files and hint files. Files grow on demand up to their max size
+#[cfg(not(feature = "small-footprint"))]
+const DEFAULT_INIT_FILE_CAPACITY: usize = 1024 * 1024;
+#[cfg(feature = "small-footprint")]
+const DEFAULT_INIT_FILE_CAPACITY: usize = 64 * 1024;
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct DataStorageOptions {
     pub max_data_file_size: usize,
     pub init_data_file_capacity: usize,
     pub storage_type: DataSotrageType,
+    /// Skip corrupted rows and keep going when iterating a data file instead of stopping at them
+    pub skip_corrupted: bool,
+    /// Verify the checksum of each row read by its location. Rows are always verified during iteration
+    pub verify_crc_on_read: bool,
+    /// Rows with larger keys are rejected before written to data file
+    pub max_key_size: usize,
+    /// Rows with larger values are rejected before written to data file
+    pub max_value_size: usize,
+    /// Encodes rows of new data files instead of the builtin formatter
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub row_formatter: Option<&'static dyn RowFormatter>,
+    /// Data files of other layouts to recover along with data files of bitcasky
+    pub format_compat: FormatCompat,
+    /// Bytes of rows buffered in memory before written to data file, 0 writes each row at once.
+    /// Only used by `DataSotrageType::File`
+    pub write_buffer_size: usize,
+    /// Checksums rows of new data files written by the builtin formatter. Existing data files
+    /// are read with the algorithm recorded in their header
+    pub checksum_algorithm: ChecksumAlgorithm,
 }
 
 impl Default for DataStorageOptions {
     fn default() -> Self {
         Self {
             max_data_file_size: 128 * 1024 * 1024,
-            init_data_file_capacity: 1024 * 1024,
-            storage_type: DataSotrageType::Mmap,
+            init_data_file_capacity: DEFAULT_INIT_FILE_CAPACITY,
+            storage_type: DataSotrageType::default(),
+            skip_corrupted: false,
+            verify_crc_on_read: true,
+            max_key_size: 1024,
+            max_value_size: 100 * 1024,
+            row_formatter: None,
+            format_compat: FormatCompat::default(),
+            write_buffer_size: 0,
+            checksum_algorithm: ChecksumAlgorithm::default(),
         }
     }
 }
@@ -58,14 +164,72 @@ impl DataStorageOptions {
         self.storage_type = storage_type;
         self
     }
+
+    pub fn skip_corrupted(mut self, skip_corrupted: bool) -> DataStorageOptions {
+        self.skip_corrupted = skip_corrupted;
+        self
+    }
+
+    pub fn verify_crc_on_read(mut self, verify_crc_on_read: bool) -> DataStorageOptions {
+        self.verify_crc_on_read = verify_crc_on_read;
+        self
+    }
+
+    pub fn max_key_size(mut self, size: usize) -> DataStorageOptions {
+        assert!(size > 0);
+        self.max_key_size = size;
+        self
+    }
+
+    pub fn max_value_size(mut self, size: usize) -> DataStorageOptions {
+        assert!(size > 0);
+        self.max_value_size = size;
+        self
+    }
+
+    pub fn row_formatter(mut self, formatter: &'static dyn RowFormatter) -> DataStorageOptions {
+        self.row_formatter = Some(formatter);
+        self
+    }
+
+    pub fn format_compat(mut self, format_compat: FormatCompat) -> DataStorageOptions {
+        self.format_compat = format_compat;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, size: usize) -> DataStorageOptions {
+        self.write_buffer_size = size;
+        self
+    }
+
+    pub fn checksum_algorithm(mut self, algorithm: ChecksumAlgorithm) -> DataStorageOptions {
+        self.checksum_algorithm = algorithm;
+        self
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct DatabaseOptions {
     pub storage: DataStorageOptions,
     /// How frequent can we flush data
     pub sync_strategy: SyncStrategy,
     pub init_hint_file_capacity: usize,
+    /// How many data files can be scanned concurrently when rebuilding keydir on open.
+    /// Scanning runs on the maintenance pool so it's also bounded by threads of the pool
+    pub recovery_parallelism: usize,
+    /// How long to wait for pending hint files to be written when closing database
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub hint_write_timeout: Duration,
+    /// Write hint files in background on open for data files without a valid hint file
+    pub rebuild_hint_files_on_open: bool,
+    /// Write hint files for data files once they are full. Without them data files are
+    /// scanned on open
+    pub write_hint_files: bool,
+    /// Max number of stable data files kept open, others are closed and reopened on read.
+    /// Unlimited if not set
+    pub max_open_files: Option<usize>,
 }
 
 impl DatabaseOptions {
@@ -73,20 +237,82 @@ impl DatabaseOptions {
         self.storage = storage;
         self
     }
+
+    pub fn recovery_parallelism(mut self, parallelism: usize) -> Self {
+        assert!(parallelism > 0);
+        self.recovery_parallelism = parallelism;
+        self
+    }
+
+    pub fn hint_write_timeout(mut self, timeout: Duration) -> Self {
+        self.hint_write_timeout = timeout;
+        self
+    }
+
+    pub fn rebuild_hint_files_on_open(mut self, rebuild: bool) -> Self {
+        self.rebuild_hint_files_on_open = rebuild;
+        self
+    }
+
+    pub fn write_hint_files(mut self, write: bool) -> Self {
+        self.write_hint_files = write;
+        self
+    }
+
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        assert!(max_open_files > 0);
+        self.max_open_files = Some(max_open_files);
+        self
+    }
 }
 
 impl Default for DatabaseOptions {
     fn default() -> Self {
         Self {
             storage: DataStorageOptions::default(),
-            init_hint_file_capacity: 1024 * 1024,
+            init_hint_file_capacity: DEFAULT_INIT_FILE_CAPACITY,
+            #[cfg(feature = "sync-worker")]
             sync_strategy: SyncStrategy::Interval(Duration::from_secs(60)),
+            #[cfg(not(feature = "sync-worker"))]
+            sync_strategy: SyncStrategy::None,
+            #[cfg(not(feature = "small-footprint"))]
+            recovery_parallelism: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            #[cfg(feature = "small-footprint")]
+            recovery_parallelism: 1,
+            hint_write_timeout: Duration::from_secs(10),
+            rebuild_hint_files_on_open: false,
+            write_hint_files: true,
+            max_open_files: None,
         }
     }
 }
 
+/// Options of the bloom filter over keys, used by `Bitcasky::may_contain`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BloomFilterOptions {
+    /// How many keys the filter is sized for. False positive rate grows when more keys are stored
+    pub expected_items: usize,
+    /// Target false positive rate when `expected_items` keys are stored
+    pub false_positive_rate: f64,
+}
+
+/// Callback registered by `BitcaskyOptions::on_recovery_progress`
+#[derive(Clone)]
+pub struct RecoveryProgressCallback(pub Arc<dyn Fn(RecoveryProgress) + Send + Sync>);
+
+impl std::fmt::Debug for RecoveryProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RecoveryProgressCallback")
+    }
+}
+
 /// Bitcask optional options. Used on opening Bitcask instance.
+/// With `serde` feature, options are validated on deserialization and missing fields take default values.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct BitcaskyOptions {
     pub database: DatabaseOptions,
     // maximum key size, default: 1 KB
@@ -94,7 +320,63 @@ pub struct BitcaskyOptions {
     // maximum value size, default: 100 KB
     pub max_value_size: usize,
     // clock to get time,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub clock: BitcaskyClock,
+    // merge automatically when dead bytes ratio of all data files exceeds this threshold
+    pub auto_merge_threshold: Option<f64>,
+    // how frequent to check whether to merge automatically
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub auto_merge_check_interval: Duration,
+    // pool to run background work on, default: a pool with 2 threads owned by the instance
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub maintenance_pool: Option<Arc<MaintenancePool>>,
+    // bloom filter over keys to check key existence without keydir lookup
+    pub bloom_filter: Option<BloomFilterOptions>,
+    // how long to wait for the directory lock held by other process on open
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub lock_wait_timeout: Duration,
+    // codec applied to values written to data files
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub value_codec: Option<Arc<dyn ValueCodec>>,
+    // reject timestamps passed to put_with_timestamp and delete_with_timestamp which are later
+    // than local time by more than this
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs::option"))]
+    pub max_clock_skew: Option<Duration>,
+    // how many times merge retries reading a row after it failed
+    pub merge_read_retries: usize,
+    // wait before the first retry of reading a row in merge, doubled on each retry
+    #[cfg_attr(feature = "serde", serde(with = "duration_millis"))]
+    pub merge_read_retry_backoff: Duration,
+    // what merge does with a row which can not be read after retries
+    pub merge_error_policy: MergeErrorPolicy,
+    // rows expired less than this ago are kept by merge, in case clocks of nodes are skewed
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub merge_expire_margin: Duration,
+    // refuse to merge when the estimated free space it requires is not available on disk
+    pub merge_check_free_space: bool,
+    // bytes merge reads and writes per second, including hint files of merged data files
+    pub merge_rate_limit_bytes_per_sec: Option<u64>,
+    // record this many latest keydir mutations for Bitcasky::trace_for_key and doctor report
+    pub mutation_trace_capacity: Option<usize>,
+    // remove keys with expired values from keydir in background at this interval
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs::option"))]
+    pub expiry_sweep_interval: Option<Duration>,
+    // how many keys are checked by the expiry sweeper before expired ones are removed under one
+    // hold of the keydir write lock
+    pub expiry_sweep_chunk_size: usize,
+    // write tombstones for keys removed by the expiry sweeper, so readers of data files see them
+    // deleted
+    pub expiry_sweep_write_tombstones: bool,
+    // keep keydir in SnapshotKeyDirBackend when opened by Bitcasky::open
+    pub keydir_snapshot: bool,
+    // room for at least this many keys is reserved in keydir before data files are recovered
+    pub initial_keydir_capacity: usize,
+    // called each time a data file is recovered on open
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub on_recovery_progress: Option<RecoveryProgressCallback>,
+    // verify checksum of every row in data files before they are recovered on open
+    pub validate_on_open: bool,
+    pub corruption_policy: CorruptionPolicy,
 }
 
 /// Default Bitcask Options
@@ -105,11 +387,147 @@ impl Default for BitcaskyOptions {
             max_key_size: 1024,
             max_value_size: 100 * 1024,
             clock: BitcaskyClock::default(),
+            auto_merge_threshold: None,
+            auto_merge_check_interval: Duration::from_secs(60),
+            maintenance_pool: None,
+            bloom_filter: None,
+            lock_wait_timeout: Duration::ZERO,
+            value_codec: None,
+            max_clock_skew: None,
+            merge_read_retries: 0,
+            merge_read_retry_backoff: Duration::from_millis(100),
+            merge_error_policy: MergeErrorPolicy::default(),
+            merge_expire_margin: Duration::ZERO,
+            merge_check_free_space: false,
+            merge_rate_limit_bytes_per_sec: None,
+            mutation_trace_capacity: None,
+            expiry_sweep_interval: None,
+            expiry_sweep_chunk_size: 1024,
+            expiry_sweep_write_tombstones: false,
+            keydir_snapshot: false,
+            initial_keydir_capacity: 0,
+            on_recovery_progress: None,
+            validate_on_open: false,
+            corruption_policy: CorruptionPolicy::default(),
         }
     }
 }
 
 impl BitcaskyOptions {
+    /// Options for devices with little memory. Data files are read and written through file IO
+    /// instead of mapped into memory, files start small, recovery scans one data file at a time
+    /// and nothing is synced in background, call `Bitcasky::sync` to sync writes.
+    pub fn minimal() -> BitcaskyOptions {
+        BitcaskyOptions::default()
+            .max_data_file_size(8 * 1024 * 1024)
+            .init_data_file_capacity(16 * 1024)
+            .init_hint_file_capacity(4 * 1024)
+            .storage_type(DataSotrageType::File)
+            .sync_strategy(SyncStrategy::None)
+            .recovery_parallelism(1)
+    }
+
+    /// Checks the same constraints as the builder functions
+    pub fn validate(&self) -> BitcaskyResult<()> {
+        let storage = &self.database.storage;
+        let positive_sizes = [
+            ("max_data_file_size", storage.max_data_file_size),
+            ("init_data_file_capacity", storage.init_data_file_capacity),
+            (
+                "init_hint_file_capacity",
+                self.database.init_hint_file_capacity,
+            ),
+            ("recovery_parallelism", self.database.recovery_parallelism),
+            ("max_open_files", self.database.max_open_files.unwrap_or(1)),
+            ("max_key_size", self.max_key_size),
+            ("max_value_size", self.max_value_size),
+            ("expiry_sweep_chunk_size", self.expiry_sweep_chunk_size),
+            ("database.storage.max_key_size", storage.max_key_size),
+            ("database.storage.max_value_size", storage.max_value_size),
+        ];
+        for (name, size) in positive_sizes {
+            if size == 0 {
+                return Err(BitcaskyError::InvalidParameter(
+                    name.into(),
+                    "should be greater than zero".into(),
+                ));
+            }
+        }
+        if let Some(threshold) = self.auto_merge_threshold {
+            if !(threshold > 0.0 && threshold < 1.0) {
+                return Err(BitcaskyError::InvalidParameter(
+                    "auto_merge_threshold".into(),
+                    format!("should be between 0 and 1, but is {}", threshold),
+                ));
+            }
+        }
+        if self.auto_merge_check_interval.is_zero() {
+            return Err(BitcaskyError::InvalidParameter(
+                "auto_merge_check_interval".into(),
+                "should not be zero".into(),
+            ));
+        }
+        if self.merge_rate_limit_bytes_per_sec == Some(0) {
+            return Err(BitcaskyError::InvalidParameter(
+                "merge_rate_limit_bytes_per_sec".into(),
+                "should be greater than zero".into(),
+            ));
+        }
+        if self.mutation_trace_capacity == Some(0) {
+            return Err(BitcaskyError::InvalidParameter(
+                "mutation_trace_capacity".into(),
+                "should be greater than zero".into(),
+            ));
+        }
+        if self.expiry_sweep_interval.is_some_and(|i| i.is_zero()) {
+            return Err(BitcaskyError::InvalidParameter(
+                "expiry_sweep_interval".into(),
+                "should not be zero".into(),
+            ));
+        }
+        #[cfg(not(feature = "sync-worker"))]
+        if let SyncStrategy::Interval(_) = self.database.sync_strategy {
+            return Err(BitcaskyError::InvalidParameter(
+                "database.sync_strategy".into(),
+                "interval sync needs the sync-worker feature".into(),
+            ));
+        }
+        if let Some(formatter) = self.database.storage.row_formatter {
+            if self.database.storage.checksum_algorithm != ChecksumAlgorithm::default() {
+                return Err(BitcaskyError::InvalidParameter(
+                    "database.storage.checksum_algorithm".into(),
+                    "rows encoded by a custom formatter are checksummed by the formatter".into(),
+                ));
+            }
+            if formatter.version() < MIN_CUSTOM_FORMATTER_VERSION {
+                return Err(BitcaskyError::InvalidParameter(
+                    "database.storage.row_formatter".into(),
+                    format!(
+                        "version should not be less than {}, but is {}",
+                        MIN_CUSTOM_FORMATTER_VERSION,
+                        formatter.version()
+                    ),
+                ));
+            }
+        }
+        if let Some(bloom_filter) = self.bloom_filter {
+            if bloom_filter.expected_items == 0 {
+                return Err(BitcaskyError::InvalidParameter(
+                    "bloom_filter.expected_items".into(),
+                    "should be greater than zero".into(),
+                ));
+            }
+            let rate = bloom_filter.false_positive_rate;
+            if !(rate > 0.0 && rate < 1.0) {
+                return Err(BitcaskyError::InvalidParameter(
+                    "bloom_filter.false_positive_rate".into(),
+                    format!("should be between 0 and 1, but is {}", rate),
+                ));
+            }
+        }
+        Ok(())
+    }
+
     // maximum data file size, default: 128 MB
     pub fn max_data_file_size(mut self, size: usize) -> BitcaskyOptions {
         assert!(size > 0);
@@ -150,12 +568,245 @@ impl BitcaskyOptions {
         self
     }
 
+    // skip corrupted rows instead of stopping at them when iterating data files, default: false
+    pub fn skip_corrupted(mut self, skip_corrupted: bool) -> BitcaskyOptions {
+        self.database.storage.skip_corrupted = skip_corrupted;
+        self
+    }
+
+    // verify row checksum when reading a value by its location, default: true
+    pub fn verify_crc_on_read(mut self, verify_crc_on_read: bool) -> BitcaskyOptions {
+        self.database.storage.verify_crc_on_read = verify_crc_on_read;
+        self
+    }
+
     // How to sync data to file. default: sync data on every minute
     pub fn sync_strategy(mut self, sync_strategy: SyncStrategy) -> BitcaskyOptions {
         self.database.sync_strategy = sync_strategy;
         self
     }
 
+    // how many data files can be recovered concurrently on open, default: available parallelism
+    pub fn recovery_parallelism(mut self, parallelism: usize) -> BitcaskyOptions {
+        assert!(parallelism > 0);
+        self.database.recovery_parallelism = parallelism;
+        self
+    }
+
+    // how long to wait for pending hint files to be written on close, default: 10 seconds
+    pub fn hint_write_timeout(mut self, timeout: Duration) -> BitcaskyOptions {
+        self.database.hint_write_timeout = timeout;
+        self
+    }
+
+    // write missing or corrupted hint files in background on open, default: false
+    pub fn rebuild_hint_files_on_open(mut self, rebuild: bool) -> BitcaskyOptions {
+        self.database.rebuild_hint_files_on_open = rebuild;
+        self
+    }
+
+    // write hint files for full data files, turn it off for write heavy workloads where hint
+    // files cost more IO than they save on open, which then scans data files without a hint
+    // file, default: true
+    pub fn write_hint_files(mut self, write: bool) -> BitcaskyOptions {
+        self.database.write_hint_files = write;
+        self
+    }
+
+    // keep at most about this many data files open besides the writing file, closing the
+    // ones not read recently and reopening them on read, so thousands of data files do not
+    // exhaust file descriptors, default: unlimited
+    pub fn max_open_files(mut self, max_open_files: usize) -> BitcaskyOptions {
+        assert!(max_open_files > 0);
+        self.database.max_open_files = Some(max_open_files);
+        self
+    }
+
+    // merge automatically when dead bytes ratio of all data files exceeds threshold, default: disabled
+    pub fn auto_merge(mut self, threshold: f64) -> BitcaskyOptions {
+        assert!(threshold > 0.0 && threshold < 1.0);
+        self.auto_merge_threshold = Some(threshold);
+        self
+    }
+
+    // how frequent to check whether to merge automatically, default: every minute
+    pub fn auto_merge_check_interval(mut self, interval: Duration) -> BitcaskyOptions {
+        assert!(!interval.is_zero());
+        self.auto_merge_check_interval = interval;
+        self
+    }
+
+    // enable bloom filter sized for expected_items keys with the target false positive rate, default: disabled
+    pub fn bloom_filter(
+        mut self,
+        expected_items: usize,
+        false_positive_rate: f64,
+    ) -> BitcaskyOptions {
+        assert!(expected_items > 0);
+        assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0);
+        self.bloom_filter = Some(BloomFilterOptions {
+            expected_items,
+            false_positive_rate,
+        });
+        self
+    }
+
+    // wait for the directory lock held by other process on open instead of failing immediately, default: not wait
+    pub fn lock_wait_timeout(mut self, timeout: Duration) -> BitcaskyOptions {
+        self.lock_wait_timeout = timeout;
+        self
+    }
+
+    // encode values before they are written to data files, e.g. to encrypt them, default: no codec.
+    // A database must always be opened with the same codec
+    pub fn value_codec(mut self, codec: Arc<dyn ValueCodec>) -> BitcaskyOptions {
+        self.value_codec = Some(codec);
+        self
+    }
+
+    // reject timestamps passed to put_with_timestamp and delete_with_timestamp which are later
+    // than local time by more than skew, so a replicated row stamped by a broken clock can not
+    // shadow every later write of its key, default: not checked
+    pub fn max_clock_skew(mut self, skew: Duration) -> BitcaskyOptions {
+        self.max_clock_skew = Some(skew);
+        self
+    }
+
+    // retry reading a row in merge up to retries times, waiting backoff before the first retry
+    // and doubling it on each retry, default: not retried
+    pub fn merge_read_retries(mut self, retries: usize, backoff: Duration) -> BitcaskyOptions {
+        self.merge_read_retries = retries;
+        self.merge_read_retry_backoff = backoff;
+        self
+    }
+
+    // abort merge or skip the row when a row can not be read after retries,
+    // default: MergeErrorPolicy::Abort
+    pub fn merge_error_policy(mut self, policy: MergeErrorPolicy) -> BitcaskyOptions {
+        self.merge_error_policy = policy;
+        self
+    }
+
+    // keep rows expired less than margin ago in merge instead of dropping them,
+    // default: expired rows are dropped
+    pub fn merge_expire_margin(mut self, margin: Duration) -> BitcaskyOptions {
+        self.merge_expire_margin = margin;
+        self
+    }
+
+    // refuse to start merge with InsufficientFreeSpace when disk space available is less than
+    // the space required by merge estimated like Bitcasky::merge_estimate, default: false
+    pub fn merge_check_free_space(mut self, check: bool) -> BitcaskyOptions {
+        self.merge_check_free_space = check;
+        self
+    }
+
+    // limit bytes read and written by merge per second, including reading merged data files
+    // back and writing their hint files, so merge leaves disk bandwidth to live traffic,
+    // default: None, not limited
+    pub fn merge_rate_limit_bytes_per_sec(mut self, limit: Option<u64>) -> BitcaskyOptions {
+        assert!(limit != Some(0));
+        self.merge_rate_limit_bytes_per_sec = limit;
+        self
+    }
+
+    // record the latest capacity keydir mutations with the hash of their keys, queried by
+    // Bitcasky::trace_for_key and included in doctor report, default: disabled
+    pub fn mutation_trace(mut self, capacity: usize) -> BitcaskyOptions {
+        assert!(capacity > 0);
+        self.mutation_trace_capacity = Some(capacity);
+        self
+    }
+
+    // remove keys with expired values from keydir at the interval in background, in chunks of
+    // chunk_size keys, default: disabled
+    pub fn expiry_sweep(mut self, interval: Duration, chunk_size: usize) -> BitcaskyOptions {
+        assert!(!interval.is_zero());
+        assert!(chunk_size > 0);
+        self.expiry_sweep_interval = Some(interval);
+        self.expiry_sweep_chunk_size = chunk_size;
+        self
+    }
+
+    // write tombstones for keys removed by the expiry sweeper, so replicas reading data files
+    // converge, default: false
+    pub fn expiry_sweep_write_tombstones(mut self, write: bool) -> BitcaskyOptions {
+        self.expiry_sweep_write_tombstones = write;
+        self
+    }
+
+    // write keydir to a snapshot file in the database directory on close and load it on open
+    // instead of recovering data files, when data files did not change since, default: false
+    pub fn keydir_snapshot(mut self, enable: bool) -> BitcaskyOptions {
+        self.keydir_snapshot = enable;
+        self
+    }
+
+    // reserve room for at least this many keys in keydir before data files are recovered, so it
+    // does not grow and rehash repeatedly on recovery. Room is also reserved by an estimate
+    // from the size of data files, default: 0
+    pub fn initial_keydir_capacity(mut self, capacity: usize) -> BitcaskyOptions {
+        self.initial_keydir_capacity = capacity;
+        self
+    }
+
+    // call f each time a data file is recovered to rebuild keydir on open, with how much of
+    // all the data files is recovered so far, default: not called
+    pub fn on_recovery_progress(
+        mut self,
+        f: Arc<dyn Fn(RecoveryProgress) + Send + Sync>,
+    ) -> BitcaskyOptions {
+        self.on_recovery_progress = Some(RecoveryProgressCallback(f));
+        self
+    }
+
+    // verify checksum of every row in data files before they are recovered on open, like
+    // Bitcasky::verify, default: false
+    pub fn validate_on_open(mut self, validate: bool) -> BitcaskyOptions {
+        self.validate_on_open = validate;
+        self
+    }
+
+    // what to do with corrupted rows found by validate_on_open, default: FailFast
+    pub fn corruption_policy(mut self, policy: CorruptionPolicy) -> BitcaskyOptions {
+        self.corruption_policy = policy;
+        self
+    }
+
+    // encode rows of new data files with a custom formatter, default: the builtin formatter.
+    // Data files written by it can only be opened while it is set or registered
+    pub fn row_formatter(mut self, formatter: &'static dyn RowFormatter) -> BitcaskyOptions {
+        self.database.storage.row_formatter = Some(formatter);
+        self
+    }
+
+    // also recover from data files of other layouts, e.g. to migrate a directory of the original
+    // Erlang bitcask, default: only data files of bitcasky
+    pub fn format_compat(mut self, format_compat: FormatCompat) -> BitcaskyOptions {
+        self.database.storage.format_compat = format_compat;
+        self
+    }
+
+    // buffer written rows in memory until this many bytes are buffered or the writing file is
+    // flushed, only for file storage type. Buffered rows are lost on crash, default: 0
+    pub fn write_buffer_size(mut self, size: usize) -> BitcaskyOptions {
+        self.database.storage.write_buffer_size = size;
+        self
+    }
+
+    // checksum rows of new data files by the algorithm, files written before keep theirs and
+    // are rewritten with it on merge, default: ChecksumAlgorithm::Crc32Posix
+    pub fn checksum_algorithm(mut self, algorithm: ChecksumAlgorithm) -> BitcaskyOptions {
+        self.database.storage.checksum_algorithm = algorithm;
+        self
+    }
+
+    // run background work on a pool shared with other instances, default: a pool owned by this instance
+    pub fn maintenance_pool(mut self, pool: Arc<MaintenancePool>) -> BitcaskyOptions {
+        self.maintenance_pool = Some(pool);
+        self
+    }
+
     #[cfg(test)]
     // Use debug clock
     pub fn debug_clock(mut self, clock: Arc<DebugClock>) -> BitcaskyOptions {
@@ -163,3 +814,298 @@ impl BitcaskyOptions {
         self
     }
 }
+
+/// Fields of `BitcaskyOptions` which can be deserialized. Deserialized options are validated
+/// before converted to `BitcaskyOptions`.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct DeserializedOptions {
+    database: DatabaseOptions,
+    max_key_size: usize,
+    max_value_size: usize,
+    auto_merge_threshold: Option<f64>,
+    #[serde(with = "duration_secs")]
+    auto_merge_check_interval: Duration,
+    bloom_filter: Option<BloomFilterOptions>,
+    #[serde(with = "duration_secs")]
+    lock_wait_timeout: Duration,
+    #[serde(with = "duration_secs::option")]
+    max_clock_skew: Option<Duration>,
+    merge_read_retries: usize,
+    #[serde(with = "duration_millis")]
+    merge_read_retry_backoff: Duration,
+    merge_error_policy: MergeErrorPolicy,
+    #[serde(with = "duration_secs")]
+    merge_expire_margin: Duration,
+    merge_check_free_space: bool,
+    merge_rate_limit_bytes_per_sec: Option<u64>,
+    mutation_trace_capacity: Option<usize>,
+    #[serde(with = "duration_secs::option")]
+    expiry_sweep_interval: Option<Duration>,
+    expiry_sweep_chunk_size: usize,
+    expiry_sweep_write_tombstones: bool,
+    keydir_snapshot: bool,
+    initial_keydir_capacity: usize,
+    validate_on_open: bool,
+    corruption_policy: CorruptionPolicy,
+}
+
+#[cfg(feature = "serde")]
+impl Default for DeserializedOptions {
+    fn default() -> Self {
+        let options = BitcaskyOptions::default();
+        Self {
+            database: options.database,
+            max_key_size: options.max_key_size,
+            max_value_size: options.max_value_size,
+            auto_merge_threshold: options.auto_merge_threshold,
+            auto_merge_check_interval: options.auto_merge_check_interval,
+            bloom_filter: options.bloom_filter,
+            lock_wait_timeout: options.lock_wait_timeout,
+            max_clock_skew: options.max_clock_skew,
+            merge_read_retries: options.merge_read_retries,
+            merge_read_retry_backoff: options.merge_read_retry_backoff,
+            merge_error_policy: options.merge_error_policy,
+            merge_expire_margin: options.merge_expire_margin,
+            merge_check_free_space: options.merge_check_free_space,
+            merge_rate_limit_bytes_per_sec: options.merge_rate_limit_bytes_per_sec,
+            mutation_trace_capacity: options.mutation_trace_capacity,
+            expiry_sweep_interval: options.expiry_sweep_interval,
+            expiry_sweep_chunk_size: options.expiry_sweep_chunk_size,
+            expiry_sweep_write_tombstones: options.expiry_sweep_write_tombstones,
+            keydir_snapshot: options.keydir_snapshot,
+            initial_keydir_capacity: options.initial_keydir_capacity,
+            validate_on_open: options.validate_on_open,
+            corruption_policy: options.corruption_policy,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for BitcaskyOptions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let o = DeserializedOptions::deserialize(deserializer)?;
+        let options = BitcaskyOptions {
+            database: o.database,
+            max_key_size: o.max_key_size,
+            max_value_size: o.max_value_size,
+            clock: BitcaskyClock::default(),
+            auto_merge_threshold: o.auto_merge_threshold,
+            auto_merge_check_interval: o.auto_merge_check_interval,
+            maintenance_pool: None,
+            bloom_filter: o.bloom_filter,
+            lock_wait_timeout: o.lock_wait_timeout,
+            value_codec: None,
+            max_clock_skew: o.max_clock_skew,
+            merge_read_retries: o.merge_read_retries,
+            merge_read_retry_backoff: o.merge_read_retry_backoff,
+            merge_error_policy: o.merge_error_policy,
+            merge_expire_margin: o.merge_expire_margin,
+            merge_check_free_space: o.merge_check_free_space,
+            merge_rate_limit_bytes_per_sec: o.merge_rate_limit_bytes_per_sec,
+            mutation_trace_capacity: o.mutation_trace_capacity,
+            expiry_sweep_interval: o.expiry_sweep_interval,
+            expiry_sweep_chunk_size: o.expiry_sweep_chunk_size,
+            expiry_sweep_write_tombstones: o.expiry_sweep_write_tombstones,
+            keydir_snapshot: o.keydir_snapshot,
+            initial_keydir_capacity: o.initial_keydir_capacity,
+            on_recovery_progress: None,
+            validate_on_open: o.validate_on_open,
+            corruption_policy: o.corruption_policy,
+        };
+        options.validate().map_err(serde::de::Error::custom)?;
+        Ok(options)
+    }
+}
+
+/// Serializes `Duration` as integer seconds
+#[cfg(feature = "serde")]
+pub(crate) mod duration_secs {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+    }
+
+    /// Serializes `Option<Duration>` as optional integer seconds
+    pub mod option {
+        use std::time::Duration;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            duration: &Option<Duration>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match duration {
+                Some(d) => serializer.serialize_some(&d.as_secs()),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+        }
+    }
+}
+
+/// Serializes `Duration` as integer milliseconds
+#[cfg(feature = "serde")]
+pub(crate) mod duration_millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn test_options_toml_round_trip() {
+        let options = BitcaskyOptions::default()
+            .max_data_file_size(1024)
+            .init_data_file_capacity(512)
+            .init_hint_file_capacity(256)
+            .max_key_size(64)
+            .max_value_size(128)
+            .skip_corrupted(true)
+            .verify_crc_on_read(false)
+            .sync_strategy(SyncStrategy::Interval(Duration::from_secs(5)))
+            .recovery_parallelism(3)
+            .max_open_files(64)
+            .hint_write_timeout(Duration::from_secs(7))
+            .write_hint_files(false)
+            .auto_merge(0.4)
+            .auto_merge_check_interval(Duration::from_secs(30))
+            .bloom_filter(1000, 0.01)
+            .max_clock_skew(Duration::from_secs(60))
+            .merge_read_retries(3, Duration::from_millis(250))
+            .merge_error_policy(MergeErrorPolicy::Skip)
+            .merge_expire_margin(Duration::from_secs(90))
+            .merge_check_free_space(true)
+            .merge_rate_limit_bytes_per_sec(Some(1024 * 1024))
+            .mutation_trace(128)
+            .expiry_sweep(Duration::from_secs(120), 100)
+            .expiry_sweep_write_tombstones(true)
+            .keydir_snapshot(true)
+            .initial_keydir_capacity(4096)
+            .validate_on_open(true)
+            .corruption_policy(CorruptionPolicy::TruncateAtCorruption)
+            .checksum_algorithm(ChecksumAlgorithm::Crc32c);
+
+        let toml_str = toml::to_string(&options).unwrap();
+        let deserialized: BitcaskyOptions = toml::from_str(&toml_str).unwrap();
+        assert_eq!(toml_str, toml::to_string(&deserialized).unwrap());
+
+        let storage = &deserialized.database.storage;
+        assert_eq!(1024, storage.max_data_file_size);
+        assert_eq!(512, storage.init_data_file_capacity);
+        assert!(storage.skip_corrupted);
+        assert!(!storage.verify_crc_on_read);
+        assert_eq!(ChecksumAlgorithm::Crc32c, storage.checksum_algorithm);
+        assert_eq!(256, deserialized.database.init_hint_file_capacity);
+        assert_eq!(3, deserialized.database.recovery_parallelism);
+        assert_eq!(Some(64), deserialized.database.max_open_files);
+        assert_eq!(
+            Duration::from_secs(7),
+            deserialized.database.hint_write_timeout
+        );
+        assert!(!deserialized.database.write_hint_files);
+        assert_matches!(
+            deserialized.database.sync_strategy,
+            SyncStrategy::Interval(d) if d == Duration::from_secs(5)
+        );
+        assert_eq!(64, deserialized.max_key_size);
+        assert_eq!(128, deserialized.max_value_size);
+        assert_eq!(Some(0.4), deserialized.auto_merge_threshold);
+        assert_eq!(
+            Duration::from_secs(30),
+            deserialized.auto_merge_check_interval
+        );
+        assert_eq!(Some(Duration::from_secs(60)), deserialized.max_clock_skew);
+        assert_eq!(3, deserialized.merge_read_retries);
+        assert_eq!(
+            Duration::from_millis(250),
+            deserialized.merge_read_retry_backoff
+        );
+        assert_eq!(MergeErrorPolicy::Skip, deserialized.merge_error_policy);
+        assert_eq!(Duration::from_secs(90), deserialized.merge_expire_margin);
+        assert!(deserialized.merge_check_free_space);
+        assert_eq!(
+            Some(1024 * 1024),
+            deserialized.merge_rate_limit_bytes_per_sec
+        );
+        assert_eq!(Some(128), deserialized.mutation_trace_capacity);
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            deserialized.expiry_sweep_interval
+        );
+        assert_eq!(100, deserialized.expiry_sweep_chunk_size);
+        assert!(deserialized.expiry_sweep_write_tombstones);
+        assert!(deserialized.keydir_snapshot);
+        assert_eq!(4096, deserialized.initial_keydir_capacity);
+        assert!(deserialized.validate_on_open);
+        assert_eq!(
+            CorruptionPolicy::TruncateAtCorruption,
+            deserialized.corruption_policy
+        );
+        let bloom_filter = deserialized.bloom_filter.unwrap();
+        assert_eq!(1000, bloom_filter.expected_items);
+        assert_eq!(0.01, bloom_filter.false_positive_rate);
+    }
+
+    #[test]
+    fn test_deserialize_partial_options() {
+        let options: BitcaskyOptions = toml::from_str(
+            r#"
+            max_key_size = 10
+
+            [database]
+            sync_strategy = "OSync"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(10, options.max_key_size);
+        assert_matches!(options.database.sync_strategy, SyncStrategy::OSync);
+        assert_eq!(
+            BitcaskyOptions::default().max_value_size,
+            options.max_value_size
+        );
+    }
+
+    #[test]
+    fn test_deserialize_invalid_options() {
+        let err = toml::from_str::<BitcaskyOptions>("auto_merge_threshold = 1.5")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("auto_merge_threshold"));
+
+        let err = toml::from_str::<BitcaskyOptions>("[database.storage]\nmax_data_file_size = 0")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("max_data_file_size"));
+    }
+}
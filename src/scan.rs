@@ -0,0 +1,129 @@
+use std::{sync::Arc, vec};
+
+use crate::lock_stats::TimedRwLock;
+
+use crate::{
+    database::{Database, RowLocation},
+    error::BitcaskyResult,
+    keydir::KeyDir,
+};
+
+/// Iterates keys in a range in lexicographic order, created by `Bitcasky::scan`.
+///
+/// Keys are collected when the iterator is created, values are read when iterated.
+/// Keys deleted after the iterator was created are skipped and keys updated after
+/// that yield their latest value.
+pub struct ScanIter {
+    keys: vec::IntoIter<Vec<u8>>,
+    /// Bytes stripped from the head of keys returned
+    key_prefix_len: usize,
+    keydir: Arc<TimedRwLock<KeyDir>>,
+    database: Arc<Database>,
+}
+
+impl ScanIter {
+    pub(crate) fn new(
+        keydir: Arc<TimedRwLock<KeyDir>>,
+        database: Arc<Database>,
+        start: &[u8],
+        end: &[u8],
+    ) -> ScanIter {
+        Self::filtered(keydir, database, |k| k >= start && k < end)
+    }
+
+    pub(crate) fn with_prefix(
+        keydir: Arc<TimedRwLock<KeyDir>>,
+        database: Arc<Database>,
+        prefix: &[u8],
+    ) -> ScanIter {
+        Self::filtered(keydir, database, |k| k.starts_with(prefix))
+    }
+
+    fn filtered<F>(keydir: Arc<TimedRwLock<KeyDir>>, database: Arc<Database>, f: F) -> ScanIter
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut keys = {
+            let kd = keydir.read();
+            kd.iter()
+                .filter(|(k, _)| f(k))
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<Vec<u8>>>()
+        };
+        keys.sort();
+        ScanIter {
+            keys: keys.into_iter(),
+            key_prefix_len: 0,
+            keydir,
+            database,
+        }
+    }
+
+    /// Returns keys without their first `len` bytes, which all the keys in range share
+    pub(crate) fn strip_key_prefix(mut self, len: usize) -> ScanIter {
+        self.key_prefix_len = len;
+        self
+    }
+}
+
+impl Iterator for ScanIter {
+    type Item = BitcaskyResult<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in self.keys.by_ref() {
+            // hold keydir so the location is not changed by merge during reading
+            let kd = self.keydir.read();
+            let Some(location) = kd.get(&key) else {
+                continue;
+            };
+            match self.database.read_value(&location) {
+                Ok(Some(v)) => return Some(Ok((key[self.key_prefix_len..].to_vec(), v.value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        None
+    }
+}
+
+/// Iterates keys along with the locations of their values, created by `Bitcasky::key_locations`.
+///
+/// Entries are copied from keydir when the iterator is created, so it's not changed by writes or
+/// merge after that. Locations stay readable by `Bitcasky::read_at` until the location generation
+/// changes.
+pub struct KeyLocations {
+    entries: vec::IntoIter<(Vec<u8>, RowLocation)>,
+    location_generation: u64,
+}
+
+impl KeyLocations {
+    pub(crate) fn new(kd: &KeyDir) -> KeyLocations {
+        let entries = kd
+            .iter()
+            .map(|(k, location)| (k.clone(), *location))
+            .collect::<Vec<(Vec<u8>, RowLocation)>>();
+        KeyLocations {
+            entries: entries.into_iter(),
+            location_generation: kd.location_generation(),
+        }
+    }
+
+    /// Location generation observed when the locations were copied from keydir
+    pub fn location_generation(&self) -> u64 {
+        self.location_generation
+    }
+}
+
+impl Iterator for KeyLocations {
+    type Item = (Vec<u8>, RowLocation);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl ExactSizeIterator for KeyLocations {}
@@ -0,0 +1,75 @@
+//! Flushes databases on `SIGTERM` and `SIGINT` before the process exits, installed by
+//! `Bitcasky::install_signal_handlers`.
+//!
+//! Signals are delivered by `signal_hook::iterator::Signals` to a thread, which syncs and drains
+//! the hint writer of every registered database, then runs the default action of the signal by
+//! `emulate_default_handler`, so the process exits as if it had no handler. Handlers installed
+//! before ours are chained by signal-hook and still run when the signal arrives.
+
+use std::{
+    io,
+    sync::{Arc, OnceLock, Weak},
+    thread,
+};
+
+use parking_lot::Mutex;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+    low_level,
+};
+
+use crate::bitcasky::Bitcasky;
+use crate::logging::{info, warn};
+
+const HANDLED_SIGNALS: [libc::c_int; 2] = [SIGTERM, SIGINT];
+
+/// Databases to flush on signals. Weak, so a database closes as usual once the caller drops it
+type Registry = Arc<Mutex<Vec<Weak<Bitcasky>>>>;
+
+static INSTALLED: OnceLock<io::Result<Registry>> = OnceLock::new();
+
+/// Registers the database to flush on signals, installing the handlers on first call
+pub fn register(bitcasky: Arc<Bitcasky>) -> io::Result<()> {
+    let registry = INSTALLED
+        .get_or_init(install)
+        .as_ref()
+        .map_err(|e| io::Error::new(e.kind(), e.to_string()))?;
+    let mut databases = registry.lock();
+    databases.retain(|db| db.strong_count() > 0);
+    let db = Arc::downgrade(&bitcasky);
+    if !databases.iter().any(|d| d.ptr_eq(&db)) {
+        databases.push(db);
+    }
+    Ok(())
+}
+
+fn install() -> io::Result<Registry> {
+    let signals = Signals::new(HANDLED_SIGNALS)?;
+    let registry = Registry::default();
+    let databases = registry.clone();
+    thread::Builder::new()
+        .name("bitcasky-signal".into())
+        .spawn(move || flush_on_signals(signals, databases))?;
+    Ok(registry)
+}
+
+fn flush_on_signals(mut signals: Signals, registry: Registry) {
+    for signal in signals.forever() {
+        let databases = registry
+            .lock()
+            .iter()
+            .filter_map(|db| db.upgrade())
+            .collect::<Vec<Arc<Bitcasky>>>();
+        info!(target: "Bitcasky", "flush {} databases on signal: {}", databases.len(), signal);
+        for db in databases {
+            if let Err(e) = db.flush_on_signal() {
+                warn!(target: "Bitcasky", "flush database on signal: {} failed. {}", signal, e);
+            }
+        }
+
+        if let Err(e) = low_level::emulate_default_handler(signal) {
+            warn!(target: "Bitcasky", "run default action of signal: {} failed. {}", signal, e);
+        }
+    }
+}
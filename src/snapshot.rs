@@ -0,0 +1,80 @@
+use crate::{
+    database::{PinnedStorages, RowLocation},
+    error::BitcaskyResult,
+    keydir::{KeyDir, KeyDirIterator},
+};
+
+/// A consistent view of the database at the time it was taken.
+///
+/// Writes, deletes and merges after the snapshot was taken are not visible through it.
+/// All the data files referenced by the snapshot are kept open and pinned until it's dropped,
+/// so a concurrent merge defers deleting them until then.
+#[derive(Debug)]
+pub struct Snapshot {
+    keydir: KeyDir,
+    storages: PinnedStorages,
+}
+
+impl Snapshot {
+    pub(crate) fn new(keydir: KeyDir, storages: PinnedStorages) -> Snapshot {
+        Snapshot { keydir, storages }
+    }
+
+    /// Fetches value for a key at the time the snapshot was taken
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> BitcaskyResult<Option<Vec<u8>>> {
+        match self.keydir.get(key.as_ref()) {
+            Some(location) => self.read_value(&location),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns true if the key existed at the time the snapshot was taken
+    pub fn has<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.keydir.contains_key(key.as_ref())
+    }
+
+    /// Returns the number of keys in this snapshot
+    pub fn len(&self) -> usize {
+        self.keydir.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keydir.len() == 0
+    }
+
+    /// Iterates all the key value pairs in this snapshot. Values expired after the snapshot
+    /// was taken are skipped.
+    pub fn iter(&self) -> SnapshotIter<'_> {
+        SnapshotIter {
+            snapshot: self,
+            keydir_iter: self.keydir.iter(),
+        }
+    }
+
+    fn read_value(&self, location: &RowLocation) -> BitcaskyResult<Option<Vec<u8>>> {
+        Ok(self
+            .storages
+            .read_value(location)?
+            .map(|v| v.value.to_vec()))
+    }
+}
+
+pub struct SnapshotIter<'a> {
+    snapshot: &'a Snapshot,
+    keydir_iter: KeyDirIterator<'a>,
+}
+
+impl<'a> Iterator for SnapshotIter<'a> {
+    type Item = BitcaskyResult<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (key, location) in self.keydir_iter.by_ref() {
+            match self.snapshot.read_value(location) {
+                Ok(Some(value)) => return Some(Ok((key.clone(), value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
@@ -1,30 +1,51 @@
-use std::ops::Add;
-
-use log::info;
+use crate::logging::info;
 use parking_lot::Mutex;
+use thiserror::Error;
 
 pub type StorageId = u32;
 
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("No storage id left to allocate after storage id {0}")]
+pub struct StorageIdOverflowError(pub StorageId);
+
+/// Largest storage id generated. `StorageId::MAX` is never handed out, so the largest id found
+/// on open can always be passed to `update_id`
+const MAX_GENERATED_ID: StorageId = StorageId::MAX - 1;
+
 #[derive(Debug)]
 pub struct StorageIdGenerator {
     id: Mutex<StorageId>,
 }
 
 impl StorageIdGenerator {
-    pub fn generate_next_id(&self) -> StorageId {
+    pub fn generate_next_id(&self) -> Result<StorageId, StorageIdOverflowError> {
         let mut id = self.id.lock();
-        let next_id = id.add(1);
+        if *id >= MAX_GENERATED_ID {
+            return Err(StorageIdOverflowError(*id));
+        }
+        let next_id = *id + 1;
         *id = next_id;
-        next_id
+        Ok(next_id)
     }
 
-    pub fn update_id(&self, known_max_storage_id: StorageId) {
+    /// Make sure ids generated later are greater than `known_max_storage_id`.
+    /// Fails when `known_max_storage_id` leaves no id to generate
+    pub fn update_id(&self, known_max_storage_id: StorageId) -> Result<(), StorageIdOverflowError> {
+        if known_max_storage_id == StorageId::MAX {
+            return Err(StorageIdOverflowError(known_max_storage_id));
+        }
         let mut id = self.id.lock();
         if known_max_storage_id < *id {
-            return;
+            return Ok(());
         }
         *id = known_max_storage_id;
         info!(target: "StorageIdGenerator", "update storage id to {}", *id);
+        Ok(())
+    }
+
+    /// How many storage ids can still be generated
+    pub fn remaining_ids(&self) -> u64 {
+        MAX_GENERATED_ID.saturating_sub(*self.id.lock()) as u64
     }
 
     #[allow(dead_code)]
@@ -47,19 +68,48 @@ mod tests {
     #[test]
     fn test_generate_id() {
         let id_gen = StorageIdGenerator::default();
-        assert_eq!(1, id_gen.generate_next_id());
-        assert_eq!(2, id_gen.generate_next_id());
-        assert_eq!(3, id_gen.generate_next_id());
+        assert_eq!(1, id_gen.generate_next_id().unwrap());
+        assert_eq!(2, id_gen.generate_next_id().unwrap());
+        assert_eq!(3, id_gen.generate_next_id().unwrap());
         assert_eq!(3, id_gen.get_id());
+        assert_eq!(MAX_GENERATED_ID as u64 - 3, id_gen.remaining_ids());
     }
 
     #[test]
     fn test_update_storage_id() {
         let id_gen = StorageIdGenerator::default();
-        assert_eq!(1, id_gen.generate_next_id());
-        id_gen.update_id(10);
-        assert_eq!(11, id_gen.generate_next_id());
-        assert_eq!(12, id_gen.generate_next_id());
+        assert_eq!(1, id_gen.generate_next_id().unwrap());
+        id_gen.update_id(10).unwrap();
+        assert_eq!(11, id_gen.generate_next_id().unwrap());
+        assert_eq!(12, id_gen.generate_next_id().unwrap());
         assert_eq!(12, id_gen.get_id());
     }
+
+    #[test]
+    fn test_generate_id_near_overflow() {
+        let id_gen = StorageIdGenerator::default();
+        id_gen.update_id(StorageId::MAX - 3).unwrap();
+        assert_eq!(2, id_gen.remaining_ids());
+        assert_eq!(StorageId::MAX - 2, id_gen.generate_next_id().unwrap());
+        assert_eq!(StorageId::MAX - 1, id_gen.generate_next_id().unwrap());
+        assert_eq!(0, id_gen.remaining_ids());
+        assert_eq!(
+            Err(StorageIdOverflowError(StorageId::MAX - 1)),
+            id_gen.generate_next_id()
+        );
+        assert_eq!(StorageId::MAX - 1, id_gen.get_id());
+        // the largest generated id can always be updated to on open
+        id_gen.update_id(StorageId::MAX - 1).unwrap();
+    }
+
+    #[test]
+    fn test_update_storage_id_to_max() {
+        let id_gen = StorageIdGenerator::default();
+        assert_eq!(
+            Err(StorageIdOverflowError(StorageId::MAX)),
+            id_gen.update_id(StorageId::MAX)
+        );
+        assert_eq!(0, id_gen.get_id());
+        assert_eq!(1, id_gen.generate_next_id().unwrap());
+    }
 }
@@ -0,0 +1,395 @@
+//! Derives options from the expected workload, so a database does not start with defaults
+//! which fit none of caches, archives of large values or ledgers syncing every write.
+
+#[cfg(feature = "sync-worker")]
+use std::time::Duration;
+
+use crate::options::{BitcaskyOptions, SyncStrategy};
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * KIB;
+const GIB: usize = 1024 * MIB;
+
+/// Bytes taken by a key and the row header along with each value, assumed when no key size is known
+const ROW_OVERHEAD: usize = 64;
+/// Approximate bytes of an entry in hint file
+const HINT_ENTRY_SIZE: usize = 48;
+/// Values this large are expensive to rewrite by merge
+const LARGE_VALUE_SIZE: usize = 64 * KIB;
+
+/// How much of the latest writes can be lost on crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes not synced yet are lost, e.g. a cache which can be refilled
+    Relaxed,
+    /// Writes in the last second may be lost
+    Balanced,
+    /// Every write is synced before return
+    Strict,
+}
+
+/// Expected workload of a database used by `BitcaskyOptions::tuned_for`
+#[derive(Debug, Clone, Copy)]
+pub struct WorkloadProfile {
+    pub expected_keys: usize,
+    /// Average bytes of values
+    pub avg_value_size: usize,
+    pub writes_per_sec: usize,
+    pub reads_per_sec: usize,
+    pub durability: Durability,
+}
+
+/// Why an option is set to its value by `BitcaskyOptions::tuned_for`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TuningChoice {
+    pub option: &'static str,
+    pub reason: String,
+}
+
+/// Options derived from a `WorkloadProfile` and the reasons of each choice
+#[derive(Debug)]
+pub struct TunedOptions {
+    pub options: BitcaskyOptions,
+    pub choices: Vec<TuningChoice>,
+}
+
+impl TunedOptions {
+    /// Reasons of all the choices, one option per line
+    pub fn explanation(&self) -> String {
+        self.choices
+            .iter()
+            .map(|c| format!("{}: {}", c.option, c.reason))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+impl BitcaskyOptions {
+    /// Derives options for the workload from the default options. The result only depends on
+    /// the profile and the enabled features. Options not related to the workload keep their
+    /// default values.
+    pub fn tuned_for(profile: WorkloadProfile) -> TunedOptions {
+        let mut choices = vec![];
+        let mut choose =
+            |option: &'static str, reason: String| choices.push(TuningChoice { option, reason });
+        let mut options = BitcaskyOptions::default();
+
+        let row_size = profile.avg_value_size.saturating_add(ROW_OVERHEAD);
+        let live_bytes = profile.expected_keys.saturating_mul(row_size);
+        let read_heavy = profile.reads_per_sec > profile.writes_per_sec;
+
+        let max_value_size = options
+            .max_value_size
+            .max(profile.avg_value_size.saturating_mul(4));
+        if max_value_size != options.max_value_size {
+            options = options.max_value_size(max_value_size);
+            choose(
+                "max_value_size",
+                format!(
+                    "{}, 4 times of the average value size",
+                    format_size(max_value_size)
+                ),
+            );
+        }
+
+        // a data file holds at least 4 values of the max size
+        let min_data_file_size = (8 * MIB).max(max_value_size.saturating_mul(4));
+        let max_data_file_size = pow2_clamp(
+            live_bytes / 8,
+            min_data_file_size,
+            GIB.max(min_data_file_size),
+        );
+        options = options.max_data_file_size(max_data_file_size);
+        choose(
+            "max_data_file_size",
+            format!(
+                "{}, about 1/8 of the {} of live data between 8 MiB and 1 GiB, so a partial merge rewrites a small part of data",
+                format_size(max_data_file_size),
+                format_size(live_bytes)
+            ),
+        );
+
+        let init_data_file_capacity = pow2_clamp(
+            profile
+                .writes_per_sec
+                .saturating_mul(row_size)
+                .saturating_mul(10),
+            64 * KIB,
+            max_data_file_size.min(64 * MIB),
+        );
+        options = options.init_data_file_capacity(init_data_file_capacity);
+        choose(
+            "init_data_file_capacity",
+            format!(
+                "{}, about 10 seconds of writes, so a new data file does not grow right after created",
+                format_size(init_data_file_capacity)
+            ),
+        );
+
+        let init_hint_file_capacity = pow2_clamp(
+            max_data_file_size / row_size * HINT_ENTRY_SIZE,
+            4 * KIB,
+            64 * MIB,
+        );
+        options = options.init_hint_file_capacity(init_hint_file_capacity);
+        choose(
+            "init_hint_file_capacity",
+            format!(
+                "{}, enough for the hint entries of a full data file",
+                format_size(init_hint_file_capacity)
+            ),
+        );
+
+        let (sync_strategy, reason) = sync_strategy_for(profile.durability);
+        options = options.sync_strategy(sync_strategy);
+        choose("sync_strategy", reason.into());
+
+        let (threshold, reason) = if profile.avg_value_size >= LARGE_VALUE_SIZE {
+            (0.6, "values are large and expensive to rewrite by merge")
+        } else if read_heavy {
+            (0.3, "reads dominate, keep data files compact")
+        } else {
+            (
+                0.5,
+                "writes dominate, merge less often to save disk bandwidth",
+            )
+        };
+        options = options.auto_merge(threshold);
+        choose(
+            "auto_merge_threshold",
+            format!("{} of dead bytes, {}", threshold, reason),
+        );
+
+        if read_heavy {
+            let expected_items = profile.expected_keys.max(1).saturating_mul(2);
+            options = options.bloom_filter(expected_items, 0.01);
+            choose(
+                "bloom_filter",
+                format!(
+                    "sized for {} keys with 1% false positive rate, reads dominate so absent keys are checked without keydir lookup",
+                    expected_items
+                ),
+            );
+        } else {
+            choose(
+                "bloom_filter",
+                "disabled, writes dominate and keeping the filter costs memory".into(),
+            );
+        }
+
+        TunedOptions { options, choices }
+    }
+}
+
+fn sync_strategy_for(durability: Durability) -> (SyncStrategy, &'static str) {
+    match durability {
+        Durability::Relaxed => (
+            SyncStrategy::None,
+            "never synced by bitcasky, writes are lost on crash until the OS flushes them",
+        ),
+        #[cfg(feature = "sync-worker")]
+        Durability::Balanced => (
+            SyncStrategy::Interval(Duration::from_secs(1)),
+            "synced every second, writes in the last second may be lost on crash",
+        ),
+        #[cfg(not(feature = "sync-worker"))]
+        Durability::Balanced => (
+            SyncStrategy::None,
+            "interval sync needs the sync-worker feature, call Bitcasky::sync every second instead",
+        ),
+        Durability::Strict => (SyncStrategy::OSync, "every write is synced before return"),
+    }
+}
+
+/// Rounds up to power of two and then clamps
+fn pow2_clamp(size: usize, min: usize, max: usize) -> usize {
+    size.checked_next_power_of_two()
+        .unwrap_or(max)
+        .clamp(min, max)
+}
+
+fn format_size(size: usize) -> String {
+    if size >= GIB && size.is_multiple_of(GIB) {
+        format!("{} GiB", size / GIB)
+    } else if size >= MIB {
+        format!("{} MiB", size / MIB)
+    } else if size >= KIB {
+        format!("{} KiB", size / KIB)
+    } else {
+        format!("{} B", size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    struct Expected {
+        name: &'static str,
+        profile: WorkloadProfile,
+        max_value_size: usize,
+        max_data_file_size: usize,
+        init_data_file_capacity: usize,
+        init_hint_file_capacity: usize,
+        sync_strategy: SyncStrategy,
+        auto_merge_threshold: f64,
+        bloom_filter_items: Option<usize>,
+    }
+
+    fn balanced_sync() -> SyncStrategy {
+        #[cfg(feature = "sync-worker")]
+        return SyncStrategy::Interval(Duration::from_secs(1));
+        #[cfg(not(feature = "sync-worker"))]
+        return SyncStrategy::None;
+    }
+
+    #[test]
+    fn test_tuned_for_profiles() {
+        let cases = [
+            Expected {
+                name: "tiny-values cache",
+                profile: WorkloadProfile {
+                    expected_keys: 1_000_000,
+                    avg_value_size: 64,
+                    writes_per_sec: 50_000,
+                    reads_per_sec: 200_000,
+                    durability: Durability::Relaxed,
+                },
+                max_value_size: 100 * KIB,
+                max_data_file_size: 16 * MIB,
+                init_data_file_capacity: 16 * MIB,
+                init_hint_file_capacity: 8 * MIB,
+                sync_strategy: SyncStrategy::None,
+                auto_merge_threshold: 0.3,
+                bloom_filter_items: Some(2_000_000),
+            },
+            Expected {
+                name: "large-blob archive",
+                profile: WorkloadProfile {
+                    expected_keys: 100_000,
+                    avg_value_size: 4 * MIB,
+                    writes_per_sec: 10,
+                    reads_per_sec: 1,
+                    durability: Durability::Balanced,
+                },
+                max_value_size: 16 * MIB,
+                max_data_file_size: GIB,
+                init_data_file_capacity: 64 * MIB,
+                init_hint_file_capacity: 16 * KIB,
+                sync_strategy: balanced_sync(),
+                auto_merge_threshold: 0.6,
+                bloom_filter_items: None,
+            },
+            Expected {
+                name: "durable ledger",
+                profile: WorkloadProfile {
+                    expected_keys: 10_000_000,
+                    avg_value_size: 256,
+                    writes_per_sec: 2_000,
+                    reads_per_sec: 500,
+                    durability: Durability::Strict,
+                },
+                max_value_size: 100 * KIB,
+                max_data_file_size: 512 * MIB,
+                init_data_file_capacity: 8 * MIB,
+                init_hint_file_capacity: 64 * MIB,
+                sync_strategy: SyncStrategy::OSync,
+                auto_merge_threshold: 0.5,
+                bloom_filter_items: None,
+            },
+        ];
+
+        for case in cases {
+            let tuned = BitcaskyOptions::tuned_for(case.profile);
+            let options = &tuned.options;
+            options.validate().unwrap();
+            assert_eq!(case.max_value_size, options.max_value_size, "{}", case.name);
+            assert_eq!(
+                case.max_data_file_size, options.database.storage.max_data_file_size,
+                "{}",
+                case.name
+            );
+            assert_eq!(
+                case.init_data_file_capacity, options.database.storage.init_data_file_capacity,
+                "{}",
+                case.name
+            );
+            assert_eq!(
+                case.init_hint_file_capacity, options.database.init_hint_file_capacity,
+                "{}",
+                case.name
+            );
+            assert_eq!(
+                format!("{:?}", case.sync_strategy),
+                format!("{:?}", options.database.sync_strategy),
+                "{}",
+                case.name
+            );
+            assert_eq!(
+                Some(case.auto_merge_threshold),
+                options.auto_merge_threshold,
+                "{}",
+                case.name
+            );
+            assert_eq!(
+                case.bloom_filter_items,
+                options.bloom_filter.map(|b| b.expected_items),
+                "{}",
+                case.name
+            );
+
+            for option in [
+                "max_data_file_size",
+                "init_data_file_capacity",
+                "init_hint_file_capacity",
+                "sync_strategy",
+                "auto_merge_threshold",
+                "bloom_filter",
+            ] {
+                assert!(
+                    tuned.choices.iter().any(|c| c.option == option),
+                    "{} has no reason for {}",
+                    case.name,
+                    option
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_tuned_for_huge_values() {
+        let tuned = BitcaskyOptions::tuned_for(WorkloadProfile {
+            expected_keys: 10,
+            avg_value_size: 512 * MIB,
+            writes_per_sec: 1,
+            reads_per_sec: 1,
+            durability: Durability::Strict,
+        });
+        tuned.options.validate().unwrap();
+        assert_eq!(2 * GIB, tuned.options.max_value_size);
+        assert_eq!(8 * GIB, tuned.options.database.storage.max_data_file_size);
+    }
+
+    #[test]
+    fn test_tuned_for_is_deterministic() {
+        let profile = WorkloadProfile {
+            expected_keys: 0,
+            avg_value_size: 0,
+            writes_per_sec: 0,
+            reads_per_sec: 0,
+            durability: Durability::Balanced,
+        };
+        let tuned = BitcaskyOptions::tuned_for(profile);
+        tuned.options.validate().unwrap();
+        assert_eq!(8 * MIB, tuned.options.database.storage.max_data_file_size);
+        assert_eq!(
+            tuned.explanation(),
+            BitcaskyOptions::tuned_for(profile).explanation()
+        );
+        assert!(tuned
+            .explanation()
+            .starts_with("max_data_file_size: 8 MiB, about 1/8 of the 0 B of live data"));
+    }
+}
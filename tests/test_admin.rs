@@ -0,0 +1,399 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use bitcasky::admin::{DoctorDepth, KeyLengthStats, Maintenance, MaintenanceOutcome};
+use bitcasky::bitcasky::{key_hash, Bitcasky, MutationCause};
+use bitcasky::error::BitcaskyResult;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use test_log::test;
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(120)
+        .init_data_file_capacity(100)
+}
+
+fn put_values(bc: &Bitcasky) {
+    for i in 0..10 {
+        bc.put(format!("k{}", i), "value").unwrap();
+    }
+    // leave some data files without live values
+    for i in 0..5 {
+        bc.put(format!("k{}", i), "new-value").unwrap();
+    }
+}
+
+fn assert_values(bc: &Bitcasky) {
+    for i in 0..10 {
+        let expected = if i < 5 { "new-value" } else { "value" };
+        assert_eq!(
+            expected.as_bytes(),
+            bc.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_maintenance_reports() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    put_values(&bc);
+
+    let writing_storage_id = bc.durability_state().writing_file;
+    let report = bc.rotate().unwrap().report().unwrap();
+    assert_eq!(Some(writing_storage_id), report.rotated_storage_id);
+    let report = bc.rotate().unwrap().report().unwrap();
+    assert_eq!(None, report.rotated_storage_id);
+
+    let dead_files = bc.fragmented_files(0.99);
+    assert!(!dead_files.is_empty());
+    let report = bc.drop_dead_files().unwrap().report().unwrap();
+    assert_eq!(dead_files, report.dropped_storage_ids);
+    let report = bc.drop_dead_files().unwrap().report().unwrap();
+    assert!(report.dropped_storage_ids.is_empty());
+    assert_values(&bc);
+
+    let report = bc.merge_if_needed().unwrap().report().unwrap();
+    assert!(!report.merged);
+    assert_eq!(0.5, report.threshold);
+
+    let mut small_files = vec![];
+    for k in ["k10", "k11"] {
+        bc.put(k, "value").unwrap();
+        small_files.push(
+            bc.rotate()
+                .unwrap()
+                .report()
+                .unwrap()
+                .rotated_storage_id
+                .unwrap(),
+        );
+    }
+    let report = bc.compact_small_files().unwrap().report().unwrap();
+    assert!(small_files
+        .iter()
+        .all(|id| report.compacted_storage_ids.contains(id)));
+    assert_values(&bc);
+    assert_eq!(b"value".to_vec(), bc.get("k11").unwrap().unwrap());
+
+    bc.put("k12", "value").unwrap();
+    let report = bc.quiesce().unwrap().report().unwrap();
+    assert_eq!(
+        bc.durability_state().writing_file,
+        report.writing_storage_id
+    );
+    assert!(report.synced_offset > 0);
+    let report = Maintenance::rebuild_hint_files(&bc)
+        .unwrap()
+        .report()
+        .unwrap();
+    assert_eq!(0, report.rebuilt_hint_files);
+    assert!(Maintenance::verify(&bc)
+        .unwrap()
+        .report()
+        .unwrap()
+        .is_healthy());
+}
+
+/// Returns true if the operation completed
+type Operation = fn(&Bitcasky) -> BitcaskyResult<bool>;
+
+fn completed<R>(outcome: MaintenanceOutcome<R>) -> bool {
+    outcome.is_completed()
+}
+
+#[test]
+fn test_run_maintenance_concurrently() {
+    // name, operation, whether it's only excluded by itself so one of two concurrent runs must complete
+    let operations: Vec<(&str, Operation, bool)> = vec![
+        ("rotate", |bc| bc.rotate().map(completed), true),
+        (
+            "merge_if_needed",
+            |bc| bc.merge_if_needed().map(completed),
+            false,
+        ),
+        (
+            "rebuild_hint_files",
+            |bc| Maintenance::rebuild_hint_files(bc).map(completed),
+            false,
+        ),
+        (
+            "verify",
+            |bc| {
+                Maintenance::verify(bc).map(|o| match o {
+                    MaintenanceOutcome::Completed(r) => {
+                        assert!(r.is_healthy());
+                        true
+                    }
+                    MaintenanceOutcome::AlreadyRunning => false,
+                })
+            },
+            true,
+        ),
+        (
+            "drop_dead_files",
+            |bc| bc.drop_dead_files().map(completed),
+            false,
+        ),
+        (
+            "compact_small_files",
+            |bc| bc.compact_small_files().map(completed),
+            false,
+        ),
+        ("quiesce", |bc| bc.quiesce().map(completed), false),
+    ];
+
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    put_values(&bc);
+
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|s| {
+        s.spawn(|| {
+            // run every operation twice at the same time, and all the operations at the same time
+            let results = thread::scope(|s| {
+                let handles = operations
+                    .iter()
+                    .flat_map(|(name, op, _)| {
+                        let bc = &bc;
+                        [
+                            s.spawn(move || (*name, op(bc))),
+                            s.spawn(move || (*name, op(bc))),
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            sender.send(results).unwrap();
+        });
+        let results = receiver
+            .recv_timeout(Duration::from_secs(30))
+            .expect("maintenance operations deadlocked");
+        for (name, _, must_complete) in operations.iter() {
+            let outcomes = results
+                .iter()
+                .filter(|(n, _)| n == name)
+                .map(|(_, r)| *r.as_ref().unwrap())
+                .collect::<Vec<bool>>();
+            assert_eq!(2, outcomes.len());
+            if *must_complete {
+                assert!(outcomes.iter().any(|c| *c), "{} never completed", name);
+            }
+        }
+    });
+
+    assert_values(&bc);
+    // nothing is running now, so every operation completes
+    for (name, op, _) in operations.iter() {
+        assert!(op(&bc).unwrap(), "{} did not complete", name);
+    }
+    assert_values(&bc);
+    drop(bc);
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_values(&bc);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialize_maintenance_report() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    put_values(&bc);
+    let outcome = bc.rotate().unwrap();
+    let json = serde_json::to_string(&outcome).unwrap();
+    assert_eq!(
+        outcome,
+        serde_json::from_str::<MaintenanceOutcome<_>>(&json).unwrap()
+    );
+}
+
+#[test]
+fn test_doctor_report() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    put_values(&bc);
+
+    let report = bc.doctor_report(DoctorDepth::Quick).unwrap();
+    assert_eq!(DoctorDepth::Quick, report.depth);
+    assert!(report
+        .lock_holder
+        .unwrap()
+        .starts_with(&std::process::id().to_string()));
+    assert!(report.database_error.is_none());
+    assert!(report.files.iter().any(|f| f.name == "bitcask.lock"));
+    let data_files = report
+        .files
+        .iter()
+        .filter(|f| f.name.ends_with(".data"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        report.telemetry.database.stable_storages.len() + 1,
+        data_files.len()
+    );
+    assert!(data_files.iter().all(|f| f.formatter_version.is_some()));
+    assert_eq!(10, report.telemetry.keydir.number_of_keys);
+    assert!(report.key_lengths.is_none());
+    assert!(report.verify.is_none());
+
+    let report = bc.doctor_report(DoctorDepth::Deep).unwrap();
+    assert_eq!(
+        Some(KeyLengthStats {
+            keys: 10,
+            total_bytes: 20,
+            max_length: 2,
+        }),
+        report.key_lengths
+    );
+    let verify = report.verify.unwrap();
+    assert!(verify.is_healthy());
+    assert_eq!(15, verify.good_rows);
+}
+
+#[test]
+fn test_trace_for_key() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options().mutation_trace(100)).unwrap();
+    bc.put("k1", "v1").unwrap();
+    bc.put("k1", "v2").unwrap();
+    bc.put_with_ttl("k2", "v1", Duration::from_millis(1))
+        .unwrap();
+    bc.put("k3", "v1").unwrap();
+    bc.delete("k1").unwrap();
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(1, bc.sweep_expired().unwrap());
+    bc.merge().unwrap();
+
+    let causes = |key: &str| {
+        bc.trace_for_key(key)
+            .iter()
+            .map(|m| m.cause)
+            .collect::<Vec<MutationCause>>()
+    };
+    assert_eq!(
+        vec![
+            MutationCause::Put,
+            MutationCause::Put,
+            MutationCause::Delete
+        ],
+        causes("k1")
+    );
+    assert_eq!(
+        vec![MutationCause::Put, MutationCause::TtlSweep],
+        causes("k2")
+    );
+    assert_eq!(
+        vec![MutationCause::Put, MutationCause::MergeApply],
+        causes("k3")
+    );
+    assert!(bc.trace_for_key("k4").is_empty());
+
+    // every mutation starts where the previous one of the key ended
+    for key in ["k1", "k2", "k3"] {
+        let trace = bc.trace_for_key(key);
+        assert!(trace[0].old_location.is_none());
+        for pair in trace.windows(2) {
+            assert_eq!(pair[0].new_location, pair[1].old_location);
+            assert!(pair[0].sequence < pair[1].sequence);
+            assert!(pair[0].timestamp <= pair[1].timestamp);
+        }
+        assert!(trace.iter().all(|m| m.key_hash == key_hash(key.as_bytes())));
+    }
+    assert_eq!(
+        bc.get_location("k3").unwrap().map(|(l, _)| l),
+        bc.trace_for_key("k3")[1].new_location
+    );
+
+    let trace = bc
+        .doctor_report(DoctorDepth::Quick)
+        .unwrap()
+        .mutation_trace
+        .unwrap();
+    assert_eq!(7, trace.len());
+    assert_eq!(
+        (0..7).collect::<Vec<u64>>(),
+        trace.iter().map(|m| m.sequence).collect::<Vec<u64>>()
+    );
+}
+
+#[test]
+fn test_mutation_trace_disabled() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    bc.put("k1", "v1").unwrap();
+    assert!(bc.trace_for_key("k1").is_empty());
+    assert!(bc
+        .doctor_report(DoctorDepth::Quick)
+        .unwrap()
+        .mutation_trace
+        .is_none());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_doctor_report_json_schema() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    put_values(&bc);
+
+    for depth in [DoctorDepth::Quick, DoctorDepth::Deep] {
+        let json = serde_json::to_string(&bc.doctor_report(depth).unwrap()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let mut fields = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        fields.sort();
+        assert_eq!(
+            vec![
+                "database_error",
+                "depth",
+                "directory",
+                "files",
+                "key_lengths",
+                "lock_holder",
+                "mutation_trace",
+                "options",
+                "read_only",
+                "telemetry",
+                "verify",
+            ],
+            fields
+        );
+        for field in ["name", "size", "formatter_version"] {
+            assert!(value["files"][0].get(field).is_some());
+        }
+        // keys and values are never reported
+        assert!(!json.contains("\"k1\""));
+        assert!(!json.contains("new-value"));
+    }
+}
+
+#[cfg(feature = "instrument-locks")]
+#[test]
+fn test_quick_doctor_report_holds_locks_briefly() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, BitcaskyOptions::default()).unwrap();
+    for i in 0..50000 {
+        bc.put(format!("k{}", i), "value").unwrap();
+    }
+
+    let long_hold = Duration::from_millis(10);
+    let count_long_holds = |bc: &Bitcasky| {
+        let stats = bc.lock_stats();
+        [stats.keydir, stats.writing_storage, stats.stable_storages]
+            .iter()
+            .map(|s| s.hold.count_at_least(long_hold))
+            .sum::<u64>()
+    };
+    let before = count_long_holds(&bc);
+    bc.doctor_report(DoctorDepth::Quick).unwrap();
+    assert_eq!(before, count_long_holds(&bc));
+}
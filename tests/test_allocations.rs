@@ -0,0 +1,89 @@
+//! Counts bytes allocated on the current thread by a global allocator, which is installed for
+//! this test binary only
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::{BitcaskyOptions, DataSotrageType};
+use test_log::test;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED_BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count(bytes: usize) {
+    let _ = ALLOCATED_BYTES.try_with(|b| b.set(b.get() + bytes));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size.saturating_sub(layout.size()));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocated_bytes_by<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATED_BYTES.with(|b| b.get());
+    f();
+    ALLOCATED_BYTES.with(|b| b.get()) - before
+}
+
+#[test]
+fn test_merge_does_not_allocate_values() {
+    const VALUES: usize = 32;
+    const VALUE_SIZE: usize = 64 * 1024;
+    for storage_type in [
+        DataSotrageType::File,
+        #[cfg(feature = "mmap")]
+        DataSotrageType::Mmap,
+    ] {
+        for verify_crc in [true, false] {
+            let db_path = get_temporary_directory_path();
+            let bc = Bitcasky::open(
+                &db_path,
+                BitcaskyOptions::default()
+                    .max_value_size(VALUE_SIZE)
+                    .storage_type(storage_type)
+                    .verify_crc_on_read(verify_crc),
+            )
+            .unwrap();
+            for i in 0..VALUES {
+                bc.put(format!("k{}", i), vec![i as u8; VALUE_SIZE])
+                    .unwrap();
+            }
+
+            let allocated = allocated_bytes_by(|| {
+                bc.merge().unwrap();
+            });
+            assert!(
+                allocated < VALUES * VALUE_SIZE / 4,
+                "merge allocated {} bytes with {:?} storage, verify crc: {}",
+                allocated,
+                storage_type,
+                verify_crc
+            );
+            assert_eq!(vec![1; VALUE_SIZE], bc.get("k1").unwrap().unwrap());
+        }
+    }
+}
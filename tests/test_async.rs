@@ -0,0 +1,118 @@
+use std::future::poll_fn;
+use std::pin::Pin;
+
+use bitcasky::async_bitcasky::{AsyncBitcasky, ScanStream};
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use futures_core::Stream;
+
+async fn next(stream: &mut ScanStream) -> Option<(Vec<u8>, Vec<u8>)> {
+    poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx))
+        .await
+        .map(|r| r.unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_async_read_write() {
+    let db_path = get_temporary_directory_path();
+    let bc = AsyncBitcasky::open(&db_path, BitcaskyOptions::default())
+        .await
+        .unwrap();
+    bc.put("k1", "value1").await.unwrap();
+    bc.put_multi(vec![
+        (b"k2".to_vec(), b"value2".to_vec()),
+        (b"k3".to_vec(), b"value3".to_vec()),
+    ])
+    .await
+    .unwrap();
+    assert_eq!(Some(b"value1".to_vec()), bc.get("k1").await.unwrap());
+    assert_eq!(Some(b"value3".to_vec()), bc.get("k3").await.unwrap());
+
+    assert!(bc.delete("k1").await.unwrap());
+    assert!(!bc.delete("k1").await.unwrap());
+    assert_eq!(None, bc.get("k1").await.unwrap());
+    bc.sync().await.unwrap();
+
+    let report = bc.merge().await.unwrap();
+    assert!(report.files_processed > 0);
+    assert_eq!(Some(b"value2".to_vec()), bc.get("k2").await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_async_scan() {
+    let db_path = get_temporary_directory_path();
+    let bc = AsyncBitcasky::open(&db_path, BitcaskyOptions::default())
+        .await
+        .unwrap();
+    for i in 0..200 {
+        bc.put(format!("k{}", i), format!("value{}", i))
+            .await
+            .unwrap();
+    }
+
+    let mut stream = bc.scan().await.unwrap();
+    // not seen by the snapshot taken before
+    bc.put("new-key", "value").await.unwrap();
+    let mut pairs = vec![];
+    while let Some(pair) = next(&mut stream).await {
+        pairs.push(pair);
+    }
+    pairs.sort();
+    let mut expect = (0..200)
+        .map(|i| {
+            (
+                format!("k{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        })
+        .collect::<Vec<_>>();
+    expect.sort();
+    assert_eq!(expect, pairs);
+
+    // dropping a stream half consumed stops the scan
+    let mut stream = bc.scan().await.unwrap();
+    assert!(next(&mut stream).await.is_some());
+    drop(stream);
+    bc.put("k0", "new-value").await.unwrap();
+    assert_eq!(Some(b"new-value".to_vec()), bc.get("k0").await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_concurrent_writes_bounded_by_pending_writes() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    let bc = AsyncBitcasky::with_max_pending_writes(bc, 2);
+
+    let tasks = (0..50)
+        .map(|i| {
+            let bc = bc.clone();
+            tokio::spawn(async move { bc.put(format!("k{}", i), format!("value{}", i)).await })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+    for i in 0..50 {
+        assert_eq!(
+            Some(format!("value{}", i).into_bytes()),
+            bc.get(format!("k{}", i)).await.unwrap()
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_dropped_write_leaves_database_usable() {
+    let db_path = get_temporary_directory_path();
+    let bc = AsyncBitcasky::open(&db_path, BitcaskyOptions::default())
+        .await
+        .unwrap();
+    // polled once then dropped, the put either completes or never starts
+    let put = bc.put("k1", "value1");
+    let _ = tokio::time::timeout(std::time::Duration::ZERO, put).await;
+
+    bc.put("k2", "value2").await.unwrap();
+    let v = bc.get("k1").await.unwrap();
+    assert!(v.is_none() || v == Some(b"value1".to_vec()));
+    assert_eq!(Some(b"value2".to_vec()), bc.get("k2").await.unwrap());
+}
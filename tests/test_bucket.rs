@@ -0,0 +1,205 @@
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::error::BitcaskyError;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use test_log::test;
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(1024)
+        .init_data_file_capacity(100)
+}
+
+fn bucket_keys(bc: &Bitcasky, name: &str) -> Vec<Vec<u8>> {
+    let mut keys = vec![];
+    bc.bucket(name)
+        .unwrap()
+        .foreach_key(|k| keys.push(k.to_vec()))
+        .unwrap();
+    keys.sort();
+    keys
+}
+
+#[test]
+fn test_buckets_are_isolated() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    let b1 = bc.bucket("b1").unwrap();
+    let b2 = bc.bucket("b2").unwrap();
+    b1.put("k1", "value1").unwrap();
+    b2.put("k1", "value2").unwrap();
+    bc.put("k1", "value3").unwrap();
+
+    assert_eq!(b"b1", b1.name());
+    assert_eq!("value1".as_bytes(), b1.get("k1").unwrap().unwrap());
+    assert_eq!("value2".as_bytes(), b2.get("k1").unwrap().unwrap());
+    assert_eq!("value3".as_bytes(), bc.get("k1").unwrap().unwrap());
+    assert!(!b1.has("k2").unwrap());
+    assert_eq!(3, bc.count_keys().unwrap());
+
+    b1.delete("k1").unwrap();
+    assert!(b1.get("k1").unwrap().is_none());
+    assert_eq!("value2".as_bytes(), b2.get("k1").unwrap().unwrap());
+    assert_eq!("value3".as_bytes(), bc.get("k1").unwrap().unwrap());
+}
+
+#[test]
+fn test_bucket_name_is_length_framed() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    bc.bucket("a").unwrap().put("bk", "value1").unwrap();
+    bc.bucket("ab").unwrap().put("k", "value2").unwrap();
+
+    assert_eq!(vec![b"bk".to_vec()], bucket_keys(&bc, "a"));
+    assert_eq!(vec![b"k".to_vec()], bucket_keys(&bc, "ab"));
+    assert!(matches!(
+        bc.bucket(vec![b'a'; u16::MAX as usize + 1]),
+        Err(BitcaskyError::InvalidParameter(_, _))
+    ));
+}
+
+#[test]
+fn test_scan_bucket() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    let bucket = bc.bucket("b1").unwrap();
+    for i in 0..5 {
+        bucket
+            .put(format!("k{}", i), format!("value{}", i))
+            .unwrap();
+        bc.put(format!("k{}", i), "plain").unwrap();
+    }
+    bc.bucket("b2").unwrap().put("k2", "other").unwrap();
+
+    let rows = bucket
+        .scan("k1", "k4")
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        vec![
+            (b"k1".to_vec(), b"value1".to_vec()),
+            (b"k2".to_vec(), b"value2".to_vec()),
+            (b"k3".to_vec(), b"value3".to_vec()),
+        ],
+        rows
+    );
+}
+
+#[test]
+fn test_namespace() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    let users = bc.namespace(b"users").unwrap();
+    let orders = bc.namespace(b"orders").unwrap();
+    users.put("alice", "value1").unwrap();
+    users.put("bob", "value2").unwrap();
+    users.put("carol", "value3").unwrap();
+    orders.put("alice", "value4").unwrap();
+    // crafted key in a shorter namespace can not reach keys in another one
+    bc.namespace(b"u")
+        .unwrap()
+        .put("sersalice", "value5")
+        .unwrap();
+
+    assert_eq!("value1".as_bytes(), users.get("alice").unwrap().unwrap());
+    assert_eq!("value4".as_bytes(), orders.get("alice").unwrap().unwrap());
+    let rows = users
+        .scan_prefix("a")
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(vec![(b"alice".to_vec(), b"value1".to_vec())], rows);
+
+    users.delete("bob").unwrap();
+    let mut rows = vec![];
+    users
+        .foreach(|k, v| rows.push((k.to_vec(), v.to_vec())))
+        .unwrap();
+    rows.sort();
+    assert_eq!(
+        vec![
+            (b"alice".to_vec(), b"value1".to_vec()),
+            (b"carol".to_vec(), b"value3".to_vec()),
+        ],
+        rows
+    );
+}
+
+#[test]
+fn test_drop_bucket() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        let b1 = bc.bucket("b1").unwrap();
+        let b2 = bc.bucket("b2").unwrap();
+        for i in 0..10 {
+            b1.put(format!("k{}", i), "value").unwrap();
+        }
+        b2.put("k1", "value").unwrap();
+
+        assert_eq!(10, bc.drop_bucket("b1").unwrap());
+        assert_eq!(0, b1.stats().unwrap().key_count);
+        assert!(b1.get("k1").unwrap().is_none());
+        assert_eq!(1, b2.stats().unwrap().key_count);
+        assert_eq!(0, bc.drop_bucket("b3").unwrap());
+    }
+
+    // tombstones are recovered
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert!(bucket_keys(&bc, "b1").is_empty());
+    assert_eq!(vec![b"k1".to_vec()], bucket_keys(&bc, "b2"));
+}
+
+#[test]
+fn test_bucket_stats() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    let bucket = bc.bucket("b1").unwrap();
+    assert_eq!(0, bucket.stats().unwrap().approximate_bytes);
+
+    bucket.put("k1", "value1").unwrap();
+    bucket.put("k2", "value2").unwrap();
+    bc.put("k1", "value1").unwrap();
+    let stats = bucket.stats().unwrap();
+    assert_eq!(2, stats.key_count);
+    let bytes = stats.approximate_bytes;
+    assert!(bytes > 2 * "k1value1".len());
+
+    bucket.put("k1", "value1").unwrap();
+    assert_eq!(bytes, bucket.stats().unwrap().approximate_bytes);
+}
+
+#[test]
+fn test_buckets_after_merge_and_recovery() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        let b1 = bc.bucket("b1").unwrap();
+        let b2 = bc.bucket("b2").unwrap();
+        for i in 0..20 {
+            b1.put(format!("k{}", i), format!("value{}", i)).unwrap();
+            b2.put(format!("k{}", i), format!("value{}", i)).unwrap();
+        }
+        bc.drop_bucket("b2").unwrap();
+        for i in 0..10 {
+            b1.delete(format!("k{}", i)).unwrap();
+        }
+        bc.merge().unwrap();
+
+        assert_eq!(10, b1.stats().unwrap().key_count);
+        assert_eq!(0, bc.bucket("b2").unwrap().stats().unwrap().key_count);
+    }
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    let b1 = bc.bucket("b1").unwrap();
+    assert_eq!(10, b1.stats().unwrap().key_count);
+    for i in 10..20 {
+        assert_eq!(
+            format!("value{}", i).as_bytes(),
+            b1.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
+    assert!(bucket_keys(&bc, "b2").is_empty());
+    assert_eq!(10, bc.count_keys().unwrap());
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::internals::{get_temporary_directory_path, BitcaskyFormatter};
+use bitcasky::options::{BitcaskyOptions, FormatCompat};
+use test_log::test;
+
+// Database directories generated by older versions of bitcasky. See compat_fixtures/generator
+// for how they are generated. Every change on file format should add fixtures here.
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/compat_fixtures");
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(256)
+        .init_data_file_capacity(256)
+}
+
+/// Copies fixture to a temporary directory, opening it in place will modify it
+fn copy_fixture(name: &str) -> PathBuf {
+    let src = Path::new(FIXTURES_DIR).join(name);
+    let dst = get_temporary_directory_path();
+    fs::create_dir_all(&dst).unwrap();
+    for entry in fs::read_dir(&src).unwrap() {
+        let path = entry.unwrap().path();
+        fs::copy(&path, dst.join(path.file_name().unwrap())).unwrap();
+    }
+    dst
+}
+
+fn read_expected(dir: &Path) -> BTreeMap<String, Option<String>> {
+    fs::read_to_string(dir.join("expected"))
+        .unwrap()
+        .lines()
+        .map(|line| match line.split_once('\t') {
+            Some((k, v)) => (k.to_string(), Some(v.to_string())),
+            None => (line.to_string(), None),
+        })
+        .collect()
+}
+
+fn assert_values(bc: &Bitcasky, expected: &BTreeMap<String, Option<String>>) {
+    for (k, v) in expected.iter() {
+        assert_eq!(
+            v.as_ref().map(|v| v.as_bytes().to_vec()),
+            bc.get(k).unwrap(),
+            "unexpected value for key: {}",
+            k
+        );
+    }
+    let live_keys = expected.values().filter(|v| v.is_some()).count();
+    assert_eq!(live_keys, bc.get_telemetry_data().keydir.number_of_keys);
+}
+
+/// Formatter versions in file headers of data files, ordered by storage id
+fn formatter_versions_of_data_files(dir: &Path) -> Vec<u8> {
+    let mut files = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "data"))
+        .filter(|p| !is_erlang_bitcask_file(p))
+        .map(|p| {
+            let id: u32 = p.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            (id, fs::read(&p).unwrap()[3])
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files.into_iter().map(|(_, version)| version).collect()
+}
+
+fn is_erlang_bitcask_file(path: &Path) -> bool {
+    path.file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains(".bitcask.")
+}
+
+fn erlang_bitcask_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| is_erlang_bitcask_file(p))
+        .collect()
+}
+
+fn check_fixture(name: &str) {
+    let latest_version = BitcaskyFormatter::default().version();
+    let dir = copy_fixture(name);
+    let mut expected = read_expected(&dir);
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        assert_values(&bc, &expected);
+        // new rows are always written with the latest formatter
+        bc.put("new-key", "new-value").unwrap();
+        expected.insert("new-key".into(), Some("new-value".into()));
+        assert_eq!(
+            Some(&latest_version),
+            formatter_versions_of_data_files(&dir).last()
+        );
+        assert_values(&bc, &expected);
+        // merge rewrites all the rows with the latest formatter
+        bc.merge().unwrap();
+        assert_values(&bc, &expected);
+        assert!(bc.verify().unwrap().is_healthy());
+        assert!(formatter_versions_of_data_files(&dir)
+            .iter()
+            .all(|v| *v == latest_version));
+    }
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_values(&bc, &expected);
+}
+
+#[test]
+fn test_open_v1_without_hint_files() {
+    check_fixture("v1/no_hints");
+}
+
+#[test]
+fn test_open_v1_with_hint_files() {
+    check_fixture("v1/hints");
+}
+
+#[test]
+fn test_open_v1_after_merge() {
+    check_fixture("v1/merged");
+}
+
+#[test]
+fn test_open_v1_with_writing_file() {
+    check_fixture("v1/writing_file");
+}
+
+#[test]
+fn test_open_v2_without_hint_files() {
+    check_fixture("v2/no_hints");
+}
+
+#[test]
+fn test_open_v2_with_hint_files() {
+    check_fixture("v2/hints");
+}
+
+#[test]
+fn test_open_v2_after_merge() {
+    check_fixture("v2/merged");
+}
+
+#[test]
+fn test_open_v2_with_writing_file() {
+    check_fixture("v2/writing_file");
+}
+
+#[test]
+fn test_put_with_timestamp_over_v2_rows() {
+    let dir = copy_fixture("v2/hints");
+    let expected = read_expected(&dir);
+    let (key, _) = expected.iter().find(|(_, v)| v.is_some()).unwrap();
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    // rows written before formatter v3 have no write timestamp and are older than any
+    assert!(bc
+        .put_with_timestamp(key.as_str(), "replicated", 1)
+        .unwrap());
+    assert_eq!(b"replicated".to_vec(), bc.get(key).unwrap().unwrap());
+    assert!(!bc.put_with_timestamp(key.as_str(), "older", 0).unwrap());
+}
+
+fn get_erlang_bitcask_options() -> BitcaskyOptions {
+    get_options().format_compat(FormatCompat::ErlangBitcask)
+}
+
+#[test]
+fn test_open_erlang_bitcask() {
+    let latest_version = BitcaskyFormatter::default().version();
+    let dir = copy_fixture("erlang_bitcask");
+    let mut expected = read_expected(&dir);
+    {
+        let bc = Bitcasky::open(&dir, get_erlang_bitcask_options()).unwrap();
+        assert_values(&bc, &expected);
+        // files of Erlang bitcask are read only, new rows go to data files of bitcasky
+        bc.put("key-0", "new-value").unwrap();
+        bc.delete("key-1").unwrap();
+        expected.insert("key-0".into(), Some("new-value".into()));
+        expected.insert("key-1".into(), None);
+        assert_values(&bc, &expected);
+        assert_eq!(5, erlang_bitcask_files(&dir).len());
+        assert_eq!(vec![latest_version], formatter_versions_of_data_files(&dir));
+    }
+    {
+        let bc = Bitcasky::open(&dir, get_erlang_bitcask_options()).unwrap();
+        assert_values(&bc, &expected);
+        // merge moves all the rows to data files of bitcasky
+        bc.merge().unwrap();
+        assert_values(&bc, &expected);
+        assert!(bc.verify().unwrap().is_healthy());
+        assert!(erlang_bitcask_files(&dir).is_empty());
+        assert!(formatter_versions_of_data_files(&dir)
+            .iter()
+            .all(|v| *v == latest_version));
+    }
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_values(&bc, &expected);
+}
+
+#[test]
+fn test_open_erlang_bitcask_with_corrupted_hint_file() {
+    let dir = copy_fixture("erlang_bitcask");
+    let expected = read_expected(&dir);
+    let hint_path = dir.join("1.bitcask.hint");
+    let mut bs = fs::read(&hint_path).unwrap();
+    bs[20] ^= 0xff;
+    fs::write(&hint_path, &bs).unwrap();
+
+    // keys are recovered from the data file when its hint file is corrupted
+    let bc = Bitcasky::open(&dir, get_erlang_bitcask_options()).unwrap();
+    assert_values(&bc, &expected);
+}
+
+#[test]
+fn test_ignore_erlang_bitcask_files_without_compat() {
+    let dir = copy_fixture("erlang_bitcask");
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_eq!(0, bc.get_telemetry_data().keydir.number_of_keys);
+    assert!(bc.get("key-0").unwrap().is_none());
+}
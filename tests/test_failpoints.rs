@@ -0,0 +1,411 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::error::BitcaskyError;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::{BitcaskyOptions, MergeErrorPolicy, SyncStrategy};
+use test_log::test;
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(120)
+        .init_data_file_capacity(100)
+        .sync_strategy(SyncStrategy::Interval(Duration::from_secs(1)))
+}
+
+/// Blocks threads reaching a fail point until it is resumed
+struct PausePoint {
+    reached: Arc<AtomicBool>,
+    resumed: Arc<AtomicBool>,
+}
+
+impl PausePoint {
+    fn setup(name: &str) -> PausePoint {
+        let reached = Arc::new(AtomicBool::new(false));
+        let resumed = Arc::new(AtomicBool::new(false));
+        let (r, c) = (reached.clone(), resumed.clone());
+        fail::cfg_callback(name, move || {
+            r.store(true, Ordering::Release);
+            while !c.load(Ordering::Acquire) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        })
+        .unwrap();
+        PausePoint { reached, resumed }
+    }
+
+    fn wait_reached(&self) {
+        while !self.reached.load(Ordering::Acquire) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn resume(&self) {
+        self.resumed.store(true, Ordering::Release);
+    }
+}
+
+fn count_hint_files(dir: &Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|f| f.unwrap().path())
+        .filter(|p| p.extension().map(|e| e == "hint").unwrap_or(false))
+        .count()
+}
+
+fn put_values(dir: &Path) {
+    let bc = Bitcasky::open(dir, get_options()).unwrap();
+    for i in 0..10 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+}
+
+fn assert_values(dir: &Path) {
+    let bc = Bitcasky::open(dir, get_options()).unwrap();
+    for i in 0..10 {
+        assert_eq!(
+            format!("value{}", i).as_bytes(),
+            bc.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_crash_before_hint_file_renamed() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    fail::cfg("hint::before_rename", "return").unwrap();
+    put_values(&dir);
+    fail::remove("hint::before_rename");
+
+    assert_eq!(0, count_hint_files(&dir));
+    assert_values(&dir);
+    scenario.teardown();
+}
+
+#[test]
+fn test_crash_before_data_file_synced() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        bc.put("k0", "value0").unwrap();
+        fail::cfg("data_storage::before_sync_all", "return").unwrap();
+    }
+    fail::remove("data_storage::before_sync_all");
+
+    // the data file was not sealed, so no hint file is written for it
+    assert_eq!(0, count_hint_files(&dir));
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_eq!("value0".as_bytes(), bc.get("k0").unwrap().unwrap());
+    scenario.teardown();
+}
+
+#[test]
+fn test_hint_file_written_after_data_file_synced() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    put_values(&dir);
+    assert!(count_hint_files(&dir) > 0);
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert!(
+        bc.get_telemetry_data()
+            .keydir
+            .recovery_stats
+            .recovered_from_hint
+    );
+    drop(bc);
+    assert_values(&dir);
+    scenario.teardown();
+}
+
+#[test]
+fn test_read_repair_keys_missed_by_merge() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    put_values(&dir);
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        fail::cfg("merge::skip_apply_merged_rows", "return").unwrap();
+        let before_purge = PausePoint::setup("merge::before_purge");
+        let merge_handle = bc.merge_async().unwrap();
+        before_purge.wait_reached();
+
+        // keys still point to the data files going to be purged
+        for i in 0..10 {
+            assert_eq!(
+                format!("value{}", i).as_bytes(),
+                bc.get(format!("k{}", i)).unwrap().unwrap()
+            );
+        }
+        before_purge.resume();
+        merge_handle.join().unwrap();
+
+        for i in 0..10 {
+            assert_eq!(
+                format!("value{}", i).as_bytes(),
+                bc.get(format!("k{}", i)).unwrap().unwrap()
+            );
+        }
+    }
+    fail::remove("merge::skip_apply_merged_rows");
+    fail::remove("merge::before_purge");
+
+    assert_values(&dir);
+    scenario.teardown();
+}
+
+#[test]
+fn test_purge_waits_for_read_in_flight() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    put_values(&dir);
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        fail::cfg("merge::skip_apply_merged_rows", "return").unwrap();
+        let before_purge = PausePoint::setup("merge::before_purge");
+        let merge_handle = bc.merge_async().unwrap();
+        before_purge.wait_reached();
+
+        let read_pending = PausePoint::setup("database::read_pending_purge_value");
+        std::thread::scope(|s| {
+            let reader = s.spawn(|| bc.get("k0").unwrap());
+            read_pending.wait_reached();
+
+            before_purge.resume();
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(!merge_handle.is_finished());
+
+            read_pending.resume();
+            assert_eq!("value0".as_bytes(), reader.join().unwrap().unwrap());
+        });
+        merge_handle.join().unwrap();
+        assert_eq!("value0".as_bytes(), bc.get("k0").unwrap().unwrap());
+    }
+    fail::remove("merge::skip_apply_merged_rows");
+    fail::remove("merge::before_purge");
+    fail::remove("database::read_pending_purge_value");
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_eq!("value0".as_bytes(), bc.get("k0").unwrap().unwrap());
+    scenario.teardown();
+}
+
+#[test]
+fn test_merge_retries_transient_read_failure() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    put_values(&dir);
+    {
+        let bc = Bitcasky::open(
+            &dir,
+            get_options().merge_read_retries(2, Duration::from_millis(1)),
+        )
+        .unwrap();
+        fail::cfg("merge::read_value", "2*return").unwrap();
+        let report = bc.merge().unwrap();
+        assert_eq!(10, report.merged_keys);
+        assert!(report.failed_keys.is_empty());
+        assert!(report.retained_storage_ids.is_empty());
+    }
+    fail::remove("merge::read_value");
+
+    assert_values(&dir);
+    scenario.teardown();
+}
+
+#[test]
+fn test_merge_abort_on_read_failure() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    put_values(&dir);
+    {
+        let bc = Bitcasky::open(
+            &dir,
+            get_options().merge_read_retries(2, Duration::from_millis(1)),
+        )
+        .unwrap();
+        fail::cfg("merge::read_value", "return(k3)").unwrap();
+        assert!(bc.merge().is_err());
+        fail::remove("merge::read_value");
+
+        for i in 0..10 {
+            assert_eq!(
+                format!("value{}", i).as_bytes(),
+                bc.get(format!("k{}", i)).unwrap().unwrap()
+            );
+        }
+    }
+    assert_values(&dir);
+    scenario.teardown();
+}
+
+#[test]
+fn test_merge_skip_keys_failed_to_read() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    let options = || get_options().merge_error_policy(MergeErrorPolicy::Skip);
+    let k3_location = {
+        let bc = Bitcasky::open(&dir, options()).unwrap();
+        bc.put("k3", "value3").unwrap();
+        bc.put("gone", "value").unwrap();
+        let (k3_location, _) = bc.get_location("k3").unwrap().unwrap();
+        let (gone_location, _) = bc.get_location("gone").unwrap().unwrap();
+        assert_eq!(k3_location.storage_id, gone_location.storage_id);
+        for i in (0..10).filter(|i| *i != 3) {
+            bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+        }
+        // the tombstone is in a data file purged by merge
+        bc.delete("gone").unwrap();
+        assert_ne!(
+            k3_location.storage_id,
+            bc.get_location("k9").unwrap().unwrap().0.storage_id
+        );
+
+        fail::cfg("merge::read_value", "return(k3)").unwrap();
+        let report = bc.merge().unwrap();
+        fail::remove("merge::read_value");
+        assert_eq!(9, report.merged_keys);
+        assert_eq!(vec![b"k3".to_vec()], report.failed_keys);
+        assert_eq!(vec![k3_location.storage_id], report.retained_storage_ids);
+
+        // the failed key is left at its old location
+        assert_eq!(k3_location, bc.get_location("k3").unwrap().unwrap().0);
+        assert!(bc.get("gone").unwrap().is_none());
+        assert_eq!(10, bc.count_keys().unwrap());
+        k3_location
+    };
+
+    // deleted key in the retained data file is not recovered
+    let bc = Bitcasky::open(&dir, options()).unwrap();
+    assert!(bc.get("gone").unwrap().is_none());
+    for i in 0..10 {
+        assert_eq!(
+            format!("value{}", i).as_bytes(),
+            bc.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
+
+    // the retained data file is purged once its keys are merged
+    let report = bc.merge().unwrap();
+    assert_eq!(10, report.merged_keys);
+    assert!(report.retained_storage_ids.is_empty());
+    drop(bc);
+    assert!(!dir
+        .join(format!("{}.data", k3_location.storage_id))
+        .exists());
+    assert_values(&dir);
+    scenario.teardown();
+}
+
+#[test]
+fn test_auto_merge_collects_errors() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    put_values(&dir);
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        for i in 0..5 {
+            bc.delete(format!("k{}", i)).unwrap();
+        }
+        fail::cfg("merge::read_value", "return(k7)").unwrap();
+        let handle = bc.start_auto_merge(Duration::from_millis(10), 0.1).unwrap();
+        let start = std::time::Instant::now();
+        let mut errors = vec![];
+        while errors.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+            errors = handle.take_errors();
+        }
+        drop(handle);
+        fail::remove("merge::read_value");
+        assert!(bc.should_merge(0.1));
+    }
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    for i in 5..10 {
+        assert_eq!(
+            format!("value{}", i).as_bytes(),
+            bc.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
+    scenario.teardown();
+}
+
+#[test]
+fn test_merge_refused_without_free_space() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    put_values(&dir);
+    {
+        let bc = Bitcasky::open(&dir, get_options().merge_check_free_space(true)).unwrap();
+        let required = bc.merge_estimate().required_free_bytes;
+        assert!(required > 0);
+
+        fail::cfg("fs::available_space", &format!("return({})", required - 1)).unwrap();
+        assert!(matches!(
+            bc.merge(),
+            Err(BitcaskyError::InsufficientFreeSpace(r, a)) if r == required && a == required - 1
+        ));
+        assert!(!bc.get_telemetry_data().merge_manager.is_merging);
+
+        fail::cfg("fs::available_space", &format!("return({})", required)).unwrap();
+        bc.merge().unwrap();
+        fail::remove("fs::available_space");
+    }
+    assert_values(&dir);
+    scenario.teardown();
+}
+
+fn count_temp_data_files(dir: &Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|f| f.unwrap().file_name().into_string().unwrap())
+        .filter(|n| n.starts_with("tmp-") && n.ends_with(".data"))
+        .count()
+}
+
+#[test]
+fn test_crash_while_creating_data_file() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    fail::cfg("database::create_data_file::before_header", "return").unwrap();
+    assert!(Bitcasky::open(&dir, get_options()).is_err());
+    fail::remove("database::create_data_file::before_header");
+    assert_eq!(1, count_temp_data_files(&dir));
+
+    put_values(&dir);
+    assert_eq!(0, count_temp_data_files(&dir));
+    assert_values(&dir);
+    scenario.teardown();
+}
+
+#[test]
+fn test_crash_while_rotating_data_file() {
+    let scenario = fail::FailScenario::setup();
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        bc.put("k0", "value0").unwrap();
+        fail::cfg("database::create_data_file::before_header", "return").unwrap();
+        let mut ret = Ok(());
+        for i in 1..10 {
+            ret = bc.put(format!("k{}", i), format!("value{}", i));
+            if ret.is_err() {
+                break;
+            }
+        }
+        assert!(ret.is_err());
+    }
+    fail::remove("database::create_data_file::before_header");
+    assert_eq!(1, count_temp_data_files(&dir));
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_eq!(0, count_temp_data_files(&dir));
+    assert_eq!("value0".as_bytes(), bc.get("k0").unwrap().unwrap());
+    bc.put("k1", "value1").unwrap();
+    scenario.teardown();
+}
@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use bitcasky::bitcasky::{Bitcasky, SledKeyDirBackend};
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use test_log::test;
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(1024)
+        .init_data_file_capacity(100)
+}
+
+fn open_with_sled(dir: &Path, keydir_dir: &Path) -> Bitcasky {
+    Bitcasky::open_with_keydir_backend(
+        dir,
+        get_options(),
+        Box::new(SledKeyDirBackend::open(keydir_dir).unwrap()),
+    )
+    .unwrap()
+}
+
+fn recovered_from_keydir_backend(bc: &Bitcasky) -> bool {
+    bc.get_telemetry_data()
+        .keydir
+        .recovery_stats
+        .recovered_from_keydir_backend
+}
+
+fn assert_values(bc: &Bitcasky) {
+    for i in 0..50 {
+        let value = bc.get(format!("k{}", i)).unwrap();
+        if i % 5 == 0 {
+            assert!(value.is_none());
+        } else {
+            assert_eq!(format!("value{}", i).as_bytes(), value.unwrap());
+        }
+    }
+    assert_eq!(40, bc.count_keys().unwrap());
+}
+
+fn write_values(dir: &Path, keydir_dir: &Path) {
+    let bc = open_with_sled(dir, keydir_dir);
+    assert!(!recovered_from_keydir_backend(&bc));
+    for i in 0..50 {
+        bc.put(format!("k{}", i), "outdated").unwrap();
+    }
+    bc.merge().unwrap();
+    for i in 0..50 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+    for i in (0..50).step_by(5) {
+        bc.delete(format!("k{}", i)).unwrap();
+    }
+    assert_values(&bc);
+}
+
+fn keydir_directory(dir: &Path) -> PathBuf {
+    dir.join("keydir")
+}
+
+#[test]
+fn test_skip_recovery_after_closed_gracefully() {
+    let dir = get_temporary_directory_path();
+    let keydir_dir = keydir_directory(&dir);
+    write_values(&dir, &keydir_dir);
+
+    let bc = open_with_sled(&dir, &keydir_dir);
+    assert!(recovered_from_keydir_backend(&bc));
+    assert_values(&bc);
+    // keys changed after open are kept too
+    bc.put("k0", "value0").unwrap();
+    bc.merge().unwrap();
+    drop(bc);
+
+    let bc = open_with_sled(&dir, &keydir_dir);
+    assert!(recovered_from_keydir_backend(&bc));
+    assert_eq!("value0".as_bytes(), bc.get("k0").unwrap().unwrap());
+    assert_eq!(41, bc.count_keys().unwrap());
+}
+
+#[test]
+fn test_recover_when_data_files_changed_after_closed() {
+    let dir = get_temporary_directory_path();
+    let keydir_dir = keydir_directory(&dir);
+    write_values(&dir, &keydir_dir);
+
+    // data files are changed without the keydir backend
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        bc.put("k0", "value0").unwrap();
+        bc.delete("k1").unwrap();
+    }
+
+    let bc = open_with_sled(&dir, &keydir_dir);
+    assert!(!recovered_from_keydir_backend(&bc));
+    assert_eq!("value0".as_bytes(), bc.get("k0").unwrap().unwrap());
+    assert!(bc.get("k1").unwrap().is_none());
+    assert_eq!(40, bc.count_keys().unwrap());
+    drop(bc);
+
+    let bc = open_with_sled(&dir, &keydir_dir);
+    assert!(recovered_from_keydir_backend(&bc));
+    assert_eq!(40, bc.count_keys().unwrap());
+}
+
+#[test]
+fn test_drop_database_with_keydir_backend() {
+    let dir = get_temporary_directory_path();
+    let keydir_dir = keydir_directory(&dir);
+    write_values(&dir, &keydir_dir);
+
+    let bc = open_with_sled(&dir, &keydir_dir);
+    bc.drop().unwrap();
+    drop(bc);
+
+    let bc = open_with_sled(&dir, &keydir_dir);
+    assert_eq!(0, bc.count_keys().unwrap());
+    assert!(bc.get("k1").unwrap().is_none());
+}
@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use test_log::test;
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(1024)
+        .init_data_file_capacity(100)
+        .keydir_snapshot(true)
+}
+
+fn recovered_from_keydir_backend(bc: &Bitcasky) -> bool {
+    bc.get_telemetry_data()
+        .keydir
+        .recovery_stats
+        .recovered_from_keydir_backend
+}
+
+fn snapshot_path(dir: &Path) -> PathBuf {
+    dir.join("keydir.snapshot")
+}
+
+fn write_values(dir: &Path) {
+    let bc = Bitcasky::open(dir, get_options()).unwrap();
+    assert!(!recovered_from_keydir_backend(&bc));
+    for i in 0..50 {
+        bc.put(format!("k{}", i), "outdated").unwrap();
+    }
+    bc.merge().unwrap();
+    for i in 0..50 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+    for i in (0..50).step_by(5) {
+        bc.delete(format!("k{}", i)).unwrap();
+    }
+}
+
+#[test]
+fn test_load_snapshot_after_closed_gracefully() {
+    let dir = get_temporary_directory_path();
+    write_values(&dir);
+    assert!(snapshot_path(&dir).exists());
+
+    // reopen without writes keeps the snapshot
+    for _ in 0..2 {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        assert!(recovered_from_keydir_backend(&bc));
+        assert_eq!(40, bc.count_keys().unwrap());
+        assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+        assert!(bc.get("k0").unwrap().is_none());
+    }
+    assert!(snapshot_path(&dir).exists());
+}
+
+#[test]
+fn test_snapshot_deleted_on_first_write() {
+    let dir = get_temporary_directory_path();
+    write_values(&dir);
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert!(recovered_from_keydir_backend(&bc));
+    bc.put("k0", "value0").unwrap();
+    // a crash from now on recovers data files
+    assert!(!snapshot_path(&dir).exists());
+    drop(bc);
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert!(recovered_from_keydir_backend(&bc));
+    assert_eq!("value0".as_bytes(), bc.get("k0").unwrap().unwrap());
+    assert_eq!(41, bc.count_keys().unwrap());
+}
+
+#[test]
+fn test_recover_when_data_files_changed_after_closed() {
+    let dir = get_temporary_directory_path();
+    write_values(&dir);
+
+    // data files are changed without loading the snapshot
+    {
+        let bc = Bitcasky::open(&dir, get_options().keydir_snapshot(false)).unwrap();
+        bc.put("k0", "value0").unwrap();
+        bc.delete("k1").unwrap();
+    }
+    assert!(snapshot_path(&dir).exists());
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert!(!recovered_from_keydir_backend(&bc));
+    assert_eq!("value0".as_bytes(), bc.get("k0").unwrap().unwrap());
+    assert!(bc.get("k1").unwrap().is_none());
+    assert_eq!(40, bc.count_keys().unwrap());
+}
+
+#[test]
+fn test_drop_database_with_keydir_snapshot() {
+    let dir = get_temporary_directory_path();
+    write_values(&dir);
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    bc.drop().unwrap();
+    drop(bc);
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_eq!(0, bc.count_keys().unwrap());
+    assert!(bc.get("k1").unwrap().is_none());
+}
+
+#[test]
+fn test_load_bloom_filter_with_snapshot() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_options().bloom_filter(100, 0.01)).unwrap();
+        for i in 0..50 {
+            bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+        }
+        for i in (0..50).step_by(5) {
+            bc.delete(format!("k{}", i)).unwrap();
+        }
+    }
+
+    // a rebuilt bloom filter would only count the 40 keys left
+    let bc = Bitcasky::open(&dir, get_options().bloom_filter(100, 0.01)).unwrap();
+    assert!(recovered_from_keydir_backend(&bc));
+    assert_eq!(50, bc.bloom_stats().unwrap().items);
+    assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+    drop(bc);
+
+    // saved by other options
+    let bc = Bitcasky::open(&dir, get_options().bloom_filter(200, 0.01)).unwrap();
+    assert!(recovered_from_keydir_backend(&bc));
+    assert_eq!(40, bc.bloom_stats().unwrap().items);
+}
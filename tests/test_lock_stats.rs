@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::codec::{ValueCodec, ValueCodecError};
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use test_log::test;
+
+const SLOW_WRITE: Duration = Duration::from_millis(50);
+
+/// Makes every put slow while the keydir write lock and the writing storage lock are held
+#[derive(Debug)]
+struct SlowCodec;
+
+impl ValueCodec for SlowCodec {
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        thread::sleep(SLOW_WRITE);
+        value.to_vec()
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, ValueCodecError> {
+        Ok(encoded.to_vec())
+    }
+}
+
+#[test]
+fn test_lock_stats_without_contention() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, BitcaskyOptions::default()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+
+    let stats = bc.lock_stats();
+    assert!(stats.keydir.wait.count >= 2);
+    assert_eq!(stats.keydir.wait.count, stats.keydir.hold.count);
+    assert_eq!(0, stats.keydir.wait.count_at_least(SLOW_WRITE));
+    assert!(stats.writing_storage.hold.count >= 2);
+}
+
+#[test]
+fn test_lock_stats_reflect_slow_writer() {
+    let dir = get_temporary_directory_path();
+    let bc = Arc::new(
+        Bitcasky::open(
+            &dir,
+            BitcaskyOptions::default().value_codec(Arc::new(SlowCodec)),
+        )
+        .unwrap(),
+    );
+    let writing = Arc::new(AtomicBool::new(true));
+    let readers = (0..4)
+        .map(|_| {
+            let bc = bc.clone();
+            let writing = writing.clone();
+            thread::spawn(move || {
+                while writing.load(Ordering::Acquire) {
+                    bc.get("k1").unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for i in 0..5 {
+        bc.put("k1", format!("value{}", i)).unwrap();
+    }
+    writing.store(false, Ordering::Release);
+    for r in readers {
+        r.join().unwrap();
+    }
+
+    let stats = bc.lock_stats();
+    // every put holds the keydir lock while encoding the value
+    assert!(stats.keydir.hold.count_at_least(SLOW_WRITE) >= 5);
+    assert!(stats.writing_storage.hold.count_at_least(SLOW_WRITE) >= 5);
+    // readers keep waiting for the slow writer
+    assert!(stats.keydir.wait.count_at_least(SLOW_WRITE / 2) > 0);
+    assert!(stats.keydir.wait.max_micros >= (SLOW_WRITE / 2).as_micros() as u64);
+    assert!(stats.keydir.wait.quantile(1.0) >= SLOW_WRITE / 2);
+}
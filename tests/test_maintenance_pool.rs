@@ -0,0 +1,86 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::maintenance::MaintenancePool;
+use bitcasky::options::BitcaskyOptions;
+#[cfg(feature = "sync-worker")]
+use bitcasky::options::SyncStrategy;
+use test_log::test;
+
+fn get_options(pool: &Arc<MaintenancePool>) -> BitcaskyOptions {
+    let options = BitcaskyOptions::default()
+        .max_data_file_size(120)
+        .init_data_file_capacity(100)
+        .auto_merge(0.5)
+        .auto_merge_check_interval(Duration::from_millis(10))
+        .maintenance_pool(pool.clone());
+    #[cfg(feature = "sync-worker")]
+    let options = options.sync_strategy(SyncStrategy::Interval(Duration::from_secs(1)));
+    options
+}
+
+#[test]
+fn test_instances_share_maintenance_pool() {
+    let pool = Arc::new(MaintenancePool::new(2));
+    let dirs = (0..4)
+        .map(|_| get_temporary_directory_path())
+        .collect::<Vec<_>>();
+    {
+        let instances = dirs
+            .iter()
+            .map(|dir| Bitcasky::open(dir, get_options(&pool)).unwrap())
+            .collect::<Vec<Bitcasky>>();
+        for bc in instances.iter() {
+            for i in 0..10 {
+                bc.put(format!("k{}", i), "value").unwrap();
+            }
+            for i in 0..5 {
+                bc.delete(format!("k{}", i)).unwrap();
+            }
+        }
+        let handles = instances
+            .iter()
+            .filter_map(|bc| bc.merge_async().ok())
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let start = Instant::now();
+        for bc in instances.iter() {
+            while bc
+                .get_telemetry_data()
+                .database
+                .hint_file_writer
+                .write_times
+                == 0
+            {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        let telemetry = instances[0].get_telemetry_data().maintenance_pool;
+        assert_eq!(2, telemetry.threads);
+        assert_eq!(4, telemetry.queues);
+    }
+
+    // all the background tasks are stopped on close
+    assert_eq!(1, Arc::strong_count(&pool));
+    assert_eq!(0, pool.get_telemetry_data().queues);
+
+    for dir in dirs.iter() {
+        let bc = Bitcasky::open(dir, get_options(&pool)).unwrap();
+        for i in 0..5 {
+            assert_eq!(None, bc.get(format!("k{}", i)).unwrap());
+        }
+        for i in 5..10 {
+            assert_eq!(
+                "value".as_bytes(),
+                bc.get(format!("k{}", i)).unwrap().unwrap()
+            );
+        }
+    }
+    drop(pool);
+}
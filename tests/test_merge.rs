@@ -1,8 +1,13 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use bitcasky::bitcasky::Bitcasky;
+use bitcasky::bitcasky::{Bitcasky, LocationInvalidation, MergeCancelToken};
+use bitcasky::error::BitcaskyError;
 use bitcasky::internals::get_temporary_directory_path;
-use bitcasky::options::BitcaskyOptions;
+use bitcasky::options::{BitcaskyOptions, DataSotrageType};
 use test_log::test;
 
 #[test]
@@ -61,6 +66,80 @@ fn test_merge_duplicate() {
     assert_eq!(1, after_merge_telemetry.keydir.number_of_keys);
 }
 
+#[test]
+fn test_amplification_ratios() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    let telemetry = bc.get_telemetry_data().database;
+    let row_size = telemetry.total_written_bytes;
+    assert_eq!(1.0, telemetry.write_amplification_ratio);
+
+    bc.put("k1", "value2").unwrap();
+    bc.put("k1", "value3").unwrap();
+    let before_merge_telemetry = bc.get_telemetry_data().database;
+    assert_eq!(3 * row_size, before_merge_telemetry.total_written_bytes);
+    assert_eq!(3.0, before_merge_telemetry.write_amplification_ratio);
+    assert!(before_merge_telemetry.space_amplification_ratio >= 3.0);
+
+    bc.merge().unwrap();
+    // merge rewrites the live row, so write amplification keeps growing while space
+    // amplification drops
+    let after_merge_telemetry = bc.get_telemetry_data().database;
+    assert_eq!(4 * row_size, after_merge_telemetry.total_written_bytes);
+    assert_eq!(4.0, after_merge_telemetry.write_amplification_ratio);
+    assert!(
+        after_merge_telemetry.space_amplification_ratio
+            < before_merge_telemetry.space_amplification_ratio
+    );
+    drop(bc);
+
+    // written bytes restart from bytes in data files on reopen
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    let telemetry = bc.get_telemetry_data().database;
+    assert_eq!(
+        telemetry.storage_aggregate.total_data_size as u64,
+        telemetry.total_written_bytes
+    );
+}
+
+#[test]
+fn test_merge_large_values() {
+    for storage_type in [
+        DataSotrageType::File,
+        #[cfg(feature = "mmap")]
+        DataSotrageType::Mmap,
+    ] {
+        for verify_crc in [true, false] {
+            let db_path = get_temporary_directory_path();
+            let options = || {
+                BitcaskyOptions::default()
+                    .max_data_file_size(64 * 1024)
+                    .storage_type(storage_type)
+                    .verify_crc_on_read(verify_crc)
+            };
+            let bc = Bitcasky::open(&db_path, options()).unwrap();
+            for i in 0..20 {
+                bc.put(format!("k{}", i), vec![i as u8; 10 * 1024]).unwrap();
+            }
+            for i in 0..10 {
+                bc.delete(format!("k{}", i)).unwrap();
+            }
+            bc.merge().unwrap();
+            drop(bc);
+
+            let bc = Bitcasky::open(&db_path, options()).unwrap();
+            assert_eq!(10, bc.count_keys().unwrap());
+            for i in 10..20 {
+                assert_eq!(
+                    vec![i as u8; 10 * 1024],
+                    bc.get(format!("k{}", i)).unwrap().unwrap()
+                );
+            }
+        }
+    }
+}
+
 #[test]
 fn test_merge_recover_after_merge() {
     let db_path = get_temporary_directory_path();
@@ -136,3 +215,881 @@ fn test_recover_expirable_value() {
     assert!(bc.get("expireK4").unwrap().is_none());
     assert_eq!(bc.get("notEpireK5").unwrap().unwrap(), "value5".as_bytes());
 }
+
+#[test]
+fn test_merge_async() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    bc.put("k2", "value2").unwrap();
+    bc.put("k3", "value3").unwrap();
+    bc.delete("k1").unwrap();
+
+    let handle = bc.merge_async().unwrap();
+    bc.put("k4", "value4").unwrap();
+    bc.delete("k2").unwrap();
+    handle.join().unwrap();
+
+    assert!(!bc.get_telemetry_data().merge_manager.is_merging);
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!(None, bc.get("k2").unwrap());
+    assert_eq!("value3".as_bytes(), bc.get("k3").unwrap().unwrap());
+    assert_eq!("value4".as_bytes(), bc.get("k4").unwrap().unwrap());
+
+    drop(bc);
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    assert_eq!(None, bc.get("k2").unwrap());
+    assert_eq!("value3".as_bytes(), bc.get("k3").unwrap().unwrap());
+    assert_eq!("value4".as_bytes(), bc.get("k4").unwrap().unwrap());
+}
+
+#[test]
+fn test_cancel_merge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    for k in ["k1", "k2", "k3", "k4"] {
+        bc.put(k, "value").unwrap();
+    }
+    bc.delete("k1").unwrap();
+    let stable_files = bc.get_telemetry_data().database.stable_storages.len();
+
+    let cancel = MergeCancelToken::new();
+    cancel.cancel();
+    assert!(matches!(
+        bc.merge_cancellable(&cancel),
+        Err(BitcaskyError::MergeCancelled())
+    ));
+    assert!(!db_path.join("Merge").exists());
+    assert!(!bc.get_telemetry_data().merge_manager.is_merging);
+    // the writing file is rotated on start, merged files are kept
+    assert!(bc.get_telemetry_data().database.stable_storages.len() > stable_files);
+
+    // cancelled or not, values are intact
+    let handle = bc.merge_async().unwrap();
+    handle.cancel();
+    let ret = handle.join();
+    assert!(matches!(ret, Ok(_) | Err(BitcaskyError::MergeCancelled())));
+
+    let assert_values = |bc: &Bitcasky| {
+        assert_eq!(None, bc.get("k1").unwrap());
+        for k in ["k2", "k3", "k4"] {
+            assert_eq!("value".as_bytes(), bc.get(k).unwrap().unwrap());
+        }
+    };
+    assert_values(&bc);
+    bc.merge().unwrap();
+    assert_values(&bc);
+    drop(bc);
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    assert_values(&bc);
+}
+
+#[test]
+fn test_dead_bytes_recovered_on_reopen() {
+    let db_path = get_temporary_directory_path();
+    let (dead_bytes, data_size) = {
+        let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+        bc.put("k1", "value1").unwrap();
+        bc.put("k2", "value2").unwrap();
+        bc.put("k3", "value3").unwrap();
+        bc.put("k1", "value4").unwrap();
+        bc.delete("k2").unwrap();
+        assert!(bc.should_merge(0.5));
+        assert!(!bc.should_merge(0.9));
+        let telemetry = bc.get_telemetry_data().database.storage_aggregate;
+        (telemetry.total_dead_bytes, telemetry.total_data_size)
+    };
+
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    let telemetry = bc.get_telemetry_data().database.storage_aggregate;
+    assert_eq!(dead_bytes, telemetry.total_dead_bytes);
+    assert_eq!(data_size, telemetry.total_data_size);
+    assert!(bc.should_merge(0.5));
+
+    bc.merge().unwrap();
+    assert!(!bc.should_merge(0.0));
+}
+
+#[test]
+fn test_auto_merge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(
+        &db_path,
+        BitcaskyOptions::default()
+            .auto_merge(0.5)
+            .auto_merge_check_interval(Duration::from_millis(10)),
+    )
+    .unwrap();
+    bc.put("k1", "value1").unwrap();
+    bc.put("k2", "value2").unwrap();
+    bc.delete("k1").unwrap();
+    bc.delete("k2").unwrap();
+    assert!(bc.should_merge(0.5));
+
+    let start = std::time::Instant::now();
+    while bc.should_merge(0.5) {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(0, bc.get_telemetry_data().keydir.number_of_keys);
+}
+
+#[test]
+fn test_start_auto_merge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(
+        &db_path,
+        BitcaskyOptions::default()
+            .max_data_file_size(120)
+            .init_data_file_capacity(100),
+    )
+    .unwrap();
+    assert!(matches!(
+        bc.start_auto_merge(Duration::from_millis(10), 1.5),
+        Err(BitcaskyError::InvalidParameter(name, _)) if name == "dead_byte_threshold"
+    ));
+    assert!(matches!(
+        bc.start_auto_merge(Duration::ZERO, 0.5),
+        Err(BitcaskyError::InvalidParameter(name, _)) if name == "interval"
+    ));
+
+    let handle = bc.start_auto_merge(Duration::from_millis(10), 0.5).unwrap();
+    for round in 0..3 {
+        // overwrite all keys on many small data files
+        for i in 0..20 {
+            bc.put(format!("k{}", i), format!("value{}-{}", i, round))
+                .unwrap();
+        }
+        let start = std::time::Instant::now();
+        while bc.should_merge(0.5) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    assert!(handle.take_errors().is_empty());
+    for i in 0..20 {
+        assert_eq!(
+            format!("value{}-2", i).as_bytes(),
+            bc.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
+
+    // no merge after the handle is dropped
+    drop(handle);
+    for i in 0..20 {
+        bc.delete(format!("k{}", i)).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(bc.should_merge(0.5));
+}
+
+#[test]
+fn test_auto_merge_suspended_in_read_only_mode() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    bc.put("k2", "value2").unwrap();
+    bc.delete("k1").unwrap();
+    bc.delete("k2").unwrap();
+
+    bc.set_read_only(true);
+    let handle = bc.start_auto_merge(Duration::from_millis(10), 0.5).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(bc.should_merge(0.5));
+
+    bc.set_read_only(false);
+    let start = std::time::Instant::now();
+    while bc.should_merge(0.5) {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(handle.take_errors().is_empty());
+}
+
+#[test]
+fn test_location_generation_bumped_by_merge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    let invalidations = Arc::new(Mutex::new(vec![]));
+    {
+        let invalidations = invalidations.clone();
+        bc.on_location_invalidated(move |inv| invalidations.lock().unwrap().push(inv.clone()));
+    }
+    bc.put("k1", "value1").unwrap();
+    bc.put("k2", "value2").unwrap();
+    bc.put("k1", "value3").unwrap();
+
+    let mut cache = HashMap::new();
+    for k in ["k1", "k2"] {
+        cache.insert(k, bc.get_location(k).unwrap().unwrap());
+    }
+    assert_eq!(None, bc.get_location("k3").unwrap());
+    let (location, generation) = cache["k1"];
+    let initial = bc.location_generation();
+    assert_eq!(initial, generation);
+    assert_eq!(
+        (Some("value3".as_bytes().to_vec()), initial),
+        bc.read_at(&location, generation).unwrap()
+    );
+
+    bc.merge().unwrap();
+
+    assert_eq!(initial + 1, bc.location_generation());
+    let (location, generation) = cache["k1"];
+    assert!(matches!(
+        bc.read_at(&location, generation),
+        Err(BitcaskyError::StaleLocation(g, current)) if g == initial && current == initial + 1
+    ));
+    match &invalidations.lock().unwrap()[..] {
+        [LocationInvalidation::Keys { generation, keys }] => {
+            assert_eq!(initial + 1, *generation);
+            let mut keys = keys.clone();
+            keys.sort();
+            assert_eq!(vec![b"k1".to_vec(), b"k2".to_vec()], keys);
+        }
+        invalidations => panic!("unexpected invalidations: {:?}", invalidations),
+    }
+
+    let (location, generation) = bc.get_location("k1").unwrap().unwrap();
+    assert_eq!(
+        (Some("value3".as_bytes().to_vec()), initial + 1),
+        bc.read_at(&location, generation).unwrap()
+    );
+
+    bc.drop().unwrap();
+    assert_eq!(initial + 2, bc.location_generation());
+    assert_eq!(
+        Some(&LocationInvalidation::All {
+            generation: initial + 2
+        }),
+        invalidations.lock().unwrap().last()
+    );
+}
+
+#[test]
+fn test_location_stale_after_reopen() {
+    let db_path = get_temporary_directory_path();
+    let (location, generation) = {
+        let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+        let initial = bc.location_generation();
+        for i in 0..10 {
+            bc.put("k1", format!("value{}", i)).unwrap();
+            bc.merge().unwrap();
+        }
+        assert_eq!(initial + 10, bc.location_generation());
+        bc.get_location("k1").unwrap().unwrap()
+    };
+
+    // reopened at once, generation does not continue from where it was
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    assert_ne!(generation, bc.location_generation());
+    assert!(matches!(
+        bc.read_at(&location, generation),
+        Err(BitcaskyError::StaleLocation(..))
+    ));
+}
+
+fn get_partial_merge_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(120)
+        .init_data_file_capacity(100)
+}
+
+#[test]
+fn test_merge_fragmented_files() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    for k in ["k1", "k2", "k3", "k4", "k5", "k6"] {
+        bc.put(k, "value").unwrap();
+    }
+    let location_of = |k| bc.get_location(k).unwrap().unwrap().0.storage_id;
+    let fragmented_file = location_of("k1");
+    let untouched_file = location_of("k4");
+    assert_ne!(fragmented_file, untouched_file);
+    assert_eq!(fragmented_file, location_of("k2"));
+    bc.put("k1", "new value").unwrap();
+    bc.delete("k2").unwrap();
+
+    assert_eq!(vec![fragmented_file], bc.fragmented_files(0.5));
+    bc.merge_files(&[fragmented_file]).unwrap();
+
+    let stable_storages = bc.get_telemetry_data().database.stable_storages;
+    assert!(!stable_storages.contains_key(&fragmented_file));
+    assert!(stable_storages.contains_key(&untouched_file));
+    assert_eq!(untouched_file, location_of("k4"));
+
+    let assert_values = |bc: &Bitcasky| {
+        assert_eq!("new value".as_bytes(), bc.get("k1").unwrap().unwrap());
+        assert_eq!(None, bc.get("k2").unwrap());
+        for k in ["k3", "k4", "k5", "k6"] {
+            assert_eq!("value".as_bytes(), bc.get(k).unwrap().unwrap());
+        }
+    };
+    assert_values(&bc);
+    drop(bc);
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    assert_values(&bc);
+}
+
+#[test]
+fn test_merge_fragmented() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    for k in ["k1", "k2", "k3", "k4", "k5", "k6"] {
+        bc.put(k, "value").unwrap();
+    }
+    let location_of = |k| bc.get_location(k).unwrap().unwrap().0;
+    let fragmented_file = location_of("k1").storage_id;
+    let untouched_locations = ["k3", "k4", "k5", "k6"]
+        .into_iter()
+        .map(|k| (k, location_of(k)))
+        .filter(|(_, l)| l.storage_id != fragmented_file)
+        .collect::<Vec<_>>();
+    assert!(!untouched_locations.is_empty());
+
+    assert_eq!(0, bc.merge_fragmented(0.5).unwrap().files_processed);
+    bc.put("k1", "new value").unwrap();
+    bc.delete("k2").unwrap();
+
+    let report = bc.merge_fragmented(0.5).unwrap();
+    assert_eq!(1, report.files_processed);
+    let stable_storages = bc.get_telemetry_data().database.stable_storages;
+    assert!(!stable_storages.contains_key(&fragmented_file));
+    // keys in untouched files keep their locations
+    for (k, location) in untouched_locations {
+        assert_eq!(location, location_of(k));
+    }
+    assert_eq!("new value".as_bytes(), bc.get("k1").unwrap().unwrap());
+    assert_eq!(None, bc.get("k2").unwrap());
+    for k in ["k3", "k4", "k5", "k6"] {
+        assert_eq!("value".as_bytes(), bc.get(k).unwrap().unwrap());
+    }
+}
+
+#[test]
+fn test_merge_report_stats() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    for k in ["k1", "k2", "k3", "k4", "k5", "k6"] {
+        bc.put(k, "value").unwrap();
+    }
+    for k in ["k1", "k2", "k3", "k4"] {
+        bc.put(k, "new value").unwrap();
+    }
+    bc.delete("k5").unwrap();
+    let telemetry = bc.get_telemetry_data().database;
+    // the writing file has data so it's merged too
+    let data_files = telemetry.stable_storages.len() + 1;
+    let data_size = telemetry.storage_aggregate.total_data_size as u64;
+
+    let report = bc.merge().unwrap();
+    assert_eq!(5, report.merged_keys);
+    assert_eq!(data_files, report.files_processed);
+    let merged_data_size = bc
+        .get_telemetry_data()
+        .database
+        .storage_aggregate
+        .total_data_size as u64;
+    assert_eq!(merged_data_size, report.written_bytes);
+    assert_eq!(data_size - merged_data_size, report.bytes_reclaimed);
+    assert!(report.bytes_reclaimed > 0);
+    assert!(report.duration > Duration::ZERO);
+}
+
+fn hint_files_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "hint"))
+        .map(|p| std::fs::metadata(p).unwrap().len())
+        .sum()
+}
+
+#[test]
+fn test_merge_report_write_amplification() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    for k in ["k1", "k2", "k3", "k4"] {
+        bc.put(k, "value").unwrap();
+    }
+    bc.put("k1", "new value").unwrap();
+    bc.put("k2", "new value").unwrap();
+    bc.delete("k3").unwrap();
+    let row_size = |k: &str| bc.get_location(k).unwrap().unwrap().0.row_size as u64;
+    let live_bytes = row_size("k1") + row_size("k2") + row_size("k4");
+
+    let report = bc.merge().unwrap();
+    // only rows referenced by keydir are read, and they are copied as they are
+    assert_eq!(live_bytes, report.read_bytes);
+    assert_eq!(live_bytes, report.live_bytes);
+    assert_eq!(live_bytes, report.written_bytes);
+    assert_eq!(hint_files_size(&db_path), report.hint_bytes);
+    assert!(report.hint_bytes > 0);
+    assert_eq!(
+        (live_bytes + report.hint_bytes) as f64 / live_bytes as f64,
+        report.write_amplification_ratio()
+    );
+    let totals = bc.get_telemetry_data().merge_manager.totals;
+    assert_eq!(1, totals.merges);
+    assert_eq!(report.read_bytes, totals.read_bytes);
+    assert_eq!(report.hint_bytes, totals.hint_bytes);
+
+    // partial merge reads all the rows of the merged files
+    bc.put("k5", "value").unwrap();
+    bc.put("k5", "new value").unwrap();
+    let storage_id = bc.get_location("k5").unwrap().unwrap().0.storage_id;
+    while bc.get_telemetry_data().database.writing_storage.storage_id == storage_id {
+        bc.put("k6", "value").unwrap();
+    }
+    let file_size = bc.get_telemetry_data().database.stable_storages[&storage_id].data_size as u64;
+
+    let report = bc.merge_files(&[storage_id]).unwrap();
+    assert_eq!(1, report.merged_keys);
+    assert_eq!(file_size, report.read_bytes);
+    assert_eq!(row_size("k5"), report.live_bytes);
+    let delta = bc.get_telemetry_data().merge_manager.totals.since(&totals);
+    assert_eq!(1, delta.merges);
+    assert_eq!(report.read_bytes, delta.read_bytes);
+    assert_eq!(report.written_bytes, delta.written_bytes);
+    assert_eq!(report.hint_bytes, delta.hint_bytes);
+    assert_eq!(report.live_bytes, delta.live_bytes);
+}
+
+#[test]
+fn test_merge_rate_limit() {
+    let db_path = get_temporary_directory_path();
+    let limit = 100 * 1024;
+    let bc = Bitcasky::open(
+        &db_path,
+        BitcaskyOptions::default()
+            .max_data_file_size(8 * 1024)
+            .init_data_file_capacity(1024)
+            .merge_rate_limit_bytes_per_sec(Some(limit)),
+    )
+    .unwrap();
+    for i in 0..100 {
+        bc.put(format!("k{}", i), vec![b'v'; 256]).unwrap();
+    }
+
+    let report = bc.merge().unwrap();
+    // rows are read then written, and merged data files are read back to write hint files
+    let bytes = report.read_bytes + 2 * report.written_bytes + report.hint_bytes;
+    let expected = Duration::from_secs_f64(bytes as f64 / limit as f64);
+    assert!(expected > Duration::from_millis(500));
+    assert!(report.duration >= expected.mul_f64(0.9));
+    assert!(report.duration < expected + Duration::from_secs(2));
+    assert_eq!(100, report.merged_keys);
+}
+
+#[test]
+fn test_merge_files_keeps_deleted_keys_deleted() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    bc.put("k1", "value").unwrap();
+    let older_file = bc.get_location("k1").unwrap().unwrap().0.storage_id;
+    let writing_file = || bc.get_telemetry_data().database.writing_storage.storage_id;
+    let mut keys = vec![];
+    while writing_file() == older_file {
+        let k = format!("k{}", keys.len() + 2);
+        bc.put(k.as_str(), "value").unwrap();
+        keys.push(k);
+    }
+    bc.delete("k1").unwrap();
+    let tombstone_file = writing_file();
+    while writing_file() == tombstone_file {
+        let k = format!("k{}", keys.len() + 2);
+        bc.put(k.as_str(), "value").unwrap();
+        keys.push(k);
+    }
+
+    bc.merge_files(&[tombstone_file]).unwrap();
+    assert_eq!(None, bc.get("k1").unwrap());
+    drop(bc);
+
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    assert_eq!(None, bc.get("k1").unwrap());
+    for k in keys {
+        assert_eq!("value".as_bytes(), bc.get(k).unwrap().unwrap());
+    }
+}
+
+#[test]
+fn test_snapshot_survives_merge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    bc.put("k2", "value2").unwrap();
+    bc.put("k3", "value3").unwrap();
+    bc.delete("k3").unwrap();
+
+    let snapshot = bc.snapshot().unwrap();
+    bc.put("k1", "new_value1").unwrap();
+    bc.delete("k2").unwrap();
+    bc.put("k4", "value4").unwrap();
+    bc.merge().unwrap();
+    bc.put("k1", "newer_value1").unwrap();
+    bc.merge().unwrap();
+
+    assert_eq!(2, snapshot.len());
+    assert_eq!("value1".as_bytes(), snapshot.get("k1").unwrap().unwrap());
+    assert_eq!("value2".as_bytes(), snapshot.get("k2").unwrap().unwrap());
+    assert_eq!(None, snapshot.get("k3").unwrap());
+    assert_eq!(None, snapshot.get("k4").unwrap());
+    let mut pairs = snapshot
+        .iter()
+        .map(|r| r.unwrap())
+        .collect::<Vec<(Vec<u8>, Vec<u8>)>>();
+    pairs.sort();
+    assert_eq!(
+        vec![
+            (b"k1".to_vec(), b"value1".to_vec()),
+            (b"k2".to_vec(), b"value2".to_vec())
+        ],
+        pairs
+    );
+
+    assert_eq!("newer_value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+    assert_eq!(None, bc.get("k2").unwrap());
+}
+
+#[test]
+fn test_snapshot_defers_merge_purge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    bc.put("k2", "value2").unwrap();
+    let storage_id = bc.get_location("k1").unwrap().unwrap().0.storage_id;
+    let data_file = format!("{}.data", storage_id);
+
+    let snapshot = bc.snapshot().unwrap();
+    bc.put("k1", "new_value1").unwrap();
+    bc.merge().unwrap();
+    let deferred_purges = |bc: &Bitcasky| bc.get_telemetry_data().database.deferred_purges;
+    assert_eq!(1, deferred_purges(&bc));
+    // moved out of the directory, so it's never recovered
+    assert!(!db_path.join(&data_file).exists());
+    assert!(db_path.join("Purge").join(&data_file).exists());
+    assert_eq!("value1".as_bytes(), snapshot.get("k1").unwrap().unwrap());
+
+    drop(snapshot);
+    let start = Instant::now();
+    while deferred_purges(&bc) > 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!db_path.join("Purge").join(&data_file).exists());
+    assert_eq!("new_value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+    assert_eq!("value2".as_bytes(), bc.get("k2").unwrap().unwrap());
+}
+
+fn hint_file_paths(db_path: &Path) -> Vec<PathBuf> {
+    let mut paths = std::fs::read_dir(db_path)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "hint"))
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn test_merge_removes_hint_files_without_data_file() {
+    let db_path = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+        for k in ["k1", "k2", "k3", "k4", "k5", "k6"] {
+            bc.put(k, "value").unwrap();
+        }
+    }
+    let hint_files = hint_file_paths(&db_path);
+    assert!(!hint_files.is_empty());
+    // hint files left behind by data files which were removed
+    std::fs::copy(&hint_files[0], db_path.join("1000.hint")).unwrap();
+    std::fs::copy(&hint_files[0], db_path.join("1001.hint")).unwrap();
+    let hint_files_before_merge = hint_file_paths(&db_path).len();
+
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    bc.merge().unwrap();
+
+    let hint_files_after_merge = hint_file_paths(&db_path);
+    assert!(hint_files_after_merge.len() < hint_files_before_merge);
+    assert!(!db_path.join("1000.hint").exists());
+    assert!(!db_path.join("1001.hint").exists());
+    for k in ["k1", "k2", "k3", "k4", "k5", "k6"] {
+        assert_eq!("value".as_bytes(), bc.get(k).unwrap().unwrap());
+    }
+}
+
+#[test]
+fn test_merge_compacts_hint_files_of_untouched_files() {
+    let db_path = get_temporary_directory_path();
+    let (covered_file, merged_file) = {
+        let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+        for k in ["k1", "k2", "k3", "k4", "k5", "k6"] {
+            bc.put(k, "value").unwrap();
+        }
+        let location_of = |k| bc.get_location(k).unwrap().unwrap().0.storage_id;
+        let covered_file = location_of("k1");
+        let merged_file = location_of("k4");
+        assert_ne!(covered_file, merged_file);
+        bc.put("k1", "new value").unwrap();
+        bc.delete("k2").unwrap();
+        (covered_file, merged_file)
+    };
+    let covered_hint_file = db_path.join(format!("{}.hint", covered_file));
+    let hint_file_size = || std::fs::metadata(&covered_hint_file).unwrap().len();
+    let size_before_merge = hint_file_size();
+
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    bc.merge_files(&[merged_file]).unwrap();
+    assert!(hint_file_size() < size_before_merge);
+    drop(bc);
+
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    assert!(
+        bc.get_telemetry_data()
+            .keydir
+            .recovery_stats
+            .recovered_from_hint
+    );
+    assert_eq!("new value".as_bytes(), bc.get("k1").unwrap().unwrap());
+    assert_eq!(None, bc.get("k2").unwrap());
+    for k in ["k3", "k4", "k5", "k6"] {
+        assert_eq!("value".as_bytes(), bc.get(k).unwrap().unwrap());
+    }
+}
+
+#[test]
+fn test_merge_drops_expired_rows() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    bc.put_with_ttl("k1", "value1", Duration::from_millis(1))
+        .unwrap();
+    bc.put_with_ttl("k2", "value2", Duration::from_millis(1))
+        .unwrap();
+    bc.put("k3", "value3").unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+    let data_size = bc
+        .get_telemetry_data()
+        .database
+        .storage_aggregate
+        .total_data_size;
+
+    let report = bc.merge().unwrap();
+    assert_eq!(1, report.merged_keys);
+    assert_eq!(2, report.expired_keys);
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!(None, bc.get("k2").unwrap());
+    assert_eq!("value3".as_bytes(), bc.get("k3").unwrap().unwrap());
+    assert_eq!(1, bc.count_keys().unwrap());
+    let telemetry = bc.get_telemetry_data();
+    assert_eq!(1, telemetry.keydir.number_of_keys);
+    assert!(telemetry.database.storage_aggregate.total_data_size < data_size);
+    drop(bc);
+
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!(1, bc.count_keys().unwrap());
+}
+
+#[test]
+fn test_merge_keeps_rows_expired_within_margin() {
+    let db_path = get_temporary_directory_path();
+    let opts = || BitcaskyOptions::default().merge_expire_margin(Duration::from_secs(3600));
+    let bc = Bitcasky::open(&db_path, opts()).unwrap();
+    bc.put_with_ttl("k1", "value1", Duration::from_millis(1))
+        .unwrap();
+    bc.put("k2", "value2").unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+
+    let report = bc.merge().unwrap();
+    assert_eq!(2, report.merged_keys);
+    assert_eq!(0, report.expired_keys);
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!(2, bc.get_telemetry_data().keydir.number_of_keys);
+    drop(bc);
+
+    let bc = Bitcasky::open(&db_path, opts()).unwrap();
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!("value2".as_bytes(), bc.get("k2").unwrap().unwrap());
+}
+
+#[test]
+fn test_merge_files_drops_expired_rows() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    bc.put_with_ttl("k1", "value", Duration::from_millis(1))
+        .unwrap();
+    bc.put("k2", "value").unwrap();
+    let merged_file = bc.get_location("k2").unwrap().unwrap().0.storage_id;
+    let writing_file = || bc.get_telemetry_data().database.writing_storage.storage_id;
+    let mut keys = vec![];
+    while writing_file() == merged_file {
+        let k = format!("k{}", keys.len() + 3);
+        bc.put(k.as_str(), "value").unwrap();
+        keys.push(k);
+    }
+    std::thread::sleep(Duration::from_millis(5));
+
+    let report = bc.merge_files(&[merged_file]).unwrap();
+    assert_eq!(1, report.expired_keys);
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert!(bc.get_location("k1").unwrap().is_none());
+    drop(bc);
+
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!("value".as_bytes(), bc.get("k2").unwrap().unwrap());
+    for k in keys {
+        assert_eq!("value".as_bytes(), bc.get(k).unwrap().unwrap());
+    }
+}
+
+#[test]
+fn test_merge_estimate() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, get_partial_merge_options()).unwrap();
+    for i in 0..20 {
+        bc.put(format!("k{}", i), "value").unwrap();
+    }
+    for i in 0..10 {
+        bc.delete(format!("k{}", i)).unwrap();
+    }
+    let live_bytes = (10..20)
+        .map(|i| {
+            bc.get_location(format!("k{}", i))
+                .unwrap()
+                .unwrap()
+                .0
+                .row_size as u64
+        })
+        .sum::<u64>();
+
+    let options = get_partial_merge_options();
+    let max_file_size = options.database.storage.max_data_file_size as u64;
+    let estimate = bc.merge_estimate();
+    assert_eq!(live_bytes, estimate.live_bytes_to_rewrite);
+    assert_eq!(
+        live_bytes.div_ceil(max_file_size),
+        estimate.est_output_files
+    );
+    assert_eq!(
+        estimate.est_output_files
+            * (max_file_size + options.database.init_hint_file_capacity as u64),
+        estimate.required_free_bytes
+    );
+
+    bc.merge().unwrap();
+    assert_eq!(live_bytes, bc.merge_estimate().live_bytes_to_rewrite);
+}
+
+#[test]
+fn test_foreach_key_during_merge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(
+        &db_path,
+        BitcaskyOptions::default()
+            .max_data_file_size(1024)
+            .init_data_file_capacity(100),
+    )
+    .unwrap();
+    let mut keys = (0..200)
+        .map(|i| format!("k{}", i).into_bytes())
+        .collect::<Vec<Vec<u8>>>();
+    keys.sort();
+    for k in keys.iter() {
+        bc.put(k.clone(), "value").unwrap();
+    }
+
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let merger = s.spawn(|| {
+            let mut round = 0;
+            while !stop.load(std::sync::atomic::Ordering::Acquire) {
+                // overwrite keys so every merge moves them
+                for k in keys.iter().skip(round % 2).step_by(2) {
+                    bc.put(k.clone(), format!("value{}", round)).unwrap();
+                }
+                bc.merge().unwrap();
+                round += 1;
+            }
+        });
+
+        for _ in 0..50 {
+            let mut visited = vec![];
+            bc.foreach_key(|k| visited.push(k.clone())).unwrap();
+            visited.sort();
+            assert_eq!(keys, visited);
+        }
+        stop.store(true, std::sync::atomic::Ordering::Release);
+        merger.join().unwrap();
+    });
+}
+
+#[test]
+fn test_merge_keeps_puts_during_merge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(
+        &db_path,
+        BitcaskyOptions::default()
+            .max_data_file_size(1024)
+            .init_data_file_capacity(100),
+    )
+    .unwrap();
+    for i in 0..100 {
+        bc.put(format!("k{}", i), "value").unwrap();
+    }
+
+    let rounds = 20;
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let merger = s.spawn(|| {
+            while !stop.load(std::sync::atomic::Ordering::Acquire) {
+                bc.merge().unwrap();
+            }
+        });
+        for round in 0..rounds {
+            for i in 0..100 {
+                bc.put(format!("k{}", i), format!("value{}", round))
+                    .unwrap();
+            }
+        }
+        stop.store(true, std::sync::atomic::Ordering::Release);
+        merger.join().unwrap();
+    });
+
+    // merged rows never overwrite puts arriving during merge
+    let expected = format!("value{}", rounds - 1).into_bytes();
+    for i in 0..100 {
+        assert_eq!(Some(expected.clone()), bc.get(format!("k{}", i)).unwrap());
+    }
+    bc.merge().unwrap();
+    for i in 0..100 {
+        assert_eq!(Some(expected.clone()), bc.get(format!("k{}", i)).unwrap());
+    }
+}
+
+#[test]
+fn test_shrink_keydir_after_merge() {
+    let db_path = get_temporary_directory_path();
+    let bc = Bitcasky::open(&db_path, BitcaskyOptions::default()).unwrap();
+    let capacity = || bc.get_telemetry_data().keydir.capacity;
+    for round in 0..2 {
+        for i in 0..20000 {
+            bc.put(format!("k{}", i), "value").unwrap();
+        }
+        assert!(capacity() >= 20000);
+        for i in 100..20000 {
+            bc.delete(format!("k{}", i)).unwrap();
+        }
+
+        if round == 0 {
+            bc.merge().unwrap();
+        } else {
+            bc.shrink_to_fit();
+        }
+        assert!(capacity() < 1000);
+        assert_eq!(100, bc.count_keys().unwrap());
+        assert_eq!("value".as_bytes(), bc.get("k99").unwrap().unwrap());
+    }
+}
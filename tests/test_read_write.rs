@@ -1,31 +1,49 @@
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::HashSet,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use bitcasky::internals::{
-    get_temporary_directory_path, RandomTestingDataGenerator, TestingOperations, TestingOperator,
+    get_temporary_directory_path, BitcaskyFormatter, Formatter, RandomTestingDataGenerator,
+    TestingOperations, TestingOperator,
+};
+use bitcasky::options::{BitcaskyOptions, ChecksumAlgorithm, CorruptionPolicy, SyncStrategy};
+use bitcasky::{
+    bitcasky::{Bitcasky, MemoryKeyDirBackend, RecoveryProgress},
+    error::BitcaskyError,
 };
-use bitcasky::options::{BitcaskyOptions, SyncStrategy};
-use bitcasky::{bitcasky::Bitcasky, error::BitcaskyError};
 use test_log::test;
 
 fn execute_testing_operations(bc: &Bitcasky, ops: &TestingOperations) {
     for op in ops.operations() {
         match op.operator() {
             TestingOperator::PUT => bc.put(op.key(), op.value()).unwrap(),
-            TestingOperator::DELETE => bc.delete(&op.key()).unwrap(),
-            TestingOperator::MERGE => bc.merge().unwrap(),
+            TestingOperator::DELETE => {
+                bc.delete(op.key()).unwrap();
+            }
+            TestingOperator::MERGE => {
+                bc.merge().unwrap();
+            }
             TestingOperator::NONE => {}
         }
     }
 }
 
 fn get_default_options() -> BitcaskyOptions {
-    BitcaskyOptions::default()
+    let options = BitcaskyOptions::default()
         .max_data_file_size(10 * 1024)
         .init_data_file_capacity(100)
         .init_hint_file_capacity(1024)
-        .sync_strategy(SyncStrategy::Interval(Duration::from_secs(1)))
         .max_key_size(64)
-        .max_value_size(1024)
+        .max_value_size(1024);
+    // without the sync-worker feature writes are only synced on demand
+    #[cfg(feature = "sync-worker")]
+    let options = options.sync_strategy(SyncStrategy::Interval(Duration::from_secs(1)));
+    options
 }
 
 #[test]
@@ -40,6 +58,72 @@ fn test_open_db_twice() {
     ));
 }
 
+const LOCK_HOLDER_DIR_ENV: &str = "BITCASKY_TEST_LOCK_HOLDER_DIR";
+const LOCK_HOLDER_MILLIS_ENV: &str = "BITCASKY_TEST_LOCK_HOLDER_MILLIS";
+
+/// Only does something in the child process spawned by `spawn_lock_holder`,
+/// where it holds the directory lock for a while then exits
+#[test]
+fn lock_holder_process() {
+    let Ok(dir) = std::env::var(LOCK_HOLDER_DIR_ENV) else {
+        return;
+    };
+    let millis = std::env::var(LOCK_HOLDER_MILLIS_ENV)
+        .unwrap()
+        .parse::<u64>()
+        .unwrap();
+    let _bc = Bitcasky::open(Path::new(&dir), get_default_options()).unwrap();
+    std::thread::sleep(Duration::from_millis(millis));
+}
+
+fn spawn_lock_holder(dir: &Path, hold: Duration) -> Child {
+    let child = Command::new(std::env::current_exe().unwrap())
+        .args(["lock_holder_process", "--exact", "--nocapture"])
+        .env(LOCK_HOLDER_DIR_ENV, dir)
+        .env(LOCK_HOLDER_MILLIS_ENV, hold.as_millis().to_string())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    // the holder records its pid in the lock file after the directory is locked
+    let start = Instant::now();
+    while !read_lock_file(dir).starts_with(&format!("{} ", child.id())) {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    child
+}
+
+fn read_lock_file(dir: &Path) -> String {
+    std::fs::read_to_string(dir.join("bitcask.lock")).unwrap_or_default()
+}
+
+#[test]
+fn test_open_db_after_lock_holder_killed() {
+    let dir = get_temporary_directory_path();
+    let mut child = spawn_lock_holder(&dir, Duration::from_secs(60));
+    assert!(matches!(
+        Bitcasky::open(&dir, get_default_options()).err(),
+        Some(BitcaskyError::LockDirectoryFailed(_))
+    ));
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let _bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert!(read_lock_file(&dir).starts_with(&format!("{} ", std::process::id())));
+}
+
+#[test]
+fn test_wait_for_directory_lock() {
+    let dir = get_temporary_directory_path();
+    let mut child = spawn_lock_holder(&dir, Duration::from_millis(300));
+    let _bc = Bitcasky::open(
+        &dir,
+        get_default_options().lock_wait_timeout(Duration::from_secs(10)),
+    )
+    .unwrap();
+    assert!(child.wait().unwrap().success());
+}
+
 #[test]
 fn test_read_write_writing_file() {
     let dir = get_temporary_directory_path();
@@ -111,6 +195,149 @@ fn test_recovery() {
     }
 }
 
+#[test]
+fn test_parallel_recovery_matches_sequential() {
+    let mut gen = RandomTestingDataGenerator::new(
+        64,
+        512,
+        vec![TestingOperator::PUT, TestingOperator::DELETE],
+    );
+    let ops = gen.generate_testing_operations(5000);
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        execute_testing_operations(&bc, &ops);
+    }
+    // recover from data files only, where keys are written many times in a file
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|e| e == "hint") {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    let recover = |parallelism: usize| {
+        let bc = Bitcasky::open(
+            &dir,
+            get_default_options().recovery_parallelism(parallelism),
+        )
+        .unwrap();
+        let mut locations = bc.key_locations().unwrap().collect::<Vec<_>>();
+        locations.sort_by(|a, b| a.0.cmp(&b.0));
+        locations
+    };
+    let sequential = recover(1);
+    assert_eq!(ops.squash().len(), sequential.len());
+    assert_eq!(sequential, recover(4));
+}
+
+#[test]
+fn test_recovery_progress() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        for i in 0..100 {
+            bc.put(format!("k{}", i), "v".repeat(512)).unwrap();
+        }
+    }
+
+    for parallelism in [1, 4] {
+        let progresses = Arc::new(Mutex::new(Vec::<RecoveryProgress>::new()));
+        let reported = progresses.clone();
+        let bc = Bitcasky::open(
+            &dir,
+            get_default_options()
+                .recovery_parallelism(parallelism)
+                .on_recovery_progress(Arc::new(move |p| reported.lock().unwrap().push(p))),
+        )
+        .unwrap();
+        let progresses = progresses.lock().unwrap();
+        let total_files = bc.get_telemetry_data().database.stable_storages.len() + 1;
+        assert!(total_files > 1);
+        assert_eq!(total_files, progresses.len());
+        for (i, p) in progresses.iter().enumerate() {
+            assert_eq!(i + 1, p.recovered_files);
+            assert_eq!(total_files, p.total_files);
+        }
+        assert!(progresses
+            .windows(2)
+            .all(|w| w[0].processed_bytes <= w[1].processed_bytes));
+        let last = progresses.last().unwrap();
+        assert_eq!(last.total_bytes, last.processed_bytes);
+        assert_eq!(100, last.recovered_rows);
+    }
+}
+
+#[test]
+fn test_max_open_files() {
+    let dir = get_temporary_directory_path();
+    let options = || {
+        get_default_options()
+            .max_data_file_size(1024)
+            .max_open_files(8)
+    };
+    {
+        let bc = Bitcasky::open(&dir, options()).unwrap();
+        for i in 0..200 {
+            bc.put(format!("k{}", i), "v".repeat(100)).unwrap();
+        }
+        assert!(bc.get_telemetry_data().database.stable_storages.len() > 8);
+    }
+
+    let bc = Bitcasky::open(&dir, options()).unwrap();
+    let telemetry = bc.get_telemetry_data().database;
+    assert!(telemetry.open_stable_files <= 9);
+    for _ in 0..3 {
+        for i in 0..200 {
+            assert_eq!(
+                "v".repeat(100).as_bytes(),
+                bc.get(format!("k{}", i)).unwrap().unwrap()
+            );
+        }
+    }
+    let telemetry = bc.get_telemetry_data().database;
+    // the file just reopened by the last read is counted until next read
+    assert!(telemetry.open_stable_files <= 9);
+    assert!(telemetry.storage_aggregate.total_reopen_times > 0);
+
+    for i in 0..100 {
+        bc.delete(format!("k{}", i)).unwrap();
+    }
+    bc.merge().unwrap();
+    assert!(bc.get_telemetry_data().database.open_stable_files <= 9);
+    for i in 0..200 {
+        assert_eq!(i >= 100, bc.has(format!("k{}", i)).unwrap());
+        if i >= 100 {
+            assert_eq!(
+                "v".repeat(100).as_bytes(),
+                bc.get(format!("k{}", i)).unwrap().unwrap()
+            );
+        }
+    }
+    let mut pairs = 0;
+    bc.foreach(|_, _| pairs += 1).unwrap();
+    assert_eq!(100, pairs);
+}
+
+#[test]
+fn test_put_sync() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(
+            &dir,
+            get_default_options().sync_strategy(SyncStrategy::None),
+        )
+        .unwrap();
+        bc.put_sync("k1", "value1").unwrap();
+        bc.put_sync("k2", "value2").unwrap();
+        bc.put_sync("k1", "value3").unwrap();
+        assert_eq!(bc.get("k1").unwrap().unwrap(), "value3".as_bytes());
+    }
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!(bc.get("k1").unwrap().unwrap(), "value3".as_bytes());
+    assert_eq!(bc.get("k2").unwrap().unwrap(), "value2".as_bytes());
+}
+
 #[test]
 fn test_delete() {
     let dir = get_temporary_directory_path();
@@ -126,165 +353,1671 @@ fn test_delete() {
     bc.delete("k2").unwrap();
     assert_eq!(bc.get("k2").unwrap(), None);
 
-    bc.delete("k3").unwrap();
+    assert!(bc.delete("k3").unwrap());
     assert_eq!(bc.get("k3").unwrap(), None);
+
+    // nothing is written for absent keys
+    let write_times = || {
+        bc.get_telemetry_data()
+            .database
+            .storage_aggregate
+            .total_write_times
+    };
+    let writes = write_times();
+    assert!(!bc.delete("k3").unwrap());
+    assert!(!bc.delete("absent").unwrap());
+    assert_eq!(writes, write_times());
 }
 
 #[test]
-fn test_delete_not_exists_key() {
+fn test_put_multi() {
     let dir = get_temporary_directory_path();
-    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
-
-    bc.delete("k1").unwrap();
-    assert_eq!(bc.get("k1").unwrap(), None);
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.put("k1", "old").unwrap();
+        let results = bc
+            .put_multi([
+                ("k1".to_string(), "value1".to_string()),
+                ("k".repeat(65), "value".to_string()),
+                ("k2".to_string(), "value2".to_string()),
+                ("k3".to_string(), "v".repeat(1025)),
+            ])
+            .unwrap();
+        assert_eq!(4, results.len());
+        assert_eq!(
+            bc.get_location("k1").unwrap().unwrap().0,
+            *results[0].as_ref().unwrap()
+        );
+        assert!(matches!(
+            results[1],
+            Err(BitcaskyError::InvalidParameter(_, _))
+        ));
+        assert!(results[2].is_ok());
+        assert!(matches!(
+            results[3],
+            Err(BitcaskyError::InvalidParameter(_, _))
+        ));
+        assert!(bc.put_multi(Vec::<(&str, &str)>::new()).unwrap().is_empty());
 
-    bc.delete("k2").unwrap();
-    assert_eq!(bc.get("k2").unwrap(), None);
+        // spans data files
+        let results = bc
+            .put_multi((0..100).map(|i| (format!("key{}", i), "v".repeat(512))))
+            .unwrap();
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert!(results[0].as_ref().unwrap().storage_id < results[99].as_ref().unwrap().storage_id);
+    }
 
-    bc.delete("k3").unwrap();
-    assert_eq!(bc.get("k3").unwrap(), None);
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!(b"value1".to_vec(), bc.get("k1").unwrap().unwrap());
+    assert_eq!(b"value2".to_vec(), bc.get("k2").unwrap().unwrap());
+    assert!(!bc.has("k3").unwrap());
+    assert_eq!(102, bc.count_keys().unwrap());
 }
 
 #[test]
-fn test_foreach_keys() {
-    let mut gen = RandomTestingDataGenerator::new(64, 512, vec![TestingOperator::PUT]);
-    let ops = gen.generate_testing_operations(100);
+fn test_delete_many() {
     let dir = get_temporary_directory_path();
-    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
-    execute_testing_operations(&bc, &ops);
-
-    let mut expected_set: HashSet<Vec<u8>> = HashSet::new();
-    let mut actual_set: HashSet<Vec<u8>> = HashSet::new();
-    for op in ops.squash() {
-        expected_set.insert(op.key());
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        for i in 0..5 {
+            bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+        }
+        let writes = bc
+            .get_telemetry_data()
+            .database
+            .storage_aggregate
+            .total_write_times;
+        assert_eq!(
+            vec![true, false, true, false],
+            bc.delete_many(["k1", "absent", "k3", "k1"]).unwrap()
+        );
+        assert_eq!(
+            writes + 2,
+            bc.get_telemetry_data()
+                .database
+                .storage_aggregate
+                .total_write_times
+        );
+        assert!(bc.delete_many(Vec::<&str>::new()).unwrap().is_empty());
     }
 
-    bc.foreach_key(|k| {
-        actual_set.insert(k.clone());
-    })
-    .unwrap();
-    assert_eq!(expected_set, actual_set);
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    for i in 0..5 {
+        assert_eq!(i % 2 == 0, bc.has(format!("k{}", i)).unwrap());
+    }
 }
 
 #[test]
-fn test_fold_keys() {
-    let mut gen = RandomTestingDataGenerator::new(64, 512, vec![TestingOperator::PUT]);
-    let ops = gen.generate_testing_operations(100);
+fn test_delete_prefix() {
     let dir = get_temporary_directory_path();
-    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
-    execute_testing_operations(&bc, &ops);
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        // more keys than one chunk
+        for i in 0..3000 {
+            bc.put(format!("user:{}", i), "v").unwrap();
+        }
+        for i in 0..10 {
+            bc.put(format!("order:{}", i), "v").unwrap();
+        }
+        bc.put("user", "v").unwrap();
 
-    let mut expected_set: HashSet<Vec<u8>> = HashSet::new();
-    let mut actual_set: HashSet<Vec<u8>> = HashSet::new();
-    for op in ops.squash() {
-        expected_set.insert(op.key());
+        assert!(matches!(
+            bc.delete_prefix(b""),
+            Err(BitcaskyError::InvalidParameter(name, _)) if name == "prefix"
+        ));
+        assert_eq!(3000, bc.delete_prefix(b"user:").unwrap());
+        assert_eq!(0, bc.delete_prefix(b"user:").unwrap());
+        assert_eq!(11, bc.count_keys().unwrap());
     }
 
-    let ret = bc
-        .fold_key(
-            |k, acc| {
-                actual_set.insert(k.clone());
-                Ok(Some(acc.unwrap() + 1))
-            },
-            Some(0),
-        )
-        .unwrap();
-    assert_eq!(expected_set.len(), ret.unwrap());
-    assert_eq!(expected_set, actual_set);
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert!(!bc.has("user:0").unwrap());
+    assert!(bc.has("user").unwrap());
+    assert!(bc.has("order:0").unwrap());
+
+    assert_eq!(11, bc.delete_all().unwrap());
+    assert!(bc.is_empty().unwrap());
 }
 
 #[test]
-fn test_foreach() {
-    let mut gen = RandomTestingDataGenerator::new(64, 512, vec![TestingOperator::PUT]);
-    let ops = gen.generate_testing_operations(100);
+fn test_retain() {
     let dir = get_temporary_directory_path();
-    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
-    execute_testing_operations(&bc, &ops);
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        for i in 0..10 {
+            bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+        }
+        bc.delete("k0").unwrap();
 
-    let expected_pair = ops
-        .squash()
-        .iter()
-        .map(|op| (op.key(), op.value()))
-        .collect::<Vec<(Vec<u8>, Vec<u8>)>>();
+        let mut visited = HashSet::new();
+        let deleted = bc
+            .retain(|k, v| {
+                // rows written by retain are not visited
+                assert!(visited.insert(k.to_vec()));
+                assert_eq!(&k[1..], &v[5..]);
+                k != b"k1" && k != b"k2" && v != b"value3"
+            })
+            .unwrap();
+        assert_eq!(3, deleted);
+        assert_eq!(9, visited.len());
+        assert_eq!(6, bc.count_keys().unwrap());
+        for i in 1..4 {
+            assert!(bc.get(format!("k{}", i)).unwrap().is_none());
+        }
 
-    let mut actual_pair: Vec<(Vec<u8>, Vec<u8>)> = vec![];
-    bc.foreach(|k, v| {
-        actual_pair.push((k.clone(), v.clone()));
-    })
-    .unwrap();
+        assert_eq!(0, bc.retain(|_, _| true).unwrap());
+    }
 
-    assert_eq!(expected_pair, actual_pair);
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!(6, bc.count_keys().unwrap());
+    for i in 4..10 {
+        assert_eq!(
+            format!("value{}", i).as_bytes(),
+            bc.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
+    assert_eq!(6, bc.retain(|_, _| false).unwrap());
+    assert!(bc.is_empty().unwrap());
 }
 
 #[test]
-fn test_fold() {
-    let mut gen = RandomTestingDataGenerator::new(64, 512, vec![TestingOperator::PUT]);
-    let ops = gen.generate_testing_operations(100);
+fn test_mixed_key_types() {
     let dir = get_temporary_directory_path();
     let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
-    execute_testing_operations(&bc, &ops);
+    bc.put("k1", "value1").unwrap();
+    bc.put(String::from("k2"), "value2").unwrap();
+    bc.put(&b"k3"[..], "value3").unwrap();
+    bc.put(b"k4".to_vec(), "value4").unwrap();
 
-    let expected_pair = ops
-        .squash()
-        .iter()
-        .map(|op| (op.key(), op.value()))
-        .collect::<Vec<(Vec<u8>, Vec<u8>)>>();
+    assert_eq!(bc.get(b"k1").unwrap(), Some(b"value1".to_vec()));
+    assert_eq!(
+        bc.get(String::from("k2")).unwrap(),
+        Some(b"value2".to_vec())
+    );
+    let k4 = b"k4".to_vec();
+    assert!(bc.has(&b"k3"[..]).unwrap());
+    assert!(bc.has(&k4).unwrap());
 
-    let mut actual_pair: Vec<(Vec<u8>, Vec<u8>)> = vec![];
-    let ret = bc
-        .fold(
-            |k, v, acc| {
-                actual_pair.push((k.clone(), v.clone()));
-                Ok(Some(acc.unwrap() + 1))
-            },
-            Some(0),
-        )
-        .unwrap();
-    assert_eq!(expected_pair.len(), ret.unwrap());
-    assert_eq!(expected_pair, actual_pair);
+    bc.delete(String::from("k1")).unwrap();
+    bc.delete(k4).unwrap();
+    assert!(!bc.has("k1").unwrap());
+    assert!(!bc.has("k4").unwrap());
 }
 
 #[test]
-fn test_dead_bytes_by_delete() {
+fn test_count_keys() {
     let dir = get_temporary_directory_path();
     let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert!(bc.is_empty().unwrap());
+    assert_eq!(0, bc.count_keys().unwrap());
+
     bc.put("k1", "value1").unwrap();
     bc.put("k2", "value2").unwrap();
+    bc.put("k2", "value3").unwrap();
+    assert!(!bc.is_empty().unwrap());
+    assert_eq!(2, bc.count_keys().unwrap());
+    assert!(bc.contains_key(&b"k1"[..]).unwrap());
 
     bc.delete("k1").unwrap();
-
-    assert!(
-        bc.get_telemetry_data()
-            .database
-            .storage_aggregate
-            .total_fragment
-            < 1.0
+    assert!(!bc.contains_key(&b"k1"[..]).unwrap());
+    assert_eq!(1, bc.count_keys().unwrap());
+    assert_eq!(
+        bc.get_telemetry_data().keydir.number_of_keys,
+        bc.count_keys().unwrap()
     );
+}
+
+#[test]
+fn test_delete_not_exists_key() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+
+    bc.delete("k1").unwrap();
+    assert_eq!(bc.get("k1").unwrap(), None);
 
     bc.delete("k2").unwrap();
-    assert_eq!(
-        1.0,
-        bc.get_telemetry_data()
-            .database
-            .storage_aggregate
-            .total_fragment
-    );
+    assert_eq!(bc.get("k2").unwrap(), None);
+
+    bc.delete("k3").unwrap();
+    assert_eq!(bc.get("k3").unwrap(), None);
 }
 
 #[test]
-fn test_dead_bytes_by_put() {
+fn test_put_and_delete_return_previous_value() {
     let dir = get_temporary_directory_path();
     let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
-    bc.put("k1", "value1").unwrap();
-    bc.put("k2", "value2").unwrap();
+    assert_eq!(None, bc.put_previous("k1", "value1").unwrap());
+    assert_eq!(
+        Some("value1".as_bytes().to_vec()),
+        bc.put_previous("k1", "value2").unwrap()
+    );
+    assert_eq!("value2".as_bytes(), bc.get("k1").unwrap().unwrap());
 
-    bc.put("k1", "value3").unwrap();
-    bc.put("k2", "value4").unwrap();
     assert_eq!(
-        0.5,
-        bc.get_telemetry_data()
-            .database
-            .storage_aggregate
-            .total_fragment
+        Some("value2".as_bytes().to_vec()),
+        bc.delete_previous("k1").unwrap()
     );
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!(None, bc.delete_previous("k1").unwrap());
+    assert_eq!(None, bc.put_previous("k1", "value3").unwrap());
+}
+
+#[test]
+fn test_put_with_timestamp() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        assert!(bc.put_with_timestamp("k1", "value1", 200).unwrap());
+        // older write arriving late is skipped
+        assert!(!bc.put_with_timestamp("k1", "value0", 100).unwrap());
+        assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+        // on equal timestamps the later write wins
+        assert!(bc.put_with_timestamp("k1", "value2", 200).unwrap());
+        assert_eq!("value2".as_bytes(), bc.get("k1").unwrap().unwrap());
+
+        // local writes are stamped with current time
+        bc.put("k2", "value1").unwrap();
+        assert!(!bc.put_with_timestamp("k2", "value0", 100).unwrap());
+        assert!(bc.put_with_timestamp("k2", "value2", u64::MAX).unwrap());
+
+        // deleted key keeps no timestamp
+        bc.delete("k1").unwrap();
+        assert!(bc.put_with_timestamp("k1", "value3", 100).unwrap());
+        assert!(bc.put_with_timestamp("k3", "value1", 300).unwrap());
+    }
+    let check = |bc: &Bitcasky| {
+        assert_eq!("value3".as_bytes(), bc.get("k1").unwrap().unwrap());
+        assert_eq!("value2".as_bytes(), bc.get("k2").unwrap().unwrap());
+        assert!(!bc.put_with_timestamp("k3", "value0", 299).unwrap());
+        assert_eq!("value1".as_bytes(), bc.get("k3").unwrap().unwrap());
+    };
+    // timestamps are kept in data files, and rewritten along with rows by merge
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    check(&bc);
+    bc.merge().unwrap();
+    check(&bc);
+    drop(bc);
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    check(&bc);
+}
+
+#[test]
+fn test_put_raw_timestamp() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    bc.put_raw_timestamp("k1", "value1", 200).unwrap();
+    bc.put_raw_timestamp("k1", "value0", 100).unwrap();
+    let entry = bc.get_with_metadata("k1").unwrap().unwrap();
+    assert_eq!(b"value1".to_vec(), entry.value);
+    assert_eq!(200, entry.write_timestamp);
+
+    assert!(matches!(
+        bc.put_raw_timestamp(vec![0; 65], "value", 300),
+        Err(BitcaskyError::InvalidParameter(_, _))
+    ));
+}
+
+#[test]
+fn test_get_with_metadata() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert!(bc.put_with_timestamp("k1", "value1", 200).unwrap());
+    let entry = bc.get_with_metadata("k1").unwrap().unwrap();
+    assert_eq!(b"value1".to_vec(), entry.value);
+    assert_eq!(200, entry.write_timestamp);
+    assert_eq!(0, entry.expire_timestamp);
+    let (location, _) = bc.get_location("k1").unwrap().unwrap();
+    assert_eq!(location, entry.location);
+
+    // overwrite
+    bc.put("k1", "value2").unwrap();
+    let overwritten = bc.get_with_metadata("k1").unwrap().unwrap();
+    assert_eq!(b"value2".to_vec(), overwritten.value);
+    assert!(overwritten.write_timestamp > 200);
+    assert!(overwritten.location.row_offset > entry.location.row_offset);
+
+    // merge relocates the row and keeps its timestamp
+    bc.put_with_ttl("k2", "value", Duration::from_secs(60))
+        .unwrap();
+    let expirable = bc.get_with_metadata("k2").unwrap().unwrap();
+    assert!(expirable.expire_timestamp > expirable.write_timestamp);
+    bc.merge().unwrap();
+    for (key, before) in [("k1", overwritten), ("k2", expirable)] {
+        let after = bc.get_with_metadata(key).unwrap().unwrap();
+        assert_ne!(before.location, after.location);
+        assert_eq!(before.value, after.value);
+        assert_eq!(before.write_timestamp, after.write_timestamp);
+        assert_eq!(before.expire_timestamp, after.expire_timestamp);
+    }
+
+    bc.delete("k1").unwrap();
+    assert!(bc.get_with_metadata("k1").unwrap().is_none());
+    assert!(bc.get_with_metadata("absent").unwrap().is_none());
+}
+
+#[test]
+fn test_export_import_csv() {
+    let bc = Bitcasky::open(&get_temporary_directory_path(), get_default_options()).unwrap();
+    for i in 0..1000u32 {
+        let key = format!("k{}", i).into_bytes();
+        // binary values with commas and line breaks
+        let value = [i.to_be_bytes().as_slice(), b",\n\r\xff"].concat();
+        assert!(bc.put_with_timestamp(key, value, 1000 + i as u64).unwrap());
+    }
+    bc.delete("k0").unwrap();
+
+    let mut csv = vec![];
+    assert_eq!(999, bc.export_to_csv(&mut csv).unwrap());
+
+    let imported = Bitcasky::open(&get_temporary_directory_path(), get_default_options()).unwrap();
+    assert_eq!(999, imported.import_from_csv(csv.as_slice()).unwrap());
+    assert_eq!(999, imported.count_keys().unwrap());
+    assert!(!imported.has("k0").unwrap());
+    for i in 1..1000u32 {
+        let key = format!("k{}", i);
+        let expect = bc.get_with_metadata(&key).unwrap().unwrap();
+        let actual = imported.get_with_metadata(&key).unwrap().unwrap();
+        assert_eq!(expect.value, actual.value);
+        assert_eq!(1000 + i as u64, actual.write_timestamp);
+    }
+
+    // rows older than the stored values are skipped
+    imported.put_with_timestamp("k1", "newer", 5000).unwrap();
+    assert_eq!(998, imported.import_from_csv(csv.as_slice()).unwrap());
+    assert_eq!(b"newer".to_vec(), imported.get("k1").unwrap().unwrap());
+}
+
+#[test]
+fn test_import_invalid_csv() {
+    let bc = Bitcasky::open(&get_temporary_directory_path(), get_default_options()).unwrap();
+    let csv = b"key_hex,value_hex,timestamp\n6b31,7631,100\n6b32,7,100\n6b33,7633,1\n";
+    assert!(matches!(
+        bc.import_from_csv(csv.as_slice()),
+        Err(BitcaskyError::InvalidCsvData(3, _))
+    ));
+    assert_eq!(b"v1".to_vec(), bc.get("k1").unwrap().unwrap());
+    assert!(!bc.has("k3").unwrap());
+
+    let csv = b"6b34,7634,100\n6b35,\xff,100\n";
+    assert!(matches!(
+        bc.import_from_csv(csv.as_slice()),
+        Err(BitcaskyError::InvalidCsvData(2, reason)) if reason.contains("UTF-8")
+    ));
+    assert!(bc.has("k4").unwrap());
+}
+
+#[test]
+fn test_export_import_archive() {
+    let bc = Bitcasky::open(&get_temporary_directory_path(), get_default_options()).unwrap();
+    for i in 0..1000u32 {
+        let key = format!("k{}", i).into_bytes();
+        let value = [i.to_be_bytes().as_slice(), b"\0\xff"].concat();
+        assert!(bc.put_with_timestamp(key, value, 1000 + i as u64).unwrap());
+    }
+    bc.delete("k0").unwrap();
+    bc.put_with_ttl("ttl", "value", Duration::from_secs(3600))
+        .unwrap();
+
+    let mut archive = vec![];
+    assert_eq!(1000, bc.export(&mut archive).unwrap());
+
+    let imported = Bitcasky::import(
+        &get_temporary_directory_path(),
+        archive.as_slice(),
+        get_default_options(),
+    )
+    .unwrap();
+    assert_eq!(1000, imported.count_keys().unwrap());
+    assert!(!imported.has("k0").unwrap());
+    for key in (1..1000u32)
+        .map(|i| format!("k{}", i))
+        .chain(["ttl".into()])
+    {
+        let expect = bc.get_with_metadata(&key).unwrap().unwrap();
+        let actual = imported.get_with_metadata(&key).unwrap().unwrap();
+        assert_eq!(expect.value, actual.value);
+        assert_eq!(expect.write_timestamp, actual.write_timestamp);
+        assert_eq!(expect.expire_timestamp, actual.expire_timestamp);
+    }
+    assert_ne!(
+        0,
+        imported
+            .get_with_metadata("ttl")
+            .unwrap()
+            .unwrap()
+            .expire_timestamp
+    );
+}
+
+#[test]
+fn test_import_invalid_archive() {
+    let bc = Bitcasky::open(&get_temporary_directory_path(), get_default_options()).unwrap();
+    for i in 0..10 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+    let mut archive = vec![];
+    assert_eq!(10, bc.export(&mut archive).unwrap());
+
+    // checksum mismatch is found at the end of archive, entries imported before are dropped
+    let dir = get_temporary_directory_path();
+    let mut corrupted = archive.clone();
+    let len = corrupted.len();
+    corrupted[len - 20] ^= 1;
+    assert!(matches!(
+        Bitcasky::import(&dir, corrupted.as_slice(), get_default_options()),
+        Err(BitcaskyError::InvalidArchive(reason)) if reason.contains("checksum")
+    ));
+    let reopened = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!(0, reopened.count_keys().unwrap());
+    drop(reopened);
+
+    let mut unknown_version = archive.clone();
+    unknown_version[8] = 2;
+    assert!(matches!(
+        Bitcasky::import(&dir, unknown_version.as_slice(), get_default_options()),
+        Err(BitcaskyError::InvalidArchive(reason)) if reason.contains("version")
+    ));
+
+    // only imports to empty database
+    drop(bc);
+    let dir = get_temporary_directory_path();
+    Bitcasky::open(&dir, get_default_options())
+        .unwrap()
+        .put("k", "v")
+        .unwrap();
+    assert!(matches!(
+        Bitcasky::import(&dir, archive.as_slice(), get_default_options()),
+        Err(BitcaskyError::InvalidParameter(name, _)) if name == "directory"
+    ));
+}
+
+#[test]
+fn test_toggle_read_only() {
+    let bc = Bitcasky::open(&get_temporary_directory_path(), get_default_options()).unwrap();
+    for i in 0..100 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let writers = (0..4)
+            .map(|t| {
+                let (bc, stop) = (&bc, &stop);
+                s.spawn(move || {
+                    let (mut written, mut rejected) = (0, 0);
+                    while !stop.load(std::sync::atomic::Ordering::Acquire) {
+                        match bc.put(format!("t{}", t), format!("value{}", written)) {
+                            Ok(_) => written += 1,
+                            Err(BitcaskyError::ReadOnlyMode()) => rejected += 1,
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                    (written, rejected)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        std::thread::sleep(Duration::from_millis(20));
+        bc.set_read_only(true);
+        assert!(bc.is_read_only());
+        std::thread::sleep(Duration::from_millis(20));
+        for t in 0..4 {
+            let value = bc.get(format!("t{}", t)).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+            assert_eq!(value, bc.get(format!("t{}", t)).unwrap());
+        }
+        assert!(matches!(
+            bc.delete("k1"),
+            Err(BitcaskyError::ReadOnlyMode())
+        ));
+        assert!(matches!(
+            bc.delete_many(["k1", "k2"]),
+            Err(BitcaskyError::ReadOnlyMode())
+        ));
+        assert!(matches!(
+            bc.retain(|_, _| false),
+            Err(BitcaskyError::ReadOnlyMode())
+        ));
+        assert!(matches!(bc.merge(), Err(BitcaskyError::ReadOnlyMode())));
+        assert!(matches!(
+            bc.merge_async(),
+            Err(BitcaskyError::ReadOnlyMode())
+        ));
+        assert!(matches!(bc.drop(), Err(BitcaskyError::ReadOnlyMode())));
+        assert_eq!(b"value1".to_vec(), bc.get("k1").unwrap().unwrap());
+        assert_eq!(104, bc.count_keys().unwrap());
+        bc.verify().unwrap();
+        bc.sync().unwrap();
+
+        bc.set_read_only(false);
+        std::thread::sleep(Duration::from_millis(20));
+        stop.store(true, std::sync::atomic::Ordering::Release);
+        for w in writers {
+            let (written, rejected) = w.join().unwrap();
+            assert!(written > 0);
+            assert!(rejected > 0);
+        }
+    });
+
+    assert!(!bc.is_read_only());
+    assert!(bc.delete("k1").unwrap());
+    bc.merge().unwrap();
+    assert_eq!(103, bc.count_keys().unwrap());
+}
+
+#[test]
+fn test_key_locations() {
+    let bc = Bitcasky::open(&get_temporary_directory_path(), get_default_options()).unwrap();
+    for i in 0..100 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+    bc.delete("k0").unwrap();
+
+    let locations = bc.key_locations().unwrap();
+    assert_eq!(99, locations.len());
+    let generation = locations.location_generation();
+    let mut keys = HashSet::new();
+    for (key, location) in locations {
+        assert_eq!(Some((location, generation)), bc.get_location(&key).unwrap());
+        assert_eq!(
+            bc.get(&key).unwrap(),
+            bc.read_at(&location, generation).unwrap().0
+        );
+        keys.insert(key);
+    }
+    assert_eq!(99, keys.len());
+    assert!(!keys.contains(b"k0".as_slice()));
+
+    // entries are copied when created
+    let locations = bc.key_locations().unwrap();
+    bc.put("k100", "value100").unwrap();
+    bc.merge().unwrap();
+    assert_eq!(99, locations.count());
+    let locations = bc.key_locations().unwrap();
+    assert!(locations.location_generation() > generation);
+    assert_eq!(100, locations.count());
+}
+
+#[test]
+fn test_delete_with_timestamp() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        assert!(bc.put_with_timestamp("k1", "value1", 200).unwrap());
+        // older delete arriving late is skipped
+        assert!(!bc.delete_with_timestamp("k1", 100).unwrap());
+        assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+        assert!(bc.delete_with_timestamp("k1", 200).unwrap());
+        assert!(bc.get("k1").unwrap().is_none());
+        // older put and delete arriving after the deletion are skipped
+        assert!(!bc.put_with_timestamp("k1", "value0", 150).unwrap());
+        assert!(bc.get("k1").unwrap().is_none());
+        assert!(!bc.delete_with_timestamp("k1", 100).unwrap());
+        assert!(bc.put_with_timestamp("k1", "value2", 250).unwrap());
+        assert_eq!("value2".as_bytes(), bc.get("k1").unwrap().unwrap());
+
+        bc.put("k2", "value1").unwrap();
+        assert!(!bc.delete_with_timestamp("k2", 100).unwrap());
+
+        // deleting an absent key still keeps the deletion
+        assert!(bc.delete_with_timestamp("k3", 300).unwrap());
+        assert!(!bc.put_with_timestamp("k3", "value0", 299).unwrap());
+        assert!(bc.get("k3").unwrap().is_none());
+    }
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!("value2".as_bytes(), bc.get("k1").unwrap().unwrap());
+    assert_eq!("value1".as_bytes(), bc.get("k2").unwrap().unwrap());
+    assert!(bc.get("k3").unwrap().is_none());
+}
+
+#[test]
+fn test_reject_timestamp_beyond_clock_skew() {
+    let bc = Bitcasky::open(
+        &get_temporary_directory_path(),
+        get_default_options().max_clock_skew(Duration::from_secs(60)),
+    )
+    .unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let far_future = now + Duration::from_secs(3600).as_millis() as u64;
+
+    assert!(matches!(
+        bc.put_with_timestamp("k1", "value1", far_future),
+        Err(BitcaskyError::InvalidParameter(_, _))
+    ));
+    assert!(matches!(
+        bc.delete_with_timestamp("k1", far_future),
+        Err(BitcaskyError::InvalidParameter(_, _))
+    ));
+    assert!(bc.get("k1").unwrap().is_none());
+
+    assert!(bc.put_with_timestamp("k1", "value1", now + 1000).unwrap());
+    assert!(bc.delete_with_timestamp("k1", now + 1000).unwrap());
+}
+
+#[test]
+fn test_may_contain() {
+    let bc = Bitcasky::open(
+        &get_temporary_directory_path(),
+        get_default_options().bloom_filter(100, 0.01),
+    )
+    .unwrap();
+    for i in 0..50 {
+        bc.put(format!("k{}", i), "value").unwrap();
+    }
+    for i in 0..50 {
+        assert!(bc.may_contain(format!("k{}", i)).unwrap());
+    }
+    for i in 0..25 {
+        bc.delete(format!("k{}", i)).unwrap();
+    }
+    assert_eq!(50, bc.bloom_stats().unwrap().items);
+
+    // bloom filter is rebuilt on merge to forget deleted keys
+    bc.merge().unwrap();
+    let stats = bc.bloom_stats().unwrap();
+    assert_eq!(25, stats.items);
+    assert!(stats.estimated_fpp < 0.01);
+    for i in 25..50 {
+        assert!(bc.may_contain(format!("k{}", i)).unwrap());
+    }
+
+    // falls back to keydir without bloom filter
+    let bc = Bitcasky::open(&get_temporary_directory_path(), get_default_options()).unwrap();
+    bc.put("k1", "value").unwrap();
+    assert!(bc.bloom_stats().is_none());
+    assert!(bc.may_contain("k1").unwrap());
+    assert!(!bc.may_contain("k2").unwrap());
+}
+
+fn collect_scan(bc: &Bitcasky, start: &str, end: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+    bc.scan(start, end)
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect::<Vec<_>>()
+}
+
+#[test]
+fn test_scan() {
+    let bc = Bitcasky::open(
+        &get_temporary_directory_path(),
+        get_default_options().max_data_file_size(120),
+    )
+    .unwrap();
+    for i in (0..10).rev() {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+    assert!(bc.get_telemetry_data().database.stable_storages.len() > 1);
+
+    // range spans multiple data files
+    let pairs = collect_scan(&bc, "k2", "k7");
+    assert_eq!(
+        (2..7)
+            .map(|i| (
+                format!("k{}", i).into_bytes(),
+                format!("value{}", i).into_bytes()
+            ))
+            .collect::<Vec<_>>(),
+        pairs
+    );
+
+    assert_eq!(
+        vec![(b"k3".to_vec(), b"value3".to_vec())],
+        collect_scan(&bc, "k3", "k3\0")
+    );
+    assert!(collect_scan(&bc, "k3", "k3").is_empty());
+    assert!(collect_scan(&bc, "k7", "k2").is_empty());
+    assert!(collect_scan(&bc, "a", "b").is_empty());
+
+    // values are read lazily
+    let mut iter = bc.scan("k0", "k3").unwrap();
+    bc.delete("k1").unwrap();
+    bc.put("k2", "new_value2").unwrap();
+    assert_eq!(
+        (b"k0".to_vec(), b"value0".to_vec()),
+        iter.next().unwrap().unwrap()
+    );
+    assert_eq!(
+        (b"k2".to_vec(), b"new_value2".to_vec()),
+        iter.next().unwrap().unwrap()
+    );
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn test_foreach_keys() {
+    let mut gen = RandomTestingDataGenerator::new(64, 512, vec![TestingOperator::PUT]);
+    let ops = gen.generate_testing_operations(100);
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    execute_testing_operations(&bc, &ops);
+
+    let mut expected_set: HashSet<Vec<u8>> = HashSet::new();
+    let mut actual_set: HashSet<Vec<u8>> = HashSet::new();
+    for op in ops.squash() {
+        expected_set.insert(op.key());
+    }
+
+    bc.foreach_key(|k| {
+        actual_set.insert(k.clone());
+    })
+    .unwrap();
+    assert_eq!(expected_set, actual_set);
+}
+
+#[test]
+fn test_fold_keys() {
+    let mut gen = RandomTestingDataGenerator::new(64, 512, vec![TestingOperator::PUT]);
+    let ops = gen.generate_testing_operations(100);
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    execute_testing_operations(&bc, &ops);
+
+    let mut expected_set: HashSet<Vec<u8>> = HashSet::new();
+    let mut actual_set: HashSet<Vec<u8>> = HashSet::new();
+    for op in ops.squash() {
+        expected_set.insert(op.key());
+    }
+
+    let ret = bc
+        .fold_key(
+            |k, acc| {
+                actual_set.insert(k.clone());
+                Ok(Some(acc.unwrap() + 1))
+            },
+            Some(0),
+        )
+        .unwrap();
+    assert_eq!(expected_set.len(), ret.unwrap());
+    assert_eq!(expected_set, actual_set);
+}
+
+#[test]
+fn test_foreach_matching() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    for i in 0..20 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+    bc.delete("k10").unwrap();
+    let matching = |k: &[u8]| k.starts_with(b"k1");
+
+    let mut visited = vec![];
+    bc.foreach_matching(matching, |k, v| {
+        assert_eq!(&k[1..], &v[5..]);
+        visited.push(k.to_vec());
+    })
+    .unwrap();
+    visited.sort();
+    let mut expected = (11..20)
+        .chain([1])
+        .map(|i| format!("k{}", i).into_bytes())
+        .collect::<Vec<Vec<u8>>>();
+    expected.sort();
+    assert_eq!(expected, visited);
+
+    let ret = bc
+        .fold_matching(
+            matching,
+            |_, v, acc| Ok(Some(acc.unwrap() + v.len())),
+            Some(0),
+        )
+        .unwrap();
+    assert_eq!(Some(6 + 9 * 7), ret);
+    assert_eq!(
+        None,
+        bc.fold_matching(|_| false, |_, _, _| Ok(Some(1)), None)
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_foreach() {
+    let mut gen = RandomTestingDataGenerator::new(64, 512, vec![TestingOperator::PUT]);
+    let ops = gen.generate_testing_operations(100);
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    execute_testing_operations(&bc, &ops);
+
+    let expected_pair = ops
+        .squash()
+        .iter()
+        .map(|op| (op.key(), op.value()))
+        .collect::<Vec<(Vec<u8>, Vec<u8>)>>();
+
+    let mut actual_pair: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+    bc.foreach(|k, v| {
+        actual_pair.push((k.clone(), v.clone()));
+    })
+    .unwrap();
+
+    assert_eq!(expected_pair, actual_pair);
+}
+
+#[test]
+fn test_fold() {
+    let mut gen = RandomTestingDataGenerator::new(64, 512, vec![TestingOperator::PUT]);
+    let ops = gen.generate_testing_operations(100);
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    execute_testing_operations(&bc, &ops);
+
+    let expected_pair = ops
+        .squash()
+        .iter()
+        .map(|op| (op.key(), op.value()))
+        .collect::<Vec<(Vec<u8>, Vec<u8>)>>();
+
+    let mut actual_pair: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+    let ret = bc
+        .fold(
+            |k, v, acc| {
+                actual_pair.push((k.clone(), v.clone()));
+                Ok(Some(acc.unwrap() + 1))
+            },
+            Some(0),
+        )
+        .unwrap();
+    assert_eq!(expected_pair.len(), ret.unwrap());
+    assert_eq!(expected_pair, actual_pair);
+}
+
+#[test]
+fn test_dead_bytes_by_delete() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    bc.put("k2", "value2").unwrap();
+
+    bc.delete("k1").unwrap();
+
+    assert!(
+        bc.get_telemetry_data()
+            .database
+            .storage_aggregate
+            .total_fragment
+            < 1.0
+    );
+
+    bc.delete("k2").unwrap();
+    assert_eq!(
+        1.0,
+        bc.get_telemetry_data()
+            .database
+            .storage_aggregate
+            .total_fragment
+    );
+}
+
+#[test]
+fn test_dead_bytes_by_put() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    bc.put("k2", "value2").unwrap();
+
+    bc.put("k1", "value3").unwrap();
+    bc.put("k2", "value4").unwrap();
+    assert_eq!(
+        0.5,
+        bc.get_telemetry_data()
+            .database
+            .storage_aggregate
+            .total_fragment
+    );
+}
+
+#[test]
+fn test_recover_from_hint_files_after_close() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.put("k1", "value1").unwrap();
+        bc.put("k2", "value2").unwrap();
+    }
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        let recovery_stats = bc.get_telemetry_data().keydir.recovery_stats;
+        assert!(recovery_stats.recovered_from_hint);
+        assert_eq!(1, recovery_stats.hint_files);
+        bc.delete("k1").unwrap();
+        bc.put("k3", "value3").unwrap();
+    }
+
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    let recovery_stats = bc.get_telemetry_data().keydir.recovery_stats;
+    assert!(recovery_stats.recovered_from_hint);
+    assert_eq!(2, recovery_stats.hint_files);
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!("value2".as_bytes(), bc.get("k2").unwrap().unwrap());
+    assert_eq!("value3".as_bytes(), bc.get("k3").unwrap().unwrap());
+}
+
+#[test]
+fn test_wait_for_hint_flush() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    // rotate several data files, each queues a hint file
+    for i in 0..100 {
+        bc.put(format!("k{}", i), vec![b'v'; 512]).unwrap();
+    }
+    let stable_files = bc.get_telemetry_data().database.stable_storages.len();
+    assert!(stable_files > 1);
+
+    assert!(bc.wait_for_hint_flush(Duration::from_secs(10)));
+    let telemetry = bc.get_telemetry_data().database.hint_file_writer;
+    assert_eq!(0, telemetry.number_of_pending_hint_files);
+    let hint_files = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "hint"))
+        .count();
+    assert_eq!(stable_files, hint_files);
+}
+
+#[test]
+fn test_rebuild_all_hint_files() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    for i in 0..100 {
+        bc.put(format!("k{}", i), vec![b'v'; 512]).unwrap();
+    }
+    assert!(bc.wait_for_hint_flush(Duration::from_secs(10)));
+    let stable_files = bc.get_telemetry_data().database.stable_storages.len();
+    assert!(stable_files > 1);
+    // valid hint files are rewritten too
+    assert_eq!(0, bc.rebuild_hint_files_blocking().unwrap());
+    assert_eq!(stable_files, bc.rebuild_all_hint_files().unwrap());
+    drop(bc);
+
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    let recovery_stats = bc.get_telemetry_data().keydir.recovery_stats;
+    assert!(recovery_stats.recovered_from_hint);
+    // the writing file gets its hint file on close
+    assert_eq!(stable_files + 1, recovery_stats.hint_files);
+    assert_eq!(vec![b'v'; 512], bc.get("k99").unwrap().unwrap());
+}
+
+#[test]
+fn test_write_hint_files_off() {
+    let dir = get_temporary_directory_path();
+    let options = || get_default_options().write_hint_files(false);
+    {
+        let bc = Bitcasky::open(&dir, options()).unwrap();
+        for i in 0..100 {
+            bc.put(format!("k{}", i), vec![b'v'; 512]).unwrap();
+        }
+        assert!(bc.get_telemetry_data().database.stable_storages.len() > 1);
+        assert_eq!(0, bc.rebuild_all_hint_files().unwrap());
+    }
+    let hint_files = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "hint"))
+        .count();
+    assert_eq!(0, hint_files);
+
+    let bc = Bitcasky::open(&dir, options()).unwrap();
+    assert!(
+        !bc.get_telemetry_data()
+            .keydir
+            .recovery_stats
+            .recovered_from_hint
+    );
+    for i in 0..100 {
+        assert_eq!(vec![b'v'; 512], bc.get(format!("k{}", i)).unwrap().unwrap());
+    }
+}
+
+#[test]
+fn test_rebuild_hint_files() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.put("k1", "value1").unwrap();
+        bc.put("k2", "value2").unwrap();
+    }
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.put("k3", "value3").unwrap();
+    }
+    let mut hint_file_paths = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|f| f.unwrap().path())
+        .filter(|p| p.extension().map(|e| e == "hint").unwrap_or(false))
+        .collect::<Vec<_>>();
+    assert_eq!(2, hint_file_paths.len());
+    // keep the hint file of the last data file so it won't be reused as writing file
+    hint_file_paths.sort();
+    std::fs::remove_file(&hint_file_paths[0]).unwrap();
+
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        assert!(
+            !bc.get_telemetry_data()
+                .keydir
+                .recovery_stats
+                .recovered_from_hint
+        );
+        assert_eq!(1, bc.rebuild_hint_files_blocking().unwrap());
+        assert_eq!(0, bc.rebuild_hint_files().unwrap());
+    }
+
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert!(
+        bc.get_telemetry_data()
+            .keydir
+            .recovery_stats
+            .recovered_from_hint
+    );
+    assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+    assert_eq!("value3".as_bytes(), bc.get("k3").unwrap().unwrap());
+}
+
+#[test]
+fn test_check_integrity() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.put("k1", "value1").unwrap();
+        bc.put("k2", "value2").unwrap();
+
+        let report = Bitcasky::check_integrity(&dir, get_default_options()).unwrap();
+        assert!(report.is_healthy());
+        assert_eq!(2, report.data_files[0].rows);
+        assert!(matches!(
+            Bitcasky::repair(&dir, get_default_options()),
+            Err(BitcaskyError::LockDirectoryFailed(_))
+        ));
+    }
+
+    let report = Bitcasky::check_integrity(&dir, get_default_options()).unwrap();
+    assert!(report.is_healthy());
+    assert!(report.data_files[0].hint_file.is_some());
+    let repair_report = Bitcasky::repair(&dir, get_default_options()).unwrap();
+    assert!(repair_report.truncated_data_files.is_empty());
+    assert!(repair_report.deleted_hint_files.is_empty());
+}
+
+#[test]
+fn test_switch_checksum_algorithm() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.put("k1", "value1").unwrap();
+    }
+    {
+        let bc = Bitcasky::open(
+            &dir,
+            get_default_options().checksum_algorithm(ChecksumAlgorithm::Crc32c),
+        )
+        .unwrap();
+        bc.put("k2", "value2").unwrap();
+        assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+        assert_eq!(2, bc.verify().unwrap().good_rows);
+    }
+
+    // every data file is read by the algorithm in its header
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+    assert_eq!("value2".as_bytes(), bc.get("k2").unwrap().unwrap());
+    assert!(bc.verify().unwrap().is_healthy());
+    drop(bc);
+    assert!(Bitcasky::check_integrity(&dir, get_default_options())
+        .unwrap()
+        .is_healthy());
+}
+
+fn remove_hint_files(dir: &Path, every: usize) {
+    let mut hint_files: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "hint"))
+        .collect();
+    hint_files.sort();
+    for p in hint_files.iter().step_by(every) {
+        std::fs::remove_file(p).unwrap();
+    }
+}
+
+fn assert_estimate_close(expect: usize, estimate: usize) {
+    assert!(
+        estimate.abs_diff(expect) * 20 <= expect,
+        "estimate: {} is too far from: {}",
+        estimate,
+        expect
+    );
+}
+
+#[test]
+fn test_estimate_key_count() {
+    let dir = get_temporary_directory_path();
+    let keys = 300;
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        for i in 0..keys {
+            bc.put(format!("k{:04}", i), "value").unwrap();
+        }
+        // estimate works while the database is opened
+        let estimate = bitcasky::bitcasky::estimate_key_count(&dir).unwrap();
+        assert!(estimate.unhinted_files > 0);
+        assert_estimate_close(keys, estimate.total());
+    }
+
+    let estimate = bitcasky::bitcasky::estimate_key_count(&dir).unwrap();
+    assert_eq!(0, estimate.unhinted_files);
+    assert_eq!(0, estimate.estimated);
+    assert!(estimate.hinted_files > 1);
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!(bc.count_keys().unwrap(), estimate.exact);
+    drop(bc);
+
+    remove_hint_files(&dir, 2);
+    let estimate = bitcasky::bitcasky::estimate_key_count(&dir).unwrap();
+    assert!(estimate.exact > 0);
+    assert!(estimate.unhinted_files > 0);
+    assert_estimate_close(keys, estimate.total());
+
+    remove_hint_files(&dir, 1);
+    let estimate = bitcasky::bitcasky::estimate_key_count(&dir).unwrap();
+    assert_eq!(0, estimate.exact);
+    assert_eq!(0, estimate.hinted_files);
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_estimate_close(bc.count_keys().unwrap(), estimate.total());
+}
+
+#[test]
+fn test_verify() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    for i in 0..3 {
+        bc.put(format!("k{}", i), "value").unwrap();
+    }
+    bc.merge().unwrap();
+    for i in 3..6 {
+        bc.put(format!("k{}", i), "value").unwrap();
+    }
+    let report = bc.verify().unwrap();
+    assert!(report.is_healthy());
+    assert_eq!(6, report.good_rows);
+
+    // corrupt values of one row in stable file and one in writing file
+    let header_size = BitcaskyFormatter::default().row_header_size();
+    let mut bad_locations = vec![];
+    for key in ["k1", "k4"] {
+        let (location, _) = bc.get_location(key).unwrap().unwrap();
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.join(format!("{}.data", location.storage_id)))
+            .unwrap();
+        f.seek(SeekFrom::Start(
+            (location.row_offset + header_size + key.len()) as u64,
+        ))
+        .unwrap();
+        f.write_all(b"x").unwrap();
+        bad_locations.push((location.storage_id, location.row_offset as u64));
+    }
+
+    let report = bc.verify().unwrap();
+    assert!(!report.is_healthy());
+    assert_eq!(4, report.good_rows);
+    assert_eq!(2, report.bad_rows);
+    assert_eq!(bad_locations, report.bad_locations);
+
+    // rewrite rows in stable file with a custom crc
+    #[cfg(feature = "unsafe_crc")]
+    {
+        use bitcasky::internals::RowToWrite;
+
+        let formatter = BitcaskyFormatter::default();
+        let rewrite_row = |key: &str, crc_of: &dyn Fn(u32) -> u32| {
+            let entry = bc.get_with_metadata(key).unwrap().unwrap();
+            let location = entry.location;
+            let mut f = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(dir.join(format!("{}.data", location.storage_id)))
+                .unwrap();
+            let mut original = vec![0; location.row_size];
+            f.seek(SeekFrom::Start(location.row_offset as u64)).unwrap();
+            f.read_exact(&mut original).unwrap();
+            let crc = formatter.decode_row_header(&original).crc;
+
+            let row =
+                RowToWrite::with_custom_crc(key.as_bytes(), entry.value.as_slice(), 0, crc_of(crc))
+                    .with_write_timestamp(entry.write_timestamp);
+            let mut bs = vec![0; location.row_size];
+            formatter.encode_row(&row, &mut bs);
+            f.seek(SeekFrom::Start(location.row_offset as u64)).unwrap();
+            f.write_all(&bs).unwrap();
+            (
+                original == bs,
+                (location.storage_id, location.row_offset as u64),
+            )
+        };
+
+        // a row replicated with its crc is the same as the original
+        assert!(rewrite_row("k0", &|crc| crc).0);
+        assert_eq!(bad_locations, bc.verify().unwrap().bad_locations);
+
+        let (same, bad_location) = rewrite_row("k2", &|crc| !crc);
+        assert!(!same);
+        // bad locations are reported in order of storage id and row offset
+        bad_locations.push(bad_location);
+        bad_locations.sort();
+        let report = bc.verify().unwrap();
+        assert_eq!(3, report.good_rows);
+        assert_eq!(bad_locations, report.bad_locations);
+    }
+}
+
+#[test]
+fn test_get_reader() {
+    let dir = get_temporary_directory_path();
+    let options = || {
+        get_default_options()
+            .max_data_file_size(4 * 1024 * 1024)
+            .max_value_size(1024 * 1024)
+    };
+    let large_value: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let read_all = |bc: &Bitcasky, key: &str| {
+        let mut value = vec![];
+        bc.get_reader(key)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut value)
+            .unwrap();
+        value
+    };
+    {
+        let bc = Bitcasky::open(&dir, options()).unwrap();
+        bc.put("large", &large_value).unwrap();
+        bc.put("small", "value").unwrap();
+        bc.put("deleted", "value").unwrap();
+        bc.delete("deleted").unwrap();
+        assert_eq!(large_value, read_all(&bc, "large"));
+        assert_eq!(b"value".to_vec(), read_all(&bc, "small"));
+        assert!(bc.get_reader("deleted").unwrap().is_none());
+        assert!(bc.get_reader("absent").unwrap().is_none());
+    }
+
+    let bc = Bitcasky::open(&dir, options()).unwrap();
+    let mut reader = bc.get_reader("large").unwrap().unwrap();
+    // value read keeps its content after the key is changed
+    bc.put("large", "value").unwrap();
+    let mut buf = vec![0; 1000];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(large_value[..1000], buf);
+    let mut rest = vec![];
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(large_value[1000..], rest);
+    assert_eq!(b"value".to_vec(), read_all(&bc, "large"));
+}
+
+#[test]
+fn test_get_reader_verifies_crc_at_end() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    bc.put("k1", vec![b'v'; 1000]).unwrap();
+
+    let (location, _) = bc.get_location("k1").unwrap().unwrap();
+    let header_size = BitcaskyFormatter::default().row_header_size();
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .open(dir.join(format!("{}.data", location.storage_id)))
+        .unwrap();
+    f.seek(SeekFrom::Start(
+        (location.row_offset + header_size + "k1".len() + 999) as u64,
+    ))
+    .unwrap();
+    f.write_all(b"x").unwrap();
+
+    let mut reader = bc.get_reader("k1").unwrap().unwrap();
+    let mut buf = vec![0; 999];
+    reader.read_exact(&mut buf).unwrap();
+    let err = reader.read_to_end(&mut vec![]).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+}
+
+#[test]
+fn test_repair_corrupted_database() {
+    let dir = get_temporary_directory_path();
+    let location = {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        for i in 0..3 {
+            bc.put(format!("k{}", i), "value").unwrap();
+        }
+        let (location, _) = bc.get_location("k1").unwrap().unwrap();
+        location
+    };
+    let header_size = BitcaskyFormatter::default().row_header_size();
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .open(dir.join(format!("{}.data", location.storage_id)))
+        .unwrap();
+    f.seek(SeekFrom::Start(
+        (location.row_offset + header_size + 2) as u64,
+    ))
+    .unwrap();
+    f.write_all(b"x").unwrap();
+    assert!(!Bitcasky::check_integrity(&dir, get_default_options())
+        .unwrap()
+        .is_healthy());
+
+    let report = Bitcasky::repair(&dir, get_default_options()).unwrap();
+    assert_eq!(vec![location.storage_id], report.rewritten_data_files);
+    assert_eq!(1, report.dropped_rows);
+    assert_eq!(location.row_size, report.dropped_bytes);
+
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!(b"value".to_vec(), bc.get("k0").unwrap().unwrap());
+    assert_eq!(b"value".to_vec(), bc.get("k2").unwrap().unwrap());
+}
+
+#[test]
+fn test_validate_on_open() {
+    let write_and_corrupt_row = |dir: &Path| {
+        let location = {
+            let bc = Bitcasky::open(dir, get_default_options()).unwrap();
+            for i in 0..3 {
+                bc.put(format!("k{}", i), "value").unwrap();
+            }
+            let (location, _) = bc.get_location("k1").unwrap().unwrap();
+            location
+        };
+        let header_size = BitcaskyFormatter::default().row_header_size();
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.join(format!("{}.data", location.storage_id)))
+            .unwrap();
+        f.seek(SeekFrom::Start(
+            (location.row_offset + header_size + 2) as u64,
+        ))
+        .unwrap();
+        f.write_all(b"x").unwrap();
+        location
+    };
+
+    let dir = get_temporary_directory_path();
+    let location = write_and_corrupt_row(&dir);
+    let ret = Bitcasky::open(&dir, get_default_options().validate_on_open(true));
+    assert!(matches!(
+        ret,
+        Err(BitcaskyError::DataFileCorrupted(storage_id, offset))
+            if storage_id == location.storage_id && offset == location.row_offset as u64
+    ));
+    // opened without validation
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!(b"value".to_vec(), bc.get("k0").unwrap().unwrap());
+
+    let dir = get_temporary_directory_path();
+    write_and_corrupt_row(&dir);
+    let bc = Bitcasky::open(
+        &dir,
+        get_default_options()
+            .validate_on_open(true)
+            .corruption_policy(CorruptionPolicy::SkipCorrupted),
+    )
+    .unwrap();
+    assert_eq!(b"value".to_vec(), bc.get("k0").unwrap().unwrap());
+    assert_eq!(b"value".to_vec(), bc.get("k2").unwrap().unwrap());
+
+    let dir = get_temporary_directory_path();
+    write_and_corrupt_row(&dir);
+    let options = || {
+        get_default_options()
+            .validate_on_open(true)
+            .corruption_policy(CorruptionPolicy::TruncateAtCorruption)
+    };
+    let bc = Bitcasky::open(&dir, options()).unwrap();
+    assert_eq!(b"value".to_vec(), bc.get("k0").unwrap().unwrap());
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!(None, bc.get("k2").unwrap());
+    bc.put("k3", "value").unwrap();
+    drop(bc);
+    let bc = Bitcasky::open(&dir, options()).unwrap();
+    assert!(bc.verify().unwrap().is_healthy());
+    assert_eq!(2, bc.count_keys().unwrap());
+}
+
+#[test]
+fn test_open_with_incomplete_data_file() {
+    let dir = get_temporary_directory_path();
+    let storage_id = {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.put("k0", "value0").unwrap();
+        bc.get_location("k0").unwrap().unwrap().0.storage_id
+    };
+    // left by a crash after the data file was created but before its header reached disk
+    let incomplete = dir.join(format!("{}.data", storage_id + 10));
+    std::fs::write(&incomplete, vec![0; 100]).unwrap();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert!(!incomplete.exists());
+    assert_eq!(b"value0".to_vec(), bc.get("k0").unwrap().unwrap());
+    bc.put("k1", "value1").unwrap();
+    drop(bc);
+
+    // rows in it may be referenced by its hint file
+    let storage_id = storage_id + 100;
+    std::fs::write(dir.join(format!("{}.data", storage_id)), b"").unwrap();
+    std::fs::write(dir.join(format!("{}.hint", storage_id)), b"").unwrap();
+    assert!(matches!(
+        Bitcasky::open(&dir, get_default_options()),
+        Err(BitcaskyError::IncompleteDataFile(id)) if id == storage_id
+    ));
+}
+
+#[test]
+fn test_recover_with_initial_keydir_capacity() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.reserve(3000);
+        // more rows than sampled to estimate keys on recovery
+        for i in 0..3000 {
+            bc.put(format!("k{}", i % 2000), format!("value{}", i))
+                .unwrap();
+        }
+    }
+    for capacity in [0, 100, 10000] {
+        let bc = Bitcasky::open(
+            &dir,
+            get_default_options().initial_keydir_capacity(capacity),
+        )
+        .unwrap();
+        assert_eq!(2000, bc.count_keys().unwrap());
+        assert_eq!(b"value2999".to_vec(), bc.get("k999").unwrap().unwrap());
+        assert_eq!(b"value1000".to_vec(), bc.get("k1000").unwrap().unwrap());
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialize_telemetry() {
+    let dir = get_temporary_directory_path();
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    bc.put("k1", "value1").unwrap();
+    bc.merge().unwrap();
+
+    let telemetry = bc.get_telemetry_data();
+    let json = serde_json::to_string(&telemetry).unwrap();
+    let deserialized: bitcasky::bitcasky::BitcaskTelemetry = serde_json::from_str(&json).unwrap();
+    assert_eq!(1, deserialized.keydir.number_of_keys);
+    assert_eq!(
+        telemetry.database.stable_storages.len(),
+        deserialized.database.stable_storages.len()
+    );
+}
+
+#[test]
+fn test_recover_from_corrupted_hint_file() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+        bc.put("k1", "value1").unwrap();
+        bc.put("k2", "value2").unwrap();
+        bc.delete("k1").unwrap();
+    }
+    let hint_file_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|f| f.unwrap().path())
+        .find(|p| p.extension().map(|e| e == "hint").unwrap_or(false))
+        .unwrap();
+    let mut bs = std::fs::read(&hint_file_path).unwrap();
+    // flip every byte after file header
+    bs.iter_mut().skip(8).for_each(|b| *b = !*b);
+    std::fs::write(&hint_file_path, bs).unwrap();
+
+    let bc = Bitcasky::open(&dir, get_default_options()).unwrap();
+    assert_eq!(None, bc.get("k1").unwrap());
+    assert_eq!("value2".as_bytes(), bc.get("k2").unwrap().unwrap());
+}
+
+#[test]
+fn test_sweep_expired_keys() {
+    let dir = get_temporary_directory_path();
+    let opts = || get_default_options().expiry_sweep_write_tombstones(true);
+    {
+        let bc = Bitcasky::open(&dir, opts()).unwrap();
+        for i in 0..10 {
+            bc.put_with_ttl(format!("k{}", i), "value", Duration::from_millis(1))
+                .unwrap();
+        }
+        for i in 10..15 {
+            bc.put(format!("k{}", i), "value").unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(15, bc.count_keys().unwrap());
+
+        assert_eq!(10, bc.sweep_expired().unwrap());
+        assert_eq!(0, bc.sweep_expired().unwrap());
+        assert_eq!(5, bc.count_keys().unwrap());
+        let telemetry = bc.get_telemetry_data().expiry_sweeper;
+        assert!(!telemetry.enabled);
+        assert_eq!(10, telemetry.purged_keys);
+        // only row headers are read to find expired keys
+        assert_eq!(
+            0,
+            bc.get_telemetry_data()
+                .database
+                .storage_aggregate
+                .total_read_value_times
+        );
+
+        bc.set_read_only(true);
+        assert!(matches!(
+            bc.sweep_expired(),
+            Err(BitcaskyError::ReadOnlyMode())
+        ));
+    }
+
+    let bc = Bitcasky::open(&dir, opts()).unwrap();
+    assert_eq!(5, bc.count_keys().unwrap());
+    assert!(bc.get("k0").unwrap().is_none());
+    assert_eq!("value".as_bytes(), bc.get("k10").unwrap().unwrap());
+}
+
+#[test]
+fn test_expiry_sweeper_in_background() {
+    let bc = Bitcasky::open(
+        &get_temporary_directory_path(),
+        get_default_options().expiry_sweep(Duration::from_millis(10), 4),
+    )
+    .unwrap();
+    bc.set_expiry_sweep_paused(true);
+    for i in 0..10 {
+        bc.put_with_ttl(format!("k{}", i), "value", Duration::from_millis(1))
+            .unwrap();
+    }
+    bc.put("k10", "value").unwrap();
+
+    std::thread::sleep(Duration::from_millis(50));
+    let telemetry = bc.get_telemetry_data().expiry_sweeper;
+    assert!(telemetry.enabled && telemetry.paused);
+    assert_eq!(0, telemetry.purged_keys);
+    assert_eq!(11, bc.count_keys().unwrap());
+
+    bc.set_expiry_sweep_paused(false);
+    let start = Instant::now();
+    while bc.get_telemetry_data().expiry_sweeper.purged_keys < 10 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(1, bc.count_keys().unwrap());
+}
+
+#[test]
+fn test_sweep_expired_keeps_rewritten_keys() {
+    let bc = Bitcasky::open(
+        &get_temporary_directory_path(),
+        get_default_options().expiry_sweep(Duration::from_secs(3600), 1),
+    )
+    .unwrap();
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let sweeper = s.spawn(|| {
+            while !stop.load(std::sync::atomic::Ordering::Acquire) {
+                bc.sweep_expired().unwrap();
+            }
+        });
+
+        for round in 0..50 {
+            for i in 0..10 {
+                bc.put_with_ttl(format!("k{}", i), "old", Duration::from_millis(1))
+                    .unwrap();
+            }
+            std::thread::sleep(Duration::from_millis(2));
+            for i in 0..10 {
+                let k = format!("k{}", i);
+                // expired value is never returned, whether the key is swept or not
+                assert!(bc.get(&k).unwrap().is_none());
+                let value = format!("new{}", round);
+                bc.put(k.as_str(), &value).unwrap();
+                assert_eq!(value.as_bytes(), bc.get(&k).unwrap().unwrap());
+            }
+        }
+        stop.store(true, std::sync::atomic::Ordering::Release);
+        sweeper.join().unwrap();
+    });
+    assert_eq!(10, bc.count_keys().unwrap());
+    for i in 0..10 {
+        assert_eq!(
+            "new49".as_bytes(),
+            bc.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_keydir_with_custom_hasher() {
+    let db_path = get_temporary_directory_path();
+    let open = || {
+        Bitcasky::open_with_keydir_backend(
+            &db_path,
+            get_default_options(),
+            Box::new(MemoryKeyDirBackend::with_hasher(
+                std::collections::hash_map::RandomState::new(),
+            )),
+        )
+        .unwrap()
+    };
+    let bc = open();
+    for i in 0..100 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+    bc.delete("k0").unwrap();
+    drop(bc);
+
+    let bc = open();
+    assert_eq!(99, bc.count_keys().unwrap());
+    assert!(bc.get("k0").unwrap().is_none());
+    for i in 1..100 {
+        assert_eq!(
+            format!("value{}", i).as_bytes(),
+            bc.get(format!("k{}", i)).unwrap().unwrap()
+        );
+    }
 }
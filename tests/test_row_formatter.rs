@@ -0,0 +1,167 @@
+use std::path::Path;
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::error::BitcaskyError;
+use bitcasky::format::{
+    register_row_formatter, FormatterError, RowFormatter, RowHeader, RowMeta, RowToWrite,
+};
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use byteorder::{ByteOrder, LittleEndian};
+use crc::{Crc, CRC_32_ISCSI};
+use test_log::test;
+
+const COMPACT_HEADER_SIZE: usize = 20;
+
+/// Keeps key size and value size in 4 bytes and checksums rows with crc32c
+#[derive(Debug)]
+struct CompactFormatter {
+    version: u8,
+}
+
+impl CompactFormatter {
+    fn checksum(meta: &RowMeta, kv: &[u8]) -> u32 {
+        let crc32c = Crc::<u32>::new(&CRC_32_ISCSI);
+        let mut digest = crc32c.digest();
+        digest.update(&meta.expire_timestamp.to_le_bytes());
+        digest.update(&(meta.key_size as u32).to_le_bytes());
+        digest.update(&(meta.value_size as u32).to_le_bytes());
+        digest.update(kv);
+        digest.finalize()
+    }
+}
+
+impl RowFormatter for CompactFormatter {
+    fn version(&self) -> u8 {
+        self.version
+    }
+
+    fn row_header_size(&self) -> usize {
+        COMPACT_HEADER_SIZE
+    }
+
+    fn encode_row(&self, row: &RowToWrite<&[u8], &[u8]>, output: &mut [u8]) -> usize {
+        let key_end = COMPACT_HEADER_SIZE + row.key.len();
+        let row_end = key_end + row.value.len();
+        output[COMPACT_HEADER_SIZE..key_end].copy_from_slice(row.key);
+        output[key_end..row_end].copy_from_slice(row.value);
+        let crc = Self::checksum(&row.meta, &output[COMPACT_HEADER_SIZE..row_end]);
+        LittleEndian::write_u32(output, crc);
+        LittleEndian::write_u64(&mut output[4..], row.meta.expire_timestamp);
+        LittleEndian::write_u32(&mut output[12..], row.meta.key_size as u32);
+        LittleEndian::write_u32(&mut output[16..], row.meta.value_size as u32);
+        row_end
+    }
+
+    fn decode_row_header(&self, bs: &[u8]) -> RowHeader {
+        RowHeader {
+            crc: LittleEndian::read_u32(bs),
+            meta: RowMeta {
+                expire_timestamp: LittleEndian::read_u64(&bs[4..]),
+                write_timestamp: 0,
+                key_size: LittleEndian::read_u32(&bs[12..]) as usize,
+                value_size: LittleEndian::read_u32(&bs[16..]) as usize,
+            },
+        }
+    }
+
+    fn validate_key_value(&self, header: &RowHeader, kv: &[u8]) -> Result<(), FormatterError> {
+        let actual_crc = Self::checksum(&header.meta, kv);
+        if header.crc != actual_crc {
+            return Err(FormatterError::CrcCheckFailed {
+                expected_crc: header.crc,
+                actual_crc,
+            });
+        }
+        Ok(())
+    }
+}
+
+static COMPACT_FORMATTER: CompactFormatter = CompactFormatter { version: 200 };
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(256)
+        .init_data_file_capacity(256)
+        .row_formatter(&COMPACT_FORMATTER)
+}
+
+fn formatter_versions_of_data_files(dir: &Path) -> Vec<u8> {
+    let mut paths = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "data"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths.iter().map(|p| std::fs::read(p).unwrap()[3]).collect()
+}
+
+#[test]
+fn test_custom_formatter_coexists_with_builtin_formatter() {
+    let dir = get_temporary_directory_path();
+    {
+        let bc = Bitcasky::open(&dir, BitcaskyOptions::default()).unwrap();
+        bc.put("k0", "value written by builtin formatter").unwrap();
+    }
+    let keys = (1..20).map(|i| format!("k{}", i)).collect::<Vec<_>>();
+    {
+        let bc = Bitcasky::open(&dir, get_options()).unwrap();
+        for k in keys.iter() {
+            bc.put(k.as_str(), format!("value of {}", k)).unwrap();
+        }
+    }
+    let versions = formatter_versions_of_data_files(&dir);
+    assert!(versions.contains(&3));
+    assert!(versions.contains(&COMPACT_FORMATTER.version()));
+
+    let assert_values = |bc: &Bitcasky| {
+        assert_eq!(
+            "value written by builtin formatter".as_bytes(),
+            bc.get("k0").unwrap().unwrap()
+        );
+        for k in keys.iter() {
+            assert_eq!(
+                format!("value of {}", k).as_bytes(),
+                bc.get(k).unwrap().unwrap()
+            );
+        }
+    };
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_values(&bc);
+    assert!(bc.verify().unwrap().is_healthy());
+
+    bc.merge().unwrap();
+    assert_values(&bc);
+    assert!(formatter_versions_of_data_files(&dir)
+        .iter()
+        .all(|v| *v == COMPACT_FORMATTER.version()));
+    drop(bc);
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    assert_values(&bc);
+}
+
+#[test]
+fn test_register_conflicting_formatter() {
+    static RESERVED: CompactFormatter = CompactFormatter { version: 3 };
+    static CONFLICTING: CompactFormatter = CompactFormatter { version: 201 };
+    static REGISTERED: CompactFormatter = CompactFormatter { version: 201 };
+
+    assert!(matches!(
+        register_row_formatter(&RESERVED),
+        Err(FormatterError::ReservedFormatterVersion(3))
+    ));
+    register_row_formatter(&REGISTERED).unwrap();
+    register_row_formatter(&REGISTERED).unwrap();
+    assert!(matches!(
+        register_row_formatter(&CONFLICTING),
+        Err(FormatterError::FormatterVersionConflict(201))
+    ));
+
+    let dir = get_temporary_directory_path();
+    assert!(matches!(
+        Bitcasky::open(&dir, BitcaskyOptions::default().row_formatter(&RESERVED)),
+        Err(BitcaskyError::InvalidParameter(_, _))
+    ));
+    assert!(Bitcasky::open(&dir, BitcaskyOptions::default().row_formatter(&CONFLICTING)).is_err());
+}
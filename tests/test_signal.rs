@@ -0,0 +1,86 @@
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::{BitcaskyOptions, SyncStrategy};
+
+/// Set to the database directory in the child process
+const CHILD_DIRECTORY_ENV: &str = "BITCASKY_SIGNAL_TEST_DIRECTORY";
+
+fn get_options() -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(1024)
+        .init_data_file_capacity(100)
+        .sync_strategy(SyncStrategy::None)
+}
+
+fn count_files(dir: &Path, extension: &str) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .filter(|e| {
+            e.as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|e| e == extension)
+        })
+        .count()
+}
+
+/// Runs in the child process only, writes and waits to be killed
+#[test]
+fn signal_child_process() {
+    let Ok(dir) = std::env::var(CHILD_DIRECTORY_ENV) else {
+        return;
+    };
+    let bc = Arc::new(Bitcasky::open(Path::new(&dir), get_options()).unwrap());
+    Bitcasky::install_signal_handlers(bc.clone()).unwrap();
+    // calling it again does nothing
+    Bitcasky::install_signal_handlers(bc.clone()).unwrap();
+    for i in 0..100 {
+        bc.put(format!("k{}", i), format!("value{}", i)).unwrap();
+    }
+    println!("ready");
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[test]
+fn test_flush_on_sigterm() {
+    let dir = get_temporary_directory_path();
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["signal_child_process", "--exact", "--nocapture"])
+        .env(CHILD_DIRECTORY_ENV, &dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    // libtest prints the test name on the same line
+    assert!(stdout.lines().any(|l| l.unwrap().ends_with("ready")));
+
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    let status = child.wait().unwrap();
+    // the default action exits the process after the database is flushed
+    assert_eq!(Some(libc::SIGTERM), status.signal());
+
+    // hint files of all the data files but the writing one are written before exit
+    let data_files = count_files(&dir, "data");
+    assert!(data_files > 1);
+    assert_eq!(data_files - 1, count_files(&dir, "hint"));
+
+    let bc = Bitcasky::open(&dir, get_options()).unwrap();
+    for i in 0..100 {
+        assert_eq!(
+            Some(format!("value{}", i).into_bytes()),
+            bc.get(format!("k{}", i)).unwrap()
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+
+/// Peak heap usage allowed for writing, reading and recovering 10k keys
+const MEMORY_BUDGET: usize = 3 * 1024 * 1024;
+
+/// Counts bytes allocated on heap. Only one test lives in this file so nothing else allocates
+/// while it is measuring.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn key(i: usize) -> String {
+    format!("key-{:012}", i)
+}
+
+fn value(i: usize) -> String {
+    format!("{:0>100}", i)
+}
+
+#[test]
+fn test_peak_memory_of_minimal_options() {
+    let dir = get_temporary_directory_path();
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK_ALLOCATED.store(baseline, Ordering::Relaxed);
+    {
+        let bc = Bitcasky::open(
+            &dir,
+            BitcaskyOptions::minimal().max_data_file_size(256 * 1024),
+        )
+        .unwrap();
+        for i in 0..10_000 {
+            bc.put(key(i), value(i)).unwrap();
+        }
+        for i in (0..10_000).step_by(10) {
+            bc.delete(key(i)).unwrap();
+        }
+        bc.sync().unwrap();
+        assert_eq!(value(1).as_bytes(), bc.get(key(1)).unwrap().unwrap());
+    }
+    {
+        let bc = Bitcasky::open(
+            &dir,
+            BitcaskyOptions::minimal().max_data_file_size(256 * 1024),
+        )
+        .unwrap();
+        for i in 0..10_000 {
+            let v = bc.get(key(i)).unwrap();
+            if i % 10 == 0 {
+                assert!(v.is_none());
+            } else {
+                assert_eq!(value(i).as_bytes(), v.unwrap());
+            }
+        }
+    }
+    let peak = PEAK_ALLOCATED.load(Ordering::Relaxed) - baseline;
+    assert!(
+        peak < MEMORY_BUDGET,
+        "peak heap usage: {} exceeds budget: {}",
+        peak,
+        MEMORY_BUDGET
+    );
+}
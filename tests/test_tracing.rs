@@ -0,0 +1,93 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use tracing_subscriber::fmt::format::FmtSpan;
+
+/// Collects everything written by the subscriber
+#[derive(Clone, Default)]
+struct CapturedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedOutput {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+}
+
+fn capture_spans(f: impl FnOnce()) -> Vec<String> {
+    let output = CapturedOutput::default();
+    let writer = output.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, f);
+    output.lines()
+}
+
+/// Lines reporting spans with the name closed, along with the fields recorded in them
+fn span_closed<'a>(lines: &'a [String], name: &str) -> Vec<&'a str> {
+    lines
+        .iter()
+        .filter_map(|l| l.split_once(": bitcasky::logging: close"))
+        // the closed span is the innermost one in the span path
+        .filter_map(|(path, _)| path.rsplit("}:").next())
+        .filter(|span| span.contains(&format!("{}{{", name)))
+        .collect()
+}
+
+#[test]
+fn test_emit_operation_spans() {
+    let dir = get_temporary_directory_path();
+    let lines = capture_spans(|| {
+        let bc = Bitcasky::open(&dir, BitcaskyOptions::default()).unwrap();
+        bc.put("k1", "value1").unwrap();
+        assert_eq!("value1".as_bytes(), bc.get("k1").unwrap().unwrap());
+        bc.merge().unwrap();
+    });
+
+    let puts = span_closed(&lines, "put");
+    assert_eq!(1, puts.len(), "{:?}", lines);
+    assert!(puts[0].contains("key_len=2"), "{}", puts[0]);
+    assert!(puts[0].contains("storage_id="), "{}", puts[0]);
+    assert!(puts[0].contains("row_offset="), "{}", puts[0]);
+
+    let gets = span_closed(&lines, "get");
+    assert_eq!(1, gets.len(), "{:?}", lines);
+    assert!(gets[0].contains("key_len=2"), "{}", gets[0]);
+    assert!(gets[0].contains("row_offset="), "{}", gets[0]);
+
+    let merges = span_closed(&lines, "merge");
+    assert_eq!(1, merges.len(), "{:?}", lines);
+    assert!(merges[0].contains("storage_id="), "{}", merges[0]);
+
+    // merge flushes the writing file
+    let flushes = span_closed(&lines, "flush");
+    assert!(!flushes.is_empty(), "{:?}", lines);
+
+    // logs are emitted through tracing as well
+    assert!(
+        lines.iter().any(|l| l.contains("merge success")),
+        "{:?}",
+        lines
+    );
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use bitcasky::bitcasky::Bitcasky;
+use bitcasky::codec::{ValueCodec, ValueCodecError};
+use bitcasky::internals::get_temporary_directory_path;
+use bitcasky::options::BitcaskyOptions;
+use test_log::test;
+
+const NONCE_SIZE: usize = 12;
+
+/// Encrypts values with AES-256-GCM, the random nonce is stored before the cipher text
+struct AesGcmCodec {
+    cipher: Aes256Gcm,
+}
+
+impl AesGcmCodec {
+    fn new(key: &[u8; 32]) -> AesGcmCodec {
+        AesGcmCodec {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+}
+
+impl fmt::Debug for AesGcmCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AesGcmCodec")
+    }
+}
+
+impl ValueCodec for AesGcmCodec {
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut encoded = nonce.to_vec();
+        encoded.extend(self.cipher.encrypt(&nonce, value).unwrap());
+        encoded
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, ValueCodecError> {
+        if encoded.len() < NONCE_SIZE {
+            return Err(ValueCodecError(format!(
+                "encoded value is too short: {}",
+                encoded.len()
+            )));
+        }
+        let (nonce, cipher_text) = encoded.split_at(NONCE_SIZE);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), cipher_text)
+            .map_err(|e| ValueCodecError(e.to_string()))
+    }
+}
+
+fn get_options(key: &[u8; 32]) -> BitcaskyOptions {
+    BitcaskyOptions::default()
+        .max_data_file_size(256)
+        .init_data_file_capacity(256)
+        .value_codec(Arc::new(AesGcmCodec::new(key)))
+}
+
+fn data_files_contain(dir: &Path, bs: &[u8]) -> bool {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|f| f.unwrap().path())
+        .filter(|p| p.extension().map(|e| e == "data").unwrap_or(false))
+        .any(|p| std::fs::read(p).unwrap().windows(bs.len()).any(|w| w == bs))
+}
+
+fn assert_values(bc: &Bitcasky) {
+    for i in 0..10 {
+        let expected = match i {
+            3 | 4 => None,
+            5..=7 => Some(format!("plain-value-{}-new", i).into_bytes()),
+            _ => Some(format!("plain-value-{}", i).into_bytes()),
+        };
+        assert_eq!(expected, bc.get(format!("k{}", i)).unwrap());
+        // values are decoded before read by reader
+        let read = bc.get_reader(format!("k{}", i)).unwrap().map(|mut r| {
+            let mut value = vec![];
+            r.read_to_end(&mut value).unwrap();
+            value
+        });
+        assert_eq!(expected, read);
+    }
+}
+
+#[test]
+fn test_encrypt_values() {
+    let dir = get_temporary_directory_path();
+    let key = [7u8; 32];
+    {
+        let bc = Bitcasky::open(&dir, get_options(&key)).unwrap();
+        for i in 0..10 {
+            bc.put(format!("k{}", i), format!("plain-value-{}", i))
+                .unwrap();
+        }
+        bc.delete("k3").unwrap();
+        bc.delete("k4").unwrap();
+        for i in 5..8 {
+            bc.put(format!("k{}", i), format!("plain-value-{}-new", i))
+                .unwrap();
+        }
+        bc.sync().unwrap();
+        assert!(!data_files_contain(&dir, b"plain-value"));
+        assert!(data_files_contain(&dir, b"k1"));
+        assert_values(&bc);
+
+        bc.merge().unwrap();
+        assert!(!data_files_contain(&dir, b"plain-value"));
+        assert_values(&bc);
+        assert!(bc.verify().unwrap().is_healthy());
+    }
+
+    let bc = Bitcasky::open(&dir, get_options(&key)).unwrap();
+    assert_values(&bc);
+    drop(bc);
+
+    let ret = Bitcasky::open(&dir, get_options(&[8u8; 32])).and_then(|bc| bc.get("k0"));
+    assert!(ret.is_err());
+}